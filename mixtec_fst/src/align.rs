@@ -0,0 +1,265 @@
+//! Partial credit for a prediction that is not the gold form: how far apart
+//! the two are as sequences of symbols (`test --partial-credit`).
+//!
+//! The symbols are aligned by a weighted edit distance in which a tone can
+//! only be substituted by a tone and a segment by a segment, each at its own
+//! cost, so that the distance splits into the tone errors and the segment
+//! errors. Symbols are those of the symbol table, so a multi-character
+//! symbol is one symbol, not several characters.
+
+use rustfst::{Label, SymbolTable};
+
+use crate::automaton::{tokenize_lenient, Tokenization};
+use crate::tones::ToneSet;
+
+/// What the edits of an alignment cost. Inserting or deleting a symbol
+/// always costs 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Costs {
+    /// Substituting a tone by another tone.
+    pub tone_substitution: f64,
+    /// Substituting a segment by another segment.
+    pub segment_substitution: f64,
+}
+
+impl Default for Costs {
+    fn default() -> Self {
+        Costs { tone_substitution: 1.0, segment_substitution: 1.0 }
+    }
+}
+
+/// The cheapest alignment of a prediction with a gold sequence.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Alignment {
+    /// Symbols aligned with an equal symbol.
+    pub matches: usize,
+    /// What the edits of tones cost.
+    pub tone_cost: f64,
+    /// What the edits of segments cost.
+    pub segment_cost: f64,
+}
+
+impl Alignment {
+    /// The edit distance.
+    pub fn cost(&self) -> f64 {
+        self.tone_cost + self.segment_cost
+    }
+}
+
+/// One step of an alignment, read from the prediction to the gold form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edit<T> {
+    /// A predicted symbol equal to the gold one.
+    Match(T),
+    /// A predicted symbol in place of a gold one, as `(predicted, gold)`.
+    Substitute(T, T),
+    /// A predicted symbol the gold form does not have.
+    Extra(T),
+    /// A gold symbol the prediction does not have.
+    Missing(T),
+}
+
+/// Which cell an alignment cell was reached from.
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    Start,
+    Prediction,
+    Gold,
+    Both,
+}
+
+/// The cheapest alignment of `prediction` with `gold` under `costs`, telling
+/// the tones from the segments with `is_tone`. Of alignments that cost the
+/// same, one with the most matches is taken.
+pub fn align<T: PartialEq>(prediction: &[T], gold: &[T], costs: Costs, is_tone: impl Fn(&T) -> bool) -> Alignment {
+    align_with_edits(prediction, gold, costs, is_tone).0
+}
+
+/// [`align`], with the edits of the alignment in order.
+pub fn align_with_edits<'a, T: PartialEq>(prediction: &'a [T], gold: &'a [T], costs: Costs, is_tone: impl Fn(&T) -> bool) -> (Alignment, Vec<Edit<&'a T>>) {
+    let indel = |t: &T| if is_tone(t) { (1.0, 0.0) } else { (0.0, 1.0) };
+    let substitution = |p: &T, g: &T| match (is_tone(p), is_tone(g)) {
+        _ if p == g => Some((0.0, 0.0)),
+        (true, true) => Some((costs.tone_substitution, 0.0)),
+        (false, false) => Some((0.0, costs.segment_substitution)),
+        // A tone for a segment is a deletion and an insertion.
+        _ => None,
+    };
+    let add = |a: Alignment, (tone, segment): (f64, f64), matched: bool| Alignment {
+        matches: a.matches + matched as usize,
+        tone_cost: a.tone_cost + tone,
+        segment_cost: a.segment_cost + segment,
+    };
+    let better = |a: &Alignment, b: &Alignment| a.cost() < b.cost() - 1e-9 || (a.cost() <= b.cost() + 1e-9 && a.matches > b.matches);
+    // best[i][j] aligns the first i symbols of the prediction with the first j
+    // of the gold, reached from the cell step[i][j] says.
+    let mut best = vec![vec![Alignment::default(); gold.len() + 1]; prediction.len() + 1];
+    let mut step = vec![vec![Step::Start; gold.len() + 1]; prediction.len() + 1];
+    for i in 0..=prediction.len() {
+        for j in 0..=gold.len() {
+            let mut candidates = Vec::with_capacity(3);
+            if i > 0 {
+                candidates.push((add(best[i - 1][j], indel(&prediction[i - 1]), false), Step::Prediction));
+            }
+            if j > 0 {
+                candidates.push((add(best[i][j - 1], indel(&gold[j - 1]), false), Step::Gold));
+            }
+            if i > 0 && j > 0 && let Some(cost) = substitution(&prediction[i - 1], &gold[j - 1]) {
+                candidates.push((add(best[i - 1][j - 1], cost, prediction[i - 1] == gold[j - 1]), Step::Both));
+            }
+            if let Some(first) = candidates.first().copied() {
+                (best[i][j], step[i][j]) = candidates.into_iter().fold(first, |a, b| if better(&b.0, &a.0) { b } else { a });
+            }
+        }
+    }
+    let mut edits = Vec::new();
+    let (mut i, mut j) = (prediction.len(), gold.len());
+    loop {
+        match step[i][j] {
+            Step::Start => break,
+            Step::Prediction => {
+                i -= 1;
+                edits.push(Edit::Extra(&prediction[i]));
+            }
+            Step::Gold => {
+                j -= 1;
+                edits.push(Edit::Missing(&gold[j]));
+            }
+            Step::Both => {
+                (i, j) = (i - 1, j - 1);
+                edits.push(if prediction[i] == gold[j] { Edit::Match(&prediction[i]) } else { Edit::Substitute(&prediction[i], &gold[j]) });
+            }
+        }
+    }
+    edits.reverse();
+    (best[prediction.len()][gold.len()], edits)
+}
+
+/// Whether `label` is one of the `tones` in `symt`.
+fn is_tone(symt: &SymbolTable, tones: &ToneSet, label: Label) -> bool {
+    tones.is_tone_symbol(symt.get_symbol(label).unwrap_or(""))
+}
+
+/// The edits that align `prediction` with `gold`, as symbols, split and
+/// aligned as [`Score::of`] does.
+pub fn symbol_edits(symt: &SymbolTable, prediction: &str, gold: &str, tokenization: Tokenization, tones: &ToneSet, costs: Costs) -> Vec<Edit<String>> {
+    let (predicted, _) = tokenize_lenient(symt, prediction, tokenization);
+    let (gold_labels, _) = tokenize_lenient(symt, gold, tokenization);
+    let symbol = |label: &Label| symt.get_symbol(*label).unwrap_or("").to_string();
+    let (_, edits) = align_with_edits(&predicted, &gold_labels, costs, |&label| is_tone(symt, tones, label));
+    edits
+        .into_iter()
+        .map(|edit| match edit {
+            Edit::Match(l) => Edit::Match(symbol(l)),
+            Edit::Substitute(p, g) => Edit::Substitute(symbol(p), symbol(g)),
+            Edit::Extra(l) => Edit::Extra(symbol(l)),
+            Edit::Missing(l) => Edit::Missing(symbol(l)),
+        })
+        .collect()
+}
+
+/// The partial credit of an item: its best prediction, in the notation of its
+/// gold form, and how close that is to the gold form.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Score {
+    /// `None` if the FST has no analysis of the input.
+    pub prediction: Option<String>,
+    /// The edit distance over the number of gold symbols.
+    pub cer: f64,
+    /// The part of `cer` spent on tones.
+    pub tone_cer: f64,
+    /// The part of `cer` spent on segments.
+    pub segment_cer: f64,
+    /// The F-score of the symbols the alignment matches.
+    pub f1: f64,
+}
+
+impl Score {
+    /// Score `prediction` against `gold`, both split into the symbols of
+    /// `symt`; parts that are not symbols are left out.
+    pub fn of(symt: &SymbolTable, prediction: Option<&str>, gold: &str, tokenization: Tokenization, tones: &ToneSet, costs: Costs) -> Self {
+        let (predicted, _) = prediction.map_or((Vec::new(), Vec::new()), |p| tokenize_lenient(symt, p, tokenization));
+        let (gold_labels, _) = tokenize_lenient(symt, gold, tokenization);
+        let alignment = align(&predicted, &gold_labels, costs, |&label| is_tone(symt, tones, label));
+        let n = gold_labels.len().max(1) as f64;
+        let compared = (predicted.len() + gold_labels.len()).max(1) as f64;
+        Score {
+            prediction: prediction.map(String::from),
+            cer: alignment.cost() / n,
+            tone_cer: alignment.tone_cost / n,
+            segment_cer: alignment.segment_cost / n,
+            f1: if predicted.is_empty() && gold_labels.is_empty() { 1.0 } else { 2.0 * alignment.matches as f64 / compared },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symt() -> SymbolTable {
+        rustfst::symt!["#", "k", "a", "i", "t", "ch", "1", "3"]
+    }
+
+    fn score(prediction: &str, gold: &str, costs: Costs) -> Score {
+        Score::of(&symt(), Some(prediction), gold, Tokenization::default(), &ToneSet::default(), costs)
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn test_wrong_tone() {
+        // ka3 for ka1: the tone is substituted, and k and a match.
+        let s = score("ka3", "ka1", Costs::default());
+        assert_close(s.cer, 1.0 / 3.0);
+        assert_close(s.tone_cer, 1.0 / 3.0);
+        assert_close(s.segment_cer, 0.0);
+        assert_close(s.f1, 2.0 * 2.0 / 6.0);
+        // At half the cost, a wrong tone only counts half an error.
+        let s = score("ka3", "ka1", Costs { tone_substitution: 0.5, ..Costs::default() });
+        assert_close(s.cer, 0.5 / 3.0);
+        assert_close(s.tone_cer, 0.5 / 3.0);
+    }
+
+    #[test]
+    fn test_wrong_vowel_and_missing_tone() {
+        // ki for ka1: i for a is a segment error, and the tone is deleted.
+        let s = score("ki", "ka1", Costs::default());
+        assert_close(s.cer, 2.0 / 3.0);
+        assert_close(s.tone_cer, 1.0 / 3.0);
+        assert_close(s.segment_cer, 1.0 / 3.0);
+        assert_close(s.f1, 2.0 * 1.0 / 5.0);
+        // A tone is never substituted by a segment: ka for k1 deletes the
+        // tone and inserts the vowel.
+        let s = score("ka", "k1", Costs::default());
+        assert_close(s.cer, 1.0);
+        assert_close(s.tone_cer, 0.5);
+        assert_close(s.segment_cer, 0.5);
+    }
+
+    #[test]
+    fn test_multi_character_symbol_is_one_edit() {
+        // cha1 for ta1: ch is one symbol, so one substitution, not two edits.
+        let s = score("cha1", "ta1", Costs::default());
+        assert_close(s.cer, 1.0 / 3.0);
+        assert_close(s.segment_cer, 1.0 / 3.0);
+        assert_close(s.f1, 2.0 * 2.0 / 6.0);
+        let s = score("ta1", "ta1", Costs::default());
+        assert_eq!((s.cer, s.f1), (0.0, 1.0));
+        // No analysis at all deletes every gold symbol.
+        let none = Score::of(&symt(), None, "ta1", Tokenization::default(), &ToneSet::default(), Costs::default());
+        assert_eq!((none.cer, none.f1), (1.0, 0.0));
+    }
+
+    #[test]
+    fn test_symbol_edits() {
+        let edits = |prediction, gold| symbol_edits(&symt(), prediction, gold, Tokenization::default(), &ToneSet::default(), Costs::default());
+        let s = |s: &str| s.to_string();
+        assert_eq!(edits("cha3", "ta1"), [Edit::Substitute(s("ch"), s("t")), Edit::Match(s("a")), Edit::Substitute(s("3"), s("1"))]);
+        // ki for ka1, as in the score above.
+        assert_eq!(edits("ki", "ka1"), [Edit::Match(s("k")), Edit::Substitute(s("i"), s("a")), Edit::Missing(s("1"))]);
+        assert_eq!(edits("kat1", "ka1"), [Edit::Match(s("k")), Edit::Match(s("a")), Edit::Extra(s("t")), Edit::Match(s("1"))]);
+    }
+}
@@ -0,0 +1,213 @@
+//! FSTs typed by the alphabets they read and write, so that composing one on
+//! the wrong side of another is a type error rather than an empty lattice.
+//!
+//! The segmentation FST reads surface forms and writes analyses
+//! ([`SurfaceToAnalysisFst`]); inputs are [`SurfaceAcceptor`]s, gold analyses,
+//! filters and lexicons [`AnalysisAcceptor`]s, and the G3-to-base converter
+//! rewrites analyses ([`AnalysisToAnalysisFst`]), as a tokenizer rewrites
+//! surface forms ([`SurfaceToSurfaceFst`]). [`Compose`] is only
+//! implemented where the output alphabet of the left operand is the input
+//! alphabet of the right one, so `gold.compose(&fst, ..)` with an analysis
+//! acceptor on the input side does not compile.
+//!
+//! Each wrapper derefs to its [`VectorFst`] for reading, and its field is
+//! public for the operations that do not care about direction (minimizing,
+//! drawing, writing).
+
+use std::ops::Deref;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rustfst::prelude::{invert, TropicalWeight, VectorFst};
+use rustfst::SymbolTable;
+
+use crate::analysis::AnalysisFormat;
+use crate::automaton::{linear_automaton_checked, Tokenization};
+use crate::composition::{sorted_compose, ComposeFilter};
+
+/// An FST from surface forms to analyses, such as the segmentation FST or the
+/// lattice of one word.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SurfaceToAnalysisFst(pub VectorFst<TropicalWeight>);
+
+/// An FST from analyses to surface forms, such as a generation lattice.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalysisToSurfaceFst(pub VectorFst<TropicalWeight>);
+
+/// An FST from analyses to analyses, such as the G3-to-base converter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalysisToAnalysisFst(pub VectorFst<TropicalWeight>);
+
+/// An FST from surface forms to surface forms, such as a tokenizer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SurfaceToSurfaceFst(pub VectorFst<TropicalWeight>);
+
+/// An acceptor of surface forms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SurfaceAcceptor(pub VectorFst<TropicalWeight>);
+
+/// An acceptor of analyses, such as a gold analysis, a filter or a lexicon.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalysisAcceptor(pub VectorFst<TropicalWeight>);
+
+macro_rules! deref_fst {
+    ($($ty:ident),*) => {$(
+        impl Deref for $ty {
+            type Target = VectorFst<TropicalWeight>;
+
+            fn deref(&self) -> &Self::Target {
+                &self.0
+            }
+        }
+    )*};
+}
+
+deref_fst!(SurfaceToAnalysisFst, AnalysisToSurfaceFst, AnalysisToAnalysisFst, SurfaceToSurfaceFst, SurfaceAcceptor, AnalysisAcceptor);
+
+impl SurfaceAcceptor {
+    /// The acceptor of `s`, split into symbols with `tokenization` and wrapped
+    /// in word boundaries with `wrap`.
+    pub fn of(symt: &Arc<SymbolTable>, s: &str, tokenization: Tokenization, wrap: Option<&AnalysisFormat>) -> Result<Self> {
+        Ok(SurfaceAcceptor(linear_automaton_checked(symt, s, tokenization, wrap)?))
+    }
+}
+
+impl AnalysisAcceptor {
+    /// The acceptor of the analysis `s`, as for [`SurfaceAcceptor::of`].
+    pub fn of(symt: &Arc<SymbolTable>, s: &str, tokenization: Tokenization, wrap: Option<&AnalysisFormat>) -> Result<Self> {
+        Ok(AnalysisAcceptor(linear_automaton_checked(symt, s, tokenization, wrap)?))
+    }
+}
+
+impl From<AnalysisAcceptor> for AnalysisToAnalysisFst {
+    /// An acceptor is the identity on the analyses it accepts.
+    fn from(acceptor: AnalysisAcceptor) -> Self {
+        AnalysisToAnalysisFst(acceptor.0)
+    }
+}
+
+impl SurfaceToAnalysisFst {
+    /// The same relation, from analyses to surface forms.
+    pub fn invert(mut self) -> AnalysisToSurfaceFst {
+        invert(&mut self.0);
+        AnalysisToSurfaceFst(self.0)
+    }
+}
+
+/// Composition of `Self` with `Rhs`, where the output alphabet of `Self` is
+/// the input alphabet of `Rhs` (see [`sorted_compose`]).
+pub trait Compose<Rhs> {
+    type Output;
+
+    fn compose(&self, rhs: &Rhs, filter: ComposeFilter) -> Result<Self::Output>;
+}
+
+macro_rules! compose {
+    ($($lhs:ident, $rhs:ident => $out:ident;)*) => {$(
+        impl Compose<$rhs> for $lhs {
+            type Output = $out;
+
+            fn compose(&self, rhs: &$rhs, filter: ComposeFilter) -> Result<$out> {
+                Ok($out(sorted_compose(&self.0, &rhs.0, filter)?))
+            }
+        }
+    )*};
+}
+
+compose! {
+    SurfaceAcceptor, SurfaceToAnalysisFst => SurfaceToAnalysisFst;
+    SurfaceToSurfaceFst, SurfaceToAnalysisFst => SurfaceToAnalysisFst;
+    SurfaceToAnalysisFst, AnalysisAcceptor => SurfaceToAnalysisFst;
+    SurfaceToAnalysisFst, AnalysisToAnalysisFst => SurfaceToAnalysisFst;
+    AnalysisToAnalysisFst, AnalysisAcceptor => AnalysisToAnalysisFst;
+    AnalysisAcceptor, AnalysisToSurfaceFst => AnalysisToSurfaceFst;
+    AnalysisAcceptor, AnalysisToAnalysisFst => AnalysisToAnalysisFst;
+    AnalysisToSurfaceFst, SurfaceAcceptor => AnalysisToSurfaceFst;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::marker::PhantomData;
+
+    use rustfst::prelude::{CoreFst, Fst};
+    use rustfst::utils::transducer;
+    use rustfst::Semiring;
+
+    use crate::decode::{decode_distinct_outputs, display_labels};
+    use crate::ranking::Lexicographic;
+
+    /// Whether `$lhs` composes with `$rhs`: the inherent `composes` only
+    /// exists where `Compose` is implemented, and the trait fallback is found
+    /// otherwise.
+    macro_rules! composes {
+        ($lhs:ty, $rhs:ty) => {{
+            struct Probe<L, R>(PhantomData<(L, R)>);
+            // Only one of the two is used by each probe.
+            #[allow(dead_code)]
+            trait Fallback {
+                fn composes(&self) -> bool {
+                    false
+                }
+            }
+            impl<L, R> Fallback for Probe<L, R> {}
+            #[allow(dead_code)]
+            impl<L: Compose<R>, R> Probe<L, R> {
+                fn composes(&self) -> bool {
+                    true
+                }
+            }
+            Probe::<$lhs, $rhs>(PhantomData).composes()
+        }};
+    }
+
+    fn symt() -> Arc<SymbolTable> {
+        Arc::new(rustfst::symt!["#", "a", "b"])
+    }
+
+    /// Reads `#a#` and writes `#b#`.
+    fn segmentation() -> SurfaceToAnalysisFst {
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 1 => 1, 3, 1];
+        fst.set_input_symbols(symt());
+        fst.set_output_symbols(symt());
+        SurfaceToAnalysisFst(fst)
+    }
+
+    fn outputs(fst: &VectorFst<TropicalWeight>) -> Vec<String> {
+        let symt = symt();
+        decode_distinct_outputs(fst, None, &Lexicographic, |l| display_labels(&symt, l)).unwrap().into_iter().map(|(_, o)| o).collect()
+    }
+
+    #[test]
+    fn test_only_matching_alphabets_compose() {
+        assert!(composes!(SurfaceAcceptor, SurfaceToAnalysisFst));
+        assert!(composes!(SurfaceToAnalysisFst, AnalysisAcceptor));
+        assert!(composes!(SurfaceToAnalysisFst, AnalysisToAnalysisFst));
+        assert!(composes!(AnalysisToAnalysisFst, AnalysisAcceptor));
+        assert!(composes!(SurfaceToSurfaceFst, SurfaceToAnalysisFst));
+        // The direction errors: an analysis where a surface form is read, or
+        // the other way round.
+        assert!(!composes!(AnalysisAcceptor, SurfaceToAnalysisFst));
+        assert!(!composes!(SurfaceToAnalysisFst, SurfaceAcceptor));
+        assert!(!composes!(SurfaceToAnalysisFst, SurfaceToAnalysisFst));
+        assert!(!composes!(AnalysisToAnalysisFst, SurfaceAcceptor));
+        assert!(!composes!(SurfaceAcceptor, AnalysisToSurfaceFst));
+        assert!(!composes!(SurfaceToAnalysisFst, SurfaceToSurfaceFst));
+    }
+
+    #[test]
+    fn test_composition_matches_raw_composition() {
+        let fst = segmentation();
+        let input = SurfaceAcceptor::of(&symt(), "#a#", Tokenization::default(), None).unwrap();
+        let lattice = input.compose(&fst, ComposeFilter::Auto).unwrap();
+        assert_eq!(lattice.0, sorted_compose(&input.0, &fst.0, ComposeFilter::Auto).unwrap());
+        assert_eq!(outputs(&lattice), ["#b#"]);
+        let gold = AnalysisAcceptor::of(&symt(), "#b#", Tokenization::default(), None).unwrap();
+        assert_eq!(outputs(&lattice.compose(&gold, ComposeFilter::Auto).unwrap()), ["#b#"]);
+        let other = AnalysisAcceptor::of(&symt(), "#a#", Tokenization::default(), None).unwrap();
+        assert!(lattice.compose(&other, ComposeFilter::Auto).unwrap().start().is_none());
+        // Generating from the analysis gives the surface form back.
+        let generated = gold.compose(&fst.invert(), ComposeFilter::Auto).unwrap();
+        assert_eq!(outputs(&generated), ["#a#"]);
+    }
+}
@@ -0,0 +1,204 @@
+//! The inputs the rules leave ambiguous (`test --list-ambiguous`): those with
+//! more than one distinct analysis within `--ambiguity-margin` of the best.
+//! Those are where a disambiguating rule or a weight tweak is wanted, and the
+//! ones with the most competing analyses are listed first.
+//!
+//! `test --assert-functional-on` asks the narrower question of a word list:
+//! whether each word has exactly one best analysis ([`functionality`]), the
+//! FST being functional on that vocabulary up to ties at the best weight.
+
+use std::collections::HashSet;
+
+use anyhow::Result;
+use rustfst::Semiring;
+
+use crate::prepared::PreparedFst;
+use crate::search::Candidate;
+
+/// Analyses within this much of the best count as tied with it.
+const EPSILON: f32 = 1e-5;
+
+/// The distinct analyses of `input` whose weight is within `margin` of the
+/// best, best first; empty if there is no analysis.
+pub fn competitors(prepared: &PreparedFst, input: &str, margin: f32) -> Result<Vec<Candidate>> {
+    let mut analyses = prepared.analyses(input);
+    let Some(best) = analyses.next().transpose()? else {
+        return Ok(Vec::new());
+    };
+    let bound = *best.weight.value() + margin + EPSILON;
+    let mut competitors = vec![best];
+    for candidate in analyses {
+        let candidate = candidate?;
+        if *candidate.weight.value() > bound {
+            break;
+        }
+        competitors.push(candidate);
+    }
+    Ok(competitors)
+}
+
+/// Whether an input has exactly one best analysis.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Functionality {
+    One(Candidate),
+    NoAnalysis,
+    /// Analyses that tie for the best, best ranked first.
+    Tied(Vec<Candidate>),
+}
+
+/// Whether `input` has exactly one best analysis: the competitors of its best
+/// with no margin.
+pub fn functionality(prepared: &PreparedFst, input: &str) -> Result<Functionality> {
+    let mut competitors = competitors(prepared, input, 0.0)?;
+    Ok(match competitors.len() {
+        0 => Functionality::NoAnalysis,
+        1 => Functionality::One(competitors.remove(0)),
+        _ => Functionality::Tied(competitors),
+    })
+}
+
+/// An input with more than one competing analysis.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ambiguous {
+    pub input: String,
+    pub competitors: Vec<Candidate>,
+}
+
+/// The ambiguous inputs of a run, each once however often it comes.
+#[derive(Debug, Clone)]
+pub struct AmbiguityReport {
+    margin: f32,
+    /// How many competitors are listed for each input.
+    max_competitors: usize,
+    seen: HashSet<String>,
+    ambiguous: Vec<Ambiguous>,
+}
+
+impl AmbiguityReport {
+    pub fn new(margin: f32, max_competitors: usize) -> Self {
+        AmbiguityReport { margin, max_competitors, seen: HashSet::new(), ambiguous: Vec::new() }
+    }
+
+    pub fn margin(&self) -> f32 {
+        self.margin
+    }
+
+    /// Whether `input` is yet to be checked.
+    pub fn wants(&self, input: &str) -> bool {
+        !self.seen.contains(input)
+    }
+
+    /// Record the competing analyses of `input`, keeping it if there is more
+    /// than one.
+    pub fn add(&mut self, input: &str, competitors: Vec<Candidate>) {
+        self.seen.insert(input.to_string());
+        if competitors.len() > 1 {
+            self.ambiguous.push(Ambiguous { input: input.to_string(), competitors });
+        }
+    }
+
+    /// Check `input` with `prepared`, unless it already was.
+    pub fn check(&mut self, prepared: &PreparedFst, input: &str) -> Result<()> {
+        if self.wants(input) {
+            let competitors = competitors(prepared, input, self.margin)?;
+            self.add(input, competitors);
+        }
+        Ok(())
+    }
+
+    /// The ambiguous inputs, most competitors first, and otherwise in the
+    /// order they came in.
+    pub fn sorted(&self) -> Vec<&Ambiguous> {
+        let mut sorted: Vec<&Ambiguous> = self.ambiguous.iter().collect();
+        sorted.sort_by_key(|a| std::cmp::Reverse(a.competitors.len()));
+        sorted
+    }
+
+    /// The listing printed after the run.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{} of {} inputs have more than one analysis within {} of the best:",
+            self.ambiguous.len(),
+            self.seen.len(),
+            self.margin
+        )];
+        for a in self.sorted() {
+            lines.push(format!("  {}: {} analyses", a.input, a.competitors.len()));
+            for c in a.competitors.iter().take(self.max_competitors) {
+                lines.push(format!("    {} ({})", c.analysis, c.weight));
+            }
+            if a.competitors.len() > self.max_competitors {
+                lines.push(format!("    ... and {} more", a.competitors.len() - self.max_competitors));
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use rustfst::prelude::{Fst, MutableFst, TropicalWeight, VectorFst};
+    use rustfst::{SymbolTable, Tr};
+
+    use crate::alphabet::SurfaceToAnalysisFst;
+    use crate::analysis::AnalysisFormat;
+
+    fn prepared() -> PreparedFst {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b", "c", "d"]);
+        let mut fst = VectorFst::<TropicalWeight>::new();
+        let states: Vec<_> = (0..4).map(|_| fst.add_state()).collect();
+        fst.set_start(states[0]).unwrap();
+        fst.set_final(states[3], 0.0).unwrap();
+        fst.add_tr(states[0], Tr::new(1, 1, 0.0, states[1])).unwrap();
+        fst.add_tr(states[2], Tr::new(1, 1, 0.0, states[3])).unwrap();
+        // a has three analyses that tie, b three a weight or more apart, and
+        // c one.
+        for (i, o, w) in [(2, 3, 0.0), (2, 4, 0.0), (2, 5, 0.0), (3, 4, 0.0), (3, 5, 1.0), (3, 3, 3.0), (4, 5, 0.0)] {
+            fst.add_tr(states[1], Tr::new(i, o, w, states[2])).unwrap();
+        }
+        fst.set_input_symbols(symt.clone());
+        fst.set_output_symbols(symt);
+        PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap()
+    }
+
+    #[test]
+    fn test_competitors_within_margin() {
+        let prepared = prepared();
+        let analyses = |input: &str, margin: f32| -> Vec<String> {
+            competitors(&prepared, input, margin).unwrap().into_iter().map(|c| c.analysis).collect()
+        };
+        assert_eq!(analyses("a", 0.0).len(), 3);
+        assert_eq!(analyses("b", 0.0).len(), 1);
+        assert_eq!(analyses("b", 1.0), ["c", "d"]);
+        assert_eq!(analyses("c", 0.0), ["d"]);
+    }
+
+    #[test]
+    fn test_report_lists_most_ambiguous_first() {
+        let prepared = prepared();
+        let mut report = AmbiguityReport::new(1.0, 2);
+        for input in ["b", "c", "a", "b"] {
+            report.check(&prepared, input).unwrap();
+        }
+        let inputs: Vec<&str> = report.sorted().iter().map(|a| a.input.as_str()).collect();
+        assert_eq!(inputs, ["a", "b"]);
+        let lines = report.lines();
+        assert_eq!(lines[0], "2 of 3 inputs have more than one analysis within 1 of the best:");
+        assert_eq!(lines[1], "  a: 3 analyses");
+        assert_eq!(lines[4], "    ... and 1 more");
+        assert_eq!(lines[5], "  b: 2 analyses");
+    }
+
+    #[test]
+    fn test_functionality() {
+        let prepared = prepared();
+        let Functionality::Tied(tied) = functionality(&prepared, "a").unwrap() else { panic!("a ties") };
+        assert_eq!(tied.len(), 3);
+        let Functionality::One(one) = functionality(&prepared, "b").unwrap() else { panic!("b has one best") };
+        assert_eq!(one.analysis, "c");
+        assert_eq!(functionality(&prepared, "d").unwrap(), Functionality::NoAnalysis);
+    }
+}
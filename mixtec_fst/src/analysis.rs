@@ -0,0 +1,383 @@
+//! Conventions for the strings produced by the segmentation FST.
+//!
+//! An analysis has the shape `BASE##PROC##PROC...`: the base form, followed by
+//! zero or more tone-process annotations, each introduced by the analysis
+//! separator. When the FST is applied, inputs and outputs are additionally
+//! wrapped in word boundaries (`#`). Since the default separator is itself
+//! made of boundary characters, everything that adds or removes boundaries
+//! goes through [`AnalysisFormat`] so the two are never confused.
+//!
+//! Within the base form, morphs may be separated by a morph boundary (`-`,
+//! as in `ni3-jo14`), which rules write with the predefined macro `::mb::`
+//! (see [`crate::rules::predefined_macros`]). It is word-internal, and
+//! unlike the separator has nothing to do with `#`. For golds that do not mark
+//! morphs, [`AnalysisFormat::ignore_morph_boundaries`] compares analyses
+//! without them.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use rustfst::prelude::{Fst, MutableFst, StateIterator, TropicalWeight, VectorFst};
+use rustfst::{SymbolTable, EPS_LABEL};
+
+use crate::tones::ToneSet;
+
+/// The word boundary symbol used by the rule compiler (`RegexAST::Boundary`).
+pub const DEFAULT_BOUNDARY: &str = "#";
+
+/// The separator between the base form and its process annotations.
+pub const DEFAULT_SEPARATOR: &str = "##";
+
+/// The boundary between the morphs of a base form.
+pub const DEFAULT_MORPH_BOUNDARY: &str = "-";
+
+/// An analysis split into its base form and process annotations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analysis {
+    pub base: String,
+    /// The morphs of the base form, split at the morph boundary; none if
+    /// the base form is empty.
+    pub morphs: Vec<String>,
+    pub processes: Vec<String>,
+}
+
+/// Word boundary and analysis separator used when wrapping and splitting analyses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnalysisFormat {
+    pub boundary: String,
+    pub separator: String,
+    /// Whether inputs and analyses are wrapped in word boundaries; not for
+    /// word fragments (`--no-boundaries`).
+    pub boundaries: bool,
+    /// Written between the morphs of a base form.
+    pub morph_boundary: String,
+    /// Whether analyses are compared with their morph boundaries left out
+    /// (`--ignore-morph-boundaries`), for golds that do not mark them.
+    pub ignore_morph_boundaries: bool,
+}
+
+impl Default for AnalysisFormat {
+    fn default() -> Self {
+        AnalysisFormat {
+            boundary: DEFAULT_BOUNDARY.to_string(),
+            separator: DEFAULT_SEPARATOR.to_string(),
+            boundaries: true,
+            morph_boundary: DEFAULT_MORPH_BOUNDARY.to_string(),
+            ignore_morph_boundaries: false,
+        }
+    }
+}
+
+impl AnalysisFormat {
+    pub fn new(separator: &str) -> Self {
+        AnalysisFormat {
+            separator: separator.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// The format of word fragments, which are not wrapped in boundaries.
+    pub fn without_boundaries(self) -> Self {
+        AnalysisFormat { boundaries: false, ..self }
+    }
+
+    pub fn with_morph_boundary(self, morph_boundary: &str) -> Self {
+        AnalysisFormat { morph_boundary: morph_boundary.to_string(), ..self }
+    }
+
+    /// The format that compares analyses without their morph boundaries.
+    pub fn ignoring_morph_boundaries(self) -> Self {
+        AnalysisFormat { ignore_morph_boundaries: true, ..self }
+    }
+
+    /// Check that the separator is usable and that every character of the
+    /// boundary and separator has a label in `symt`. The morph boundary need
+    /// not have one (the FST then never writes it), but may not overlap the
+    /// word boundary or the separator.
+    pub fn validate(&self, symt: &Arc<SymbolTable>) -> Result<()> {
+        if self.separator.is_empty() {
+            bail!("Analysis separator must not be empty");
+        }
+        if self.separator == self.boundary {
+            bail!(
+                "Analysis separator '{}' must be distinct from the word boundary '{}'",
+                self.separator,
+                self.boundary
+            );
+        }
+        if self.morph_boundary.is_empty() {
+            bail!("Morph boundary must not be empty");
+        }
+        if self.morph_boundary.contains(self.boundary.as_str()) || self.separator.contains(self.morph_boundary.as_str()) {
+            bail!(
+                "Morph boundary '{}' must be distinct from the word boundary '{}' and the separator '{}'",
+                self.morph_boundary,
+                self.boundary,
+                self.separator
+            );
+        }
+        let missing: Vec<String> = self
+            .boundary
+            .chars()
+            .chain(self.separator.chars())
+            .map(|c| c.to_string())
+            .filter(|c| symt.get_label(c).is_none())
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "Symbol table cannot represent the analysis separator '{}': missing {:?}",
+                self.separator,
+                missing
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that the gold analysis `gold` neither starts nor ends with the
+    /// separator. Wrapped, such a separator would run into the word boundary
+    /// (`##14>14` would become `###14>14#`), and the boundary could be read as
+    /// part of it, so it is an error in the data.
+    pub fn check_gold(&self, gold: &str) -> Result<()> {
+        if !self.boundaries {
+            return Ok(());
+        }
+        let edge = match (gold.starts_with(self.separator.as_str()), gold.ends_with(self.separator.as_str())) {
+            (true, _) => "starts",
+            (_, true) => "ends",
+            _ => return Ok(()),
+        };
+        bail!(
+            "Gold form '{}' {} with the analysis separator '{}', which would run into the word boundary '{}'; give the base form and each process around it",
+            gold,
+            edge,
+            self.separator,
+            self.boundary
+        )
+    }
+
+    /// Add exactly one word boundary on each side of `s`. A gold form is
+    /// first checked with [`AnalysisFormat::check_gold`], so that no separator
+    /// is next to the boundaries.
+    pub fn wrap(&self, s: &str) -> String {
+        match self.boundaries {
+            true => format!("{}{}{}", self.boundary, s, self.boundary),
+            false => s.to_string(),
+        }
+    }
+
+    /// Remove exactly one word boundary from each side of `s`, if present.
+    /// This is the inverse of [`AnalysisFormat::wrap`].
+    pub fn strip<'a>(&self, s: &'a str) -> &'a str {
+        if !self.boundaries {
+            return s;
+        }
+        let s = s.strip_prefix(self.boundary.as_str()).unwrap_or(s);
+        s.strip_suffix(self.boundary.as_str()).unwrap_or(s)
+    }
+
+    /// Split an (unwrapped) analysis into its base form, the morphs of the
+    /// base form and its process annotations.
+    pub fn split(&self, s: &str) -> Analysis {
+        let mut parts = s.split(self.separator.as_str()).map(|p| p.to_string());
+        let base = parts.next().unwrap_or_default();
+        let morphs = match base.is_empty() {
+            true => Vec::new(),
+            false => base.split(self.morph_boundary.as_str()).map(|m| m.to_string()).collect(),
+        };
+        Analysis {
+            base,
+            morphs,
+            processes: parts.collect(),
+        }
+    }
+
+    /// An (unwrapped) analysis with the morph boundaries of its base form
+    /// left out.
+    pub fn unmark_morphs(&self, s: &str) -> String {
+        match s.split_once(self.separator.as_str()) {
+            Some((base, processes)) => format!("{}{}{}", base.replace(self.morph_boundary.as_str(), ""), self.separator, processes),
+            None => s.replace(self.morph_boundary.as_str(), ""),
+        }
+    }
+
+    /// Write the morph boundaries `fst` outputs as epsilons if they are
+    /// ignored, so that its analyses compare with golds without them.
+    pub fn unmark_morph_outputs(&self, fst: &mut VectorFst<TropicalWeight>) -> Result<()> {
+        let label = fst.output_symbols().and_then(|symt| symt.get_label(&self.morph_boundary));
+        let Some(label) = label.filter(|_| self.ignore_morph_boundaries) else {
+            return Ok(());
+        };
+        let states: Vec<_> = fst.states_iter().collect();
+        for s in states {
+            for mut tr in fst.pop_trs(s)? {
+                if tr.olabel == label {
+                    tr.olabel = EPS_LABEL;
+                }
+                fst.add_tr(s, tr)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// An (unwrapped) analysis as it is compared with others: without its
+    /// morph boundaries if they are ignored.
+    pub fn for_comparison(&self, s: &str) -> String {
+        match self.ignore_morph_boundaries {
+            true => self.unmark_morphs(s),
+            false => s.to_string(),
+        }
+    }
+
+    /// The tone melody of an analysis: the `tones` of its base form, with
+    /// one `.` between the tones of consecutive syllables.
+    pub fn melody(&self, s: &str, tones: &ToneSet) -> String {
+        let base = self.split(s).base;
+        let mut melody = String::new();
+        let mut in_tone = false;
+        for c in base.chars() {
+            if tones.contains(c) {
+                if !in_tone && !melody.is_empty() {
+                    melody.push('.');
+                }
+                melody.push(c);
+                in_tone = true;
+            } else {
+                in_tone = false;
+            }
+        }
+        melody
+    }
+}
+
+/// Where an output writes the path weight of each analysis (`--weights`):
+/// in a field of its own, or inline, after the analysis.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum WeightOutput {
+    #[default]
+    Field,
+    Inline,
+}
+
+/// An analysis with its weight inline: `ni3jo14 (w=3.0)`.
+pub fn with_inline_weight(analysis: &str, weight: f32) -> String {
+    format!("{} (w={:?})", analysis, weight)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inline_weight() {
+        assert_eq!(with_inline_weight("ni3jo14", 3.0), "ni3jo14 (w=3.0)");
+        assert_eq!(with_inline_weight("ni3-jo14##sandhi", 1.25), "ni3-jo14##sandhi (w=1.25)");
+    }
+
+    #[test]
+    fn test_wrap_and_strip_plain() {
+        let fmt = AnalysisFormat::default();
+        assert_eq!(fmt.wrap("ni3jo14"), "#ni3jo14#");
+        assert_eq!(fmt.strip("#ni3jo14#"), "ni3jo14");
+    }
+
+    #[test]
+    fn test_gold_ending_with_separator_is_rejected() {
+        let fmt = AnalysisFormat::default();
+        let err = fmt.check_gold("ni3jo14##3>1>4##").unwrap_err().to_string();
+        assert!(err.contains("'ni3jo14##3>1>4##' ends with the analysis separator '##'"), "{}", err);
+        // A separator between the base form and a process is never next to a boundary.
+        assert!(fmt.check_gold("ni3jo14##3>1>4").is_ok());
+        assert_eq!(fmt.strip(&fmt.wrap("ni3jo14##3>1>4")), "ni3jo14##3>1>4");
+    }
+
+    #[test]
+    fn test_gold_starting_with_separator_is_rejected() {
+        let fmt = AnalysisFormat::default();
+        let err = fmt.check_gold("##14>14").unwrap_err().to_string();
+        assert!(err.contains("'##14>14' starts with the analysis separator '##'"), "{}", err);
+        assert!(AnalysisFormat::new("|").check_gold("|14>14").is_err());
+        // Fragments are not wrapped, so nothing runs into a boundary.
+        assert!(AnalysisFormat::default().without_boundaries().check_gold("##14>14").is_ok());
+    }
+
+    #[test]
+    fn test_split() {
+        let fmt = AnalysisFormat::default();
+        let analysis = fmt.split("ni3jo14##3>1>4##14>14");
+        assert_eq!(analysis.base, "ni3jo14");
+        assert_eq!(analysis.processes, vec!["3>1>4", "14>14"]);
+    }
+
+    #[test]
+    fn test_split_separator_at_edges() {
+        let fmt = AnalysisFormat::default();
+        assert_eq!(
+            fmt.split("##14>14"),
+            Analysis { base: "".to_string(), morphs: Vec::new(), processes: vec!["14>14".to_string()] }
+        );
+        assert_eq!(
+            fmt.split("i4##"),
+            Analysis { base: "i4".to_string(), morphs: vec!["i4".to_string()], processes: vec!["".to_string()] }
+        );
+    }
+
+    #[test]
+    fn test_split_morphs() {
+        let fmt = AnalysisFormat::default();
+        let analysis = fmt.split("ni3-jo14##3>1>4");
+        assert_eq!(analysis.base, "ni3-jo14");
+        assert_eq!(analysis.morphs, vec!["ni3", "jo14"]);
+        assert_eq!(analysis.processes, vec!["3>1>4"]);
+        assert_eq!(fmt.melody("ni3-jo14##3>1>4", &ToneSet::default()), "3.14");
+        let fmt = fmt.with_morph_boundary("+");
+        assert_eq!(fmt.split("ni3+jo14-i4").morphs, vec!["ni3", "jo14-i4"]);
+    }
+
+    #[test]
+    fn test_ignoring_morph_boundaries() {
+        let fmt = AnalysisFormat::default();
+        assert_eq!(fmt.unmark_morphs("ni3-jo14##3>1>4"), "ni3jo14##3>1>4");
+        assert_eq!(fmt.unmark_morphs("ni3-jo14"), "ni3jo14");
+        assert_eq!(fmt.for_comparison("ni3-jo14"), "ni3-jo14");
+        assert_eq!(fmt.ignoring_morph_boundaries().for_comparison("ni3-jo14##14>14"), "ni3jo14##14>14");
+    }
+
+    #[test]
+    fn test_custom_separator() {
+        let fmt = AnalysisFormat::new("|");
+        let analysis = fmt.split("ni3jo14|3>1>4");
+        assert_eq!(analysis.base, "ni3jo14");
+        assert_eq!(analysis.processes, vec!["3>1>4"]);
+    }
+
+    #[test]
+    fn test_melody() {
+        let fmt = AnalysisFormat::default();
+        let tones = ToneSet::default();
+        assert_eq!(fmt.melody("ni3jo14##3>1>4##14>14", &tones), "3.14");
+        assert_eq!(fmt.melody("##14>14", &tones), "");
+        // Only the configured tones count.
+        let tones = ToneSet::parse("HLM").unwrap();
+        assert_eq!(fmt.melody("niMjoHL2##3>1>4", &tones), "M.HL");
+    }
+
+    #[test]
+    fn test_fragments_are_not_wrapped() {
+        let fmt = AnalysisFormat::default().without_boundaries();
+        assert_eq!(fmt.wrap("ni1"), "ni1");
+        assert_eq!(fmt.strip("#ni1#"), "#ni1#");
+        assert_eq!(fmt.split("ni1##3>1").processes, vec!["3>1"]);
+    }
+
+    #[test]
+    fn test_validate() {
+        let symt = Arc::new(rustfst::symt!["a", "#", "|"]);
+        assert!(AnalysisFormat::default().validate(&symt).is_ok());
+        assert!(AnalysisFormat::new("|").validate(&symt).is_ok());
+        assert!(AnalysisFormat::new("#").validate(&symt).is_err());
+        assert!(AnalysisFormat::new("$$").validate(&symt).is_err());
+        assert!(AnalysisFormat::default().with_morph_boundary("#").validate(&symt).is_err());
+        assert!(AnalysisFormat::new("|").with_morph_boundary("|").validate(&symt).is_err());
+        assert!(AnalysisFormat::default().with_morph_boundary("").validate(&symt).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+//! A copy of a test CSV with the FST's best segmentation of each row beside
+//! the gold one (`test --segment-column-output`), for linguists to review and
+//! annotate in one file.
+//!
+//! Every column and row of the original is kept as it was, in order, and the
+//! best segmentation is added as a last column, [`SEGMENT_COLUMN`]; with
+//! `with_match`, a [`MATCH_COLUMN`] after it says whether that segmentation
+//! counts as the gold one (see [`counts_as`]). With `--weights`, the path
+//! weight of the segmentation goes in a [`WEIGHT_COLUMN`] after it, or inline
+//! in its cell. A row whose form has no analysis, cannot be spelled in the
+//! symbol table or is given up on for the limits (see [`crate::limits`]) gets
+//! an empty segmentation (and weight), and a row without a gold segmentation
+//! an empty match.
+
+use std::io::{Read, Write};
+
+use anyhow::{anyhow, Context, Result};
+use rustfst::Semiring;
+
+use crate::analysis::{with_inline_weight, WeightOutput};
+use crate::check::{best_analysis, counts_as};
+use crate::graphemes::GraphemeMap;
+use crate::limits::given_up;
+use crate::prepared::PreparedFst;
+
+/// The column the best segmentation is added in.
+pub const SEGMENT_COLUMN: &str = "system_segmentation";
+
+/// The column the weight of the best segmentation is added in, with
+/// [`WeightOutput::Field`].
+pub const WEIGHT_COLUMN: &str = "system_weight";
+
+/// The column saying whether the best segmentation is the gold one.
+pub const MATCH_COLUMN: &str = "match";
+
+/// How many rows were copied, analysed and matched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AnnotateSummary {
+    pub rows: usize,
+    /// Rows whose form has an analysis.
+    pub analysed: usize,
+    /// Rows with a gold segmentation.
+    pub gold: usize,
+    /// Rows whose best segmentation counts as the gold one.
+    pub matched: usize,
+    /// Rows whose form was too long or too complex to analyse.
+    pub given_up: usize,
+}
+
+/// Copy the test CSV `reader` to `writer`, with the best segmentation of the
+/// `form` of each row added (and whether it matches the row's `segmentation`,
+/// if `with_match`, and its weight where `weights` says).
+pub fn annotate<R: Read, W: Write>(
+    prepared: &PreparedFst,
+    graphemes: &GraphemeMap,
+    reader: R,
+    writer: W,
+    with_match: bool,
+    weights: Option<WeightOutput>,
+) -> Result<AnnotateSummary> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let mut headers = reader.headers().context("Failed to read the header of the test file")?.clone();
+    let column = |name: &str| headers.iter().position(|h| h == name);
+    let form_column = column("form").ok_or_else(|| anyhow!("The test file has no form column"))?;
+    let gold_column = column("segmentation");
+    if with_match && gold_column.is_none() {
+        return Err(anyhow!("The test file has no segmentation column to match against"));
+    }
+    headers.push_field(SEGMENT_COLUMN);
+    if weights == Some(WeightOutput::Field) {
+        headers.push_field(WEIGHT_COLUMN);
+    }
+    if with_match {
+        headers.push_field(MATCH_COLUMN);
+    }
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(&headers)?;
+    let mut summary = AnnotateSummary::default();
+    for (i, record) in reader.records().enumerate() {
+        let mut record = record.with_context(|| format!("Failed to read row {} of the test file", i + 2))?;
+        let form = record.get(form_column).unwrap_or("").trim();
+        let best = match graphemes.apply(&prepared.symt, form) {
+            Ok(mapped) if !mapped.is_empty() => match given_up(best_analysis(prepared, &mapped))? {
+                Ok(best) => best.map(|(weight, analysis)| (*weight.value(), analysis)),
+                Err(limit) => {
+                    log::warn!("Row {}: {}", i + 2, limit);
+                    summary.given_up += 1;
+                    None
+                }
+            },
+            Ok(_) => None,
+            Err(e) => {
+                log::warn!("Row {}: {}", i + 2, e);
+                None
+            }
+        };
+        summary.rows += 1;
+        summary.analysed += best.is_some() as usize;
+        match (&best, weights) {
+            (Some((weight, analysis)), Some(WeightOutput::Inline)) => record.push_field(&with_inline_weight(analysis, *weight)),
+            (Some((_, analysis)), _) => record.push_field(analysis),
+            (None, _) => record.push_field(""),
+        }
+        if weights == Some(WeightOutput::Field) {
+            record.push_field(&best.as_ref().map_or(String::new(), |(weight, _)| weight.to_string()));
+        }
+        if with_match {
+            let gold = gold_column.and_then(|c| record.get(c)).unwrap_or("").trim().to_string();
+            let matched = match (&best, gold.is_empty()) {
+                (_, true) => None,
+                (None, false) => Some(false),
+                (Some((_, best)), false) => Some(counts_as(prepared, best, &gold)?),
+            };
+            summary.gold += !gold.is_empty() as usize;
+            summary.matched += (matched == Some(true)) as usize;
+            record.push_field(matched.map_or("", |m| if m { "true" } else { "false" }));
+        }
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use rustfst::prelude::{Fst, TropicalWeight, VectorFst};
+    use rustfst::utils::transducer;
+    use rustfst::{Semiring, SymbolTable};
+
+    use crate::alphabet::SurfaceToAnalysisFst;
+    use crate::analysis::AnalysisFormat;
+    use crate::limits::Limits;
+
+    /// Analyses `ab` as `ba`, and as `ab` at a higher weight.
+    fn prepared() -> PreparedFst {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b"]);
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 3, 1 => 1, 3, 2, 1; 1.5];
+        let identity: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 3, 1 => 1, 2, 3, 1; 2.0];
+        rustfst::algorithms::union::union(&mut fst, &identity).unwrap();
+        fst.set_input_symbols(symt.clone());
+        fst.set_output_symbols(symt);
+        PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap()
+    }
+
+    fn annotated(csv: &str, with_match: bool) -> (String, AnnotateSummary) {
+        let mut out = Vec::new();
+        let summary = annotate(&prepared(), &GraphemeMap::default(), csv.as_bytes(), &mut out, with_match, None).unwrap();
+        (String::from_utf8(out).unwrap(), summary)
+    }
+
+    #[test]
+    fn test_keeps_columns_and_adds_best_segmentation() {
+        let csv = "id,form,segmentation,note\n1,ab,ba,\"first, quoted\"\n2,ab,ab,\n3,b,,no analysis\n4,ax,ab,unspellable\n";
+        let (out, summary) = annotated(csv, false);
+        assert_eq!(
+            out,
+            "id,form,segmentation,note,system_segmentation\n1,ab,ba,\"first, quoted\",ba\n2,ab,ab,,ba\n3,b,,no analysis,\n4,ax,ab,unspellable,\n"
+        );
+        assert_eq!(summary, AnnotateSummary { rows: 4, analysed: 2, gold: 0, matched: 0, given_up: 0 });
+    }
+
+    #[test]
+    fn test_match_column() {
+        let csv = "form,segmentation\nab,ba\nab,ab\nb,\nb,b\n";
+        let (out, summary) = annotated(csv, true);
+        assert_eq!(out, "form,segmentation,system_segmentation,match\nab,ba,ba,true\nab,ab,ba,false\nb,,,\nb,b,,false\n");
+        assert_eq!(summary, AnnotateSummary { rows: 4, analysed: 2, gold: 3, matched: 1, given_up: 0 });
+    }
+
+    #[test]
+    fn test_weights_in_a_column_or_inline() {
+        let csv = "form,segmentation\nab,ba\nb,b\n";
+        let annotated = |weights| {
+            let mut out = Vec::new();
+            annotate(&prepared(), &GraphemeMap::default(), csv.as_bytes(), &mut out, true, Some(weights)).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(annotated(WeightOutput::Field), "form,segmentation,system_segmentation,system_weight,match\nab,ba,ba,1.5,true\nb,b,,,false\n");
+        assert_eq!(annotated(WeightOutput::Inline), "form,segmentation,system_segmentation,match\nab,ba,ba (w=1.5),true\nb,b,,false\n");
+    }
+
+    #[test]
+    fn test_rows_too_long_are_left_unsegmented() {
+        let prepared = prepared().with_limits(Limits { max_input_len: 3, ..Limits::default() });
+        let mut out = Vec::new();
+        let summary = annotate(&prepared, &GraphemeMap::default(), "form
+ab
+abab
+".as_bytes(), &mut out, false, None).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "form,system_segmentation
+ab,ba
+abab,
+");
+        assert_eq!(summary, AnnotateSummary { rows: 2, analysed: 1, given_up: 1, ..Default::default() });
+    }
+
+    #[test]
+    fn test_needs_form_and_gold_columns() {
+        let mut out = Vec::new();
+        let err = annotate(&prepared(), &GraphemeMap::default(), "word,segmentation\nab,ba\n".as_bytes(), &mut out, false, None).unwrap_err();
+        assert!(err.to_string().contains("no form column"), "{}", err);
+        let err = annotate(&prepared(), &GraphemeMap::default(), "form\nab\n".as_bytes(), &mut out, true, None).unwrap_err();
+        assert!(err.to_string().contains("no segmentation column"), "{}", err);
+    }
+}
@@ -0,0 +1,176 @@
+//! Atomic artifact writes, and reads that tell a truncated artifact from a
+//! corrupt one.
+//!
+//! Every artifact (FSTs, their sidecars, reports, cache entries, checkpoints)
+//! is written to a temporary file next to it and renamed over it once complete,
+//! so an interrupted write leaves the previous artifact, not a partial one. A
+//! file truncated some other way (a full disk, a copy cut short) is caught on
+//! reading: its header promises more states and arcs than it holds, or it
+//! ends inside its header or symbol tables.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use rustfst::prelude::{SerializableFst, TropicalWeight, VectorFst};
+
+/// The OpenFst magic number that binary FSTs start with.
+const FST_MAGIC: i32 = 2_125_659_606;
+
+/// The temporary file `path` is written to before it is renamed into place.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()))
+}
+
+/// Write the artifact at `path` by calling `write` with a temporary path next
+/// to it, and rename the temporary file into place once `write` succeeds. On
+/// failure the temporary file is removed and `path` is left as it was.
+pub fn write_atomic(path: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let temp = temp_path(path);
+    let result = write(&temp)
+        .and_then(|()| Ok(File::open(&temp)?.sync_all()?))
+        .and_then(|()| Ok(std::fs::rename(&temp, path)?));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result.with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Create the artifact at `path` with what `write` writes, atomically.
+pub fn create_atomic(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> Result<()>) -> Result<()> {
+    write_atomic(path, |temp| {
+        let mut file = BufWriter::new(File::create(temp)?);
+        write(&mut file)?;
+        file.flush()?;
+        Ok(())
+    })
+}
+
+/// Write `contents` to `path`, atomically.
+pub fn write_file_atomic(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    write_atomic(path, |temp| Ok(std::fs::write(temp, contents)?))
+}
+
+/// Write `fst` to `path` in binary, atomically.
+pub fn write_fst(fst: &VectorFst<TropicalWeight>, path: &Path) -> Result<()> {
+    write_atomic(path, |temp| fst.write(temp))
+}
+
+/// Write `fst` to `path` in OpenFst text format, atomically.
+pub fn write_fst_text(fst: &VectorFst<TropicalWeight>, path: &Path) -> Result<()> {
+    write_atomic(path, |temp| fst.write_text(temp))
+}
+
+/// Reads the fixed part of a binary FST header.
+struct HeaderReader<'a>(&'a [u8]);
+
+impl HeaderReader<'_> {
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        let (head, rest) = self.0.split_at_checked(n)?;
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        Some(i32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn i64(&mut self) -> Option<i64> {
+        Some(i64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let n = usize::try_from(self.i32()?).ok()?;
+        Some(String::from_utf8_lossy(self.take(n)?).into_owned())
+    }
+}
+
+/// The fewest bytes a binary FST with `data`'s header can take, without its
+/// symbol tables: each state has a final weight and an arc count, each arc
+/// two labels, a weight and a target. `None` if the header itself is cut
+/// short.
+fn expected_min_len(data: &[u8]) -> Option<usize> {
+    let mut header = HeaderReader(data);
+    header.i32()?;
+    header.string()?;
+    header.string()?;
+    header.take(4 + 4 + 8 + 8)?; // version, flags, properties, start
+    let num_states = usize::try_from(header.i64()?).ok()?;
+    let num_trs = usize::try_from(header.i64()?).ok()?;
+    let body = num_states.checked_mul(12)?.checked_add(num_trs.checked_mul(16)?)?;
+    (data.len() - header.0.len()).checked_add(body)
+}
+
+/// The message for an artifact at `path` that ends too soon.
+pub fn truncated(path: &Path) -> String {
+    format!("{} appears truncated; was a build interrupted? Rebuild it", path.display())
+}
+
+/// Read the binary FST at `path`, saying so if it is truncated rather than
+/// failing on the first missing byte.
+pub fn read_fst(path: &Path) -> Result<VectorFst<TropicalWeight>> {
+    let data = std::fs::read(path).with_context(|| format!("Failed to read FST {}", path.display()))?;
+    if data.len() < 4 {
+        bail!(truncated(path));
+    }
+    if i32::from_le_bytes(data[..4].try_into().unwrap()) != FST_MAGIC {
+        bail!("{} is not a binary FST", path.display());
+    }
+    match expected_min_len(&data) {
+        Some(min) if data.len() >= min => {}
+        _ => bail!(truncated(path)),
+    }
+    // Past the magic number and the size check, what fails to parse is almost
+    // always cut short inside the symbol tables.
+    VectorFst::<TropicalWeight>::load(&data).with_context(|| truncated(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use rustfst::prelude::Fst;
+    use rustfst::utils::transducer;
+    use rustfst::{Semiring, SymbolTable};
+
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_failed_write_keeps_the_old_artifact() {
+        let dir = TempDir::new("artifact-atomic");
+        let path = dir.join("report.txt");
+        write_file_atomic(&path, "good\n").unwrap();
+        let failed = create_atomic(&path, |file| {
+            writeln!(file, "partial")?;
+            bail!("interrupted")
+        });
+        assert!(failed.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "good\n");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1, "the temporary file is cleaned up");
+    }
+
+    #[test]
+    fn test_truncated_fst_is_reported_as_truncated() {
+        let dir = TempDir::new("artifact-truncated");
+        let path = dir.join("out.fst");
+        let symt = Arc::new(rustfst::symt!["#", "a", "b"]);
+        let mut fst: VectorFst<TropicalWeight> = transducer(&[1, 2, 1], &[2, 2, 1], TropicalWeight::new(1.0));
+        fst.set_input_symbols(symt.clone());
+        fst.set_output_symbols(symt);
+        write_fst(&fst, &path).unwrap();
+        assert_eq!(read_fst(&path).unwrap(), fst);
+
+        // Cut short in the header, in the symbol tables, in the body, and to nothing.
+        let full = std::fs::read(&path).unwrap();
+        for len in [0, 2, 20, full.len() / 2, full.len() - 1] {
+            std::fs::write(&path, &full[..len]).unwrap();
+            let e = read_fst(&path).unwrap_err().to_string();
+            assert!(e.contains("appears truncated; was a build interrupted?"), "{}: {}", len, e);
+        }
+        std::fs::write(&path, "a -> b / _ c\n").unwrap();
+        assert!(read_fst(&path).unwrap_err().to_string().contains("is not a binary FST"));
+    }
+}
@@ -0,0 +1,232 @@
+//! Attributing analyses to the rule file that produced them.
+//!
+//! When building with source attribution, every path through a rule file's FST
+//! emits that file's marker label once on the output side. Marker labels are
+//! not in the symbol table; they live in an auxiliary table written next to the
+//! FST as `<outpath>.sources`. Decoding separates the markers from the displayed
+//! analysis, and [`SourceMarkers::strip`] removes them before any comparison
+//! against gold analyses.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use rustfst::prelude::concat::concat;
+use rustfst::prelude::{MutableFst, StateIterator, TropicalWeight, VectorFst};
+use rustfst::utils::transducer;
+use rustfst::{Label, Semiring, SymbolTable, EPS_LABEL};
+
+use crate::artifact::write_file_atomic;
+use crate::decode::decode_distinct_outputs;
+use crate::ranking::CandidateRanker;
+
+/// Attribution of analyses produced by the weighted sigma-star fallback alone.
+pub const IDENTITY_SOURCE: &str = "identity";
+
+/// First marker label; well clear of any symbol-table label, including the
+/// auxiliary symbols the rule compiler appends.
+const MARKER_BASE: Label = 1 << 24;
+
+/// Marker labels for a list of rule sources, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMarkers {
+    sources: Vec<String>,
+}
+
+impl SourceMarkers {
+    pub fn new(files: &[PathBuf]) -> Self {
+        SourceMarkers {
+            sources: files.iter().map(|f| f.display().to_string()).collect(),
+        }
+    }
+
+    /// The marker label of the `i`-th source.
+    pub fn label(&self, i: usize) -> Label {
+        MARKER_BASE + i as Label
+    }
+
+    /// Every marker label, in source order.
+    pub fn labels(&self) -> impl Iterator<Item = Label> + '_ {
+        (0..self.sources.len()).map(|i| self.label(i))
+    }
+
+    /// The source a marker label stands for, if `label` is a marker.
+    pub fn source(&self, label: Label) -> Option<&str> {
+        let i = label.checked_sub(MARKER_BASE)? as usize;
+        self.sources.get(i).map(String::as_str)
+    }
+
+    /// Make every path through `fst` emit the marker of the `i`-th source.
+    pub fn mark(&self, fst: &mut VectorFst<TropicalWeight>, i: usize) -> Result<()> {
+        let marker: VectorFst<TropicalWeight> = rustfst::fst![EPS_LABEL => self.label(i)];
+        concat(fst, &marker)?;
+        Ok(())
+    }
+
+    /// Replace marker output labels with epsilons.
+    pub fn strip(&self, fst: &mut VectorFst<TropicalWeight>) -> Result<()> {
+        let states: Vec<_> = fst.states_iter().collect();
+        for s in states {
+            for mut tr in fst.pop_trs(s)? {
+                if self.source(tr.olabel).is_some() {
+                    tr.olabel = EPS_LABEL;
+                }
+                fst.add_tr(s, tr)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The displayed analysis for a path's output labels, and the sources of
+    /// its markers ([`IDENTITY_SOURCE`] if it has none).
+    pub fn decode(&self, symt: &SymbolTable, olabels: &[Label]) -> (String, Vec<String>) {
+        let mut sources = Vec::new();
+        let mut output = String::new();
+        for &l in olabels {
+            match self.source(l) {
+                Some(source) => sources.push(source.to_string()),
+                None => output.push_str(symt.get_symbol(l).unwrap_or("")),
+            }
+        }
+        if sources.is_empty() {
+            sources.push(IDENTITY_SOURCE.to_string());
+        }
+        (output, sources)
+    }
+
+    /// The distinct `analysis [source, ...]` outputs of `fst` with their best
+    /// weights, best first and ties ordered by `ranker`; at most `cap` of them.
+    pub fn decode_paths(
+        &self,
+        symt: &SymbolTable,
+        fst: &VectorFst<TropicalWeight>,
+        cap: Option<usize>,
+        ranker: &dyn CandidateRanker,
+    ) -> Result<Vec<(TropicalWeight, String)>> {
+        decode_distinct_outputs(fst, cap, ranker, |olabels| {
+            let (output, sources) = self.decode(symt, olabels);
+            format!("{} [{}]", output, sources.join(", "))
+        })
+    }
+
+    fn table_path(fst_path: &Path) -> PathBuf {
+        let mut path = fst_path.as_os_str().to_owned();
+        path.push(".sources");
+        PathBuf::from(path)
+    }
+
+    /// Write the marker table next to the FST at `fst_path`.
+    pub fn write(&self, fst_path: &Path) -> Result<()> {
+        let table: String = self
+            .sources
+            .iter()
+            .enumerate()
+            .map(|(i, source)| format!("{}\t{}\n", self.label(i), source))
+            .collect();
+        write_file_atomic(&Self::table_path(fst_path), table)
+    }
+
+    /// Read the marker table written next to the FST at `fst_path`.
+    pub fn read(fst_path: &Path) -> Result<Self> {
+        let path = Self::table_path(fst_path);
+        let table = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow!("Failed to read source table {}: {}", path.display(), e))?;
+        let mut sources = Vec::new();
+        for (i, line) in table.lines().enumerate() {
+            let (label, source) = line
+                .split_once('\t')
+                .ok_or_else(|| anyhow!("Malformed line {} in source table {}", i + 1, path.display()))?;
+            if label.parse::<Label>().ok() != Some(MARKER_BASE + i as Label) {
+                return Err(anyhow!("Unexpected marker label {} in source table {}", label, path.display()));
+            }
+            sources.push(source.to_string());
+        }
+        Ok(SourceMarkers { sources })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    use parserule::rulefst;
+    use rustfst::prelude::{tr_sort, CoreFst, Fst, ILabelCompare};
+
+    use crate::analysis::AnalysisFormat;
+    use crate::build::build_from_rule_files;
+    use crate::rules::{compile_rule_file, RuleChecks};
+    use crate::testutil::{fixture_golds, fixture_symt, min_rules, TempDir};
+
+    /// The (display, sources) candidates for `input`.
+    fn candidates(
+        fst: &VectorFst<TropicalWeight>,
+        symt: &Arc<SymbolTable>,
+        markers: &SourceMarkers,
+        input: &str,
+    ) -> Vec<(String, Vec<String>)> {
+        let mut fst = fst.clone();
+        tr_sort(&mut fst, ILabelCompare {});
+        let e2e = rulefst::apply_fst_to_string(symt.clone(), fst, input.to_string()).unwrap();
+        e2e.string_paths_iter().unwrap().map(|p| markers.decode(symt, p.olabels())).collect()
+    }
+
+    fn outputs(fst: &VectorFst<TropicalWeight>, symt: &Arc<SymbolTable>, input: &str) -> HashSet<String> {
+        let mut fst = fst.clone();
+        tr_sort(&mut fst, ILabelCompare {});
+        let e2e = rulefst::apply_fst_to_string(symt.clone(), fst, input.to_string()).unwrap();
+        rulefst::decode_paths_through_fst(symt.clone(), e2e).into_iter().map(|(_, o)| o).collect()
+    }
+
+    #[test]
+    fn test_attribution_matches_generating_file() {
+        let symt = fixture_symt();
+        let golds = fixture_golds();
+        let files = min_rules(&["neg_4.txt", "hab_14.txt"]);
+        let markers = SourceMarkers::new(&files);
+        let fst = build_from_rule_files(symt.clone(), &files, &Default::default(), Default::default(), Default::default(), Some(&markers), None, &mut Default::default()).unwrap();
+        let per_file: Vec<_> = files.iter().map(|f| compile_rule_file(symt.clone(), f, &mut RuleChecks::default()).unwrap()).collect();
+        let fmt = AnalysisFormat::default();
+        let mut seen = HashSet::new();
+        for (form, _) in golds.iter() {
+            let input = fmt.wrap(form);
+            for (output, sources) in candidates(&fst, &symt, &markers, &input) {
+                assert_eq!(sources.len(), 1, "{} -> {}: {:?}", input, output, sources);
+                let source = &sources[0];
+                seen.insert(source.clone());
+                if source == IDENTITY_SOURCE {
+                    assert_eq!(output, input);
+                } else {
+                    let i = files.iter().position(|f| &f.display().to_string() == source).unwrap();
+                    assert!(outputs(&per_file[i], &symt, &input).contains(&output), "{} -> {} [{}]", input, output, source);
+                }
+            }
+        }
+        assert!(seen.contains(IDENTITY_SOURCE));
+        assert!(seen.len() > 1, "{:?}", seen);
+    }
+
+    #[test]
+    fn test_strip_removes_markers() {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b"]);
+        let markers = SourceMarkers::new(&[PathBuf::from("x.txt")]);
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![2, 3 => 3, 2];
+        fst.set_input_symbols(symt.clone());
+        fst.set_output_symbols(symt.clone());
+        markers.mark(&mut fst, 0).unwrap();
+        let (output, sources) = candidates(&fst, &symt, &markers, "ab").remove(0);
+        assert_eq!((output.as_str(), sources), ("ba", vec!["x.txt".to_string()]));
+        markers.strip(&mut fst).unwrap();
+        assert!(fst.states_iter().all(|s| fst.get_trs(s).unwrap().iter().all(|tr| markers.source(tr.olabel).is_none())));
+        assert_eq!(candidates(&fst, &symt, &markers, "ab").remove(0).1, vec![IDENTITY_SOURCE.to_string()]);
+    }
+
+    #[test]
+    fn test_source_table_round_trip() {
+        let dir = TempDir::new("sources");
+        let path = dir.join("out.fst");
+        let markers = SourceMarkers::new(&[PathBuf::from("rules/a.txt"), PathBuf::from("rules/b.txt")]);
+        markers.write(&path).unwrap();
+        assert_eq!(SourceMarkers::read(&path).unwrap(), markers);
+    }
+}
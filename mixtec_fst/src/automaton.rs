@@ -0,0 +1,153 @@
+//! Linear automata of strings, checked against the symbol table.
+//!
+//! [`rulefst::string_to_linear_automaton`](parserule::rulefst::string_to_linear_automaton)
+//! reads a string one character at a time and drops the characters that have
+//! no label, so a symbol spelled with several characters (a base letter and a
+//! combining mark) loses its mark, and a string with an unknown character
+//! becomes a different string. Compositions with such an automaton then
+//! succeed vacuously, or fail far from the cause. Here strings are tokenized
+//! into symbols first, and any part that is not a symbol is an error, unless
+//! it is skipped on purpose to see what the word would give without it
+//! ([`skip_missing_symbols`]).
+
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use rustfst::prelude::{Fst, TropicalWeight, VectorFst};
+use rustfst::utils::acceptor;
+use rustfst::{Label, Semiring, SymbolTable, EPS_LABEL};
+use unicode_normalization::char::is_combining_mark;
+
+use crate::analysis::AnalysisFormat;
+
+/// How a string is split into symbols of the symbol table (`--tokenization`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Tokenization {
+    /// One symbol per grapheme: a character with the combining marks after it
+    Grapheme,
+    /// The longest symbol that matches at each position
+    #[default]
+    LongestMatch,
+}
+
+/// The labels of the symbols of `s`, tokenized with `tokenization`. Every part
+/// of `s` that is not a symbol is reported, with its character position.
+pub fn tokenize(symt: &SymbolTable, s: &str, tokenization: Tokenization) -> Result<Vec<Label>> {
+    let (labels, missing) = tokenize_lenient(symt, s, tokenization);
+    if !missing.is_empty() {
+        bail!("'{}' has symbols missing from the symbol table: {}", s, missing.join(", "));
+    }
+    Ok(labels)
+}
+
+/// `s` with the parts that are not symbols left out, as if they had been
+/// epsilon, and the parts left out with their character positions.
+pub fn skip_missing_symbols(symt: &SymbolTable, s: &str, tokenization: Tokenization) -> (String, Vec<String>) {
+    let (labels, missing) = tokenize_lenient(symt, s, tokenization);
+    (labels.iter().filter_map(|&l| symt.get_symbol(l)).collect(), missing)
+}
+
+/// The labels of the symbols of `s`, and the parts of `s` that are not symbols.
+pub(crate) fn tokenize_lenient(symt: &SymbolTable, s: &str, tokenization: Tokenization) -> (Vec<Label>, Vec<String>) {
+    let label = |symbol: &str| symt.get_label(symbol).filter(|&l| l != EPS_LABEL);
+    let chars: Vec<(usize, char)> = s.char_indices().collect();
+    let byte = |i: usize| chars.get(i).map_or(s.len(), |&(b, _)| b);
+    let max_symbol_len = symt.iter().map(|(_, s)| s.chars().count()).max().unwrap_or(1);
+    let mut labels = Vec::new();
+    let mut missing = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let end = match tokenization {
+            Tokenization::Grapheme => {
+                let marks = chars[i + 1..].iter().take_while(|(_, c)| is_combining_mark(*c)).count();
+                let end = i + 1 + marks;
+                label(&s[byte(i)..byte(end)]).map(|l| (l, end)).ok_or(end)
+            }
+            Tokenization::LongestMatch => (i + 1..=chars.len().min(i + max_symbol_len))
+                .rev()
+                .find_map(|end| label(&s[byte(i)..byte(end)]).map(|l| (l, end)))
+                .ok_or(i + 1),
+        };
+        match end {
+            Ok((l, end)) => {
+                labels.push(l);
+                i = end;
+            }
+            Err(end) => {
+                missing.push(format!("'{}' at {}", &s[byte(i)..byte(end)], i));
+                i = end;
+            }
+        }
+    }
+    (labels, missing)
+}
+
+/// An acceptor of `s`, wrapped in word boundaries with `wrap`, with `symt` as
+/// its input and output symbols. Fails if any part of `s` is not a symbol.
+pub fn linear_automaton_checked(
+    symt: &Arc<SymbolTable>,
+    s: &str,
+    tokenization: Tokenization,
+    wrap: Option<&AnalysisFormat>,
+) -> Result<VectorFst<TropicalWeight>> {
+    let s = match wrap {
+        Some(fmt) => fmt.wrap(s),
+        None => s.to_string(),
+    };
+    let mut fst: VectorFst<TropicalWeight> = acceptor(&tokenize(symt, &s, tokenization)?, TropicalWeight::one());
+    fst.set_input_symbols(symt.clone());
+    fst.set_output_symbols(symt.clone());
+    Ok(fst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parserule::normalize::nfd_normalize;
+    use rustfst::prelude::ExpandedFst;
+
+    fn symt() -> Arc<SymbolTable> {
+        let mut symt = rustfst::symt!["#", "a", "n", "1", "4", "##"];
+        symt.add_symbol(nfd_normalize("ñ"));
+        Arc::new(symt)
+    }
+
+    fn labels(s: &str, tokenization: Tokenization) -> Vec<Label> {
+        tokenize(&symt(), s, tokenization).unwrap()
+    }
+
+    #[test]
+    fn test_out_of_vocabulary_symbols_are_listed_with_positions() {
+        let err = linear_automaton_checked(&symt(), "nax4z", Tokenization::LongestMatch, None).unwrap_err().to_string();
+        assert!(err.contains("'x' at 2, 'z' at 4"), "{}", err);
+        let err = tokenize(&symt(), "a\u{301}n", Tokenization::Grapheme).unwrap_err().to_string();
+        assert!(err.contains("'a\u{301}' at 0") && !err.contains("'n'"), "{}", err);
+    }
+
+    #[test]
+    fn test_skipping_missing_symbols() {
+        assert_eq!(skip_missing_symbols(&symt(), "nax4z", Tokenization::LongestMatch), ("na4".to_string(), vec!["'x' at 2".to_string(), "'z' at 4".to_string()]));
+        assert_eq!(skip_missing_symbols(&symt(), "na4", Tokenization::Grapheme), ("na4".to_string(), vec![]));
+    }
+
+    #[test]
+    fn test_multi_char_symbols() {
+        let nye = symt().get_label(nfd_normalize("ñ")).unwrap();
+        assert_eq!(labels(&nfd_normalize("ña4"), Tokenization::LongestMatch), [nye, 2, 5]);
+        assert_eq!(labels(&nfd_normalize("ña4"), Tokenization::Grapheme), [nye, 2, 5]);
+        // Longest match reads `##` as one symbol; graphemes are single characters.
+        assert_eq!(labels("a##1", Tokenization::LongestMatch), [2, 6, 4]);
+        assert_eq!(labels("a##1", Tokenization::Grapheme), [2, 1, 1, 4]);
+    }
+
+    #[test]
+    fn test_boundary_wrapping() {
+        let fmt = AnalysisFormat::default();
+        let fst = linear_automaton_checked(&symt(), "na1", Tokenization::Grapheme, Some(&fmt)).unwrap();
+        let path = fst.paths_iter().next().unwrap();
+        assert_eq!(path.ilabels, [1, 3, 2, 4, 1]);
+        assert_eq!(fst.input_symbols(), Some(&symt()));
+        assert_eq!(fst.num_states(), 6);
+        assert!(linear_automaton_checked(&symt(), "", Tokenization::Grapheme, Some(&fmt)).is_ok());
+    }
+}
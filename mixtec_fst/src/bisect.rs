@@ -0,0 +1,258 @@
+//! Bisecting the rule files to find the ones that break a gold item: a
+//! delta-debugging search over subsets of the files, each rebuilt as the union
+//! of their cached per-file FSTs rather than compiled again.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use rustfst::prelude::{TropicalWeight, VectorFst};
+use rustfst::{Semiring, SymbolTable};
+
+use crate::alphabet::SurfaceToAnalysisFst;
+use crate::boundary::FallbackBoundary;
+use crate::build::{file_name, weight_offset, RuleUnion};
+use crate::cache::compile_rule_file_cached;
+use crate::check::{accepts_pair, best_analysis, counts_as};
+use crate::prepared::PreparedFst;
+use crate::rule_config::RuleFileConfig;
+use crate::rules::RuleChecks;
+
+/// How the union of a subset of the rule files does on the item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The best analysis counts as the gold one.
+    Pass,
+    /// The gold analysis is among the analyses, but another outranks it.
+    Fail,
+    /// The gold analysis is not among the analyses at all.
+    Unresolved,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Outcome::Pass => "pass",
+            Outcome::Fail => "FAIL",
+            Outcome::Unresolved => "unresolved",
+        })
+    }
+}
+
+/// A compiled rule file, with the number of rules its paths are ranked by.
+pub struct RuleFile {
+    pub path: PathBuf,
+    pub fst: VectorFst<TropicalWeight>,
+    pub num_rules: usize,
+    /// The settings of its `.toml`.
+    pub config: RuleFileConfig,
+}
+
+impl RuleFile {
+    /// Compile the file at `path` through the per-file cache, checking its
+    /// rules with `checks`.
+    pub fn load(symt: Arc<SymbolTable>, path: &Path, cache_dir: Option<&Path>, checks: &mut RuleChecks) -> Result<Self> {
+        let config = RuleFileConfig::load(path)?;
+        let kept = checks.kept;
+        let fst = compile_rule_file_cached(symt, path, cache_dir, checks)?;
+        let num_rules = checks.kept - kept;
+        Ok(RuleFile { path: path.to_path_buf(), fst, num_rules, config })
+    }
+}
+
+/// The smallest subset of `0..n` that [`ddmin`] could find for which `fails`
+/// holds, taking it to hold of the whole set: removing any one element of
+/// the result makes it false. Each subset is passed sorted and tried at most
+/// once.
+pub fn ddmin<F>(n: usize, mut fails: F) -> Result<Vec<usize>>
+where
+    F: FnMut(&[usize]) -> Result<bool>,
+{
+    let mut tried: HashMap<Vec<usize>, bool> = HashMap::new();
+    let mut test = |subset: &[usize]| -> Result<bool> {
+        if let Some(&result) = tried.get(subset) {
+            return Ok(result);
+        }
+        let result = fails(subset)?;
+        tried.insert(subset.to_vec(), result);
+        Ok(result)
+    };
+    let mut current: Vec<usize> = (0..n).collect();
+    let mut granularity = 2;
+    while current.len() >= 2 {
+        let chunk = current.len().div_ceil(granularity);
+        let chunks: Vec<Vec<usize>> = current.chunks(chunk).map(<[usize]>::to_vec).collect();
+        if let Some(failing) = first_failing(&chunks, &mut test)? {
+            current = failing;
+            granularity = 2;
+            continue;
+        }
+        let complements: Vec<Vec<usize>> = chunks
+            .iter()
+            .map(|c| current.iter().copied().filter(|i| !c.contains(i)).collect())
+            .collect();
+        // With two chunks, each complement is the other chunk, already tried.
+        if chunks.len() > 2
+            && let Some(failing) = first_failing(&complements, &mut test)?
+        {
+            current = failing;
+            granularity = (granularity - 1).max(2);
+            continue;
+        }
+        if chunks.len() >= current.len() {
+            break;
+        }
+        granularity = (granularity * 2).min(current.len());
+    }
+    // A lone element is only minimal if the empty set passes.
+    if current.len() == 1 && test(&[])? {
+        current.clear();
+    }
+    Ok(current)
+}
+
+fn first_failing<F>(subsets: &[Vec<usize>], test: &mut F) -> Result<Option<Vec<usize>>>
+where
+    F: FnMut(&[usize]) -> Result<bool>,
+{
+    for subset in subsets {
+        if test(subset)? {
+            return Ok(Some(subset.clone()));
+        }
+    }
+    Ok(None)
+}
+
+/// What a bisection found: a minimal set of files under which the item
+/// fails, and what each of them does there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bisection {
+    /// Indices of the minimal failing set.
+    pub minimal: Vec<usize>,
+    /// The files of `minimal` without which the item passes.
+    pub culprits: Vec<usize>,
+    /// The files of `minimal` without which the gold analysis is lost: the
+    /// ones the item needs, that the culprits outrank.
+    pub supporting: Vec<usize>,
+}
+
+/// Search subsets of `files` for a minimal one under which `prepare`d unions
+/// fail `(form, gold)`, printing the outcome and best analysis of each subset
+/// tried. The union of all of them must fail.
+pub fn bisect<P>(
+    symt: Arc<SymbolTable>,
+    files: &[RuleFile],
+    weight_offsets: &HashMap<String, f32>,
+    fallback: FallbackBoundary,
+    prepare: P,
+    form: &str,
+    gold: &str,
+) -> Result<Bisection>
+where
+    P: Fn(SurfaceToAnalysisFst) -> Result<PreparedFst>,
+{
+    let mut outcomes: HashMap<Vec<usize>, Outcome> = HashMap::new();
+    let mut outcome = |subset: &[usize]| -> Result<Outcome> {
+        if let Some(&outcome) = outcomes.get(subset) {
+            return Ok(outcome);
+        }
+        let mut union = RuleUnion::new(symt.clone(), fallback)?;
+        for &i in subset {
+            let offset = weight_offset(weight_offsets, &files[i].path, &files[i].config);
+            union.add(files[i].fst.clone(), files[i].num_rules, offset)?;
+        }
+        let prepared = prepare(SurfaceToAnalysisFst(union.finish()?))?;
+        let best = best_analysis(&prepared, form)?;
+        let outcome = match &best {
+            Some((_, analysis)) if counts_as(&prepared, analysis, gold)? => Outcome::Pass,
+            _ if accepts_pair(&prepared, form, gold)? => Outcome::Fail,
+            _ => Outcome::Unresolved,
+        };
+        let names: Vec<String> = subset.iter().map(|&i| file_name(&files[i].path)).collect();
+        let best = match best {
+            Some((weight, analysis)) => format!("{} ({})", analysis, weight.value()),
+            None => "no analysis".to_string(),
+        };
+        println!("{:<10} {}: [{}]", outcome, best, names.join(", "));
+        outcomes.insert(subset.to_vec(), outcome);
+        Ok(outcome)
+    };
+
+    let all: Vec<usize> = (0..files.len()).collect();
+    match outcome(&all)? {
+        Outcome::Fail => {}
+        Outcome::Pass => bail!("'{}' already gets the analysis '{}' from all the rule files; there is nothing to bisect", form, gold),
+        Outcome::Unresolved => bail!("No rule file maps '{}' to '{}'; bisecting needs an item the files outrank, not one they never produce", form, gold),
+    }
+    let minimal = ddmin(files.len(), |subset| Ok(outcome(subset)? == Outcome::Fail))?;
+    let mut culprits = Vec::new();
+    let mut supporting = Vec::new();
+    for &i in &minimal {
+        let without: Vec<usize> = minimal.iter().copied().filter(|&j| j != i).collect();
+        match outcome(&without)? {
+            Outcome::Pass => culprits.push(i),
+            Outcome::Unresolved => supporting.push(i),
+            Outcome::Fail => {}
+        }
+    }
+    Ok(Bisection { minimal, culprits, supporting })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::AnalysisFormat;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_ddmin_finds_a_minimal_failing_subset() {
+        // Fails whenever both 2 and 5 are in.
+        let mut tries = 0;
+        let minimal = ddmin(8, |s| {
+            tries += 1;
+            Ok(s.contains(&2) && s.contains(&5))
+        })
+        .unwrap();
+        assert_eq!(minimal, [2, 5]);
+        assert!(tries < 2usize.pow(8));
+        // Fails on its own.
+        assert_eq!(ddmin(5, |s| Ok(s.contains(&3))).unwrap(), [3]);
+        // Fails even with nothing in, so no element is to blame.
+        assert!(ddmin(3, |_| Ok(true)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_bisect_isolates_conflicting_file() {
+        let dir = TempDir::new("bisect");
+        let rules = [
+            ("a_to_e.txt", "a -> e / _ :: 1\n"),
+            ("c_to_d.txt", "c -> d / _ \n"),
+            // Cheaper than the gold rewrite, and so outranks it.
+            ("a_to_o.txt", "a -> o / _ \n"),
+            ("d_to_c.txt", "d -> c / _ \n"),
+        ];
+        let symt = Arc::new(rustfst::symt!["#", "a", "c", "d", "e", "o"]);
+        let files: Vec<RuleFile> = rules
+            .iter()
+            .map(|(name, contents)| {
+                let path = dir.join(name);
+                std::fs::write(&path, contents).unwrap();
+                RuleFile::load(symt.clone(), &path, None, &mut RuleChecks::default()).unwrap()
+            })
+            .collect();
+        let prepare = |fst| PreparedFst::new(fst, None, AnalysisFormat::default());
+        let fallback = FallbackBoundary::default();
+        let offsets = HashMap::new();
+        let bisection = bisect(symt.clone(), &files, &offsets, fallback, prepare, "a", "e").unwrap();
+        assert_eq!(bisection.culprits, [2]);
+        assert_eq!(bisection.supporting, [0]);
+        assert_eq!(bisection.minimal, [0, 2]);
+
+        // Without the conflicting file, the item passes and there is nothing
+        // to bisect.
+        let err = bisect(symt, &files[..2], &offsets, fallback, prepare, "a", "e").unwrap_err();
+        assert!(err.to_string().contains("nothing to bisect"), "{}", err);
+    }
+}
@@ -0,0 +1,295 @@
+//! One policy for the word boundary symbol (`#`).
+//!
+//! Inputs are wrapped in boundaries before the FST is applied (see
+//! [`AnalysisFormat::wrap`](crate::analysis::AnalysisFormat::wrap)), and a
+//! boundary in a rule context (`RegexAST::Boundary`) is meant to match only
+//! those two edges. Rules also write `#` themselves, since the analysis
+//! separator is made of boundaries, and left alone such a `#` matches
+//! `Boundary` in every later rule of the file. So while a script is compiled,
+//! boundaries written by rule targets are spelled with [`INTERNAL_BOUNDARY`],
+//! an extra symbol no context can name, and only turned back into `#` once the
+//! whole file is compiled.
+//!
+//! The identity fallback of a build likewise copies `#` at the edges only
+//! ([`FallbackBoundary`]), and [`check_edge_boundaries`] rejects a built FST
+//! that can still emit a `#` outside the edges and the analysis separators.
+//!
+//! The morph boundary (`-`, written by rules as `::mb::`) is not a word
+//! boundary: it is an ordinary symbol inside a word, which `Boundary` never
+//! matches, the fallback copies anywhere, and the check lets through.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use parserule::rulefst::weighted_sigma_star;
+use parserule::ruleparse::{RegexAST, RewriteRule, Statement};
+use rustfst::prelude::compose::compose;
+use rustfst::prelude::determinize::determinize;
+use rustfst::prelude::{
+    connect, shortest_path, tr_sort, CoreFst, ExpandedFst, Fst, ILabelCompare, MutableFst, OLabelCompare,
+    StateIterator, TropicalWeight, VectorFst,
+};
+use rustfst::{Label, Semiring, SymbolTable, Tr};
+
+use crate::analysis::AnalysisFormat;
+use crate::attribution::SourceMarkers;
+use crate::decode::display_labels;
+use crate::rewrite::resolve_macros;
+use crate::status::internal;
+
+/// Stand-in for a `#` written by a rule target while its file is compiled; a
+/// private-use character, so it cannot clash with `chars.txt`.
+pub const INTERNAL_BOUNDARY: &str = "\u{E000}";
+
+/// Which positions the identity fallback of a build copies `#` at.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FallbackBoundary {
+    /// Only at the word edges, so the fallback accepts wrapped words only.
+    #[default]
+    Edges,
+    /// Anywhere, as an ordinary symbol (the behaviour of older builds).
+    Anywhere,
+}
+
+fn boundary_label(symt: &SymbolTable, boundary: &str) -> Result<Label> {
+    symt.get_label(boundary).ok_or_else(|| anyhow!("Symbol table has no word boundary '{}'", boundary))
+}
+
+/// The labels of `symt` other than epsilon and the boundary.
+fn word_labels(symt: &SymbolTable, boundary: Label) -> Vec<Label> {
+    symt.iter().map(|(l, _)| l).filter(|&l| l != 0 && l != boundary).collect()
+}
+
+/// The identity fallback: copies its input, with `weight` on every symbol.
+pub fn identity_fallback(symt: Arc<SymbolTable>, weight: f32, mode: FallbackBoundary) -> Result<VectorFst<TropicalWeight>> {
+    if mode == FallbackBoundary::Anywhere {
+        return weighted_sigma_star(symt, weight);
+    }
+    let boundary = boundary_label(&symt, "#")?;
+    let mut fst = VectorFst::<TropicalWeight>::new();
+    let start = fst.add_state();
+    let word = fst.add_state();
+    let end = fst.add_state();
+    fst.set_start(start)?;
+    fst.set_final(end, TropicalWeight::one())?;
+    fst.add_tr(start, Tr::new(boundary, boundary, weight, word))?;
+    for l in word_labels(&symt, boundary) {
+        fst.add_tr(word, Tr::new(l, l, weight, word))?;
+    }
+    fst.add_tr(word, Tr::new(boundary, boundary, weight, end))?;
+    fst.set_input_symbols(symt.clone());
+    fst.set_output_symbols(symt);
+    Ok(fst)
+}
+
+/// `symt` with [`INTERNAL_BOUNDARY`] added, to compile scripts prepared by
+/// [`mark_written_boundaries`] against.
+pub fn with_internal_boundary(symt: &SymbolTable) -> Arc<SymbolTable> {
+    let mut symt = symt.clone();
+    symt.add_symbol(INTERNAL_BOUNDARY);
+    Arc::new(symt)
+}
+
+/// `script` with every boundary its rule targets write, including those in
+/// macros, spelled as [`INTERNAL_BOUNDARY`].
+pub fn mark_written_boundaries(script: Vec<Statement>) -> Result<Vec<Statement>> {
+    let macros: HashMap<String, RegexAST> = resolve_macros(&script)?.into_iter().collect();
+    Ok(script
+        .into_iter()
+        .map(|statement| match statement {
+            Statement::Rule(rule) => Statement::Rule(RewriteRule { target: mark_node(&macros, rule.target), ..rule }),
+            other => other,
+        })
+        .collect())
+}
+
+fn mark_node(macros: &HashMap<String, RegexAST>, node: RegexAST) -> RegexAST {
+    let mark_all = |nodes: Vec<RegexAST>| nodes.into_iter().map(|n| mark_node(macros, n)).collect();
+    match node {
+        RegexAST::Char(c) if c == "#" => RegexAST::Char(INTERNAL_BOUNDARY.to_string()),
+        RegexAST::Boundary => RegexAST::Char(INTERNAL_BOUNDARY.to_string()),
+        RegexAST::Macro(mac) => match macros.get(&mac) {
+            Some(def) => mark_node(macros, def.clone()),
+            None => RegexAST::Macro(mac),
+        },
+        RegexAST::Group(nodes) => RegexAST::Group(mark_all(nodes)),
+        RegexAST::Disjunction(nodes) => RegexAST::Disjunction(mark_all(nodes)),
+        RegexAST::Process(stages) => RegexAST::Process(mark_all(stages)),
+        RegexAST::Option(n) => RegexAST::Option(Box::new(mark_node(macros, *n))),
+        RegexAST::Star(n) => RegexAST::Star(Box::new(mark_node(macros, *n))),
+        RegexAST::Plus(n) => RegexAST::Plus(Box::new(mark_node(macros, *n))),
+        other => other,
+    }
+}
+
+/// Undo [`with_internal_boundary`] on an FST compiled from a marked script:
+/// written boundaries become `#` again, and paths reading one (which no input
+/// can) are dropped.
+pub fn restore_boundaries(fst: &mut VectorFst<TropicalWeight>, symt: Arc<SymbolTable>) -> Result<()> {
+    let internal = with_internal_boundary(&symt).get_label(INTERNAL_BOUNDARY).unwrap();
+    let boundary = boundary_label(&symt, "#")?;
+    let states: Vec<_> = fst.states_iter().collect();
+    for s in states {
+        for mut tr in fst.pop_trs(s)? {
+            if tr.ilabel == internal {
+                continue;
+            }
+            if tr.olabel == internal {
+                tr.olabel = boundary;
+            }
+            fst.add_tr(s, tr)?;
+        }
+    }
+    fst.set_input_symbols(symt.clone());
+    fst.set_output_symbols(symt);
+    Ok(())
+}
+
+/// A deterministic acceptor of every string over `symt` that is not a wrapped
+/// analysis in `fmt`: `#`, words separated by the separator, `#`.
+fn malformed_analyses(symt: &SymbolTable, fmt: &AnalysisFormat) -> Result<VectorFst<TropicalWeight>> {
+    let boundary = boundary_label(symt, &fmt.boundary)?;
+    let separator: Vec<Label> =
+        fmt.separator.chars().map(|c| boundary_label(symt, &c.to_string())).collect::<Result<_>>()?;
+    let mut nfa = VectorFst::<TropicalWeight>::new();
+    let start = nfa.add_state();
+    let word = nfa.add_state();
+    let end = nfa.add_state();
+    nfa.set_start(start)?;
+    nfa.set_final(end, TropicalWeight::one())?;
+    nfa.add_tr(start, Tr::new(boundary, boundary, TropicalWeight::one(), word))?;
+    for l in word_labels(symt, boundary) {
+        nfa.add_tr(word, Tr::new(l, l, TropicalWeight::one(), word))?;
+    }
+    nfa.add_tr(word, Tr::new(boundary, boundary, TropicalWeight::one(), end))?;
+    let mut from = word;
+    for (i, &l) in separator.iter().enumerate() {
+        let to = if i + 1 == separator.len() { word } else { nfa.add_state() };
+        nfa.add_tr(from, Tr::new(l, l, TropicalWeight::one(), to))?;
+        from = to;
+    }
+
+    // Complete the DFA of well-formed analyses with a sink, then complement it.
+    let mut dfa: VectorFst<TropicalWeight> = internal(determinize(&nfa))?;
+    let alphabet: Vec<Label> = symt.iter().map(|(l, _)| l).filter(|&l| l != 0).collect();
+    let sink = dfa.add_state();
+    for s in 0..dfa.num_states() as u32 {
+        let seen: BTreeSet<Label> = dfa.get_trs(s)?.iter().map(|tr| tr.ilabel).collect();
+        for &l in alphabet.iter().filter(|l| !seen.contains(l)) {
+            dfa.add_tr(s, Tr::new(l, l, TropicalWeight::one(), sink))?;
+        }
+        if dfa.is_final(s)? {
+            dfa.delete_final_weight(s)?;
+        } else {
+            dfa.set_final(s, TropicalWeight::one())?;
+        }
+    }
+    Ok(dfa)
+}
+
+/// Fail if `fst` can map a wrapped word to an output with a `#` anywhere but
+/// at its edges and in analysis separators, giving the best such example.
+/// Source markers are ignored.
+pub fn check_edge_boundaries(
+    fst: &VectorFst<TropicalWeight>,
+    markers: Option<&SourceMarkers>,
+    symt: Arc<SymbolTable>,
+    fmt: &AnalysisFormat,
+) -> Result<()> {
+    let mut fst = fst.clone();
+    if let Some(markers) = markers {
+        markers.strip(&mut fst)?;
+    }
+    let mut words = identity_fallback(symt.clone(), 0.0, FallbackBoundary::Edges)?;
+    tr_sort(&mut words, OLabelCompare {});
+    tr_sort(&mut fst, ILabelCompare {});
+    let mut lattice: VectorFst<TropicalWeight> = internal(compose(words, fst))?;
+    tr_sort(&mut lattice, OLabelCompare {});
+    let mut bad: VectorFst<TropicalWeight> = internal(compose(lattice, malformed_analyses(&symt, fmt)?))?;
+    internal(connect(&mut bad))?;
+    if bad.start().is_none() {
+        return Ok(());
+    }
+    let example: VectorFst<TropicalWeight> = internal(shortest_path(&bad))?;
+    let path = example.paths_iter().next().ok_or_else(|| anyhow!("No path through a non-empty FST"))?;
+    bail!(
+        "The FST emits '{}' in a non-edge position, e.g. {} -> {} (use --fallback-boundary or fix the rule writing it)",
+        fmt.boundary,
+        display_labels(&symt, &path.ilabels),
+        display_labels(&symt, &path.olabels)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    use parserule::rulefst;
+    use parserule::ruleparse::parse_script;
+    use rustfst::utils::transducer;
+
+    use crate::rules::{compile_rule_script, RuleChecks};
+
+    fn symt() -> Arc<SymbolTable> {
+        Arc::new(rustfst::symt!["#", "a", "b", "c", "-"])
+    }
+
+    fn outputs(fst: VectorFst<TropicalWeight>, input: &str) -> HashSet<String> {
+        let mut fst = fst;
+        tr_sort(&mut fst, ILabelCompare {});
+        let e2e = rulefst::apply_fst_to_string(symt(), fst, input.to_string()).unwrap();
+        rulefst::decode_paths_through_fst(symt(), e2e).into_iter().map(|(_, o)| o).collect()
+    }
+
+    /// Inserts a separator between `a` and `b`, then rewrites a word-initial `b`.
+    fn script() -> Vec<Statement> {
+        parse_script("0 -> ## / a _ b\nb -> c / # _ \n").unwrap().1 .0
+    }
+
+    fn compile(script: Vec<Statement>) -> VectorFst<TropicalWeight> {
+        compile_rule_script(symt(), script.into(), "script", &mut RuleChecks::default()).unwrap()
+    }
+
+    #[test]
+    fn test_written_boundary_does_not_match_later_context() {
+        let unmarked = outputs(rulefst::compile_script(symt(), script()).unwrap(), "#ab#");
+        assert!(unmarked.contains("#a##c#"), "{:?}", unmarked);
+        let marked = outputs(compile(script()), "#ab#");
+        assert!(marked.contains("#a##b#") && !marked.contains("#a##c#"), "{:?}", marked);
+        let fst = compile(script());
+        assert!(check_edge_boundaries(&fst, None, symt(), &AnalysisFormat::default()).is_ok());
+    }
+
+    #[test]
+    fn test_morph_boundary_is_not_a_word_boundary() {
+        let script = parse_script("0 -> ::mb:: / a _ b\nb -> c / # _ \n").unwrap().1 .0;
+        let written = outputs(compile(script.clone()), "#ab#");
+        assert_eq!(written, HashSet::from(["#ab#".to_string(), "#a-b#".to_string()]));
+        let fst = compile(script);
+        assert!(check_edge_boundaries(&fst, None, symt(), &AnalysisFormat::default()).is_ok());
+        let fallback = identity_fallback(symt(), 10.0, FallbackBoundary::Edges).unwrap();
+        assert_eq!(outputs(fallback, "#a-b#"), HashSet::from(["#a-b#".to_string()]));
+    }
+
+    #[test]
+    fn test_check_rejects_single_internal_boundary() {
+        let fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 3, 1 => 1, 2, 1, 3, 1];
+        let err = check_edge_boundaries(&fst, None, symt(), &AnalysisFormat::default()).unwrap_err().to_string();
+        assert!(err.contains("#ab# -> #a#b#"), "{}", err);
+        let fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 3, 1 => 1, 2, 1, 1, 3, 1];
+        assert!(check_edge_boundaries(&fst, None, symt(), &AnalysisFormat::default()).is_ok());
+        let err = check_edge_boundaries(&fst, None, symt(), &AnalysisFormat::new("c")).unwrap_err().to_string();
+        assert!(err.contains("#a##b#"), "{}", err);
+    }
+
+    #[test]
+    fn test_identity_fallback_copies_boundaries_at_edges_only() {
+        let edges = identity_fallback(symt(), 10.0, FallbackBoundary::Edges).unwrap();
+        assert_eq!(outputs(edges.clone(), "#ab#"), HashSet::from(["#ab#".to_string()]));
+        assert!(outputs(edges, "#a#b#").is_empty());
+        let anywhere = identity_fallback(symt(), 10.0, FallbackBoundary::Anywhere).unwrap();
+        assert_eq!(outputs(anywhere, "#a#b#"), HashSet::from(["#a#b#".to_string()]));
+    }
+}
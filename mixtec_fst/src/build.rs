@@ -0,0 +1,740 @@
+//! Building the segmentation FST from a set of rule files.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use itertools::enumerate;
+use rustfst::prelude::concat::concat;
+use rustfst::prelude::rm_epsilon::rm_epsilon;
+use rustfst::prelude::union::union;
+use rustfst::algorithms::{state_sort, tr_sum};
+use rustfst::fst_properties::FstProperties;
+use rustfst::prelude::{
+    connect, tr_sort, CoreFst, ExpandedFst, ILabelCompare, StateIterator, TrCompare, TropicalWeight, VectorFst,
+};
+use rustfst::utils::transducer;
+use rustfst::{Label, Semiring, StateId, SymbolTable, Tr, EPS_LABEL};
+
+use crate::artifact::write_file_atomic;
+use crate::attribution::SourceMarkers;
+use crate::boundary::{identity_fallback, FallbackBoundary};
+use crate::cancel::CancelToken;
+use crate::dump::{guard_in_place, Operation};
+use crate::memory::MemoryMeter;
+use crate::optimize::LadderReport;
+use crate::producible::ProducibleLabels;
+use crate::profile::profile_span;
+use crate::provenance::{info_path, Provenance};
+use crate::relabel::Relabeling;
+use crate::rule_config::RuleFileConfig;
+use crate::rules::{compile_rule_script, load_script, RuleChecks, RuleEffect, Script};
+use crate::simultaneous::{compile_simultaneous, RuleApplication};
+use crate::status::internal;
+use crate::style::warn;
+
+/// The rule files built when no source directory is given, relative to the
+/// working directory.
+pub const DEFAULT_RULE_FILES: [&str; 3] = ["rules/from_14.txt", "rules/from_4.txt", "rules/special.txt"];
+
+/// Weight per symbol of the identity fallback seed, and of each step used to reweight files with
+/// different numbers of rules against each other.
+pub const REWEIGHT_STEP: f32 = 10.0;
+
+/// [`DEFAULT_RULE_FILES`], as paths.
+pub fn default_rule_files() -> Vec<PathBuf> {
+    DEFAULT_RULE_FILES.iter().map(PathBuf::from).collect()
+}
+
+/// Parse a `FILE=WEIGHT` weight offset, as given on the command line.
+pub fn parse_weight_offset(s: &str) -> Result<(String, f32)> {
+    let (file, weight) = s
+        .rsplit_once('=')
+        .ok_or_else(|| anyhow!("Expected FILE=WEIGHT, got '{}'", s))?;
+    let weight = weight
+        .trim()
+        .parse::<f32>()
+        .map_err(|e| anyhow!("Invalid weight in '{}': {}", s, e))?;
+    Ok((file.trim().to_string(), weight))
+}
+
+/// Union `other` into `fst`, adding `offset` to the weight of every path
+/// through `other`. A negative offset makes `other` outrank paths of `fst`.
+pub fn weighted_union(
+    fst: &mut VectorFst<TropicalWeight>,
+    other: &VectorFst<TropicalWeight>,
+    offset: f32,
+) -> Result<()> {
+    if offset == 0.0 {
+        return internal(union(fst, other));
+    }
+    let mut weighted: VectorFst<TropicalWeight> = rustfst::fst![0 => 0; offset];
+    internal(concat(&mut weighted, other))?;
+    internal(union(fst, &weighted))
+}
+
+/// Union of the FSTs compiled from `files`, seeded with a weighted identity
+/// fallback that copies `#` as `fallback` says. The rules of each file combine
+/// as `application` says.
+///
+/// A file with fewer rules than the largest seen so far is padded with weighted
+/// epsilons, and the union so far is padded when a file has more, so that paths
+/// through different files are ranked by rule count. A file whose name is in
+/// `weight_offsets`, or whose `.toml` sets one, is then unioned with that
+/// offset (see [`weighted_union`] and [`weight_offset`]).
+/// With `markers`, each file's paths also emit that file's source marker. With
+/// `memory`, the peak memory of each compile and union is recorded. Empty and
+/// identity-only rules are recorded in `checks`. Ctrl-C cancels the build
+/// between files (see [`crate::cancel`]).
+#[allow(clippy::too_many_arguments)]
+pub fn build_from_rule_files(
+    symt: Arc<SymbolTable>,
+    files: &[PathBuf],
+    weight_offsets: &HashMap<String, f32>,
+    fallback: FallbackBoundary,
+    application: RuleApplication,
+    markers: Option<&SourceMarkers>,
+    memory: Option<&MemoryMeter>,
+    checks: &mut RuleChecks,
+) -> Result<VectorFst<TropicalWeight>> {
+    let scripts = files.iter().map(|f| Ok((f.clone(), load_script(f)?))).collect::<Result<Vec<_>>>()?;
+    build_from_scripts(symt, scripts, weight_offsets, fallback, application, markers, memory, checks)
+}
+
+/// [`build_from_rule_files`], from scripts already loaded from the paths
+/// they are paired with.
+#[allow(clippy::too_many_arguments)]
+pub fn build_from_scripts(
+    symt: Arc<SymbolTable>,
+    scripts: Vec<(PathBuf, Script)>,
+    weight_offsets: &HashMap<String, f32>,
+    fallback: FallbackBoundary,
+    application: RuleApplication,
+    markers: Option<&SourceMarkers>,
+    memory: Option<&MemoryMeter>,
+    checks: &mut RuleChecks,
+) -> Result<VectorFst<TropicalWeight>> {
+    let fst = union_scripts(symt, scripts, weight_offsets, fallback, application, markers, memory, checks)?.finish()?;
+    if let Some(memory) = memory {
+        memory.stage("rm_epsilon");
+    }
+    Ok(fst)
+}
+
+/// [`build_from_scripts`] up to the union, before its epsilons are removed.
+#[allow(clippy::too_many_arguments)]
+pub fn union_scripts(
+    symt: Arc<SymbolTable>,
+    scripts: Vec<(PathBuf, Script)>,
+    weight_offsets: &HashMap<String, f32>,
+    fallback: FallbackBoundary,
+    application: RuleApplication,
+    markers: Option<&SourceMarkers>,
+    memory: Option<&MemoryMeter>,
+    checks: &mut RuleChecks,
+) -> Result<RuleUnion> {
+    for name in weight_offsets.keys() {
+        if !scripts.iter().any(|(f, _)| file_name(f) == *name) {
+            bail!("Weight offset given for '{}', which is not among the rule files", name);
+        }
+    }
+    let mut fst = RuleUnion::new(symt.clone(), fallback)?;
+    let cancel = CancelToken::interrupt();
+    for (i, (filepath, script)) in enumerate(scripts) {
+        cancel.check()?;
+        println!("\nProcessing file: {}", filepath.display());
+        for (j, rule) in enumerate(script.statements.iter()) {
+            println!("Rule {}: {:?}", j + 1, rule);
+        }
+        let file = filepath.display().to_string();
+        let _span = profile_span!("rule_file", file = %file);
+        let offset = weight_offset(weight_offsets, &filepath, &script.config);
+        let kept = checks.kept;
+        let mut fst_oth = {
+            let _span = profile_span!("compile");
+            match application {
+                RuleApplication::Sequential => compile_rule_script(symt.clone(), script, &file, checks)?,
+                RuleApplication::Simultaneous => compile_simultaneous(symt.clone(), script, &file, checks)?,
+            }
+        };
+        if let Some(memory) = memory {
+            memory.stage(&format!("compile {}", filepath.display()));
+        }
+        // Only the rules the checks kept were compiled into the FST.
+        let num_rules = checks.kept - kept;
+        // Padding or unioning an FST with no final state would only leave
+        // dead paths in the union.
+        if !RuleUnion::contributes(&fst_oth, num_rules) {
+            warn(format!("Warning: {} contributes no rules; skipping it", filepath.display()));
+            continue;
+        }
+        if let Some(markers) = markers {
+            markers.mark(&mut fst_oth, i)?;
+        }
+        if num_rules > fst.num_compose {
+            println!("Reweighting...");
+        }
+        println!("Unioning...");
+        let _union_span = profile_span!("union");
+        fst.add(fst_oth, num_rules, offset)?;
+        if let Some(memory) = memory {
+            memory.stage(&format!("union {}", filepath.display()));
+        }
+    }
+    Ok(fst)
+}
+
+/// The union of rule files that [`build_from_scripts`] builds, one compiled
+/// file at a time, over the weighted identity fallback.
+pub struct RuleUnion {
+    fst: VectorFst<TropicalWeight>,
+    /// The most rules of any file added so far (at least 1).
+    num_compose: usize,
+}
+
+impl RuleUnion {
+    pub fn new(symt: Arc<SymbolTable>, fallback: FallbackBoundary) -> Result<Self> {
+        Ok(RuleUnion { fst: identity_fallback(symt, REWEIGHT_STEP, fallback)?, num_compose: 1 })
+    }
+
+    /// Whether the FST compiled from a file of `num_rules` rules adds any
+    /// path to the union: a file of no rules (empty, only comments and
+    /// definitions, or rules that all compiled to nothing) does not, nor does
+    /// one whose FST has no final state.
+    pub fn contributes(fst: &VectorFst<TropicalWeight>, num_rules: usize) -> bool {
+        num_rules > 0 && fst.states_iter().any(|s| fst.is_final(s).unwrap_or(false))
+    }
+
+    /// Add the FST compiled from a file of `num_rules` rules, padding it or
+    /// the union so far so that both are ranked by rule count, with `offset`
+    /// added to its paths (see [`weighted_union`]). A file that does not
+    /// [contribute](RuleUnion::contributes) is left out, and changes neither
+    /// the union nor its padding.
+    pub fn add(&mut self, mut fst: VectorFst<TropicalWeight>, num_rules: usize, offset: f32) -> Result<()> {
+        if !Self::contributes(&fst, num_rules) {
+            return Ok(());
+        }
+        if num_rules > self.num_compose {
+            while self.num_compose < num_rules {
+                concat::<TropicalWeight, VectorFst<_>, VectorFst<_>>(&mut self.fst, &rustfst::fst![0 => 0; REWEIGHT_STEP])?;
+                self.num_compose += 1;
+            }
+        } else {
+            for _ in 0..self.num_compose - num_rules {
+                concat::<TropicalWeight, VectorFst<_>, VectorFst<_>>(&mut fst, &rustfst::fst![0 => 0; REWEIGHT_STEP])?;
+            }
+        }
+        weighted_union(&mut self.fst, &fst, offset)
+    }
+
+    /// The union, with the epsilons of the unions and padding removed.
+    pub fn finish(mut self) -> Result<VectorFst<TropicalWeight>> {
+        let _span = profile_span!("rm_epsilon");
+        guard_in_place(Operation::RmEpsilon, &mut self.fst, rm_epsilon)?;
+        Ok(self.fst)
+    }
+
+    /// The union with its epsilons, for `--optimize auto` to remove or not
+    /// (see [`crate::optimize`]).
+    pub fn into_fst(self) -> VectorFst<TropicalWeight> {
+        self.fst
+    }
+}
+
+/// The weight offset of the rule file at `path`: the one `--weight-offset`
+/// gives it in `weight_offsets`, or else the one its `.toml` sets.
+pub fn weight_offset(weight_offsets: &HashMap<String, f32>, path: &Path, config: &RuleFileConfig) -> f32 {
+    weight_offsets.get(&file_name(path)).copied().or(config.weight_offset).unwrap_or(0.0)
+}
+
+/// The name of a rule file, as `--weight-offset` gives it.
+pub fn file_name(path: &Path) -> String {
+    path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// Number of states and transitions of an FST.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FstSize {
+    pub num_states: usize,
+    pub num_trs: usize,
+}
+
+impl FstSize {
+    pub fn of(fst: &VectorFst<TropicalWeight>) -> Self {
+        FstSize {
+            num_states: fst.num_states(),
+            num_trs: fst.states_iter().map(|s| fst.num_trs(s).unwrap_or(0)).sum(),
+        }
+    }
+}
+
+/// How many transitions read and write each label, as `(inputs, outputs)`.
+pub fn label_arc_counts(fst: &VectorFst<TropicalWeight>) -> Result<BTreeMap<Label, (usize, usize)>> {
+    let mut counts: BTreeMap<Label, (usize, usize)> = BTreeMap::new();
+    for s in fst.states_iter() {
+        for tr in fst.get_trs(s)?.iter() {
+            counts.entry(tr.ilabel).or_default().0 += 1;
+            counts.entry(tr.olabel).or_default().1 += 1;
+        }
+    }
+    Ok(counts)
+}
+
+/// How many transitions read and write one symbol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolUse {
+    pub label: Label,
+    pub symbol: String,
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+/// [`label_arc_counts`] by symbol name, sorted by name. Labels `symt` has no
+/// symbol for (such as source markers) are named `<label>`.
+pub fn symbol_use(fst: &VectorFst<TropicalWeight>, symt: Option<&SymbolTable>) -> Result<Vec<SymbolUse>> {
+    let mut uses: Vec<SymbolUse> = label_arc_counts(fst)?
+        .into_iter()
+        .map(|(label, (inputs, outputs))| {
+            let symbol = symt.and_then(|t| t.get_symbol(label)).map(String::from).unwrap_or_else(|| format!("<{}>", label));
+            SymbolUse { label, symbol, inputs, outputs }
+        })
+        .collect();
+    uses.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+    Ok(uses)
+}
+
+/// Remove states that are unreachable from the start or cannot reach a final
+/// state, returning the sizes before and after.
+pub fn connect_with_sizes(fst: &mut VectorFst<TropicalWeight>) -> Result<(FstSize, FstSize)> {
+    let before = FstSize::of(fst);
+    connect(fst)?;
+    Ok((before, FstSize::of(fst)))
+}
+
+/// Merge the parallel transitions of `fst` (same source, target and labels,
+/// as the identity backbones of unioned rule files leave) into one with the
+/// best of their weights, returning the sizes before and after. Under the
+/// tropical semiring the weight of every input-output pair is unchanged; only
+/// the number of paths `--k-paths` counts can drop.
+pub fn dedup_arcs_with_sizes(fst: &mut VectorFst<TropicalWeight>) -> (FstSize, FstSize) {
+    let before = FstSize::of(fst);
+    tr_sum(fst);
+    (before, FstSize::of(fst))
+}
+
+/// The default longest path `build --count-final-paths` counts, in arcs.
+pub const DEFAULT_FINAL_PATHS_LENGTH: usize = 10;
+
+/// The number of accepting paths of `fst` of at most `max_len` transitions,
+/// counted a layer of transitions at a time rather than enumerated, and
+/// saturating at `u128::MAX`. As a path is counted once per way of reading
+/// its input, it grows with the ambiguity of the rules as well as with the
+/// number of inputs they accept.
+pub fn count_accepting_paths(fst: &VectorFst<TropicalWeight>, max_len: usize) -> Result<u128> {
+    let Some(start) = fst.start() else {
+        return Ok(0);
+    };
+    let finals: Vec<bool> = (0..fst.num_states() as StateId).map(|s| fst.is_final(s)).collect::<Result<_>>()?;
+    // The number of paths of the current length from the start to each state.
+    let mut layer = vec![0u128; fst.num_states()];
+    layer[start as usize] = 1;
+    let mut total: u128 = 0;
+    for len in 0..=max_len {
+        for (s, &count) in layer.iter().enumerate() {
+            if finals[s] {
+                total = total.saturating_add(count);
+            }
+        }
+        if len == max_len {
+            break;
+        }
+        let mut next = vec![0u128; fst.num_states()];
+        for (s, &count) in layer.iter().enumerate().filter(|&(_, &count)| count > 0) {
+            for tr in fst.get_trs(s as StateId)?.iter() {
+                let slot = &mut next[tr.nextstate as usize];
+                *slot = slot.saturating_add(count);
+            }
+        }
+        if next.iter().all(|&count| count == 0) {
+            break;
+        }
+        layer = next;
+    }
+    Ok(total)
+}
+
+/// Fail if `fst` has any epsilon-input, epsilon-output transition, as
+/// `rm_epsilon` should have removed them all; some OpenFST consumers assume
+/// there are none.
+pub fn check_epsilon_free(fst: &VectorFst<TropicalWeight>) -> Result<()> {
+    let mut count = 0;
+    let mut first = None;
+    for s in fst.states_iter() {
+        for tr in fst.get_trs(s)?.iter().filter(|tr| tr.ilabel == EPS_LABEL && tr.olabel == EPS_LABEL) {
+            count += 1;
+            first.get_or_insert((s, tr.nextstate));
+        }
+    }
+    match first {
+        None => Ok(()),
+        Some((from, to)) => bail!("FST is not epsilon-free: {} epsilon transitions, the first from state {} to {}", count, from, to),
+    }
+}
+
+/// Orders transitions by input label, output label, weight and then target.
+struct CanonicalCompare;
+
+impl TrCompare for CanonicalCompare {
+    fn compare<W: Semiring>(a: &Tr<W>, b: &Tr<W>) -> Ordering {
+        (a.ilabel, a.olabel)
+            .cmp(&(b.ilabel, b.olabel))
+            .then_with(|| a.weight.partial_cmp(&b.weight).unwrap_or(Ordering::Equal))
+            .then(a.nextstate.cmp(&b.nextstate))
+    }
+
+    fn properties(inprops: FstProperties) -> FstProperties {
+        ILabelCompare::properties(inprops)
+    }
+}
+
+/// Put `fst` in a canonical order (`--canonical-order`), so that equal FSTs
+/// built in different runs serialize to the same bytes: transitions are sorted
+/// as [`CanonicalCompare`] does, and states are renumbered in breadth-first
+/// order from the start, following the sorted transitions. States unreachable
+/// from the start keep their relative order, after the others.
+pub fn canonical_order(fst: &mut VectorFst<TropicalWeight>) -> Result<()> {
+    tr_sort(fst, CanonicalCompare);
+    let mut order: Vec<Option<StateId>> = vec![None; fst.num_states()];
+    let mut next: StateId = 0;
+    let mut queue: VecDeque<StateId> = fst.start().into_iter().collect();
+    if let Some(start) = fst.start() {
+        order[start as usize] = Some(next);
+        next += 1;
+    }
+    while let Some(s) = queue.pop_front() {
+        for tr in fst.get_trs(s)?.iter() {
+            if order[tr.nextstate as usize].is_none() {
+                order[tr.nextstate as usize] = Some(next);
+                next += 1;
+                queue.push_back(tr.nextstate);
+            }
+        }
+    }
+    let order: Vec<StateId> = order
+        .into_iter()
+        .map(|o| {
+            o.unwrap_or_else(|| {
+                next += 1;
+                next - 1
+            })
+        })
+        .collect();
+    state_sort(fst, &order)?;
+    // Targets were renumbered, which can reorder transitions that differ only there.
+    tr_sort(fst, CanonicalCompare);
+    Ok(())
+}
+
+/// Write the build summary next to the FST at `outpath`, as `<outpath>.info`.
+/// The level `--optimize auto` reached is recorded if it ran (see
+/// [`LadderReport::sidecar_lines`]), what connect and the arc dedup removed
+/// if they ran, a
+/// frequency relabeling as `old:new` label pairs, and rules that
+/// compiled to empty or identity-only transducers as `file:rule` pairs, followed
+/// by the provenance of the build (see [`Provenance::sidecar_lines`]) and the
+/// labels the FST can output (see [`ProducibleLabels`]).
+#[allow(clippy::too_many_arguments)]
+pub fn write_build_info(
+    outpath: &Path,
+    size: FstSize,
+    producible: &ProducibleLabels,
+    connect_sizes: Option<(FstSize, FstSize)>,
+    dedup_sizes: Option<(FstSize, FstSize)>,
+    relabeling: Option<&Relabeling>,
+    ladder: Option<&LadderReport>,
+    checks: &RuleChecks,
+    provenance: &Provenance,
+) -> Result<()> {
+    let mut info = format!("num_states={}\nnum_trs={}\n", size.num_states, size.num_trs);
+    if let Some(ladder) = ladder {
+        info.push_str(&ladder.sidecar_lines());
+    }
+    info.push_str(&provenance.sidecar_lines());
+    info.push_str(&producible.sidecar_line());
+    for (effect, key) in [(RuleEffect::Empty, "empty_rules"), (RuleEffect::IdentityOnly, "identity_rules")] {
+        let rules: Vec<String> = checks.with_effect(effect).map(|n| format!("{}:{}", n.file, n.rule)).collect();
+        if !rules.is_empty() {
+            info.push_str(&format!("{}={}\n", key, rules.join(" ")));
+        }
+    }
+    if let Some((before, after)) = connect_sizes {
+        info.push_str(&format!(
+            "connect_removed_states={}\nconnect_removed_trs={}\n",
+            before.num_states - after.num_states,
+            before.num_trs - after.num_trs
+        ));
+    }
+    if let Some((before, after)) = dedup_sizes {
+        info.push_str(&format!("dedup_removed_trs={}\n", before.num_trs - after.num_trs));
+    }
+    if let Some(relabeling) = relabeling {
+        info.push_str(&format!("relabel={}\n", relabeling.describe()));
+    }
+    write_file_atomic(&info_path(outpath), info)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parserule::rulefst;
+
+    use crate::alphabet::SurfaceToAnalysisFst;
+    use crate::rules::list_rule_files;
+    use crate::testutil::{copy_min_rules, fixture_golds, fixture_symt, min_rules, root, TempDir};
+
+    /// Building an explicit file list (as the default build does with
+    /// [`DEFAULT_RULE_FILES`]) gives the same FST as building the directory holding
+    /// those files.
+    #[test]
+    fn test_file_list_build_matches_srcdir_build() {
+        let symt = fixture_symt();
+        let dir = TempDir::new("build");
+        let names = ["neg_4.txt", "hab_14.txt", "compl_11.txt"];
+        copy_min_rules(&dir, &names);
+        let files = min_rules(&names);
+        let mut sorted = files.clone();
+        sorted.sort_by_key(|f| f.file_name().unwrap().to_owned());
+        let from_files = build_from_rule_files(symt.clone(), &sorted, &HashMap::new(), Default::default(), Default::default(), None, None, &mut RuleChecks::default()).unwrap();
+        let from_dir = build_from_rule_files(symt, &list_rule_files(&dir, false).unwrap(), &HashMap::new(), Default::default(), Default::default(), None, None, &mut RuleChecks::default()).unwrap();
+        assert_eq!(from_files, from_dir);
+    }
+
+    #[test]
+    fn test_symbol_use_counts_each_side() {
+        let symt = rustfst::symt!["a", "b", "c"];
+        let fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 1 => 1, 3];
+        let uses = symbol_use(&fst, Some(&symt)).unwrap();
+        let row = |label, s: &str, inputs, outputs| SymbolUse { label, symbol: s.to_string(), inputs, outputs };
+        assert_eq!(uses, [row(0, "<eps>", 0, 1), row(1, "a", 2, 1), row(2, "b", 1, 0), row(3, "c", 0, 1)]);
+        let unnamed = symbol_use(&fst, None).unwrap();
+        assert_eq!(unnamed[0], row(0, "<0>", 0, 1));
+    }
+
+    #[test]
+    fn test_check_epsilon_free() {
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2 => 2, 0];
+        assert!(check_epsilon_free(&fst).is_ok());
+        concat::<TropicalWeight, VectorFst<_>, VectorFst<_>>(&mut fst, &rustfst::fst![0 => 0; 1.0]).unwrap();
+        let err = check_epsilon_free(&fst).unwrap_err().to_string();
+        assert!(err.contains("not epsilon-free"), "{}", err);
+        rm_epsilon(&mut fst).unwrap();
+        assert!(check_epsilon_free(&fst).is_ok());
+    }
+
+    #[test]
+    fn test_files_with_no_rules_are_skipped() {
+        use rustfst::prelude::MutableFst;
+
+        let symt = fixture_symt();
+        let dir = TempDir::new("build-no-rules");
+        copy_min_rules(&dir, &["neg_4.txt"]);
+        std::fs::write(dir.join("all_comments.txt"), "% Nothing here yet\n% ::v:: = (a|e)\n").unwrap();
+        std::fs::write(dir.join("empty.txt"), "").unwrap();
+        let build = |files: &[PathBuf]| build_from_rule_files(symt.clone(), files, &HashMap::new(), Default::default(), Default::default(), None, None, &mut RuleChecks::default()).unwrap();
+        let with_empty = build(&list_rule_files(&dir, false).unwrap());
+        let alone = build(&[dir.join("neg_4.txt")]);
+        assert_eq!(with_empty, alone);
+
+        // A file whose every rule compiled to nothing keeps none of them, and
+        // so is skipped too, though its script compiles to Σ*.
+        let mut checks = RuleChecks::default();
+        assert!(!checks.check("empty_rules.txt", 1, &VectorFst::new()).unwrap());
+        let sigma_star = rulefst::weighted_sigma_star(symt.clone(), 0.0).unwrap();
+        assert!(!RuleUnion::contributes(&sigma_star, checks.kept));
+
+        // Nor do they, or rules that compiled to nothing, pad the union.
+        let mut union = RuleUnion::new(symt, FallbackBoundary::default()).unwrap();
+        let before = union.fst.clone();
+        union.add(rustfst::fst![1 => 1], 0, 0.0).unwrap();
+        union.add(VectorFst::new(), 3, 0.0).unwrap();
+        assert_eq!((union.num_compose, union.fst), (1, before));
+    }
+
+    #[test]
+    fn test_canonical_order_makes_rebuilds_byte_identical() {
+        use rustfst::prelude::SerializableFst;
+        let symt = fixture_symt();
+        let files = list_rule_files(&root().join("rules/min"), false).unwrap();
+        let dir = TempDir::new("canonical");
+        let bytes: Vec<Vec<u8>> = (0..2)
+            .map(|run| {
+                let mut fst = build_from_rule_files(symt.clone(), &files, &HashMap::new(), Default::default(), Default::default(), None, None, &mut RuleChecks::default()).unwrap();
+                canonical_order(&mut fst).unwrap();
+                let path = dir.join(format!("{}.fst", run));
+                fst.write(&path).unwrap();
+                std::fs::read(path).unwrap()
+            })
+            .collect();
+        assert_eq!(bytes[0], bytes[1]);
+    }
+
+    #[test]
+    fn test_canonical_order_ignores_state_and_arc_numbering() {
+        use rustfst::prelude::{isomorphic, MutableFst};
+        // The same FST, 0 -a:b-> 1 -c:c-> 2 and 0 -a:a-> 2, numbered and ordered two ways.
+        let build = |states: [StateId; 3], arcs_reversed: bool| {
+            let mut fst = VectorFst::<TropicalWeight>::new();
+            fst.add_states(3);
+            fst.set_start(states[0]).unwrap();
+            fst.set_final(states[2], 0.0).unwrap();
+            let mut arcs = vec![(states[0], Tr::new(1, 2, 0.5, states[1])), (states[0], Tr::new(1, 1, 0.0, states[2]))];
+            if arcs_reversed {
+                arcs.reverse();
+            }
+            arcs.push((states[1], Tr::new(3, 3, 0.0, states[2])));
+            for (s, tr) in arcs {
+                fst.add_tr(s, tr).unwrap();
+            }
+            fst
+        };
+        let (mut one, mut other) = (build([0, 1, 2], false), build([2, 0, 1], true));
+        assert!(isomorphic(&one, &other).unwrap());
+        assert_ne!(one, other);
+        canonical_order(&mut one).unwrap();
+        canonical_order(&mut other).unwrap();
+        assert_eq!(one, other);
+        assert_eq!(one.start(), Some(0));
+        assert_eq!(one.get_trs(0).unwrap().iter().map(|tr| (tr.olabel, tr.nextstate)).collect::<Vec<_>>(), [(1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn test_parse_weight_offset() {
+        assert_eq!(parse_weight_offset("special.txt=-5").unwrap(), ("special.txt".to_string(), -5.0));
+        assert_eq!(parse_weight_offset("a=b.txt = 2.5").unwrap(), ("a=b.txt".to_string(), 2.5));
+        assert!(parse_weight_offset("special.txt").is_err());
+        assert!(parse_weight_offset("special.txt=x").is_err());
+    }
+
+    #[test]
+    fn test_weighted_union_offset_sets_precedence() {
+        use rustfst::prelude::{shortest_path, Fst};
+        let best_output = |offset: f32| {
+            let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1 => 2; 1.0];
+            let other: VectorFst<TropicalWeight> = rustfst::fst![1 => 3; 1.5];
+            weighted_union(&mut fst, &other, offset).unwrap();
+            let best: VectorFst<TropicalWeight> = shortest_path(&fst).unwrap();
+            let path = best.paths_iter().next().unwrap();
+            (path.olabels, path.weight)
+        };
+        assert_eq!(best_output(0.0), (vec![2], TropicalWeight::new(1.0)));
+        assert_eq!(best_output(-1.0), (vec![3], TropicalWeight::new(0.5)));
+    }
+
+    #[test]
+    fn test_weight_offset_for_unknown_file_is_an_error() {
+        let symt = Arc::new(rustfst::symt!["#", "a"]);
+        let offsets = HashMap::from([("nope.txt".to_string(), -1.0)]);
+        assert!(build_from_rule_files(symt, &[], &offsets, Default::default(), Default::default(), None, None, &mut RuleChecks::default()).is_err());
+    }
+
+    #[test]
+    fn test_weight_offset_from_settings_file() {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b"]);
+        let dir = TempDir::new("settings");
+        let file = dir.join("ab.txt");
+        std::fs::write(&file, "a -> b / _ \n").unwrap();
+        let build = |offsets: &[(&str, f32)]| {
+            let offsets = offsets.iter().map(|&(f, w)| (f.to_string(), w)).collect();
+            build_from_rule_files(symt.clone(), std::slice::from_ref(&file), &offsets, Default::default(), Default::default(), None, None, &mut RuleChecks::default()).unwrap()
+        };
+        let (flagged, overridden) = (build(&[("ab.txt", -1.0)]), build(&[("ab.txt", 2.0)]));
+        std::fs::write(dir.join("ab.toml"), "weight_offset = -1.0\n").unwrap();
+        let (from_settings, flag_wins) = (build(&[]), build(&[("ab.txt", 2.0)]));
+        assert_eq!(from_settings, flagged);
+        assert_eq!(flag_wins, overridden);
+        assert_ne!(from_settings, flag_wins);
+    }
+
+    #[test]
+    fn test_dedup_arcs_keeps_best_weight() {
+        use rustfst::prelude::MutableFst;
+
+        let mut fst = VectorFst::<TropicalWeight>::new();
+        let (s, t) = (fst.add_state(), fst.add_state());
+        fst.set_start(s).unwrap();
+        fst.set_final(t, 0.0).unwrap();
+        for (ilabel, olabel, weight) in [(1, 1, 0.0), (1, 1, 0.0), (1, 1, 0.5), (1, 2, 1.0), (2, 2, 3.0)] {
+            fst.add_tr(s, Tr::new(ilabel, olabel, weight, t)).unwrap();
+        }
+        let (before, after) = dedup_arcs_with_sizes(&mut fst);
+        assert_eq!((before.num_trs, after.num_trs), (5, 3));
+        let trs: Vec<(Label, Label, f32)> = fst.get_trs(s).unwrap().iter().map(|tr| (tr.ilabel, tr.olabel, *tr.weight.value())).collect();
+        assert_eq!(trs, [(1, 1, 0.0), (1, 2, 1.0), (2, 2, 3.0)]);
+    }
+
+    #[test]
+    fn test_count_accepting_paths() {
+        use rustfst::prelude::MutableFst;
+
+        // 1 | 1 2 | 1 2 2 | ...: one accepting path of each length from 1.
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1 => 1];
+        fst.add_tr(1, Tr::new(2, 2, 0.0, 1)).unwrap();
+        assert_eq!(count_accepting_paths(&fst, 0).unwrap(), 0);
+        assert_eq!(count_accepting_paths(&fst, 5).unwrap(), 5);
+        // A parallel path doubles the paths at every repetition.
+        fst.add_tr(1, Tr::new(2, 3, 0.0, 1)).unwrap();
+        assert_eq!(count_accepting_paths(&fst, 3).unwrap(), 1 + 2 + 4);
+        // Saturates rather than overflowing.
+        assert_eq!(count_accepting_paths(&fst, 200).unwrap(), u128::MAX);
+        assert_eq!(count_accepting_paths(&VectorFst::new(), 3).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_dedup_arcs_keeps_fixture_candidates() {
+        use crate::analysis::AnalysisFormat;
+        use crate::prepared::PreparedFst;
+
+        let golds = fixture_golds();
+        let files = min_rules(&["neg_4.txt", "hab_14.txt"]);
+        let fst = build_from_rule_files(fixture_symt(), &files, &HashMap::new(), Default::default(), Default::default(), None, None, &mut RuleChecks::default()).unwrap();
+        let mut deduped = fst.clone();
+        let (before, after) = dedup_arcs_with_sizes(&mut deduped);
+        assert!(after.num_trs <= before.num_trs);
+        let fst = PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap();
+        let deduped = PreparedFst::new(SurfaceToAnalysisFst(deduped), None, AnalysisFormat::default()).unwrap();
+        let candidates = |prepared: &PreparedFst, form: &str| -> Vec<(String, TropicalWeight)> {
+            prepared.analyses(form).take(10).map(|c| c.map(|c| (c.analysis, c.weight))).collect::<Result<_>>().unwrap()
+        };
+        for (form, _) in golds.iter() {
+            assert_eq!(candidates(&fst, form), candidates(&deduped, form), "{}", form);
+        }
+    }
+
+    #[test]
+    fn test_connect_keeps_fixture_results() {
+        use crate::analysis::AnalysisFormat;
+        use crate::check::accepts_pair;
+        use crate::prepared::PreparedFst;
+
+        let golds = fixture_golds();
+        let files = min_rules(&["neg_4.txt", "hab_14.txt"]);
+        let fst = build_from_rule_files(fixture_symt(), &files, &HashMap::new(), Default::default(), Default::default(), None, None, &mut RuleChecks::default()).unwrap();
+        let mut connected = fst.clone();
+        let (before, after) = connect_with_sizes(&mut connected).unwrap();
+        assert!(after.num_states <= before.num_states && after.num_trs <= before.num_trs);
+        let fst = PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap();
+        let connected = PreparedFst::new(SurfaceToAnalysisFst(connected), None, AnalysisFormat::default()).unwrap();
+        for (form, segmentation) in golds.iter() {
+            assert_eq!(
+                accepts_pair(&fst, form, segmentation).unwrap(),
+                accepts_pair(&connected, form, segmentation).unwrap(),
+                "{} -> {}",
+                form,
+                segmentation
+            );
+        }
+    }
+}
@@ -0,0 +1,392 @@
+//! Segmenting a large token stream for throughput (`bulk-apply`).
+//!
+//! Word frequencies are Zipfian, so a token stream has far fewer distinct forms
+//! than tokens. The forms are deduplicated and analysed in parallel, in chunks;
+//! after each chunk its results are appended to a journal (`<out>.journal`), so
+//! that an interrupted run can resume without redoing them. Once every form is
+//! analysed, the results are written as a map sorted by form (`<out>.map`) and
+//! joined back onto the token stream, one output line per input line.
+//!
+//! Both the map and the output are tab-separated, with a status column:
+//! `ok` (with the best analysis and its weight), `none` (no analysis),
+//! `skipped` (longer than `--max-len` characters or `--max-input-len`
+//! symbols), `too_complex` (the search for its analyses gave up, see
+//! [`crate::limits`]) or `invalid` (not spelled in the symbol table, with the
+//! reason). With `--weights inline`, the output has the weight after the
+//! analysis instead of in a column of its own; the map and journal keep the
+//! column, to be read back.
+//!
+//! The prepared FST's [`CancelToken`](crate::cancel::CancelToken) cancels a
+//! run between chunks, and its searches within one; the forms analysed by then
+//! are in the journal, for `--resume`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use rustfst::{Semiring, SymbolTable};
+
+use crate::analysis::{with_inline_weight, WeightOutput};
+use crate::artifact::create_atomic;
+use crate::check::best_analysis;
+use crate::graphemes::GraphemeMap;
+use crate::limits::{given_up, LimitError};
+use crate::pool::par_map;
+use crate::prepared::PreparedFst;
+
+/// The result for one distinct form.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormResult {
+    Analysis { analysis: String, weight: f32 },
+    NoAnalysis,
+    Skipped,
+    TooComplex,
+    Invalid(String),
+}
+
+impl FormResult {
+    /// The status, analysis and weight columns.
+    fn columns(&self) -> String {
+        match self {
+            FormResult::Analysis { analysis, weight } => format!("ok\t{}\t{}", analysis, weight),
+            FormResult::NoAnalysis => "none\t\t".to_string(),
+            FormResult::Skipped => "skipped\t\t".to_string(),
+            FormResult::TooComplex => "too_complex\t\t".to_string(),
+            // Keep the reason on one line.
+            FormResult::Invalid(reason) => format!("invalid\t{}\t", reason.replace(['\t', '\n'], " ")),
+        }
+    }
+
+    /// The columns of the output, with the weight where `weights` says.
+    fn output_columns(&self, weights: WeightOutput) -> String {
+        let columns = self.columns();
+        match (self, weights) {
+            (_, WeightOutput::Field) => columns,
+            (FormResult::Analysis { analysis, weight }, WeightOutput::Inline) => format!("ok\t{}", with_inline_weight(analysis, *weight)),
+            (_, WeightOutput::Inline) => columns.strip_suffix('\t').unwrap_or(&columns).to_string(),
+        }
+    }
+
+    fn parse(columns: &[&str]) -> Option<Self> {
+        Some(match columns {
+            ["ok", analysis, weight] => FormResult::Analysis { analysis: analysis.to_string(), weight: weight.parse().ok()? },
+            ["none", "", ""] => FormResult::NoAnalysis,
+            ["skipped", "", ""] => FormResult::Skipped,
+            ["too_complex", "", ""] => FormResult::TooComplex,
+            ["invalid", reason, ""] => FormResult::Invalid(reason.to_string()),
+            _ => return None,
+        })
+    }
+}
+
+/// How forms are processed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BulkOptions {
+    pub jobs: usize,
+    /// Number of forms analysed between checkpoints.
+    pub checkpoint_every: usize,
+    /// Forms longer than this many characters are skipped.
+    pub max_len: Option<usize>,
+    /// Reuse the results in the journal of an earlier run.
+    pub resume: bool,
+    /// Where the output has the weight of each analysis.
+    pub weights: WeightOutput,
+}
+
+/// What a run did.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BulkSummary {
+    pub tokens: usize,
+    pub forms: usize,
+    /// Forms whose results were taken from the journal of an earlier run.
+    pub resumed: usize,
+    pub analysed: usize,
+    pub no_analysis: usize,
+    pub skipped: usize,
+    pub too_complex: usize,
+    pub invalid: usize,
+    pub elapsed: Duration,
+}
+
+impl BulkSummary {
+    /// Tokens annotated per second of this run.
+    pub fn tokens_per_sec(&self) -> f64 {
+        self.tokens as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+}
+
+fn with_suffix(out: &Path, suffix: &str) -> PathBuf {
+    let mut path = out.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// The sorted map from form to result written next to the output `out`.
+pub fn map_path(out: &Path) -> PathBuf {
+    with_suffix(out, ".map")
+}
+
+/// The checkpoint journal of a run writing `out`.
+pub fn journal_path(out: &Path) -> PathBuf {
+    with_suffix(out, ".journal")
+}
+
+/// Read `form\tstatus\tanalysis\tweight` lines. A truncated last line, as left
+/// by a crash in the middle of a write, is ignored.
+fn read_results(path: &Path) -> Result<BTreeMap<String, FormResult>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut results = BTreeMap::new();
+    let mut lines = text.split_inclusive('\n').peekable();
+    while let Some(line) = lines.next() {
+        let columns: Vec<&str> = line.trim_end_matches('\n').split('\t').collect();
+        match FormResult::parse(&columns[1..]) {
+            Some(result) => {
+                results.insert(columns[0].to_string(), result);
+            }
+            None if lines.peek().is_none() && !line.ends_with('\n') => {}
+            None => bail!("Malformed line in {}: {}", path.display(), line.trim_end()),
+        }
+    }
+    Ok(results)
+}
+
+fn analyse(prepared: &PreparedFst, graphemes: &GraphemeMap, symt: &SymbolTable, form: &str, max_len: Option<usize>) -> Result<FormResult> {
+    if max_len.is_some_and(|max| form.chars().count() > max) {
+        return Ok(FormResult::Skipped);
+    }
+    let mapped = match graphemes.apply(symt, form) {
+        Ok(mapped) => mapped,
+        Err(e) => return Ok(FormResult::Invalid(e.to_string())),
+    };
+    Ok(match given_up(best_analysis(prepared, &mapped))? {
+        Ok(Some((weight, analysis))) => FormResult::Analysis { analysis, weight: *weight.value() },
+        Ok(None) => FormResult::NoAnalysis,
+        Err(LimitError::TooLong { .. }) => FormResult::Skipped,
+        Err(LimitError::TooComplex { .. }) => FormResult::TooComplex,
+    })
+}
+
+/// Annotate every line of `tokens` (the text of a token file) with the best
+/// analysis of its trimmed form, writing the result to `out`; blank lines stay
+/// blank.
+pub fn bulk_apply(
+    prepared: &PreparedFst,
+    graphemes: &GraphemeMap,
+    tokens: &str,
+    out: &Path,
+    opts: &BulkOptions,
+) -> Result<BulkSummary> {
+    bulk_apply_reporting(prepared, graphemes, tokens, out, opts, |done, forms, per_sec| {
+        println!("{}/{} forms ({:.1} forms/s)", done, forms, per_sec)
+    })
+}
+
+/// [`bulk_apply`], calling `progress` with the forms done, the forms in all
+/// and the forms analysed per second after each chunk.
+fn bulk_apply_reporting<P>(
+    prepared: &PreparedFst,
+    graphemes: &GraphemeMap,
+    tokens: &str,
+    out: &Path,
+    opts: &BulkOptions,
+    mut progress: P,
+) -> Result<BulkSummary>
+where
+    P: FnMut(usize, usize, f64),
+{
+    let start = Instant::now();
+    let mut summary = BulkSummary::default();
+    let mut forms = BTreeSet::new();
+    for line in tokens.lines() {
+        let form = line.trim();
+        if !form.is_empty() {
+            summary.tokens += 1;
+            forms.insert(form.to_string());
+        }
+    }
+    summary.forms = forms.len();
+
+    let journal = journal_path(out);
+    let mut results = if opts.resume && journal.exists() { read_results(&journal)? } else { BTreeMap::new() };
+    results.retain(|form, _| forms.contains(form));
+    summary.resumed = results.len();
+    let todo: Vec<String> = forms.into_iter().filter(|f| !results.contains_key(f)).collect();
+    // Rewrite the journal before appending to it, dropping a truncated last
+    // line; through a temporary file, so a crash now loses nothing.
+    create_atomic(&journal, |journal_file| {
+        for (form, result) in results.iter() {
+            writeln!(journal_file, "{}\t{}", form, result.columns())?;
+        }
+        Ok(())
+    })?;
+    let mut journal_file = BufWriter::new(OpenOptions::new().append(true).open(&journal)?);
+
+    let symt = prepared.symt.clone();
+    let mut done = 0;
+    for chunk in todo.chunks(opts.checkpoint_every.max(1)) {
+        prepared.cancel.check()?;
+        let chunk_results = par_map(opts.jobs, chunk, |form| analyse(prepared, graphemes, &symt, form, opts.max_len));
+        // Journal the forms analysed before a failure (or cancellation) too.
+        let mut failure = None;
+        for (form, result) in chunk.iter().zip(chunk_results) {
+            match result {
+                Ok(result) => {
+                    writeln!(journal_file, "{}\t{}", form, result.columns())?;
+                    results.insert(form.clone(), result);
+                }
+                Err(e) => {
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+        journal_file.flush()?;
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        done += chunk.len();
+        progress(summary.resumed + done, summary.forms, done as f64 / start.elapsed().as_secs_f64().max(1e-9));
+    }
+    drop(journal_file);
+
+    let map = map_path(out);
+    create_atomic(&map, |map_file| {
+        for (form, result) in results.iter() {
+            writeln!(map_file, "{}\t{}", form, result.columns())?;
+            match result {
+                FormResult::Analysis { .. } => summary.analysed += 1,
+                FormResult::NoAnalysis => summary.no_analysis += 1,
+                FormResult::Skipped => summary.skipped += 1,
+                FormResult::TooComplex => summary.too_complex += 1,
+                FormResult::Invalid(_) => summary.invalid += 1,
+            }
+        }
+        Ok(())
+    })?;
+
+    create_atomic(out, |output| {
+        for line in tokens.lines() {
+            let form = line.trim();
+            match results.get(form) {
+                Some(result) => writeln!(output, "{}\t{}", form, result.output_columns(opts.weights))?,
+                None => writeln!(output)?,
+            }
+        }
+        Ok(())
+    })?;
+    // Everything is in the map now.
+    std::fs::remove_file(&journal)?;
+    summary.elapsed = start.elapsed();
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use rustfst::prelude::{Fst, TropicalWeight, VectorFst};
+    use rustfst::utils::transducer;
+
+    use crate::alphabet::SurfaceToAnalysisFst;
+    use crate::analysis::AnalysisFormat;
+    use crate::cancel::{CancelToken, Cancelled};
+    use crate::limits::Limits;
+    use crate::testutil::TempDir;
+
+    /// Analyses `ab` as `ba`, and nothing else.
+    fn prepared() -> PreparedFst {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b"]);
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 3, 1 => 1, 3, 2, 1; 1.5];
+        fst.set_input_symbols(symt.clone());
+        fst.set_output_symbols(symt);
+        PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap()
+    }
+
+    fn opts(resume: bool) -> BulkOptions {
+        BulkOptions { jobs: 2, checkpoint_every: 1, max_len: Some(3), resume, weights: WeightOutput::Field }
+    }
+
+    #[test]
+    fn test_bulk_apply_dedups_and_joins_back() {
+        let dir = TempDir::new("bulk-join");
+        let out = dir.join("out.tsv");
+        let tokens = "ab\nb\n\nabab\nab\nax\n";
+        let summary = bulk_apply(&prepared(), &GraphemeMap::default(), tokens, &out, &opts(false)).unwrap();
+        assert_eq!((summary.tokens, summary.forms), (5, 4));
+        assert_eq!((summary.analysed, summary.no_analysis, summary.skipped, summary.invalid), (1, 1, 1, 1));
+        let output = std::fs::read_to_string(&out).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[..5], ["ab\tok\tba\t1.5", "b\tnone\t\t", "", "abab\tskipped\t\t", "ab\tok\tba\t1.5"]);
+        assert!(lines[5].starts_with("ax\tinvalid\t"), "{}", lines[5]);
+        let map = std::fs::read_to_string(map_path(&out)).unwrap();
+        let forms: Vec<&str> = map.lines().map(|l| l.split('\t').next().unwrap()).collect();
+        assert_eq!(forms, ["ab", "abab", "ax", "b"]);
+        assert!(!journal_path(&out).exists());
+    }
+
+    #[test]
+    fn test_inline_weights_in_output_only() {
+        let dir = TempDir::new("bulk-inline");
+        let out = dir.join("out.tsv");
+        let opts = BulkOptions { weights: WeightOutput::Inline, ..opts(false) };
+        bulk_apply(&prepared(), &GraphemeMap::default(), "ab\nb\n", &out, &opts).unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "ab\tok\tba (w=1.5)\nb\tnone\t\n");
+        assert_eq!(std::fs::read_to_string(map_path(&out)).unwrap(), "ab\tok\tba\t1.5\nb\tnone\t\t\n");
+    }
+
+    #[test]
+    fn test_limits_skip_forms() {
+        let dir = TempDir::new("bulk-limits");
+        let out = dir.join("out.tsv");
+        let opts = BulkOptions { max_len: None, ..opts(false) };
+        let short = prepared().with_limits(Limits { max_input_len: 3, ..Limits::default() });
+        let summary = bulk_apply(&short, &GraphemeMap::default(), "ab
+abab
+", &out, &opts).unwrap();
+        assert_eq!((summary.analysed, summary.skipped), (1, 1));
+        let hard = prepared().with_limits(Limits { max_expansions: 1, ..Limits::default() });
+        let summary = bulk_apply(&hard, &GraphemeMap::default(), "ab
+", &out, &opts).unwrap();
+        assert_eq!(summary.too_complex, 1);
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "ab	too_complex		
+");
+    }
+
+    #[test]
+    fn test_resume_reuses_journal_and_ignores_truncated_line() {
+        let dir = TempDir::new("bulk-resume");
+        let out = dir.join("out.tsv");
+        let tokens = "ab\nb\n";
+        // A stand-in analysis shows that `ab` was not analysed again.
+        std::fs::write(journal_path(&out), "ab\tok\tXY\t9\nb\tno").unwrap();
+        let summary = bulk_apply(&prepared(), &GraphemeMap::default(), tokens, &out, &opts(true)).unwrap();
+        assert_eq!(summary.resumed, 1);
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "ab\tok\tXY\t9\nb\tnone\t\t\n");
+
+        std::fs::write(journal_path(&out), "ab\tok\tXY\t9\n").unwrap();
+        let summary = bulk_apply(&prepared(), &GraphemeMap::default(), tokens, &out, &opts(false)).unwrap();
+        assert_eq!(summary.resumed, 0);
+        assert!(std::fs::read_to_string(&out).unwrap().starts_with("ab\tok\tba\t1.5\n"));
+    }
+
+    #[test]
+    fn test_cancelled_run_leaves_a_usable_checkpoint() {
+        let dir = TempDir::new("bulk-cancel");
+        let out = dir.join("out.tsv");
+        let tokens = "ab\nb\nab\nba\n";
+        let token = CancelToken::new();
+        let cancelled = prepared().with_cancel(token.clone());
+        let e = bulk_apply_reporting(&cancelled, &GraphemeMap::default(), tokens, &out, &opts(false), |_, _, _| token.cancel())
+            .unwrap_err();
+        assert!(Cancelled::of(&e));
+        assert_eq!(std::fs::read_to_string(journal_path(&out)).unwrap(), "ab\tok\tba\t1.5\n");
+        assert!(!out.exists());
+
+        let summary = bulk_apply(&prepared(), &GraphemeMap::default(), tokens, &out, &opts(true)).unwrap();
+        assert_eq!((summary.resumed, summary.analysed, summary.no_analysis), (1, 1, 2));
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "ab\tok\tba\t1.5\nb\tnone\t\t\nab\tok\tba\t1.5\nba\tnone\t\t\n");
+    }
+}
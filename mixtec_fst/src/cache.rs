@@ -0,0 +1,241 @@
+//! On-disk cache of compiled per-file rule FSTs, and of the FSTs `test`
+//! prepares before checking anything (`test --prepared-cache`).
+//!
+//! Entries are keyed on the rule file contents and the symbol table, so editing
+//! either one (or `chars.txt`) simply produces a new entry. A prepared FST is
+//! keyed on the contents of the FST file it was read from.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use rustfst::prelude::{tr_sort, Fst, ILabelCompare, TropicalWeight, VectorFst};
+use rustfst::SymbolTable;
+
+use crate::artifact::{read_fst, write_file_atomic, write_fst};
+use crate::rule_config::RuleFileConfig;
+use crate::rules::{compile_rule_file, compile_rule_script, parse_script_source, read_script_source, RuleChecks, RuleEffect, RuleNote, Script};
+use crate::tones::ToneSet;
+
+pub const DEFAULT_CACHE_DIR: &str = ".fst_cache";
+
+/// FNV-1a, used instead of `DefaultHasher` so keys are stable across toolchains.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Hash of a file's contents, as recorded in build provenance.
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    fnv1a(0xcbf29ce484222325, bytes)
+}
+
+/// Hash of a symbol table's symbols, in label order.
+pub fn symt_hash(symt: &SymbolTable) -> u64 {
+    symt.iter().fold(0xcbf29ce484222325, |h, (_, s)| fnv1a(fnv1a(h, s.as_bytes()), b"\n"))
+}
+
+/// Bumped whenever the same script starts compiling to a different FST, so that
+/// stale entries are not reused.
+const COMPILER_VERSION: &[u8] = b"edge-boundaries,skip-empty-rules\n";
+
+fn cache_key(symt: &SymbolTable, contents: &str) -> u64 {
+    fnv1a(fnv1a(symt_hash(symt), COMPILER_VERSION), contents.as_bytes())
+}
+
+/// Bumped whenever `test` starts preparing FSTs differently.
+const PREPARED_VERSION: &[u8] = b"ilabel-sorted\n";
+
+/// The FST cached at `entry`, or else the one `make` returns, cached there.
+fn read_or_make<F>(entry: &Path, make: F) -> Result<VectorFst<TropicalWeight>>
+where
+    F: FnOnce() -> Result<VectorFst<TropicalWeight>>,
+{
+    if entry.exists() {
+        match read_fst(entry) {
+            Ok(fst) => {
+                log::debug!("cache hit: {}", entry.display());
+                return Ok(fst);
+            }
+            Err(e) => log::warn!("Ignoring unreadable cache entry {}: {:#}", entry.display(), e),
+        }
+    }
+    let fst = make()?;
+    if let Some(dir) = entry.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    write_fst(&fst, entry)?;
+    Ok(fst)
+}
+
+/// What the checks of a rule file found when it was compiled, cached beside
+/// its FST so that a cache hit reports the same.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CachedChecks {
+    notes: Vec<(usize, RuleEffect)>,
+    kept: usize,
+}
+
+/// Compile a rule file, reusing a previously cached FST from `cache_dir` if the
+/// file, its settings and the symbol table are unchanged. Its rules are checked
+/// with `checks`; what a cached file's checks found is reported again. With no
+/// `cache_dir` this is just [`compile_rule_file`].
+pub fn compile_rule_file_cached(
+    symt: Arc<SymbolTable>,
+    path: &Path,
+    cache_dir: Option<&Path>,
+    checks: &mut RuleChecks,
+) -> Result<VectorFst<TropicalWeight>> {
+    let Some(cache_dir) = cache_dir else {
+        return compile_rule_file(symt, path, checks);
+    };
+    let file = path.display().to_string();
+    let raw_script = read_script_source(path)?;
+    let config = RuleFileConfig::load(path)?;
+    // Files without settings keep the keys they had before there were any.
+    let key = match config == RuleFileConfig::default() {
+        true => cache_key(&symt, &raw_script),
+        false => cache_key(&symt, &format!("{}\n{:?}", raw_script, config)),
+    };
+    let entry = cache_entry(cache_dir, path, key);
+    let checks_entry = entry.with_extension("checks.json");
+    // An entry cached without its checks is compiled again.
+    let cached = std::fs::read_to_string(&checks_entry).ok().and_then(|json| serde_json::from_str::<CachedChecks>(&json).ok());
+    let compile = |checks: &mut RuleChecks| {
+        let script = Script { config, ..parse_script_source(path, &raw_script)? };
+        compile_rule_script(symt.clone(), script, &file, checks)
+    };
+    let mut fst = match cached {
+        Some(cached) => {
+            if checks.check_probabilities {
+                checks.check_variant_probabilities(&file, &parse_script_source(path, &raw_script)?);
+            }
+            for (rule, effect) in cached.notes {
+                checks.note(RuleNote { file: file.clone(), rule, effect })?;
+            }
+            checks.kept += cached.kept;
+            read_or_make(&entry, || compile(&mut checks.fork()))?
+        }
+        None => {
+            let mut file_checks = checks.fork();
+            let fst = compile(&mut file_checks)?;
+            std::fs::create_dir_all(cache_dir)?;
+            write_fst(&fst, &entry)?;
+            let notes = file_checks.notes.iter().map(|note| (note.rule, note.effect)).collect();
+            let cached = CachedChecks { notes, kept: file_checks.kept };
+            write_file_atomic(&checks_entry, serde_json::to_string(&cached)?)?;
+            checks.merge(file_checks);
+            fst
+        }
+    };
+    fst.set_input_symbols(symt.clone());
+    fst.set_output_symbols(symt);
+    Ok(fst)
+}
+
+/// The FST at `path`, as `load` reads it, sorted by input label for
+/// composing with, reused from `cache_dir` while the file is unchanged. It
+/// keeps the symbol tables of the file.
+pub fn sorted_fst_cached<F>(path: &Path, cache_dir: &Path, load: F) -> Result<VectorFst<TropicalWeight>>
+where
+    F: FnOnce() -> Result<VectorFst<TropicalWeight>>,
+{
+    let contents = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let key = fnv1a(content_hash(&contents), PREPARED_VERSION);
+    let stem = path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+    read_or_make(&cache_dir.join(format!("{}-sorted-{:016x}.fst", stem, key)), || {
+        let mut fst = load()?;
+        tr_sort(&mut fst, ILabelCompare {});
+        Ok(fst)
+    })
+}
+
+/// The G3-to-base converter for `tones` on `symt`, as `compile` makes it,
+/// reused from `cache_dir` while neither changes.
+pub fn g3_to_base_cached<F>(symt: Arc<SymbolTable>, tones: &ToneSet, cache_dir: &Path, compile: F) -> Result<VectorFst<TropicalWeight>>
+where
+    F: FnOnce() -> Result<VectorFst<TropicalWeight>>,
+{
+    let entry = cache_entry(cache_dir, Path::new("g3_to_base"), cache_key(&symt, &tones.g3_to_base_script()));
+    let mut fst = read_or_make(&entry, compile)?;
+    fst.set_input_symbols(symt.clone());
+    fst.set_output_symbols(symt);
+    Ok(fst)
+}
+
+fn cache_entry(cache_dir: &Path, path: &Path, key: u64) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+    cache_dir.join(format!("{}-{:016x}.fst", stem, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustfst::prelude::{CoreFst, MutableFst};
+    use rustfst::utils::transducer;
+    use rustfst::Semiring;
+
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_symt_hash_depends_on_symbols() {
+        let a = rustfst::symt!["a", "b"];
+        let b = rustfst::symt!["a", "c"];
+        assert_eq!(symt_hash(&a), symt_hash(&a.clone()));
+        assert_ne!(symt_hash(&a), symt_hash(&b));
+    }
+
+    #[test]
+    fn test_prepared_fsts_are_reused_until_their_source_changes() {
+        let dir = TempDir::new("prepared-cache");
+        let path = dir.join("t.fst");
+        let symt = Arc::new(rustfst::symt!["a", "b"]);
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![2, 1 => 1, 2];
+        fst.set_input_symbols(symt.clone());
+        fst.set_output_symbols(symt.clone());
+        write_fst(&fst, &path).unwrap();
+        let cache = dir.join("cache");
+        let load = || read_fst(&path);
+        let sorted = sorted_fst_cached(&path, &cache, load).unwrap();
+        assert_eq!(sorted_fst_cached(&path, &cache, || panic!("not reused")).unwrap(), sorted);
+        assert_eq!(sorted.input_symbols(), Some(&symt));
+
+        // A rebuilt FST is prepared again.
+        write_fst(&rustfst::fst![1 => 1], &path).unwrap();
+        assert_ne!(sorted_fst_cached(&path, &cache, load).unwrap(), sorted);
+
+        let tones = ToneSet::default();
+        let converter = g3_to_base_cached(symt.clone(), &tones, &cache, || Ok(fst.clone())).unwrap();
+        assert_eq!(g3_to_base_cached(symt.clone(), &tones, &cache, || panic!("not reused")).unwrap(), converter);
+        let other = rustfst::symt!["a", "c"];
+        assert!(g3_to_base_cached(Arc::new(other), &tones, &cache, || Ok(VectorFst::new())).unwrap().start().is_none());
+    }
+
+    #[test]
+    fn test_cached_rule_files_report_their_checks_again() {
+        let dir = TempDir::new("rule-cache-checks");
+        let path = dir.join("same.txt");
+        std::fs::write(&path, "a -> a / _ \n").unwrap();
+        let symt = Arc::new(rustfst::symt!["#", "a", "b"]);
+        let cache = dir.join("cache");
+        let compile = || {
+            let mut checks = RuleChecks::default();
+            let fst = compile_rule_file_cached(symt.clone(), &path, Some(&cache), &mut checks).unwrap();
+            (fst, checks)
+        };
+        let (fst, checks) = compile();
+        assert_eq!(checks.with_effect(RuleEffect::IdentityOnly).count(), 1);
+        assert_eq!(std::fs::read_dir(&cache).unwrap().count(), 2);
+        assert_eq!(compile(), (fst, checks));
+    }
+
+    #[test]
+    fn test_cache_entry_changes_with_contents() {
+        let symt = rustfst::symt!["a", "b"];
+        let dir = Path::new("cache");
+        let path = Path::new("rules/x.txt");
+        let e1 = cache_entry(dir, path, cache_key(&symt, "a -> b / _ "));
+        let e2 = cache_entry(dir, path, cache_key(&symt, "b -> a / _ "));
+        assert_ne!(e1, e2);
+        assert!(e1.file_name().unwrap().to_string_lossy().starts_with("x-"));
+    }
+}
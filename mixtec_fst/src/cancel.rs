@@ -0,0 +1,134 @@
+//! Cancelling long-running work: a run interrupted with Ctrl-C, or work whose
+//! result nobody is waiting for any more.
+//!
+//! A [`CancelToken`] is a flag shared by whoever cancels and the work, which
+//! checks it in the crate's own loops: between the expansions of the search
+//! for analyses ([`crate::search`]), between the paths decoded from a lattice
+//! ([`crate::decode`]), between the files of a build and between the chunks of
+//! `bulk-apply`. Once it is set they fail with [`Cancelled`], which callers
+//! tell from other failures, as with [`crate::limits`], to write what they
+//! have (a checkpoint, a partial report) before they stop.
+//!
+//! rustfst's algorithms (composition, determinization, minimization) cannot be
+//! stopped in the middle, so work is only cancelled between them: a build
+//! interrupted while it minimizes a large union finishes the minimization
+//! first. The budgets of single calls (`--timeout`, `--max-expansions`,
+//! `--optimize-time-budget`) remain the way to bound those.
+//!
+//! [`CancelToken::interrupt`] is the token Ctrl-C sets, once [`on_ctrl_c`] has
+//! been called; a second Ctrl-C kills the process as usual.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+/// A flag that cancels the work checking it once set (see the module
+/// documentation). Clones share the flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+/// The token Ctrl-C sets.
+static INTERRUPT: OnceLock<CancelToken> = OnceLock::new();
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// The token Ctrl-C sets (see [`on_ctrl_c`]), which work not given a
+    /// token of its own checks.
+    pub fn interrupt() -> CancelToken {
+        INTERRUPT.get_or_init(CancelToken::new).clone()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Fail with [`Cancelled`] if the token is set.
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() { Err(Cancelled) } else { Ok(()) }
+    }
+}
+
+/// The error of work that was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl Cancelled {
+    /// Whether `e` is, or was caused by, a cancellation.
+    pub fn of(e: &anyhow::Error) -> bool {
+        e.downcast_ref::<Cancelled>().is_some()
+    }
+
+    /// [`Cancelled::of`] for an error boxed as the CLI returns it.
+    pub fn in_chain(e: &(dyn std::error::Error + 'static)) -> bool {
+        std::iter::successors(Some(e), |e| e.source()).any(|e| e.is::<Cancelled>())
+    }
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Set [`CancelToken::interrupt`] on the first Ctrl-C, rather than be killed,
+/// and restore the default action so that a second one kills the process.
+#[cfg(unix)]
+pub fn on_ctrl_c() {
+    extern "C" fn handle(_: libc::c_int) {
+        // Only an atomic store: anything more is not safe in a signal handler.
+        if let Some(token) = INTERRUPT.get() {
+            token.cancel();
+        }
+    }
+    CancelToken::interrupt();
+    // SAFETY: the handler only reads an initialized `OnceLock` and stores to
+    // an atomic, both async-signal-safe.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESETHAND;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut()) != 0 {
+            log::warn!("Could not handle Ctrl-C: {}", std::io::Error::last_os_error());
+        }
+    }
+}
+
+/// Ctrl-C kills the process where no handler is installed.
+#[cfg(not(unix))]
+pub fn on_ctrl_c() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clones_share_the_flag() {
+        let token = CancelToken::new();
+        let clone = token.clone();
+        assert_eq!(clone.check(), Ok(()));
+        token.cancel();
+        assert_eq!(clone.check(), Err(Cancelled));
+        assert!(!CancelToken::new().is_cancelled());
+    }
+
+    #[test]
+    fn test_cancellation_is_told_from_other_errors() {
+        let e = anyhow::Error::from(Cancelled).context("Failed to segment");
+        assert!(Cancelled::of(&e));
+        assert!(!Cancelled::of(&anyhow::anyhow!("other")));
+        let boxed: Box<dyn std::error::Error> = e.into();
+        assert!(Cancelled::in_chain(boxed.as_ref()));
+        let boxed: Box<dyn std::error::Error> = anyhow::anyhow!("other").into();
+        assert!(!Cancelled::in_chain(boxed.as_ref()));
+    }
+}
@@ -0,0 +1,342 @@
+//! Yes/no checks of whether an FST maps a given input to a given output.
+
+use anyhow::Result;
+use rustfst::prelude::{
+    connect, project, shortest_path, CoreFst, Fst, ProjectType,
+    TropicalWeight, VectorFst,
+};
+use rustfst::{Label, Semiring};
+
+use crate::alphabet::{AnalysisAcceptor, AnalysisToAnalysisFst, AnalysisToSurfaceFst, Compose, SurfaceAcceptor, SurfaceToAnalysisFst};
+use crate::decode::{decode_distinct_outputs, display_labels, k_best_distinct};
+use crate::prepared::PreparedFst;
+use crate::report::Prediction;
+
+/// An acceptor of the outputs that count as `output`: `output` itself, or with a
+/// G3-to-base converter, every G3 analysis whose base form is `output`. Under
+/// `ignore_morph_boundaries`, which the FST then does not output (see
+/// [`PreparedFst::new`]), `output` counts without its own.
+fn output_constraint(prepared: &PreparedFst, output: &str) -> Result<AnalysisToAnalysisFst> {
+    prepared.fmt.check_gold(output)?;
+    let output = prepared.fmt.for_comparison(output);
+    let acc_out = AnalysisAcceptor::of(&prepared.symt, &output, prepared.tokenization, Some(&prepared.fmt))?;
+    Ok(match &prepared.g3_to_base {
+        None => acc_out.into(),
+        Some(get_base) => get_base.compose(&acc_out, prepared.compose_filter)?,
+    })
+}
+
+/// An acceptor of `s` wrapped in word boundaries, tokenized as `prepared` says.
+/// Fails with a [`LimitError`](crate::limits::LimitError) if `s` is longer
+/// than the limit.
+fn wrapped_acceptor(prepared: &PreparedFst, s: &str) -> Result<SurfaceAcceptor> {
+    prepared.limits.check_len(&prepared.symt, s, prepared.tokenization)?;
+    SurfaceAcceptor::of(&prepared.symt, s, prepared.tokenization, Some(&prepared.fmt))
+}
+
+/// The paths of the FST on `input`, not yet trimmed.
+pub fn input_lattice(prepared: &PreparedFst, input: &str) -> Result<SurfaceToAnalysisFst> {
+    wrapped_acceptor(prepared, input)?.compose(&prepared.fst, prepared.compose_filter)
+}
+
+/// Whether the FST has any analysis of `input`.
+pub fn accepts(prepared: &PreparedFst, input: &str) -> Result<bool> {
+    let mut lattice = input_lattice(prepared, input)?;
+    connect(&mut lattice.0)?;
+    Ok(lattice.start().is_some())
+}
+
+/// The best analysis (unwrapped) the FST gives `input`, with its weight: the
+/// first of [`PreparedFst::analyses`], so analyses that tie with the best are
+/// ordered by the prepared ranker.
+pub fn best_analysis(prepared: &PreparedFst, input: &str) -> Result<Option<(TropicalWeight, String)>> {
+    let best = prepared.analyses(input).next().transpose()?;
+    Ok(best.map(|c| (c.weight, c.analysis)))
+}
+
+/// The best analysis (unwrapped) the FST gives `input`, in the notation of the
+/// gold forms: with a G3-to-base converter, its best base form. It comes with
+/// the weight of the analysis and the margin by which it beats the next (none
+/// if it is the only one).
+pub fn weighed_prediction(prepared: &PreparedFst, input: &str) -> Result<Option<Prediction>> {
+    let mut analyses = prepared.analyses(input);
+    let Some(best) = analyses.next().transpose()? else {
+        return Ok(None);
+    };
+    let margin = analyses.next().transpose()?.map(|next| next.weight.value() - best.weight.value());
+    Ok(in_gold_notation(prepared, best.analysis)?.map(|form| Prediction { form, weight: *best.weight.value(), margin }))
+}
+
+/// An analysis (unwrapped) in the notation of the gold forms: with a
+/// G3-to-base converter, its best base form.
+fn in_gold_notation(prepared: &PreparedFst, analysis: String) -> Result<Option<String>> {
+    let Some(get_base) = &prepared.g3_to_base else {
+        return Ok(Some(analysis));
+    };
+    let acc = AnalysisAcceptor::of(&prepared.symt, &analysis, prepared.tokenization, Some(&prepared.fmt))?;
+    let base = best_path(&acc.compose(get_base, prepared.compose_filter)?.0)?;
+    Ok(base.map(|(_, olabels)| prepared.fmt.strip(&display_labels(&prepared.symt, &olabels)).to_string()))
+}
+
+/// Whether the FST maps `input` to `output`.
+///
+/// Composes the linear input acceptor, the FST and the output constraint
+/// directly and tests the result for emptiness, skipping the minimization and
+/// path decoding `can_generate_form` does. With a G3-to-base converter, `output`
+/// is matched against the base form of the analyses rather than verbatim.
+pub fn accepts_pair(prepared: &PreparedFst, input: &str, output: &str) -> Result<bool> {
+    let lattice = input_lattice(prepared, input)?;
+    if lattice.start().is_none() {
+        return Ok(false);
+    }
+
+    let constraint = output_constraint(prepared, output)?;
+    let mut generated = lattice.compose(&constraint, prepared.compose_filter)?;
+    connect(&mut generated.0)?;
+    Ok(generated.start().is_some())
+}
+
+/// Whether the analysis `analysis` (unwrapped) counts as `output`: is
+/// `output`, or with a G3-to-base converter, has it as its base form.
+pub fn counts_as(prepared: &PreparedFst, analysis: &str, output: &str) -> Result<bool> {
+    let analysis = prepared.fmt.for_comparison(analysis);
+    let acc = AnalysisAcceptor::of(&prepared.symt, &analysis, prepared.tokenization, Some(&prepared.fmt))?;
+    let mut matched = acc.compose(&output_constraint(prepared, output)?, prepared.compose_filter)?;
+    connect(&mut matched.0)?;
+    Ok(matched.start().is_some())
+}
+
+/// Every path of the FST whose output counts as `output`, from the analysis
+/// to the surfaces the FST generates from it.
+fn generation_lattice(prepared: &PreparedFst, output: &str) -> Result<AnalysisToSurfaceFst> {
+    let constraint = output_constraint(prepared, output)?;
+    let mut generated = prepared.fst.compose(&constraint, prepared.compose_filter)?;
+    connect(&mut generated.0)?;
+    Ok(generated.invert())
+}
+
+/// The weight and output labels of the best path of `fst`, if it has any.
+fn best_path(fst: &VectorFst<TropicalWeight>) -> Result<Option<(TropicalWeight, Vec<Label>)>> {
+    if fst.start().is_none() {
+        return Ok(None);
+    }
+    let best: VectorFst<TropicalWeight> = shortest_path(fst)?;
+    Ok(best.paths_iter().next().map(|p| (p.weight, p.olabels)))
+}
+
+/// The best surface form the FST generates from `output` (unwrapped), with its
+/// weight.
+pub fn best_surface(prepared: &PreparedFst, output: &str) -> Result<Option<(TropicalWeight, String)>> {
+    let best = best_path(&generation_lattice(prepared, output)?.0)?;
+    Ok(best.map(|(weight, olabels)| {
+        let surface = display_labels(&prepared.symt, &olabels);
+        (weight, prepared.fmt.strip(&surface).to_string())
+    }))
+}
+
+/// The `k` best distinct surface forms (unwrapped) the FST generates from
+/// `output`, with their weights, best first (see [`k_best_distinct`]).
+pub fn top_surfaces(prepared: &PreparedFst, output: &str, k: usize) -> Result<Vec<(TropicalWeight, String)>> {
+    let mut generated = generation_lattice(prepared, output)?;
+    project(&mut generated.0, ProjectType::ProjectOutput);
+    let ranker = prepared.ranker();
+    let surfaces = k_best_distinct(&generated, k, |nbest| {
+        decode_distinct_outputs(nbest, Some(k), ranker.as_ref(), |olabels| display_labels(&prepared.symt, olabels))
+    })?;
+    Ok(surfaces.into_iter().map(|(weight, surface)| (weight, prepared.fmt.strip(&surface).to_string())).collect())
+}
+
+/// Whether generating from `output` recovers `input`: whether `input` is (one
+/// of) the best surface forms the FST generates from `output`. This is the
+/// reverse of [`accepts_pair`], which only asks that the pair be possible.
+pub fn recovers_input(prepared: &PreparedFst, input: &str, output: &str) -> Result<bool> {
+    let generated = generation_lattice(prepared, output)?;
+    let Some((best, _)) = best_path(&generated)? else {
+        return Ok(false);
+    };
+    let own = generated.compose(&wrapped_acceptor(prepared, input)?, prepared.compose_filter)?;
+    Ok(best_path(&own)?.is_some_and(|(weight, _)| weight.approx_equal(best, 1e-5)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    use parserule::rulefst;
+    use rustfst::SymbolTable;
+    use rustfst::utils::transducer;
+
+    use crate::analysis::AnalysisFormat;
+    use crate::rules::{compile_rule_file, list_rule_files, RuleChecks};
+    use crate::verify::{minimize_nondet, Nondeterminism};
+    use crate::testutil::{fixture_golds, fixture_symt, root};
+    use crate::{apply_fst_to_input_string, apply_fst_to_output_string, get_fst_g3_to_base};
+
+    /// The lattice path `can_generate_form` takes, without the printing.
+    fn generates_pair(prepared: &PreparedFst, input: &str, output: &str) -> Result<bool> {
+        let symt = prepared.symt.clone();
+        let fmt = &prepared.fmt;
+        let wrapped = fmt.wrap(output);
+        let mut e2e = apply_fst_to_input_string(&prepared.fst, &fmt.wrap(input), prepared.tokenization, prepared.compose_filter)?;
+        minimize_nondet(&mut e2e.0, Nondeterminism::Allow)?;
+        let mut generated = match &prepared.g3_to_base {
+            None => apply_fst_to_output_string(symt.clone(), e2e, wrapped.clone(), prepared.tokenization, prepared.compose_filter)?,
+            Some(get_base) => {
+                let gen_output = apply_fst_to_output_string(symt.clone(), get_base.clone(), wrapped.clone(), prepared.tokenization, prepared.compose_filter)?;
+                e2e.compose(&gen_output, prepared.compose_filter)?
+            }
+        };
+        minimize_nondet(&mut generated.0, Nondeterminism::Allow)?;
+        let paths = decode_distinct_outputs(&generated, Some(1), prepared.ranker().as_ref(), |olabels| display_labels(&symt, olabels))?;
+        Ok(paths.first().is_some_and(|(_, result)| result == &wrapped))
+    }
+
+    /// Each file of the `rules/min` fixture, prepared for both G3 and base golds,
+    /// together with the fixture gold items.
+    fn fixture() -> (Vec<PreparedFst>, Vec<(String, String)>) {
+        let symt = fixture_symt();
+        let golds = fixture_golds();
+        let g3_to_base = get_fst_g3_to_base(symt.clone(), &Default::default()).unwrap();
+        let mut prepared = Vec::new();
+        for path in list_rule_files(&root().join("rules/min"), false).unwrap() {
+            let fst = compile_rule_file(symt.clone(), &path, &mut RuleChecks::default()).unwrap();
+            for get_base in [None, Some(g3_to_base.clone())] {
+                prepared.push(PreparedFst::new(SurfaceToAnalysisFst(fst.clone()), get_base, AnalysisFormat::default()).unwrap());
+            }
+        }
+        (prepared, golds)
+    }
+
+    #[test]
+    fn test_accepts_pair_simple() {
+        let symt = std::sync::Arc::new(rustfst::symt!["#", "a", "b", "c"]);
+        let script = parserule::ruleparse::parse_script("a -> b / _ \n").unwrap().1 .0;
+        let fst = rulefst::compile_script(symt, script).unwrap();
+        let prepared = PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap();
+        assert!(accepts_pair(&prepared, "ac", "bc").unwrap());
+        assert!(!accepts_pair(&prepared, "ac", "cc").unwrap());
+        assert!(!accepts_pair(&prepared, "ac", "bb").unwrap());
+    }
+
+    #[test]
+    fn test_fragments_skip_boundary_rules() {
+        let symt = std::sync::Arc::new(rustfst::symt!["#", "a", "b", "c"]);
+        let script = parserule::ruleparse::parse_script("a -> b / # _ \nc -> b / a _ \n").unwrap().1 .0;
+        let fst = rulefst::compile_script(symt, script).unwrap();
+        let word = PreparedFst::new(SurfaceToAnalysisFst(fst.clone()), None, AnalysisFormat::default()).unwrap();
+        let fragment = PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default().without_boundaries()).unwrap();
+        assert!(accepts_pair(&word, "ac", "bc").unwrap());
+        assert!(!accepts_pair(&fragment, "ac", "bc").unwrap());
+        // Rules without a boundary apply inside the fragment as they do in a word.
+        assert!(accepts_pair(&fragment, "cac", "cab").unwrap());
+        assert_eq!(best_analysis(&fragment, "cac").unwrap().map(|(_, a)| a), Some("cab".to_string()));
+        assert_eq!(best_surface(&fragment, "cab").unwrap().map(|(_, s)| s), Some("cac".to_string()));
+    }
+
+    #[test]
+    fn test_ignoring_morph_boundaries() {
+        let symt = std::sync::Arc::new(rustfst::symt!["#", "a", "b", "c", "-"]);
+        let script = parserule::ruleparse::parse_script("0 -> ::mb:: / a _ b\n").unwrap().1 .0;
+        let fst = crate::rules::compile_rule_script(symt, script.into(), "t.txt", &mut Default::default()).unwrap();
+        let marked = PreparedFst::new(SurfaceToAnalysisFst(fst.clone()), None, AnalysisFormat::default()).unwrap();
+        assert!(accepts_pair(&marked, "ab", "a-b").unwrap());
+        assert!(!accepts_pair(&marked, "ac", "a-c").unwrap());
+        let ignoring = AnalysisFormat::default().ignoring_morph_boundaries();
+        let ignoring = PreparedFst::new(SurfaceToAnalysisFst(fst), None, ignoring).unwrap();
+        // Either way round: a gold with or without the boundary the FST writes.
+        assert!(accepts_pair(&ignoring, "ab", "a-b").unwrap());
+        assert!(accepts_pair(&ignoring, "ab", "ab").unwrap());
+        assert!(accepts_pair(&ignoring, "ac", "a-c").unwrap());
+        assert_eq!(weighed_prediction(&ignoring, "ab").unwrap().map(|p| p.form), Some("ab".to_string()));
+    }
+
+    #[test]
+    fn test_gold_with_separator_at_an_edge_is_an_error() {
+        let symt = std::sync::Arc::new(rustfst::symt!["#", "a", "b", "c", "-"]);
+        let script = parserule::ruleparse::parse_script("a -> b / _ c\n").unwrap().1 .0;
+        let fst = crate::rules::compile_rule_script(symt, script.into(), "t.txt", &mut Default::default()).unwrap();
+        let prepared = PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap();
+        assert!(accepts_pair(&prepared, "ac", "bc").unwrap());
+        for gold in ["##bc", "bc##"] {
+            let err = accepts_pair(&prepared, "ac", gold).unwrap_err().to_string();
+            assert!(err.contains("analysis separator"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_accepts_only_analysable_words() {
+        let symt = std::sync::Arc::new(rustfst::symt!["#", "a", "b", "c"]);
+        // Only #a# has an analysis, #c#.
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 1 => 1, 4, 1];
+        fst.set_input_symbols(symt.clone());
+        fst.set_output_symbols(symt);
+        let prepared = PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap();
+        assert!(accepts(&prepared, "a").unwrap());
+        assert!(!accepts(&prepared, "b").unwrap());
+        assert!(!accepts(&prepared, "aa").unwrap());
+        let top: Vec<_> = top_surfaces(&prepared, "c", 3).unwrap().into_iter().map(|(_, s)| s).collect();
+        assert_eq!(top, ["a"]);
+        assert!(top_surfaces(&prepared, "a", 3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recovers_input_prefers_best_surface() {
+        let symt = std::sync::Arc::new(rustfst::symt!["#", "a", "b", "c"]);
+        let script = parserule::ruleparse::parse_script("a -> b / _ c\n").unwrap().1 .0;
+        let fst = rulefst::compile_script(symt, script).unwrap();
+        let prepared = PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap();
+        // bc is a possible surface of bc, but the rewrite from ac is cheaper.
+        assert!(accepts_pair(&prepared, "bc", "bc").unwrap());
+        assert!(!recovers_input(&prepared, "bc", "bc").unwrap());
+        assert!(recovers_input(&prepared, "ac", "bc").unwrap());
+        assert!(recovers_input(&prepared, "ba", "ba").unwrap());
+        assert!(!recovers_input(&prepared, "ac", "cc").unwrap());
+        assert_eq!(best_surface(&prepared, "bc").unwrap().map(|(_, s)| s), Some("ac".to_string()));
+        let top: Vec<_> = top_surfaces(&prepared, "bc", 3).unwrap().into_iter().map(|(_, s)| s).collect();
+        assert_eq!(top, ["ac", "bc"]);
+        assert_eq!(top_surfaces(&prepared, "bc", 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_accepts_pair_agrees_with_lattice_path() {
+        let (prepared, golds) = fixture();
+        for p in prepared.iter() {
+            for (form, segmentation) in golds.iter() {
+                assert_eq!(
+                    accepts_pair(p, form, segmentation).unwrap(),
+                    generates_pair(p, form, segmentation).unwrap(),
+                    "{} -> {} (g3={})",
+                    form,
+                    segmentation,
+                    p.g3_to_base.is_none()
+                );
+            }
+        }
+    }
+
+    /// Timing comparison on the fixture set; run with
+    /// `cargo test --release -- --ignored --nocapture bench_accepts_pair`.
+    #[test]
+    #[ignore]
+    fn bench_accepts_pair() {
+        let (prepared, golds) = fixture();
+        let start = Instant::now();
+        for p in prepared.iter() {
+            for (form, segmentation) in golds.iter() {
+                accepts_pair(p, form, segmentation).unwrap();
+            }
+        }
+        let fast = start.elapsed();
+        let start = Instant::now();
+        for p in prepared.iter() {
+            for (form, segmentation) in golds.iter() {
+                generates_pair(p, form, segmentation).unwrap();
+            }
+        }
+        let slow = start.elapsed();
+        let n = prepared.len() * golds.len();
+        println!("accepts_pair:   {:?} for {} pairs ({:?}/pair)", fast, n, fast / n as u32);
+        println!("lattice path:   {:?} for {} pairs ({:?}/pair)", slow, n, slow / n as u32);
+    }
+}
@@ -0,0 +1,206 @@
+//! Composition with a chosen epsilon filter (`--compose-filter`).
+//!
+//! The FSTs here are full of epsilon transitions from concatenation and union,
+//! and when both sides of a composition have them there are several ways to
+//! interleave the epsilon moves of one path. The filter decides which are kept.
+//! Where one side has no epsilons at all, as with the linear acceptor of an
+//! input, there is nothing to interleave and the cheapest filter is exact.
+
+use std::borrow::Borrow;
+
+use anyhow::Result;
+use rustfst::algorithms::compose::{compose_with_config, ComposeConfig, ComposeFilterEnum, MatcherConfig};
+use rustfst::fst_properties::FstProperties;
+use rustfst::prelude::{
+    tr_sort, CoreFst, ExpandedFst, ILabelCompare, OLabelCompare, StateIterator, TropicalWeight, VectorFst,
+};
+use rustfst::EPS_LABEL;
+
+use crate::dump::{guard, Operation};
+use crate::status::internal;
+
+/// The epsilon filter of a composition (`--compose-filter`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ComposeFilter {
+    /// Trivial where one side is epsilon-free, sequence otherwise
+    #[default]
+    Auto,
+    /// Epsilons on the left are read before epsilons on the right
+    Sequence,
+    /// Epsilons on the right are read before epsilons on the left
+    AltSequence,
+    /// Epsilons on both sides are read together wherever they can be
+    Match,
+    /// Epsilons on both sides are never read together
+    NoMatch,
+    /// No filtering: only exact when one side is epsilon-free
+    Trivial,
+}
+
+impl ComposeFilter {
+    /// The filter for composing `fst1` with `fst2`, with `Auto` resolved. Only
+    /// `fst1` is scanned for epsilons; for `fst2` the properties already known
+    /// are used, since it is usually the (large) rule FST.
+    fn resolve(self, fst1: &VectorFst<TropicalWeight>, fst2: &VectorFst<TropicalWeight>) -> ComposeFilterEnum {
+        match self {
+            ComposeFilter::Auto => {
+                let free1 = fst1.properties().contains(FstProperties::NO_O_EPSILONS)
+                    || fst1.states_iter().all(|s| fst1.get_trs(s).is_ok_and(|trs| trs.iter().all(|tr| tr.olabel != EPS_LABEL)));
+                let free2 = fst2.properties().contains(FstProperties::NO_I_EPSILONS);
+                if free1 || free2 {
+                    ComposeFilterEnum::TrivialFilter
+                } else {
+                    ComposeFilterEnum::SequenceFilter
+                }
+            }
+            ComposeFilter::Sequence => ComposeFilterEnum::SequenceFilter,
+            ComposeFilter::AltSequence => ComposeFilterEnum::AltSequenceFilter,
+            ComposeFilter::Match => ComposeFilterEnum::MatchFilter,
+            ComposeFilter::NoMatch => ComposeFilterEnum::NoMatchFilter,
+            ComposeFilter::Trivial => ComposeFilterEnum::TrivialFilter,
+        }
+    }
+}
+
+/// How [`sorted_compose`] composes: the epsilon filter, and whether states that
+/// are not on a successful path are trimmed from the result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComposeOptions {
+    pub filter: ComposeFilter,
+    pub connect: bool,
+}
+
+impl Default for ComposeOptions {
+    fn default() -> Self {
+        ComposeOptions { filter: ComposeFilter::default(), connect: true }
+    }
+}
+
+impl From<ComposeFilter> for ComposeOptions {
+    fn from(filter: ComposeFilter) -> Self {
+        ComposeOptions { filter, ..Default::default() }
+    }
+}
+
+/// The composition of `fst1` with `fst2`. The matching needs one of them
+/// sorted on the side that is matched: unless either is already known to be, a
+/// copy of the one with fewer states is sorted.
+pub fn sorted_compose(
+    fst1: impl Borrow<VectorFst<TropicalWeight>>,
+    fst2: impl Borrow<VectorFst<TropicalWeight>>,
+    opts: impl Into<ComposeOptions>,
+) -> Result<VectorFst<TropicalWeight>> {
+    let opts = opts.into();
+    let (fst1, fst2) = (fst1.borrow(), fst2.borrow());
+    let operands = [fst1, fst2];
+    let sorted = fst1.properties().contains(FstProperties::O_LABEL_SORTED)
+        || fst2.properties().contains(FstProperties::I_LABEL_SORTED);
+    let (mut sorted1, mut sorted2) = (None, None);
+    if !sorted && fst1.num_states() <= fst2.num_states() {
+        let mut fst = fst1.clone();
+        tr_sort(&mut fst, OLabelCompare {});
+        sorted1 = Some(fst);
+    } else if !sorted {
+        let mut fst = fst2.clone();
+        tr_sort(&mut fst, ILabelCompare {});
+        sorted2 = Some(fst);
+    }
+    let (fst1, fst2) = (sorted1.as_ref().unwrap_or(fst1), sorted2.as_ref().unwrap_or(fst2));
+    let config = ComposeConfig {
+        compose_filter: opts.filter.resolve(fst1, fst2),
+        matcher1_config: MatcherConfig::default(),
+        matcher2_config: MatcherConfig::default(),
+        connect: opts.connect,
+    };
+    let op = Operation::Compose { filter: opts.filter, connect: opts.connect };
+    guard(op, &operands, || internal(compose_with_config::<_, VectorFst<_>, VectorFst<_>, _, _, _>(fst1, fst2, config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustfst::prelude::{Fst, MutableFst};
+    use rustfst::{Semiring, SymbolTable, Tr};
+    use std::sync::Arc;
+
+    use crate::automaton::{linear_automaton_checked, Tokenization};
+    use crate::decode::{decode_distinct_outputs, display_labels};
+    use crate::ranking::Lexicographic;
+
+    const ALL: [ComposeFilter; 6] = [
+        ComposeFilter::Auto,
+        ComposeFilter::Sequence,
+        ComposeFilter::AltSequence,
+        ComposeFilter::Match,
+        ComposeFilter::NoMatch,
+        ComposeFilter::Trivial,
+    ];
+
+    fn symt() -> Arc<SymbolTable> {
+        Arc::new(rustfst::symt!["a", "b", "c"])
+    }
+
+    /// `a` to `a` or `ab`, with an epsilon output before the `b` or the end.
+    fn left() -> VectorFst<TropicalWeight> {
+        let mut fst = VectorFst::new();
+        let s: Vec<_> = (0..4).map(|_| fst.add_state()).collect();
+        fst.set_start(s[0]).unwrap();
+        fst.add_tr(s[0], Tr::new(1, 1, TropicalWeight::new(1.0), s[1])).unwrap();
+        fst.add_tr(s[1], Tr::new(EPS_LABEL, EPS_LABEL, TropicalWeight::one(), s[2])).unwrap();
+        fst.add_tr(s[1], Tr::new(EPS_LABEL, 2, TropicalWeight::new(2.0), s[3])).unwrap();
+        fst.set_final(s[2], TropicalWeight::one()).unwrap();
+        fst.set_final(s[3], TropicalWeight::one()).unwrap();
+        fst
+    }
+
+    /// Copies `a` and `b`, and may insert one `c` anywhere with an epsilon input.
+    fn right() -> VectorFst<TropicalWeight> {
+        let mut fst = VectorFst::new();
+        let (s, t) = (fst.add_state(), fst.add_state());
+        fst.set_start(s).unwrap();
+        for q in [s, t] {
+            fst.set_final(q, TropicalWeight::one()).unwrap();
+            for l in [1, 2] {
+                fst.add_tr(q, Tr::new(l, l, TropicalWeight::one(), q)).unwrap();
+            }
+        }
+        fst.add_tr(s, Tr::new(EPS_LABEL, 3, TropicalWeight::new(5.0), t)).unwrap();
+        fst
+    }
+
+    fn outputs(fst: &VectorFst<TropicalWeight>) -> Vec<(TropicalWeight, String)> {
+        let symt = symt();
+        let mut out = decode_distinct_outputs(fst, Some(8), &Lexicographic, |l| display_labels(&symt, l)).unwrap();
+        out.sort_by(|a, b| a.1.cmp(&b.1));
+        out
+    }
+
+    #[test]
+    fn test_filters_agree_up_to_duplicate_paths() {
+        let expected = outputs(&sorted_compose(left(), right(), ComposeFilter::Sequence).unwrap());
+        assert!(expected.iter().any(|(_, o)| o == "ab") && expected.iter().any(|(_, o)| o == "abc"));
+        for filter in ALL {
+            let fst = sorted_compose(left(), right(), filter).unwrap();
+            assert_eq!(outputs(&fst), expected, "{:?}", filter);
+            // Only the filters that never read two epsilons in more than one
+            // order leave one path per output.
+            let paths = fst.paths_iter().count();
+            match filter {
+                ComposeFilter::NoMatch | ComposeFilter::Trivial => assert!(paths > expected.len(), "{:?}", filter),
+                _ => assert_eq!(paths, expected.len(), "{:?}", filter),
+            }
+        }
+    }
+
+    #[test]
+    fn test_auto_is_trivial_against_an_acceptor() {
+        let acc = linear_automaton_checked(&symt(), "ab", Tokenization::default(), None).unwrap();
+        assert_eq!(ComposeFilter::Auto.resolve(&acc, &right()), ComposeFilterEnum::TrivialFilter);
+        assert_eq!(ComposeFilter::Auto.resolve(&left(), &right()), ComposeFilterEnum::SequenceFilter);
+        let auto = sorted_compose(&acc, right(), ComposeFilter::Auto).unwrap();
+        let sequence = sorted_compose(&acc, right(), ComposeFilter::Sequence).unwrap();
+        assert_eq!(outputs(&auto), outputs(&sequence));
+        assert_eq!(auto.num_states(), sequence.num_states());
+    }
+}
@@ -0,0 +1,187 @@
+//! Rule weights learned from how often each rule fires on the gold data
+//! (`build --weights-from-counts`).
+//!
+//! Each gold pair is explained as `--explain-weights` explains the best
+//! analysis of a word: by the cheapest path from the form to the gold analysis
+//! through the rule files or the fallback. Every rule that changes the string
+//! on that path has fired once. A rule's probability is its share of the
+//! firings of its file, with one firing added to every rule so that a rule the
+//! gold data never needs keeps a finite cost, and its cost is -ln of that
+//! probability: the rules that fire most are the cheapest.
+//!
+//! The gold analyses are followed through the rules, not converted, so they
+//! must be written as the rule files write them (as `test --g3` compares
+//! them). Pairs that nothing explains are counted and left out. Under
+//! simultaneous application the rules of a file are not separable, so there
+//! is nothing to count.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use rustfst::SymbolTable;
+
+use crate::analysis::AnalysisFormat;
+use crate::automaton::{tokenize, Tokenization};
+use crate::explain::Explainer;
+use crate::rules::{RuleCost, Script};
+use crate::simultaneous::RuleApplication;
+
+/// The weight learned for one rule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LearnedWeight {
+    pub file: String,
+    /// The rule's statement number, as in other messages about rules.
+    pub rule: usize,
+    pub text: String,
+    /// Number of gold pairs whose explanation the rule fires in.
+    pub fired: usize,
+    pub cost: RuleCost,
+}
+
+/// The weights learned from a set of gold pairs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LearnedWeights {
+    pub weights: Vec<LearnedWeight>,
+    pub items: usize,
+    /// The gold pairs that no rule file (nor the fallback) gives.
+    pub unexplained: Vec<(String, String)>,
+}
+
+/// Count the rules that fire in the explanation of each of `golds` through
+/// `scripts`, built with `weight_offsets` and `application`, and weigh them
+/// by their counts.
+pub fn learn_rule_weights(
+    symt: Arc<SymbolTable>,
+    scripts: &[(PathBuf, Script)],
+    weight_offsets: &HashMap<String, f32>,
+    application: RuleApplication,
+    golds: &[(String, String)],
+) -> Result<LearnedWeights> {
+    if application == RuleApplication::Simultaneous {
+        bail!("Cannot learn weights from counts under simultaneous application, where the rules of a file do not fire separately");
+    }
+    let explainer = Explainer::from_scripts(symt.clone(), scripts.to_vec(), weight_offsets, application)?;
+    let fmt = AnalysisFormat::default();
+    let mut fired: HashMap<(String, usize), usize> = HashMap::new();
+    let mut unexplained = Vec::new();
+    for (form, segmentation) in golds {
+        let input = tokenize(&symt, &fmt.wrap(form), Tokenization::LongestMatch)?;
+        let analysis = tokenize(&symt, &fmt.wrap(segmentation), Tokenization::LongestMatch)
+            .with_context(|| format!("Failed to read the gold analysis of {}", form))?;
+        let Some(explanation) = explainer.explain(&input, &analysis, 0.0)? else {
+            unexplained.push((form.clone(), segmentation.clone()));
+            continue;
+        };
+        let Some(file) = explanation.file else { continue };
+        for step in explanation.steps.iter().filter(|s| s.before != s.after) {
+            *fired.entry((file.clone(), step.rule - 1)).or_default() += 1;
+        }
+    }
+
+    let rules = explainer.rules();
+    let mut by_file: HashMap<&str, (usize, usize)> = HashMap::new();
+    for &(file, i, _) in rules.iter() {
+        let (count, total) = by_file.entry(file).or_default();
+        *count += 1;
+        *total += fired.get(&(file.to_string(), i)).copied().unwrap_or(0);
+    }
+    let weights = rules
+        .iter()
+        .map(|&(file, i, text)| {
+            let count = fired.get(&(file.to_string(), i)).copied().unwrap_or(0);
+            let (rules, total) = by_file[file];
+            let p = (count + 1) as f32 / (total + rules) as f32;
+            LearnedWeight { file: file.to_string(), rule: i + 1, text: text.to_string(), fired: count, cost: RuleCost { cost: -p.ln(), probability: Some(p) } }
+        })
+        .collect();
+    Ok(LearnedWeights { weights, items: golds.len(), unexplained })
+}
+
+impl LearnedWeights {
+    /// Set the costs of the rules of `scripts` to the learned ones, in place of
+    /// their annotations.
+    pub fn apply(&self, scripts: &mut [(PathBuf, Script)]) {
+        for (path, script) in scripts.iter_mut() {
+            let file = path.display().to_string();
+            for w in self.weights.iter().filter(|w| w.file == file) {
+                script.costs.insert(w.rule - 1, w.cost);
+            }
+        }
+    }
+
+    /// Write the learned weights as CSV, one row per rule, for review.
+    pub fn write_csv<W: Write>(&self, out: W) -> Result<()> {
+        let mut writer = csv::Writer::from_writer(out);
+        writer.write_record(["file", "rule", "text", "fired", "probability", "cost"])?;
+        for w in self.weights.iter() {
+            let probability = w.cost.probability.map_or_else(String::new, |p| p.to_string());
+            writer.write_record([w.file.as_str(), &w.rule.to_string(), &w.text, &w.fired.to_string(), &probability, &w.cost.cost.to_string()])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn summary(&self) -> String {
+        let explained = self.items - self.unexplained.len();
+        let firings: usize = self.weights.iter().map(|w| w.fired).sum();
+        let mut out = format!(
+            "Learned the weights of {} rules from {} firings in {}/{} gold pairs\n",
+            self.weights.len(),
+            firings,
+            explained,
+            self.items
+        );
+        for (form, segmentation) in self.unexplained.iter() {
+            out.push_str(&format!("  not explained: {} -> {}\n", form, segmentation));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    use crate::rules::load_script;
+    use crate::testutil::TempDir;
+
+    fn write(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_frequent_rules_get_lower_costs() {
+        let dir = TempDir::new("counts");
+        let path = write(&dir, "rules.txt", "a -> b / _ c\nd -> c / _ \nc -> a / d _ :: 3\n");
+        let mut scripts = vec![(path.clone(), load_script(&path).unwrap())];
+        let symt = Arc::new(rustfst::symt!["#", "a", "b", "c", "d"]);
+        let golds: Vec<(String, String)> = [("ac", "bc"), ("acc", "bcc"), ("dac", "cbc"), ("aa", "bb")]
+            .iter()
+            .map(|(f, s)| (f.to_string(), s.to_string()))
+            .collect();
+        let learned = learn_rule_weights(symt.clone(), &scripts, &HashMap::new(), RuleApplication::Sequential, &golds).unwrap();
+        assert_eq!(learned.weights.iter().map(|w| (w.rule, w.fired)).collect::<Vec<_>>(), [(1, 3), (2, 1), (3, 0)]);
+        assert_eq!(learned.unexplained, [("aa".to_string(), "bb".to_string())]);
+        let costs: Vec<f32> = learned.weights.iter().map(|w| w.cost.cost).collect();
+        assert!(costs[0] < costs[1] && costs[1] < costs[2], "{:?}", costs);
+        assert!((learned.weights.iter().filter_map(|w| w.cost.probability).sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!(learned.summary().starts_with("Learned the weights of 3 rules from 4 firings in 3/4 gold pairs\n"));
+
+        learned.apply(&mut scripts);
+        assert_eq!(scripts[0].1.costs[&2], learned.weights[2].cost);
+        let mut csv = Vec::new();
+        learned.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("file,rule,text,fired,probability,cost\n"));
+        assert!(csv.contains(",1,a -> b / _ c,3,0.5714"), "{}", csv);
+
+        let simultaneous = learn_rule_weights(symt, &scripts, &HashMap::new(), RuleApplication::Simultaneous, &golds);
+        assert!(simultaneous.is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! Per-rule-file coverage of the gold data, computed without building the full
+//! union of all rule files.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+use rustfst::SymbolTable;
+
+use crate::alphabet::{AnalysisToAnalysisFst, SurfaceToAnalysisFst};
+use crate::analysis::AnalysisFormat;
+use crate::cache::compile_rule_file_cached;
+use crate::check::accepts_pair;
+use crate::pool::par_map;
+use crate::prepared::PreparedFst;
+use crate::rules::RuleChecks;
+
+/// Which gold items each rule file produces on its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverageReport {
+    pub files: Vec<PathBuf>,
+    /// For each file (same order as `files`), the indices of the gold items it covers.
+    pub covered: Vec<Vec<usize>>,
+    /// Indices of gold items that no single file covers.
+    pub uncovered: Vec<usize>,
+}
+
+/// Compile each of `files` separately and check, for every gold `(form, segmentation)`
+/// pair, whether that file's FST alone maps the form to the segmentation. The
+/// rules of the files are checked with `checks`.
+#[allow(clippy::too_many_arguments)]
+pub fn coverage_by_rule(
+    symt: Arc<SymbolTable>,
+    files: &[PathBuf],
+    golds: &[(String, String)],
+    g3_to_base: Option<&AnalysisToAnalysisFst>,
+    fmt: &AnalysisFormat,
+    cache_dir: Option<&Path>,
+    jobs: usize,
+    checks: &mut RuleChecks,
+) -> Result<CoverageReport> {
+    let compiled = par_map(jobs, files, |path| -> Result<_> {
+        println!("Compiling {}", path.display());
+        let mut file_checks = checks.fork();
+        let fst = compile_rule_file_cached(symt.clone(), path, cache_dir, &mut file_checks)?;
+        Ok((PreparedFst::new(SurfaceToAnalysisFst(fst), g3_to_base.cloned(), fmt.clone())?, file_checks))
+    });
+    let mut prepared = Vec::new();
+    for result in compiled {
+        let (fst, file_checks) = result?;
+        checks.merge(file_checks);
+        prepared.push(fst);
+    }
+
+    let pairs: Vec<(usize, usize)> = (0..files.len())
+        .flat_map(|f| (0..golds.len()).map(move |g| (f, g)))
+        .collect();
+    let results = par_map(jobs, &pairs, |&(f, g)| {
+        let (form, segmentation) = &golds[g];
+        accepts_pair(&prepared[f], form, segmentation)
+    });
+
+    let mut covered = vec![Vec::new(); files.len()];
+    for (&(f, g), result) in pairs.iter().zip(results) {
+        if result? {
+            covered[f].push(g);
+        }
+    }
+    let uncovered = (0..golds.len())
+        .filter(|g| !covered.iter().any(|items| items.contains(g)))
+        .collect();
+    Ok(CoverageReport { files: files.to_vec(), covered, uncovered })
+}
+
+impl CoverageReport {
+    /// Write the sparse file × gold matrix as CSV, one row per covered item.
+    pub fn write_matrix<W: Write>(&self, golds: &[(String, String)], out: W) -> Result<()> {
+        let mut writer = csv::Writer::from_writer(out);
+        writer.write_record(["rule_file", "form", "segmentation"])?;
+        for (file, items) in self.files.iter().zip(&self.covered) {
+            for &g in items {
+                let (form, segmentation) = &golds[g];
+                writer.write_record([file.display().to_string().as_str(), form, segmentation])?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Print per-file counts and the items no single file covers.
+    pub fn print_summary(&self, golds: &[(String, String)]) {
+        for (file, items) in self.files.iter().zip(&self.covered) {
+            println!("{}: {}/{}", file.display(), items.len(), golds.len());
+        }
+        println!("{} items not covered by any single file:", self.uncovered.len());
+        for &g in &self.uncovered {
+            let (form, segmentation) = &golds[g];
+            println!("  {} -> {}", form, segmentation);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn write_rules(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_coverage_by_rule() {
+        let dir = TempDir::new("coverage");
+        let a_to_b = write_rules(&dir, "a_to_b.txt", "a -> b / _ \n");
+        let c_to_d = write_rules(&dir, "c_to_d.txt", "c -> d / _ \n");
+        let symt = Arc::new(rustfst::symt!["#", "a", "b", "c", "d"]);
+        let golds = vec![
+            ("a".to_string(), "b".to_string()),
+            ("c".to_string(), "d".to_string()),
+            ("ac".to_string(), "bd".to_string()),
+        ];
+        let report = coverage_by_rule(
+            symt,
+            &[a_to_b, c_to_d],
+            &golds,
+            None,
+            &AnalysisFormat::default(),
+            None,
+            2,
+            &mut RuleChecks::default(),
+        )
+        .unwrap();
+        assert_eq!(report.covered, vec![vec![0], vec![1]]);
+        assert_eq!(report.uncovered, vec![2]);
+
+        let mut csv = Vec::new();
+        report.write_matrix(&golds, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 3);
+        assert!(csv.lines().nth(1).unwrap().ends_with("a_to_b.txt,a,b"));
+    }
+}
@@ -0,0 +1,186 @@
+//! K-fold cross-validation over the gold data (`cross-validate`).
+//!
+//! Each gold item is assigned to a fold by a hash of its form and the seed, so
+//! the folds depend only on the items and the seed, not on the order of the
+//! rows. Items are stratified by the tone melody of their form (its tones in
+//! order), and each melody's items are dealt out across the folds in turn, so
+//! that a rare melody is not left to a single fold.
+//!
+//! Nothing is tuned on the training folds yet: rule weights are fixed when the
+//! FST is built. Each held-out fold is checked as `test --fast-check` checks an
+//! item, and the spread of the accuracy across folds shows how much the
+//! figure depends on which items happen to be tested.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use itertools::Itertools;
+
+use crate::cache::content_hash;
+use crate::check::accepts_pair;
+use crate::pool::par_map;
+use crate::prepared::PreparedFst;
+use crate::report::TestReport;
+use crate::tones::ToneSet;
+
+/// Folds used when `--folds` is not given.
+pub const DEFAULT_FOLDS: usize = 5;
+
+/// The tones of `form`, in order.
+pub fn tone_melody(tones: &ToneSet, form: &str) -> String {
+    form.chars().filter(|&c| tones.contains(c)).collect()
+}
+
+/// The fold of each of `golds`, of `k`.
+pub fn assign_folds(golds: &[(String, String)], k: usize, seed: u64, tones: &ToneSet) -> Vec<usize> {
+    let mut strata: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (i, (form, _)) in golds.iter().enumerate() {
+        strata.entry(tone_melody(tones, form)).or_default().push(i);
+    }
+    let key = |i: usize| {
+        let (form, segmentation) = &golds[i];
+        (content_hash(format!("{}\n{}", seed, form).as_bytes()), form, segmentation)
+    };
+    let mut folds = vec![0; golds.len()];
+    // Dealing goes on from stratum to stratum, so the folds differ in size by
+    // at most one item.
+    let mut next = 0;
+    for items in strata.into_values() {
+        for i in items.into_iter().sorted_by_key(|&i| key(i)) {
+            folds[i] = next % k;
+            next += 1;
+        }
+    }
+    folds
+}
+
+/// The results on one held-out fold.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct FoldResult {
+    pub fold: usize,
+    pub items: usize,
+    pub passed: usize,
+    /// Percentage of the items that passed.
+    pub accuracy: f64,
+}
+
+/// The results on every fold, with the mean and sample standard deviation of
+/// their accuracies (in percent).
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct CrossValidation {
+    pub folds: Vec<FoldResult>,
+    pub seed: u64,
+    /// Number of distinct tone melodies the items were stratified by.
+    pub melodies: usize,
+    pub mean_accuracy: f64,
+    pub std_accuracy: f64,
+}
+
+/// Check every gold item against `prepared` and report the accuracy on each
+/// of `k` folds.
+pub fn cross_validate(
+    prepared: &PreparedFst,
+    golds: &[(String, String)],
+    k: usize,
+    seed: u64,
+    tones: &ToneSet,
+    jobs: usize,
+) -> Result<CrossValidation> {
+    if k < 2 || k > golds.len() {
+        bail!("Cannot make {} folds of {} items; there must be at least 2 folds and no more folds than items", k, golds.len());
+    }
+    let folds = assign_folds(golds, k, seed, tones);
+    let results = par_map(jobs, golds, |(form, segmentation)| accepts_pair(prepared, form, segmentation));
+    let mut reports = vec![TestReport::counts_only(); k];
+    for (((form, segmentation), &fold), passed) in golds.iter().zip(&folds).zip(results) {
+        reports[fold].record(form, segmentation, false, passed?);
+    }
+    let folds: Vec<FoldResult> = reports
+        .iter()
+        .enumerate()
+        .map(|(fold, report)| FoldResult { fold, items: report.total(), passed: report.passed, accuracy: report.accuracy() })
+        .collect();
+    let mean = folds.iter().map(|f| f.accuracy).sum::<f64>() / k as f64;
+    let variance = folds.iter().map(|f| (f.accuracy - mean).powi(2)).sum::<f64>() / (k - 1) as f64;
+    let melodies = golds.iter().map(|(form, _)| tone_melody(tones, form)).unique().count();
+    Ok(CrossValidation { folds, seed, melodies, mean_accuracy: mean, std_accuracy: variance.sqrt() })
+}
+
+impl CrossValidation {
+    /// One line per fold, then the mean and standard deviation.
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        for f in &self.folds {
+            out.push_str(&format!("Fold {}: {}/{} passed ({:.1}%)\n", f.fold + 1, f.passed, f.items, f.accuracy));
+        }
+        out.push_str(&format!(
+            "{} folds, {} tone melodies: accuracy {:.1}% ± {:.1}%\n",
+            self.folds.len(),
+            self.melodies,
+            self.mean_accuracy,
+            self.std_accuracy
+        ));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use parserule::rulefst;
+    use parserule::ruleparse::parse_script;
+    use rustfst::prelude::{Fst, TropicalWeight, VectorFst};
+    use rustfst::SymbolTable;
+
+    use crate::alphabet::SurfaceToAnalysisFst;
+    use crate::analysis::AnalysisFormat;
+
+    fn golds() -> Vec<(String, String)> {
+        let forms = ["ka1a4", "ku1u4", "ki1i4", "ko1o4", "ka3a3", "ku3u3", "ki3i3", "ko3o3", "ka2", "ku2"];
+        forms.iter().map(|f| (f.to_string(), f.replace('k', "g"))).collect()
+    }
+
+    #[test]
+    fn test_folds_ignore_row_order_and_spread_melodies() {
+        let tones = ToneSet::default();
+        let golds = golds();
+        let folds = assign_folds(&golds, 2, 7, &tones);
+        let mut reversed = golds.clone();
+        reversed.reverse();
+        let mut reversed_folds = assign_folds(&reversed, 2, 7, &tones);
+        reversed_folds.reverse();
+        assert_eq!(folds, reversed_folds);
+        // Each melody is split between the folds, and the folds are even.
+        for melody in ["14", "33", "2"] {
+            let of_melody: Vec<usize> = (0..golds.len()).filter(|&i| tone_melody(&tones, &golds[i].0) == melody).map(|i| folds[i]).collect();
+            assert!(of_melody.contains(&0) && of_melody.contains(&1), "{}: {:?}", melody, of_melody);
+        }
+        assert_eq!(folds.iter().filter(|&&f| f == 0).count(), 5);
+        // Another seed deals the items differently.
+        assert!((0..8).any(|seed| assign_folds(&golds, 2, seed, &tones) != folds));
+        assert_eq!(assign_folds(&golds, 2, 7, &tones), folds);
+    }
+
+    #[test]
+    fn test_cross_validation_aggregates_folds() {
+        let mut symt = rustfst::symt!["#", "a", "g", "i", "k", "o", "u", "1", "2", "3", "4"];
+        symt.add_symbol("##");
+        let symt = Arc::new(symt);
+        // Voices `k` only before `a`.
+        let (_, (script, _)) = parse_script("k -> g / _ a\n").unwrap();
+        let mut fst: VectorFst<TropicalWeight> = rulefst::compile_script(symt.clone(), script).unwrap();
+        fst.set_input_symbols(symt.clone());
+        fst.set_output_symbols(symt);
+        let prepared = PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap();
+        let result = cross_validate(&prepared, &golds(), 2, 7, &ToneSet::default(), 2).unwrap();
+        assert_eq!(result.folds.iter().map(|f| f.items).sum::<usize>(), 10);
+        assert_eq!(result.folds.iter().map(|f| f.passed).sum::<usize>(), 3);
+        assert_eq!(result.melodies, 3);
+        let mean = result.folds.iter().map(|f| f.accuracy).sum::<f64>() / 2.0;
+        assert!((result.mean_accuracy - mean).abs() < 1e-12);
+        assert!(result.summary().ends_with(&format!("2 folds, 3 tone melodies: accuracy {:.1}% ± {:.1}%\n", mean, result.std_accuracy)));
+        assert!(cross_validate(&prepared, &golds(), 1, 7, &ToneSet::default(), 2).is_err());
+    }
+}
@@ -0,0 +1,392 @@
+//! Decoding the distinct outputs of a lattice without materializing every path.
+//!
+//! [`rulefst::decode_paths_through_fst`](parserule::rulefst::decode_paths_through_fst)
+//! collects a (weight, string) pair for every path before anything is
+//! deduplicated, and lattices with many paths per output make that list huge.
+//! Here paths are walked depth-first, one at a time, and folded into a map from
+//! output to best weight as they are found. With a cap, the map is pruned back
+//! to the best `cap` outputs whenever it grows to twice that, which keeps the
+//! result exact: an output dropped by pruning is beaten by `cap` others whose
+//! weights can only improve.
+//!
+//! Outputs of equal weight are ordered by a [`CandidateRanker`], and left in the
+//! order they were found when it has no preference.
+//!
+//! The k best paths of a nondeterministic lattice often spell the same output
+//! several times, so [`k_best_distinct`] asks for the k best distinct outputs
+//! instead, extracting more paths until it has them.
+//!
+//! [`decode_raw_outputs`] keeps the epsilons of each path and shows every label
+//! with its symbol, for when the rendered strings hide what the FST produced.
+//!
+//! Ctrl-C cancels a walk between the paths it decodes (see [`crate::cancel`]).
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use rustfst::prelude::{
+    shortest_path_with_config, CoreFst, ExpandedFst, Fst, ShortestPathConfig, TropicalWeight, VectorFst,
+};
+use rustfst::{Label, Semiring, StateId, SymbolTable, EPS_LABEL};
+
+use crate::cancel::CancelToken;
+use crate::ranking::CandidateRanker;
+
+/// Number of distinct outputs kept for display when no other limit is given.
+pub const DEFAULT_MAX_OUTPUTS: usize = 1000;
+
+/// Most paths [`k_best_distinct`] extracts per output asked for.
+pub const MAX_PATHS_PER_OUTPUT: usize = 32;
+
+/// The output symbols of `olabels`, concatenated.
+pub fn display_labels(symt: &SymbolTable, olabels: &[Label]) -> String {
+    olabels.iter().map(|&l| symt.get_symbol(l).unwrap_or("")).collect()
+}
+
+/// Each of `labels` as `label(symbol)`, epsilons included, separated by
+/// spaces. A label with no symbol shows `?`.
+pub fn display_raw_labels(symt: &SymbolTable, labels: &[Label]) -> String {
+    labels.iter().map(|&l| format!("{}({})", l, symt.get_symbol(l).unwrap_or("?"))).collect::<Vec<_>>().join(" ")
+}
+
+struct Walk<'a, F> {
+    fst: &'a VectorFst<TropicalWeight>,
+    display: F,
+    cap: Option<usize>,
+    ranker: &'a dyn CandidateRanker,
+    /// Whether epsilon output labels are kept for `display`.
+    epsilons: bool,
+    on_path: Vec<bool>,
+    olabels: Vec<Label>,
+    /// Best weight of each output, and when the output was first found.
+    best: HashMap<String, (TropicalWeight, usize)>,
+    found: usize,
+    cancel: CancelToken,
+}
+
+impl<F: Fn(&[Label]) -> String> Walk<'_, F> {
+    fn visit(&mut self, state: StateId, weight: TropicalWeight) -> Result<()> {
+        if self.on_path[state as usize] {
+            bail!("Cannot decode a cyclic lattice (cycle through state {})", state);
+        }
+        self.on_path[state as usize] = true;
+        if let Some(final_weight) = self.fst.final_weight(state)? {
+            self.record(weight.times(final_weight)?)?;
+        }
+        for tr in self.fst.get_trs(state)?.iter() {
+            let kept = self.epsilons || tr.olabel != EPS_LABEL;
+            if kept {
+                self.olabels.push(tr.olabel);
+            }
+            self.visit(tr.nextstate, weight.times(tr.weight)?)?;
+            if kept {
+                self.olabels.pop();
+            }
+        }
+        self.on_path[state as usize] = false;
+        Ok(())
+    }
+
+    fn record(&mut self, weight: TropicalWeight) -> Result<()> {
+        self.cancel.check()?;
+        let output = (self.display)(&self.olabels);
+        let found = self.found;
+        let (best, _) = self.best.entry(output).or_insert_with(|| (weight, found));
+        if weight < *best {
+            *best = weight;
+        }
+        self.found += 1;
+        if let Some(cap) = self.cap
+            && self.best.len() >= 2 * cap.max(1)
+        {
+            let kept = sorted(std::mem::take(&mut self.best), Some(cap), self.ranker);
+            self.best = kept.into_iter().map(|(w, found, o)| (o, (w, found))).collect();
+        }
+        Ok(())
+    }
+}
+
+/// Best first, ties broken by `ranker` and then by when they were found; at
+/// most `cap` of them.
+fn sorted(
+    best: HashMap<String, (TropicalWeight, usize)>,
+    cap: Option<usize>,
+    ranker: &dyn CandidateRanker,
+) -> Vec<(TropicalWeight, usize, String)> {
+    let mut outputs: Vec<_> = best.into_iter().map(|(o, (w, found))| (w, found, o)).collect();
+    outputs.sort_by(|(w1, f1, o1), (w2, f2, o2)| {
+        w1.partial_cmp(w2).unwrap_or(Ordering::Equal).then_with(|| ranker.compare(o1, o2)).then(f1.cmp(f2))
+    });
+    if let Some(cap) = cap {
+        outputs.truncate(cap);
+    }
+    outputs
+}
+
+/// The distinct outputs of the paths through the acyclic `fst`, as rendered by
+/// `display` from each path's non-epsilon output labels, each with its best
+/// weight, best first and ties ordered by `ranker`. With `cap`, only the best
+/// `cap` outputs are returned.
+pub fn decode_distinct_outputs<F>(
+    fst: &VectorFst<TropicalWeight>,
+    cap: Option<usize>,
+    ranker: &dyn CandidateRanker,
+    display: F,
+) -> Result<Vec<(TropicalWeight, String)>>
+where
+    F: Fn(&[Label]) -> String,
+{
+    walk(fst, cap, ranker, false, display)
+}
+
+/// [`decode_distinct_outputs`], with every output label of each path shown by
+/// [`display_raw_labels`], epsilons included. Paths that put their epsilons in
+/// different places, or spell a symbol with different labels, are told apart.
+pub fn decode_raw_outputs(
+    fst: &VectorFst<TropicalWeight>,
+    cap: Option<usize>,
+    ranker: &dyn CandidateRanker,
+    symt: &SymbolTable,
+) -> Result<Vec<(TropicalWeight, String)>> {
+    walk(fst, cap, ranker, true, |olabels| display_raw_labels(symt, olabels))
+}
+
+fn walk<F>(
+    fst: &VectorFst<TropicalWeight>,
+    cap: Option<usize>,
+    ranker: &dyn CandidateRanker,
+    epsilons: bool,
+    display: F,
+) -> Result<Vec<(TropicalWeight, String)>>
+where
+    F: Fn(&[Label]) -> String,
+{
+    let Some(start) = fst.start() else {
+        return Ok(Vec::new());
+    };
+    let mut walk = Walk {
+        fst,
+        display,
+        cap,
+        ranker,
+        epsilons,
+        on_path: vec![false; fst.num_states()],
+        olabels: Vec::new(),
+        best: HashMap::new(),
+        found: 0,
+        cancel: CancelToken::interrupt(),
+    };
+    walk.visit(start, TropicalWeight::one())?;
+    Ok(sorted(walk.best, cap, ranker).into_iter().map(|(w, _, o)| (w, o)).collect())
+}
+
+/// The `k` best distinct outputs of `fst`, as `decode` lists them from a lattice
+/// of its best paths.
+///
+/// The k best paths can spell fewer than `k` outputs, so the number of paths
+/// extracted is doubled from `k` until `decode` finds `k` outputs in them, `fst`
+/// has no more paths, or [`MAX_PATHS_PER_OUTPUT`] paths per output have been
+/// extracted. Each output keeps its best weight: its best path comes before its
+/// others.
+pub fn k_best_distinct<D>(fst: &VectorFst<TropicalWeight>, k: usize, decode: D) -> Result<Vec<(TropicalWeight, String)>>
+where
+    D: Fn(&VectorFst<TropicalWeight>) -> Result<Vec<(TropicalWeight, String)>>,
+{
+    if k == 0 || fst.start().is_none() {
+        return Ok(Vec::new());
+    }
+    let bound = k.saturating_mul(MAX_PATHS_PER_OUTPUT);
+    let mut n = k;
+    loop {
+        let nbest: VectorFst<TropicalWeight> =
+            shortest_path_with_config(fst, ShortestPathConfig::default().with_nshortest(n))?;
+        let outputs = decode(&nbest)?;
+        let exhausted = nbest.paths_iter().count() < n;
+        if outputs.len() >= k || exhausted || n >= bound {
+            log::trace!("k_best_distinct: {} outputs from {} best paths", outputs.len(), n);
+            return Ok(outputs);
+        }
+        n = n.saturating_mul(2).min(bound);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::analysis::AnalysisFormat;
+    use crate::ranking::{Lexicographic, TieBreak};
+
+    use parserule::rulefst;
+    use rustfst::prelude::MutableFst;
+    use rustfst::utils::transducer;
+    use rustfst::Tr;
+
+    /// `n` segments of two parallel arcs that both output `a` but with different
+    /// weights: 2^n paths, one output.
+    fn diamond_chain(n: usize) -> VectorFst<TropicalWeight> {
+        let mut fst = VectorFst::<TropicalWeight>::new();
+        let mut s = fst.add_state();
+        fst.set_start(s).unwrap();
+        for _ in 0..n {
+            let next = fst.add_state();
+            fst.add_tr(s, Tr::new(1, 1, 1.0, next)).unwrap();
+            fst.add_tr(s, Tr::new(1, 1, 0.5, next)).unwrap();
+            s = next;
+        }
+        fst.set_final(s, 0.0).unwrap();
+        fst
+    }
+
+    #[test]
+    fn test_matches_dedup_of_all_paths() {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b"]);
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![2, 3 => 3, 2; 1.0];
+        let other: VectorFst<TropicalWeight> = rustfst::fst![2, 3 => 3, 2; 0.5];
+        let third: VectorFst<TropicalWeight> = rustfst::fst![2, 3 => 2, 2; 2.0];
+        rustfst::prelude::union::union(&mut fst, &other).unwrap();
+        rustfst::prelude::union::union(&mut fst, &third).unwrap();
+        let decoded = decode_distinct_outputs(&fst, None, &Lexicographic, |l| display_labels(&symt, l)).unwrap();
+        assert_eq!(
+            decoded,
+            vec![(TropicalWeight::new(0.5), "ba".to_string()), (TropicalWeight::new(2.0), "aa".to_string())]
+        );
+        let mut all = rulefst::decode_paths_through_fst(symt.clone(), fst.clone());
+        all.dedup_by(|(_, o1), (_, o2)| o1 == o2);
+        assert_eq!(all.first(), decoded.first());
+        let capped = decode_distinct_outputs(&fst, Some(1), &Lexicographic, |l| display_labels(&symt, l)).unwrap();
+        assert_eq!(capped, decoded[..1]);
+    }
+
+    #[test]
+    fn test_raw_outputs_keep_epsilons() {
+        // `ab` twice, with the epsilon before the `b` on one path and after it
+        // on the other, which also starts with the epsilon of the union.
+        let symt = rustfst::symt!["a", "b"];
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 3 => 1, EPS_LABEL, 2; 1.0];
+        let other: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 3 => 1, 2, EPS_LABEL; 2.0];
+        rustfst::prelude::union::union(&mut fst, &other).unwrap();
+        let decoded = decode_distinct_outputs(&fst, None, &Lexicographic, |l| display_labels(&symt, l)).unwrap();
+        assert_eq!(decoded, [(TropicalWeight::new(1.0), "ab".to_string())]);
+        let raw = decode_raw_outputs(&fst, None, &Lexicographic, &symt).unwrap();
+        assert_eq!(
+            raw,
+            [
+                (TropicalWeight::new(1.0), "1(a) 0(<eps>) 2(b)".to_string()),
+                (TropicalWeight::new(2.0), "0(<eps>) 1(a) 2(b) 0(<eps>)".to_string())
+            ]
+        );
+        assert_eq!(display_raw_labels(&symt, &[7]), "7(?)");
+    }
+
+    #[test]
+    fn test_cap_keeps_exact_best() {
+        // Outputs 1..=9 with weight equal to their label, enumerated worst first.
+        let mut fst = VectorFst::<TropicalWeight>::new();
+        let s = fst.add_state();
+        let f = fst.add_state();
+        fst.set_start(s).unwrap();
+        fst.set_final(f, 0.0).unwrap();
+        for l in (1..=9).rev() {
+            fst.add_tr(s, Tr::new(l, l, l as f32, f)).unwrap();
+        }
+        let decoded = decode_distinct_outputs(&fst, Some(2), &Lexicographic, |l| format!("{:?}", l)).unwrap();
+        assert_eq!(decoded, vec![(TropicalWeight::new(1.0), "[1]".to_string()), (TropicalWeight::new(2.0), "[2]".to_string())]);
+        let decoded = decode_distinct_outputs(&diamond_chain(10), Some(5), &Lexicographic, |l| format!("{:?}", l)).unwrap();
+        assert_eq!(decoded, vec![(TropicalWeight::new(5.0), format!("{:?}", vec![1; 10]))]);
+    }
+
+    #[test]
+    fn test_ties_ordered_by_ranker() {
+        // Three analyses of weight 1, in the order the search finds them, and
+        // a cheaper one that comes first whatever the mode.
+        let symt = Arc::new(rustfst::symt!["#", "a", "1", "3", ">"]);
+        let mut fst = VectorFst::<TropicalWeight>::new();
+        let s = fst.add_state();
+        fst.set_start(s).unwrap();
+        for (analysis, weight) in [("a##1>3>1>3", 1.0), ("a##3>1>3", 1.0), ("a##1>3##3>1", 1.0), ("a", 0.5)] {
+            let mut prev = s;
+            for (i, c) in analysis.chars().enumerate() {
+                let label = symt.get_label(c.to_string()).unwrap();
+                let next = fst.add_state();
+                fst.add_tr(prev, Tr::new(label, label, if i == 0 { weight } else { 0.0 }, next)).unwrap();
+                prev = next;
+            }
+            fst.set_final(prev, 0.0).unwrap();
+        }
+        let fmt = AnalysisFormat::default();
+        let order = |mode: TieBreak| -> Vec<String> {
+            let decoded = decode_distinct_outputs(&fst, None, mode.ranker(&fmt).as_ref(), |l| display_labels(&symt, l));
+            decoded.unwrap().into_iter().map(|(_, o)| o).collect()
+        };
+        assert_eq!(order(TieBreak::Processes), ["a", "a##3>1>3", "a##1>3>1>3", "a##1>3##3>1"]);
+        assert_eq!(order(TieBreak::Lexicographic), ["a", "a##1>3##3>1", "a##1>3>1>3", "a##3>1>3"]);
+        assert_eq!(order(TieBreak::Simple), ["a", "a##1>3>1>3", "a##3>1>3", "a##1>3##3>1"]);
+    }
+
+    #[test]
+    fn test_k_best_distinct_skips_duplicate_paths() {
+        // Outputs 1..=7 with weight equal to their label, each spelled by three
+        // paths, so that the five best paths only spell two outputs.
+        let mut fst = VectorFst::<TropicalWeight>::new();
+        let s = fst.add_state();
+        let f = fst.add_state();
+        fst.set_start(s).unwrap();
+        fst.set_final(f, 0.0).unwrap();
+        for l in 1..=7 {
+            for i in 0..3 {
+                fst.add_tr(s, Tr::new(l, l, l as f32 + i as f32 / 10.0, f)).unwrap();
+            }
+        }
+        let decode = |nbest: &VectorFst<TropicalWeight>, k| {
+            decode_distinct_outputs(nbest, Some(k), &Lexicographic, |l| format!("{:?}", l))
+        };
+        let naive: VectorFst<TropicalWeight> =
+            shortest_path_with_config(&fst, ShortestPathConfig::default().with_nshortest(5)).unwrap();
+        assert_eq!(decode(&naive, 5).unwrap().len(), 2);
+
+        let distinct = k_best_distinct(&fst, 5, |nbest| decode(nbest, 5)).unwrap();
+        let expected: Vec<_> = (1..=5).map(|l| (TropicalWeight::new(l as f32), format!("[{}]", l))).collect();
+        assert_eq!(distinct, expected);
+        // Fewer outputs than asked for: all of them, once the paths run out.
+        assert_eq!(k_best_distinct(&fst, 10, |nbest| decode(nbest, 10)).unwrap().len(), 7);
+        // One output with 2^10 paths: the search stops at its bound.
+        assert_eq!(k_best_distinct(&diamond_chain(10), 2, |nbest| decode(nbest, 2)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_cyclic_lattice_is_an_error() {
+        let mut fst = diamond_chain(1);
+        fst.add_tr(1, Tr::new(1, 1, 0.0, 0)).unwrap();
+        assert!(decode_distinct_outputs(&fst, None, &Lexicographic, |l| format!("{:?}", l)).is_err());
+    }
+
+    /// Peak memory of both decoders on a lattice with 2^20 paths and a single
+    /// output; run each separately, since the peak only grows, with
+    /// `cargo test --release -- --ignored --nocapture bench_decode_memory_<which>`.
+    fn bench_decode_memory(all_paths: bool) {
+        let symt = Arc::new(rustfst::symt!["#", "a"]);
+        let fst = diamond_chain(20);
+        let before = crate::memory::peak_rss_kb();
+        let outputs = if all_paths {
+            rulefst::decode_paths_through_fst(symt, fst).len()
+        } else {
+            decode_distinct_outputs(&fst, Some(DEFAULT_MAX_OUTPUTS), &Lexicographic, |l| display_labels(&symt, l)).unwrap().len()
+        };
+        println!("{} outputs; peak RSS {:?} kB -> {:?} kB", outputs, before, crate::memory::peak_rss_kb());
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_decode_memory_all_paths() {
+        bench_decode_memory(true);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_decode_memory_distinct() {
+        bench_decode_memory(false);
+    }
+}
@@ -0,0 +1,294 @@
+//! Dumps of the inputs of a failed FST operation (`--debug-dump-on-error`),
+//! and running the operation again from a dump (`replay`).
+//!
+//! A failure deep inside a long build is slow to reach again. With dumping on,
+//! the operations wrapped in [`guard`] or [`guard_in_place`] write their
+//! operands (with any symbol tables they carry), the operation and its
+//! configuration, the command line and the error to a new directory when they
+//! fail, along with `chars.txt` from the working directory. Dumping is set up
+//! once per process, since the operations sit far below where the options are
+//! parsed.
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{bail, Context, Result};
+use rustfst::prelude::determinize::{determinize_with_config, DeterminizeConfig, DeterminizeType};
+use rustfst::prelude::rm_epsilon::rm_epsilon;
+use rustfst::prelude::{minimize_with_config, ExpandedFst, MinimizeConfig, TropicalWeight, VectorFst};
+
+use crate::artifact::{read_fst, write_file_atomic, write_fst};
+use crate::composition::{sorted_compose, ComposeFilter, ComposeOptions};
+use crate::status::internal;
+use crate::style::warn;
+
+/// Most states the operands of a dump may have in all, unless
+/// `--debug-dump-max-states` says otherwise.
+pub const DEFAULT_MAX_STATES: usize = 1_000_000;
+
+/// The file that describes a dump, in its directory.
+const MANIFEST: &str = "dump.json";
+
+/// An FST operation that can be dumped and replayed, with its configuration.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "name", rename_all = "snake_case")]
+pub enum Operation {
+    /// [`sorted_compose`] of two operands.
+    Compose { filter: ComposeFilter, connect: bool },
+    Determinize { delta: f32, functional: bool },
+    Minimize { delta: f32, allow_nondet: bool },
+    RmEpsilon,
+}
+
+impl Operation {
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Compose { .. } => "compose",
+            Operation::Determinize { .. } => "determinize",
+            Operation::Minimize { .. } => "minimize",
+            Operation::RmEpsilon => "rm_epsilon",
+        }
+    }
+
+    fn arity(self) -> usize {
+        match self {
+            Operation::Compose { .. } => 2,
+            _ => 1,
+        }
+    }
+
+    /// The operation applied to `operands`.
+    pub fn run(self, mut operands: Vec<VectorFst<TropicalWeight>>) -> Result<VectorFst<TropicalWeight>> {
+        if operands.len() != self.arity() {
+            bail!("{} takes {} operands, not {}", self.name(), self.arity(), operands.len());
+        }
+        let mut fst = operands.remove(0);
+        match self {
+            Operation::Compose { filter, connect } => sorted_compose(fst, &operands[0], ComposeOptions { filter, connect }),
+            Operation::Determinize { delta, functional } => {
+                let det_type = if functional { DeterminizeType::DeterminizeFunctional } else { DeterminizeType::DeterminizeNonFunctional };
+                internal(determinize_with_config(&fst, DeterminizeConfig { delta, det_type }))
+            }
+            Operation::Minimize { delta, allow_nondet } => {
+                internal(minimize_with_config(&mut fst, MinimizeConfig { delta, allow_nondet }))?;
+                Ok(fst)
+            }
+            Operation::RmEpsilon => {
+                internal(rm_epsilon(&mut fst))?;
+                Ok(fst)
+            }
+        }
+    }
+}
+
+/// What a dump holds besides its operand files.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub operation: Operation,
+    /// The operand files, in order, relative to the dump.
+    pub operands: Vec<String>,
+    /// The command line of the run that failed.
+    pub command_line: Vec<String>,
+    /// The error the operation failed with.
+    pub error: String,
+}
+
+/// Where and how much to dump.
+#[derive(Debug, Clone)]
+pub struct DumpConfig {
+    /// Each dump is a new directory under this one.
+    pub dir: PathBuf,
+    /// Most states the operands may have in all.
+    pub max_states: usize,
+}
+
+static CONFIG: OnceLock<DumpConfig> = OnceLock::new();
+
+/// Turn dumping on for the rest of the process.
+pub fn enable(config: DumpConfig) {
+    let _ = CONFIG.set(config);
+}
+
+impl DumpConfig {
+    /// Dump `operands` of `op`, which failed with `error`, and say where. Does
+    /// nothing but say so when the operands are too large. Returns the dump's
+    /// directory, if it was written.
+    pub fn dump(&self, op: Operation, operands: &[&VectorFst<TropicalWeight>], error: &anyhow::Error) -> Option<PathBuf> {
+        let states: usize = operands.iter().map(|fst| fst.num_states()).sum();
+        if states > self.max_states {
+            self.too_large(op, states);
+            return None;
+        }
+        match self.write(op, operands, error) {
+            Ok(dir) => {
+                eprintln!("Dumped the failed {} to {}; run `replay {}` to repeat it", op.name(), dir.display(), dir.display());
+                Some(dir)
+            }
+            Err(e) => {
+                warn(format!("Warning: could not dump the failed {}: {:#}", op.name(), e));
+                None
+            }
+        }
+    }
+
+    fn too_large(&self, op: Operation, states: usize) {
+        eprintln!(
+            "Not dumping the failed {}: its operands have {} states, more than --debug-dump-max-states {}",
+            op.name(),
+            states,
+            self.max_states
+        );
+    }
+
+    fn write(&self, op: Operation, operands: &[&VectorFst<TropicalWeight>], error: &anyhow::Error) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.dir).with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        let dir = (1..)
+            .map(|n| self.dir.join(format!("{:03}-{}", n, op.name())))
+            .find(|dir| !dir.exists())
+            .expect("some dump number is free");
+        std::fs::create_dir(&dir)?;
+        let mut files = Vec::new();
+        for (i, fst) in operands.iter().enumerate() {
+            let file = format!("operand{}.fst", i + 1);
+            write_fst(fst, &dir.join(&file))?;
+            files.push(file);
+        }
+        if Path::new("chars.txt").is_file() {
+            std::fs::copy("chars.txt", dir.join("chars.txt"))?;
+        }
+        let manifest = Manifest {
+            operation: op,
+            operands: files,
+            command_line: std::env::args().collect(),
+            error: format!("{:#}", error),
+        };
+        write_file_atomic(&dir.join(MANIFEST), internal(serde_json::to_string_pretty(&manifest))?)?;
+        Ok(dir)
+    }
+}
+
+/// The result of `f`, which carries out `op` on `operands`, dumping them first
+/// if it fails and dumping is on.
+pub fn guard<T>(op: Operation, operands: &[&VectorFst<TropicalWeight>], f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let result = f();
+    if let (Err(e), Some(config)) = (&result, CONFIG.get()) {
+        config.dump(op, operands, e);
+    }
+    result
+}
+
+/// [`guard`] for an operation that changes its operand in place. With dumping
+/// on, the operand is copied before the operation, unless it is too large.
+pub fn guard_in_place(
+    op: Operation,
+    fst: &mut VectorFst<TropicalWeight>,
+    f: impl FnOnce(&mut VectorFst<TropicalWeight>) -> Result<()>,
+) -> Result<()> {
+    let config = CONFIG.get();
+    let before = config.filter(|c| fst.num_states() <= c.max_states).map(|_| fst.clone());
+    let states = fst.num_states();
+    let result = f(fst);
+    if let (Err(e), Some(config)) = (&result, config) {
+        match &before {
+            Some(before) => {
+                config.dump(op, &[before], e);
+            }
+            None => config.too_large(op, states),
+        }
+    }
+    result
+}
+
+/// A dump, with the result of running its operation again.
+pub struct Replay {
+    pub manifest: Manifest,
+    pub result: Result<VectorFst<TropicalWeight>>,
+}
+
+impl Replay {
+    /// Whether the operation failed again, with the error of the dump.
+    pub fn reproduced(&self) -> bool {
+        matches!(&self.result, Err(e) if format!("{:#}", e) == self.manifest.error)
+    }
+}
+
+/// Load the dump in `dir` and run its operation again.
+pub fn replay(dir: &Path) -> Result<Replay> {
+    let path = dir.join(MANIFEST);
+    let text = std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let manifest: Manifest = serde_json::from_str(&text).with_context(|| format!("Failed to parse {}", path.display()))?;
+    let operands = manifest
+        .operands
+        .iter()
+        .map(|file| read_fst(&dir.join(file)).with_context(|| format!("Failed to read operand {}", file)))
+        .collect::<Result<Vec<_>>>()?;
+    let result = manifest.operation.run(operands);
+    Ok(Replay { manifest, result })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustfst::prelude::{Fst, MutableFst};
+    use rustfst::utils::transducer;
+    use rustfst::{Semiring, SymbolTable, Tr};
+    use std::sync::Arc;
+
+    use crate::testutil::TempDir;
+
+    /// Dumps into a directory not made yet, in a scratch directory that goes
+    /// when the first is dropped.
+    fn config(name: &str, max_states: usize) -> (TempDir, DumpConfig) {
+        let scratch = TempDir::new(name);
+        let dir = scratch.join("dumps");
+        (scratch, DumpConfig { dir, max_states })
+    }
+
+    /// `a:b`, with a transition to a state that does not exist.
+    fn broken() -> VectorFst<TropicalWeight> {
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1 => 2];
+        fst.set_input_symbols(Arc::new(rustfst::symt!["a", "b"]));
+        fst.add_tr(0, Tr::new(1, 1, TropicalWeight::new(1.0), 99)).unwrap();
+        fst
+    }
+
+    #[test]
+    fn test_dump_and_replay_reproduce_the_error() {
+        let (_scratch, config) = config("dump-replay", DEFAULT_MAX_STATES);
+        let (left, right): (VectorFst<TropicalWeight>, VectorFst<TropicalWeight>) = (broken(), rustfst::fst![1 => 1]);
+        let op = Operation::Compose { filter: ComposeFilter::Sequence, connect: true };
+        let error = op.run(vec![left.clone(), right.clone()]).unwrap_err();
+        let dir = config.dump(op, &[&left, &right], &error).unwrap();
+
+        let replay = replay(&dir).unwrap();
+        assert_eq!(replay.manifest.operation, op);
+        assert_eq!(replay.manifest.operands, ["operand1.fst", "operand2.fst"]);
+        assert!(replay.reproduced(), "{:?} vs {}", replay.result.err(), replay.manifest.error);
+        let operand = read_fst(&dir.join("operand1.fst")).unwrap();
+        assert_eq!(operand.input_symbols(), left.input_symbols());
+        // A second failure gets a directory of its own.
+        let second = config.dump(op, &[&left, &right], &error).unwrap();
+        assert_ne!(second, dir);
+    }
+
+    #[test]
+    fn test_large_operands_are_not_dumped() {
+        let (_scratch, config) = config("dump-large", 1);
+        let fst = broken();
+        let op = Operation::Determinize { delta: 1e-7, functional: true };
+        let error = op.run(vec![fst.clone()]).unwrap_err();
+        assert!(config.dump(op, &[&fst], &error).is_none());
+        assert!(!config.dir.exists());
+    }
+
+    #[test]
+    fn test_replayed_operation_can_succeed() {
+        let (_scratch, config) = config("dump-success", DEFAULT_MAX_STATES);
+        let fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2 => 2, 1];
+        let op = Operation::RmEpsilon;
+        let dir = config.dump(op, &[&fst], &anyhow::anyhow!("went away")).unwrap();
+        let replay = replay(&dir).unwrap();
+        assert!(!replay.reproduced());
+        assert_eq!(replay.result.unwrap().paths_iter().count(), 1);
+    }
+}
@@ -0,0 +1,294 @@
+//! Decoding user-supplied text files.
+//!
+//! Some older gold files are Latin-1 rather than UTF-8. A file with a byte-order
+//! mark is decoded as the encoding the mark names; otherwise it is decoded as
+//! UTF-8 if it is valid UTF-8, and as Latin-1 if not. `--encoding` forces one of
+//! the two. Any normalization of the text happens after decoding.
+//!
+//! Files too large to hold in memory, such as big test files, can be streamed
+//! with [`open_text`] instead, which decodes them as they are read.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use encoding_rs::{Decoder, DecoderResult, Encoding, UTF_8, WINDOWS_1252};
+
+/// Bytes read at a time when a file is streamed.
+const CHUNK: usize = 64 * 1024;
+
+/// An encoding that can be forced on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TextEncoding {
+    Utf8,
+    Latin1,
+}
+
+impl TextEncoding {
+    fn encoding(self) -> &'static Encoding {
+        match self {
+            TextEncoding::Utf8 => UTF_8,
+            // As in browsers, Latin-1 is decoded as its superset windows-1252.
+            TextEncoding::Latin1 => WINDOWS_1252,
+        }
+    }
+}
+
+impl fmt::Display for TextEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TextEncoding::Utf8 => write!(f, "UTF-8"),
+            TextEncoding::Latin1 => write!(f, "Latin-1"),
+        }
+    }
+}
+
+/// Decode `bytes` as `encoding`, failing with the byte offset of the first
+/// malformed sequence.
+fn decode_strict(path: &Path, bytes: &[u8], encoding: &'static Encoding) -> Result<String> {
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut text = String::with_capacity(decoder.max_utf8_buffer_length(bytes.len()).unwrap_or(bytes.len()));
+    let (result, read) = decoder.decode_to_string_without_replacement(bytes, &mut text, true);
+    match result {
+        DecoderResult::InputEmpty => Ok(text),
+        DecoderResult::Malformed(bad, after) => Err(anyhow!(
+            "{} is not valid {}: malformed sequence at byte offset {}",
+            path.display(),
+            encoding.name(),
+            read - bad as usize - after as usize
+        )),
+        DecoderResult::OutputFull => Err(anyhow!("Ran out of buffer space decoding {}", path.display())),
+    }
+}
+
+/// Decode the contents of the file at `path`, returning the text and the name
+/// of the encoding used.
+pub fn decode(path: &Path, bytes: &[u8], forced: Option<TextEncoding>) -> Result<(String, String)> {
+    if let Some(forced) = forced {
+        let bytes = match forced {
+            TextEncoding::Utf8 => bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes),
+            TextEncoding::Latin1 => bytes,
+        };
+        return Ok((decode_strict(path, bytes, forced.encoding())?, forced.to_string()));
+    }
+    if let Some((encoding, bom_len)) = Encoding::for_bom(bytes) {
+        let text = decode_strict(path, &bytes[bom_len..], encoding)?;
+        return Ok((text, format!("{} (byte-order mark)", encoding.name())));
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Ok((text.to_string(), TextEncoding::Utf8.to_string())),
+        Err(_) => Ok((decode_strict(path, bytes, WINDOWS_1252)?, TextEncoding::Latin1.to_string())),
+    }
+}
+
+/// Read the file at `path` as text, detecting its encoding unless `forced`.
+pub fn read_text(path: &Path, forced: Option<TextEncoding>) -> Result<String> {
+    let bytes = std::fs::read(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+    let (text, encoding) = decode(path, &bytes, forced)?;
+    eprintln!("Decoded {} as {}", path.display(), encoding);
+    Ok(text)
+}
+
+/// Whether everything `reader` reads is valid UTF-8, checked a chunk at a time.
+fn is_utf8(mut reader: impl Read) -> io::Result<bool> {
+    let mut buf = vec![0; CHUNK];
+    // A character split by the end of the last chunk, moved to the front.
+    let mut carried = 0;
+    loop {
+        let n = reader.read(&mut buf[carried..])?;
+        if n == 0 {
+            return Ok(carried == 0);
+        }
+        let filled = carried + n;
+        match std::str::from_utf8(&buf[..filled]) {
+            Ok(_) => carried = 0,
+            Err(e) if e.error_len().is_none() => {
+                buf.copy_within(e.valid_up_to()..filled, 0);
+                carried = filled - e.valid_up_to();
+            }
+            Err(_) => return Ok(false),
+        }
+    }
+}
+
+/// The encoding [`decode`] would pick for the file at `path`, the length of the
+/// byte-order mark to skip and the name of the encoding used, found without
+/// reading the whole file into memory.
+fn detect(path: &Path, forced: Option<TextEncoding>) -> Result<(&'static Encoding, usize, String)> {
+    let open = || File::open(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e));
+    let mut head = Vec::with_capacity(3);
+    open()?.take(3).read_to_end(&mut head)?;
+    if let Some(forced) = forced {
+        let bom_len = if forced == TextEncoding::Utf8 && head.starts_with(b"\xEF\xBB\xBF") { 3 } else { 0 };
+        return Ok((forced.encoding(), bom_len, forced.to_string()));
+    }
+    if let Some((encoding, bom_len)) = Encoding::for_bom(&head) {
+        return Ok((encoding, bom_len, format!("{} (byte-order mark)", encoding.name())));
+    }
+    if is_utf8(open()?)? {
+        Ok((UTF_8, 0, TextEncoding::Utf8.to_string()))
+    } else {
+        Ok((WINDOWS_1252, 0, TextEncoding::Latin1.to_string()))
+    }
+}
+
+/// A reader of the UTF-8 text of `inner`, decoded a chunk at a time. Malformed
+/// sequences are an error, as in [`decode`].
+struct DecodingReader<R> {
+    inner: R,
+    decoder: Decoder,
+    path: PathBuf,
+    raw: Vec<u8>,
+    text: String,
+    /// How much of `text` has been read.
+    pos: usize,
+    /// How many bytes of `inner` have been decoded, for error messages.
+    offset: usize,
+    done: bool,
+}
+
+impl<R: Read> DecodingReader<R> {
+    fn new(inner: R, encoding: &'static Encoding, path: &Path) -> Self {
+        DecodingReader {
+            inner,
+            decoder: encoding.new_decoder_without_bom_handling(),
+            path: path.to_path_buf(),
+            raw: vec![0; CHUNK],
+            text: String::new(),
+            pos: 0,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    /// Decode the next chunk of `inner` into `text`.
+    fn refill(&mut self) -> io::Result<()> {
+        let n = self.inner.read(&mut self.raw)?;
+        let last = n == 0;
+        self.text.clear();
+        self.pos = 0;
+        self.text.reserve(self.decoder.max_utf8_buffer_length(n).unwrap_or(n));
+        let (result, read) = self.decoder.decode_to_string_without_replacement(&self.raw[..n], &mut self.text, last);
+        match result {
+            DecoderResult::InputEmpty => {}
+            DecoderResult::Malformed(bad, after) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} is not valid {}: malformed sequence at byte offset {}",
+                        self.path.display(),
+                        self.decoder.encoding().name(),
+                        self.offset + read - bad as usize - after as usize
+                    ),
+                ));
+            }
+            DecoderResult::OutputFull => {
+                return Err(io::Error::other(format!("Ran out of buffer space decoding {}", self.path.display())));
+            }
+        }
+        self.offset += read;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecodingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.text.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.refill()?;
+        }
+        let bytes = &self.text.as_bytes()[self.pos..];
+        let n = bytes.len().min(buf.len());
+        buf[..n].copy_from_slice(&bytes[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Open the file at `path` as a stream of UTF-8 text, detecting its encoding
+/// unless `forced` as [`read_text`] does, without reading it all into memory.
+pub fn open_text(path: &Path, forced: Option<TextEncoding>) -> Result<impl Read + use<>> {
+    let (encoding, bom_len, name) = detect(path, forced)?;
+    let mut file = File::open(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+    io::copy(&mut (&mut file).take(bom_len as u64), &mut io::sink())?;
+    eprintln!("Decoding {} as {}", path.display(), name);
+    Ok(DecodingReader::new(file, encoding, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{fixture_symt, min_rules, root, TempDir};
+
+    #[test]
+    fn test_detects_utf8_latin1_and_bom() {
+        let path = Path::new("x.csv");
+        assert_eq!(decode(path, "ñá".as_bytes(), None).unwrap(), ("ñá".to_string(), "UTF-8".to_string()));
+        assert_eq!(decode(path, b"\xF1\xE1", None).unwrap(), ("ñá".to_string(), "Latin-1".to_string()));
+        assert_eq!(decode(path, b"\xEF\xBB\xBF\xC3\xB1", None).unwrap().0, "ñ");
+        assert_eq!(decode(path, b"\xFF\xFE\xF1\x00", None).unwrap().0, "ñ");
+        assert_eq!(decode(path, b"\xEF\xBB\xBFa", Some(TextEncoding::Utf8)).unwrap().0, "a");
+        assert_eq!(decode(path, "ñ".as_bytes(), Some(TextEncoding::Latin1)).unwrap().0, "Ã±");
+    }
+
+    #[test]
+    fn test_forced_utf8_error_names_file_and_offset() {
+        let err = decode(Path::new("old.csv"), b"ab\xF1c", Some(TextEncoding::Utf8)).unwrap_err().to_string();
+        assert!(err.contains("old.csv") && err.contains("byte offset 2"), "{}", err);
+    }
+
+    #[test]
+    fn test_streamed_text_matches_read_text() {
+        let dir = TempDir::new("stream");
+        let streamed = |bytes: &[u8], forced| -> Result<String> {
+            let path = dir.join("text.csv");
+            std::fs::write(&path, bytes).unwrap();
+            let mut text = String::new();
+            open_text(&path, forced)?.read_to_string(&mut text)?;
+            assert_eq!(text, read_text(&path, forced)?);
+            Ok(text)
+        };
+        // Characters split across chunks, both when detecting and when decoding.
+        let long = format!("a{}", "ñ".repeat(CHUNK));
+        assert_eq!(streamed(long.as_bytes(), None).unwrap(), long);
+        let mut latin1 = long.clone().into_bytes();
+        latin1.push(0xF1);
+        assert!(streamed(&latin1, None).unwrap().ends_with("Ã±Ã±ñ"));
+        assert_eq!(streamed(b"\xEF\xBB\xBF\xC3\xB1", None).unwrap(), "ñ");
+        assert_eq!(streamed(b"\xFF\xFE\xF1\x00", None).unwrap(), "ñ");
+        let err = streamed(&latin1, Some(TextEncoding::Utf8)).unwrap_err().to_string();
+        assert!(err.contains(&format!("byte offset {}", long.len())), "{}", err);
+    }
+
+    #[test]
+    fn test_latin1_fixture_analyses_like_utf8_twin() {
+        use crate::alphabet::SurfaceToAnalysisFst;
+        use crate::analysis::AnalysisFormat;
+        use crate::build::build_from_rule_files;
+        use crate::check::accepts_pair;
+        use crate::graphemes::GraphemeMap;
+        use crate::prepared::PreparedFst;
+        use crate::{map_test_inputs, read_tests};
+
+        let symt = fixture_symt();
+        let read = |name: &str, forced| {
+            let tests = read_tests(root().join("tests").join(name).to_str().unwrap(), forced).unwrap();
+            map_test_inputs(&GraphemeMap::default(), &symt, tests).unwrap()
+        };
+        let utf8 = read("accents.csv", None);
+        assert_eq!(read("accents_latin1.csv", None), utf8);
+        assert_eq!(read("accents_latin1.csv", Some(TextEncoding::Latin1)), utf8);
+        assert!(read_tests(root().join("tests/accents_latin1.csv").to_str().unwrap(), Some(TextEncoding::Utf8)).is_err());
+
+        let files = min_rules(&["neg_4.txt"]);
+        let fst = build_from_rule_files(symt.clone(), &files, &Default::default(), Default::default(), Default::default(), None, None, &mut Default::default()).unwrap();
+        let prepared = PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap();
+        let results: Vec<bool> = utf8.iter().map(|(input, form)| accepts_pair(&prepared, input, form).unwrap()).collect();
+        assert_eq!(results, [true, true, false]);
+    }
+}
@@ -0,0 +1,424 @@
+//! Breaking down the weight of a word's best analysis (`build --explain-weights`).
+//!
+//! A built FST is determinized and minimized, so its weights no longer sit on
+//! the transitions that earned them. The breakdown is recomputed from the rule
+//! files instead, with the options of the build. The word is pushed through a
+//! file's rules one at a time, keeping every string each rule can leave with
+//! the best weight of getting there, and the best path to the analysis is then
+//! traced back through them. That gives the string each rule leaves and the
+//! weight it adds.
+//!
+//! The weight of an analysis comes from:
+//!
+//! - the sigma-star hops of each rule: the rule compiler charges 1 for every
+//!   symbol its sigma-stars copy, and a rule that rewrites copies fewer;
+//! - the cost annotation of a rule that changes its input (`:: 2`);
+//! - the identity fallback, which charges [`REWEIGHT_STEP`] per symbol;
+//! - the padding of files with fewer rules than the largest, a
+//!   [`REWEIGHT_STEP`] for each missing rule;
+//! - a `--weight-offset` for the file.
+//!
+//! Classes and boundaries carry no weight of their own in the rule compiler.
+//! Under simultaneous application the rules are not separable, so a file's
+//! paths are given as a whole.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use parserule::ruleparse::Statement;
+use rustfst::prelude::compose::compose;
+use rustfst::prelude::rm_epsilon::rm_epsilon;
+use rustfst::prelude::{
+    connect, project, shortest_path, tr_sort, CoreFst, Fst, ILabelCompare, MutableFst, OLabelCompare,
+    ProjectType, StateIterator, TropicalWeight, VectorFst,
+};
+use rustfst::{Label, Semiring, SymbolTable, Tr, EPS_LABEL};
+
+use crate::analysis::AnalysisFormat;
+use crate::boundary::INTERNAL_BOUNDARY;
+use crate::build::{weight_offset, REWEIGHT_STEP};
+use crate::decode::display_labels;
+use crate::rules::{compile_cascade_rules, load_script, read_script_source, CascadeRules, RuleChecks, RuleCost, Script};
+use crate::simultaneous::{compile_simultaneous, RuleApplication};
+use crate::status::internal;
+
+/// What one rule of a cascade did on the best path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleStep {
+    /// The rule's statement number, as in other messages about rules.
+    pub rule: usize,
+    pub text: String,
+    pub before: String,
+    pub after: String,
+    pub weight: f32,
+    /// The rule's cost annotation, if it has one.
+    pub cost: Option<f32>,
+}
+
+impl RuleStep {
+    /// The part of the weight that the rule's sigma-stars charge.
+    pub fn hops(&self) -> f32 {
+        match self.cost {
+            Some(cost) if self.before != self.after => self.weight - cost,
+            _ => self.weight,
+        }
+    }
+}
+
+/// The breakdown of the weight of a word's best analysis.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explanation {
+    /// The word, wrapped in boundaries.
+    pub input: String,
+    pub analysis: String,
+    /// Weight of the analysis in the built FST.
+    pub fst_weight: f32,
+    /// The rule file whose paths give the analysis; `None` for the identity fallback.
+    pub file: Option<String>,
+    /// The rules of that file, in order.
+    pub steps: Vec<RuleStep>,
+    /// Weight of the path through the file, or through the fallback.
+    pub base: f32,
+    /// Number of [`REWEIGHT_STEP`]s padding the file to the largest rule count.
+    pub padding_steps: usize,
+    pub offset: f32,
+}
+
+impl Explanation {
+    pub fn total(&self) -> f32 {
+        self.base + self.padding_steps as f32 * REWEIGHT_STEP + self.offset
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = format!("Weight of the best analysis of {}: {} ({})\n", self.input, self.analysis, self.fst_weight);
+        match &self.file {
+            None => out.push_str(&format!(
+                "  identity fallback: {} symbols at {} = {}\n",
+                self.input.chars().count(),
+                REWEIGHT_STEP,
+                self.base
+            )),
+            Some(file) => out.push_str(&format!("  paths of {}: {}\n", file, self.base)),
+        }
+        let width = self.steps.iter().map(|s| s.text.chars().count()).max().unwrap_or(0);
+        for step in self.steps.iter() {
+            let mut parts = format!("{} sigma-star hops", step.hops());
+            if let Some(cost) = step.cost.filter(|_| step.before != step.after) {
+                parts.push_str(&format!(" + cost {}", cost));
+            }
+            let change = if step.before == step.after {
+                "unchanged".to_string()
+            } else {
+                format!("{} -> {}", step.before, step.after)
+            };
+            out.push_str(&format!(
+                "    rule {:>3}  {:width$}  {:>6}  ({})  {}\n",
+                step.rule, step.text, step.weight, parts, change
+            ));
+        }
+        if self.padding_steps > 0 {
+            out.push_str(&format!(
+                "  padding for rule count: {} steps of {} = {}\n",
+                self.padding_steps,
+                REWEIGHT_STEP,
+                self.padding_steps as f32 * REWEIGHT_STEP
+            ));
+        }
+        if self.offset != 0.0 {
+            out.push_str(&format!("  weight offset: {}\n", self.offset));
+        }
+        out.push_str(&format!("  total: {}\n", self.total()));
+        if (self.total() - self.fst_weight).abs() > 1e-3 {
+            out.push_str(&format!("  (the built FST gives {}; was it built with other options?)\n", self.fst_weight));
+        }
+        out
+    }
+}
+
+/// An acceptor of the single string `labels`.
+fn linear(labels: &[Label]) -> VectorFst<TropicalWeight> {
+    let mut fst = VectorFst::new();
+    let mut state = fst.add_state();
+    fst.set_start(state).unwrap();
+    for &l in labels {
+        let next = fst.add_state();
+        fst.add_tr(state, Tr::new(l, l, TropicalWeight::one(), next)).unwrap();
+        state = next;
+    }
+    fst.set_final(state, TropicalWeight::one()).unwrap();
+    fst
+}
+
+fn compose_sorted(mut a: VectorFst<TropicalWeight>, mut b: VectorFst<TropicalWeight>) -> Result<VectorFst<TropicalWeight>> {
+    tr_sort(&mut a, OLabelCompare {});
+    tr_sort(&mut b, ILabelCompare {});
+    let mut fst: VectorFst<TropicalWeight> = internal(compose(a, b))?;
+    internal(connect(&mut fst))?;
+    Ok(fst)
+}
+
+/// The weight, input and output of a path.
+type BestPath = (f32, Vec<Label>, Vec<Label>);
+
+/// The best path through `fst`, if any.
+fn best(fst: &VectorFst<TropicalWeight>) -> Result<Option<BestPath>> {
+    if fst.start().is_none() {
+        return Ok(None);
+    }
+    let path: VectorFst<TropicalWeight> = internal(shortest_path(fst))?;
+    Ok(path.paths_iter().next().map(|p| {
+        let labels = |ls: &[Label]| ls.iter().copied().filter(|&l| l != EPS_LABEL).collect();
+        (*p.weight.value(), labels(&p.ilabels), labels(&p.olabels))
+    }))
+}
+
+/// A rule file compiled once, to explain any number of analyses.
+enum CompiledFile {
+    /// The rules of the file, each on its own, with the symbol table they
+    /// share and the text of each statement.
+    Cascade { internal: Arc<SymbolTable>, rules: Vec<(usize, VectorFst<TropicalWeight>)>, costs: HashMap<usize, RuleCost>, lines: Vec<String> },
+    /// The whole file, whose rules are not separable.
+    Simultaneous(VectorFst<TropicalWeight>),
+}
+
+struct ExplainedFile {
+    name: String,
+    /// Number of rules, which sets the padding of the file.
+    rules: usize,
+    offset: f32,
+    compiled: CompiledFile,
+}
+
+impl ExplainedFile {
+    /// The best weight and the rule steps of the paths from `input` to
+    /// `analysis` through the file, if it has any.
+    fn explain(&self, input: &[Label], analysis: &[Label]) -> Result<Option<(f32, Vec<RuleStep>)>> {
+        let (symt, rules, costs, lines) = match &self.compiled {
+            CompiledFile::Simultaneous(fst) => {
+                let paths = compose_sorted(compose_sorted(linear(input), fst.clone())?, linear(analysis))?;
+                return Ok(best(&paths)?.map(|(weight, _, _)| (weight, Vec::new())));
+            }
+            CompiledFile::Cascade { internal, rules, costs, lines } => (internal, rules, costs, lines),
+        };
+        let display = |labels: &[Label]| display_labels(symt, labels).replace(INTERNAL_BOUNDARY, "#");
+
+        // The strings each rule can leave, with the best weight of getting there.
+        let mut lattices = vec![linear(input)];
+        for (_, rule) in rules.iter() {
+            let mut next = compose_sorted(lattices.last().unwrap().clone(), rule.clone())?;
+            project(&mut next, ProjectType::ProjectOutput);
+            internal(rm_epsilon(&mut next))?;
+            lattices.push(next);
+        }
+        // Boundaries written by the rules are spelled `#` in the analysis.
+        let mut restored = lattices.last().unwrap().clone();
+        let (internal_label, boundary) = (symt.get_label(INTERNAL_BOUNDARY), symt.get_label("#"));
+        let states: Vec<_> = restored.states_iter().collect();
+        for s in states {
+            for mut tr in restored.pop_trs(s)? {
+                if Some(tr.olabel) == internal_label {
+                    tr.olabel = boundary.ok_or_else(|| anyhow!("Symbol table has no word boundary"))?;
+                }
+                restored.add_tr(s, tr)?;
+            }
+        }
+        let Some((weight, mut after, _)) = best(&compose_sorted(restored, linear(analysis))?)? else {
+            return Ok(None);
+        };
+
+        // Trace the best path back through the rules.
+        let mut steps = Vec::new();
+        for k in (0..rules.len()).rev() {
+            let (i, rule) = &rules[k];
+            let lost = || anyhow!("Lost the best path through rule {} of {}", i + 1, self.name);
+            let into = compose_sorted(compose_sorted(lattices[k].clone(), rule.clone())?, linear(&after))?;
+            let (_, before, _) = best(&into)?.ok_or_else(lost)?;
+            let step = compose_sorted(compose_sorted(linear(&before), rule.clone())?, linear(&after))?;
+            let (step_weight, _, _) = best(&step)?.ok_or_else(lost)?;
+            steps.push(RuleStep {
+                rule: i + 1,
+                text: lines.get(*i).cloned().unwrap_or_default(),
+                before: display(&before),
+                after: display(&after),
+                weight: step_weight,
+                cost: costs.get(i).map(|c| c.cost),
+            });
+            after = before;
+        }
+        steps.reverse();
+        Ok(Some((weight, steps)))
+    }
+}
+
+/// The rule files of a build, compiled once, so that the analyses of many
+/// words can be explained.
+pub struct Explainer {
+    symt: Arc<SymbolTable>,
+    files: Vec<ExplainedFile>,
+    /// The largest rule count of the files, which the others are padded to.
+    most: usize,
+}
+
+impl Explainer {
+    /// Compile `files` as a build with `weight_offsets` and `application`
+    /// would.
+    pub fn new(symt: Arc<SymbolTable>, files: &[PathBuf], weight_offsets: &HashMap<String, f32>, application: RuleApplication) -> Result<Self> {
+        let scripts = files.iter().map(|f| Ok((f.clone(), load_script(f)?))).collect::<Result<Vec<_>>>()?;
+        Self::from_scripts(symt, scripts, weight_offsets, application)
+    }
+
+    /// [`Explainer::new`], from scripts already loaded from the paths they are
+    /// paired with.
+    pub fn from_scripts(
+        symt: Arc<SymbolTable>,
+        scripts: Vec<(PathBuf, Script)>,
+        weight_offsets: &HashMap<String, f32>,
+        application: RuleApplication,
+    ) -> Result<Self> {
+        let mut files = Vec::new();
+        for (path, script) in scripts {
+            let name = path.display().to_string();
+            let rules = script.statements.iter().filter(|s| matches!(s, Statement::Rule(_))).count();
+            let offset = weight_offset(weight_offsets, &path, &script.config);
+            let mut checks = RuleChecks::default();
+            let compiled = match application {
+                RuleApplication::Simultaneous => CompiledFile::Simultaneous(compile_simultaneous(symt.clone(), script, &name, &mut checks)?),
+                RuleApplication::Sequential => {
+                    let costs = script.costs.clone();
+                    let CascadeRules { symt: internal, rules } = compile_cascade_rules(symt.clone(), script, &name, &mut checks)?;
+                    let source = read_script_source(&path)?;
+                    let lines = source.lines().map(str::trim).filter(|l| !l.is_empty()).map(str::to_string).collect();
+                    CompiledFile::Cascade { internal, rules, costs, lines }
+                }
+            };
+            files.push(ExplainedFile { name, rules, offset, compiled });
+        }
+        let most = files.iter().map(|f| f.rules).max().unwrap_or(0).max(1);
+        Ok(Explainer { symt, files, most })
+    }
+
+    /// The rules of each file, in order, as the file, the statement index and
+    /// the statement's text. Under simultaneous application the rules are not
+    /// separable, and there are none.
+    pub fn rules(&self) -> Vec<(&str, usize, &str)> {
+        let mut out = Vec::new();
+        for file in self.files.iter() {
+            if let CompiledFile::Cascade { rules, lines, .. } = &file.compiled {
+                out.extend(rules.iter().map(|(i, _)| (file.name.as_str(), *i, lines.get(*i).map_or("", String::as_str))));
+            }
+        }
+        out
+    }
+
+    /// The labels of `word` wrapped in boundaries, one symbol per character.
+    pub fn input_labels(&self, word: &str) -> Result<Vec<Label>> {
+        AnalysisFormat::default()
+            .wrap(word)
+            .chars()
+            .map(|c| self.symt.get_label(c.to_string()).ok_or_else(|| anyhow!("'{}' is not in the symbol table", c)))
+            .collect()
+    }
+
+    /// Explain the weight of the paths from `input` to `analysis`, which the
+    /// built FST gives `fst_weight`: of the fallback and the files, the
+    /// cheapest path explains it. `None` if nothing gives the analysis.
+    pub fn explain(&self, input: &[Label], analysis: &[Label], fst_weight: f32) -> Result<Option<Explanation>> {
+        let explanation = |file, steps, base, rules: usize, offset| Explanation {
+            input: display_labels(&self.symt, input),
+            analysis: display_labels(&self.symt, analysis),
+            fst_weight,
+            file,
+            steps,
+            base,
+            padding_steps: self.most - rules,
+            offset,
+        };
+        let mut cheapest: Option<Explanation> = None;
+        let mut consider = |candidate: Explanation| {
+            if cheapest.as_ref().is_none_or(|c| candidate.total() < c.total()) {
+                cheapest = Some(candidate);
+            }
+        };
+        if analysis == input {
+            consider(explanation(None, Vec::new(), REWEIGHT_STEP * input.len() as f32, 1, 0.0));
+        }
+        for file in self.files.iter() {
+            if let Some((base, steps)) = file.explain(input, analysis)? {
+                consider(explanation(Some(file.name.clone()), steps, base, file.rules, file.offset));
+            }
+        }
+        Ok(cheapest)
+    }
+}
+
+/// Explain the weight of the best analysis of `word` in `fst`, built from
+/// `files` with `weight_offsets` and `application`; `None` if the word has no
+/// analysis.
+pub fn explain_weights(
+    symt: Arc<SymbolTable>,
+    fst: &VectorFst<TropicalWeight>,
+    files: &[PathBuf],
+    weight_offsets: &HashMap<String, f32>,
+    application: RuleApplication,
+    word: &str,
+) -> Result<Option<Explanation>> {
+    let explainer = Explainer::new(symt, files, weight_offsets, application)?;
+    let input = explainer.input_labels(word)?;
+    let Some((fst_weight, _, analysis)) = best(&compose_sorted(linear(&input), fst.clone())?)? else {
+        return Ok(None);
+    };
+    let explanation = explainer.explain(&input, &analysis, fst_weight)?;
+    explanation.map(Some).ok_or_else(|| anyhow!("No rule file explains the analysis of {}", AnalysisFormat::default().wrap(word)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    use crate::build::build_from_rule_files;
+    use crate::testutil::TempDir;
+
+    fn write(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_breakdown_adds_up_to_the_fst_weight() {
+        let dir = TempDir::new("explain");
+        let files = [
+            write(&dir, "feed.txt", "a -> b / _ c\nc -> d / b _ :: 1\n"),
+            write(&dir, "other.txt", "d -> a / _ \n"),
+        ];
+        let symt = Arc::new(rustfst::symt!["#", "a", "b", "c", "d"]);
+        let build = |offsets: &HashMap<String, f32>| {
+            build_from_rule_files(symt.clone(), &files, offsets, Default::default(), Default::default(), None, None, &mut RuleChecks::default()).unwrap()
+        };
+        let explain = |fst: &VectorFst<TropicalWeight>, offsets: &HashMap<String, f32>, word: &str| {
+            let explanation = explain_weights(symt.clone(), fst, &files, offsets, RuleApplication::Sequential, word).unwrap().unwrap();
+            assert!((explanation.total() - explanation.fst_weight).abs() < 1e-3, "{}", explanation.render());
+            explanation
+        };
+
+        let offsets = HashMap::from([("other.txt".to_string(), 20.0)]);
+        let explanation = explain(&build(&offsets), &offsets, "ac");
+        assert_eq!(explanation.file.as_deref(), Some(files[0].display().to_string().as_str()));
+        assert_eq!(explanation.analysis, "#bd#");
+        let steps = &explanation.steps;
+        assert_eq!(steps.iter().map(|s| s.rule).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!((steps[0].before.as_str(), steps[0].after.as_str()), ("#ac#", "#bc#"));
+        assert_eq!((steps[1].before.as_str(), steps[1].after.as_str()), ("#bc#", "#bd#"));
+        assert_eq!(steps[1].hops(), steps[1].weight - 1.0);
+        assert_eq!(steps.iter().map(|s| s.weight).sum::<f32>(), explanation.base);
+        assert!(explanation.render().contains("+ cost 1"));
+
+        // The file with fewer rules is padded to the other's rule count.
+        let explanation = explain(&build(&HashMap::new()), &HashMap::new(), "d");
+        assert_eq!(explanation.analysis, "#a#");
+        assert_eq!((explanation.padding_steps, explanation.offset), (1, 0.0));
+    }
+}
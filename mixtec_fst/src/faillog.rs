@@ -0,0 +1,217 @@
+//! The log a `test` run keeps of the items that did not pass (`log.txt` by
+//! default), as text lines or as JSON lines whose fields are those of the
+//! items of the JSON report.
+//!
+//! Items are logged by the loop that checks them, one at a time, so the log
+//! is in the order of the test file whatever `--timeout` runs the checks on.
+//! A log that is appended to (`--log-append`) starts each run with a header
+//! naming its time, the FST and its provenance, and the command line.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::provenance::{summary_header, utc_now};
+use crate::report::{Outcome, RunInfo};
+use crate::status::internal;
+
+pub const DEFAULT_LOG: &str = "log.txt";
+
+/// How the log is written (`--log-format`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// One line per item, as the run prints it.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Jsonl,
+}
+
+/// Which way an item was checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// From the input to the form.
+    Forward,
+    /// From the form back to the input (`--both-directions`).
+    Reverse,
+}
+
+impl Direction {
+    fn arrow(self) -> &'static str {
+        match self {
+            Direction::Forward => "->",
+            Direction::Reverse => "<-",
+        }
+    }
+}
+
+/// One checked item, with what the run says about it beyond its outcome
+/// (such as the time limit it ran out of, or the best surface it got).
+#[derive(Debug, Clone, Copy)]
+pub struct LogItem<'a> {
+    pub direction: Direction,
+    pub input: &'a str,
+    pub form: &'a str,
+    pub outcome: Outcome,
+    /// Appended as is to the text line, so it starts with its separator.
+    pub detail: &'a str,
+}
+
+impl LogItem<'_> {
+    /// The item as the run prints it and the text log keeps it.
+    pub fn line(&self) -> String {
+        format!("{} {} {} {}{}", self.input, self.direction.arrow(), self.form, self.outcome.label(), self.detail)
+    }
+}
+
+#[derive(serde::Serialize)]
+struct JsonItem<'a> {
+    direction: Direction,
+    input: &'a str,
+    form: &'a str,
+    outcome: Outcome,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    detail: &'a str,
+}
+
+#[derive(serde::Serialize)]
+struct JsonHeader<'a> {
+    started_at: &'a str,
+    command: &'a [String],
+    run: &'a RunInfo,
+}
+
+pub struct FailureLog {
+    file: File,
+    format: LogFormat,
+}
+
+impl FailureLog {
+    /// Open the log at `path`, replacing it, or with `append` adding to it
+    /// after a header for `run`, started with `command`.
+    pub fn open(path: &Path, format: LogFormat, append: bool, run: &RunInfo, command: &[String]) -> Result<Self> {
+        let file = if append {
+            OpenOptions::new().create(true).append(true).open(path)
+        } else {
+            File::create(path)
+        };
+        let file = file.with_context(|| format!("Failed to open the log {}", path.display()))?;
+        let mut log = FailureLog { file, format };
+        if append {
+            log.header(run, command)?;
+        }
+        Ok(log)
+    }
+
+    fn header(&mut self, run: &RunInfo, command: &[String]) -> Result<()> {
+        let started_at = utc_now();
+        match self.format {
+            LogFormat::Text => {
+                writeln!(self.file, "=== {} {}", started_at, command.join(" "))?;
+                write!(self.file, "{}", summary_header(&run.fst, run.tag.as_deref(), run.provenance.as_ref()))?;
+            }
+            LogFormat::Jsonl => {
+                internal(serde_json::to_writer(&mut self.file, &JsonHeader { started_at: &started_at, command, run }))?;
+                writeln!(self.file)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Log a checked item.
+    pub fn item(&mut self, item: &LogItem) -> Result<()> {
+        match self.format {
+            LogFormat::Text => writeln!(self.file, "{}", item.line())?,
+            LogFormat::Jsonl => {
+                let detail = item.detail.trim_start_matches([' ', ':', '|']);
+                let json = JsonItem { direction: item.direction, input: item.input, form: item.form, outcome: item.outcome, detail };
+                internal(serde_json::to_writer(&mut self.file, &json))?;
+                writeln!(self.file)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Log a word of `test --assert-accepts-all` that has no analysis.
+    pub fn rejected(&mut self, word: &str, reason: &str) -> Result<()> {
+        match self.format {
+            LogFormat::Text => writeln!(self.file, "{} NO ANALYSIS ({})", word, reason)?,
+            LogFormat::Jsonl => {
+                internal(serde_json::to_writer(&mut self.file, &serde_json::json!({ "input": word, "outcome": "no_analysis", "detail": reason })))?;
+                writeln!(self.file)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provenance::Provenance;
+    use crate::testutil::TempDir;
+
+    fn run() -> RunInfo {
+        let provenance = Provenance::parse("version=0.1.0\nbuilt_at=2026-10-13T00:00:00Z\nvariant=\nrule_file=00ff rules/a.txt\n");
+        RunInfo { fst: "out.fst".to_string(), tag: Some("nightly".to_string()), provenance: Some(provenance) }
+    }
+
+    fn command() -> Vec<String> {
+        ["mixtec_fst", "test", "out.fst", "-t", "gold.csv"].map(String::from).to_vec()
+    }
+
+    fn failed<'a>(input: &'a str, detail: &'a str) -> LogItem<'a> {
+        LogItem { direction: Direction::Forward, input, form: "b", outcome: Outcome::Fail, detail }
+    }
+
+    #[test]
+    fn test_appended_runs_each_get_a_header() {
+        let dir = TempDir::new("faillog-append");
+        let path = dir.join("log.txt");
+        for input in ["first", "second"] {
+            let mut log = FailureLog::open(&path, LogFormat::Text, true, &run(), &command()).unwrap();
+            log.item(&failed(input, " after 5s")).unwrap();
+        }
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        let headers: Vec<&&str> = lines.iter().filter(|l| l.starts_with("=== ")).collect();
+        assert_eq!(headers.len(), 2);
+        assert!(headers[0].ends_with("Z mixtec_fst test out.fst -t gold.csv"), "{}", headers[0]);
+        assert!(text.contains("FST out.fst [nightly]\n  built 2026-10-13T00:00:00Z by mixtec_fst 0.1.0, variant: default options\n"), "{}", text);
+        assert!(text.contains("  rule file rules/a.txt (00ff)\n"));
+        let items: Vec<&&str> = lines.iter().filter(|l| l.contains(" -> ")).collect();
+        assert_eq!(items, [&"first -> b FAILED after 5s", &"second -> b FAILED after 5s"]);
+
+        // Without --log-append, a run replaces the log, and has no header.
+        let mut log = FailureLog::open(&path, LogFormat::Text, false, &run(), &command()).unwrap();
+        log.rejected("third", "no analysis").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "third NO ANALYSIS (no analysis)\n");
+    }
+
+    #[test]
+    fn test_jsonl_lines_parse() {
+        let dir = TempDir::new("faillog-jsonl");
+        let path = dir.join("log.jsonl");
+        let mut log = FailureLog::open(&path, LogFormat::Jsonl, true, &run(), &command()).unwrap();
+        log.item(&failed("a", "")).unwrap();
+        let reverse = LogItem { direction: Direction::Reverse, input: "c", form: "d", outcome: Outcome::XFail, detail: ": best surface e (3)" };
+        log.item(&reverse).unwrap();
+        log.rejected("f", "no analysis").unwrap();
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["run"]["fst"], "out.fst");
+        assert_eq!(lines[0]["run"]["provenance"]["rule_files"][0]["hash"], "00ff");
+        assert_eq!(lines[0]["command"][1], "test");
+        assert_eq!(lines[1], serde_json::json!({"direction": "forward", "input": "a", "form": "b", "outcome": "fail"}));
+        assert_eq!(lines[2]["outcome"], "xfail");
+        assert_eq!(lines[2]["detail"], "best surface e (3)");
+        assert_eq!(lines[3]["outcome"], "no_analysis");
+    }
+}
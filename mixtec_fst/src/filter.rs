@@ -0,0 +1,201 @@
+//! Constraining the analyses of a word with a filter acceptor (`--filter`).
+//!
+//! A filter is either an FST read from a file, used as an acceptor over the
+//! output alphabet, or a regular expression in rule syntax that must match a
+//! whole analysis (without its word boundaries). It is composed onto the output
+//! side of each word's lattice before the best analyses are extracted, so that
+//! only analyses it accepts are listed.
+//!
+//! A lexicon (`--lexicon`) is a list of the analyses that are known to be
+//! words, compiled into an acceptor of exactly those and applied the same way.
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use rustfst::prelude::compose::compose;
+use rustfst::prelude::rm_epsilon::rm_epsilon;
+use rustfst::prelude::union::union;
+use rustfst::prelude::{
+    connect, tr_sort, ExpandedFst, Fst, ILabelCompare, MutableFst, OLabelCompare, StateIterator, TropicalWeight,
+    VectorFst,
+};
+use rustfst::{Label, Semiring, SymbolTable, Tr};
+
+use parserule::ruleparse::{parse_script, RegexAST, Statement};
+
+use crate::alphabet::{AnalysisAcceptor, SurfaceToAnalysisFst};
+use crate::analysis::AnalysisFormat;
+
+use crate::attribution::SourceMarkers;
+use crate::automaton::{linear_automaton_checked, Tokenization};
+use crate::cache::symt_hash;
+use crate::rewrite::{node_fst, LinearOptions};
+use crate::status::internal;
+
+/// Compile `pattern`, a regular expression in rule syntax, to an acceptor of
+/// the analyses it matches in full. Symbols missing from `symt` are an error
+/// rather than epsilon, so that a typo cannot silently widen the filter.
+pub fn compile_filter(symt: Arc<SymbolTable>, pattern: &str) -> Result<AnalysisAcceptor> {
+    let (rest, (statements, _)) = parse_script(&format!("::filter:: = {}", pattern))?;
+    let regex = match statements.as_slice() {
+        [Statement::MacroDef((_, regex))] if rest.trim().is_empty() => regex.clone(),
+        _ => bail!("Cannot parse filter '{}' as a regular expression", pattern),
+    };
+    let opts = LinearOptions { strict: true, ..Default::default() };
+    let node = RegexAST::Group(vec![RegexAST::Boundary, regex, RegexAST::Boundary]);
+    let mut fst = node_fst(symt.clone(), &HashMap::new(), opts, node)?;
+    internal(rm_epsilon(&mut fst))?;
+    fst.set_input_symbols(symt.clone());
+    fst.set_output_symbols(symt);
+    Ok(AnalysisAcceptor(fst))
+}
+
+/// Compile `entries`, analyses without their word boundaries, to an acceptor
+/// of exactly those analyses, split into symbols with `tokenization`. Symbols
+/// missing from `symt` are an error, as for [`compile_filter`].
+pub fn compile_lexicon(
+    symt: Arc<SymbolTable>,
+    entries: &[String],
+    fmt: &AnalysisFormat,
+    tokenization: Tokenization,
+) -> Result<AnalysisAcceptor> {
+    if entries.is_empty() {
+        bail!("The lexicon is empty");
+    }
+    let mut fst: VectorFst<TropicalWeight> = VectorFst::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let word = linear_automaton_checked(&symt, entry, tokenization, Some(fmt))
+            .with_context(|| format!("Lexicon entry {} '{}'", i + 1, entry))?;
+        if i == 0 {
+            fst = word;
+        } else {
+            internal(union(&mut fst, &word))?;
+        }
+    }
+    internal(rm_epsilon(&mut fst))?;
+    fst.set_input_symbols(symt.clone());
+    fst.set_output_symbols(symt);
+    Ok(AnalysisAcceptor(fst))
+}
+
+/// Renumber the labels of a filter read from a file onto `symt`. Filters with
+/// a symbol table are relabelled by symbol if it differs from `symt`; those
+/// without one must only use labels `symt` has.
+pub fn align_filter(symt: Arc<SymbolTable>, fst: &mut VectorFst<TropicalWeight>) -> Result<()> {
+    let own = fst.input_symbols().or_else(|| fst.output_symbols()).cloned();
+    let relabel = |label: Label| -> Result<Label> {
+        match &own {
+            Some(own) => {
+                let symbol = own.get_symbol(label).ok_or_else(|| anyhow!("Filter label {} has no symbol", label))?;
+                symt.get_label(symbol).ok_or_else(|| anyhow!("Filter symbol '{}' is not in the symbol table", symbol))
+            }
+            None if symt.get_symbol(label).is_some() => Ok(label),
+            None => bail!("Filter label {} is not in the symbol table", label),
+        }
+    };
+    if own.as_ref().is_none_or(|own| symt_hash(own) != symt_hash(&symt)) {
+        let states: Vec<_> = fst.states_iter().collect();
+        for s in states {
+            for mut tr in fst.pop_trs(s)? {
+                tr.ilabel = relabel(tr.ilabel)?;
+                tr.olabel = relabel(tr.olabel)?;
+                fst.add_tr(s, tr)?;
+            }
+        }
+    }
+    fst.set_input_symbols(symt.clone());
+    fst.set_output_symbols(symt);
+    Ok(())
+}
+
+/// The paths of `lattice` whose output `filter` accepts. With source markers,
+/// the filter lets every marker through, since it only constrains analyses.
+pub fn apply_filter(
+    lattice: &SurfaceToAnalysisFst,
+    filter: &AnalysisAcceptor,
+    markers: Option<&SourceMarkers>,
+) -> Result<SurfaceToAnalysisFst> {
+    let mut filter = filter.0.clone();
+    if let Some(markers) = markers {
+        let labels: BTreeSet<Label> = markers.labels().collect();
+        for s in 0..filter.num_states() as u32 {
+            for &l in labels.iter() {
+                filter.add_tr(s, Tr::new(l, l, TropicalWeight::one(), s))?;
+            }
+        }
+    }
+    tr_sort(&mut filter, ILabelCompare {});
+    let mut lattice = lattice.0.clone();
+    tr_sort(&mut lattice, OLabelCompare {});
+    let mut filtered: VectorFst<TropicalWeight> = internal(compose(lattice, filter))?;
+    internal(connect(&mut filtered))?;
+    Ok(SurfaceToAnalysisFst(filtered))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustfst::utils::transducer;
+
+    use crate::decode::{decode_distinct_outputs, display_labels};
+    use crate::ranking::Lexicographic;
+
+    fn symt() -> Arc<SymbolTable> {
+        Arc::new(rustfst::symt!["#", "n", "i", "1", "{", ">", "}"])
+    }
+
+    /// The lattice of `ni1`, with the analyses `#ni1#` and `#ni{>1}1#`.
+    fn lattice() -> SurfaceToAnalysisFst {
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 3, 4, 1 => 1, 2, 3, 4, 1; 73.0];
+        let other: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 3, 4, 1 => 1, 2, 3, 5, 6, 4, 7, 4, 1; 96.0];
+        union(&mut fst, &other).unwrap();
+        SurfaceToAnalysisFst(fst)
+    }
+
+    fn analyses(fst: &VectorFst<TropicalWeight>) -> Vec<String> {
+        let symt = symt();
+        decode_distinct_outputs(fst, None, &Lexicographic, |l| display_labels(&symt, l)).unwrap().into_iter().map(|(_, o)| o).collect()
+    }
+
+    #[test]
+    fn test_filter_keeps_one_of_two_analyses() {
+        assert_eq!(analyses(&lattice()), ["#ni1#", "#ni{>1}1#"]);
+        let filter = compile_filter(symt(), r"ni{\>1}1").unwrap();
+        assert_eq!(analyses(&apply_filter(&lattice(), &filter, None).unwrap()), ["#ni{>1}1#"]);
+        let filter = compile_filter(symt(), "n[i1]*").unwrap();
+        assert_eq!(analyses(&apply_filter(&lattice(), &filter, None).unwrap()), ["#ni1#"]);
+        let filter = compile_filter(symt(), "i*").unwrap();
+        assert!(analyses(&apply_filter(&lattice(), &filter, None).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_filter_with_unknown_symbol_is_an_error() {
+        assert!(compile_filter(symt(), "nix").is_err());
+    }
+
+    #[test]
+    fn test_filter_from_other_symbol_table_is_relabelled() {
+        let other = Arc::new(rustfst::symt!["}", ">", "{", "1", "i", "n", "#"]);
+        let mut filter = compile_filter(other, r"ni{\>1}1").unwrap();
+        align_filter(symt(), &mut filter.0).unwrap();
+        assert_eq!(analyses(&apply_filter(&lattice(), &filter, None).unwrap()), ["#ni{>1}1#"]);
+
+        let mut unknown = compile_filter(Arc::new(rustfst::symt!["#", "x"]), "x").unwrap();
+        let err = align_filter(symt(), &mut unknown.0).unwrap_err().to_string();
+        assert!(err.contains("'x'"), "{}", err);
+    }
+
+    #[test]
+    fn test_lexicon_keeps_listed_analyses() {
+        let fmt = AnalysisFormat::default();
+        let words = |ws: &[&str]| ws.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        let lexicon = compile_lexicon(symt(), &words(&["ni{>1}1", "n"]), &fmt, Tokenization::default()).unwrap();
+        assert_eq!(analyses(&apply_filter(&lattice(), &lexicon, None).unwrap()), ["#ni{>1}1#"]);
+        let lexicon = compile_lexicon(symt(), &words(&["ni"]), &fmt, Tokenization::default()).unwrap();
+        assert!(analyses(&apply_filter(&lattice(), &lexicon, None).unwrap()).is_empty());
+        let err = compile_lexicon(symt(), &words(&["ni1", "nix"]), &fmt, Tokenization::default()).unwrap_err().to_string();
+        assert!(err.contains("entry 2"), "{}", err);
+    }
+}
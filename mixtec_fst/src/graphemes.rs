@@ -0,0 +1,196 @@
+//! Mapping input graphemes onto symbol-table symbols.
+//!
+//! Source texts do not always spell things the way the symbol table does:
+//! ligatures stand for two symbols, and some characters have no symbol of
+//! their own. A grapheme map is a CSV file with a `grapheme` and a `symbols`
+//! column, the latter a space-separated sequence of symbols, e.g.
+//!
+//! ```text
+//! grapheme,symbols
+//! æ,a e
+//! ```
+//!
+//! Inputs are NFD-normalized, then tokenized by longest match against the
+//! mapped graphemes and the symbols themselves, before they are wrapped in
+//! word boundaries. An FST built with `--normalizer-fst` does all of that
+//! itself (see [`crate::normalizer`]), and is given its inputs as written.
+
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use parserule::normalize::nfd_normalize;
+use rustfst::SymbolTable;
+
+use crate::encoding::{read_text, TextEncoding};
+
+#[derive(Debug, serde::Deserialize)]
+struct MappingEntry {
+    grapheme: String,
+    symbols: String,
+}
+
+/// Graphemes to rewrite as sequences of symbols before an input is tokenized.
+#[derive(Debug, Clone, Default)]
+pub struct GraphemeMap {
+    /// NFD-normalized grapheme and its symbols, longest grapheme first.
+    entries: Vec<(String, Vec<String>)>,
+    /// Leave inputs as written, for an FST that normalizes them itself.
+    verbatim: bool,
+}
+
+impl GraphemeMap {
+    pub fn new<I: IntoIterator<Item = (String, Vec<String>)>>(entries: I) -> Self {
+        let mut entries: Vec<_> = entries
+            .into_iter()
+            .map(|(g, syms)| (nfd_normalize(&g), syms.iter().map(|s| nfd_normalize(s)).collect()))
+            .collect();
+        entries.sort_by_key(|(g, _): &(String, Vec<String>)| std::cmp::Reverse(g.chars().count()));
+        GraphemeMap { entries, verbatim: false }
+    }
+
+    /// The map of an FST that embeds its normalizer: [`GraphemeMap::apply`]
+    /// leaves inputs as written.
+    pub fn verbatim() -> Self {
+        GraphemeMap { entries: Vec::new(), verbatim: true }
+    }
+
+    /// The NFD-normalized graphemes and their symbols, longest grapheme first.
+    pub fn entries(&self) -> &[(String, Vec<String>)] {
+        &self.entries
+    }
+
+    /// Read a grapheme map from a CSV file, detecting its encoding unless given.
+    pub fn read(path: &Path, encoding: Option<TextEncoding>) -> Result<Self> {
+        let text = read_text(path, encoding)?;
+        let mut reader = csv::Reader::from_reader(text.as_bytes());
+        let mut entries = Vec::new();
+        for r in reader.deserialize() {
+            let entry: MappingEntry = r.map_err(|e| anyhow!("Failed to read grapheme map {}: {}", path.display(), e))?;
+            if entry.grapheme.is_empty() {
+                bail!("Empty grapheme in grapheme map {}", path.display());
+            }
+            entries.push((entry.grapheme, entry.symbols.split_whitespace().map(String::from).collect()));
+        }
+        Ok(GraphemeMap::new(entries))
+    }
+
+    /// Check that every symbol the map produces is in `symt`.
+    pub fn validate(&self, symt: &SymbolTable) -> Result<()> {
+        for (grapheme, symbols) in self.entries.iter() {
+            if let Some(s) = symbols.iter().find(|s| symt.get_label(s.as_str()).is_none()) {
+                bail!("Grapheme map entry '{}' maps to '{}', which is not in the symbol table", grapheme, s);
+            }
+        }
+        Ok(())
+    }
+
+    /// The symbols of `input`, after NFD normalization and grapheme mapping.
+    /// Fails on the first character that is neither mapped nor a symbol.
+    pub fn tokenize(&self, symt: &SymbolTable, input: &str) -> Result<Vec<String>> {
+        let input = nfd_normalize(input);
+        let max_symbol_len = symt.iter().map(|(_, s)| s.chars().count()).max().unwrap_or(1);
+        let mut symbols = Vec::new();
+        let mut rest = input.as_str();
+        'outer: while !rest.is_empty() {
+            for (grapheme, mapped) in self.entries.iter() {
+                if let Some(r) = rest.strip_prefix(grapheme.as_str()) {
+                    symbols.extend(mapped.iter().cloned());
+                    rest = r;
+                    continue 'outer;
+                }
+            }
+            let ends = rest.char_indices().map(|(i, _)| i).skip(1).chain([rest.len()]);
+            let prefixes: Vec<usize> = ends.take(max_symbol_len).collect();
+            for &end in prefixes.iter().rev() {
+                if symt.get_label(&rest[..end]).is_some() {
+                    symbols.push(rest[..end].to_string());
+                    rest = &rest[end..];
+                    continue 'outer;
+                }
+            }
+            let c = rest.chars().next().unwrap_or_default();
+            bail!(
+                "Input '{}' contains '{}' (U+{:04X}), which is not in the symbol table; \
+                 add a mapping for it to the grapheme map (--graphemes)",
+                input,
+                c,
+                c as u32
+            );
+        }
+        Ok(symbols)
+    }
+
+    /// `input` rewritten as the concatenation of its symbols, or as written
+    /// by a [`GraphemeMap::verbatim`] map.
+    pub fn apply(&self, symt: &SymbolTable, input: &str) -> Result<String> {
+        if self.verbatim {
+            return Ok(input.to_string());
+        }
+        Ok(self.tokenize(symt, input)?.concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symt() -> SymbolTable {
+        let mut symt = rustfst::symt!["#", "a", "e", "n", "1", "4"];
+        symt.add_symbol(nfd_normalize("ñ"));
+        symt
+    }
+
+    #[test]
+    fn test_tokenize_ligature_to_two_symbols() {
+        let map = GraphemeMap::new([("æ".to_string(), vec!["a".to_string(), "e".to_string()])]);
+        map.validate(&symt()).unwrap();
+        assert_eq!(map.tokenize(&symt(), "næ4").unwrap(), ["n", "a", "e", "4"]);
+        assert_eq!(map.apply(&symt(), "næ4").unwrap(), "nae4");
+    }
+
+    #[test]
+    fn test_tokenize_precomposed_matches_decomposed_symbol() {
+        let map = GraphemeMap::default();
+        let nye = nfd_normalize("ñ");
+        assert_eq!(map.tokenize(&symt(), "\u{f1}a1").unwrap(), [nye.as_str(), "a", "1"]);
+        assert_eq!(map.tokenize(&symt(), "n\u{303}a1").unwrap(), [nye.as_str(), "a", "1"]);
+    }
+
+    #[test]
+    fn test_tokenize_reports_unmapped_character() {
+        let err = GraphemeMap::default().tokenize(&symt(), "næ4").unwrap_err().to_string();
+        assert!(err.contains("'æ'") && err.contains("--graphemes"), "{}", err);
+    }
+
+    #[test]
+    fn test_mapped_input_analyses_like_hand_expanded_input() {
+        use std::sync::Arc;
+        use parserule::{rulefst, ruleparse};
+        use crate::alphabet::SurfaceToAnalysisFst;
+    use crate::analysis::AnalysisFormat;
+        use crate::check::accepts_pair;
+        use crate::prepared::PreparedFst;
+
+        let symt = Arc::new(symt());
+        let script = ruleparse::parse_script("a -> e / n _ e\n").unwrap().1 .0;
+        let fst = rulefst::compile_script(symt.clone(), script).unwrap();
+        let prepared = PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap();
+        let map = GraphemeMap::new([("æ".to_string(), vec!["a".to_string(), "e".to_string()])]);
+        let mapped = map.apply(&symt, "næ4").unwrap();
+        for output in ["nee4", "nae4"] {
+            assert_eq!(
+                accepts_pair(&prepared, &mapped, output).unwrap(),
+                accepts_pair(&prepared, "nae4", output).unwrap(),
+                "{}",
+                output
+            );
+        }
+        assert!(accepts_pair(&prepared, &mapped, "nee4").unwrap());
+    }
+
+    #[test]
+    fn test_validate_rejects_unknown_symbols() {
+        let map = GraphemeMap::new([("æ".to_string(), vec!["a".to_string(), "z".to_string()])]);
+        assert!(map.validate(&symt()).is_err());
+    }
+}
@@ -0,0 +1,370 @@
+//! The test report as a single HTML file (`test --report-html`), for reading
+//! the failures of a run in a browser.
+//!
+//! The page is filled in from [`report.html`](../report.html), from the same
+//! reports as the JSON one: the run and the summaries at the top, then a table
+//! of every item that did not pass, sortable by any column and filterable by
+//! text and by why it failed. Each failure of the forward direction shows its
+//! best prediction aligned with its gold form symbol by symbol, as
+//! `--partial-credit` aligns them (see [`crate::align`]). The table stops at a
+//! number of rows; past it, every failure is written to a CSV file next to the
+//! page, which links to it.
+
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::Result;
+use rustfst::SymbolTable;
+
+use crate::align::{symbol_edits, Costs, Edit};
+use crate::artifact::{create_atomic, write_file_atomic};
+use crate::automaton::Tokenization;
+use crate::provenance::summary_header;
+use crate::report::{ItemResult, Outcome, Prediction, RunInfo, TestReport};
+use crate::tones::ToneSet;
+
+const TEMPLATE: &str = include_str!("report.html");
+
+/// How predictions are aligned with gold forms.
+#[derive(Debug, Clone, Copy)]
+pub struct Aligner<'a> {
+    pub symt: &'a SymbolTable,
+    pub tokenization: Tokenization,
+    pub tones: &'a ToneSet,
+    pub costs: Costs,
+}
+
+/// Why an item did not pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    /// The FST has no analysis of the input.
+    NoResult,
+    /// The best prediction only gets tones wrong.
+    Tone,
+    /// The best prediction only gets segments wrong.
+    Segment,
+    /// The best prediction gets both wrong.
+    ToneAndSegment,
+    /// The best prediction is the gold form, but the check still failed, as
+    /// when the gold form only ties for the best.
+    Ranking,
+    /// The item failed, but its prediction was not computed, as in the
+    /// reverse direction.
+    Unpredicted,
+    Timeout,
+    Unproducible,
+    TooLong,
+    TooComplex,
+}
+
+impl Category {
+    const ALL: [Category; 10] = [
+        Category::NoResult,
+        Category::Tone,
+        Category::Segment,
+        Category::ToneAndSegment,
+        Category::Ranking,
+        Category::Unpredicted,
+        Category::Timeout,
+        Category::Unproducible,
+        Category::TooLong,
+        Category::TooComplex,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Category::NoResult => "no result",
+            Category::Tone => "tone",
+            Category::Segment => "segment",
+            Category::ToneAndSegment => "tone and segment",
+            Category::Ranking => "ranking",
+            Category::Unpredicted => "not predicted",
+            Category::Timeout => "timeout",
+            Category::Unproducible => "unproducible gold",
+            Category::TooLong => "input too long",
+            Category::TooComplex => "too complex",
+        }
+    }
+
+    /// The category of a failure of `outcome` whose best prediction aligns
+    /// with its gold form by `edits`, if it was computed.
+    fn of(outcome: Outcome, prediction: Option<&Option<Prediction>>, edits: &[Edit<String>], tones: &ToneSet) -> Self {
+        match outcome {
+            Outcome::Timeout => return Category::Timeout,
+            Outcome::Unproducible => return Category::Unproducible,
+            Outcome::TooLong => return Category::TooLong,
+            Outcome::TooComplex => return Category::TooComplex,
+            _ => {}
+        }
+        match prediction {
+            None => Category::Unpredicted,
+            Some(None) => Category::NoResult,
+            Some(Some(_)) => {
+                let (mut tone, mut segment) = (false, false);
+                for edit in edits {
+                    match edit {
+                        Edit::Match(_) => continue,
+                        Edit::Substitute(s, _) | Edit::Extra(s) | Edit::Missing(s) => match tones.is_tone_symbol(s) {
+                            true => tone = true,
+                            false => segment = true,
+                        },
+                    }
+                }
+                match (tone, segment) {
+                    (false, false) => Category::Ranking,
+                    (true, false) => Category::Tone,
+                    (false, true) => Category::Segment,
+                    (true, true) => Category::ToneAndSegment,
+                }
+            }
+        }
+    }
+}
+
+/// An item that did not pass, as the table shows it.
+struct Failure<'a> {
+    direction: &'static str,
+    item: &'a ItemResult,
+    edits: Vec<Edit<String>>,
+    category: Category,
+}
+
+impl<'a> Failure<'a> {
+    fn of(direction: &'static str, item: &'a ItemResult, aligner: &Aligner) -> Self {
+        let edits = match &item.prediction {
+            Some(Some(p)) => symbol_edits(aligner.symt, &p.form, &item.form, aligner.tokenization, aligner.tones, aligner.costs),
+            _ => Vec::new(),
+        };
+        let category = Category::of(item.outcome, item.prediction.as_ref(), &edits, aligner.tones);
+        Failure { direction, item, edits, category }
+    }
+
+    fn prediction(&self) -> Option<&Prediction> {
+        self.item.prediction.as_ref().and_then(Option::as_ref)
+    }
+
+    /// The alignment as text: matches as they are, a substitution as
+    /// `predicted:gold`, an extra symbol as `+symbol` and a missing one as
+    /// `-symbol`.
+    fn alignment_text(&self) -> String {
+        let edits = self.edits.iter().map(|edit| match edit {
+            Edit::Match(s) => s.clone(),
+            Edit::Substitute(p, g) => format!("{}:{}", p, g),
+            Edit::Extra(s) => format!("+{}", s),
+            Edit::Missing(s) => format!("-{}", s),
+        });
+        edits.collect::<Vec<_>>().join(" ")
+    }
+
+    fn alignment_html(&self) -> String {
+        let mut html = String::new();
+        for edit in &self.edits {
+            let _ = match edit {
+                Edit::Match(s) => write!(html, r#"<span class="match">{}</span>"#, escape(s)),
+                Edit::Substitute(p, g) => write!(html, r#"<span class="sub" title="for {}">{}</span>"#, escape(g), escape(p)),
+                Edit::Extra(s) => write!(html, r#"<span class="extra">{}</span>"#, escape(s)),
+                Edit::Missing(s) => write!(html, r#"<span class="missing">{}</span>"#, escape(s)),
+            };
+        }
+        html
+    }
+
+    fn row(&self) -> String {
+        let prediction = self.prediction();
+        let (weight, margin) = match prediction {
+            Some(p) => (p.weight.to_string(), p.margin.map_or(String::new(), |m| m.to_string())),
+            None => (String::new(), String::new()),
+        };
+        let predicted = match &self.item.prediction {
+            Some(Some(p)) => escape(&p.form),
+            Some(None) => "<em>no result</em>".to_string(),
+            None => String::new(),
+        };
+        format!(
+            "<tr data-category=\"{category}\"><td>{}</td><td class=\"form\">{}</td><td class=\"form\">{}</td><td class=\"form\">{}</td>\
+             <td>{}</td><td>{}</td><td class=\"form\">{}</td><td>{}</td><td>{category}</td></tr>",
+            escape(self.direction),
+            escape(&self.item.input),
+            escape(&self.item.form),
+            predicted,
+            weight,
+            margin,
+            self.alignment_html(),
+            escape(self.item.outcome.label()),
+            category = self.category.label(),
+        )
+    }
+
+    fn record(&self) -> [String; 9] {
+        let prediction = self.prediction();
+        [
+            self.direction.to_string(),
+            self.item.input.clone(),
+            self.item.form.clone(),
+            prediction.map_or(String::new(), |p| p.form.clone()),
+            prediction.map_or(String::new(), |p| p.weight.to_string()),
+            prediction.and_then(|p| p.margin).map_or(String::new(), |m| m.to_string()),
+            self.alignment_text(),
+            self.item.outcome.label().to_string(),
+            self.category.label().to_string(),
+        ]
+    }
+}
+
+/// The items of the reports that did not pass, forward ones first.
+fn failures<'a>(forward: &'a TestReport, reverse: Option<&'a TestReport>, aligner: &Aligner) -> Vec<Failure<'a>> {
+    let reports = [("->", Some(forward)), ("<-", reverse)];
+    let items = reports.into_iter().flat_map(|(direction, report)| report.into_iter().flat_map(move |r| r.items.iter().map(move |item| (direction, item))));
+    items
+        .filter(|(_, item)| !matches!(item.outcome, Outcome::Pass | Outcome::XPass))
+        .map(|(direction, item)| Failure::of(direction, item, aligner))
+        .collect()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// `template` with each `{{name}}` replaced by its value.
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    values.iter().fold(template.to_string(), |page, (name, value)| page.replace(&format!("{{{{{}}}}}", name), value))
+}
+
+/// The page for the run and its reports, showing at most `max_rows` failures;
+/// past them, it links to `full_csv`.
+fn render(run: &RunInfo, forward: &TestReport, reverse: Option<&TestReport>, aligner: &Aligner, max_rows: usize, full_csv: &str) -> String {
+    let failures = failures(forward, reverse, aligner);
+    let mut summary = String::new();
+    for (name, report) in [("forward (input -> form)", Some(forward)), ("reverse (form -> input)", reverse)] {
+        if let Some(report) = report {
+            let _ = write!(summary, "<tr><th>{}</th><td>{}</td></tr>", name, escape(&report.summary()));
+        }
+    }
+    if let Some(mean) = &forward.partial_credit {
+        let _ = write!(summary, "<tr><th>partial credit</th><td>{}</td></tr>", escape(&mean.summary()));
+    }
+    let categories: String = Category::ALL.iter().map(|c| format!("<option>{}</option>", c.label())).collect();
+    let note = match failures.len() > max_rows {
+        true => format!(
+            "<p class=\"note\">Showing the first {} of {} failures; every one is in <a href=\"{}\">{}</a>.</p>",
+            max_rows,
+            failures.len(),
+            escape(full_csv),
+            escape(full_csv)
+        ),
+        false => String::new(),
+    };
+    let rows: Vec<_> = failures.iter().take(max_rows).map(Failure::row).collect();
+    let title = format!("Test report: {}", run.fst);
+    let header = summary_header(&run.fst, run.tag.as_deref(), run.provenance.as_ref());
+    fill(
+        TEMPLATE,
+        &[
+            ("title", &escape(&title)),
+            ("run", &escape(header.trim_end())),
+            ("summary", &summary),
+            ("categories", &categories),
+            ("note", &note),
+            ("rows", &rows.join("\n")),
+        ],
+    )
+}
+
+/// Write the run and its reports to `path` as HTML, with at most `max_rows`
+/// failures in the table; if there are more, every failure is also written
+/// to a CSV file with the same name, which the page links to.
+pub fn write_html_report(path: &Path, run: &RunInfo, forward: &TestReport, reverse: Option<&TestReport>, aligner: &Aligner, max_rows: usize) -> Result<()> {
+    let csv_path = path.with_extension("csv");
+    let csv_name = csv_path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+    write_file_atomic(path, render(run, forward, reverse, aligner, max_rows, &csv_name))?;
+    let failures = failures(forward, reverse, aligner);
+    if failures.len() > max_rows {
+        create_atomic(&csv_path, |file| {
+            let mut writer = csv::Writer::from_writer(file);
+            writer.write_record(["direction", "input", "gold", "prediction", "weight", "margin", "alignment", "outcome", "category"])?;
+            for failure in &failures {
+                writer.write_record(failure.record())?;
+            }
+            writer.flush()?;
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn symt() -> SymbolTable {
+        rustfst::symt!["#", "k", "a", "i", "t", "ch", "1", "3"]
+    }
+
+    fn fixture() -> TestReport {
+        let predicted = |form: &str, weight, margin| Some(Some(Prediction { form: form.to_string(), weight, margin }));
+        let mut report = TestReport::default();
+        report.record("ta1", "ta1", false, true);
+        report.record_checked("ka1", "ka1", false, false, None, predicted("ka3", 2.0, Some(0.5)));
+        report.record_checked("chi", "cha1", true, false, None, predicted("ki", 3.0, None));
+        report.record_checked("xa", "ka1", false, false, None, Some(None));
+        report.record_timeout("kaka", "ka1ka1");
+        report
+    }
+
+    fn without_whitespace(s: &str) -> String {
+        s.chars().filter(|c| !c.is_whitespace()).collect()
+    }
+
+    fn tbody(page: &str) -> &str {
+        let start = page.find("<tbody>").unwrap();
+        &page[start..page.find("</tbody>").unwrap() + "</tbody>".len()]
+    }
+
+    #[test]
+    fn test_failure_table_snapshot() {
+        let symt = symt();
+        let aligner = Aligner { symt: &symt, tokenization: Tokenization::default(), tones: &ToneSet::default(), costs: Costs::default() };
+        let page = render(&RunInfo { fst: "out.fst".to_string(), ..RunInfo::default() }, &fixture(), None, &aligner, 10, "report.csv");
+        let expected = r#"
+            <tbody>
+            <tr data-category="tone"><td>-&gt;</td><td class="form">ka1</td><td class="form">ka1</td><td class="form">ka3</td><td>2</td><td>0.5</td>
+              <td class="form"><span class="match">k</span><span class="match">a</span><span class="sub" title="for 1">3</span></td>
+              <td>FAILED</td><td>tone</td></tr>
+            <tr data-category="tone and segment"><td>-&gt;</td><td class="form">chi</td><td class="form">cha1</td><td class="form">ki</td><td>3</td><td></td>
+              <td class="form"><span class="sub" title="for ch">k</span><span class="sub" title="for a">i</span><span class="missing">1</span></td>
+              <td>FAILED (expected)</td><td>tone and segment</td></tr>
+            <tr data-category="no result"><td>-&gt;</td><td class="form">xa</td><td class="form">ka1</td><td class="form"><em>no result</em></td><td></td><td></td>
+              <td class="form"></td><td>FAILED</td><td>no result</td></tr>
+            <tr data-category="timeout"><td>-&gt;</td><td class="form">kaka</td><td class="form">ka1ka1</td><td class="form"></td><td></td><td></td>
+              <td class="form"></td><td>TIMED OUT</td><td>timeout</td></tr>
+            </tbody>
+        "#;
+        assert_eq!(without_whitespace(tbody(&page)), without_whitespace(expected));
+        assert!(page.contains("<title>Test report: out.fst</title>"));
+        assert!(page.contains("1/5 passed (20.0%)"), "{}", page);
+        assert!(!page.contains("{{"));
+        assert!(!page.contains("class=\"note\""));
+    }
+
+    #[test]
+    fn test_large_runs_are_capped_and_written_in_full_to_csv() {
+        let symt = symt();
+        let aligner = Aligner { symt: &symt, tokenization: Tokenization::default(), tones: &ToneSet::default(), costs: Costs::default() };
+        let dir = TempDir::new("html-report");
+        let path = dir.join("report.html");
+        write_html_report(&path, &RunInfo::default(), &fixture(), None, &aligner, 2).unwrap();
+        let page = std::fs::read_to_string(&path).unwrap();
+        let csv = std::fs::read_to_string(dir.join("report.csv")).unwrap();
+        assert_eq!(tbody(&page).matches("<tr").count(), 2);
+        assert!(page.contains(r#"Showing the first 2 of 4 failures; every one is in <a href="report.csv">report.csv</a>."#));
+        assert_eq!(
+            csv,
+            "direction,input,gold,prediction,weight,margin,alignment,outcome,category\n\
+             ->,ka1,ka1,ka3,2,0.5,k a 3:1,FAILED,tone\n\
+             ->,chi,cha1,ki,3,,k:ch i:a -1,FAILED (expected),tone and segment\n\
+             ->,xa,ka1,,,,,FAILED,no result\n\
+             ->,kaka,ka1ka1,,,,,TIMED OUT,timeout\n"
+        );
+    }
+}
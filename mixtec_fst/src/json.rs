@@ -0,0 +1,156 @@
+//! A JSON rendering of an FST, for web tooling.
+//!
+//! States are listed in order, each with its final weight (`null` if not final)
+//! and transitions; symbol tables are arrays of symbols indexed by label. The
+//! rendering is lossless, so [`JsonFst::to_fst`] gives back the original FST.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use rustfst::prelude::{CoreFst, ExpandedFst, Fst, MutableFst, StateIterator, TropicalWeight, VectorFst};
+use rustfst::{Label, Semiring, StateId, SymbolTable, Tr};
+
+use crate::artifact::{create_atomic, truncated};
+use crate::status::internal;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct JsonTr {
+    pub ilabel: Label,
+    pub olabel: Label,
+    pub weight: f32,
+    pub next: StateId,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct JsonState {
+    #[serde(rename = "final")]
+    pub final_weight: Option<f32>,
+    pub trs: Vec<JsonTr>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct JsonFst {
+    pub start: Option<StateId>,
+    pub states: Vec<JsonState>,
+    pub input_symbols: Option<Vec<String>>,
+    pub output_symbols: Option<Vec<String>>,
+}
+
+fn symbols(symt: Option<&Arc<SymbolTable>>) -> Option<Vec<String>> {
+    symt.map(|symt| symt.iter().map(|(_, s)| s.to_string()).collect())
+}
+
+fn symbol_table(symbols: &[String]) -> Arc<SymbolTable> {
+    let mut symt = SymbolTable::empty();
+    for s in symbols {
+        symt.add_symbol(s);
+    }
+    Arc::new(symt)
+}
+
+impl JsonFst {
+    pub fn from_fst(fst: &VectorFst<TropicalWeight>) -> Result<Self> {
+        let mut states = Vec::with_capacity(fst.num_states());
+        for s in fst.states_iter() {
+            let trs = fst
+                .get_trs(s)?
+                .iter()
+                .map(|tr| JsonTr {
+                    ilabel: tr.ilabel,
+                    olabel: tr.olabel,
+                    weight: *tr.weight.value(),
+                    next: tr.nextstate,
+                })
+                .collect();
+            states.push(JsonState {
+                final_weight: fst.final_weight(s)?.map(|w| *w.value()),
+                trs,
+            });
+        }
+        Ok(JsonFst {
+            start: fst.start(),
+            states,
+            input_symbols: symbols(fst.input_symbols()),
+            output_symbols: symbols(fst.output_symbols()),
+        })
+    }
+
+    pub fn to_fst(&self) -> Result<VectorFst<TropicalWeight>> {
+        let mut fst = VectorFst::<TropicalWeight>::new();
+        fst.add_states(self.states.len());
+        for (s, state) in self.states.iter().enumerate() {
+            let s = s as StateId;
+            if let Some(w) = state.final_weight {
+                fst.set_final(s, w)?;
+            }
+            for tr in state.trs.iter() {
+                if tr.next as usize >= self.states.len() {
+                    return Err(anyhow!("Transition from state {} to missing state {}", s, tr.next));
+                }
+                fst.add_tr(s, Tr::new(tr.ilabel, tr.olabel, tr.weight, tr.next))?;
+            }
+        }
+        if let Some(start) = self.start {
+            fst.set_start(start)?;
+        }
+        if let Some(symbols) = &self.input_symbols {
+            fst.set_input_symbols(symbol_table(symbols));
+        }
+        if let Some(symbols) = &self.output_symbols {
+            fst.set_output_symbols(symbol_table(symbols));
+        }
+        Ok(fst)
+    }
+}
+
+/// Write `fst` as JSON to `path`.
+pub fn write_json_fst(fst: &VectorFst<TropicalWeight>, path: &Path) -> Result<()> {
+    let json = JsonFst::from_fst(fst)?;
+    create_atomic(path, |file| internal(serde_json::to_writer(file, &json)))
+}
+
+/// Read an FST written by [`write_json_fst`].
+pub fn read_json_fst(path: &Path) -> Result<VectorFst<TropicalWeight>> {
+    let file = std::fs::File::open(path)?;
+    let json: JsonFst = serde_json::from_reader(std::io::BufReader::new(file)).map_err(|e| match e.is_eof() {
+        true => anyhow!(truncated(path)),
+        false => e.into(),
+    })?;
+    json.to_fst()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustfst::utils::transducer;
+
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_json_round_trip() {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b"]);
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![2, 3 => 3, 0; 1.5];
+        let extra = fst.add_state();
+        fst.add_tr(0, Tr::new(1, 1, 0.25, extra)).unwrap();
+        fst.set_final(extra, 2.0).unwrap();
+        fst.set_input_symbols(symt.clone());
+        fst.set_output_symbols(symt);
+        let dir = TempDir::new("json");
+        let path = dir.join("out.json");
+        write_json_fst(&fst, &path).unwrap();
+        let read = read_json_fst(&path).unwrap();
+        assert_eq!(read, fst);
+        assert_eq!(read.input_symbols().unwrap().as_ref(), fst.input_symbols().unwrap().as_ref());
+    }
+
+    #[test]
+    fn test_json_shape() {
+        let fst: VectorFst<TropicalWeight> = rustfst::fst![1 => 2; 0.5];
+        let json = serde_json::to_value(JsonFst::from_fst(&fst).unwrap()).unwrap();
+        assert_eq!(json["start"], 0);
+        assert_eq!(json["states"][0]["trs"][0], serde_json::json!({"ilabel": 1, "olabel": 2, "weight": 0.0, "next": 1}));
+        assert_eq!(json["states"][0]["final"], serde_json::Value::Null);
+        assert_eq!(json["states"][1]["final"], 0.5);
+    }
+}
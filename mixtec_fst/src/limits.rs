@@ -0,0 +1,113 @@
+//! Limits that keep one absurd input from holding up a run: a whole sentence
+//! pasted in as one word builds a lattice for minutes, and a pathological
+//! word can have paths enough to keep the search of its analyses busy for as
+//! long.
+//!
+//! Inputs longer than `--max-input-len` symbols are refused before anything
+//! is built for them, and the search for analyses ([`crate::search`]) gives
+//! up after `--max-expansions` expansions. Either way the error is a
+//! [`LimitError`], which callers tell from other failures to report the
+//! input with its own status rather than fail the run.
+
+use std::fmt;
+
+use rustfst::SymbolTable;
+
+use crate::automaton::{tokenize_lenient, Tokenization};
+
+/// Longest input analysed, in symbols, unless `--max-input-len` says otherwise.
+pub const DEFAULT_MAX_INPUT_LEN: usize = 128;
+
+/// Most paths expanded in the search for the analyses of one input, unless
+/// `--max-expansions` says otherwise.
+pub const DEFAULT_MAX_EXPANSIONS: usize = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Longest input analysed, in symbols after tokenization.
+    pub max_input_len: usize,
+    /// Most paths expanded in the search for the analyses of one input.
+    pub max_expansions: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits { max_input_len: DEFAULT_MAX_INPUT_LEN, max_expansions: DEFAULT_MAX_EXPANSIONS }
+    }
+}
+
+impl Limits {
+    /// Refuse `input` if it has more than `max_input_len` symbols. Parts that
+    /// are not symbols count as one each.
+    pub fn check_len(&self, symt: &SymbolTable, input: &str, tokenization: Tokenization) -> Result<(), LimitError> {
+        let (labels, missing) = tokenize_lenient(symt, input, tokenization);
+        let symbols = labels.len() + missing.len();
+        if symbols > self.max_input_len {
+            return Err(LimitError::TooLong { symbols, max: self.max_input_len });
+        }
+        Ok(())
+    }
+}
+
+/// Why an input was given up on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    /// The input has more symbols than the limit.
+    TooLong { symbols: usize, max: usize },
+    /// The search for its analyses expanded the most paths it may.
+    TooComplex { max_expansions: usize },
+}
+
+impl LimitError {
+    /// The limit `e` ran into, if it is a [`LimitError`].
+    pub fn of(e: &anyhow::Error) -> Option<LimitError> {
+        e.downcast_ref().copied()
+    }
+
+}
+
+/// `result`, with a failure for a limit told apart from other failures.
+pub fn given_up<T>(result: anyhow::Result<T>) -> anyhow::Result<Result<T, LimitError>> {
+    match result {
+        Ok(value) => Ok(Ok(value)),
+        Err(e) => match LimitError::of(&e) {
+            Some(limit) => Ok(Err(limit)),
+            None => Err(e),
+        },
+    }
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::TooLong { symbols, max } => {
+                write!(f, "input too long: {} symbols, more than --max-input-len {}", symbols, max)
+            }
+            LimitError::TooComplex { max_expansions } => {
+                write!(f, "analysis too complex: gave up after {} expansions (--max-expansions)", max_expansions)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length_is_counted_in_symbols() {
+        let symt = rustfst::symt!["#", "a", "ch", "1"];
+        let limits = Limits { max_input_len: 3, ..Limits::default() };
+        assert_eq!(limits.check_len(&symt, "cha1", Tokenization::default()), Ok(()));
+        assert_eq!(limits.check_len(&symt, "chaa1", Tokenization::default()), Err(LimitError::TooLong { symbols: 4, max: 3 }));
+        // What is not a symbol still counts.
+        assert!(limits.check_len(&symt, "aqqq", Tokenization::default()).is_err());
+        let e = anyhow::Error::from(LimitError::TooComplex { max_expansions: 10 }).context("Failed to segment");
+        assert_eq!(LimitError::of(&e), Some(LimitError::TooComplex { max_expansions: 10 }));
+        assert_eq!(LimitError::of(&anyhow::anyhow!("other")), None);
+        assert_eq!(given_up::<()>(Err(e)).unwrap(), Err(LimitError::TooComplex { max_expansions: 10 }));
+        assert!(given_up::<()>(Err(anyhow::anyhow!("other"))).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+//! The linearize pipeline, run stage by stage with intermediate artifacts.
+//!
+//! Each of the [`NUM_STAGES`] stage scripts `to_linear_<i>.txt` is compiled to
+//! `stage_<i>.fst` in a working directory, and a run of consecutive stages is
+//! composed (minimizing after each composition) into `stages_<from>-<to>.fst`.
+//! Every artifact has a `<artifact>.meta` sidecar recording the label its
+//! transitions are sorted on and the hash of the symbol table it was built
+//! against, so that a composition can reject incompatible inputs before doing
+//! any work, and a failed stage can be rerun without redoing the others.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use rustfst::prelude::compose::compose;
+use rustfst::prelude::{tr_sort, Fst, ILabelCompare, OLabelCompare, TropicalWeight, VectorFst};
+use rustfst::SymbolTable;
+
+use crate::artifact::{read_fst, write_file_atomic, write_fst};
+use crate::cache::symt_hash;
+use crate::profile::profile_span;
+use crate::rewrite::{compile_as_linear, LinearOptions};
+use crate::rules::{load_script, RuleChecks};
+use crate::status::internal;
+use crate::verify::minimize_verified;
+
+pub const NUM_STAGES: usize = 4;
+
+pub const DEFAULT_WORKDIR: &str = ".linear";
+
+/// The label an artifact's transitions are sorted on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    ILabel,
+    OLabel,
+}
+
+impl SortOrder {
+    fn name(self) -> &'static str {
+        match self {
+            SortOrder::ILabel => "ilabel",
+            SortOrder::OLabel => "olabel",
+        }
+    }
+
+    fn sort(self, fst: &mut VectorFst<TropicalWeight>) {
+        match self {
+            SortOrder::ILabel => tr_sort(fst, ILabelCompare {}),
+            SortOrder::OLabel => tr_sort(fst, OLabelCompare {}),
+        }
+    }
+}
+
+/// The contents of an artifact's `.meta` sidecar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArtifactMeta {
+    pub sort: SortOrder,
+    pub symt_hash: u64,
+}
+
+impl ArtifactMeta {
+    fn path(artifact: &Path) -> PathBuf {
+        let mut path = artifact.as_os_str().to_owned();
+        path.push(".meta");
+        PathBuf::from(path)
+    }
+
+    pub fn write(&self, artifact: &Path) -> Result<()> {
+        let meta = format!("sort={}\nsymt_hash={:016x}\n", self.sort.name(), self.symt_hash);
+        write_file_atomic(&Self::path(artifact), meta)
+    }
+
+    pub fn read(artifact: &Path) -> Result<Self> {
+        let path = Self::path(artifact);
+        let meta = std::fs::read_to_string(&path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+        let mut sort = None;
+        let mut symt_hash = None;
+        for line in meta.lines() {
+            match line.split_once('=') {
+                Some(("sort", "ilabel")) => sort = Some(SortOrder::ILabel),
+                Some(("sort", "olabel")) => sort = Some(SortOrder::OLabel),
+                Some(("symt_hash", hash)) => symt_hash = u64::from_str_radix(hash, 16).ok(),
+                _ => bail!("Malformed line '{}' in {}", line, path.display()),
+            }
+        }
+        match (sort, symt_hash) {
+            (Some(sort), Some(symt_hash)) => Ok(ArtifactMeta { sort, symt_hash }),
+            _ => bail!("{} lacks a sort order or symbol table hash", path.display()),
+        }
+    }
+}
+
+/// Everything needed to compile and compose linearize stages.
+pub struct LinearPipeline {
+    pub symt: Arc<SymbolTable>,
+    /// Directory holding the `to_linear_<i>.txt` stage scripts.
+    pub rules_dir: PathBuf,
+    /// Directory the stage artifacts are written to.
+    pub workdir: PathBuf,
+    pub opts: LinearOptions,
+    pub dump_macros: bool,
+}
+
+impl LinearPipeline {
+    pub fn stage_script(&self, stage: usize) -> PathBuf {
+        self.rules_dir.join(format!("to_linear_{}.txt", stage))
+    }
+
+    pub fn stage_artifact(&self, stage: usize) -> PathBuf {
+        self.workdir.join(format!("stage_{}.fst", stage))
+    }
+
+    pub fn composed_artifact(&self, from: usize, to: usize) -> PathBuf {
+        self.workdir.join(format!("stages_{}-{}.fst", from, to))
+    }
+
+    fn check_stage(stage: usize) -> Result<()> {
+        if !(1..=NUM_STAGES).contains(&stage) {
+            bail!("No linearize stage {} (stages are 1 to {})", stage, NUM_STAGES);
+        }
+        Ok(())
+    }
+
+    fn write_artifact(&self, fst: &VectorFst<TropicalWeight>, path: &Path, sort: SortOrder) -> Result<()> {
+        std::fs::create_dir_all(&self.workdir)?;
+        write_fst(fst, path)?;
+        ArtifactMeta { sort, symt_hash: symt_hash(&self.symt) }.write(path)
+    }
+
+    /// Check an artifact's sidecar against the current symbol table and, if
+    /// given, the sort order composition needs.
+    fn validate(&self, path: &Path, sort: Option<SortOrder>, rerun: &str) -> Result<ArtifactMeta> {
+        if !path.exists() {
+            bail!("Missing {}; run `{}` first", path.display(), rerun);
+        }
+        let meta = ArtifactMeta::read(path)?;
+        let expected = symt_hash(&self.symt);
+        if meta.symt_hash != expected {
+            bail!(
+                "{} was built against a different symbol table (hash {:016x}, expected {:016x}); rerun `{}`",
+                path.display(),
+                meta.symt_hash,
+                expected,
+                rerun
+            );
+        }
+        if let Some(sort) = sort
+            && meta.sort != sort
+        {
+            bail!("{} is sorted on {}, not {}; rerun `{}`", path.display(), meta.sort.name(), sort.name(), rerun);
+        }
+        Ok(meta)
+    }
+
+    fn read_artifact(&self, path: &Path) -> Result<VectorFst<TropicalWeight>> {
+        let mut fst = read_fst(path)?;
+        fst.set_input_symbols(self.symt.clone());
+        fst.set_output_symbols(self.symt.clone());
+        Ok(fst)
+    }
+
+    /// Compile one stage script, writing its artifact. The first stage is
+    /// sorted on output labels and the others on input labels, ready to be
+    /// composed in order.
+    pub fn compile(&self, stage: usize) -> Result<PathBuf> {
+        Self::check_stage(stage)?;
+        let _span = profile_span!("compile_stage", stage);
+        let script_path = self.stage_script(stage);
+        let script = load_script(&script_path)?;
+        let mut checks = RuleChecks::new(self.opts.strict);
+        let file = script_path.display().to_string();
+        let mut fst = compile_as_linear(self.symt.clone(), script, self.dump_macros, self.opts, &file, &mut checks)
+            .with_context(|| format!("Failed to compile {}", script_path.display()))?;
+        print!("{}", checks.summary());
+        let sort = if stage == 1 { SortOrder::OLabel } else { SortOrder::ILabel };
+        sort.sort(&mut fst);
+        let path = self.stage_artifact(stage);
+        self.write_artifact(&fst, &path, sort)?;
+        println!("Stage {} written to {}", stage, path.display());
+        Ok(path)
+    }
+
+    /// The longest already-composed run `from..=k` with `k < to` that is newer
+    /// than every stage artifact it was built from.
+    fn reusable_prefix(&self, from: usize, to: usize) -> Option<(usize, PathBuf)> {
+        let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+        (from + 1..to).rev().find_map(|k| {
+            let path = self.composed_artifact(from, k);
+            let built = modified(&path)?;
+            let fresh = (from..=k).all(|s| modified(&self.stage_artifact(s)).is_some_and(|t| t <= built));
+            (fresh && self.validate(&path, None, "").is_ok()).then_some((k, path))
+        })
+    }
+
+    /// Compose stages `from` to `to` in order, minimizing after each
+    /// composition, and write the result. Every input is validated before
+    /// anything is composed, and a previously composed prefix of the run is
+    /// reused if it is up to date.
+    pub fn compose(&self, from: usize, to: usize) -> Result<PathBuf> {
+        Self::check_stage(from)?;
+        Self::check_stage(to)?;
+        if from >= to {
+            bail!("Nothing to compose from stage {} to stage {}", from, to);
+        }
+        let rerun = |s: usize| format!("linearize compile --stage {}", s);
+        let (start, left_path) = match self.reusable_prefix(from, to) {
+            Some((k, path)) => {
+                println!("Reusing {}", path.display());
+                (k, path)
+            }
+            None => (from, self.stage_artifact(from)),
+        };
+        let left_meta = self.validate(&left_path, None, &rerun(from))?;
+        for stage in start + 1..=to {
+            self.validate(&self.stage_artifact(stage), Some(SortOrder::ILabel), &rerun(stage))?;
+        }
+
+        let mut fst = self.read_artifact(&left_path)?;
+        if left_meta.sort != SortOrder::OLabel {
+            SortOrder::OLabel.sort(&mut fst);
+        }
+        for stage in start + 1..=to {
+            let right = self.read_artifact(&self.stage_artifact(stage))?;
+            println!("Composing stage {}...", stage);
+            let _span = profile_span!("compose_stage", stage);
+            fst = {
+                let _span = profile_span!("compose");
+                internal(compose(fst, right))?
+            };
+            let _minimize_span = profile_span!("minimize");
+            minimize_verified(&self.symt, &mut fst, self.opts.verify_minimize, self.opts.nondeterminism)?;
+            SortOrder::OLabel.sort(&mut fst);
+            println!("Composition with stage {} complete", stage);
+        }
+        let path = self.composed_artifact(from, to);
+        self.write_artifact(&fst, &path, SortOrder::OLabel)?;
+        println!("Stages {} to {} written to {}", from, to, path.display());
+        Ok(path)
+    }
+
+    /// Compile every stage and compose them all.
+    pub fn run_all(&self) -> Result<VectorFst<TropicalWeight>> {
+        for stage in 1..=NUM_STAGES {
+            self.compile(stage)?;
+        }
+        let path = self.compose(1, NUM_STAGES)?;
+        self.read_artifact(&path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{root, TempDir};
+
+    fn pipeline(workdir: &Path) -> LinearPipeline {
+        LinearPipeline {
+            symt: Arc::new(rustfst::symt!["#", "n", "a", "1", "3", "4", "{", "}", ">"]),
+            rules_dir: root().join("tests/linear"),
+            workdir: workdir.to_path_buf(),
+            opts: LinearOptions::default(),
+            dump_macros: false,
+        }
+    }
+
+    #[test]
+    fn test_stage_by_stage_matches_all_at_once() {
+        let all_dir = TempDir::new("linear-all");
+        let all = pipeline(&all_dir).run_all().unwrap();
+
+        let staged_dir = TempDir::new("linear-staged");
+        let staged = pipeline(&staged_dir);
+        for stage in [2, 1, 3] {
+            staged.compile(stage).unwrap();
+        }
+        staged.compose(1, 3).unwrap();
+        let err = staged.compose(1, 4).unwrap_err().to_string();
+        assert!(err.contains("stage_4.fst") && err.contains("--stage 4"), "{}", err);
+        staged.compile(4).unwrap();
+        let path = staged.compose(1, 4).unwrap();
+        let result = staged.read_artifact(&path).unwrap();
+        assert_eq!(result, all);
+    }
+
+    #[test]
+    fn test_compose_rejects_other_symbol_table() {
+        let dir = TempDir::new("linear-symt");
+        let p = pipeline(&dir);
+        p.compile(1).unwrap();
+        p.compile(2).unwrap();
+        let mut other = pipeline(&dir);
+        other.symt = Arc::new(rustfst::symt!["#", "n", "a", "1", "3", "4", "{", "}", ">", "x"]);
+        let err = other.compose(1, 2).unwrap_err().to_string();
+        assert!(err.contains("different symbol table"), "{}", err);
+    }
+
+    #[test]
+    fn test_meta_round_trip() {
+        let dir = TempDir::new("linear-meta");
+        let path = dir.join("stage_1.fst");
+        let meta = ArtifactMeta { sort: SortOrder::OLabel, symt_hash: 0xdeadbeef };
+        meta.write(&path).unwrap();
+        let read = ArtifactMeta::read(&path).unwrap();
+        assert_eq!(read, meta);
+    }
+}
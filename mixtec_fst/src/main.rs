@@ -1,118 +1,1045 @@
+mod align;
+mod ambiguity;
+mod alphabet;
+mod analysis;
+mod annotate;
+mod artifact;
+mod attribution;
+mod automaton;
+mod bisect;
+mod boundary;
+mod build;
+mod bulk;
+mod cache;
+mod cancel;
+mod check;
+mod composition;
+mod counts;
+mod coverage;
+mod crossval;
+mod decode;
+mod dump;
+mod encoding;
+mod explain;
+mod faillog;
+mod filter;
+mod graphemes;
+mod html;
+mod json;
+mod limits;
+mod linear;
+mod memory;
+mod minimize_dir;
+mod normalizer;
+mod optimize;
+mod pairs;
+mod paradigm;
+mod pool;
+mod prefix;
+mod prepared;
+mod producible;
+mod profile;
+mod provenance;
+mod ranking;
+mod relabel;
+mod report;
 mod rewrite;
+mod rule_config;
+mod rules;
+mod search;
+#[cfg(feature = "server")]
+mod serve;
+mod simultaneous;
+mod status;
+mod style;
+mod symdiff;
+#[cfg(test)]
+mod testutil;
+mod tokenizer;
+mod tones;
+mod verify;
 
-use parserule::rulefst::{sigma_star, weighted_sigma_star};
-use rustfst::utils::transducer;
-use parserule::{rulefst, ruleparse};
-use rustfst::prelude::concat::concat;
-use rustfst::prelude::determinize::{determinize_with_config, DeterminizeConfig, DeterminizeType};
-use rustfst::prelude::rm_epsilon::rm_epsilon;
-use rustfst::Semiring;
+use parserule::rulefst;
+use rustfst::prelude::{shortest_path_with_config, CoreFst, ExpandedFst, ShortestPathConfig, StateIterator};
 use std::collections::HashMap;
-use std::{fs::File, path::Path, sync::Arc};
+use std::{path::{Path, PathBuf}, sync::Arc, time::Duration};
 use std::io::prelude::*;
-use std::cmp::Ordering;
+use std::process::ExitCode;
 
-use clap::Parser;
-use itertools::{enumerate, Itertools};
-use rustfst::{prelude::{compose::compose, minimize_with_config, tr_sort, union::union, Fst, ILabelCompare, MinimizeConfig, MutableFst, OLabelCompare, SerializableFst, TropicalWeight, VectorFst}, DrawingConfig, SymbolTable};
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use rustfst::{prelude::{tr_sort, Fst, ILabelCompare, SerializableFst, TropicalWeight, VectorFst}, DrawingConfig, Semiring, SymbolTable, EPS_LABEL};
 use parserule::normalize::nfd_normalize;
 
-use crate::rewrite::{compile_as_linear};
+use crate::align::{Costs, Score};
+use crate::ambiguity::{competitors, functionality, AmbiguityReport, Functionality};
+use crate::alphabet::{AnalysisAcceptor, AnalysisToAnalysisFst, Compose, SurfaceAcceptor, SurfaceToAnalysisFst};
+use crate::analysis::{with_inline_weight, AnalysisFormat, WeightOutput, DEFAULT_MORPH_BOUNDARY, DEFAULT_SEPARATOR};
+use crate::annotate::annotate;
+use crate::artifact::{create_atomic, read_fst, write_file_atomic, write_fst, write_fst_text};
+use crate::attribution::SourceMarkers;
+use crate::automaton::{skip_missing_symbols, Tokenization};
+use crate::bisect::{bisect, RuleFile};
+use crate::boundary::{check_edge_boundaries, FallbackBoundary};
+use crate::bulk::{bulk_apply, BulkOptions};
+use crate::build::{build_from_rule_files, build_from_scripts, check_epsilon_free, connect_with_sizes, count_accepting_paths, dedup_arcs_with_sizes, default_rule_files, parse_weight_offset, symbol_use, union_scripts, write_build_info, FstSize, DEFAULT_FINAL_PATHS_LENGTH};
+use crate::cache::{g3_to_base_cached, sorted_fst_cached, symt_hash, DEFAULT_CACHE_DIR};
+use crate::cancel::{CancelToken, Cancelled};
+use crate::check::{accepts, accepts_pair, best_surface, recovers_input, weighed_prediction};
+use crate::composition::ComposeFilter;
+use crate::counts::learn_rule_weights;
+use crate::coverage::coverage_by_rule;
+use crate::crossval::{cross_validate, DEFAULT_FOLDS};
+use crate::decode::{decode_distinct_outputs, decode_raw_outputs, display_labels, k_best_distinct, DEFAULT_MAX_OUTPUTS};
+use crate::dump::DumpConfig;
+use crate::encoding::{open_text, read_text, TextEncoding};
+use crate::explain::explain_weights;
+use crate::faillog::{Direction, FailureLog, LogFormat, LogItem, DEFAULT_LOG};
+use crate::filter::{align_filter, apply_filter, compile_filter, compile_lexicon};
+use crate::graphemes::GraphemeMap;
+use crate::html::{write_html_report, Aligner};
+use crate::json::{read_json_fst, write_json_fst};
+use crate::limits::{given_up, Limits, DEFAULT_MAX_EXPANSIONS, DEFAULT_MAX_INPUT_LEN};
+use crate::linear::{LinearPipeline, DEFAULT_WORKDIR};
+use crate::memory::MemoryMeter;
+use crate::normalizer::{compile_normalizer, embeds_normalizer, Normalization};
+use crate::optimize::{optimize_auto, OptimizeBudget, OptimizeMode};
+use crate::pairs::{find_minimal_pairs, write_pairs_csv};
+use crate::paradigm::{generate_paradigm, parse_contexts};
+use crate::pool::{parse_timeout, with_timeout};
+use crate::prefix::longest_accepted_prefix;
+use crate::prepared::PreparedFst;
+use crate::producible::ProducibleLabels;
+use crate::profile::profile_span;
+use crate::ranking::{CandidateRanker, TieBreak};
+use crate::relabel::{apply_relabeling, frequency_relabeling};
+use crate::provenance::{read_provenance, summary_header, Provenance};
+use crate::report::{write_json_report, Outcome, RunInfo, TestReport};
+use crate::rewrite::{LinearOptions, TargetPlacement};
+use crate::rules::{list_rule_files, load_script, RuleChecks};
+use crate::simultaneous::RuleApplication;
+use crate::status::{internal, Failure};
+use crate::style::{paint, set_color_choice, warn, ColorChoice, Stream, Style};
+use crate::symdiff::diff_symbols;
+use crate::tokenizer::{compose_tokenizer, read_tokenizer};
+use crate::tones::{ToneSet, DEFAULT_TONES};
+use crate::verify::{minimize_nondet, minimize_verified, sample_inputs, Nondeterminism, OnDivergence, VerifyOptions};
 
 #[derive(Parser)]
+#[command(after_help = "Exit status: 0 success, 1 checks failed, 2 usage error, 3 data error (a missing, unreadable or malformed file), 4 internal error, 130 interrupted")]
 struct Args {
-    /// Path to write the FST to
-    outpath: String,
-    /// Path to load the FST from
-    #[arg(short,long)]
-    load: Option<String>,
-    /// Test file (CSV)
-    #[arg(short,long)]
-    test: Option<String>,
-    /// Linearize G3
-    #[arg(long)]
-    linearize: bool,
+    #[command(subcommand)]
+    command: Command,
+    /// Encoding of the chars, test and grapheme map files (detected if absent)
+    #[arg(long, value_enum, global = true)]
+    encoding: Option<TextEncoding>,
+    /// Report the peak resident memory of each stage, and overall
+    #[arg(long, global = true)]
+    measure_memory: bool,
+    /// Write the time spent in each nested build stage to this file as
+    /// folded stacks, for a flamegraph (under --out-dir)
+    #[cfg(feature = "profile")]
+    #[arg(long, global = true, value_name = "FILE")]
+    profile: Option<PathBuf>,
+    /// Directory for log.txt, stage artifacts, dot files and reports given as
+    /// relative paths (created if missing)
+    #[arg(long, global = true, default_value = ".")]
+    out_dir: PathBuf,
+    /// When a compose, determinize, minimize or epsilon removal fails, dump its
+    /// operands, configuration and the command line to a new directory under
+    /// this one (under --out-dir), for `replay`
+    #[arg(long, global = true, value_name = "DIR")]
+    debug_dump_on_error: Option<PathBuf>,
+    /// Skip the dump when the operands have more states than this in all
+    #[arg(long, global = true, value_name = "N", default_value_t = dump::DEFAULT_MAX_STATES)]
+    debug_dump_max_states: usize,
+    /// When to colour output: on terminals unless NO_COLOR is set (auto),
+    /// always or never. Files are never coloured
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+}
+
+/// The directory artifacts are written to (`--out-dir`). Relative artifact
+/// paths are resolved under it; absolute ones are left alone.
+struct OutDir(PathBuf);
+
+impl OutDir {
+    fn create(dir: PathBuf) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create output directory {}", dir.display()))?;
+        Ok(OutDir(dir))
+    }
+
+    fn path(&self, artifact: impl AsRef<Path>) -> PathBuf {
+        self.0.join(artifact)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Build the segmentation FST from rule files
+    Build {
+        /// Path to write the FST to
+        outpath: String,
+        /// Source directory (defaults to from_14.txt, from_4.txt and special.txt under rules/).
+        /// A rule file <name>.txt may have its settings in <name>.toml beside it: weight_offset,
+        /// obligatory and direction (see rule_config.rs)
+        #[arg(long)]
+        srcdir: Option<String>,
+        /// Leave out, with a warning, files in --srcdir that are not rule scripts
+        /// (not UTF-8 text, or over 4 MB) rather than failing on them
+        #[arg(long, requires = "srcdir")]
+        skip_bad_files: bool,
+        /// Weight offset added to a rule file's paths when unioning, as FILE=WEIGHT
+        /// (by file name; negative to outrank other files). Overrides the weight_offset
+        /// of the file's .toml. May be repeated.
+        #[arg(long, value_parser = parse_weight_offset)]
+        weight_offset: Vec<(String, f32)>,
+        /// Mark each rule file's paths so analyses can be attributed to it (see `test --attribute-sources`)
+        #[arg(long)]
+        attribute_sources: bool,
+        /// How to optimize the union of the rule files: the fixed pipeline, or
+        /// with auto, the most thorough one that fits the budget, falling back
+        /// to cheaper ones (the level reached is in <OUTPATH>.info)
+        #[arg(long, value_enum, default_value_t = OptimizeMode::Fixed)]
+        optimize: OptimizeMode,
+        /// Seconds each level of --optimize auto may take before falling back
+        /// [default: 600]
+        #[arg(long, value_name = "SECS", value_parser = parse_timeout)]
+        optimize_time_budget: Option<Duration>,
+        /// Most states a step of --optimize auto may leave before falling back
+        #[arg(long, value_name = "N", default_value_t = optimize::DEFAULT_MAX_STATES)]
+        optimize_max_states: usize,
+        /// No minimization
+        #[arg(long)]
+        no_min: bool,
+        /// Do not remove unreachable and dead states from the built FST
+        #[arg(long)]
+        no_connect: bool,
+        /// Do not merge parallel arcs with the same labels into one with the
+        /// best of their weights before writing the FST
+        #[arg(long)]
+        no_dedup_arcs: bool,
+        /// Directory to write OpenFST-style text files to
+        #[arg(long)]
+        openfst: Option<String>,
+        /// Also write the FST as JSON, for web tooling
+        #[arg(long)]
+        json_fst: Option<String>,
+        #[command(flatten)]
+        verify: VerifyArgs,
+        /// Fail the build if --verify-determinize finds diverging inputs, or if a
+        /// rule compiles to an empty transducer or uses an undefined macro
+        #[arg(long)]
+        strict: bool,
+        /// Where the identity fallback copies the word boundary '#'
+        #[arg(long, value_enum, default_value_t = FallbackBoundary::Edges)]
+        fallback_boundary: FallbackBoundary,
+        /// Do not check that the FST only emits '#' at word edges and in analysis separators
+        #[arg(long)]
+        no_boundary_check: bool,
+        /// Fail rather than write an FST that has epsilon:epsilon transitions
+        #[arg(long)]
+        require_epsilon_free: bool,
+        /// Sort transitions and number states canonically before each write, so
+        /// that rebuilding the same rules gives byte-identical files
+        #[arg(long)]
+        canonical_order: bool,
+        /// Renumber labels by descending frequency in the built FST (see <OUTPATH>.info)
+        #[arg(long)]
+        relabel_by_frequency: bool,
+        /// Compose the input normalization (NFD, and this grapheme map if one
+        /// is given) in front of the built FST, so that it reads inputs as
+        /// written; commands querying it then normalize nothing themselves
+        #[arg(long, value_name = "GRAPHEMES", num_args = 0..=1, conflicts_with = "relabel_by_frequency")]
+        normalizer_fst: Option<Option<String>>,
+        /// After minimization, report the number of accepting paths of at most
+        /// --final-paths-length arcs, as a gauge of how ambiguous the rules are
+        #[arg(long)]
+        count_final_paths: bool,
+        /// Longest path --count-final-paths counts, in arcs
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_FINAL_PATHS_LENGTH, requires = "count_final_paths")]
+        final_paths_length: usize,
+        /// Warn when --count-final-paths finds more paths than this, as the
+        /// rules may be over-generating
+        #[arg(long, value_name = "N", requires = "count_final_paths")]
+        final_paths_threshold: Option<u64>,
+        /// Warn when the variants of a rule (same source and contexts) are
+        /// annotated with probabilities (`:: p=0.8`) that sum to more than 1
+        #[arg(long)]
+        check_variant_probabilities: bool,
+        /// How the rules of each file combine: in order, each rewriting the
+        /// output of the ones before it, or all at once against the input
+        #[arg(long, value_enum, default_value_t = RuleApplication::Sequential)]
+        application: RuleApplication,
+        /// Break down the weight of the best analysis of this word by rule,
+        /// padding, offset and fallback
+        #[arg(long, value_name = "WORD")]
+        explain_weights: Option<String>,
+        /// Set each rule's cost from how often it fires to give the gold
+        /// analyses of this CSV (written as the rules write them), and write
+        /// the learned weights to <OUTPATH>.rule_weights.csv
+        #[arg(long, value_name = "GOLD", conflicts_with = "explain_weights")]
+        weights_from_counts: Option<String>,
+    },
+    /// Run the linearize pipeline, all at once or stage by stage
+    Linearize {
+        #[command(subcommand)]
+        command: LinearizeCommand,
+    },
+    /// Check test items against a built FST
+    #[command(group = clap::ArgGroup::new("items").required(true).args(["test", "demo", "assert_accepts_all", "assert_functional_on"]))]
+    Test {
+        /// Path of the FST (JSON if it ends in .json)
+        #[arg(required_unless_present = "test_rule")]
+        fst: Option<String>,
+        /// Instead of a built FST, test an FST built from only the rule named
+        /// NAME (by a `% @NAME` comment after it) and the identity fallback
+        #[arg(long, value_name = "NAME", conflicts_with_all = ["fst", "attribute_sources"])]
+        test_rule: Option<String>,
+        /// Directory of rule files to find the --test-rule rule in (defaults to
+        /// from_14.txt, from_4.txt and special.txt under rules/)
+        #[arg(long, requires = "test_rule")]
+        srcdir: Option<String>,
+        /// Leave out, with a warning, files in --srcdir that are not rule scripts
+        #[arg(long, requires = "srcdir")]
+        skip_bad_files: bool,
+        /// Test file (CSV)
+        #[arg(short, long)]
+        test: Option<String>,
+        /// Check the built-in smoke tests instead of a test file (smoke_tests.csv
+        /// in the working directory replaces them)
+        #[arg(long)]
+        demo: bool,
+        #[command(flatten)]
+        input: InputArgs,
+        /// Only list the N best candidate analyses for each test word
+        #[arg(long)]
+        max_paths: Option<usize>,
+        /// With --max-paths, list the distinct analyses of the N best paths,
+        /// which can be fewer than N, rather than the N best distinct analyses
+        #[arg(long, requires = "max_paths")]
+        k_paths: bool,
+        /// Show each candidate analysis as its output labels with their symbols,
+        /// `label(symbol)`, epsilons included, rather than as a string
+        #[arg(long)]
+        output_symbols_in_results: bool,
+        /// Only check pass/fail for each test word, without computing predictions
+        #[arg(long)]
+        fast_check: bool,
+        /// Also check that generating from each form recovers the input as its best surface
+        #[arg(long)]
+        both_directions: bool,
+        /// When an item fails, check it again with the parts of the word and the
+        /// form that are not symbols skipped, to tell a missing symbol from a
+        /// missing rule
+        #[arg(long)]
+        retry_lenient: bool,
+        /// When an item fails because its input has no analysis at all, also
+        /// report the longest prefix of the wrapped input the FST has a path
+        /// for, to point at where in the word the rules break down
+        #[arg(long)]
+        explain_no_result: bool,
+        /// Also score how close the best prediction of each item comes to its
+        /// gold form, by the symbol error rate (split into tone and segment
+        /// errors) and the F-score of the symbols, per item and on average
+        #[arg(long)]
+        partial_credit: bool,
+        /// With --partial-credit, what substituting a tone by another tone
+        /// costs; inserting or deleting a symbol costs 1
+        #[arg(long, value_name = "COST", default_value_t = 1.0, requires = "partial_credit")]
+        tone_substitution_cost: f64,
+        /// With --partial-credit, what substituting a segment by another
+        /// segment costs
+        #[arg(long, value_name = "COST", default_value_t = 1.0, requires = "partial_credit")]
+        segment_substitution_cost: f64,
+        /// After the run, list the inputs with more than one distinct analysis
+        /// within --ambiguity-margin of the best, most competing analyses first
+        #[arg(long)]
+        list_ambiguous: bool,
+        /// With --list-ambiguous, how much worse than the best an analysis can
+        /// be and still compete with it (0 for ties only)
+        #[arg(long, value_name = "WEIGHT", default_value_t = 0.0, requires = "list_ambiguous")]
+        ambiguity_margin: f32,
+        /// With --list-ambiguous, how many competing analyses to show for each input
+        #[arg(long, value_name = "N", default_value_t = 5, requires = "list_ambiguous")]
+        max_competitors: usize,
+        /// Write per-item outcomes and pass/fail/xfail/xpass counts to this file (under --out-dir) as JSON
+        #[arg(long)]
+        json_report: Option<String>,
+        /// Write the summary and a sortable table of the failures, each with its
+        /// best prediction aligned with its gold form, to this file (under
+        /// --out-dir) as HTML
+        #[arg(long, value_name = "FILE")]
+        report_html: Option<String>,
+        /// With --report-html, how many failures the table shows; past them,
+        /// every failure is also written to a CSV file next to it
+        #[arg(long, value_name = "N", default_value_t = 1000, requires = "report_html")]
+        report_html_max_rows: usize,
+        /// Attribute each candidate analysis to the rule file that produced it
+        #[arg(long)]
+        attribute_sources: bool,
+        /// Instead of checking test items, only check that every word in this
+        /// list (one per line) has at least one analysis
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["test", "demo", "max_paths", "fast_check", "both_directions", "retry_lenient", "explain_no_result", "partial_credit", "test_rule", "json_report", "report_html", "timeout", "tag"])]
+        assert_accepts_all: Option<String>,
+        /// Instead of checking test items, only check that every word in this
+        /// list (one per line) has exactly one best analysis, listing those
+        /// whose best analyses tie, with the tied analyses
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["test", "demo", "max_paths", "fast_check", "both_directions", "retry_lenient", "explain_no_result", "partial_credit", "test_rule", "json_report", "report_html", "timeout", "tag", "assert_accepts_all", "list_ambiguous"])]
+        assert_functional_on: Option<String>,
+        /// Give up on a test word after this many seconds and move on to the next
+        #[arg(long, value_name = "SECS", value_parser = parse_timeout)]
+        timeout: Option<Duration>,
+        /// Label for the run, shown with the FST's provenance in the summary and the JSON report
+        #[arg(long)]
+        tag: Option<String>,
+        /// Keep the FST sorted for composition and the G3-to-base converter in
+        /// this directory, so that later runs on the same FST, symbols and tones,
+        /// such as after editing only the test file, skip preparing them
+        #[arg(long, value_name = "DIR")]
+        prepared_cache: Option<String>,
+        #[command(flatten)]
+        log: LogArgs,
+        /// Instead of checking test items, write a copy of the --test CSV, every
+        /// column kept, with the best segmentation of each form in an extra
+        /// column, to --out or stdout
+        #[arg(long, requires = "test", conflicts_with_all = ["test_rule", "max_paths", "fast_check", "both_directions", "retry_lenient", "explain_no_result", "partial_credit", "list_ambiguous", "json_report", "report_html", "attribute_sources", "timeout", "tag"])]
+        segment_column_output: bool,
+        /// With --segment-column-output, also add a `match` column saying whether
+        /// each best segmentation is the gold one
+        #[arg(long, requires = "segment_column_output")]
+        match_column: bool,
+        /// File (under --out-dir) to write the --segment-column-output copy to
+        #[arg(long, requires = "segment_column_output")]
+        out: Option<String>,
+        /// With --segment-column-output, also write the weight of each best
+        /// segmentation: in a `system_weight` column, or inline in its cell
+        #[arg(long, value_enum, requires = "segment_column_output")]
+        weights: Option<WeightOutput>,
+    },
+    /// Print the candidate analyses of words
+    Segment {
+        /// Path of the FST (JSON if it ends in .json)
+        #[arg(required_unless_present = "models")]
+        fst: Option<String>,
+        /// Words to segment
+        #[arg(required_unless_present = "serve")]
+        words: Vec<String>,
+        #[command(flatten)]
+        input: InputArgs,
+        /// Only list the N best analyses of each word
+        #[arg(long)]
+        max_paths: Option<usize>,
+        /// With --max-paths, list the distinct analyses of the N best paths,
+        /// which can be fewer than N, rather than the N best distinct analyses
+        #[arg(long, requires = "max_paths")]
+        k_paths: bool,
+        /// Show each candidate analysis as its output labels with their symbols,
+        /// `label(symbol)`, epsilons included, rather than as a string
+        #[arg(long)]
+        output_symbols_in_results: bool,
+        /// Attribute each analysis to the rule file that produced it
+        #[arg(long)]
+        attribute_sources: bool,
+        /// Only list analyses accepted by this filter: the path of an FST, or a
+        /// regular expression in rule syntax matching a whole analysis
+        #[arg(long)]
+        filter: Option<String>,
+        /// Only list analyses listed in this lexicon (one analysis per line,
+        /// without word boundaries)
+        #[arg(long)]
+        lexicon: Option<String>,
+        /// Rule script rewriting the raw characters of the words as symbols
+        /// (such as a digraph as the one symbol it stands for), composed
+        /// before the FST
+        #[arg(long, value_name = "PATH")]
+        tokenizer: Option<String>,
+        /// Write the weight of each analysis in a field after it, or inline,
+        /// as `ni3jo14 (w=3.0)`
+        #[arg(long, value_enum, default_value_t)]
+        weights: WeightOutput,
+        /// For each word with no result, also report the longest prefix of the
+        /// wrapped word the FST has a path for, to point at where in the word
+        /// the rules break down
+        #[arg(long)]
+        explain_no_result: bool,
+        /// Instead of segmenting WORDS, answer `/segment?word=WORD` requests on
+        /// this address (e.g. 127.0.0.1:8080) with the --max-paths (default 5)
+        /// best analyses as JSON; needs the `server` feature
+        #[arg(long, value_name = "ADDR", conflicts_with_all = ["words", "k_paths", "output_symbols_in_results", "attribute_sources", "filter", "lexicon", "tokenizer", "weights", "explain_no_result"])]
+        serve: Option<String>,
+        /// Number of threads answering --serve requests (defaults to the number of CPUs)
+        #[arg(long, requires = "serve")]
+        jobs: Option<usize>,
+        /// Also serve the FST at PATH as the model NAME, which requests choose
+        /// with `model=NAME` (the FST argument is served as `default`); repeatable
+        #[arg(long = "model", value_name = "NAME=PATH", requires = "serve", value_parser = parse_model)]
+        models: Vec<(String, String)>,
+        /// The model answering requests that do not choose one (defaults to the
+        /// FST argument, or else the first --model)
+        #[arg(long, value_name = "NAME", requires = "serve")]
+        default_model: Option<String>,
+    },
+    /// Print the size of an FST, how many arcs read and write each symbol, and its build summary
+    Info {
+        /// Path of the FST (JSON if it ends in .json)
+        fst: String,
+    },
+    /// Compare the symbol table of chars.txt with another, and fail if an FST
+    /// built against one would read differently under the other
+    DiffSymbols {
+        /// A symbol table in text format (.syms or .txt), or an FST whose
+        /// embedded table to compare (JSON if it ends in .json)
+        other: String,
+    },
+    /// Draw an FST, or its lattice for one word, in Graphviz dot format
+    Draw {
+        /// Path of the FST (JSON if it ends in .json)
+        fst: String,
+        /// Path to write the dot file to, under --out-dir
+        out: String,
+        /// Draw the lattice of analyses of this word instead of the whole FST
+        #[arg(long)]
+        word: Option<String>,
+        #[command(flatten)]
+        input: InputArgs,
+    },
+    /// Generate the predicted surface forms of underlying stems in a set of contexts
+    GenerateParadigm {
+        /// Path of the FST (JSON if it ends in .json)
+        fst: String,
+        /// Underlying stems, one per line (base notation unless --g3)
+        stems: String,
+        /// Contexts (CSV with name, prefix and suffix columns)
+        contexts: String,
+        /// Path to write the paradigm table to (CSV, under --out-dir)
+        #[arg(short, long)]
+        out: String,
+        /// Number of surface forms to list for each stem and context
+        #[arg(long, default_value_t = 3)]
+        top_k: usize,
+        /// Continue an interrupted run, skipping the stems listed in <OUT>.progress
+        #[arg(long)]
+        resume: bool,
+        #[command(flatten)]
+        input: InputArgs,
+    },
+    /// Annotate a large token list (one token per line) with the best analysis of
+    /// each token, analysing each distinct form once
+    BulkApply {
+        /// Path of the FST (JSON if it ends in .json)
+        fst: String,
+        /// Tokens, one per line
+        tokens: String,
+        /// Path to write the annotated tokens to (TSV, under --out-dir); the
+        /// sorted form map goes to <OUT>.map
+        #[arg(short, long)]
+        out: String,
+        /// Number of worker threads (defaults to the number of CPUs)
+        #[arg(long)]
+        jobs: Option<usize>,
+        /// Number of forms to analyse between checkpoints to <OUT>.journal
+        #[arg(long, default_value_t = 1000)]
+        checkpoint_every: usize,
+        /// Skip forms longer than this many characters
+        #[arg(long)]
+        max_len: Option<usize>,
+        /// Continue an interrupted run, reusing the results in <OUT>.journal
+        #[arg(long)]
+        resume: bool,
+        /// Write the weight of each analysis in a column of its own, or inline
+        /// after it, as `ni3jo14 (w=3.0)`
+        #[arg(long, value_enum, default_value_t)]
+        weights: WeightOutput,
+        #[command(flatten)]
+        input: InputArgs,
+    },
+    /// Minimize every FST in a directory, such as per-rule FSTs built with
+    /// --no-min, and write each back (or under --out), reporting the sizes
+    MinimizeDir {
+        /// Directory of the FSTs (the .fst files directly in it)
+        dir: String,
+        /// Directory (under --out-dir) to write the minimized FSTs to, with
+        /// the same names; in place if absent
+        #[arg(long)]
+        out: Option<String>,
+        /// Refuse to minimize an FST that is not input-deterministic, rather
+        /// than minimize it to a result that may not be minimal
+        #[arg(long)]
+        validate_determinism: bool,
+    },
+    /// Report which gold items each rule file can produce on its own
+    CoverageByRule {
+        /// Directory of rule files
+        srcdir: String,
+        /// Leave out, with a warning, files in SRCDIR that are not rule scripts
+        #[arg(long)]
+        skip_bad_files: bool,
+        /// Gold file (CSV)
+        test: String,
+        /// Path to write the sparse coverage matrix to (CSV, under --out-dir); stdout if absent
+        #[arg(long)]
+        out: Option<String>,
+        #[command(flatten)]
+        input: InputArgs,
+        /// Directory for cached per-file FSTs
+        #[arg(long, default_value = DEFAULT_CACHE_DIR)]
+        cache_dir: String,
+        /// Do not read or write the per-file FST cache
+        #[arg(long)]
+        no_cache: bool,
+        /// Number of worker threads (defaults to the number of CPUs)
+        #[arg(short, long)]
+        jobs: Option<usize>,
+    },
+    /// Find the rule files that make a gold item fail, by searching subsets of
+    /// SRCDIR for a minimal one whose union gets the item wrong
+    Bisect {
+        /// Directory of rule files
+        srcdir: String,
+        /// The word form of the item
+        form: String,
+        /// Its gold analysis
+        gold: String,
+        /// Leave out, with a warning, files in SRCDIR that are not rule scripts
+        #[arg(long)]
+        skip_bad_files: bool,
+        /// Add WEIGHT to every path through the file named FILE, as for `build`
+        #[arg(long, value_parser = parse_weight_offset)]
+        weight_offset: Vec<(String, f32)>,
+        /// Where the identity fallback copies the word boundary '#'
+        #[arg(long, value_enum, default_value_t = FallbackBoundary::Edges)]
+        fallback_boundary: FallbackBoundary,
+        #[command(flatten)]
+        input: InputArgs,
+        /// Directory for cached per-file FSTs
+        #[arg(long, default_value = DEFAULT_CACHE_DIR)]
+        cache_dir: String,
+        /// Do not read or write the per-file FST cache
+        #[arg(long)]
+        no_cache: bool,
+    },
+    /// Check the gold items in k folds and report the accuracy on each, with
+    /// its mean and standard deviation
+    CrossValidate {
+        /// Path of the FST (JSON if it ends in .json)
+        #[arg(required_unless_present = "srcdir")]
+        fst: Option<String>,
+        /// Build the FST from the rule files in this directory instead
+        #[arg(long, conflicts_with = "fst")]
+        srcdir: Option<String>,
+        /// Gold file (CSV)
+        #[arg(short, long)]
+        test: String,
+        /// Number of folds
+        #[arg(long, default_value_t = DEFAULT_FOLDS)]
+        folds: usize,
+        /// Seed of the assignment of items to folds
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+        /// Path to write the per-fold and aggregate results to (JSON, under --out-dir); stdout if absent
+        #[arg(long)]
+        out: Option<String>,
+        #[command(flatten)]
+        input: InputArgs,
+        /// Number of worker threads (defaults to the number of CPUs)
+        #[arg(short, long)]
+        jobs: Option<usize>,
+    },
+    /// List forms that differ in one tone and have different best analyses,
+    /// as candidate minimal pairs (CSV, most confident first)
+    MinimalPairs {
+        /// Path of the FST (JSON if it ends in .json)
+        fst: String,
+        /// Words to pair, one per line
+        #[arg(long, required_unless_present = "samples")]
+        words: Option<String>,
+        /// Instead of a word list, pair this many inputs sampled from the FST
+        #[arg(long, conflicts_with = "words")]
+        samples: Option<usize>,
+        /// Seed of the random walks that sample inputs
+        #[arg(long, default_value_t = 0, requires = "samples")]
+        seed: u64,
+        /// Path to write the pairs to (CSV, under --out-dir); stdout if absent
+        #[arg(long)]
+        out: Option<String>,
+        #[command(flatten)]
+        input: InputArgs,
+        /// Number of worker threads (defaults to the number of CPUs)
+        #[arg(short, long)]
+        jobs: Option<usize>,
+    },
+    /// Run the operation of a --debug-dump-on-error dump again
+    Replay {
+        /// Directory of the dump
+        dump: String,
+        /// Path to write the result to, if the operation succeeds this time
+        #[arg(long)]
+        out: Option<String>,
+    },
+}
+
+/// How test inputs and gold analyses are read.
+#[derive(clap::Args)]
+struct InputArgs {
     /// Is test input G3
     #[arg(long)]
     g3: bool,
-    /// Output OpenFST-style text file
+    /// Separator between the base form and process annotations in analyses
+    #[arg(long, default_value = DEFAULT_SEPARATOR)]
+    separator: String,
+    /// Boundary between the morphs of a base form in analyses
+    #[arg(long, default_value = DEFAULT_MORPH_BOUNDARY)]
+    morph_boundary: String,
+    /// Compare analyses with golds without their morph boundaries, for golds
+    /// that do not mark morphs
     #[arg(long)]
-    openfst: Option<String>,
-    /// Source directory
+    ignore_morph_boundaries: bool,
+    /// Grapheme map (CSV of grapheme -> space-separated symbols) applied to inputs
     #[arg(long)]
-    srcdir: Option<String>,
-    /// No minimization
+    graphemes: Option<String>,
+    /// Tone symbols of the dialect, as a list (12345) or range (1-5)
+    #[arg(long, default_value = DEFAULT_TONES, value_parser = ToneSet::parse)]
+    tones: ToneSet,
+    /// How to order candidates of equal weight
+    #[arg(long, value_enum, default_value_t)]
+    tie_break: TieBreak,
+    /// How inputs and gold analyses are split into symbols
+    #[arg(long, value_enum, default_value_t)]
+    tokenization: Tokenization,
+    /// How epsilon moves are filtered when composing with the FST
+    #[arg(long, value_enum, default_value_t)]
+    compose_filter: ComposeFilter,
+    /// Analyse inputs as word fragments: do not wrap them in word boundaries,
+    /// so rules with a boundary in their context do not apply to them
     #[arg(long)]
-    no_min: bool,
+    no_boundaries: bool,
+    /// Refuse inputs longer than this many symbols, rather than build a
+    /// lattice for a sentence pasted in as one word
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_INPUT_LEN)]
+    max_input_len: usize,
+    /// Give up on the analyses of an input, as too complex, after the search
+    /// for them has expanded this many paths
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_EXPANSIONS)]
+    max_expansions: usize,
+}
+
+impl InputArgs {
+    /// How analyses are wrapped and split. Under --no-boundaries, says that
+    /// the results leave out the rules that need a boundary.
+    fn format(&self) -> AnalysisFormat {
+        let mut fmt = AnalysisFormat::new(&self.separator).with_morph_boundary(&self.morph_boundary);
+        if self.ignore_morph_boundaries {
+            fmt = fmt.ignoring_morph_boundaries();
+        }
+        if !self.no_boundaries {
+            return fmt;
+        }
+        warn("Analysing inputs as word fragments: rules with a word boundary in their context do not apply");
+        fmt.without_boundaries()
+    }
+
+    fn limits(&self) -> Limits {
+        Limits { max_input_len: self.max_input_len, max_expansions: self.max_expansions }
+    }
+
+    /// The FST that strips process annotations from gold analyses, unless
+    /// they are compared as G3.
+    fn g3_to_base(&self, symt: &Arc<SymbolTable>) -> anyhow::Result<Option<AnalysisToAnalysisFst>> {
+        self.g3_to_base_cached(symt, None)
+    }
+
+    /// [`InputArgs::g3_to_base`], reused from `cache_dir` if it has it.
+    fn g3_to_base_cached(&self, symt: &Arc<SymbolTable>, cache_dir: Option<&Path>) -> anyhow::Result<Option<AnalysisToAnalysisFst>> {
+        if self.g3 {
+            return Ok(None);
+        }
+        self.tones.validate(symt)?;
+        let compile = || get_fst_g3_to_base(symt.clone(), &self.tones);
+        Ok(Some(match cache_dir {
+            Some(dir) => AnalysisToAnalysisFst(g3_to_base_cached(symt.clone(), &self.tones, dir, || Ok(compile()?.0))?),
+            None => compile()?,
+        }))
+    }
+}
+
+/// Where and how a test run logs the items that did not pass (see `faillog.rs`).
+#[derive(clap::Args)]
+struct LogArgs {
+    /// File (under --out-dir) to log the items that did not pass to
+    #[arg(long, value_name = "PATH", default_value = DEFAULT_LOG)]
+    log: String,
+    /// Add to the log, after a header naming the run, rather than replacing it
+    #[arg(long)]
+    log_append: bool,
+    /// Log each item as a line of text or as a JSON object
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+impl LogArgs {
+    fn open(&self, out_dir: &OutDir, run: &RunInfo) -> anyhow::Result<FailureLog> {
+        let command: Vec<String> = std::env::args().collect();
+        FailureLog::open(&out_dir.path(&self.log), self.log_format, self.log_append, run, &command)
+    }
+}
+
+/// Sampling checks of determinization and minimization (see `verify.rs`).
+#[derive(clap::Args)]
+struct VerifyArgs {
+    /// Check on sampled inputs that determinization kept every output
+    #[arg(long)]
+    verify_determinize: bool,
+    /// Check on sampled inputs that minimization kept every output, with its weight
+    #[arg(long)]
+    verify_minimize: bool,
+    /// What to do if --verify-minimize finds outputs lost by minimization
+    #[arg(long, value_enum, default_value_t)]
+    on_minimize_divergence: OnDivergence,
+    /// Number of inputs to sample for --verify-determinize and --verify-minimize
+    #[arg(long, default_value_t = VerifyOptions::default().samples)]
+    verify_samples: usize,
+    /// Longest input to sample for the verifications, in symbols
+    #[arg(long, default_value_t = VerifyOptions::default().max_len)]
+    verify_max_len: usize,
+    /// Seed of the random walks that sample inputs
+    #[arg(long, default_value_t = 0)]
+    verify_seed: u64,
+    /// Refuse to minimize an FST that is not input-deterministic, as from an
+    /// ambiguous rule set, rather than minimize it to a result that may not be
+    /// minimal
+    #[arg(long)]
+    validate_determinism: bool,
+}
+
+/// The budget of `--optimize auto`, or `None` for the fixed pipeline.
+fn optimize_budget(mode: OptimizeMode, time: Option<Duration>, max_states: usize) -> Option<OptimizeBudget> {
+    let default = OptimizeBudget::default();
+    (mode == OptimizeMode::Auto).then(|| OptimizeBudget { time: time.unwrap_or(default.time), max_states })
+}
+
+/// The check of the build's minimization: as --verify-minimize asks, or
+/// since minimizing the non-deterministic union determinizes it, as a
+/// determinization check under --verify-determinize alone.
+fn build_verification(verify: &VerifyArgs, strict: bool) -> Option<(VerifyOptions, OnDivergence)> {
+    let on_divergence = if strict { OnDivergence::Abort } else { OnDivergence::Report };
+    verify.minimize_options(strict).or_else(|| verify.options(strict).map(|opts| (opts, on_divergence)))
+}
+
+impl VerifyArgs {
+    fn sampling(&self, strict: bool) -> VerifyOptions {
+        VerifyOptions { samples: self.verify_samples, max_len: self.verify_max_len, seed: self.verify_seed, strict }
+    }
+
+    fn options(&self, strict: bool) -> Option<VerifyOptions> {
+        self.verify_determinize.then_some(self.sampling(strict))
+    }
+
+    fn minimize_options(&self, strict: bool) -> Option<(VerifyOptions, OnDivergence)> {
+        self.verify_minimize.then_some((self.sampling(strict), self.on_minimize_divergence))
+    }
+
+    fn nondeterminism(&self) -> Nondeterminism {
+        if self.validate_determinism { Nondeterminism::Refuse } else { Nondeterminism::Allow }
+    }
+}
+
+#[derive(Subcommand)]
+enum LinearizeCommand {
+    /// Compile one stage script into the working directory
+    Compile {
+        /// Stage to compile (1 to 4)
+        #[arg(long)]
+        stage: usize,
+        #[command(flatten)]
+        common: LinearizeArgs,
+    },
+    /// Compose a run of compiled stages
+    Compose {
+        /// First stage to compose
+        #[arg(long)]
+        from: usize,
+        /// Last stage to compose
+        #[arg(long)]
+        to: usize,
+        #[command(flatten)]
+        common: LinearizeArgs,
+    },
+    /// Compile and compose every stage, writing the result to OUTPATH
+    All {
+        /// Path to write the composed FST to
+        outpath: String,
+        #[command(flatten)]
+        common: LinearizeArgs,
+    },
+}
+
+#[derive(clap::Args)]
+struct LinearizeArgs {
+    /// Directory holding the to_linear_<i>.txt stage scripts
+    #[arg(long, default_value = "rules")]
+    rules_dir: String,
+    /// Working directory for stage artifacts, under --out-dir
+    #[arg(long, default_value = DEFAULT_WORKDIR)]
+    workdir: String,
+    /// Print each macro's fully-expanded definition before compiling
+    #[arg(long)]
+    dump_macros: bool,
+    /// Fail on rule symbols missing from the symbol table instead of falling back to epsilon,
+    /// and on rules that compile to empty transducers
+    #[arg(long)]
+    strict_symbols: bool,
+    /// Weight of each repetition of a `*` or `+` in rules, to prefer fewer repetitions
+    #[arg(long, default_value_t = 0.0)]
+    closure_weight: f32,
+    /// Where each rule writes its target: after the word, with the source
+    /// brought back to its underlying form, or in place of the source
+    #[arg(long, value_enum, default_value_t = TargetPlacement::Trailing)]
+    target_placement: TargetPlacement,
+    // Divergences found by the verification are fatal under --strict-symbols.
+    #[command(flatten)]
+    verify: VerifyArgs,
+}
+
+impl LinearizeArgs {
+    fn pipeline(&self, symt: Arc<SymbolTable>, out_dir: &OutDir) -> LinearPipeline {
+        LinearPipeline {
+            symt,
+            rules_dir: PathBuf::from(&self.rules_dir),
+            workdir: out_dir.path(&self.workdir),
+            opts: LinearOptions {
+                strict: self.strict_symbols,
+                closure_weight: self.closure_weight,
+                verify: self.verify.options(self.strict_symbols),
+                verify_minimize: self.verify.minimize_options(self.strict_symbols),
+                nondeterminism: self.verify.nondeterminism(),
+                target: self.target_placement,
+            },
+            dump_macros: self.dump_macros,
+        }
+    }
 }
 
 #[derive(Debug, serde::Deserialize)]
 struct Entry {
     form: String,
     segmentation: String,
+    /// A known failure, from the optional `xfail` column (see `report.rs`).
+    #[serde(default, deserialize_with = "deserialize_xfail")]
+    xfail: bool,
     //lx_neg: String,
     //lx_comto: String,
 }
 
-pub fn apply_fst_to_output_string(
+fn deserialize_xfail<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    let value = <String as serde::Deserialize>::deserialize(deserializer)?;
+    match value.trim().to_lowercase().as_str() {
+        "" | "0" | "false" | "no" => Ok(false),
+        "1" | "true" | "yes" | "x" | "xfail" => Ok(true),
+        other => Err(serde::de::Error::custom(format!("xfail must be empty, x, 0/1, true/false or yes/no, not '{}'", other))),
+    }
+}
+
+pub fn apply_fst_to_output_string<F: Compose<AnalysisAcceptor>>(
     symt: Arc<SymbolTable>,
-    mut fst: VectorFst<TropicalWeight>,
+    fst: F,
     output: String,
-) -> anyhow::Result<VectorFst<TropicalWeight>> {
-    let symt: Arc<SymbolTable> = symt.clone();
-    let mut acc = rulefst::string_to_linear_automaton(symt, &output);
-    acc.set_symts_from_fst(&fst);
+    tokenization: Tokenization,
+    compose_filter: ComposeFilter,
+) -> anyhow::Result<F::Output> {
+    let acc = AnalysisAcceptor::of(&symt, &output, tokenization, None)?;
     // println!("acc={:?}", acc);
     // println!("fst={:?}", fst);
 
-    tr_sort(&mut fst, OLabelCompare {});
-    tr_sort(&mut acc, ILabelCompare {});
-
-    let composed_fst: VectorFst<TropicalWeight> = compose(fst, acc)?;
+    let composed_fst = fst.compose(&acc, compose_filter)?;
     // println!("composed_fst={:?}", composed_fst);
 
     Ok(composed_fst)
 }
 
-fn can_generate_form(fst: &VectorFst<TropicalWeight>, input: &str, form: &str, is_g3: bool, save_dot: Option<&Path>) -> Result<bool, Box<dyn std::error::Error>> {
-    let input = "#".to_string() + input + "#";
-    let output = "#".to_string() + form + "#";
-    let mut e2e = rulefst::apply_fst_to_string(fst.input_symbols().unwrap().clone(), fst.clone(), input).unwrap();
-    minimize_with_config(&mut e2e, MinimizeConfig::default().with_allow_nondet(true))?;
-    let paths_all = rulefst::decode_paths_through_fst(fst.input_symbols().unwrap().clone(), e2e.clone());
-    let mut seen = HashMap::new();
-    for (weight, result) in paths_all {
-        match seen.get(&result) {
-            Some(&w) => {
-                if w > weight {
-                    seen.insert(result.clone(), weight);
-                }
-            }
-            None => {
-                seen.insert(result.clone(), weight);
-            }
-            
+/// Log the size of an intermediate FST at trace level (enable with `RUST_LOG=trace`).
+fn log_fst_size(stage: &str, fst: &VectorFst<TropicalWeight>) {
+    if log::log_enabled!(log::Level::Trace) {
+        let num_trs: usize = fst.states_iter().map(|s| fst.num_trs(s).unwrap_or(0)).sum();
+        log::trace!("{}: num_states={}, num_trs={}", stage, fst.num_states(), num_trs);
+    }
+}
+
+/// The paths of `fst` on `input`.
+pub fn apply_fst_to_input_string(
+    fst: &SurfaceToAnalysisFst,
+    input: &str,
+    tokenization: Tokenization,
+    compose_filter: ComposeFilter,
+) -> anyhow::Result<SurfaceToAnalysisFst> {
+    let symt = fst.input_symbols().ok_or_else(|| anyhow::anyhow!("FST has no input symbol table"))?;
+    SurfaceAcceptor::of(symt, input, tokenization, None)?.compose(fst, compose_filter)
+}
+
+/// The lattice of analyses of `input` (already wrapped), minimized.
+fn analysis_lattice(fst: &SurfaceToAnalysisFst, input: String, tokenization: Tokenization, compose_filter: ComposeFilter) -> anyhow::Result<SurfaceToAnalysisFst> {
+    let mut e2e = apply_fst_to_input_string(fst, &input, tokenization, compose_filter)?;
+    log_fst_size("e2e (composed)", &e2e);
+    minimize_nondet(&mut e2e.0, Nondeterminism::Allow)?;
+    log_fst_size("e2e (minimized)", &e2e);
+    Ok(e2e)
+}
+
+/// The distinct analyses in `e2e` with their weights, best first: the N best
+/// with `max_paths` (those of the N best paths, with `k_paths`), otherwise up to
+/// [`DEFAULT_MAX_OUTPUTS`], ties ordered by `ranker`. With `raw_labels`, each
+/// analysis is its output labels with their symbols, epsilons included.
+#[allow(clippy::too_many_arguments)]
+fn candidate_analyses(fst: &SurfaceToAnalysisFst, e2e: &SurfaceToAnalysisFst, max_paths: Option<usize>, k_paths: bool, raw_labels: bool, markers: Option<&SourceMarkers>, ranker: &dyn CandidateRanker) -> anyhow::Result<Vec<(TropicalWeight, String)>> {
+    let symt = fst.output_symbols().ok_or_else(|| anyhow::anyhow!("FST has no output symbol table"))?;
+    let decode = |lattice: &VectorFst<TropicalWeight>, cap: usize| match markers {
+        _ if raw_labels => decode_raw_outputs(lattice, Some(cap), ranker, symt),
+        Some(markers) => markers.decode_paths(symt, lattice, Some(cap), ranker),
+        None => decode_distinct_outputs(lattice, Some(cap), ranker, |olabels| display_labels(symt, olabels)),
+    };
+    match max_paths {
+        Some(n) if k_paths => {
+            let nbest = shortest_path_with_config(&e2e.0, ShortestPathConfig::default().with_nshortest(n))?;
+            log_fst_size("e2e (n-best)", &nbest);
+            decode(&nbest, n)
         }
+        Some(n) => k_best_distinct(e2e, n, |nbest| decode(nbest, n)),
+        None => decode(e2e, DEFAULT_MAX_OUTPUTS),
     }
-    for (result, weight) in seen.iter().sorted_by(|(_, w1), (_, w2)| w1.value().partial_cmp(&w2.value()).unwrap_or(Ordering::Equal)) {
+}
+
+#[allow(clippy::too_many_arguments)]
+fn can_generate_form(fst: &SurfaceToAnalysisFst, input: &str, form: &str, g3_to_base: Option<&AnalysisToAnalysisFst>, fmt: &AnalysisFormat, tokenization: Tokenization, compose_filter: ComposeFilter, ranker: &dyn CandidateRanker, max_paths: Option<usize>, k_paths: bool, raw_labels: bool, markers: Option<&SourceMarkers>, tones: &ToneSet, save_dot: Option<&Path>) -> anyhow::Result<bool> {
+    fmt.check_gold(form)?;
+    let input = fmt.wrap(input);
+    let output = fmt.wrap(form);
+    log::trace!("can_generate_form: input={}, output={}", input, output);
+    let symt = fst.output_symbols().ok_or_else(|| anyhow::anyhow!("FST has no output symbol table"))?;
+    let mut e2e = analysis_lattice(fst, input, tokenization, compose_filter)?;
+    for (weight, result) in candidate_analyses(fst, &e2e, max_paths, k_paths, raw_labels, markers, ranker)? {
         println!("result={}, weight={}", result, weight);
     }
     /*
      */
-    let mut generated = if is_g3 {
-        apply_fst_to_output_string(fst.output_symbols().unwrap().clone(), e2e, output)?
+    if let Some(markers) = markers {
+        markers.strip(&mut e2e.0)?;
+    }
+    let mut generated = if let Some(get_base) = g3_to_base {
+        let gen_output = apply_fst_to_output_string(symt.clone(), get_base.clone(), output.clone(), tokenization, compose_filter)?;
+        log_fst_size("gen_output", &gen_output);
+        e2e.compose(&gen_output, compose_filter)?
     } else {
-        let get_base = get_fst_g3_to_base(fst.output_symbols().unwrap().clone())?;
-        let gen_output = apply_fst_to_output_string(fst.output_symbols().unwrap().clone(), get_base, output)?;
-        tr_sort(&mut e2e, OLabelCompare {});
-        compose(e2e, gen_output)?
+        apply_fst_to_output_string(symt.clone(), e2e, output.clone(), tokenization, compose_filter)?
     };
-    minimize_with_config(&mut generated, MinimizeConfig::default().with_allow_nondet(true))?;
-    if let Some(path) = save_dot { generated.clone().draw(path, &DrawingConfig::default())?; }
-    let paths = rulefst::decode_paths_through_fst(fst.output_symbols().unwrap().clone(), generated);
+    log_fst_size("generated (composed)", &generated);
+    minimize_nondet(&mut generated.0, Nondeterminism::Allow)?;
+    log_fst_size("generated (minimized)", &generated);
+    if let Some(path) = save_dot { generated.0.clone().draw(path, &DrawingConfig::default())?; }
+    let paths = decode_distinct_outputs(&generated, Some(1), ranker, |olabels| display_labels(symt, olabels))?;
     if let Some((_, result)) = paths.first() {
-        println!("result={}", result);
-        Ok(result == &("#".to_string() + form + "#"))
+        let analysis = fmt.split(fmt.strip(result));
+        println!("result={} (base={}, morphs={:?}, melody={}, processes={:?})", result, analysis.base, analysis.morphs, fmt.melody(fmt.strip(result), tones), analysis.processes);
+        Ok(result == &output)
     }
     else {
         println!("No result");
@@ -120,226 +1047,1674 @@ fn can_generate_form(fst: &VectorFst<TropicalWeight>, input: &str, form: &str, i
     }
 }
 
-fn get_symt_from_file(path: &str) -> anyhow::Result<Arc<SymbolTable>> {
-    let data = std::fs::read_to_string(path)?.to_lowercase();
-    let syms = data.split_terminator('\n').map(|c| nfd_normalize(c)).collect::<Vec<_>>(); // Add the super-final state symbol
+/// Smoke tests run when no test file is given, embedded so that none is needed
+/// at runtime.
+const SMOKE_TESTS: &str = include_str!("../smoke_tests.csv");
 
-    let mut symt_inner = SymbolTable::new();
-    symt_inner.add_symbols(syms);
-    symt_inner.add_symbol("#");
-    println!("symt={:?}", symt_inner);
-    let symt = Arc::new(symt_inner);
-    Ok(symt)
+/// A smoke test file in the working directory that replaces [`SMOKE_TESTS`].
+const SMOKE_TESTS_FILE: &str = "smoke_tests.csv";
+
+/// The items of the test CSV `reader`, read one row at a time; rows without a
+/// segmentation are skipped.
+fn entries<R: Read>(name: &str, reader: R) -> impl Iterator<Item = anyhow::Result<Entry>> + use<R> {
+    let name = name.to_string();
+    csv::Reader::from_reader(reader)
+        .into_deserialize::<Entry>()
+        .map(move |r| r.with_context(|| format!("Failed to read {}", name)))
+        .inspect(|r| if let Ok(record) = r { println!("{:?}", record) })
+        .filter(|r| !matches!(r, Ok(record) if record.segmentation.is_empty()))
 }
 
-fn get_fst_g3_to_base(symt: Arc<SymbolTable>) -> anyhow::Result<VectorFst<TropicalWeight>> {
-    let raw_script = r"\>[1234\>]*} -> 0 / {[1234]* _ 
-{ -> 0 / _ [1234]+";
-    let (_, (script, _what)) = ruleparse::parse_script(
-        raw_script
-    )?;
-    //println!("script={:?}", script);
-    let mut fst = rulefst::compile_script(symt.clone(),script.clone())?;
-    tr_sort(&mut fst, ILabelCompare {});
+fn parse_entries(name: &str, text: &str) -> anyhow::Result<Vec<Entry>> {
+    entries(name, text.as_bytes()).collect()
+}
+
+fn parse_tests(name: &str, text: &str) -> anyhow::Result<Vec<(String, String)>> {
+    Ok(parse_entries(name, text)?.into_iter().map(|e| (e.form, e.segmentation)).collect())
+}
+
+fn read_tests(testfile: &str, encoding: Option<TextEncoding>) -> anyhow::Result<Vec<(String, String)>> {
+    parse_tests(testfile, &read_text(Path::new(testfile), encoding)?)
+}
+
+/// The items of `testfile`, streamed from disk so that a test file of any size
+/// can be checked. Without one, the smoke tests: `smoke_tests.csv` in the
+/// working directory if there is one, and the copy shipped with the crate
+/// otherwise.
+fn stream_entries(testfile: Option<&str>, encoding: Option<TextEncoding>) -> anyhow::Result<Box<dyn Iterator<Item = anyhow::Result<Entry>>>> {
+    let testfile = testfile.or(Path::new(SMOKE_TESTS_FILE).exists().then_some(SMOKE_TESTS_FILE));
+    Ok(match testfile {
+        Some(testfile) => Box::new(entries(testfile, open_text(Path::new(testfile), encoding)?)),
+        None => Box::new(entries("built-in smoke tests", SMOKE_TESTS.as_bytes())),
+    })
+}
+
+/// Read an FST written by `build` (or as JSON, if `path` ends in `.json`).
+fn load_fst(path: &str) -> anyhow::Result<VectorFst<TropicalWeight>> {
+    if path.ends_with(".json") {
+        read_json_fst(Path::new(path))
+    } else {
+        read_fst(Path::new(path))
+    }
+}
+
+/// The symbol table to query `fst` with. That is `symt` (from chars.txt),
+/// unless the FST carries its own ordering of the same symbols, as after
+/// `build --relabel-by-frequency`; anything composed with the FST, such as the
+/// G3-to-base converter or a filter, must then be compiled against that one.
+fn fst_symt(fst: &VectorFst<TropicalWeight>, symt: Arc<SymbolTable>) -> Arc<SymbolTable> {
+    match fst.input_symbols() {
+        Some(own) if own.len() == symt.len() && symt_hash(own) != symt_hash(&symt) && symt.iter().all(|(_, s)| own.contains_symbol(s)) => own.clone(),
+        _ => symt,
+    }
+}
+
+/// The filter given by `spec`: the FST at that path if there is one,
+/// relabelled onto `symt`, and otherwise `spec` compiled as a regular
+/// expression.
+fn load_filter(symt: Arc<SymbolTable>, spec: &str) -> anyhow::Result<AnalysisAcceptor> {
+    if !Path::new(spec).is_file() {
+        return compile_filter(symt, spec);
+    }
+    let mut fst = load_fst(spec)?;
+    align_filter(symt, &mut fst).with_context(|| format!("Filter {} does not fit the symbol table", spec))?;
+    Ok(AnalysisAcceptor(fst))
+}
+
+/// Read the FST at `path`, without the source markers it has if it was built
+/// with `--attribute-sources`.
+fn load_fst_unmarked(path: &str) -> anyhow::Result<VectorFst<TropicalWeight>> {
+    let mut fst = load_fst(path)?;
+    if Path::new(&format!("{}.sources", path)).exists() {
+        SourceMarkers::read(Path::new(path))?.strip(&mut fst)?;
+    }
     Ok(fst)
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+/// Read the FST at `path`, with its source table if `attribute_sources`;
+/// otherwise without the source markers it has if it was built with
+/// `--attribute-sources`. With `prepared_cache`, the FST comes sorted for
+/// composition, from the cache if it was sorted before.
+fn load_fst_with_markers(path: &str, attribute_sources: bool, prepared_cache: Option<&Path>) -> anyhow::Result<(VectorFst<TropicalWeight>, Option<SourceMarkers>)> {
+    let mut fst = match prepared_cache {
+        Some(dir) => sorted_fst_cached(Path::new(path), dir, || load_fst(path))?,
+        None => load_fst(path)?,
+    };
+    if attribute_sources {
+        return Ok((fst, Some(SourceMarkers::read(Path::new(path))?)));
+    }
+    // Stripping relabels outputs only, so a sorted FST stays sorted.
+    if Path::new(&format!("{}.sources", path)).exists() {
+        SourceMarkers::read(Path::new(path))?.strip(&mut fst)?;
+    }
+    Ok((fst, None))
+}
 
-    // Import script from file
-    let symt = get_symt_from_file("chars.txt")?;
-    if args.linearize {
-        let raw_script = std::fs::read_to_string("rules/to_linear_base.txt")?;
-        let (_, (script, _)) = ruleparse::parse_script(
-            raw_script.as_str()
-        ).unwrap_or_else(|_| panic!("Failed to parse script"));
-        let mut _fst= compile_as_linear(symt.clone(), script)?;
-        /*
-        let mut fsts = Vec::new();
-        for i in 1..5usize {
-            let raw_script = std::fs::read_to_string(format!("rules/to_linear_{}.txt",i))?;
-            let (_, (script, _)) = ruleparse::parse_script(
-                raw_script.as_str()
-            ).unwrap_or_else(|_| panic!("Failed to parse script"));
-            let mut fst= compile_as_linear(symt.clone(), script)?;
-            // These ones actually should be deterministic
-            //fst = determinize_with_config(&fst, DeterminizeConfig { delta: 1e-7, det_type: DeterminizeType::DeterminizeDisambiguate })?;
-            if i == 1 { tr_sort(&mut fst, OLabelCompare {}); }
-            else { tr_sort(&mut fst, ILabelCompare {}); }
-            if let Some(path_output) = &args.openfst { fst.write_text(Path::new(path_output).join(format!("linear_{}.fst",i)))?; }
-            fsts.push(fst);
-        }
-        println!("Composing...");
-        let mut composed_fst: VectorFst<TropicalWeight> = compose(fsts[0].clone(), fsts[1].clone())?;
-        println!("Composition 1/3 complete");
-        minimize_with_config(&mut composed_fst, MinimizeConfig { delta: 1e-7, allow_nondet: true })?;
-        tr_sort(&mut composed_fst, OLabelCompare {});
-        println!("Composing...");
-        composed_fst = compose(composed_fst, fsts[2].clone())?;
-        println!("Composition 2/3 complete");
-        minimize_with_config(&mut composed_fst, MinimizeConfig { delta: 1e-7, allow_nondet: true })?;
-        tr_sort(&mut composed_fst, OLabelCompare {});
-        println!("Composing...");
-        composed_fst = compose(composed_fst, fsts[3].clone())?;
-        println!("Composition 3/3 complete");
-        composed_fst.write(args.outpath.clone())?;
-         */
-        return Ok(());
+/// Parse a `NAME=PATH` model of `segment --serve`.
+fn parse_model(s: &str) -> anyhow::Result<(String, String)> {
+    match s.split_once('=') {
+        Some((name, path)) if !name.trim().is_empty() && !path.is_empty() => Ok((name.trim().to_string(), path.to_string())),
+        _ => Err(anyhow::anyhow!("Expected NAME=PATH, got '{}'", s)),
     }
+}
 
+/// The name `value` is given by on the command line.
+fn value_name(value: &impl clap::ValueEnum) -> String {
+    value.to_possible_value().map_or_else(String::new, |v| v.get_name().to_string())
+}
 
-    let mut fst = if let Some(load) = args.load {
-        VectorFst::<TropicalWeight>::read(load)?
-    } else if let Some(src) = args.srcdir {
-        /*
-        let sigmastar = sigma_star(symt.clone())?;
-        let sigmastar_5xweight = sort_and_compose(
-            &sort_and_compose(
-                        &sigmastar,
-                        &sort_and_compose(
-                            &sigmastar,
-                            &sigmastar
-                        )?
-                    )?,
-            &sort_and_compose(
-                &sigmastar,
-                &sigmastar
-            )? 
-        )?;
-         */
-        let mut fst = weighted_sigma_star(symt.clone(), 10.0)?;//sort_and_compose(&sigmastar_5xweight, &sigmastar_5xweight)?;
-        let mut num_compose = 1;
-        for file in std::fs::read_dir(src)? {
-            let filepath = file?.path();
-            println!("\nProcessing file: {}", filepath.clone().display());
-            let raw_script = std::fs::read_to_string(filepath)?;
-            let (_, (script, _)) = ruleparse::parse_script(
-                raw_script.as_str()
-            ).unwrap_or_else(|_| panic!("Failed to parse script"));
-            let mut num_rules = 0;
-            for (i, rule) in enumerate(script.clone()) {
-                println!("Rule {}: {:?}", i+1, rule);
-                if let ruleparse::Statement::Rule(_) = rule {
-                    num_rules += 1;
+#[allow(clippy::too_many_arguments)]
+fn run_build(
+    symt: Arc<SymbolTable>,
+    outpath: &str,
+    srcdir: Option<&str>,
+    skip_bad_files: bool,
+    weight_offset: &[(String, f32)],
+    fallback: FallbackBoundary,
+    application: RuleApplication,
+    explain: Option<&str>,
+    weights_from_counts: Option<&str>,
+    encoding: Option<TextEncoding>,
+    boundary_check: bool,
+    require_epsilon_free: bool,
+    canonical_order: bool,
+    relabel_by_frequency: bool,
+    normalizer: Option<Option<&str>>,
+    final_paths: Option<(usize, Option<u64>)>,
+    attribute_sources: bool,
+    optimize: Option<OptimizeBudget>,
+    no_min: bool,
+    nondeterminism: Nondeterminism,
+    no_connect: bool,
+    no_dedup_arcs: bool,
+    openfst: Option<&str>,
+    json_fst: Option<&str>,
+    verify: Option<(VerifyOptions, OnDivergence)>,
+    mut checks: RuleChecks,
+    memory: Option<&MemoryMeter>,
+) -> anyhow::Result<()> {
+    if optimize.is_some() && (no_min || no_connect) {
+        return Err(Failure::Usage.mark(anyhow::anyhow!("--optimize auto chooses whether to minimize and connect; it takes neither --no-min nor --no-connect")));
+    }
+    let files = match srcdir {
+        Some(src) => list_rule_files(Path::new(src), skip_bad_files)?,
+        None => default_rule_files(),
+    };
+    // The flags that change what gets built, for the provenance in the sidecar.
+    let mut variant: Vec<String> = weight_offset.iter().map(|(file, w)| format!("--weight-offset {}={}", file, w)).collect();
+    if application != RuleApplication::default() {
+        variant.push(format!("--application {}", value_name(&application)));
+    }
+    if fallback != FallbackBoundary::default() {
+        variant.push(format!("--fallback-boundary {}", value_name(&fallback)));
+    }
+    if let Some(gold) = weights_from_counts {
+        variant.push(format!("--weights-from-counts {}", gold));
+    }
+    if let Some(budget) = optimize {
+        variant.push(format!("--optimize auto --optimize-time-budget {} --optimize-max-states {}", budget.time.as_secs_f64(), budget.max_states));
+    }
+    for (set, flag) in [(attribute_sources, "--attribute-sources"), (no_min, "--no-min"), (no_connect, "--no-connect"), (no_dedup_arcs, "--no-dedup-arcs"), (relabel_by_frequency, "--relabel-by-frequency")] {
+        if set {
+            variant.push(flag.to_string());
+        }
+    }
+    match normalizer {
+        Some(Some(graphemes)) => variant.push(format!("--normalizer-fst {}", graphemes)),
+        Some(None) => variant.push("--normalizer-fst".to_string()),
+        None => {}
+    }
+    let provenance = Provenance::of_build(&files, &variant.join(" "))?;
+    let markers = attribute_sources.then(|| SourceMarkers::new(&files));
+    let weight_offsets: HashMap<String, f32> = weight_offset.iter().cloned().collect();
+    let mut scripts = files.iter().map(|f| Ok((f.clone(), load_script(f)?))).collect::<anyhow::Result<Vec<_>>>()?;
+    if let Some(gold) = weights_from_counts {
+        let graphemes = get_grapheme_map(None, &symt, encoding)?;
+        let golds = map_test_inputs(&graphemes, &symt, read_tests(gold, encoding)?)?;
+        let learned = learn_rule_weights(symt.clone(), &scripts, &weight_offsets, application, &golds)?;
+        print!("{}", learned.summary());
+        let path = format!("{}.rule_weights.csv", outpath);
+        create_atomic(Path::new(&path), |file| learned.write_csv(file))?;
+        println!("Wrote the learned rule weights to {}", path);
+        learned.apply(&mut scripts);
+    }
+    let (mut fst, ladder) = match optimize {
+        None => (build_from_scripts(symt.clone(), scripts, &weight_offsets, fallback, application, markers.as_ref(), memory, &mut checks)?, None),
+        Some(budget) => {
+            let union = union_scripts(symt.clone(), scripts, &weight_offsets, fallback, application, markers.as_ref(), memory, &mut checks)?.into_fst();
+            let (fst, report) = optimize_auto(symt.clone(), union, budget, verify, nondeterminism)?;
+            if let Some(memory) = memory {
+                memory.stage(&format!("optimize ({})", report.level.name()));
+            }
+            (fst, Some(report))
+        }
+    };
+    print!("{}", checks.summary());
+    if let Some(word) = explain {
+        let mut unmarked = fst.clone();
+        if let Some(markers) = &markers {
+            markers.strip(&mut unmarked)?;
+        }
+        match explain_weights(symt.clone(), &unmarked, &files, &weight_offsets, application, word)? {
+            Some(explanation) => print!("{}", explanation.render()),
+            None => println!("{} has no analysis, so there is no weight to explain", word),
+        }
+    }
+    if boundary_check {
+        check_edge_boundaries(&fst, markers.as_ref(), symt.clone(), &AnalysisFormat::default())?;
+    }
+    let write = |fst: &mut VectorFst<TropicalWeight>| -> anyhow::Result<()> {
+        if require_epsilon_free {
+            check_epsilon_free(fst)?;
+        }
+        if canonical_order {
+            build::canonical_order(fst)?;
+        }
+        write_fst(fst, Path::new(outpath))
+    };
+    write(&mut fst)?;
+    if let Some(markers) = &markers {
+        markers.write(Path::new(outpath))?;
+    }
+    if let Some(path_output) = openfst {
+        write_fst_text(&fst, &Path::new(path_output).join("fst_segmentation_notminimized.fst"))?;
+    }
+    if ladder.is_none() && !no_min {
+        println!("Minimizing...");
+        {
+            let _span = profile_span!("minimize");
+            minimize_verified(&symt, &mut fst, verify, nondeterminism)?;
+        }
+        println!("Done!");
+        if let Some(memory) = memory {
+            memory.stage("minimize");
+        }
+        write(&mut fst)?;
+        if let Some(path_output) = openfst { write_fst_text(&fst, &Path::new(path_output).join("fst_segmentation.fst"))?; }
+    }
+    if let Some((max_len, threshold)) = final_paths {
+        let count = count_accepting_paths(&fst, max_len)?;
+        let shown = if count == u128::MAX { format!("more than {}", count) } else { count.to_string() };
+        println!("Accepting paths of at most {} arcs: {}", max_len, shown);
+        if let Some(threshold) = threshold.filter(|&t| count > t as u128) {
+            warn(format!("Warning: {} accepting paths of at most {} arcs, more than {}; the rules may be over-generating", shown, max_len, threshold));
+        }
+    }
+    let connect_sizes = if no_connect || ladder.is_some() {
+        None
+    } else {
+        let (before, after) = {
+            let _span = profile_span!("connect");
+            connect_with_sizes(&mut fst)?
+        };
+        if let Some(memory) = memory {
+            memory.stage("connect");
+        }
+        println!(
+            "Connect removed {} of {} states and {} of {} arcs",
+            before.num_states - after.num_states,
+            before.num_states,
+            before.num_trs - after.num_trs,
+            before.num_trs
+        );
+        write(&mut fst)?;
+        Some((before, after))
+    };
+    let dedup_sizes = if no_dedup_arcs {
+        None
+    } else {
+        let (before, after) = dedup_arcs_with_sizes(&mut fst);
+        println!("Arc dedup removed {} of {} arcs", before.num_trs - after.num_trs, before.num_trs);
+        write(&mut fst)?;
+        Some((before, after))
+    };
+    let normalization = match normalizer {
+        Some(graphemes) => {
+            let normalizer = compile_normalizer(symt.clone(), &get_grapheme_map(graphemes, &symt, encoding)?)?;
+            fst = compose_tokenizer(&normalizer, &SurfaceToAnalysisFst(fst), ComposeFilter::Auto)?.0;
+            println!("Composed the normalizer: {} states, {} input symbols", fst.num_states(), normalizer.input_symbols().map_or(0, |s| s.len()));
+            write(&mut fst)?;
+            Normalization::Embedded
+        }
+        None => Normalization::String,
+    };
+    let relabeling = if relabel_by_frequency {
+        let relabeling = frequency_relabeling(&fst, &symt)?;
+        apply_relabeling(&mut fst, &relabeling)?;
+        println!("Relabelled {} of {} symbols by frequency", relabeling.moves.len(), symt.len());
+        write(&mut fst)?;
+        Some(relabeling)
+    } else {
+        None
+    };
+    let provenance = Provenance { symt_hash: fst.input_symbols().map(|s| format!("{:016x}", symt_hash(s))), normalization: Some(normalization), ..provenance };
+    write_build_info(Path::new(outpath), FstSize::of(&fst), &ProducibleLabels::of(&fst), connect_sizes, dedup_sizes, relabeling.as_ref(), ladder.as_ref(), &checks, &provenance)?;
+    if let Some(path) = json_fst {
+        write_json_fst(&fst, Path::new(path))?;
+    }
+    Ok(())
+}
+
+/// Build an FST from the rule named `name` (`% @name` after the rule) alone,
+/// with the macros of its file and the identity fallback, for testing the rule
+/// on its own. It is written to `rule_<name>.fst` under `out_dir`, whose path
+/// is returned.
+fn build_rule_fst(symt: Arc<SymbolTable>, srcdir: Option<&str>, skip_bad_files: bool, name: &str, out_dir: &OutDir) -> anyhow::Result<String> {
+    let files = match srcdir {
+        Some(src) => list_rule_files(Path::new(src), skip_bad_files)?,
+        None => default_rule_files(),
+    };
+    let mut found = Vec::new();
+    for file in files {
+        if let Some(script) = load_script(&file)?.only_rule(name) {
+            found.push((file, script));
+        }
+    }
+    match found.len() {
+        0 => anyhow::bail!("No rule is named @{}", name),
+        1 => {}
+        _ => {
+            let files: Vec<String> = found.iter().map(|(f, _)| f.display().to_string()).collect();
+            anyhow::bail!("Rules in {} are all named @{}", files.join(", "), name)
+        }
+    }
+    let mut checks = RuleChecks::default();
+    let fst = build_from_scripts(symt, found, &HashMap::new(), FallbackBoundary::default(), RuleApplication::default(), None, None, &mut checks)?;
+    print!("{}", checks.summary());
+    let path = out_dir.path(format!("rule_{}.fst", name));
+    write_fst(&fst, &path)?;
+    Ok(path.display().to_string())
+}
+
+/// What `test` checks and how it reports, from the options of
+/// [`Command::Test`].
+struct TestOptions<'a> {
+    testfile: Option<&'a str>,
+    max_paths: Option<usize>,
+    k_paths: bool,
+    raw_labels: bool,
+    fast_check: bool,
+    both_directions: bool,
+    retry_lenient: bool,
+    explain_no_result: bool,
+    partial_credit: Option<Costs>,
+    ambiguity: Option<AmbiguityReport>,
+    attribute_sources: bool,
+    json_report: Option<&'a str>,
+    /// The path of the HTML report, and the most failures it lists.
+    html_report: Option<(&'a str, usize)>,
+    timeout: Option<Duration>,
+    tag: Option<&'a str>,
+    prepared_cache: Option<&'a Path>,
+    log: &'a LogArgs,
+}
+
+fn run_test(
+    symt: Arc<SymbolTable>,
+    fst_path: &str,
+    input: &InputArgs,
+    opts: TestOptions,
+    encoding: Option<TextEncoding>,
+    out_dir: &OutDir,
+    memory: Option<&MemoryMeter>,
+) -> anyhow::Result<()> {
+    let TestOptions { testfile, max_paths, k_paths, raw_labels, fast_check, both_directions, retry_lenient, explain_no_result, partial_credit, mut ambiguity, attribute_sources, json_report, html_report, timeout, tag, prepared_cache, log: log_args } = opts;
+    let fmt = input.format();
+    fmt.validate(&symt)?;
+    let limits = input.limits();
+    let (mut fst, markers) = load_fst_with_markers(fst_path, attribute_sources, prepared_cache)?;
+    fmt.unmark_morph_outputs(&mut fst)?;
+    let run = RunInfo { fst: fst_path.to_string(), tag: tag.map(String::from), provenance: read_provenance(Path::new(fst_path))? };
+    let symt = fst_symt(&fst, symt);
+    let graphemes = input_graphemes(fst_path, input.graphemes.as_deref(), &symt, encoding)?;
+    let entries = stream_entries(testfile, encoding)?;
+    let mut log = log_args.open(out_dir, &run)?;
+    let g3_to_base = input.g3_to_base_cached(&symt, prepared_cache)?;
+    // Gold forms are compared with what the FST outputs, converted to base
+    // forms if asked.
+    let producible = ProducibleLabels::from_sidecar(Path::new(fst_path), &symt).unwrap_or_else(|| ProducibleLabels::of(&fst));
+    let producible = match &g3_to_base {
+        Some(g3_to_base) => producible.through(g3_to_base),
+        None => producible,
+    };
+    // The reverse direction always goes through the prepared FST.
+    let prepared = if fast_check || both_directions || partial_credit.is_some() || ambiguity.is_some() || html_report.is_some() || explain_no_result {
+        let mut fst = fst.clone();
+        if let Some(markers) = &markers {
+            markers.strip(&mut fst)?;
+        }
+        Some(Arc::new(PreparedFst::new(SurfaceToAnalysisFst(fst), g3_to_base.clone(), fmt.clone())?.with_tie_break(input.tie_break).with_tokenization(input.tokenization).with_compose_filter(input.compose_filter).with_limits(input.limits())))
+    } else {
+        None
+    };
+    // Shared with the worker threads that checks run on under --timeout.
+    let fst = Arc::new(SurfaceToAnalysisFst(fst));
+    let g3_to_base = g3_to_base.map(Arc::new);
+    let secs = timeout.map_or(0.0, |t| t.as_secs_f64());
+    // Only the JSON and HTML reports list every item; otherwise nothing is
+    // kept per item.
+    let report = || if json_report.is_some() || html_report.is_some() { TestReport::default() } else { TestReport::counts_only() };
+    let (mut forward, mut reverse) = (report(), report());
+    let mut lenient_passes = 0;
+    // Interrupted, the checks stop where they are, and the reports cover the
+    // items checked so far.
+    let checked = (|| -> anyhow::Result<()> {
+        for entry in entries {
+            CancelToken::interrupt().check()?;
+            let entry = entry?;
+            let xfail = entry.xfail;
+            let (word, form) = &match map_test_input(&graphemes, &symt, &entry.form, &entry.segmentation) {
+                // Under --retry-lenient the characters with no symbol are skipped below.
+                Err(_) if retry_lenient => (nfd_normalize(&entry.form), nfd_normalize(&entry.segmentation)),
+                mapped => mapped?,
+            };
+            let form = &fmt.for_comparison(form);
+            let both: &[Direction] = if both_directions { &[Direction::Forward, Direction::Reverse] } else { &[Direction::Forward] };
+            // Nothing is built for an input too long to check.
+            if let Err(limit) = limits.check_len(&symt, word, input.tokenization) {
+                let detail = format!(": {}", limit);
+                for &direction in both {
+                    let report = if direction == Direction::Forward { &mut forward } else { &mut reverse };
+                    let outcome = report.record_limit(word, form, limit);
+                    let item = LogItem { direction, input: word, form, outcome, detail: &detail };
+                    println!("{}", paint(Stream::Stdout, outcome.style(), item.line()));
+                    log.item(&item)?;
+                }
+                continue;
+            }
+            if let (Some(ambiguity), Some(prepared)) = (&mut ambiguity, &prepared)
+                && ambiguity.wants(word)
+            {
+                let (prepared, input, margin) = (prepared.clone(), word.clone(), ambiguity.margin());
+                // An input whose analyses run out of time or are too complex is left out.
+                if let Some(Ok(competitors)) = with_timeout(timeout, move || competitors(&prepared, &input, margin)).map(given_up).transpose()? {
+                    ambiguity.add(word, competitors);
+                }
+            }
+            // No rule can make the FST output a symbol it never outputs, so the
+            // item is an error in the data, and checking it would only fail.
+            let unproducible = producible.unproducible(&symt, form, input.tokenization);
+            if !unproducible.is_empty() {
+                let detail = format!(": {}", unproducible.join(", "));
+                for &direction in both {
+                    let report = if direction == Direction::Forward { &mut forward } else { &mut reverse };
+                    let outcome = report.record_unproducible(word, form, unproducible.clone());
+                    let item = LogItem { direction, input: word, form, outcome, detail: &detail };
+                    println!("{}", paint(Stream::Stdout, outcome.style(), item.line()));
+                    log.item(&item)?;
                 }
+                continue;
             }
-            let mut fst_oth = rulefst::compile_script(symt.clone(),script.clone())?;
-            if num_rules > num_compose {
-                println!("Reweighting...");
-                while num_compose < num_rules {
-                    concat::<TropicalWeight, VectorFst<_>, VectorFst<_>>(
-                        &mut fst,
-                        &rustfst::fst![0 => 0; 10.0]
-                    )?;
-                    num_compose += 1;
-                    
+            let check = |word: &str, form: &str| {
+                let (fst, g3_to_base, prepared, markers, fmt, tones) = (fst.clone(), g3_to_base.clone(), prepared.clone(), markers.clone(), fmt.clone(), input.tones.clone());
+                let (word, form, tie_break, tokenization, compose_filter) = (word.to_string(), form.to_string(), input.tie_break, input.tokenization, input.compose_filter);
+                move || match prepared.as_deref() {
+                    Some(prepared) if fast_check => accepts_pair(prepared, &word, &form),
+                    _ => can_generate_form(&fst, &word, &form, g3_to_base.as_deref(), &fmt, tokenization, compose_filter, tie_break.ranker(&fmt).as_ref(), max_paths, k_paths, raw_labels, markers.as_ref(), &tones, None),
                 }
-            } else {
-                for _ in 0..num_compose-num_rules {
-                    concat::<TropicalWeight, VectorFst<_>, VectorFst<_>>(
-                        &mut fst_oth,
-                        &rustfst::fst![0 => 0; 10.0]
-                    )?;
+            };
+            // Under --retry-lenient, the word and form with the parts that are not
+            // symbols skipped, and what was skipped.
+            let lenient = retry_lenient.then(|| {
+                let (word, skipped_word) = skip_missing_symbols(&symt, word, input.tokenization);
+                let (form, skipped_form) = skip_missing_symbols(&symt, form, input.tokenization);
+                (word, form, [skipped_word, skipped_form].concat())
+            });
+            let outcome = match &lenient {
+                // The strict check could only fail on the missing symbols.
+                Some((_, _, skipped)) if !skipped.is_empty() => forward.record(word, form, xfail, false),
+                _ => match with_timeout(timeout, check(word, form)).map(given_up).transpose()? {
+                    Some(Ok(passed)) => {
+                        if !fast_check && !passed && !xfail {
+                            println!("you get NOTHING. you LOSE. good DAY sir.");
+                        }
+                        // An item whose prediction runs out of time or is too
+                        // complex is left unscored. The HTML report only shows
+                        // the predictions of failures.
+                        let prediction = match prepared.clone() {
+                            Some(prepared) if partial_credit.is_some() || (html_report.is_some() && !passed) => {
+                                let word = word.clone();
+                                with_timeout(timeout, move || weighed_prediction(&prepared, &word)).map(given_up).transpose()?.and_then(Result::ok)
+                            }
+                            _ => None,
+                        };
+                        let score = partial_credit.zip(prediction.as_ref()).map(|(costs, prediction)| {
+                            Score::of(&symt, prediction.as_ref().map(|p| p.form.as_str()), form, input.tokenization, &input.tones, costs)
+                        });
+                        forward.record_checked(word, form, xfail, passed, score, prediction.filter(|_| html_report.is_some()))
+                    }
+                    Some(Err(limit)) => forward.record_limit(word, form, limit),
+                    None => forward.record_timeout(word, form),
+                },
+            };
+            let after = if outcome == Outcome::Timeout { format!(" after {}s", secs) } else { String::new() };
+            let retried = match lenient.filter(|_| matches!(outcome, Outcome::Fail | Outcome::XFail)) {
+                None => String::new(),
+                Some((_, _, skipped)) if skipped.is_empty() => " | lenient: no missing symbols, so a missing rule".to_string(),
+                Some((lenient_word, lenient_form, skipped)) => {
+                    let lenient = match with_timeout(timeout, check(&lenient_word, &lenient_form)).transpose()? {
+                        Some(true) => {
+                            lenient_passes += 1;
+                            Outcome::Pass.label()
+                        }
+                        Some(false) => Outcome::Fail.label(),
+                        None => Outcome::Timeout.label(),
+                    };
+                    format!(" | lenient {} -> {} {} (skipped {})", lenient_word, lenient_form, lenient, skipped.join(", "))
+                }
+            };
+            // Where the rules break down on an input they have no analysis of.
+            let explained = match prepared.clone().filter(|_| explain_no_result && matches!(outcome, Outcome::Fail | Outcome::XFail)) {
+                Some(prepared) => {
+                    let word = word.clone();
+                    let breakdown = move || -> anyhow::Result<_> {
+                        if accepts(&prepared, &word)? {
+                            return Ok(None);
+                        }
+                        let wrapped = prepared.fmt.wrap(&word);
+                        longest_accepted_prefix(&prepared.fst, &prepared.symt, &wrapped, prepared.tokenization, prepared.compose_filter).map(Some)
+                    };
+                    match with_timeout(timeout, breakdown).transpose()?.flatten() {
+                        Some(breakdown) => format!(" | no result: {}", breakdown.describe()),
+                        None => String::new(),
+                    }
+                }
+                None => String::new(),
+            };
+            let detail = format!("{}{}{}", after, retried, explained);
+            let item = LogItem { direction: Direction::Forward, input: word, form, outcome, detail: &detail };
+            println!("{}", paint(Stream::Stdout, outcome.style(), item.line()));
+            if outcome != Outcome::Pass {
+                log.item(&item)?;
+            }
+            if let Some(prepared) = prepared.clone().filter(|_| both_directions) {
+                // The best surface is only worth computing to explain a failure.
+                let check = {
+                    let (word, form) = (word.clone(), form.clone());
+                    move || -> anyhow::Result<(bool, Option<(TropicalWeight, String)>)> {
+                        let passed = recovers_input(&prepared, &word, &form)?;
+                        Ok((passed, if passed { None } else { best_surface(&prepared, &form)? }))
+                    }
+                };
+                let (outcome, best) = match with_timeout(timeout, check) {
+                    Some(checked) => {
+                        let (passed, best) = checked?;
+                        let outcome = reverse.record(word, form, xfail, passed);
+                        let best = match (outcome, best) {
+                            (Outcome::Pass | Outcome::XPass, _) => String::new(),
+                            (_, Some((weight, surface))) => format!(": best surface {} ({})", surface, weight),
+                            (_, None) => ": no surface".to_string(),
+                        };
+                        (outcome, best)
+                    }
+                    None => (reverse.record_timeout(word, form), format!(" after {}s", secs)),
+                };
+                let item = LogItem { direction: Direction::Reverse, input: word, form, outcome, detail: &best };
+                println!("{}", paint(Stream::Stdout, outcome.style(), item.line()));
+                if outcome != Outcome::Pass {
+                    log.item(&item)?;
                 }
             }
-            // */
-            println!("Unioning...");
-            union(&mut fst, &fst_oth)?;
         }
-        rm_epsilon(&mut fst)?;
-        fst.write(args.outpath.clone())?;
-        fst
+        Ok(())
+    })();
+    let write_reports = |forward: &TestReport, reverse: Option<&TestReport>| -> anyhow::Result<Vec<PathBuf>> {
+        let mut written = Vec::new();
+        if let Some(path) = json_report {
+            written.push(out_dir.path(path));
+            write_json_report(&out_dir.path(path), &run, forward, reverse)?;
+        }
+        if let Some((path, max_rows)) = html_report {
+            let aligner = Aligner { symt: &symt, tokenization: input.tokenization, tones: &input.tones, costs: partial_credit.unwrap_or_default() };
+            written.push(out_dir.path(path));
+            write_html_report(&out_dir.path(path), &run, forward, reverse, &aligner, max_rows)?;
+        }
+        Ok(written)
+    };
+    if let Err(e) = checked {
+        if !Cancelled::of(&e) {
+            return Err(e);
+        }
+        let written = write_reports(&forward, both_directions.then_some(&reverse))?;
+        if written.is_empty() {
+            return Err(e);
+        }
+        let paths = written.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(" and ");
+        return Err(e.context(format!("Interrupted; the items checked so far are reported in {}", paths)));
+    }
+    if let Some(memory) = memory {
+        memory.stage(if fast_check { "test (fast check)" } else { "test (compose)" });
+    }
+    let reverse = both_directions.then_some(reverse);
+    print!("{}", summary_header(fst_path, tag, run.provenance.as_ref()));
+    match &reverse {
+        Some(reverse) => {
+            println!("forward (input -> form): {}", paint(Stream::Stdout, forward.style(), forward.summary()));
+            println!("reverse (form -> input): {}", paint(Stream::Stdout, reverse.style(), reverse.summary()));
+        }
+        None => println!("{}", paint(Stream::Stdout, forward.style(), forward.summary())),
+    }
+    if let Some(mean) = &forward.partial_credit {
+        println!("partial credit: {}", mean.summary());
+    }
+    if retry_lenient {
+        println!("{} failures pass with the missing symbols skipped", lenient_passes);
+    }
+    for (direction, report) in [("->", Some(&forward)), ("<-", reverse.as_ref())] {
+        for item in report.into_iter().flat_map(|r| r.xpasses()) {
+            println!("{}", paint(Stream::Stdout, Style::Alert, format!("Unexpectedly passing: {} {} {}; remove its xfail mark", item.input, direction, item.form)));
+        }
+        for item in report.into_iter().flat_map(|r| r.timeouts()) {
+            println!("{}", paint(Stream::Stdout, Style::Warning, format!("Timed out after {}s: {} {} {}", secs, item.input, direction, item.form)));
+        }
+        for item in report.into_iter().flat_map(|r| r.given_up()) {
+            println!("{}", paint(Stream::Stdout, Style::Warning, format!("{}: {} {} {}", item.outcome.label(), item.input, direction, item.form)));
+        }
+    }
+    for line in ambiguity.iter().flat_map(AmbiguityReport::lines) {
+        println!("{}", line);
+    }
+    // Both directions skip the same items, so they are listed once.
+    for item in forward.unproducibles() {
+        let message = format!("Gold form {} of {} has symbols the FST never outputs: {}; fix the data", item.form, item.input, item.unproducible.join(", "));
+        println!("{}", paint(Stream::Stdout, Style::Warning, message));
+    }
+    write_reports(&forward, reverse.as_ref())?;
+    let problems = test_problems(&forward, reverse.as_ref());
+    if !problems.is_empty() {
+        return Err(Failure::Checks.mark(anyhow::anyhow!("{}", problems.join(", "))));
+    }
+    Ok(())
+}
+
+/// What makes a test run fail, for its error message: the unexpected
+/// failures, timeouts, and items given up on or not checkable.
+fn test_problems(forward: &TestReport, reverse: Option<&TestReport>) -> Vec<String> {
+    let failed = forward.failed + reverse.map_or(0, |r| r.failed);
+    let timed_out = forward.timeout + reverse.map_or(0, |r| r.timeout);
+    let mut problems = Vec::new();
+    if failed > 0 {
+        problems.push(format!("{} unexpected failures", failed));
+    }
+    if timed_out > 0 {
+        problems.push(format!("{} timeouts", timed_out));
+    }
+    let too_complex = forward.too_complex + reverse.map_or(0, |r| r.too_complex);
+    if too_complex > 0 {
+        problems.push(format!("{} checks too complex to finish", too_complex));
+    }
+    if forward.unproducible > 0 {
+        problems.push(format!("{} gold forms with unproducible symbols", forward.unproducible));
+    }
+    if forward.too_long > 0 {
+        problems.push(format!("{} inputs longer than --max-input-len", forward.too_long));
+    }
+    problems
+}
+
+/// The words of a vocabulary file, one per line, skipping blank lines.
+fn read_words(path: &str, encoding: Option<TextEncoding>) -> anyhow::Result<Vec<String>> {
+    let text = read_text(Path::new(path), encoding)?;
+    Ok(text.lines().map(str::trim).filter(|w| !w.is_empty()).map(String::from).collect())
+}
+
+/// Check that every word in the vocabulary file `vocab` has at least one
+/// analysis, listing (and logging) the words that have none, and with
+/// `ambiguity`, the words it leaves ambiguous.
+#[allow(clippy::too_many_arguments)]
+fn run_accepts_all(
+    symt: Arc<SymbolTable>,
+    fst_path: &str,
+    vocab: &str,
+    input: &InputArgs,
+    mut ambiguity: Option<AmbiguityReport>,
+    log_args: &LogArgs,
+    encoding: Option<TextEncoding>,
+    out_dir: &OutDir,
+) -> anyhow::Result<()> {
+    let fmt = input.format();
+    fmt.validate(&symt)?;
+    let graphemes = input_graphemes(fst_path, input.graphemes.as_deref(), &symt, encoding)?;
+    let prepared = PreparedFst::new(SurfaceToAnalysisFst(load_fst_unmarked(fst_path)?), None, fmt)?.with_tie_break(input.tie_break).with_tokenization(input.tokenization).with_compose_filter(input.compose_filter).with_limits(input.limits());
+    let words = read_words(vocab, encoding)?;
+    let run = RunInfo { fst: fst_path.to_string(), tag: None, provenance: read_provenance(Path::new(fst_path))? };
+    let mut log = log_args.open(out_dir, &run)?;
+    let mut rejected = Vec::new();
+    for word in words.iter() {
+        // A word that cannot even be spelled in the symbol table has no analysis either.
+        let reason = match graphemes.apply(&symt, word) {
+            Ok(mapped) if accepts(&prepared, &mapped)? => {
+                if let Some(ambiguity) = &mut ambiguity {
+                    ambiguity.check(&prepared, &mapped)?;
+                }
+                continue;
+            }
+            Ok(_) => "no analysis".to_string(),
+            Err(e) => e.to_string(),
+        };
+        log.rejected(word, &reason)?;
+        rejected.push((word, reason));
+    }
+    println!("{}/{} words have an analysis", words.len() - rejected.len(), words.len());
+    for line in ambiguity.iter().flat_map(AmbiguityReport::lines) {
+        println!("{}", line);
+    }
+    if rejected.is_empty() {
+        return Ok(());
+    }
+    println!("No analysis for {} words:", rejected.len());
+    for (word, reason) in rejected.iter() {
+        println!("  {} ({})", word, reason);
+    }
+    Err(Failure::Checks.mark(anyhow::anyhow!("{} words have no analysis", rejected.len())))
+}
+
+/// Check that every word in the vocabulary file `vocab` has exactly one best
+/// analysis, listing the words whose best analyses tie, with the tied
+/// analyses, and the words with none.
+fn run_functional_on(
+    symt: Arc<SymbolTable>,
+    fst_path: &str,
+    vocab: &str,
+    input: &InputArgs,
+    encoding: Option<TextEncoding>,
+) -> anyhow::Result<()> {
+    let fmt = input.format();
+    fmt.validate(&symt)?;
+    let graphemes = input_graphemes(fst_path, input.graphemes.as_deref(), &symt, encoding)?;
+    let prepared = PreparedFst::new(SurfaceToAnalysisFst(load_fst_unmarked(fst_path)?), None, fmt)?.with_tie_break(input.tie_break).with_tokenization(input.tokenization).with_compose_filter(input.compose_filter).with_limits(input.limits());
+    let words = read_words(vocab, encoding)?;
+    let mut tied = Vec::new();
+    let mut rejected = Vec::new();
+    for word in words.iter() {
+        match graphemes.apply(&symt, word) {
+            Ok(mapped) => match functionality(&prepared, &mapped)? {
+                Functionality::One(_) => {}
+                Functionality::NoAnalysis => rejected.push((word, "no analysis".to_string())),
+                Functionality::Tied(competitors) => tied.push((word, competitors)),
+            },
+            Err(e) => rejected.push((word, e.to_string())),
+        }
+    }
+    println!("{}/{} words have exactly one best analysis", words.len() - tied.len() - rejected.len(), words.len());
+    if !tied.is_empty() {
+        println!("Best analyses tie for {} words:", tied.len());
+        for (word, competitors) in tied.iter() {
+            println!("  {}: {} analyses", word, competitors.len());
+            for c in competitors.iter() {
+                println!("    {} ({})", c.analysis, c.weight);
+            }
+        }
+    }
+    if !rejected.is_empty() {
+        println!("No analysis for {} words:", rejected.len());
+        for (word, reason) in rejected.iter() {
+            println!("  {} ({})", word, reason);
+        }
+    }
+    if tied.is_empty() && rejected.is_empty() {
+        return Ok(());
+    }
+    Err(Failure::Checks.mark(anyhow::anyhow!("{} words do not have exactly one best analysis", tied.len() + rejected.len())))
+}
+
+/// Bisect the rule files of `srcdir` on one gold item (see [`crate::bisect`]),
+/// printing the minimal failing set, its culprits and the files it needs.
+#[allow(clippy::too_many_arguments)]
+fn run_bisect(
+    symt: Arc<SymbolTable>,
+    srcdir: &str,
+    form: &str,
+    gold: &str,
+    skip_bad_files: bool,
+    weight_offset: &[(String, f32)],
+    fallback: FallbackBoundary,
+    input: &InputArgs,
+    cache_dir: Option<&Path>,
+    encoding: Option<TextEncoding>,
+) -> anyhow::Result<()> {
+    let fmt = input.format();
+    fmt.validate(&symt)?;
+    let graphemes = get_grapheme_map(input.graphemes.as_deref(), &symt, encoding)?;
+    let (form, gold) = map_test_input(&graphemes, &symt, form, gold)?;
+    let paths = list_rule_files(Path::new(srcdir), skip_bad_files)?;
+    let mut checks = RuleChecks::default();
+    let files = paths
+        .iter()
+        .map(|path| {
+            println!("Compiling {}", path.display());
+            RuleFile::load(symt.clone(), path, cache_dir, &mut checks)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    print!("{}", checks.summary());
+    let weight_offsets: HashMap<String, f32> = weight_offset.iter().cloned().collect();
+    let g3_to_base = input.g3_to_base(&symt)?;
+    let prepare = |fst| {
+        Ok(PreparedFst::new(fst, g3_to_base.clone(), fmt.clone())?.with_tie_break(input.tie_break).with_tokenization(input.tokenization).with_compose_filter(input.compose_filter).with_limits(input.limits()))
+    };
+    println!("\nBisecting {} rule files on {} -> {}", files.len(), form, gold);
+    let bisection = bisect(symt.clone(), &files, &weight_offsets, fallback, prepare, &form, &gold)?;
+    let names = |indices: &[usize]| indices.iter().map(|&i| files[i].path.display().to_string()).collect::<Vec<_>>().join(", ");
+    println!("\nMinimal failing set: {}", names(&bisection.minimal));
+    if bisection.culprits.is_empty() {
+        println!("No single file of it breaks the item on its own; they fail together");
     } else {
-        let raw_script = std::fs::read_to_string("rules/from_14.txt")?;
-        let (_, (script, _what)) = ruleparse::parse_script(
-            raw_script.as_str()
-        ).unwrap_or_else(|_| panic!("Failed to parse script"));
-        for (i, rule) in enumerate(script.clone()) {
-            println!("Rule {}: {:?}", i+1, rule);
-        }
-        let mut fst = rulefst::compile_script(symt.clone(),script.clone())?;
-
-        let raw_script = std::fs::read_to_string("rules/from_4.txt")?;
-        let (_, (script, _what)) = ruleparse::parse_script(
-            raw_script.as_str()
-        ).unwrap_or_else(|_| panic!("Failed to parse script"));
-        for (i, rule) in enumerate(script.clone()) {
-            println!("Rule {}: {:?}", i+1, rule);
-        }
-        let fst_4 = rulefst::compile_script(symt.clone(),script.clone())?;
-
-        let raw_script = std::fs::read_to_string("rules/special.txt")?;
-        let (_, (script, _what)) = ruleparse::parse_script(
-            raw_script.as_str()
-        ).unwrap_or_else(|_| panic!("Failed to parse script"));
-        for (i, rule) in enumerate(script.clone()) {
-            println!("Rule {}: {:?}", i+1, rule);
-        }
-        let fst_oth = rulefst::compile_script(symt.clone(),script.clone())?;
-        println!("Unioning...");
-        union(&mut fst, &fst_4)?;
-        union(&mut fst, &fst_oth)?;
-        fst.write(args.outpath.clone())?;
-        fst
+        println!("{}: {}", paint(Stream::Stdout, Style::Error, "Culprits"), names(&bisection.culprits));
+    }
+    if !bisection.supporting.is_empty() {
+        println!("Needed for the gold analysis: {}", names(&bisection.supporting));
+    }
+    Ok(())
+}
+
+/// Write a copy of `testfile` with the best segmentation of each row (see
+/// [`crate::annotate`]) to `out`, or stdout.
+#[allow(clippy::too_many_arguments)]
+fn run_segment_column_output(
+    symt: Arc<SymbolTable>,
+    fst_path: &str,
+    testfile: &str,
+    input: &InputArgs,
+    match_column: bool,
+    weights: Option<WeightOutput>,
+    out: Option<&Path>,
+    encoding: Option<TextEncoding>,
+) -> anyhow::Result<()> {
+    let fmt = input.format();
+    fmt.validate(&symt)?;
+    let fst = load_fst_unmarked(fst_path)?;
+    let symt = fst_symt(&fst, symt);
+    let graphemes = input_graphemes(fst_path, input.graphemes.as_deref(), &symt, encoding)?;
+    let g3_to_base = input.g3_to_base(&symt)?;
+    let prepared = PreparedFst::new(SurfaceToAnalysisFst(fst), g3_to_base, fmt)?.with_tie_break(input.tie_break).with_tokenization(input.tokenization).with_compose_filter(input.compose_filter).with_limits(input.limits());
+    let reader = open_text(Path::new(testfile), encoding)?;
+    let summary = match out {
+        Some(out) => {
+            let mut summary = None;
+            create_atomic(out, |file| {
+                summary = Some(annotate(&prepared, &graphemes, reader, file, match_column, weights)?);
+                Ok(())
+            })?;
+            summary.unwrap_or_default()
+        }
+        None => annotate(&prepared, &graphemes, reader, std::io::stdout().lock(), match_column, weights)?,
     };
-    if let Some(path_output) = &args.openfst {
-        fst.write_text(Path::new(path_output).join("fst_segmentation_notminimized.fst")).expect("That didn't work");
+    // On stderr, so that it stays out of a copy written to stdout.
+    eprintln!("{} rows, {} with an analysis", summary.rows, summary.analysed);
+    if summary.given_up > 0 {
+        warn(format!("{} rows given up on, their forms too long or too complex to analyse", summary.given_up));
     }
-    if !args.no_min {
-        println!("Minimizing...");
-        minimize_with_config(&mut fst, MinimizeConfig { delta: 1e-7, allow_nondet: true })?;
-        println!("Done!");
-        fst.write(args.outpath)?;
-        if let Some(path_output) = &args.openfst { fst.write_text(Path::new(path_output).join("fst_segmentation.fst"))?; }
+    if match_column {
+        eprintln!("{}/{} best segmentations match the gold one", summary.matched, summary.gold);
     }
-    /*
-    let e2e = rulefst::apply_fst_to_string(symt.clone(), fst.clone(), "#".to_string() + input.as_str() + "#").unwrap();
-    e2e.clone().draw("path_output", &DrawingConfig::default())?;
-    let paths = rulefst::decode_paths_through_fst(symt.clone(), e2e);
-    let mut seen : HashSet<String> = HashSet::new();
-    for (i, (p, s)) in enumerate(paths) {
-        if seen.contains(&s) { continue; }
-        println!("Path {}: {} -> {}", i, p, s);
-        seen.insert(s);
-    }
-    println!("{} paths found", seen.len());
-    // */
-    
-    let tests = if let Some(testfile) = args.test {
-        let mut reader = csv::Reader::from_path(testfile)?; //.unwrap().into_deserialize().collect::<Result<Vec<(String, String)>, _>>()?
-        let mut out = Vec::new();
-        for r in reader.deserialize() {
-            let record : Entry = r?;
-            println!("{:?}", record);
-            if !record.segmentation.is_empty() { out.push((record.form, record.segmentation.clone())); }
-            //if !record.lx_neg.is_empty() { out.push((record.lx_neg, record.lx.clone())); }
-        }
-        out
-    } else { 
-        vec![
-            ("ni{3>1>4}jo14","ni3jo14##3>1>4##14>14"),
-            /*
-            ("ni14-", "ni1-"),
-            ("chi'14i4", "chi'3i3"),
-            ("chi'14i4", "chi'14i4"),
-            ("ni4-", "ni1-"),
-            ("i4in4", "i3in3"),
-            ("i4in4", "i4in4"),
-            // */
-        ].iter().map(|(x, y)| (x.to_string(), y.to_string())).collect()
+    if let Some(out) = out {
+        eprintln!("Wrote {}", out.display());
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_segment(
+    symt: Arc<SymbolTable>,
+    fst_path: &str,
+    words: &[String],
+    input: &InputArgs,
+    max_paths: Option<usize>,
+    k_paths: bool,
+    raw_labels: bool,
+    attribute_sources: bool,
+    filter: Option<&str>,
+    lexicon: Option<&str>,
+    tokenizer: Option<&str>,
+    weights: WeightOutput,
+    explain_no_result: bool,
+    encoding: Option<TextEncoding>,
+) -> anyhow::Result<()> {
+    let fmt = input.format();
+    fmt.validate(&symt)?;
+    let graphemes = input_graphemes(fst_path, input.graphemes.as_deref(), &symt, encoding)?;
+    let (fst, markers) = load_fst_with_markers(fst_path, attribute_sources, None)?;
+    let symt = fst_symt(&fst, symt);
+    // A filter or lexicon entry that needs a symbol the FST never outputs
+    // rejects every analysis; that is an error in it, not in the rules.
+    let producible = ProducibleLabels::from_sidecar(Path::new(fst_path), &symt).unwrap_or_else(|| ProducibleLabels::of(&fst));
+    let fst = SurfaceToAnalysisFst(fst);
+    let fst = match tokenizer {
+        Some(path) => compose_tokenizer(&read_tokenizer(symt.clone(), Path::new(path))?, &fst, input.compose_filter)?,
+        None => fst,
+    };
+    // Words are spelled in what the tokenizer reads, if there is one.
+    let input_symt = fst.input_symbols().cloned().unwrap_or_else(|| symt.clone());
+    let filter = filter.map(|spec| load_filter(symt.clone(), spec)).transpose()?;
+    if let Some(filter) = &filter
+        && !producible.accepts_any(filter)?
+    {
+        let unproducible = producible.unproducible_in(&symt, filter);
+        warn(format!("The filter needs symbols the FST never outputs ({}), so it rejects every analysis", unproducible.join(", ")));
+    }
+    let lexicon = lexicon
+        .map(|path| {
+            let entries = read_words(path, encoding)?;
+            for (i, entry) in entries.iter().enumerate() {
+                let unproducible = producible.unproducible(&symt, entry, input.tokenization);
+                if !unproducible.is_empty() {
+                    warn(format!("Lexicon {} entry {} '{}' has symbols the FST never outputs ({}), so it matches no analysis", path, i + 1, entry, unproducible.join(", ")));
+                }
+            }
+            compile_lexicon(symt.clone(), &entries, &fmt, input.tokenization).with_context(|| format!("Failed to compile lexicon {}", path))
+        })
+        .transpose()?;
+    let constraints: Vec<&AnalysisAcceptor> = filter.iter().chain(lexicon.iter()).collect();
+    let ranker = input.tie_break.ranker(&fmt);
+    let limits = input.limits();
+    let mut too_long = 0;
+    for word in words {
+        let mapped = graphemes.apply(&input_symt, word)?;
+        if let Err(limit) = limits.check_len(&input_symt, &mapped, input.tokenization) {
+            println!("{}\tNot analysed ({})", word, limit);
+            too_long += 1;
+            continue;
+        }
+        let wrapped = fmt.wrap(&mapped);
+        let e2e = analysis_lattice(&fst, wrapped.clone(), input.tokenization, input.compose_filter)?;
+        let mut constrained = e2e.clone();
+        for constraint in constraints.iter() {
+            constrained = apply_filter(&constrained, constraint, markers.as_ref())?;
+        }
+        let analyses = candidate_analyses(&fst, &constrained, max_paths, k_paths, raw_labels, markers.as_ref(), ranker.as_ref())?;
+        if analyses.is_empty() {
+            // Tell a word the FST cannot analyse from one whose analyses were
+            // all rejected by the filter or the lexicon.
+            let unconstrained =
+                if constraints.is_empty() { 0 } else { candidate_analyses(&fst, &e2e, None, false, raw_labels, markers.as_ref(), ranker.as_ref())?.len() };
+            let by = match (filter.is_some(), lexicon.is_some()) {
+                (true, true) => "the filter and the lexicon",
+                (true, false) => "the filter",
+                _ => "the lexicon",
+            };
+            match unconstrained {
+                0 if explain_no_result => {
+                    let breakdown = longest_accepted_prefix(&fst, &input_symt, &wrapped, input.tokenization, input.compose_filter)?;
+                    println!("{}\tNo result ({})", word, breakdown.describe());
+                }
+                0 => println!("{}\tNo result", word),
+                n => println!("{}\tNo result ({} rejected all {} analyses)", word, by, n),
+            }
+        }
+        for (weight, analysis) in analyses {
+            match weights {
+                WeightOutput::Field => println!("{}\t{}\t{}", word, analysis, weight),
+                WeightOutput::Inline => println!("{}\t{}", word, with_inline_weight(&analysis, *weight.value())),
+            }
+        }
+    }
+    if too_long > 0 {
+        warn(format!("{} of {} words not analysed, being longer than --max-input-len {}", too_long, words.len(), limits.max_input_len));
+    }
+    Ok(())
+}
+
+/// Answer segmentation requests on `addr` (see [`crate::serve`]) with the
+/// FSTs of `models`, by name, with the `max_paths` best analyses of each word
+/// unless a request asks for another number. FSTs whose sidecars record the
+/// same symbol table share one copy of it.
+#[cfg(feature = "server")]
+#[allow(clippy::too_many_arguments)]
+fn run_serve(
+    symt: Arc<SymbolTable>,
+    models: &[(String, String)],
+    default_model: Option<&str>,
+    addr: &str,
+    input: &InputArgs,
+    max_paths: Option<usize>,
+    jobs: usize,
+    encoding: Option<TextEncoding>,
+) -> anyhow::Result<()> {
+    let fmt = input.format();
+    fmt.validate(&symt)?;
+    let embedded = models.iter().map(|(_, path)| embeds_normalizer(Path::new(path))).collect::<anyhow::Result<Vec<_>>>()?;
+    let graphemes = if embedded.iter().all(|&e| e) && !embedded.is_empty() {
+        GraphemeMap::verbatim()
+    } else if embedded.iter().any(|&e| e) {
+        return Err(Failure::Usage.mark(anyhow::anyhow!("Some of the models embed their normalizer (build --normalizer-fst) and some do not; serve them separately")));
+    } else {
+        get_grapheme_map(input.graphemes.as_deref(), &symt, encoding)?
+    };
+    let mut shared = serve::SharedSymbols::default();
+    let models = models
+        .iter()
+        .map(|(name, path)| {
+            let mut fst = load_fst_unmarked(path)?;
+            let provenance = read_provenance(Path::new(path))?;
+            shared.share(&mut fst, provenance.as_ref().and_then(|p| p.symt_hash.as_deref()));
+            let prepared = PreparedFst::new(SurfaceToAnalysisFst(fst), None, fmt.clone())?.with_tie_break(input.tie_break).with_tokenization(input.tokenization).with_compose_filter(input.compose_filter).with_limits(input.limits());
+            Ok(serve::Model { name: name.clone(), path: path.clone(), prepared, provenance })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    println!("Loaded {} models with {} symbol tables", models.len(), shared.len());
+    let models = serve::Models::new(models, default_model)?;
+    let n = max_paths.unwrap_or(serve::DEFAULT_ANALYSES).clamp(1, serve::MAX_ANALYSES);
+    serve::serve(&models, &graphemes, addr, n, jobs)
+}
+
+#[cfg(not(feature = "server"))]
+#[allow(clippy::too_many_arguments)]
+fn run_serve(_: Arc<SymbolTable>, _: &[(String, String)], _: Option<&str>, _: &str, _: &InputArgs, _: Option<usize>, _: usize, _: Option<TextEncoding>) -> anyhow::Result<()> {
+    Err(Failure::Usage.mark(anyhow::anyhow!("segment --serve needs the `server` feature; rebuild with `cargo build --features server`")))
+}
+
+fn run_bulk_apply(
+    symt: Arc<SymbolTable>,
+    fst_path: &str,
+    tokens_path: &str,
+    out: &Path,
+    opts: &BulkOptions,
+    input: &InputArgs,
+    encoding: Option<TextEncoding>,
+) -> anyhow::Result<()> {
+    let fmt = input.format();
+    fmt.validate(&symt)?;
+    let graphemes = input_graphemes(fst_path, input.graphemes.as_deref(), &symt, encoding)?;
+    let tokens = read_text(Path::new(tokens_path), encoding)?;
+    let prepared = PreparedFst::new(SurfaceToAnalysisFst(load_fst_unmarked(fst_path)?), None, fmt)?.with_tie_break(input.tie_break).with_tokenization(input.tokenization).with_compose_filter(input.compose_filter).with_limits(input.limits());
+    let summary = bulk_apply(&prepared, &graphemes, &tokens, out, opts).map_err(|e| {
+        if Cancelled::of(&e) {
+            e.context(format!("Interrupted; the forms analysed so far are in {}, for --resume", bulk::journal_path(out).display()))
+        } else {
+            e
+        }
+    })?;
+    if summary.resumed > 0 {
+        println!("Reused {} forms analysed by an earlier run", summary.resumed);
+    }
+    println!(
+        "{} tokens, {} distinct forms: {} analysed, {} without analysis, {} skipped, {} too complex, {} invalid",
+        summary.tokens, summary.forms, summary.analysed, summary.no_analysis, summary.skipped, summary.too_complex, summary.invalid
+    );
+    println!("{:.1}s, {:.0} tokens/s", summary.elapsed.as_secs_f64(), summary.tokens_per_sec());
+    println!("Wrote {} and {}", out.display(), bulk::map_path(out).display());
+    Ok(())
+}
+
+/// Cross-validate the FST at `fst_path`, or built from the rule files in
+/// `srcdir`, on the gold items of `testfile` (see [`crate::crossval`]).
+#[allow(clippy::too_many_arguments)]
+fn run_cross_validate(
+    symt: Arc<SymbolTable>,
+    fst_path: Option<&str>,
+    srcdir: Option<&str>,
+    testfile: &str,
+    folds: usize,
+    seed: u64,
+    out: Option<&Path>,
+    input: &InputArgs,
+    jobs: usize,
+    encoding: Option<TextEncoding>,
+) -> anyhow::Result<()> {
+    let fmt = input.format();
+    fmt.validate(&symt)?;
+    let graphemes = match fst_path {
+        Some(fst_path) => input_graphemes(fst_path, input.graphemes.as_deref(), &symt, encoding)?,
+        None => get_grapheme_map(input.graphemes.as_deref(), &symt, encoding)?,
+    };
+    let golds = map_test_inputs(&graphemes, &symt, read_tests(testfile, encoding)?)?;
+    let fst = match (fst_path, srcdir) {
+        (Some(path), _) => load_fst_unmarked(path)?,
+        (None, Some(src)) => {
+            let files = list_rule_files(Path::new(src), false)?;
+            let mut checks = RuleChecks::default();
+            let fst = build_from_rule_files(symt.clone(), &files, &HashMap::new(), FallbackBoundary::default(), RuleApplication::default(), None, None, &mut checks)?;
+            print!("{}", checks.summary());
+            fst
+        }
+        (None, None) => unreachable!("clap requires an FST or --srcdir"),
+    };
+    let symt = fst_symt(&fst, symt);
+    let g3_to_base = input.g3_to_base(&symt)?;
+    let prepared = PreparedFst::new(SurfaceToAnalysisFst(fst), g3_to_base, fmt)?
+        .with_tie_break(input.tie_break)
+        .with_tokenization(input.tokenization)
+        .with_compose_filter(input.compose_filter)
+        .with_limits(input.limits());
+    let result = cross_validate(&prepared, &golds, folds, seed, &input.tones, jobs)?;
+    print!("{}", result.summary());
+    let json = internal(serde_json::to_string_pretty(&result))?;
+    match out {
+        Some(path) => write_file_atomic(path, json + "\n")?,
+        None => println!("{}", json),
+    }
+    Ok(())
+}
+
+/// List the minimal pairs of the words of `words`, or of `samples` inputs
+/// sampled from the FST at `fst_path` (see [`crate::pairs`]).
+#[allow(clippy::too_many_arguments)]
+fn run_minimal_pairs(
+    symt: Arc<SymbolTable>,
+    fst_path: &str,
+    words: Option<&str>,
+    samples: Option<usize>,
+    seed: u64,
+    out: Option<&Path>,
+    input: &InputArgs,
+    jobs: usize,
+    encoding: Option<TextEncoding>,
+) -> anyhow::Result<()> {
+    let fmt = input.format();
+    fmt.validate(&symt)?;
+    input.tones.validate(&symt)?;
+    let fst = load_fst_unmarked(fst_path)?;
+    let symt = fst_symt(&fst, symt);
+    let forms: Vec<String> = match (words, samples) {
+        (Some(path), _) => {
+            let graphemes = input_graphemes(fst_path, input.graphemes.as_deref(), &symt, encoding)?;
+            read_words(path, encoding)?.iter().map(|w| graphemes.apply(&symt, w)).collect::<anyhow::Result<_>>()?
+        }
+        (None, Some(samples)) => {
+            let opts = VerifyOptions { samples, seed, ..Default::default() };
+            sample_inputs(&fst, &opts)?
+                .iter()
+                .map(|labels| fmt.strip(&display_labels(&symt, labels)).to_string())
+                .filter(|w| !w.is_empty() && !w.contains(fmt.boundary.as_str()))
+                .collect()
+        }
+        (None, None) => unreachable!("clap requires a word list or --samples"),
+    };
+    let prepared = PreparedFst::new(SurfaceToAnalysisFst(fst), None, fmt)?
+        .with_tie_break(input.tie_break)
+        .with_tokenization(input.tokenization)
+        .with_compose_filter(input.compose_filter)
+        .with_limits(input.limits());
+    let pairs = find_minimal_pairs(&prepared, &forms, &input.tones, jobs)?;
+    eprintln!("{} minimal pairs among {} forms", pairs.len(), forms.len());
+    match out {
+        Some(path) => create_atomic(path, |file| write_pairs_csv(&pairs, file))?,
+        None => write_pairs_csv(&pairs, std::io::stdout().lock())?,
+    }
+    Ok(())
+}
+
+/// Run the operation of the dump in `dir` again, saying whether it failed as
+/// it did when dumped, and write its result to `out` if it succeeds.
+fn run_replay(dir: &Path, out: Option<&Path>) -> anyhow::Result<()> {
+    let replay = dump::replay(dir)?;
+    println!("Replaying {} from {}", replay.manifest.operation.name(), replay.manifest.command_line.join(" "));
+    let reproduced = replay.reproduced();
+    match replay.result {
+        Ok(fst) => {
+            let size = FstSize::of(&fst);
+            println!("{} succeeded ({} states, {} arcs); the dump failed with: {}", replay.manifest.operation.name(), size.num_states, size.num_trs, replay.manifest.error);
+            if let Some(out) = out {
+                write_fst(&fst, out)?;
+            }
+            Ok(())
+        }
+        Err(e) if reproduced => Err(e.context("Reproduced the error of the dump")),
+        Err(e) => Err(e.context(format!("Failed with a different error from the dump's: {}", replay.manifest.error))),
+    }
+}
+
+fn run_diff_symbols(symt: Arc<SymbolTable>, other_path: &str) -> anyhow::Result<()> {
+    let (other, fst) = if other_path.ends_with(".syms") || other_path.ends_with(".txt") {
+        let other = SymbolTable::read_text(other_path).with_context(|| format!("Failed to read symbol table {}", other_path))?;
+        (Arc::new(other), None)
+    } else {
+        let fst = load_fst(other_path)?;
+        let other = fst.input_symbols().cloned().ok_or_else(|| anyhow::anyhow!("FST {} has no embedded symbol table", other_path))?;
+        (other, Some(fst))
+    };
+    let diff = diff_symbols(&symt, &other);
+    print!("{}", diff);
+    if diff.is_empty() {
+        println!("{}: same symbols and labels as chars.txt", other_path);
+        return Ok(());
+    }
+    if diff.is_compatible() {
+        println!("{}: compatible with chars.txt ({} symbols, {} only in chars.txt, {} only in {})", other_path, other.len(), diff.only_current.len(), diff.only_other.len(), other_path);
+        return Ok(());
+    }
+    // The same symbols in another order are queried with the FST's own table.
+    if let Some(fst) = fst
+        && Arc::ptr_eq(&fst_symt(&fst, symt.clone()), &other)
+    {
+        println!("{}: carries its own ordering of the symbols of chars.txt (as after build --relabel-by-frequency), which it is queried with", other_path);
+        return Ok(());
+    }
+    Err(Failure::Checks.mark(anyhow::anyhow!("{} is incompatible with chars.txt: {} symbols moved, {} labels conflict", other_path, diff.moved.len(), diff.conflicts.len())))
+}
+
+fn run_info(fst_path: &str) -> anyhow::Result<()> {
+    let fst = load_fst(fst_path)?;
+    let size = FstSize::of(&fst);
+    println!("states: {}", size.num_states);
+    println!("arcs: {}", size.num_trs);
+    println!("final states: {}", fst.final_states_iter().count());
+    if let Some(symt) = fst.input_symbols() {
+        println!("symbols: {}", symt.len());
+    }
+    // Symbols only read are consumed but never produced, and the other way round.
+    let uses = symbol_use(&fst, fst.input_symbols().map(|t| t.as_ref()))?;
+    let width = uses.iter().map(|u| u.symbol.chars().count()).max().unwrap_or(0).max("symbol".len());
+    println!("\n{:width$}  {:>8}  {:>8}", "symbol", "in", "out");
+    for u in uses.iter() {
+        let note = match (u.inputs, u.outputs) {
+            _ if u.label == EPS_LABEL => "",
+            (_, 0) => "  input only",
+            (0, _) => "  output only",
+            _ => "",
+        };
+        println!("{:width$}  {:>8}  {:>8}{}", u.symbol, u.inputs, u.outputs, note);
+    }
+    for sidecar in ["info", "sources"] {
+        let path = format!("{}.{}", fst_path, sidecar);
+        if let Ok(contents) = std::fs::read_to_string(&path) {
+            println!("\n{}:", path);
+            print!("{}", contents);
+        }
+    }
+    Ok(())
+}
+
+fn run_draw(symt: Arc<SymbolTable>, fst_path: &str, out: &Path, word: Option<&str>, input: &InputArgs, encoding: Option<TextEncoding>) -> anyhow::Result<()> {
+    // Source markers have no symbols to draw.
+    let fst = load_fst_unmarked(fst_path)?;
+    let fst = match word {
+        Some(word) => {
+            let fmt = input.format();
+            let graphemes = input_graphemes(fst_path, input.graphemes.as_deref(), &symt, encoding)?;
+            analysis_lattice(&SurfaceToAnalysisFst(fst), fmt.wrap(&graphemes.apply(&symt, word)?), input.tokenization, input.compose_filter)?.0
+        }
+        None => fst,
     };
-    let mut log = File::create("log.txt")?;
-    for (input, form) in tests.iter() {
-        if can_generate_form(&fst, &input, &form, args.g3, None)? {
-            println!("{} -> {} OK", input, form);
+    fst.draw(out, &DrawingConfig::default())?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_generate_paradigm(
+    symt: Arc<SymbolTable>,
+    fst_path: &str,
+    stems_path: &str,
+    contexts_path: &str,
+    out: &Path,
+    top_k: usize,
+    resume: bool,
+    input: &InputArgs,
+    encoding: Option<TextEncoding>,
+) -> anyhow::Result<()> {
+    let fmt = input.format();
+    fmt.validate(&symt)?;
+    let stems = read_words(stems_path, encoding)?;
+    let contexts = parse_contexts(contexts_path, &read_text(Path::new(contexts_path), encoding)?)?;
+    paradigm::validate(&symt, &stems, &contexts)?;
+    // Generation never produces source markers, and the output constraint would reject them.
+    let fst = load_fst_unmarked(fst_path)?;
+    let g3_to_base = input.g3_to_base(&fst_symt(&fst, symt))?;
+    let prepared = PreparedFst::new(SurfaceToAnalysisFst(fst), g3_to_base, fmt)?.with_tie_break(input.tie_break).with_tokenization(input.tokenization).with_compose_filter(input.compose_filter).with_limits(input.limits());
+    let summary = generate_paradigm(&prepared, &stems, &contexts, top_k, out, resume)?;
+    if summary.resumed > 0 {
+        println!("Skipped {} stems finished by an earlier run", summary.resumed);
+    }
+    println!("{} stems x {} contexts written to {}", summary.stems, contexts.len(), out.display());
+    if !summary.empty.is_empty() {
+        println!("No surface form for {} of {} combinations:", summary.empty.len(), summary.cells);
+        for (stem, context) in summary.empty.iter() {
+            println!("  {} in {}", stem, context);
+        }
+    }
+    Ok(())
+}
+
+fn run_command(command: Command, encoding: Option<TextEncoding>, out_dir: &OutDir, memory: Option<&MemoryMeter>) -> anyhow::Result<()> {
+    match command {
+        Command::Build { outpath, srcdir, skip_bad_files, weight_offset, attribute_sources, optimize, optimize_time_budget, optimize_max_states, no_min, no_connect, no_dedup_arcs, openfst, json_fst, verify, strict, fallback_boundary, no_boundary_check, require_epsilon_free, canonical_order, relabel_by_frequency, normalizer_fst, count_final_paths, final_paths_length, final_paths_threshold, check_variant_probabilities, application, explain_weights, weights_from_counts } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            let checks = RuleChecks { check_probabilities: check_variant_probabilities, ..RuleChecks::new(strict) };
+            run_build(symt, &outpath, srcdir.as_deref(), skip_bad_files, &weight_offset, fallback_boundary, application, explain_weights.as_deref(), weights_from_counts.as_deref(), encoding, !no_boundary_check, require_epsilon_free, canonical_order, relabel_by_frequency, normalizer_fst.as_ref().map(|g| g.as_deref()), count_final_paths.then_some((final_paths_length, final_paths_threshold)), attribute_sources, optimize_budget(optimize, optimize_time_budget, optimize_max_states), no_min, verify.nondeterminism(), no_connect, no_dedup_arcs, openfst.as_deref(), json_fst.as_deref(), build_verification(&verify, strict), checks, memory)?;
+        }
+        Command::Linearize { command } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            match command {
+                LinearizeCommand::Compile { stage, common } => {
+                    common.pipeline(symt, out_dir).compile(stage)?;
+                }
+                LinearizeCommand::Compose { from, to, common } => {
+                    common.pipeline(symt, out_dir).compose(from, to)?;
+                }
+                LinearizeCommand::All { outpath, common } => {
+                    write_fst(&common.pipeline(symt, out_dir).run_all()?, Path::new(&outpath))?;
+                }
+            }
+            if let Some(memory) = memory {
+                memory.stage("linearize");
+            }
+        }
+        Command::Test { fst, input, assert_functional_on: Some(vocab), .. } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            let fst = fst.ok_or_else(|| Failure::Usage.mark(anyhow::anyhow!("--assert-functional-on needs the path of an FST")))?;
+            run_functional_on(symt, &fst, &vocab, &input, encoding)?;
+        }
+        Command::Test { fst, input, log, assert_accepts_all: Some(vocab), list_ambiguous, ambiguity_margin, max_competitors, .. } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            let fst = fst.ok_or_else(|| Failure::Usage.mark(anyhow::anyhow!("--assert-accepts-all needs the path of an FST")))?;
+            let ambiguity = list_ambiguous.then(|| AmbiguityReport::new(ambiguity_margin, max_competitors));
+            run_accepts_all(symt, &fst, &vocab, &input, ambiguity, &log, encoding, out_dir)?;
+        }
+        Command::Test { fst, test: Some(test), input, segment_column_output: true, match_column, out, weights, .. } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            let fst = fst.ok_or_else(|| Failure::Usage.mark(anyhow::anyhow!("--segment-column-output needs the path of an FST")))?;
+            run_segment_column_output(symt, &fst, &test, &input, match_column, weights, out.map(|out| out_dir.path(&out)).as_deref(), encoding)?;
+        }
+        Command::Test { fst, test_rule, srcdir, skip_bad_files, test, demo: _, input, max_paths, k_paths, output_symbols_in_results, fast_check, both_directions, retry_lenient, explain_no_result, partial_credit, tone_substitution_cost, segment_substitution_cost, list_ambiguous, ambiguity_margin, max_competitors, attribute_sources, json_report, report_html, report_html_max_rows, assert_accepts_all: None, assert_functional_on: None, timeout, tag, prepared_cache, log, .. } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            let fst = match test_rule {
+                Some(name) => build_rule_fst(symt.clone(), srcdir.as_deref(), skip_bad_files, &name, out_dir)?,
+                None => fst.ok_or_else(|| Failure::Usage.mark(anyhow::anyhow!("test needs the path of an FST, or --test-rule")))?,
+            };
+            let partial_credit = partial_credit.then_some(Costs { tone_substitution: tone_substitution_cost, segment_substitution: segment_substitution_cost });
+            let ambiguity = list_ambiguous.then(|| AmbiguityReport::new(ambiguity_margin, max_competitors));
+            let opts = TestOptions {
+                testfile: test.as_deref(),
+                max_paths,
+                k_paths,
+                raw_labels: output_symbols_in_results,
+                fast_check,
+                both_directions,
+                retry_lenient,
+                explain_no_result,
+                partial_credit,
+                ambiguity,
+                attribute_sources,
+                json_report: json_report.as_deref(),
+                html_report: report_html.as_deref().map(|path| (path, report_html_max_rows)),
+                timeout,
+                tag: tag.as_deref(),
+                prepared_cache: prepared_cache.as_deref().map(Path::new),
+                log: &log,
+            };
+            run_test(symt, &fst, &input, opts, encoding, out_dir, memory)?;
+        }
+        Command::Segment { fst, input, max_paths, serve: Some(addr), jobs, models, default_model, .. } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            let jobs = jobs.unwrap_or_else(pool::default_jobs);
+            let models: Vec<(String, String)> = fst.map(|fst| ("default".to_string(), fst)).into_iter().chain(models).collect();
+            run_serve(symt, &models, default_model.as_deref(), &addr, &input, max_paths, jobs, encoding)?;
+        }
+        Command::Segment { fst, words, input, max_paths, k_paths, output_symbols_in_results, attribute_sources, filter, lexicon, tokenizer, weights, explain_no_result, serve: None, .. } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            let fst = fst.ok_or_else(|| Failure::Usage.mark(anyhow::anyhow!("segment needs the path of an FST")))?;
+            run_segment(symt, &fst, &words, &input, max_paths, k_paths, output_symbols_in_results, attribute_sources, filter.as_deref(), lexicon.as_deref(), tokenizer.as_deref(), weights, explain_no_result, encoding)?;
+        }
+        Command::Info { fst } => run_info(&fst)?,
+        Command::DiffSymbols { other } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            run_diff_symbols(symt, &other)?;
+        }
+        Command::Draw { fst, out, word, input } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            run_draw(symt, &fst, &out_dir.path(&out), word.as_deref(), &input, encoding)?;
         }
-        else {
-            println!("you get NOTHING. you LOSE. good DAY sir.");
-            writeln!(log, "{} -> {} FAILED", input, form)?;
+        Command::GenerateParadigm { fst, stems, contexts, out, top_k, resume, input } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            run_generate_paradigm(symt, &fst, &stems, &contexts, &out_dir.path(&out), top_k, resume, &input, encoding)?;
         }
+        Command::BulkApply { fst, tokens, out, jobs, checkpoint_every, max_len, resume, weights, input } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            let jobs = jobs.unwrap_or_else(pool::default_jobs);
+            let opts = BulkOptions { jobs, checkpoint_every, max_len, resume, weights };
+            run_bulk_apply(symt, &fst, &tokens, &out_dir.path(&out), &opts, &input, encoding)?;
+        }
+        Command::MinimizeDir { dir, out, validate_determinism } => {
+            let policy = if validate_determinism { Nondeterminism::Refuse } else { Nondeterminism::Allow };
+            let out = out.map(|out| out_dir.path(&out));
+            let minimized = minimize_dir::minimize_dir(Path::new(&dir), out.as_deref(), policy, |m| println!("{}", m.report()))?;
+            println!("{}", minimize_dir::summary(&minimized));
+        }
+        Command::CoverageByRule { srcdir, skip_bad_files, test, out, input, cache_dir, no_cache, jobs } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            let fmt = input.format();
+            fmt.validate(&symt)?;
+            let graphemes = get_grapheme_map(input.graphemes.as_deref(), &symt, encoding)?;
+            let golds = map_test_inputs(&graphemes, &symt, read_tests(&test, encoding)?)?;
+            let files = list_rule_files(Path::new(&srcdir), skip_bad_files)?;
+            let g3_to_base = input.g3_to_base(&symt)?;
+            let cache_dir = (!no_cache).then(|| Path::new(&cache_dir));
+            let jobs = jobs.unwrap_or_else(pool::default_jobs);
+            let mut checks = RuleChecks::default();
+            let report = coverage_by_rule(symt, &files, &golds, g3_to_base.as_ref(), &fmt, cache_dir, jobs, &mut checks)?;
+            print!("{}", checks.summary());
+            match out {
+                Some(path) => create_atomic(&out_dir.path(path), |file| report.write_matrix(&golds, file))?,
+                None => report.write_matrix(&golds, std::io::stdout())?,
+            }
+            report.print_summary(&golds);
+        }
+        Command::Bisect { srcdir, form, gold, skip_bad_files, weight_offset, fallback_boundary, input, cache_dir, no_cache } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            let cache_dir = (!no_cache).then(|| Path::new(&cache_dir));
+            run_bisect(symt, &srcdir, &form, &gold, skip_bad_files, &weight_offset, fallback_boundary, &input, cache_dir, encoding)?;
+        }
+        Command::CrossValidate { fst, srcdir, test, folds, seed, out, input, jobs } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            let jobs = jobs.unwrap_or_else(pool::default_jobs);
+            let out = out.map(|out| out_dir.path(out));
+            run_cross_validate(symt, fst.as_deref(), srcdir.as_deref(), &test, folds, seed, out.as_deref(), &input, jobs, encoding)?;
+        }
+        Command::MinimalPairs { fst, words, samples, seed, out, input, jobs } => {
+            let symt = get_symt_from_file("chars.txt", encoding)?;
+            let jobs = jobs.unwrap_or_else(pool::default_jobs);
+            let out = out.map(|out| out_dir.path(out));
+            run_minimal_pairs(symt, &fst, words.as_deref(), samples, seed, out.as_deref(), &input, jobs, encoding)?;
+        }
+        Command::Replay { dump, out } => run_replay(Path::new(&dump), out.as_deref().map(|out| out_dir.path(out)).as_deref())?,
     }
-    //[MacroDef(("chars", Group([Disjunction([Group([Char('n')]), Group([Char('i')])]), Char('\n'), Class([Char('1'), Char('2'), Char('3'), Char('4')])])))]
-    println!("Hello, world!");
     Ok(())
 }
+
+/// The symbols of a chars file, one per line. `%` starts a comment that runs
+/// to the end of the line, and blank lines are skipped.
+fn parse_chars(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| line.split('%').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| nfd_normalize(&line.to_lowercase()))
+        .collect()
+}
+
+fn get_symt_from_file(path: &str, encoding: Option<TextEncoding>) -> anyhow::Result<Arc<SymbolTable>> {
+    let syms = parse_chars(&read_text(Path::new(path), encoding)?);
+    // Add the word boundary symbol (see boundary.rs)
+    let mut symt_inner = SymbolTable::new();
+    symt_inner.add_symbols(syms);
+    symt_inner.add_symbol("#");
+    // and the morph boundary `::mb::` writes (see analysis.rs)
+    symt_inner.add_symbol(DEFAULT_MORPH_BOUNDARY);
+    log::debug!("symt={:?}", symt_inner);
+    let symt = Arc::new(symt_inner);
+    Ok(symt)
+}
+
+fn get_grapheme_map(path: Option<&str>, symt: &SymbolTable, encoding: Option<TextEncoding>) -> anyhow::Result<GraphemeMap> {
+    let graphemes = match path {
+        Some(path) => GraphemeMap::read(Path::new(path), encoding)?,
+        None => GraphemeMap::default(),
+    };
+    graphemes.validate(symt)?;
+    Ok(graphemes)
+}
+
+/// The grapheme map to spell the inputs of the FST at `fst_path` with: the
+/// one at `path`, unless the FST embeds its normalizer (see
+/// [`crate::normalizer`]), when inputs are given to it as written.
+fn input_graphemes(fst_path: &str, path: Option<&str>, symt: &SymbolTable, encoding: Option<TextEncoding>) -> anyhow::Result<GraphemeMap> {
+    if !embeds_normalizer(Path::new(fst_path))? {
+        return get_grapheme_map(path, symt, encoding);
+    }
+    if let Some(path) = path {
+        warn(format!("Ignoring --graphemes {}: {} normalizes its inputs itself", path, fst_path));
+    }
+    Ok(GraphemeMap::verbatim())
+}
+
+/// Normalize and map the input side of each test item onto symbols, and
+/// NFD-normalize the expected analyses to match the symbol table.
+fn map_test_inputs(graphemes: &GraphemeMap, symt: &SymbolTable, tests: Vec<(String, String)>) -> anyhow::Result<Vec<(String, String)>> {
+    tests.into_iter().map(|(input, form)| map_test_input(graphemes, symt, &input, &form)).collect()
+}
+
+/// [`map_test_inputs`] for a single item.
+fn map_test_input(graphemes: &GraphemeMap, symt: &SymbolTable, input: &str, form: &str) -> anyhow::Result<(String, String)> {
+    Ok((graphemes.apply(symt, input)?, nfd_normalize(form)))
+}
+
+fn get_fst_g3_to_base(symt: Arc<SymbolTable>, tones: &ToneSet) -> anyhow::Result<AnalysisToAnalysisFst> {
+    let script = tones.g3_to_base_rules()?;
+    let mut fst = rulefst::compile_script(symt.clone(), script)?;
+    tr_sort(&mut fst, ILabelCompare {});
+    Ok(AnalysisToAnalysisFst(fst))
+}
+
+fn main() -> ExitCode {
+    // A panic is a bug, and its message is printed by the panic hook; exit with
+    // the status of an internal error rather than Rust's own.
+    match std::panic::catch_unwind(run) {
+        Ok(Ok(())) => ExitCode::SUCCESS,
+        Ok(Err(e)) if status::code(e.as_ref()) == status::INTERRUPTED => {
+            // Interrupted work has written what it had by now.
+            eprintln!("{}", e);
+            ExitCode::from(status::INTERRUPTED)
+        }
+        Ok(Err(e)) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(status::code(e.as_ref()))
+        }
+        Err(_) => ExitCode::from(status::INTERNAL),
+    }
+}
+
+/// The run `main` reports the status of (see [`crate::status`]).
+fn run() -> anyhow::Result<()> {
+    env_logger::init();
+    let args = Args::parse();
+    set_color_choice(args.color);
+    let memory = args.measure_memory.then(MemoryMeter::new);
+    let out_dir = OutDir::create(args.out_dir)?;
+    #[cfg(feature = "profile")]
+    let _profile = args.profile.map(|path| profile::start(&out_dir.path(path))).transpose()?;
+    // A replay should fail the way the dump did, not dump again.
+    if let Some(dir) = args.debug_dump_on_error.filter(|_| !matches!(args.command, Command::Replay { .. })) {
+        dump::enable(DumpConfig { dir: out_dir.path(dir), max_states: args.debug_dump_max_states });
+    }
+    cancel::on_ctrl_c();
+    run_command(args.command, args.encoding, &out_dir, memory.as_ref())?;
+    if let Some(memory) = &memory {
+        memory.print_summary();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    use crate::testutil::{fixture_symt, root, TempDir};
+
+    #[test]
+    fn test_cli_definition() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn test_embedded_smoke_tests_parse() {
+        let tests = parse_tests("built-in smoke tests", SMOKE_TESTS).unwrap();
+        assert!(tests.contains(&("ni{3>1>4}jo14".to_string(), "ni3jo14##3>1>4##14>14".to_string())));
+        assert!(tests.contains(&("ni14-".to_string(), "ni{1>14}-".to_string())));
+        assert!(tests.iter().all(|(form, segmentation)| !form.is_empty() && !segmentation.is_empty()));
+    }
+
+    #[test]
+    fn test_chars_comments_and_blank_lines() {
+        let dir = root().join("tests/comments");
+        let plain = get_symt_from_file(dir.join("chars.txt").to_str().unwrap(), None).unwrap();
+        let commented = get_symt_from_file(dir.join("chars_commented.txt").to_str().unwrap(), None).unwrap();
+        assert_eq!(commented, plain);
+        assert_eq!(parse_chars("% vowels\r\nA\r\n\r\n\u{f1} % enye\n"), ["a", "n\u{303}"]);
+    }
+
+    #[test]
+    fn test_xfail_column() {
+        let entries = parse_entries("gold", "segmentation,form,xfail\nni{1>14}-,ni14-,x\ni4in4,i4in4,\n").unwrap();
+        assert_eq!(entries.iter().map(|e| e.xfail).collect::<Vec<_>>(), [true, false]);
+        let entries = parse_entries("gold", "segmentation,form\ni4in4,i4in4\n").unwrap();
+        assert!(!entries[0].xfail);
+        assert!(parse_entries("gold", "segmentation,form,xfail\ni4in4,i4in4,maybe\n").is_err());
+    }
+
+    #[test]
+    fn test_subcommands_only_take_their_own_options() {
+        assert!(Args::try_parse_from(["mixtec_fst", "build", "out.fst", "--srcdir", "rules/min"]).is_ok());
+        assert!(Args::try_parse_from(["mixtec_fst", "test", "out.fst", "-t", "gold.csv", "--fast-check"]).is_ok());
+        assert!(Args::try_parse_from(["mixtec_fst", "test", "out.fst", "--srcdir", "rules/min"]).is_err());
+        assert!(Args::try_parse_from(["mixtec_fst", "build", "out.fst", "--fast-check"]).is_err());
+        assert!(Args::try_parse_from(["mixtec_fst", "build", "out.fst", "--normalizer-fst", "--srcdir", "rules/min"]).is_ok());
+        assert!(Args::try_parse_from(["mixtec_fst", "build", "out.fst", "--normalizer-fst", "graphemes.csv"]).is_ok());
+        assert!(Args::try_parse_from(["mixtec_fst", "build", "out.fst", "--normalizer-fst", "--relabel-by-frequency"]).is_err());
+        assert!(Args::try_parse_from(["mixtec_fst", "segment", "out.fst"]).is_err());
+        assert!(Args::try_parse_from(["mixtec_fst", "minimize-dir", "cache", "--out", "minimized"]).is_ok());
+        assert!(Args::try_parse_from(["mixtec_fst", "minimize-dir"]).is_err());
+        assert!(Args::try_parse_from(["mixtec_fst", "segment", "out.fst", "sha", "--tokenizer", "tokenizer.txt"]).is_ok());
+        assert!(Args::try_parse_from(["mixtec_fst", "segment", "out.fst", "sha", "--weights", "inline"]).is_ok());
+        assert!(Args::try_parse_from(["mixtec_fst", "segment", "out.fst", "--serve", "127.0.0.1:8080", "--weights", "inline"]).is_err());
+        assert!(Args::try_parse_from(["mixtec_fst", "test", "out.fst", "-t", "gold.csv", "--segment-column-output", "--weights", "field"]).is_ok());
+        assert!(Args::try_parse_from(["mixtec_fst", "test", "out.fst", "-t", "gold.csv", "--weights", "field"]).is_err());
+        assert!(Args::try_parse_from(["mixtec_fst", "segment", "out.fst", "--serve", "127.0.0.1:8080", "--tokenizer", "tokenizer.txt"]).is_err());
+        assert!(Args::try_parse_from(["mixtec_fst", "test", "out.fst", "--assert-accepts-all", "words.txt"]).is_ok());
+        assert!(Args::try_parse_from(["mixtec_fst", "test", "out.fst", "--assert-accepts-all", "words.txt", "-t", "gold.csv"]).is_err());
+        assert!(Args::try_parse_from(["mixtec_fst", "test", "out.fst", "--assert-functional-on", "words.txt"]).is_ok());
+        assert!(Args::try_parse_from(["mixtec_fst", "test", "out.fst", "--assert-functional-on", "words.txt", "--assert-accepts-all", "words.txt"]).is_err());
+        assert!(Args::try_parse_from(["mixtec_fst", "test", "out.fst"]).is_err());
+        assert!(Args::try_parse_from(["mixtec_fst", "test", "out.fst", "--demo"]).is_ok());
+        assert!(Args::try_parse_from(["mixtec_fst", "test", "out.fst", "--demo", "-t", "gold.csv"]).is_err());
+        assert!(Args::try_parse_from(["mixtec_fst", "test", "--test-rule", "voicing", "--srcdir", "rules/min", "--demo"]).is_ok());
+        assert!(Args::try_parse_from(["mixtec_fst", "test", "out.fst", "--test-rule", "voicing", "--demo"]).is_err());
+        assert!(Args::try_parse_from(["mixtec_fst", "test", "--demo"]).is_err());
+        let args = Args::try_parse_from(["mixtec_fst", "info", "out.fst", "--encoding", "latin1"]).unwrap();
+        assert_eq!(args.encoding, Some(TextEncoding::Latin1));
+    }
+
+    #[test]
+    fn test_gold_with_unproducible_symbols_is_a_data_error() {
+        let dir = TempDir::new("unproducible");
+        let out_dir = OutDir::create(dir.to_path_buf()).unwrap();
+        let symt = fixture_symt();
+        // Only ever outputs its input, a1.
+        let mut fst = SurfaceAcceptor::of(&symt, "a1", Tokenization::default(), Some(&AnalysisFormat::default())).unwrap().0;
+        fst.set_input_symbols(symt.clone());
+        fst.set_output_symbols(symt.clone());
+        let fst_path = dir.join("a1.fst");
+        write_fst(&fst, &fst_path).unwrap();
+        // Passes, has a 4 the FST never outputs, and fails.
+        let gold = dir.join("gold.csv");
+        std::fs::write(&gold, "segmentation,form
+a1,a1
+a4,a1
+1a,a1
+").unwrap();
+        let (fst_path, gold) = (fst_path.to_str().unwrap(), gold.to_str().unwrap());
+        let args = Args::try_parse_from(["mixtec_fst", "test", fst_path, "-t", gold, "--g3", "--json-report", "report.json"]).unwrap();
+        let Command::Test { input, log, .. } = args.command else { panic!("not a test command") };
+        let opts = TestOptions {
+            testfile: Some(gold),
+            max_paths: None,
+            k_paths: false,
+            raw_labels: false,
+            fast_check: false,
+            both_directions: false,
+            retry_lenient: false,
+            explain_no_result: false,
+            partial_credit: None,
+            ambiguity: None,
+            attribute_sources: false,
+            json_report: Some("report.json"),
+            html_report: None,
+            timeout: None,
+            tag: None,
+            prepared_cache: None,
+            log: &log,
+        };
+        let err = run_test(symt, fst_path, &input, opts, None, &out_dir, None).unwrap_err();
+        assert_eq!(err.to_string(), "1 unexpected failures, 1 gold forms with unproducible symbols");
+        let report: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join("report.json")).unwrap()).unwrap();
+        let log = std::fs::read_to_string(dir.join("log.txt")).unwrap();
+        assert_eq!((&report["forward"]["failed"], &report["forward"]["unproducible"]), (&1.into(), &1.into()));
+        assert_eq!(report["forward"]["items"][1], serde_json::json!({"input": "a1", "form": "a4", "outcome": "unproducible", "unproducible": ["4"]}));
+        assert!(log.contains("a1 -> a4 DATA ERROR (gold has unproducible symbols): 4\n"), "{}", log);
+    }
+
+    #[test]
+    fn test_timeouts_alone_are_not_reported_as_failures() {
+        let mut forward = TestReport::default();
+        forward.record("a1", "a1", false, true);
+        forward.record_timeout("a4", "a4");
+        forward.record_timeout("a3", "a3");
+        assert_eq!(test_problems(&forward, None), ["2 timeouts"]);
+        let mut reverse = TestReport::default();
+        reverse.record("a1", "a1", false, false);
+        assert_eq!(test_problems(&forward, Some(&reverse)), ["1 unexpected failures", "2 timeouts"]);
+    }
+
+    #[test]
+    fn test_out_dir_holds_relative_artifacts() {
+        let args = Args::try_parse_from(["mixtec_fst", "test", "out.fst", "--demo", "--out-dir", "runs/a"]).unwrap();
+        assert_eq!(args.out_dir, PathBuf::from("runs/a"));
+        assert_eq!(Args::try_parse_from(["mixtec_fst", "info", "out.fst"]).unwrap().out_dir, PathBuf::from("."));
+
+        let scratch = TempDir::new("out");
+        let dir = scratch.join("nested");
+        let out_dir = OutDir::create(dir.clone()).unwrap();
+        assert!(dir.is_dir());
+        assert_eq!(out_dir.path("log.txt"), dir.join("log.txt"));
+        assert_eq!(out_dir.path("/tmp/lattice.dot"), PathBuf::from("/tmp/lattice.dot"));
+    }
+}
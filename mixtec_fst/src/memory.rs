@@ -0,0 +1,102 @@
+//! Peak resident memory per build stage (`--measure-memory`).
+//!
+//! Peaks are read from `VmHWM` in `/proc/self/status`. After each sample the
+//! kernel's high-water mark is reset through `/proc/self/clear_refs`, so each
+//! stage reports the peak reached while it ran rather than the peak so far.
+//! Where either file is unavailable (outside Linux), stages are reported as
+//! unmeasured.
+
+use std::cell::RefCell;
+
+/// Peak resident memory of each stage, in kB, in the order the stages ran.
+#[derive(Debug, Default)]
+pub struct MemoryMeter {
+    stages: RefCell<Vec<(String, Option<u64>)>>,
+}
+
+/// The value in kB of `field` in the contents of `/proc/self/status`.
+fn status_field_kb(status: &str, field: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()
+}
+
+pub(crate) fn peak_rss_kb() -> Option<u64> {
+    status_field_kb(&std::fs::read_to_string("/proc/self/status").ok()?, "VmHWM")
+}
+
+fn reset_peak_rss() {
+    // Writing 5 resets the peak RSS to the current RSS (Linux 4.0+).
+    let _ = std::fs::write("/proc/self/clear_refs", "5");
+}
+
+fn format_kb(kb: Option<u64>) -> String {
+    match kb {
+        Some(kb) => format!("{:.1} MiB", kb as f64 / 1024.0),
+        None => "unavailable".to_string(),
+    }
+}
+
+impl MemoryMeter {
+    pub fn new() -> Self {
+        reset_peak_rss();
+        MemoryMeter::default()
+    }
+
+    /// Record the peak memory of the stage that just finished.
+    pub fn stage(&self, name: &str) {
+        let peak = peak_rss_kb();
+        reset_peak_rss();
+        println!("Peak memory during {}: {}", name, format_kb(peak));
+        self.stages.borrow_mut().push((name.to_string(), peak));
+    }
+
+    /// The stage with the highest peak, and that peak.
+    pub fn peak(&self) -> Option<(String, u64)> {
+        self.stages
+            .borrow()
+            .iter()
+            .filter_map(|(name, kb)| Some((name.clone(), (*kb)?)))
+            .max_by_key(|(_, kb)| *kb)
+    }
+
+    pub fn print_summary(&self) {
+        println!("\nPeak memory by stage:");
+        for (name, kb) in self.stages.borrow().iter() {
+            println!("  {:<40} {:>12}", name, format_kb(*kb));
+        }
+        match self.peak() {
+            Some((name, kb)) => println!("Overall peak: {} (during {})", format_kb(Some(kb)), name),
+            None => println!("Overall peak: {}", format_kb(None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_field_kb() {
+        let status = "Name:\tmixtec_fst\nVmPeak:\t  20480 kB\nVmHWM:\t    4096 kB\nVmRSS:\t    2048 kB\n";
+        assert_eq!(status_field_kb(status, "VmHWM"), Some(4096));
+        assert_eq!(status_field_kb(status, "VmRSS"), Some(2048));
+        assert_eq!(status_field_kb(status, "VmSwap"), None);
+    }
+
+    #[test]
+    fn test_peak_picks_largest_stage() {
+        let meter = MemoryMeter::default();
+        meter.stages.borrow_mut().extend([
+            ("union".to_string(), Some(100)),
+            ("minimize".to_string(), Some(300)),
+            ("connect".to_string(), None),
+        ]);
+        assert_eq!(meter.peak(), Some(("minimize".to_string(), 300)));
+    }
+}
@@ -0,0 +1,164 @@
+//! Minimizing every FST in a directory (`minimize-dir`), such as a collection
+//! of per-rule FSTs built with `--no-min`.
+//!
+//! The `.fst` files directly in the directory are loaded in the order of their
+//! names, minimized as the build minimizes (see
+//! [`crate::verify::minimize_nondet`]) and written back, atomically, in place
+//! or under an output directory with the same names. Minimization keeps the
+//! symbol tables. Sidecars (`.info`, `.sources`) are neither updated nor
+//! copied, so the sizes in an `.info` are those from before.
+//!
+//! Each file is reported as it is done, with its size before and after and
+//! the time it took, and the batch with its total size reduction. A run is
+//! cancelled between files by Ctrl-C (see [`crate::cancel`]); the files
+//! minimized by then are written.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::artifact::{read_fst, write_fst};
+use crate::build::FstSize;
+use crate::cancel::CancelToken;
+use crate::verify::{minimize_nondet, Nondeterminism};
+
+/// One FST of the batch, before and after minimization.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Minimized {
+    pub name: String,
+    pub before: FstSize,
+    pub after: FstSize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub elapsed: Duration,
+}
+
+impl Minimized {
+    pub fn report(&self) -> String {
+        format!(
+            "{}: {} -> {} states, {} -> {} arcs, {} -> {} bytes ({:.2}s)",
+            self.name,
+            self.before.num_states,
+            self.after.num_states,
+            self.before.num_trs,
+            self.after.num_trs,
+            self.bytes_before,
+            self.bytes_after,
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+/// The `.fst` files directly in `dir`, sorted by name.
+pub fn list_fsts(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read FST directory {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|e| e == "fst") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Minimize each FST in `dir` and write it back, or to `out` if given,
+/// calling `done` after each.
+pub fn minimize_dir(dir: &Path, out: Option<&Path>, policy: Nondeterminism, mut done: impl FnMut(&Minimized)) -> Result<Vec<Minimized>> {
+    let files = list_fsts(dir)?;
+    if let Some(out) = out {
+        std::fs::create_dir_all(out).with_context(|| format!("Failed to create {}", out.display()))?;
+    }
+    let mut minimized = Vec::new();
+    for path in files {
+        CancelToken::interrupt().check()?;
+        let start = Instant::now();
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let target = out.map_or_else(|| path.clone(), |out| out.join(&name));
+        let bytes_before = std::fs::metadata(&path)?.len();
+        let mut fst = read_fst(&path)?;
+        let before = FstSize::of(&fst);
+        minimize_nondet(&mut fst, policy).with_context(|| format!("Failed to minimize {}", path.display()))?;
+        write_fst(&fst, &target)?;
+        let result = Minimized { name, before, after: FstSize::of(&fst), bytes_before, bytes_after: std::fs::metadata(&target)?.len(), elapsed: start.elapsed() };
+        done(&result);
+        minimized.push(result);
+    }
+    Ok(minimized)
+}
+
+/// The size reduction of the batch, in one line.
+pub fn summary(minimized: &[Minimized]) -> String {
+    let before: u64 = minimized.iter().map(|m| m.bytes_before).sum();
+    let after: u64 = minimized.iter().map(|m| m.bytes_after).sum();
+    let states = |size: fn(&Minimized) -> usize| minimized.iter().map(size).sum::<usize>();
+    let elapsed: Duration = minimized.iter().map(|m| m.elapsed).sum();
+    let saved = if before == 0 { 0.0 } else { 100.0 * (before as f64 - after as f64) / before as f64 };
+    format!(
+        "Minimized {} FSTs: {} -> {} states, {} -> {} bytes ({:.1}% smaller), in {:.2}s",
+        minimized.len(),
+        states(|m| m.before.num_states),
+        states(|m| m.after.num_states),
+        before,
+        after,
+        saved,
+        elapsed.as_secs_f64()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use rustfst::prelude::union::union;
+    use rustfst::prelude::{Fst, ExpandedFst, TropicalWeight, VectorFst};
+    use rustfst::utils::transducer;
+    use rustfst::{Semiring, SymbolTable};
+
+    use crate::testutil::TempDir;
+
+    /// `a -> b` twice over, as two paths that minimization merges.
+    fn redundant() -> VectorFst<TropicalWeight> {
+        let symt = Arc::new(rustfst::symt!["a", "b"]);
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1, 1 => 2, 2; 1.0];
+        let same: VectorFst<TropicalWeight> = rustfst::fst![1, 1 => 2, 2; 1.0];
+        union(&mut fst, &same).unwrap();
+        fst.set_input_symbols(symt.clone());
+        fst.set_output_symbols(symt);
+        fst
+    }
+
+    #[test]
+    fn test_minimizes_in_name_order_keeping_symbols() {
+        let dir = TempDir::new("minimize-dir-in-place");
+        for name in ["b.fst", "a.fst"] {
+            write_fst(&redundant(), &dir.join(name)).unwrap();
+        }
+        std::fs::write(dir.join("a.fst.info"), "num_states=7\n").unwrap();
+        let mut reported = Vec::new();
+        let minimized = minimize_dir(&dir, None, Nondeterminism::Allow, |m| reported.push(m.name.clone())).unwrap();
+        assert_eq!(reported, ["a.fst", "b.fst"]);
+        for m in &minimized {
+            assert!(m.after.num_states < m.before.num_states, "{}", m.report());
+            let fst = read_fst(&dir.join(&m.name)).unwrap();
+            assert_eq!(FstSize::of(&fst), m.after);
+            assert_eq!(fst.input_symbols().unwrap().get_label("b"), Some(2));
+            assert!(fst.output_symbols().is_some());
+        }
+        assert_eq!(std::fs::read_to_string(dir.join("a.fst.info")).unwrap(), "num_states=7\n");
+        assert!(summary(&minimized).starts_with("Minimized 2 FSTs: "), "{}", summary(&minimized));
+    }
+
+    #[test]
+    fn test_writes_to_output_dir() {
+        let dir = TempDir::new("minimize-dir-from");
+        let out = dir.join("minimized");
+        write_fst(&redundant(), &dir.join("r.fst")).unwrap();
+        let minimized = minimize_dir(&dir, Some(&out), Nondeterminism::Allow, |_| {}).unwrap();
+        assert_eq!(minimized.len(), 1);
+        assert_eq!(read_fst(&dir.join("r.fst")).unwrap().num_states(), minimized[0].before.num_states);
+        assert_eq!(read_fst(&out.join("r.fst")).unwrap().num_states(), minimized[0].after.num_states);
+    }
+}
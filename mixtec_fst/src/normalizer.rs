@@ -0,0 +1,189 @@
+//! The normalization of inputs as a transducer composed in front of the
+//! segmentation FST (`build --normalizer-fst`), rather than done to the input
+//! strings by the code that queries it.
+//!
+//! Inputs are otherwise NFD-normalized and mapped through the grapheme map
+//! (see [`crate::graphemes`]) before the FST sees them, so what an artifact
+//! accepts depends on the flags it is queried with, and tools outside this
+//! crate see none of it. The normalizer is the same mapping as a transducer
+//! from raw characters to symbols:
+//!
+//! - a symbol is copied;
+//! - a grapheme of the map is rewritten as its symbols, even if it is a symbol;
+//! - a precomposed character whose canonical decomposition is spelled in the
+//!   graphemes and symbols is rewritten as those, e.g. `á` as the symbol `á`
+//!   (`a` + U+0301).
+//!
+//! Its input symbols are the table's, with the graphemes and precomposed
+//! characters added after them, so that symbols keep their labels. Combining
+//! marks written out of canonical order are not reordered, and an input with a
+//! character outside all of these has no analysis, where the string-level
+//! mapping refuses it.
+//!
+//! The build records in the sidecar that the FST embeds its normalizer
+//! ([`Normalization::Embedded`]), and the commands that query it then pass
+//! their inputs as written ([`crate::graphemes::GraphemeMap::verbatim`]), so
+//! that nothing is normalized twice.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rustfst::prelude::{Fst, MutableFst, TropicalWeight, VectorFst};
+use rustfst::{Label, Semiring, SymbolTable, Tr, EPS_LABEL};
+
+use crate::alphabet::SurfaceToSurfaceFst;
+use crate::graphemes::GraphemeMap;
+use crate::provenance::read_provenance;
+
+/// Where the inputs of an FST are normalized.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    /// By the code that queries it, as strings.
+    #[default]
+    String,
+    /// By the FST itself, through its normalizer.
+    Embedded,
+}
+
+impl Normalization {
+    pub fn name(self) -> &'static str {
+        match self {
+            Normalization::String => "string",
+            Normalization::Embedded => "embedded",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "string" => Some(Normalization::String),
+            "embedded" => Some(Normalization::Embedded),
+            _ => None,
+        }
+    }
+}
+
+/// Whether the sidecar of the FST at `fst_path` says it embeds its normalizer.
+pub fn embeds_normalizer(fst_path: &Path) -> Result<bool> {
+    Ok(read_provenance(fst_path)?.and_then(|p| p.normalization) == Some(Normalization::Embedded))
+}
+
+/// The last code point looked at for precomposed characters: the planes with
+/// canonical decompositions.
+const LAST_CODE_POINT: u32 = 0x2FFFF;
+
+/// The characters other than `symt`'s symbols that NFD-normalize to a spelling
+/// `graphemes` accepts, each with the symbols it is spelled with.
+fn precomposed(symt: &SymbolTable, graphemes: &GraphemeMap) -> Vec<(String, Vec<String>)> {
+    let mut found = Vec::new();
+    for c in (0..=LAST_CODE_POINT).filter_map(char::from_u32) {
+        let mut decomposed = 0;
+        unicode_normalization::char::decompose_canonical(c, |_| decomposed += 1);
+        let c = c.to_string();
+        if decomposed < 2 || symt.contains_symbol(&c) {
+            continue;
+        }
+        if let Ok(symbols) = graphemes.tokenize(symt, &c) {
+            found.push((c, symbols));
+        }
+    }
+    found
+}
+
+/// The normalizer of inputs spelled with `symt` through `graphemes` (see the
+/// module documentation): an unweighted transducer from raw characters to
+/// the symbols of `symt`.
+pub fn compile_normalizer(symt: Arc<SymbolTable>, graphemes: &GraphemeMap) -> Result<SurfaceToSurfaceFst> {
+    graphemes.validate(&symt)?;
+    let mut raw = (*symt).clone();
+    let label = |symbol: &str| symt.get_label(symbol).expect("the grapheme map was validated");
+    let mut rewrites: Vec<(Label, Vec<Label>)> = Vec::new();
+    for (grapheme, symbols) in graphemes.entries().iter().cloned().chain(precomposed(&symt, graphemes)) {
+        let symbols = symbols.iter().map(|s| label(s)).collect();
+        rewrites.push((raw.add_symbol(grapheme), symbols));
+    }
+    let rewritten: HashSet<Label> = rewrites.iter().map(|(l, _)| *l).collect();
+
+    let mut fst = VectorFst::<TropicalWeight>::new();
+    let start = fst.add_state();
+    fst.set_start(start)?;
+    fst.set_final(start, TropicalWeight::one())?;
+    for (l, _) in symt.iter().filter(|&(l, _)| l != EPS_LABEL && !rewritten.contains(&l)) {
+        fst.add_tr(start, Tr::new(l, l, TropicalWeight::one(), start))?;
+    }
+    for (ilabel, olabels) in rewrites {
+        // The input label, then one symbol per arc back to the start; a
+        // grapheme mapped to no symbols is deleted.
+        let n = olabels.len().max(1);
+        let (mut from, mut ilabel) = (start, ilabel);
+        for i in 0..n {
+            let to = if i + 1 == n { start } else { fst.add_state() };
+            let olabel = olabels.get(i).copied().unwrap_or(EPS_LABEL);
+            fst.add_tr(from, Tr::new(ilabel, olabel, TropicalWeight::one(), to))?;
+            (from, ilabel) = (to, EPS_LABEL);
+        }
+    }
+    fst.set_input_symbols(Arc::new(raw));
+    fst.set_output_symbols(symt);
+    Ok(SurfaceToSurfaceFst(fst))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use unicode_normalization::UnicodeNormalization;
+
+    use crate::alphabet::SurfaceAcceptor;
+    use crate::automaton::Tokenization;
+    use crate::composition::{sorted_compose, ComposeFilter};
+    use crate::decode::decode_distinct_outputs;
+    use crate::ranking::Lexicographic;
+    use crate::testutil::{fixture_symt, root};
+
+    /// The symbols the normalizer writes for `input`, space-separated, one
+    /// string per distinct output.
+    fn normalized(normalizer: &SurfaceToSurfaceFst, symt: &SymbolTable, input: &str) -> Vec<String> {
+        let raw = normalizer.input_symbols().unwrap().clone();
+        let Ok(acceptor) = SurfaceAcceptor::of(&raw, input, Tokenization::LongestMatch, None) else { return Vec::new() };
+        let lattice = sorted_compose(&acceptor.0, &normalizer.0, ComposeFilter::Auto).unwrap();
+        let display = |olabels: &[Label]| olabels.iter().map(|&l| symt.get_symbol(l).unwrap()).collect::<Vec<_>>().join(" ");
+        decode_distinct_outputs(&lattice, None, &Lexicographic, display).unwrap().into_iter().map(|(_, o)| o).collect()
+    }
+
+    #[test]
+    fn test_matches_string_level_normalization_on_fixture_words() {
+        let symt = fixture_symt();
+        let graphemes = GraphemeMap::read(&root().join("tests/normalizer/graphemes.csv"), None).unwrap();
+        let normalizer = compile_normalizer(symt.clone(), &graphemes).unwrap();
+        let words = std::fs::read_to_string(root().join("tests/normalizer/words.txt")).unwrap();
+        for word in words.lines().filter(|w| !w.is_empty()) {
+            let expected = graphemes.tokenize(&symt, word).unwrap().join(" ");
+            // Precomposed and decomposed spellings alike.
+            for spelling in [word.nfc().collect::<String>(), word.nfd().collect()] {
+                assert_eq!(normalized(&normalizer, &symt, &spelling), vec![expected.clone()], "{}", spelling);
+            }
+        }
+        // What the grapheme map cannot spell, the normalizer does not either.
+        assert!(graphemes.tokenize(&symt, "x§").is_err());
+        assert!(normalized(&normalizer, &symt, "x§").is_empty());
+    }
+
+    #[test]
+    fn test_grapheme_outranks_a_symbol_it_spells() {
+        let symt = Arc::new(rustfst::symt!["a", "e", "'"]);
+        let graphemes = GraphemeMap::new([("e".to_string(), vec!["a".to_string()]), ("ꞌ".to_string(), vec!["'".to_string()])]);
+        let normalizer = compile_normalizer(symt.clone(), &graphemes).unwrap();
+        assert_eq!(normalized(&normalizer, &symt, "eꞌa'"), ["a ' a '"]);
+    }
+
+    #[test]
+    fn test_normalization_names_round_trip() {
+        for normalization in [Normalization::String, Normalization::Embedded] {
+            assert_eq!(Normalization::parse(normalization.name()), Some(normalization));
+        }
+        assert_eq!(Normalization::parse("nfc"), None);
+    }
+}
@@ -0,0 +1,377 @@
+//! `build --optimize auto`: the final optimization of the union as a retry
+//! ladder, for rule sets whose best pipeline is not known in advance.
+//!
+//! Each level of the ladder is a cheaper pipeline than the one before it:
+//!
+//! | level            | steps                                        |
+//! |------------------|----------------------------------------------|
+//! | `full`           | rm_epsilon, determinize, minimize, connect   |
+//! | `no-determinize` | rm_epsilon, minimize, connect                |
+//! | `no-minimize`    | rm_epsilon, connect                          |
+//! | `connect-only`   | connect                                      |
+//!
+//! Each level starts over from the union and runs under a budget: at most
+//! `--optimize-time-budget` seconds, and no step may leave more than
+//! `--optimize-max-states` states. A level that runs out of either falls
+//! back to the next. The last level only removes states, so it runs without
+//! a budget and the ladder always ends with an FST. Every level keeps the
+//! relation of the union, weights included; a lower one only leaves the FST
+//! larger and slower to search. The level reached, and why the ones above it
+//! were given up, go in the `.info` sidecar.
+//!
+//! FST operations cannot be interrupted, so a level that runs out of time is
+//! left to finish in the background (see [`with_timeout`]), where it competes
+//! for the CPU with the levels after it.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use rustfst::algorithms::determinize::{determinize_with_config, DeterminizeConfig, DeterminizeType};
+use rustfst::algorithms::{connect, rm_epsilon::rm_epsilon};
+use rustfst::fst_impls::VectorFst;
+use rustfst::prelude::*;
+
+use crate::dump::{guard_in_place, Operation};
+use crate::pool::with_timeout;
+use crate::profile::profile_span;
+use crate::status::internal;
+use crate::verify::{apply_verified, minimize_verified, Nondeterminism, OnDivergence, VerifyOptions};
+
+/// Time each level of the ladder may take, unless `--optimize-time-budget`
+/// says otherwise.
+pub const DEFAULT_TIME_BUDGET_SECS: u64 = 600;
+
+/// Most states any step of a level may leave, unless `--optimize-max-states`
+/// says otherwise.
+pub const DEFAULT_MAX_STATES: usize = 5_000_000;
+
+/// Delta of determinization; tighter than rustfst's default, as minimization is.
+const DELTA: f32 = 1e-7;
+
+/// How `build` optimizes the union of the rule files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OptimizeMode {
+    /// Remove epsilons, then minimize and connect unless --no-min or
+    /// --no-connect say otherwise
+    #[default]
+    Fixed,
+    /// The most thorough pipeline that fits the budget (see optimize.rs)
+    Auto,
+}
+
+/// A level of the ladder, most thorough first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptimizeLevel {
+    Full,
+    NoDeterminize,
+    NoMinimize,
+    ConnectOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    RmEpsilon,
+    Determinize,
+    Minimize,
+    Connect,
+}
+
+impl OptimizeLevel {
+    /// The levels in the order they are tried.
+    pub const LADDER: [OptimizeLevel; 4] =
+        [OptimizeLevel::Full, OptimizeLevel::NoDeterminize, OptimizeLevel::NoMinimize, OptimizeLevel::ConnectOnly];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OptimizeLevel::Full => "full",
+            OptimizeLevel::NoDeterminize => "no-determinize",
+            OptimizeLevel::NoMinimize => "no-minimize",
+            OptimizeLevel::ConnectOnly => "connect-only",
+        }
+    }
+
+    fn steps(self) -> &'static [Step] {
+        match self {
+            OptimizeLevel::Full => &[Step::RmEpsilon, Step::Determinize, Step::Minimize, Step::Connect],
+            OptimizeLevel::NoDeterminize => &[Step::RmEpsilon, Step::Minimize, Step::Connect],
+            OptimizeLevel::NoMinimize => &[Step::RmEpsilon, Step::Connect],
+            OptimizeLevel::ConnectOnly => &[Step::Connect],
+        }
+    }
+
+    /// Whether the level runs under the budget: all but the last do.
+    fn budgeted(self) -> bool {
+        self != OptimizeLevel::ConnectOnly
+    }
+}
+
+impl Step {
+    fn name(self) -> &'static str {
+        match self {
+            Step::RmEpsilon => "rm_epsilon",
+            Step::Determinize => "determinize",
+            Step::Minimize => "minimize",
+            Step::Connect => "connect",
+        }
+    }
+}
+
+/// What each budgeted level of the ladder may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptimizeBudget {
+    pub time: Duration,
+    pub max_states: usize,
+}
+
+impl Default for OptimizeBudget {
+    fn default() -> Self {
+        OptimizeBudget { time: Duration::from_secs(DEFAULT_TIME_BUDGET_SECS), max_states: DEFAULT_MAX_STATES }
+    }
+}
+
+/// Why a level was given up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exhausted {
+    /// The level took longer than the time budget.
+    Time,
+    /// The step named left more states than the budget allows.
+    States { step: &'static str, states: usize },
+}
+
+impl fmt::Display for Exhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exhausted::Time => write!(f, "time"),
+            Exhausted::States { step, states } => write!(f, "states after {} ({})", step, states),
+        }
+    }
+}
+
+/// The level the ladder reached, and the levels given up before it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LadderReport {
+    pub level: OptimizeLevel,
+    pub exhausted: Vec<(OptimizeLevel, Exhausted)>,
+}
+
+impl LadderReport {
+    /// The sidecar lines of the report: `optimize_level=<level>`, and with
+    /// any level given up, `optimize_exhausted=` its `<level>:time` or
+    /// `<level>:states` pairs.
+    pub fn sidecar_lines(&self) -> String {
+        let mut lines = format!("optimize_level={}\n", self.level.name());
+        if !self.exhausted.is_empty() {
+            let given_up: Vec<String> = self
+                .exhausted
+                .iter()
+                .map(|(level, why)| {
+                    let budget = match why {
+                        Exhausted::Time => "time",
+                        Exhausted::States { .. } => "states",
+                    };
+                    format!("{}:{}", level.name(), budget)
+                })
+                .collect();
+            lines.push_str(&format!("optimize_exhausted={}\n", given_up.join(" ")));
+        }
+        lines
+    }
+}
+
+/// Run the steps of `level` on `fst`, giving up on the first that leaves
+/// more than `max_states` states.
+fn run_level(
+    level: OptimizeLevel,
+    symt: &SymbolTable,
+    mut fst: VectorFst<TropicalWeight>,
+    max_states: Option<usize>,
+    verify: Option<(VerifyOptions, OnDivergence)>,
+    policy: Nondeterminism,
+) -> Result<Result<VectorFst<TropicalWeight>, Exhausted>> {
+    for &step in level.steps() {
+        println!("{}: {}...", level.name(), step.name());
+        let _span = profile_span!("optimize_step", step = step.name());
+        match step {
+            Step::RmEpsilon => guard_in_place(Operation::RmEpsilon, &mut fst, rm_epsilon)?,
+            Step::Determinize => {
+                apply_verified("Determinization", symt, &mut fst, verify, |fst| {
+                    let op = Operation::Determinize { delta: DELTA, functional: false };
+                    guard_in_place(op, fst, |fst| {
+                        let config = DeterminizeConfig { delta: DELTA, det_type: DeterminizeType::DeterminizeNonFunctional };
+                        *fst = internal(determinize_with_config(fst, config))?;
+                        Ok(())
+                    })
+                })?;
+            }
+            Step::Minimize => {
+                minimize_verified(symt, &mut fst, verify, policy)?;
+            }
+            Step::Connect => connect(&mut fst)?,
+        }
+        let states = fst.num_states();
+        if let Some(max) = max_states
+            && states > max
+        {
+            return Ok(Err(Exhausted::States { step: step.name(), states }));
+        }
+    }
+    Ok(Ok(fst))
+}
+
+/// Optimize the union `fst` by the most thorough level of the ladder that
+/// fits `budget`. Failures other than running out of budget are errors, not
+/// reasons to fall back.
+pub fn optimize_auto(
+    symt: Arc<SymbolTable>,
+    fst: VectorFst<TropicalWeight>,
+    budget: OptimizeBudget,
+    verify: Option<(VerifyOptions, OnDivergence)>,
+    policy: Nondeterminism,
+) -> Result<(VectorFst<TropicalWeight>, LadderReport)> {
+    let _span = profile_span!("optimize_auto");
+    let mut exhausted = Vec::new();
+    for level in OptimizeLevel::LADDER {
+        let budgeted = level.budgeted();
+        let (symt, union) = (symt.clone(), fst.clone());
+        let max_states = budgeted.then_some(budget.max_states);
+        let run = move || run_level(level, &symt, union, max_states, verify, policy);
+        let outcome = match with_timeout(budgeted.then_some(budget.time), run) {
+            Some(result) => result?,
+            None => Err(Exhausted::Time),
+        };
+        match outcome {
+            Ok(optimized) => {
+                println!("Optimized at level {}", level.name());
+                return Ok((optimized, LadderReport { level, exhausted }));
+            }
+            Err(why) => {
+                println!("Level {} ran out of budget ({}); falling back", level.name(), why);
+                exhausted.push((level, why));
+            }
+        }
+    }
+    unreachable!("the last level of the ladder has no budget")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use crate::alphabet::SurfaceToAnalysisFst;
+    use crate::analysis::AnalysisFormat;
+    use crate::build::union_scripts;
+    use crate::check::accepts_pair;
+    use crate::prepared::PreparedFst;
+    use crate::rules::{load_script, RuleChecks};
+    use crate::testutil::{fixture_golds, fixture_symt, min_rules};
+
+    /// The union of the fixture files, before epsilon removal, and the gold
+    /// items to check it against.
+    fn fixture() -> (Arc<SymbolTable>, VectorFst<TropicalWeight>, Vec<(String, String)>) {
+        let symt = fixture_symt();
+        let golds = fixture_golds();
+        let scripts: Vec<(PathBuf, _)> = min_rules(&["neg_4.txt", "hab_14.txt"])
+            .into_iter()
+            .map(|f| (f.clone(), load_script(&f).unwrap()))
+            .collect();
+        let checks = &mut RuleChecks::default();
+        let union = union_scripts(symt.clone(), scripts, &HashMap::new(), Default::default(), Default::default(), None, None, checks).unwrap().into_fst();
+        (symt, union, golds)
+    }
+
+    /// `optimized` accepts exactly the gold pairs the fixture union does.
+    fn assert_same_results(union: &VectorFst<TropicalWeight>, optimized: &VectorFst<TropicalWeight>, golds: &[(String, String)]) {
+        let prepare = |fst: &VectorFst<TropicalWeight>| PreparedFst::new(SurfaceToAnalysisFst(fst.clone()), None, AnalysisFormat::default()).unwrap();
+        let (union, optimized) = (prepare(union), prepare(optimized));
+        for (form, segmentation) in golds {
+            assert_eq!(
+                accepts_pair(&union, form, segmentation).unwrap(),
+                accepts_pair(&optimized, form, segmentation).unwrap(),
+                "{} -> {}",
+                form,
+                segmentation
+            );
+        }
+    }
+
+    /// An identity acceptor of the words over a, b whose n-th symbol from
+    /// the end is a, which determinization blows up to 2^(n+1) states.
+    fn blows_up(n: usize) -> VectorFst<TropicalWeight> {
+        let mut fst = VectorFst::new();
+        let states: Vec<StateId> = (0..n + 2).map(|_| fst.add_state()).collect();
+        fst.set_start(states[0]).unwrap();
+        for label in [1, 2] {
+            fst.add_tr(states[0], Tr::new(label, label, TropicalWeight::one(), states[0])).unwrap();
+        }
+        fst.add_tr(states[0], Tr::new(1, 1, TropicalWeight::one(), states[1])).unwrap();
+        for i in 1..=n {
+            for label in [1, 2] {
+                fst.add_tr(states[i], Tr::new(label, label, TropicalWeight::one(), states[i + 1])).unwrap();
+            }
+        }
+        fst.set_final(states[n + 1], TropicalWeight::one()).unwrap();
+        fst
+    }
+
+    fn symt() -> Arc<SymbolTable> {
+        Arc::new(rustfst::symt!["a", "b"])
+    }
+
+    #[test]
+    fn test_ladder_stops_at_the_first_level_within_budget() {
+        let fst = blows_up(3);
+        let (optimized, report) = optimize_auto(symt(), fst.clone(), OptimizeBudget::default(), None, Nondeterminism::Allow).unwrap();
+        assert_eq!(report, LadderReport { level: OptimizeLevel::Full, exhausted: Vec::new() });
+        assert_eq!(optimized.num_states(), 16);
+
+        // Determinization needs more states than the budget allows; minimizing
+        // the nondeterministic FST does not.
+        let budget = OptimizeBudget { max_states: fst.num_states(), ..Default::default() };
+        let (optimized, report) = optimize_auto(symt(), fst.clone(), budget, None, Nondeterminism::Allow).unwrap();
+        assert_eq!(report.level, OptimizeLevel::NoDeterminize);
+        assert!(matches!(report.exhausted[..], [(OptimizeLevel::Full, Exhausted::States { step: "determinize", .. })]), "{:?}", report);
+        assert!(optimized.num_states() <= fst.num_states());
+    }
+
+    #[test]
+    fn test_time_budget_gives_up_on_slow_levels() {
+        let budget = OptimizeBudget { time: Duration::from_millis(1), ..Default::default() };
+        let (_, report) = optimize_auto(symt(), blows_up(16), budget, None, Nondeterminism::Allow).unwrap();
+        assert_eq!(report.exhausted[0], (OptimizeLevel::Full, Exhausted::Time));
+        assert!(report.level != OptimizeLevel::Full);
+    }
+
+    #[test]
+    fn test_fixture_falls_back_to_connect_only_within_budget() {
+        let (symt, union, golds) = fixture();
+        let budget = OptimizeBudget { max_states: 1, ..Default::default() };
+        let (fst, report) = optimize_auto(symt, union.clone(), budget, None, Nondeterminism::Allow).unwrap();
+        assert_eq!(report.level, OptimizeLevel::ConnectOnly);
+        let given_up: Vec<OptimizeLevel> = report.exhausted.iter().map(|(level, _)| *level).collect();
+        assert_eq!(given_up, OptimizeLevel::LADDER[..3]);
+        assert!(report.exhausted.iter().all(|(_, why)| matches!(why, Exhausted::States { step: "rm_epsilon", .. })));
+        assert!(fst.num_states() <= union.num_states());
+        assert_same_results(&union, &fst, &golds);
+        let sidecar = report.sidecar_lines();
+        assert!(sidecar.contains("optimize_level=connect-only\n"), "{}", sidecar);
+        assert!(sidecar.contains("optimize_exhausted=full:states no-determinize:states no-minimize:states\n"), "{}", sidecar);
+    }
+
+    #[test]
+    fn test_sidecar_lines() {
+        let reached = LadderReport { level: OptimizeLevel::Full, exhausted: Vec::new() };
+        assert_eq!(reached.sidecar_lines(), "optimize_level=full\n");
+        let fell_back = LadderReport {
+            level: OptimizeLevel::NoMinimize,
+            exhausted: vec![
+                (OptimizeLevel::Full, Exhausted::Time),
+                (OptimizeLevel::NoDeterminize, Exhausted::States { step: "minimize", states: 9 }),
+            ],
+        };
+        assert_eq!(fell_back.sidecar_lines(), "optimize_level=no-minimize\noptimize_exhausted=full:time no-determinize:states\n");
+    }
+}
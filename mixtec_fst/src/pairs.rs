@@ -0,0 +1,168 @@
+//! Candidate minimal pairs for elicitation (`minimal-pairs`).
+//!
+//! Each word is paired with every form that differs from it in one tone, where
+//! the FST accepts that form, and a pair is kept when the best analyses of its
+//! two forms differ by more than that tone: two forms the FST copies through
+//! unchanged are not a contrast it has anything to say about. Acceptance is the emptiness check of `test --fast-check`,
+//! so the forms the FST rejects cost one composition each; only accepted
+//! forms are searched for their analyses, and only for the best two.
+//!
+//! A pair is as trustworthy as the less certain of its two best analyses, so
+//! pairs are ranked by their margin: the smaller, over the two forms, of the
+//! weight by which the best analysis beats the runner-up.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use anyhow::Result;
+use itertools::Itertools;
+use rustfst::Semiring;
+
+use crate::check::accepts;
+use crate::pool::par_map;
+use crate::prepared::PreparedFst;
+use crate::search::Candidate;
+use crate::tones::ToneSet;
+
+/// Two forms that differ in one tone and have different best analyses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MinimalPair {
+    /// The forms, in order.
+    pub forms: [String; 2],
+    pub analyses: [Candidate; 2],
+    /// The tone of each form where they differ.
+    pub tones: [char; 2],
+    /// How far the less certain of the two best analyses is ahead of its
+    /// runner-up; infinite when both forms have one analysis.
+    pub margin: f32,
+}
+
+/// The forms that differ from `form` in exactly one tone.
+pub fn tone_substitutions(form: &str, tones: &ToneSet) -> Vec<(String, char, char)> {
+    let chars: Vec<char> = form.chars().collect();
+    let mut out = Vec::new();
+    for (i, &c) in chars.iter().enumerate().filter(|&(_, &c)| tones.contains(c)) {
+        for t in tones.to_string().chars().filter(|&t| t != c) {
+            let mut variant = chars.clone();
+            variant[i] = t;
+            out.push((variant.into_iter().collect(), c, t));
+        }
+    }
+    out
+}
+
+/// The best analysis of `form` and the margin by which it beats the next;
+/// `None` if there is no analysis.
+fn best_with_margin(prepared: &PreparedFst, form: &str) -> Result<Option<(Candidate, f32)>> {
+    let best: Vec<Candidate> = prepared.analyses(form).take(2).collect::<Result<_>>()?;
+    let mut best = best.into_iter();
+    Ok(best.next().map(|first| {
+        let margin = best.next().map_or(f32::INFINITY, |second| second.weight.value() - first.weight.value());
+        (first, margin)
+    }))
+}
+
+/// The minimal pairs of `words` with the tone substitutions of each, most
+/// confident first.
+pub fn find_minimal_pairs(prepared: &PreparedFst, words: &[String], tones: &ToneSet, jobs: usize) -> Result<Vec<MinimalPair>> {
+    let words: Vec<String> = words.iter().unique().cloned().collect();
+    let mut best: HashMap<String, Option<(Candidate, f32)>> = HashMap::new();
+    for (word, result) in words.iter().zip(par_map(jobs, &words, |w| best_with_margin(prepared, w))) {
+        best.insert(word.clone(), result?);
+    }
+    let substitutions: Vec<(String, String, char, char)> = words
+        .iter()
+        .filter(|w| matches!(best.get(*w), Some(Some(_))))
+        .flat_map(|w| tone_substitutions(w, tones).into_iter().map(move |(v, from, to)| (w.clone(), v, from, to)))
+        .collect();
+    let variants: Vec<String> = substitutions.iter().map(|(_, v, _, _)| v.clone()).filter(|v| !best.contains_key(v)).unique().collect();
+    let analysed = par_map(jobs, &variants, |v| match accepts(prepared, v)? {
+        true => best_with_margin(prepared, v),
+        false => Ok(None),
+    });
+    for (variant, result) in variants.into_iter().zip(analysed) {
+        best.insert(variant, result?);
+    }
+
+    // Each pair once, with its forms in order.
+    let mut pairs: BTreeMap<(String, String), MinimalPair> = BTreeMap::new();
+    for (word, variant, from, to) in substitutions {
+        let (Some(Some((a, margin_a))), Some(Some((b, margin_b)))) = (best.get(&word), best.get(&variant)) else { continue };
+        let echoes = tone_substitutions(&a.analysis, tones).into_iter().any(|(v, f, t)| v == b.analysis && f == from && t == to);
+        if a.analysis == b.analysis || echoes {
+            continue;
+        }
+        let pair = if word < variant {
+            MinimalPair { forms: [word, variant], analyses: [a.clone(), b.clone()], tones: [from, to], margin: margin_a.min(*margin_b) }
+        } else {
+            MinimalPair { forms: [variant, word], analyses: [b.clone(), a.clone()], tones: [to, from], margin: margin_a.min(*margin_b) }
+        };
+        pairs.entry(pair.forms.clone().into()).or_insert(pair);
+    }
+    let mut pairs: Vec<MinimalPair> = pairs.into_values().collect();
+    pairs.sort_by(|p, q| q.margin.total_cmp(&p.margin));
+    Ok(pairs)
+}
+
+/// Write `pairs` as CSV, one row per pair.
+pub fn write_pairs_csv<W: Write>(pairs: &[MinimalPair], out: W) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["form1", "analysis1", "weight1", "form2", "analysis2", "weight2", "tones", "margin"])?;
+    for p in pairs {
+        writer.write_record([
+            p.forms[0].as_str(),
+            &p.analyses[0].analysis,
+            &p.analyses[0].weight.value().to_string(),
+            p.forms[1].as_str(),
+            &p.analyses[1].analysis,
+            &p.analyses[1].weight.value().to_string(),
+            &format!("{}/{}", p.tones[0], p.tones[1]),
+            &p.margin.to_string(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use parserule::rulefst;
+    use parserule::ruleparse::parse_script;
+    use rustfst::prelude::{Fst, TropicalWeight, VectorFst};
+    use rustfst::SymbolTable;
+
+    use crate::alphabet::SurfaceToAnalysisFst;
+    use crate::analysis::AnalysisFormat;
+
+    #[test]
+    fn test_tone_substitutions() {
+        let tones = ToneSet::parse("134").unwrap();
+        let variants: Vec<String> = tone_substitutions("ka1a4", &tones).into_iter().map(|(v, _, _)| v).collect();
+        assert_eq!(variants, ["ka3a4", "ka4a4", "ka1a1", "ka1a3"]);
+        assert!(tone_substitutions("kaa", &tones).is_empty());
+    }
+
+    #[test]
+    fn test_pairs_need_different_analyses() {
+        let symt = Arc::new(rustfst::symt!["#", "a", "k", "g", "1", "3", "4"]);
+        // Voices `k` before `a3` only.
+        let (_, (script, _)) = parse_script("k -> g / _ a3\n").unwrap();
+        let mut fst: VectorFst<TropicalWeight> = rulefst::compile_script(symt.clone(), script).unwrap();
+        fst.set_input_symbols(symt.clone());
+        fst.set_output_symbols(symt);
+        let prepared = PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap();
+        let pairs = find_minimal_pairs(&prepared, &["ka1".to_string(), "ka3".to_string()], &ToneSet::parse("134").unwrap(), 2).unwrap();
+        let forms: Vec<[&str; 2]> = pairs.iter().map(|p| [p.forms[0].as_str(), p.forms[1].as_str()]).collect();
+        // ka1 and ka4 are both copied through, so they are not a pair.
+        assert_eq!(forms, [["ka1", "ka3"], ["ka3", "ka4"]]);
+        assert_eq!((pairs[0].analyses[1].analysis.as_str(), pairs[0].tones), ("ga3", ['1', '3']));
+        assert_eq!(pairs[0].margin, 2.0);
+        let mut csv = Vec::new();
+        write_pairs_csv(&pairs, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("form1,analysis1,weight1,form2,analysis2,weight2,tones,margin\nka1,ka1,25,ka3,ga3,21,1/3,2\n"), "{}", csv);
+    }
+}
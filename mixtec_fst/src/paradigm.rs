@@ -0,0 +1,238 @@
+//! Expanding underlying stems into a table of predicted surface forms
+//! (`generate-paradigm`).
+//!
+//! Every stem is placed in every context by plain string concatenation
+//! (`prefix + stem + suffix`), and the FST is run in the generation direction
+//! on the result. The table is streamed to CSV one stem at a time, and each
+//! finished stem is then appended to a `<out>.progress` file with the length
+//! of the table so far, so that an interrupted run over a long stem list can be
+//! resumed where it stopped, without the rows of a stem it was partway through.
+
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use rustfst::{Semiring, SymbolTable};
+
+use crate::check::top_surfaces;
+use crate::graphemes::GraphemeMap;
+use crate::prepared::PreparedFst;
+
+/// A morphological context, from a CSV file with `name`, `prefix` and
+/// `suffix` columns. The prefix and suffix are in the same notation as the
+/// stems, tone processes included.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct Context {
+    pub name: String,
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub suffix: String,
+}
+
+impl Context {
+    /// The underlying form of `stem` in this context.
+    pub fn apply(&self, stem: &str) -> String {
+        format!("{}{}{}", self.prefix, stem, self.suffix)
+    }
+}
+
+pub fn parse_contexts(name: &str, text: &str) -> Result<Vec<Context>> {
+    let mut reader = csv::Reader::from_reader(text.as_bytes());
+    let contexts = reader
+        .deserialize()
+        .collect::<Result<Vec<Context>, _>>()
+        .map_err(|e| anyhow!("Failed to read contexts {}: {}", name, e))?;
+    if contexts.is_empty() {
+        bail!("No contexts in {}", name);
+    }
+    Ok(contexts)
+}
+
+/// Check that every stem, prefix and suffix is spelled in symbols of `symt`,
+/// listing all of those that are not.
+pub fn validate(symt: &SymbolTable, stems: &[String], contexts: &[Context]) -> Result<()> {
+    let identity = GraphemeMap::default();
+    let strings = stems.iter().map(|s| ("stem", s)).chain(
+        contexts.iter().flat_map(|c| [("prefix of context", &c.prefix), ("suffix of context", &c.suffix)]),
+    );
+    let errors: Vec<String> = strings
+        .filter_map(|(what, s)| identity.tokenize(symt, s).err().map(|e| format!("{} '{}': {}", what, s, e)))
+        .collect();
+    if !errors.is_empty() {
+        bail!("{} invalid stems or contexts:\n  {}", errors.len(), errors.join("\n  "));
+    }
+    Ok(())
+}
+
+/// The progress file of the paradigm table written to `out`.
+pub fn progress_path(out: &Path) -> PathBuf {
+    let mut path = out.as_os_str().to_owned();
+    path.push(".progress");
+    PathBuf::from(path)
+}
+
+#[derive(serde::Serialize)]
+struct Row<'a> {
+    stem: &'a str,
+    context: &'a str,
+    rank: usize,
+    surface: &'a str,
+    weight: f32,
+}
+
+/// What a run did.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParadigmSummary {
+    pub stems: usize,
+    /// Stems skipped because an earlier run already finished them.
+    pub resumed: usize,
+    pub cells: usize,
+    /// The (stem, context) combinations with no surface form.
+    pub empty: Vec<(String, String)>,
+}
+
+/// Write the `top_k` best surface forms of every stem in every context to
+/// `out` as CSV (`stem,context,rank,surface,weight`). With `resume`, stems the
+/// progress file lists are skipped, and the table is cut back to where the
+/// last of them ended and appended to.
+pub fn generate_paradigm(
+    prepared: &PreparedFst,
+    stems: &[String],
+    contexts: &[Context],
+    top_k: usize,
+    out: &Path,
+    resume: bool,
+) -> Result<ParadigmSummary> {
+    let progress = progress_path(out);
+    // Each line is the length of the table once a stem was finished, and the stem.
+    let finished: Vec<(u64, String)> = if resume && out.exists() && progress.exists() {
+        let lines = std::fs::read_to_string(&progress)?;
+        lines
+            .lines()
+            .map(|line| {
+                let (len, stem) = line.split_once('\t').ok_or_else(|| anyhow!("Malformed line '{}' in {}", line, progress.display()))?;
+                Ok((len.parse()?, stem.to_string()))
+            })
+            .collect::<Result<_>>()?
+    } else {
+        Vec::new()
+    };
+    let resume = !finished.is_empty();
+    let open = |path: &Path| OpenOptions::new().create(true).write(true).truncate(!resume).open(path);
+    let mut file = open(out)?;
+    if let Some(&(len, _)) = finished.last() {
+        // Rows written for a stem that was not finished are dropped.
+        file.set_len(len)?;
+        file.seek(SeekFrom::End(0))?;
+    }
+    let done: HashSet<String> = finished.into_iter().map(|(_, stem)| stem).collect();
+    let mut table = csv::WriterBuilder::new().has_headers(!resume).from_writer(file);
+    let mut progress = open(&progress)?;
+    progress.seek(SeekFrom::End(0))?;
+
+    let mut summary = ParadigmSummary::default();
+    for stem in stems.iter() {
+        if done.contains(stem) {
+            summary.resumed += 1;
+            continue;
+        }
+        for context in contexts.iter() {
+            let surfaces = top_surfaces(prepared, &context.apply(stem), top_k)?;
+            if surfaces.is_empty() {
+                summary.empty.push((stem.clone(), context.name.clone()));
+            }
+            for (rank, (weight, surface)) in surfaces.iter().enumerate() {
+                table.serialize(Row { stem, context: &context.name, rank: rank + 1, surface, weight: *weight.value() })?;
+            }
+            summary.cells += 1;
+        }
+        // The stem only counts as done once all of its rows are on disk.
+        table.flush()?;
+        let len = table.get_ref().stream_position()?;
+        writeln!(progress, "{}\t{}", len, stem)?;
+        progress.flush()?;
+        summary.stems += 1;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use rustfst::prelude::union::union;
+    use rustfst::prelude::{Fst, TropicalWeight, VectorFst};
+    use rustfst::utils::transducer;
+
+    use crate::alphabet::SurfaceToAnalysisFst;
+    use crate::analysis::AnalysisFormat;
+    use crate::testutil::TempDir;
+
+    /// Generates `a` from `a`, and `ac` from `bc`, and nothing else.
+    fn prepared() -> PreparedFst {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b", "c"]);
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 1 => 1, 2, 1; 1.0];
+        let other: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 4, 1 => 1, 3, 4, 1; 2.0];
+        union(&mut fst, &other).unwrap();
+        fst.set_input_symbols(symt.clone());
+        fst.set_output_symbols(symt);
+        PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap()
+    }
+
+    fn contexts() -> Vec<Context> {
+        parse_contexts("contexts.csv", "name,prefix,suffix\nbare,,\nbefore c,,c\n").unwrap()
+    }
+
+    #[test]
+    fn test_paradigm_table_and_empty_cells() {
+        let dir = TempDir::new("paradigm-table");
+        let out = dir.join("paradigm.csv");
+        let stems = vec!["a".to_string(), "b".to_string()];
+        let summary = generate_paradigm(&prepared(), &stems, &contexts(), 3, &out, false).unwrap();
+        let table = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(table, "stem,context,rank,surface,weight\na,bare,1,a,1.0\nb,before c,1,ac,2.0\n");
+        assert_eq!((summary.stems, summary.cells), (2, 4));
+        let empty = [("a".to_string(), "before c".to_string()), ("b".to_string(), "bare".to_string())];
+        assert_eq!(summary.empty, empty);
+    }
+
+    #[test]
+    fn test_resume_skips_finished_stems() {
+        let dir = TempDir::new("paradigm-resume");
+        let out = dir.join("paradigm.csv");
+        let stems = vec!["a".to_string(), "b".to_string()];
+        generate_paradigm(&prepared(), &stems[..1], &contexts(), 3, &out, false).unwrap();
+        let summary = generate_paradigm(&prepared(), &stems, &contexts(), 3, &out, true).unwrap();
+        let table = std::fs::read_to_string(&out).unwrap();
+        let progress = std::fs::read_to_string(progress_path(&out)).unwrap();
+        assert_eq!((summary.stems, summary.resumed), (1, 1));
+        assert_eq!(table, "stem,context,rank,surface,weight\na,bare,1,a,1.0\nb,before c,1,ac,2.0\n");
+        assert_eq!(progress, "48\ta\n68\tb\n");
+    }
+
+    #[test]
+    fn test_resume_drops_the_rows_of_an_unfinished_stem() {
+        let dir = TempDir::new("paradigm-resume-partial");
+        let out = dir.join("paradigm.csv");
+        let stems = vec!["a".to_string(), "b".to_string()];
+        generate_paradigm(&prepared(), &stems[..1], &contexts(), 3, &out, false).unwrap();
+        // As if the run had been stopped after writing some of the rows of b.
+        let mut table = OpenOptions::new().append(true).open(&out).unwrap();
+        table.write_all(b"b,bare,1,b,3.0\n").unwrap();
+        generate_paradigm(&prepared(), &stems, &contexts(), 3, &out, true).unwrap();
+        let table = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(table, "stem,context,rank,surface,weight\na,bare,1,a,1.0\nb,before c,1,ac,2.0\n");
+    }
+
+    #[test]
+    fn test_validate_lists_every_bad_string() {
+        let symt = rustfst::symt!["#", "a", "b", "c"];
+        let contexts = parse_contexts("contexts.csv", "name,prefix,suffix\nx,x,\n").unwrap();
+        let err = validate(&symt, &["a".to_string(), "ay".to_string()], &contexts).unwrap_err().to_string();
+        assert!(err.starts_with("2 invalid") && err.contains("stem 'ay'") && err.contains("prefix of context 'x'"), "{}", err);
+    }
+}
@@ -0,0 +1,108 @@
+//! A small scoped worker pool for embarrassingly parallel work (per-file
+//! compilation, per-item checks), and running one piece of work with a time
+//! limit.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// Number of workers to use when none is requested explicitly.
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// Apply `f` to every item on up to `jobs` worker threads, returning the
+/// results in the order of `items`.
+pub fn par_map<T, R, F>(jobs: usize, items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let jobs = jobs.clamp(1, items.len().max(1));
+    if jobs == 1 {
+        return items.iter().map(&f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= items.len() {
+                    break;
+                }
+                let r = f(&items[i]);
+                results.lock().unwrap()[i] = Some(r);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("worker did not produce a result"))
+        .collect()
+}
+
+/// Parse a time limit in (possibly fractional) seconds, as given on the
+/// command line.
+pub fn parse_timeout(s: &str) -> anyhow::Result<Duration> {
+    let secs = s.trim().parse::<f64>().map_err(|e| anyhow::anyhow!("Invalid timeout '{}': {}", s, e))?;
+    if !(secs > 0.0 && secs.is_finite()) {
+        anyhow::bail!("Timeout must be a positive number of seconds, got '{}'", s);
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
+/// Run `f` on a worker thread, waiting at most `timeout` for its result; `None`
+/// if it did not finish in time. FST operations cannot be interrupted, so a
+/// worker that times out is left to finish in the background and its result
+/// is dropped. Without a timeout, `f` runs on the calling thread.
+pub fn with_timeout<R, F>(timeout: Option<Duration>, f: F) -> Option<R>
+where
+    R: Send + 'static,
+    F: FnOnce() -> R + Send + 'static,
+{
+    let Some(timeout) = timeout else {
+        return Some(f());
+    };
+    let (tx, rx) = mpsc::channel();
+    let worker = thread::spawn(move || {
+        // The receiver is gone if the caller stopped waiting.
+        let _ = tx.send(f());
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(r) => Some(r),
+        Err(RecvTimeoutError::Timeout) => None,
+        Err(RecvTimeoutError::Disconnected) => std::panic::resume_unwind(worker.join().unwrap_err()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_par_map_keeps_order() {
+        let items: Vec<usize> = (0..100).collect();
+        let squares = par_map(4, &items, |x| x * x);
+        assert_eq!(squares, items.iter().map(|x| x * x).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_par_map_empty() {
+        let items: Vec<usize> = Vec::new();
+        assert!(par_map(4, &items, |x| *x).is_empty());
+    }
+
+    #[test]
+    fn test_with_timeout_abandons_slow_work() {
+        assert_eq!(with_timeout(None, || 1), Some(1));
+        assert_eq!(with_timeout(Some(Duration::from_secs(10)), || 2), Some(2));
+        let slow = || thread::sleep(Duration::from_secs(2));
+        assert_eq!(with_timeout(Some(Duration::from_millis(10)), slow), None);
+    }
+}
@@ -0,0 +1,107 @@
+//! Where in a word the FST gives up on it (`--explain-no-result`).
+//!
+//! A word with no analysis says nothing of why. Here each prefix of the
+//! wrapped word, one symbol longer than the last, is composed with the FST,
+//! followed by any symbols at all, until one has no path: the longest prefix
+//! that has one is as far as the rules get, and the symbol after it is where
+//! they break down. If every prefix has a path, the rules read the whole word
+//! but no path ends with it.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use rustfst::prelude::{connect, CoreFst, ExpandedFst, Fst, MutableFst, TropicalWeight, VectorFst};
+use rustfst::utils::acceptor;
+use rustfst::{Label, Semiring, SymbolTable, Tr, EPS_LABEL};
+
+use crate::alphabet::{Compose, SurfaceAcceptor, SurfaceToAnalysisFst};
+use crate::automaton::{tokenize, Tokenization};
+use crate::composition::ComposeFilter;
+
+/// How far into a word the FST gets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakdown {
+    /// The symbols of the wrapped word.
+    pub symbols: Vec<String>,
+    /// How many of them, from the start, some path of the FST reads.
+    pub accepted: usize,
+}
+
+impl Breakdown {
+    pub fn describe(&self) -> String {
+        let word = self.symbols.concat();
+        match self.symbols.get(self.accepted) {
+            Some(next) => format!(
+                "the FST reads '{}' of '{}', but no path goes on to read '{}' (symbol {})",
+                self.symbols[..self.accepted].concat(),
+                word,
+                next,
+                self.accepted + 1
+            ),
+            None => format!("the FST reads all of '{}', but no path ends there", word),
+        }
+    }
+}
+
+/// How far into `wrapped` (a word with its boundaries) some path of `fst`
+/// gets, the word split into the symbols of `symt` with `tokenization`.
+pub fn longest_accepted_prefix(fst: &SurfaceToAnalysisFst, symt: &Arc<SymbolTable>, wrapped: &str, tokenization: Tokenization, compose_filter: ComposeFilter) -> Result<Breakdown> {
+    let labels = tokenize(symt, wrapped, tokenization)?;
+    let mut accepted = 0;
+    while accepted < labels.len() && has_path(fst, symt, &labels[..accepted + 1], compose_filter)? {
+        accepted += 1;
+    }
+    let symbols = labels.iter().map(|&l| symt.get_symbol(l).unwrap_or("").to_string()).collect();
+    Ok(Breakdown { symbols, accepted })
+}
+
+/// Whether some path of `fst` reads `prefix`, followed by anything.
+fn has_path(fst: &SurfaceToAnalysisFst, symt: &Arc<SymbolTable>, prefix: &[Label], compose_filter: ComposeFilter) -> Result<bool> {
+    let mut prefix: VectorFst<TropicalWeight> = acceptor(prefix, TropicalWeight::one());
+    // A linear acceptor ends at its last state.
+    let end = (prefix.num_states() - 1) as _;
+    for (label, _) in symt.iter().filter(|&(l, _)| l != EPS_LABEL) {
+        prefix.add_tr(end, Tr::new(label, label, TropicalWeight::one(), end))?;
+    }
+    prefix.set_input_symbols(symt.clone());
+    prefix.set_output_symbols(symt.clone());
+    let mut paths = SurfaceAcceptor(prefix).compose(fst, compose_filter)?;
+    connect(&mut paths.0)?;
+    Ok(paths.start().is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symt() -> Arc<SymbolTable> {
+        Arc::new(rustfst::symt!["#", "a", "b", "ch"])
+    }
+
+    /// An FST whose only path reads `#ab#a`.
+    fn fst() -> SurfaceToAnalysisFst {
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 3, 1, 2];
+        fst.set_input_symbols(symt());
+        fst.set_output_symbols(symt());
+        SurfaceToAnalysisFst(fst)
+    }
+
+    fn breakdown(wrapped: &str) -> Breakdown {
+        longest_accepted_prefix(&fst(), &symt(), wrapped, Tokenization::default(), ComposeFilter::default()).unwrap()
+    }
+
+    #[test]
+    fn test_breakdown_at_the_first_symbol_no_path_reads() {
+        let b = breakdown("#ach#");
+        assert_eq!(b.accepted, 2);
+        assert_eq!(b.describe(), "the FST reads '#a' of '#ach#', but no path goes on to read 'ch' (symbol 3)");
+        assert_eq!(breakdown("ba").accepted, 0);
+    }
+
+    #[test]
+    fn test_whole_word_read_without_a_path_ending_there() {
+        let b = breakdown("#ab#");
+        assert_eq!(b.accepted, 4);
+        assert_eq!(b.describe(), "the FST reads all of '#ab#', but no path ends there");
+    }
+}
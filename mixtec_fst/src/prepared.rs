@@ -0,0 +1,101 @@
+//! An FST together with everything needed to query it repeatedly, prepared once.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use itertools::Either;
+use rustfst::prelude::{tr_sort, Fst, ILabelCompare};
+use rustfst::SymbolTable;
+
+use crate::alphabet::{AnalysisToAnalysisFst, SurfaceToAnalysisFst};
+use crate::analysis::AnalysisFormat;
+use crate::automaton::Tokenization;
+use crate::cancel::CancelToken;
+use crate::composition::ComposeFilter;
+use crate::limits::Limits;
+use crate::ranking::{CandidateRanker, TieBreak};
+use crate::search::{Analyses, Candidate};
+
+/// A segmentation FST sorted for composition, plus the G3-to-base converter used
+/// to constrain outputs when test golds are given as base forms.
+#[derive(Debug, Clone)]
+pub struct PreparedFst {
+    /// The segmentation FST, sorted by input label.
+    pub fst: SurfaceToAnalysisFst,
+    pub symt: Arc<SymbolTable>,
+    /// G3-to-base converter, sorted by input label; `None` when golds are G3.
+    pub g3_to_base: Option<AnalysisToAnalysisFst>,
+    pub fmt: AnalysisFormat,
+    /// How analyses and surfaces of equal weight are ordered.
+    pub tie_break: TieBreak,
+    /// How inputs and outputs are split into symbols.
+    pub tokenization: Tokenization,
+    /// The epsilon filter of the compositions with the FST.
+    pub compose_filter: ComposeFilter,
+    /// How long an input and how hard a search may be before they are given up on.
+    pub limits: Limits,
+    /// Cancels the searches for analyses; Ctrl-C's token unless one is given.
+    pub cancel: CancelToken,
+}
+
+impl PreparedFst {
+    pub fn new(
+        mut fst: SurfaceToAnalysisFst,
+        g3_to_base: Option<AnalysisToAnalysisFst>,
+        fmt: AnalysisFormat,
+    ) -> Result<Self> {
+        let symt = fst
+            .input_symbols()
+            .ok_or_else(|| anyhow!("FST has no input symbol table"))?
+            .clone();
+        fmt.unmark_morph_outputs(&mut fst.0)?;
+        tr_sort(&mut fst.0, ILabelCompare {});
+        let g3_to_base = g3_to_base.map(|mut f| {
+            tr_sort(&mut f.0, ILabelCompare {});
+            f
+        });
+        Ok(PreparedFst { fst, symt, g3_to_base, fmt, tie_break: TieBreak::default(), tokenization: Tokenization::default(), compose_filter: ComposeFilter::default(), limits: Limits::default(), cancel: CancelToken::interrupt() })
+    }
+
+    pub fn with_tie_break(self, tie_break: TieBreak) -> Self {
+        PreparedFst { tie_break, ..self }
+    }
+
+    pub fn with_tokenization(self, tokenization: Tokenization) -> Self {
+        PreparedFst { tokenization, ..self }
+    }
+
+    pub fn with_compose_filter(self, compose_filter: ComposeFilter) -> Self {
+        PreparedFst { compose_filter, ..self }
+    }
+
+    pub fn with_limits(self, limits: Limits) -> Self {
+        PreparedFst { limits, ..self }
+    }
+
+    /// Cancel the searches with `cancel` rather than Ctrl-C's token.
+    #[cfg(test)]
+    pub fn with_cancel(self, cancel: CancelToken) -> Self {
+        PreparedFst { cancel, ..self }
+    }
+
+    /// The ranker for candidates of equal weight.
+    pub fn ranker(&self) -> Box<dyn CandidateRanker> {
+        self.tie_break.ranker(&self.fmt)
+    }
+
+    /// The distinct analyses of `input`, best first, found as they are asked
+    /// for (see [`crate::search`]): the k best are `analyses(input).take(k)`.
+    pub fn analyses(&self, input: &str) -> impl Iterator<Item = Result<Candidate>> + '_ {
+        self.analyses_cancelled_by(input, self.cancel.clone())
+    }
+
+    /// [`PreparedFst::analyses`], cancelled with `cancel` rather than the
+    /// prepared token, for work that is cancelled on its own.
+    pub fn analyses_cancelled_by(&self, input: &str, cancel: CancelToken) -> impl Iterator<Item = Result<Candidate>> + '_ {
+        match Analyses::new(self, input) {
+            Ok(analyses) => Either::Left(analyses.with_cancel(cancel)),
+            Err(e) => Either::Right(std::iter::once(Err(e))),
+        }
+    }
+}
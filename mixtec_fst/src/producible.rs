@@ -0,0 +1,173 @@
+//! The symbols the segmentation FST can ever write. A gold analysis, lexicon
+//! entry or filter that needs any other symbol can never match, whatever the
+//! rules do, which points at the data (a typo'd tone, say) rather than at a
+//! rule.
+//!
+//! `build` records the output labels of the FST it writes in its sidecar
+//! (`output_labels=`), under the hash of its symbol table; FSTs without one
+//! are scanned instead.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use anyhow::Result;
+use rustfst::prelude::{connect, CoreFst, ExpandedFst, MutableFst, StateIterator, TropicalWeight, VectorFst};
+use rustfst::{Label, StateId, SymbolTable, EPS_LABEL};
+
+use crate::alphabet::{AnalysisAcceptor, AnalysisToAnalysisFst};
+use crate::automaton::{tokenize_lenient, Tokenization};
+use crate::cache::symt_hash;
+use crate::provenance::info_path;
+
+/// The labels an FST writes on some transition, epsilon aside.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProducibleLabels(BTreeSet<Label>);
+
+impl ProducibleLabels {
+    pub fn of(fst: &VectorFst<TropicalWeight>) -> Self {
+        let mut labels = BTreeSet::new();
+        for s in fst.states_iter() {
+            // A state of the FST always has its transitions.
+            for tr in fst.get_trs(s).unwrap().iter() {
+                if tr.olabel != EPS_LABEL {
+                    labels.insert(tr.olabel);
+                }
+            }
+        }
+        ProducibleLabels(labels)
+    }
+
+    /// The labels recorded in the sidecar of the FST at `fst_path`, if it has
+    /// them under the hash of `symt`.
+    pub fn from_sidecar(fst_path: &Path, symt: &SymbolTable) -> Option<Self> {
+        let info = std::fs::read_to_string(info_path(fst_path)).ok()?;
+        let hash = format!("{:016x}", symt_hash(symt));
+        if !info.lines().any(|line| line.strip_prefix("symt_hash=") == Some(&hash)) {
+            return None;
+        }
+        let labels = info.lines().find_map(|line| line.strip_prefix("output_labels="))?;
+        labels.split_whitespace().map(|l| l.parse().ok()).collect::<Option<_>>().map(ProducibleLabels)
+    }
+
+    /// The sidecar line recording the labels.
+    pub fn sidecar_line(&self) -> String {
+        let labels: Vec<String> = self.0.iter().map(Label::to_string).collect();
+        format!("output_labels={}\n", labels.join(" "))
+    }
+
+    /// The labels written once the analyses are converted by `g3_to_base`:
+    /// those it writes reading a producible label or nothing.
+    pub fn through(&self, g3_to_base: &AnalysisToAnalysisFst) -> Self {
+        let mut labels = BTreeSet::new();
+        for s in g3_to_base.states_iter() {
+            for tr in g3_to_base.get_trs(s).unwrap().iter() {
+                if tr.olabel != EPS_LABEL && (tr.ilabel == EPS_LABEL || self.0.contains(&tr.ilabel)) {
+                    labels.insert(tr.olabel);
+                }
+            }
+        }
+        ProducibleLabels(labels)
+    }
+
+    pub fn contains(&self, label: Label) -> bool {
+        self.0.contains(&label)
+    }
+
+    /// The symbols of `s` (split with `tokenization`) that are never
+    /// produced, each once, in the order they first come in. Parts of `s`
+    /// that are not symbols at all are left to the checks that read it.
+    pub fn unproducible(&self, symt: &SymbolTable, s: &str, tokenization: Tokenization) -> Vec<String> {
+        let (labels, _) = tokenize_lenient(symt, s, tokenization);
+        let mut missing: Vec<String> = Vec::new();
+        for label in labels.into_iter().filter(|l| !self.contains(*l)) {
+            let symbol = symt.get_symbol(label).unwrap_or("?").to_string();
+            if !missing.contains(&symbol) {
+                missing.push(symbol);
+            }
+        }
+        missing
+    }
+
+    /// Whether `acceptor` accepts anything made of producible symbols only.
+    pub fn accepts_any(&self, acceptor: &AnalysisAcceptor) -> Result<bool> {
+        let mut fst = acceptor.0.clone();
+        for s in 0..fst.num_states() as StateId {
+            let trs = fst.pop_trs(s)?;
+            for tr in trs.into_iter().filter(|tr| tr.olabel == EPS_LABEL || self.contains(tr.olabel)) {
+                fst.add_tr(s, tr)?;
+            }
+        }
+        connect(&mut fst)?;
+        Ok(fst.start().is_some())
+    }
+
+    /// The symbols of `acceptor` that are never produced.
+    pub fn unproducible_in(&self, symt: &SymbolTable, acceptor: &AnalysisAcceptor) -> Vec<String> {
+        let used = ProducibleLabels::of(acceptor);
+        used.0.difference(&self.0).map(|&l| symt.get_symbol(l).unwrap_or("?").to_string()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use rustfst::utils::transducer;
+    use rustfst::Semiring;
+
+    use crate::analysis::AnalysisFormat;
+    use crate::filter::{compile_filter, compile_lexicon};
+    use crate::testutil::TempDir;
+
+    fn symt() -> Arc<SymbolTable> {
+        Arc::new(rustfst::symt!["#", "a", "b", "1", "5"])
+    }
+
+    /// Writes `#`, `a`, `b` and `1`, never `5`.
+    fn producible() -> ProducibleLabels {
+        let fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 3 => 1, 3, 0, 4];
+        ProducibleLabels::of(&fst)
+    }
+
+    #[test]
+    fn test_unproducible_symbols() {
+        let symt = symt();
+        let producible = producible();
+        assert_eq!(producible, ProducibleLabels([1, 3, 4].into_iter().collect()));
+        assert!(producible.unproducible(&symt, "a1", Tokenization::default()).contains(&"a".to_string()));
+        let producible = ProducibleLabels([1, 2, 3, 4].into_iter().collect());
+        assert!(producible.unproducible(&symt, "ab1", Tokenization::default()).is_empty());
+        assert_eq!(producible.unproducible(&symt, "a5b55", Tokenization::default()), ["5"]);
+        // Not a symbol at all, which is for others to report.
+        assert!(producible.unproducible(&symt, "aq", Tokenization::default()).is_empty());
+    }
+
+    #[test]
+    fn test_filters_and_lexicons() {
+        let symt = symt();
+        let producible = ProducibleLabels([1, 2, 3, 4].into_iter().collect());
+        let fmt = AnalysisFormat::default();
+        let lexicon = compile_lexicon(symt.clone(), &["a5".to_string()], &fmt, Tokenization::default()).unwrap();
+        assert!(!producible.accepts_any(&lexicon).unwrap());
+        assert_eq!(producible.unproducible_in(&symt, &lexicon), ["5"]);
+        let lexicon = compile_lexicon(symt.clone(), &["a5".to_string(), "a1".to_string()], &fmt, Tokenization::default()).unwrap();
+        assert!(producible.accepts_any(&lexicon).unwrap());
+        let filter = compile_filter(symt.clone(), "a(5|1)").unwrap();
+        assert!(producible.accepts_any(&filter).unwrap());
+        assert!(!producible.accepts_any(&compile_filter(symt, "a5").unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_sidecar_labels_are_tied_to_the_symbol_table() {
+        let dir = TempDir::new("producible");
+        let fst = dir.join("out.fst");
+        let symt = symt();
+        let producible = producible();
+        let info = format!("num_states=3\nsymt_hash={:016x}\n{}", symt_hash(&symt), producible.sidecar_line());
+        std::fs::write(info_path(&fst), info).unwrap();
+        assert_eq!(ProducibleLabels::from_sidecar(&fst, &symt), Some(producible));
+        let other = rustfst::symt!["#", "a"];
+        assert_eq!(ProducibleLabels::from_sidecar(&fst, &other), None);
+    }
+}
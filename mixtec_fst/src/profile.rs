@@ -0,0 +1,52 @@
+//! Call-tree profiling of builds (`--profile`, with the `profile` feature).
+//!
+//! The major build stages are wrapped in [`profile_span!`] spans, nested as
+//! the calls are: a build is spans for each rule file's compile and union,
+//! the compositions inside them, and the minimizations after. `--profile
+//! <FILE>` writes the time spent in each stack of spans to `FILE` as folded
+//! stacks, which `inferno-flamegraph` (or `flamegraph.pl`) draws:
+//!
+//! ```text
+//! cargo run --release --features profile -- --profile build.folded build ...
+//! inferno-flamegraph < build.folded > build.svg
+//! ```
+//!
+//! Without the feature, spans compile to nothing and the flag is absent.
+
+/// Enter a span named by the arguments (as to `tracing::info_span!`) until
+/// the returned guard is dropped.
+#[cfg(feature = "profile")]
+macro_rules! profile_span {
+    ($($arg:tt)*) => {
+        tracing::info_span!($($arg)*).entered()
+    };
+}
+
+#[cfg(not(feature = "profile"))]
+macro_rules! profile_span {
+    ($($arg:tt)*) => {
+        $crate::profile::NoSpan
+    };
+}
+
+pub(crate) use profile_span;
+
+/// What [`profile_span!`] returns without the `profile` feature.
+#[cfg(not(feature = "profile"))]
+pub struct NoSpan;
+
+/// Record spans from here on as folded stacks in the file at `path`, until
+/// the returned guard is dropped and flushes them.
+#[cfg(feature = "profile")]
+pub fn start(path: &std::path::Path) -> anyhow::Result<tracing_flame::FlushGuard<std::io::BufWriter<std::fs::File>>> {
+    use anyhow::Context;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let (layer, guard) =
+        tracing_flame::FlameLayer::with_file(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    // Not `SubscriberInitExt::init`, which would also claim the `log`
+    // facade that env_logger already has.
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))
+        .context("Failed to install the profiler")?;
+    Ok(guard)
+}
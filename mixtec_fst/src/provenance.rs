@@ -0,0 +1,233 @@
+//! Where a built FST came from.
+//!
+//! `build` records in the `.info` sidecar of its output the rule files it read,
+//! each with a hash of its contents (with the files it includes spliced in),
+//! when it ran, the version of this crate and the build options that change
+//! what gets built (the variant). `test` reads the sidecar of the FST it
+//! checks and puts all of that at the head of its summary and in its JSON
+//! report, so that reports from several artifacts can be told apart.
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::cache::content_hash;
+use crate::normalizer::Normalization;
+use crate::rule_config::config_path;
+use crate::rules::read_script_source;
+
+/// A rule file read by a build, with the hash of its contents at the time.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct RuleFileHash {
+    pub path: String,
+    pub hash: String,
+}
+
+/// The provenance of a build. Sidecars written before it was recorded leave
+/// every field empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct Provenance {
+    pub rule_files: Vec<RuleFileHash>,
+    /// When the build ran, in UTC.
+    pub built_at: Option<String>,
+    /// Version of the crate that ran the build.
+    pub version: Option<String>,
+    /// The build flags that change what gets built; empty for the defaults.
+    pub variant: Option<String>,
+    /// Hash of the symbol table of the built FST (see [`crate::cache::symt_hash`]), by which
+    /// FSTs loaded together tell that they can share one table.
+    pub symt_hash: Option<String>,
+    /// Whether the FST normalizes its own inputs (see [`crate::normalizer`]).
+    pub normalization: Option<Normalization>,
+}
+
+/// The build info sidecar of the FST at `fst_path`.
+pub fn info_path(fst_path: &Path) -> PathBuf {
+    let mut path = fst_path.as_os_str().to_owned();
+    path.push(".info");
+    PathBuf::from(path)
+}
+
+impl Provenance {
+    /// The provenance of a build of `files` running now, with the flags `variant`.
+    pub fn of_build(files: &[PathBuf], variant: &str) -> Result<Self> {
+        let hash = |f: &Path, contents: &[u8]| RuleFileHash { path: f.display().to_string(), hash: format!("{:016x}", content_hash(contents)) };
+        // A file it includes changes what a rule file builds as much as the
+        // file itself, and so do its settings.
+        let mut rule_files = files.iter().map(|f| Ok(hash(f, read_script_source(f)?.as_bytes()))).collect::<Result<Vec<_>>>()?;
+        for settings in files.iter().map(|f| config_path(f)).filter(|c| c.is_file()) {
+            let contents = std::fs::read(&settings).with_context(|| format!("Failed to read {}", settings.display()))?;
+            rule_files.push(hash(&settings, &contents));
+        }
+        Ok(Provenance {
+            rule_files,
+            built_at: Some(utc_now()),
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            variant: Some(variant.to_string()),
+            symt_hash: None,
+            normalization: None,
+        })
+    }
+
+    /// The sidecar lines recording this provenance, one `rule_file` line per file.
+    pub fn sidecar_lines(&self) -> String {
+        let mut lines = String::new();
+        for (key, value) in [("version", &self.version), ("built_at", &self.built_at), ("variant", &self.variant), ("symt_hash", &self.symt_hash)] {
+            if let Some(value) = value {
+                lines.push_str(&format!("{}={}\n", key, value));
+            }
+        }
+        if let Some(normalization) = self.normalization {
+            lines.push_str(&format!("normalization={}\n", normalization.name()));
+        }
+        for file in self.rule_files.iter() {
+            lines.push_str(&format!("rule_file={} {}\n", file.hash, file.path));
+        }
+        lines
+    }
+
+    /// Read the provenance back from the contents of a sidecar, ignoring its
+    /// other lines.
+    pub fn parse(info: &str) -> Self {
+        let mut provenance = Provenance::default();
+        for line in info.lines() {
+            let Some((key, value)) = line.split_once('=') else { continue };
+            match key {
+                "version" => provenance.version = Some(value.to_string()),
+                "built_at" => provenance.built_at = Some(value.to_string()),
+                "variant" => provenance.variant = Some(value.to_string()),
+                "symt_hash" => provenance.symt_hash = Some(value.to_string()),
+                "normalization" => provenance.normalization = Normalization::parse(value),
+                "rule_file" => {
+                    if let Some((hash, path)) = value.split_once(' ') {
+                        provenance.rule_files.push(RuleFileHash { path: path.to_string(), hash: hash.to_string() });
+                    }
+                }
+                _ => {}
+            }
+        }
+        provenance
+    }
+}
+
+/// The provenance recorded next to the FST at `fst_path`, or `None` if it has
+/// no sidecar.
+pub fn read_provenance(fst_path: &Path) -> Result<Option<Provenance>> {
+    let path = info_path(fst_path);
+    match std::fs::read_to_string(&path) {
+        Ok(info) => Ok(Some(Provenance::parse(&info))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// The head of a test summary: the FST checked, the run's tag and the
+/// provenance of the FST, or a note that it has none.
+pub fn summary_header(fst_path: &str, tag: Option<&str>, provenance: Option<&Provenance>) -> String {
+    let mut header = match tag {
+        Some(tag) => format!("FST {} [{}]\n", fst_path, tag),
+        None => format!("FST {}\n", fst_path),
+    };
+    let Some(provenance) = provenance else {
+        header.push_str(&format!("  no build info sidecar ({}), so its provenance is unknown\n", info_path(Path::new(fst_path)).display()));
+        return header;
+    };
+    let unknown = |field: &Option<String>| field.clone().unwrap_or_else(|| "unknown".to_string());
+    let variant = match provenance.variant.as_deref() {
+        Some("") => "default options".to_string(),
+        variant => unknown(&variant.map(String::from)),
+    };
+    header.push_str(&format!(
+        "  built {} by mixtec_fst {}, variant: {}\n",
+        unknown(&provenance.built_at),
+        unknown(&provenance.version),
+        variant
+    ));
+    if provenance.rule_files.is_empty() {
+        header.push_str("  rule files: not recorded\n");
+    }
+    for file in provenance.rule_files.iter() {
+        header.push_str(&format!("  rule file {} ({})\n", file.path, file.hash));
+    }
+    header
+}
+
+/// The current time as an RFC 3339 UTC timestamp.
+pub fn utc_now() -> String {
+    utc_timestamp(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()))
+}
+
+/// `secs` since the Unix epoch as an RFC 3339 UTC timestamp.
+fn utc_timestamp(secs: u64) -> String {
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01, after Howard Hinnant's algorithm.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_utc_timestamp() {
+        assert_eq!(utc_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(utc_timestamp(951782400 + 3661), "2000-02-29T01:01:01Z");
+        assert_eq!(utc_timestamp(1791849600), "2026-10-13T00:00:00Z");
+    }
+
+    #[test]
+    fn test_sidecar_round_trip() {
+        let dir = TempDir::new("provenance");
+        let file = dir.join("special rules.txt");
+        std::fs::write(&file, "a -> b / _ \n").unwrap();
+        let provenance = Provenance { symt_hash: Some("00ff".to_string()), normalization: Some(Normalization::Embedded), ..Provenance::of_build(std::slice::from_ref(&file), "--application simultaneous").unwrap() };
+        assert_eq!(provenance.rule_files[0].hash, format!("{:016x}", content_hash(b"a -> b / _ \n")));
+        let info = format!("num_states=3\n{}relabel=1:2\n", provenance.sidecar_lines());
+        assert_eq!(Provenance::parse(&info), provenance);
+
+        let fst = dir.join("out.fst");
+        assert_eq!(read_provenance(&fst).unwrap(), None);
+        std::fs::write(info_path(&fst), "num_states=3\n").unwrap();
+        assert_eq!(read_provenance(&fst).unwrap(), Some(Provenance::default()));
+    }
+
+    #[test]
+    fn test_rule_file_hash_covers_included_files() {
+        let dir = TempDir::new("provenance-include");
+        let file = dir.join("rules.txt");
+        std::fs::write(&file, "@include \"shared.txt\"\n").unwrap();
+        std::fs::write(dir.join("shared.txt"), "a -> b / _ \n").unwrap();
+        let before = Provenance::of_build(std::slice::from_ref(&file), "").unwrap();
+        std::fs::write(dir.join("shared.txt"), "a -> c / _ \n").unwrap();
+        let after = Provenance::of_build(std::slice::from_ref(&file), "").unwrap();
+        assert_ne!(before.rule_files, after.rule_files);
+    }
+
+    #[test]
+    fn test_summary_header() {
+        let provenance = Provenance::parse("version=0.1.0\nbuilt_at=2026-10-13T00:00:00Z\nvariant=\nrule_file=00ff a.txt\n");
+        assert_eq!(
+            summary_header("out.fst", Some("experiment-17"), Some(&provenance)),
+            "FST out.fst [experiment-17]\n  built 2026-10-13T00:00:00Z by mixtec_fst 0.1.0, variant: default options\n  rule file a.txt (00ff)\n"
+        );
+        assert_eq!(
+            summary_header("out.fst", None, Some(&Provenance::default())),
+            "FST out.fst\n  built unknown by mixtec_fst unknown, variant: unknown\n  rule files: not recorded\n"
+        );
+        assert_eq!(
+            summary_header("out.fst", None, None),
+            "FST out.fst\n  no build info sidecar (out.fst.info), so its provenance is unknown\n"
+        );
+    }
+}
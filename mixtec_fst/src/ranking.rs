@@ -0,0 +1,116 @@
+//! Breaking ties between candidates of equal weight.
+//!
+//! Rules often give several analyses of a word the same weight, and without a
+//! language model there is nothing in the FST to choose between them. A
+//! [`CandidateRanker`] orders such candidates; the default chain prefers the
+//! analysis with fewer tone processes (`##`-separated annotations), then the one
+//! with fewer `>` operators in all, then the first in lexicographic order.
+
+use std::cmp::Ordering;
+
+use crate::analysis::AnalysisFormat;
+
+/// An order on candidates of equal weight: `Less` when `a` is preferred.
+///
+/// Candidates are compared as decoded, so analyses may still carry their word
+/// boundaries or source annotations.
+pub trait CandidateRanker: Send + Sync {
+    fn compare(&self, a: &str, b: &str) -> Ordering;
+}
+
+/// Prefers fewer process annotations, counted as occurrences of the separator.
+pub struct FewerProcesses {
+    pub separator: String,
+}
+
+impl CandidateRanker for FewerProcesses {
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        let processes = |s: &str| s.matches(self.separator.as_str()).count();
+        processes(a).cmp(&processes(b))
+    }
+}
+
+/// Prefers fewer `>` operators across all processes.
+pub struct FewerOperators;
+
+impl CandidateRanker for FewerOperators {
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        let operators = |s: &str| s.matches('>').count();
+        operators(a).cmp(&operators(b))
+    }
+}
+
+/// Prefers the first candidate in lexicographic order.
+pub struct Lexicographic;
+
+impl CandidateRanker for Lexicographic {
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Ranks by each ranker in turn, moving on to the next only on a tie.
+pub struct RankerChain(pub Vec<Box<dyn CandidateRanker>>);
+
+impl CandidateRanker for RankerChain {
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        self.0.iter().map(|r| r.compare(a, b)).find(|o| o.is_ne()).unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Leaves ties unbroken, so equal-weight candidates keep the order the search
+/// found them in.
+pub struct Unranked;
+
+impl CandidateRanker for Unranked {
+    fn compare(&self, _: &str, _: &str) -> Ordering {
+        Ordering::Equal
+    }
+}
+
+/// How to order candidates of equal weight (`--tie-break`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TieBreak {
+    /// No tie-breaking: the first candidate the search finds wins
+    Simple,
+    /// Fewer processes, then fewer `>` operators, then lexicographic order
+    #[default]
+    Processes,
+    /// Lexicographic order of the candidates
+    Lexicographic,
+}
+
+impl TieBreak {
+    /// The ranker for this mode, counting processes by the separator of `fmt`.
+    pub fn ranker(self, fmt: &AnalysisFormat) -> Box<dyn CandidateRanker> {
+        match self {
+            TieBreak::Simple => Box::new(Unranked),
+            TieBreak::Processes => Box::new(RankerChain(vec![
+                Box::new(FewerProcesses { separator: fmt.separator.clone() }),
+                Box::new(FewerOperators),
+                Box::new(Lexicographic),
+            ])),
+            TieBreak::Lexicographic => Box::new(Lexicographic),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tie_break_modes() {
+        let fmt = AnalysisFormat::default();
+        // Two processes, sorted first, against one process with more operators.
+        let (two, one) = ("#a1##14>3##3>1#", "#a3##3>4>5>1#");
+        let rank = |mode: TieBreak, a: &str, b: &str| mode.ranker(&fmt).compare(a, b);
+        assert_eq!(rank(TieBreak::Processes, two, one), Ordering::Greater);
+        assert_eq!(rank(TieBreak::Lexicographic, two, one), Ordering::Less);
+        assert_eq!(rank(TieBreak::Simple, two, one), Ordering::Equal);
+        // The same number of processes: fewer operators wins over the order.
+        assert_eq!(rank(TieBreak::Processes, "#a3##3>1#", "#a1##3>4>1#"), Ordering::Less);
+        assert_eq!(rank(TieBreak::Processes, "#a3##3>1#", "#a1##4>1#"), Ordering::Greater);
+        assert_eq!(rank(TieBreak::Lexicographic, "#a3##3>1#", "#a1##3>4>1#"), Ordering::Greater);
+    }
+}
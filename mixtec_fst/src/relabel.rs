@@ -0,0 +1,129 @@
+//! Renumbering labels by how often they occur (`build --relabel-by-frequency`).
+//!
+//! Labels follow the order of `chars.txt`, which has nothing to do with how
+//! often each symbol is used. Giving the most frequent symbols the smallest
+//! labels keeps sorted transitions that are looked up most often near the
+//! start of each state's list. Epsilon stays 0 and the word boundary keeps its
+//! label, so that tools that hardcode either still work. The symbol table is
+//! renumbered along with the transitions, so anything that goes through the
+//! symbol table sees the same FST.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use rustfst::prelude::{Fst, MutableFst, StateIterator, TropicalWeight, VectorFst};
+use rustfst::{Label, SymbolTable, EPS_LABEL};
+
+use crate::analysis::DEFAULT_BOUNDARY;
+use crate::build::label_arc_counts;
+
+/// A renumbering of the labels of a symbol table, as `(old, new)` pairs for the
+/// labels that move.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relabeling {
+    pub moves: Vec<(Label, Label)>,
+    pub symt: Arc<SymbolTable>,
+}
+
+impl Relabeling {
+    /// The permutation as `old:new` pairs, for the build info sidecar.
+    pub fn describe(&self) -> String {
+        self.moves.iter().map(|(old, new)| format!("{}:{}", old, new)).collect::<Vec<_>>().join(" ")
+    }
+}
+
+/// The renumbering of `symt` that orders its labels by descending frequency in
+/// `fst` (ties by the old order), leaving epsilon and the boundary in place.
+pub fn frequency_relabeling(fst: &VectorFst<TropicalWeight>, symt: &SymbolTable) -> Result<Relabeling> {
+    let boundary = symt.get_label(DEFAULT_BOUNDARY).ok_or_else(|| anyhow!("Symbol table has no word boundary"))?;
+    // How many transitions use each label, on either side.
+    let counts: HashMap<Label, usize> = label_arc_counts(fst)?.into_iter().map(|(l, (i, o))| (l, i + o)).collect();
+    let fixed = |l: Label| l == EPS_LABEL || l == boundary;
+    let mut by_frequency: Vec<Label> = symt.iter().map(|(l, _)| l).filter(|&l| !fixed(l)).collect();
+    let slots = by_frequency.clone();
+    by_frequency.sort_by_key(|l| (std::cmp::Reverse(counts.get(l).copied().unwrap_or(0)), *l));
+    let mapping: HashMap<Label, Label> = by_frequency.into_iter().zip(slots).collect();
+
+    let new_label = |l: Label| mapping.get(&l).copied().unwrap_or(l);
+    let mut symbols: Vec<(Label, &str)> = symt.iter().map(|(l, s)| (new_label(l), s)).collect();
+    symbols.sort();
+    let mut relabelled = SymbolTable::empty();
+    for (l, s) in symbols {
+        let added = relabelled.add_symbol(s);
+        debug_assert_eq!(added, l);
+    }
+    let mut moves: Vec<(Label, Label)> = mapping.into_iter().filter(|(old, new)| old != new).collect();
+    moves.sort();
+    Ok(Relabeling { moves, symt: Arc::new(relabelled) })
+}
+
+/// Renumber the transitions of `fst` and install the renumbered symbol table.
+/// Labels outside the symbol table (source markers) are left alone.
+pub fn apply_relabeling(fst: &mut VectorFst<TropicalWeight>, relabeling: &Relabeling) -> Result<()> {
+    let mapping: HashMap<Label, Label> = relabeling.moves.iter().copied().collect();
+    let new_label = |l: Label| mapping.get(&l).copied().unwrap_or(l);
+    let states: Vec<_> = fst.states_iter().collect();
+    for s in states {
+        for mut tr in fst.pop_trs(s)? {
+            tr.ilabel = new_label(tr.ilabel);
+            tr.olabel = new_label(tr.olabel);
+            fst.add_tr(s, tr)?;
+        }
+    }
+    fst.set_input_symbols(relabeling.symt.clone());
+    fst.set_output_symbols(relabeling.symt.clone());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustfst::prelude::union::union;
+    use rustfst::utils::transducer;
+    use rustfst::Semiring;
+
+    use crate::alphabet::SurfaceToAnalysisFst;
+    use crate::analysis::AnalysisFormat;
+    use crate::check::{accepts_pair, best_analysis};
+    use crate::prepared::PreparedFst;
+
+    /// `#cab#` -> `#cc#` and `#cb#` -> `#c#`, over a table in which `c` is last.
+    fn fst() -> VectorFst<TropicalWeight> {
+        let symt = Arc::new(rustfst::symt!["a", "b", "#", "c"]);
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![3, 4, 1, 2, 3 => 3, 4, 4, 3; 1.0];
+        let other: VectorFst<TropicalWeight> = rustfst::fst![3, 4, 2, 3 => 3, 4, 3; 2.0];
+        union(&mut fst, &other).unwrap();
+        fst.set_input_symbols(symt.clone());
+        fst.set_output_symbols(symt);
+        fst
+    }
+
+    #[test]
+    fn test_frequent_labels_come_first() {
+        let fst = fst();
+        let relabeling = frequency_relabeling(&fst, fst.input_symbols().unwrap()).unwrap();
+        let symt = &relabeling.symt;
+        assert_eq!(symt.get_label("#"), Some(3));
+        assert_eq!(symt.get_label("c"), Some(1));
+        assert_eq!(symt.get_label("b"), Some(2));
+        assert_eq!(symt.get_label("a"), Some(4));
+        assert_eq!(relabeling.describe(), "1:4 4:1");
+    }
+
+    #[test]
+    fn test_relabelled_fst_answers_the_same() {
+        let before = fst();
+        let mut after = fst();
+        let relabeling = frequency_relabeling(&after, after.input_symbols().unwrap()).unwrap();
+        apply_relabeling(&mut after, &relabeling).unwrap();
+        let before = PreparedFst::new(SurfaceToAnalysisFst(before), None, AnalysisFormat::default()).unwrap();
+        let after = PreparedFst::new(SurfaceToAnalysisFst(after), None, AnalysisFormat::default()).unwrap();
+        for input in ["cab", "cb", "ab"] {
+            let best = |p: &PreparedFst| best_analysis(p, input).unwrap().map(|(w, a)| (*w.value(), a));
+            assert_eq!(best(&before), best(&after), "{}", input);
+        }
+        assert!(accepts_pair(&after, "cab", "cc").unwrap());
+        assert!(!accepts_pair(&after, "cab", "c").unwrap());
+    }
+}
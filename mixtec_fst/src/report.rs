@@ -0,0 +1,464 @@
+//! Outcomes of checking gold items, with known failures kept apart.
+//!
+//! A gold item marked `xfail` documents a case the rules cannot handle yet. It
+//! is still checked, but its failure is expected and does not count against
+//! the run; if it passes, it is flagged so that the mark can be removed.
+//! Items whose check ran out of time (`--timeout`) are neither, and are kept
+//! apart as well, as are items whose gold form has symbols the FST never
+//! outputs: those are errors in the data, which no rule can fix, and are not
+//! checked at all. So are items given up on for the limits (see
+//! [`crate::limits`]): inputs too long to check, and checks too complex to
+//! finish.
+//!
+//! With `--partial-credit`, each item checked also gets a [`Score`] of how
+//! close its best prediction came to the gold form, and the report their
+//! means. With `--report-html`, each failure also keeps its best prediction,
+//! with its weight and margin, for the HTML report (see [`crate::html`]).
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::align::Score;
+use crate::artifact::create_atomic;
+use crate::limits::LimitError;
+use crate::provenance::Provenance;
+use crate::status::internal;
+use crate::style::Style;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Pass,
+    Fail,
+    /// A failure of an item marked `xfail`.
+    XFail,
+    /// A pass of an item marked `xfail`.
+    XPass,
+    /// The check did not finish within the time limit.
+    Timeout,
+    /// The gold form has symbols the FST never outputs, so it was not checked.
+    Unproducible,
+    /// The input is longer than `--max-input-len`, so it was not checked.
+    #[serde(rename = "too_long")]
+    TooLong,
+    /// The check expanded the most paths `--max-expansions` allows.
+    #[serde(rename = "too_complex")]
+    TooComplex,
+}
+
+impl Outcome {
+    pub fn of(passed: bool, xfail: bool) -> Self {
+        match (passed, xfail) {
+            (true, false) => Outcome::Pass,
+            (false, false) => Outcome::Fail,
+            (false, true) => Outcome::XFail,
+            (true, true) => Outcome::XPass,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Outcome::Pass => "OK",
+            Outcome::Fail => "FAILED",
+            Outcome::XFail => "FAILED (expected)",
+            Outcome::XPass => "UNEXPECTEDLY PASSING (remove its xfail mark)",
+            Outcome::Timeout => "TIMED OUT",
+            Outcome::Unproducible => "DATA ERROR (gold has unproducible symbols)",
+            Outcome::TooLong => "NOT CHECKED (input too long)",
+            Outcome::TooComplex => "GAVE UP (analysis too complex)",
+        }
+    }
+
+    /// How a line reporting the outcome is shown on a terminal: failures in
+    /// red, and unexpected passes hard to miss.
+    pub fn style(self) -> Style {
+        match self {
+            Outcome::Pass => Style::Plain,
+            Outcome::Fail => Style::Error,
+            Outcome::XFail | Outcome::Timeout | Outcome::Unproducible | Outcome::TooLong | Outcome::TooComplex => Style::Warning,
+            Outcome::XPass => Style::Alert,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ItemResult {
+    pub input: String,
+    pub form: String,
+    pub outcome: Outcome,
+    /// The symbols of the gold form the FST never outputs.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unproducible: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<Score>,
+    /// The best prediction, if it was asked for: null if there is none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prediction: Option<Option<Prediction>>,
+}
+
+/// The best analysis of an item, in the notation of its gold form.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Prediction {
+    pub form: String,
+    pub weight: f32,
+    /// How much better the best analysis is than the next; none if it is the
+    /// only one.
+    pub margin: Option<f32>,
+}
+
+/// The means of the scores of the items scored.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct MeanScore {
+    pub items: usize,
+    pub cer: f64,
+    pub tone_cer: f64,
+    pub segment_cer: f64,
+    pub f1: f64,
+}
+
+impl MeanScore {
+    fn add(&mut self, score: &Score) {
+        self.items += 1;
+        let n = self.items as f64;
+        for (mean, x) in [(&mut self.cer, score.cer), (&mut self.tone_cer, score.tone_cer), (&mut self.segment_cer, score.segment_cer), (&mut self.f1, score.f1)] {
+            *mean += (x - *mean) / n;
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "mean CER {:.3} ({:.3} tones, {:.3} segments), mean symbol F1 {:.3} over {} items",
+            self.cer, self.tone_cer, self.segment_cer, self.f1, self.items
+        )
+    }
+}
+
+/// Outcomes of one direction of a test run.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct TestReport {
+    pub passed: usize,
+    pub failed: usize,
+    pub xfail: usize,
+    pub xpass: usize,
+    pub timeout: usize,
+    pub unproducible: usize,
+    pub too_long: usize,
+    pub too_complex: usize,
+    /// The means of the item scores, if the items were scored.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partial_credit: Option<MeanScore>,
+    /// Every item checked; with [`TestReport::counts_only`], only the items
+    /// listed after a run (unexpected passes, timeouts, data errors and the
+    /// items given up on for the limits).
+    pub items: Vec<ItemResult>,
+    #[serde(skip)]
+    counts_only: bool,
+}
+
+impl TestReport {
+    /// A report that does not keep the items that are only counted, so that it
+    /// stays small however many items are checked.
+    pub fn counts_only() -> Self {
+        TestReport { counts_only: true, ..Default::default() }
+    }
+
+    pub fn record(&mut self, input: &str, form: &str, xfail: bool, passed: bool) -> Outcome {
+        self.record_outcome(input, form, Outcome::of(passed, xfail), None, None)
+    }
+
+    /// Record a checked item with the score of its best prediction and the
+    /// prediction itself, either if there is one.
+    pub fn record_checked(&mut self, input: &str, form: &str, xfail: bool, passed: bool, score: Option<Score>, prediction: Option<Option<Prediction>>) -> Outcome {
+        if let Some(score) = &score {
+            self.partial_credit.get_or_insert_default().add(score);
+        }
+        self.record_outcome(input, form, Outcome::of(passed, xfail), score, prediction)
+    }
+
+    /// Record an item whose check was abandoned, whether or not it is marked
+    /// `xfail`.
+    pub fn record_timeout(&mut self, input: &str, form: &str) -> Outcome {
+        self.record_outcome(input, form, Outcome::Timeout, None, None)
+    }
+
+    /// Record an item given up on for `limit`, whether or not it is marked
+    /// `xfail`.
+    pub fn record_limit(&mut self, input: &str, form: &str, limit: LimitError) -> Outcome {
+        let outcome = match limit {
+            LimitError::TooLong { .. } => Outcome::TooLong,
+            LimitError::TooComplex { .. } => Outcome::TooComplex,
+        };
+        self.record_outcome(input, form, outcome, None, None)
+    }
+
+    /// Record an item left unchecked because its gold form has the
+    /// `unproducible` symbols, whether or not it is marked `xfail`.
+    pub fn record_unproducible(&mut self, input: &str, form: &str, unproducible: Vec<String>) -> Outcome {
+        self.unproducible += 1;
+        self.items.push(ItemResult { input: input.to_string(), form: form.to_string(), outcome: Outcome::Unproducible, unproducible, score: None, prediction: None });
+        Outcome::Unproducible
+    }
+
+    fn record_outcome(&mut self, input: &str, form: &str, outcome: Outcome, score: Option<Score>, prediction: Option<Option<Prediction>>) -> Outcome {
+        match outcome {
+            Outcome::Pass => self.passed += 1,
+            Outcome::Fail => self.failed += 1,
+            Outcome::XFail => self.xfail += 1,
+            Outcome::XPass => self.xpass += 1,
+            Outcome::Timeout => self.timeout += 1,
+            Outcome::Unproducible => self.unproducible += 1,
+            Outcome::TooLong => self.too_long += 1,
+            Outcome::TooComplex => self.too_complex += 1,
+        }
+        if !self.counts_only || !matches!(outcome, Outcome::Pass | Outcome::Fail | Outcome::XFail) {
+            self.items.push(ItemResult { input: input.to_string(), form: form.to_string(), outcome, unproducible: Vec::new(), score, prediction });
+        }
+        outcome
+    }
+
+    /// The items checked, which leaves out the data errors and the inputs
+    /// too long to check.
+    pub fn total(&self) -> usize {
+        self.passed + self.failed + self.xfail + self.xpass + self.timeout + self.too_complex
+    }
+
+    /// Share of the items checked that passed, whether or not they were
+    /// marked `xfail`.
+    pub fn accuracy(&self) -> f64 {
+        100.0 * (self.passed + self.xpass) as f64 / self.total().max(1) as f64
+    }
+
+    pub fn summary(&self) -> String {
+        let mut summary = format!("{}/{} passed ({:.1}%)", self.passed + self.xpass, self.total(), self.accuracy());
+        if self.xfail > 0 || self.xpass > 0 {
+            summary.push_str(&format!(
+                "; {} failed, {} expected failures, {} unexpectedly passing",
+                self.failed, self.xfail, self.xpass
+            ));
+        }
+        if self.timeout > 0 {
+            summary.push_str(&format!("; {} timed out", self.timeout));
+        }
+        if self.too_complex > 0 {
+            summary.push_str(&format!("; {} too complex to finish", self.too_complex));
+        }
+        if self.unproducible > 0 {
+            summary.push_str(&format!("; {} not checked, their gold forms having unproducible symbols", self.unproducible));
+        }
+        if self.too_long > 0 {
+            summary.push_str(&format!("; {} not checked, their inputs being too long", self.too_long));
+        }
+        summary
+    }
+
+    /// How the summary is shown on a terminal: in red if anything failed
+    /// unexpectedly, timed out or was too complex, and in yellow if only the
+    /// data has errors.
+    pub fn style(&self) -> Style {
+        match (self.failed + self.xpass + self.timeout + self.too_complex, self.unproducible + self.too_long) {
+            (0, 0) => Style::Success,
+            (0, _) => Style::Warning,
+            _ => Style::Error,
+        }
+    }
+
+    /// Items that passed despite being marked `xfail`.
+    pub fn xpasses(&self) -> impl Iterator<Item = &ItemResult> {
+        self.items.iter().filter(|r| r.outcome == Outcome::XPass)
+    }
+
+    /// Items whose check ran out of time.
+    pub fn timeouts(&self) -> impl Iterator<Item = &ItemResult> {
+        self.items.iter().filter(|r| r.outcome == Outcome::Timeout)
+    }
+
+    /// Items given up on for the limits.
+    pub fn given_up(&self) -> impl Iterator<Item = &ItemResult> {
+        self.items.iter().filter(|r| matches!(r.outcome, Outcome::TooLong | Outcome::TooComplex))
+    }
+
+    /// Items whose gold form has unproducible symbols.
+    pub fn unproducibles(&self) -> impl Iterator<Item = &ItemResult> {
+        self.items.iter().filter(|r| r.outcome == Outcome::Unproducible)
+    }
+}
+
+/// What a test run checked: the FST, the provenance recorded next to it (null
+/// if it has no build info sidecar) and the tag given to the run.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct RunInfo {
+    pub fst: String,
+    pub tag: Option<String>,
+    pub provenance: Option<Provenance>,
+}
+
+/// Write the run, its forward report and the reverse one if generation was
+/// also checked, to `path` as JSON.
+pub fn write_json_report(path: &Path, run: &RunInfo, forward: &TestReport, reverse: Option<&TestReport>) -> Result<()> {
+    #[derive(serde::Serialize)]
+    struct Report<'a> {
+        run: &'a RunInfo,
+        forward: &'a TestReport,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reverse: Option<&'a TestReport>,
+    }
+    create_atomic(path, |file| internal(serde_json::to_writer_pretty(file, &Report { run, forward, reverse })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_xfail_item_that_starts_passing_is_an_xpass() {
+        let mut before = TestReport::default();
+        assert_eq!(before.record("ni14-", "ni{1>14}-", true, false), Outcome::XFail);
+        assert_eq!(before.record("i4in4", "i4in4", false, true), Outcome::Pass);
+        assert_eq!((before.passed, before.failed, before.xfail, before.xpass), (1, 0, 1, 0));
+        assert_eq!(before.xpasses().count(), 0);
+
+        // A rule change fixes the known failure.
+        let mut after = TestReport::default();
+        assert_eq!(after.record("ni14-", "ni{1>14}-", true, true), Outcome::XPass);
+        assert_eq!(after.record("i4in4", "i4in4", false, false), Outcome::Fail);
+        assert_eq!((after.passed, after.failed, after.xfail, after.xpass), (0, 1, 0, 1));
+        let xpasses: Vec<_> = after.xpasses().map(|r| r.input.as_str()).collect();
+        assert_eq!(xpasses, ["ni14-"]);
+        assert_eq!(after.summary(), "1/2 passed (50.0%); 1 failed, 0 expected failures, 1 unexpectedly passing");
+    }
+
+    #[test]
+    fn test_timeouts_are_counted_apart_from_failures() {
+        let mut report = TestReport::default();
+        report.record("a", "b", false, false);
+        assert_eq!(report.record_timeout("c", "d"), Outcome::Timeout);
+        assert_eq!(report.record_timeout("e", "f"), Outcome::Timeout);
+        assert_eq!((report.failed, report.timeout), (1, 2));
+        assert_eq!(report.timeouts().map(|r| r.input.as_str()).collect::<Vec<_>>(), ["c", "e"]);
+        assert_eq!(report.summary(), "0/3 passed (0.0%); 2 timed out");
+    }
+
+    #[test]
+    fn test_counts_only_keeps_listed_items() {
+        let mut report = TestReport::counts_only();
+        for i in 0..1000 {
+            report.record(&i.to_string(), "a", i % 10 == 0, i % 2 == 0);
+        }
+        report.record_timeout("t", "a");
+        assert_eq!((report.passed, report.failed, report.xfail, report.xpass, report.timeout), (400, 500, 0, 100, 1));
+        assert_eq!(report.items.len(), 101);
+        assert_eq!(report.xpasses().count(), 100);
+        assert_eq!(report.timeouts().map(|r| r.input.as_str()).collect::<Vec<_>>(), ["t"]);
+        assert_eq!(report.summary(), "500/1001 passed (50.0%); 500 failed, 0 expected failures, 100 unexpectedly passing; 1 timed out");
+    }
+
+    #[test]
+    fn test_unproducible_gold_is_a_data_error() {
+        let mut report = TestReport::counts_only();
+        report.record("a", "b", false, true);
+        report.record("c", "d", false, false);
+        let outcome = report.record_unproducible("e", "f5", vec!["5".to_string()]);
+        assert_eq!(outcome, Outcome::Unproducible);
+        assert_eq!((report.failed, report.unproducible, report.total()), (1, 1, 2));
+        assert_eq!(report.summary(), "1/2 passed (50.0%); 1 not checked, their gold forms having unproducible symbols");
+        let listed: Vec<_> = report.unproducibles().map(|r| (r.input.as_str(), r.unproducible.clone())).collect();
+        assert_eq!(listed, [("e", vec!["5".to_string()])]);
+
+        // Data errors alone do not count as failures.
+        let mut report = TestReport::default();
+        report.record_unproducible("e", "f5", vec!["5".to_string()]);
+        assert_eq!(report.style(), Style::Warning);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["items"][0], serde_json::json!({"input": "e", "form": "f5", "outcome": "unproducible", "unproducible": ["5"]}));
+    }
+
+    #[test]
+    fn test_scores_are_averaged_over_every_item() {
+        let score = |cer: f64, tone_cer: f64, f1: f64| Score { prediction: Some("p".to_string()), cer, tone_cer, segment_cer: cer - tone_cer, f1 };
+        let mut report = TestReport::counts_only();
+        report.record_checked("a", "ka1", false, true, Some(score(0.0, 0.0, 1.0)), None);
+        report.record_checked("b", "ka1", false, false, Some(score(0.5, 0.25, 0.5)), None);
+        report.record_timeout("c", "ka1");
+        let mean = report.partial_credit.clone().unwrap();
+        assert_eq!((mean.items, mean.cer, mean.tone_cer, mean.segment_cer, mean.f1), (2, 0.25, 0.125, 0.125, 0.75));
+        assert_eq!(mean.summary(), "mean CER 0.250 (0.125 tones, 0.125 segments), mean symbol F1 0.750 over 2 items");
+        // Only the kept items carry their scores.
+        assert_eq!(report.items.len(), 1);
+
+        let mut report = TestReport::default();
+        report.record_checked("b", "ka1", false, false, Some(score(0.5, 0.25, 0.5)), None);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["items"][0]["score"]["prediction"], "p");
+        assert_eq!(json["partial_credit"]["cer"], 0.5);
+    }
+
+    #[test]
+    fn test_items_given_up_on_for_the_limits() {
+        let mut report = TestReport::counts_only();
+        report.record("a", "b", false, true);
+        let outcome = report.record_limit("aaaa", "b", LimitError::TooLong { symbols: 4, max: 3 });
+        assert_eq!(outcome, Outcome::TooLong);
+        report.record_limit("c", "d", LimitError::TooComplex { max_expansions: 10 });
+        assert_eq!((report.too_long, report.too_complex, report.total()), (1, 1, 2));
+        assert_eq!(report.summary(), "1/2 passed (50.0%); 1 too complex to finish; 1 not checked, their inputs being too long");
+        assert_eq!(report.style(), Style::Error);
+        assert_eq!(report.given_up().map(|r| r.input.as_str()).collect::<Vec<_>>(), ["aaaa", "c"]);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!((json["too_long"].clone(), json["items"][0]["outcome"].clone()), (serde_json::json!(1), serde_json::json!("too_long")));
+        assert_eq!(json["items"][1]["outcome"], "too_complex");
+    }
+
+    #[test]
+    fn test_json_report_counts() {
+        let mut forward = TestReport::default();
+        forward.record("a", "b", true, true);
+        forward.record("c", "d", true, false);
+        let dir = TempDir::new("report");
+        let path = dir.join("report.json");
+        write_json_report(&path, &RunInfo::default(), &forward, None).unwrap();
+        let json: serde_json::Value = serde_json::from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(json["forward"]["xpass"], 1);
+        assert_eq!(json["forward"]["xfail"], 1);
+        assert_eq!(json["forward"]["items"][0]["outcome"], "xpass");
+        assert!(json.get("reverse").is_none());
+    }
+
+    #[test]
+    fn test_json_report_run_snapshot() {
+        let mut forward = TestReport::default();
+        forward.record("a", "b", false, true);
+        let provenance = Provenance::parse("version=0.1.0\nbuilt_at=2026-10-13T00:00:00Z\nvariant=--no-min\nrule_file=00ff rules/a.txt\n");
+        let dir = TempDir::new("report-run");
+        let path = dir.join("report.json");
+        let json = |run: &RunInfo| -> serde_json::Value {
+            write_json_report(&path, run, &forward, None).unwrap();
+            serde_json::from_reader(std::fs::File::open(&path).unwrap()).unwrap()
+        };
+        let run = RunInfo { fst: "out.fst".to_string(), tag: Some("experiment-17".to_string()), provenance: Some(provenance) };
+        assert_eq!(
+            json(&run),
+            serde_json::json!({
+                "run": {
+                    "fst": "out.fst",
+                    "tag": "experiment-17",
+                    "provenance": {
+                        "rule_files": [{"path": "rules/a.txt", "hash": "00ff"}],
+                        "built_at": "2026-10-13T00:00:00Z",
+                        "version": "0.1.0",
+                        "variant": "--no-min",
+                        "symt_hash": null,
+                        "normalization": null
+                    }
+                },
+                "forward": {
+                    "passed": 1, "failed": 0, "xfail": 0, "xpass": 0, "timeout": 0, "unproducible": 0, "too_long": 0, "too_complex": 0,
+                    "items": [{"input": "a", "form": "b", "outcome": "pass"}]
+                }
+            })
+        );
+        let run = RunInfo { fst: "out.fst".to_string(), tag: None, provenance: None };
+        assert_eq!(json(&run)["run"], serde_json::json!({"fst": "out.fst", "tag": null, "provenance": null}));
+    }
+}
@@ -0,0 +1,413 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Context, Result};
+use itertools::enumerate;
+use rustfst::{
+    algorithms::concat::concat, fst, prelude::{closure::{closure, ClosureType}, compose::compose, determinize::{determinize_with_config, DeterminizeConfig, DeterminizeType}, tr_sort, union::union, ExpandedFst, Fst, ILabelCompare, MutableFst, OLabelCompare, TropicalWeight, VectorFst}, utils::transducer, Semiring, SymbolTable
+};
+
+use parserule::{ruleparse::{RegexAST, RewriteRule, Statement}, utils::optimize_fst};
+use parserule::rulefst::sigma_star;
+
+use crate::dump::{guard, Operation};
+use crate::profile::profile_span;
+use crate::rules::{check_target_symbols, predefined_macros, RuleChecks, Script};
+use crate::status::internal;
+use crate::verify::{minimize_verified, verify_equivalent, Nondeterminism, OnDivergence, VerifyOptions};
+
+use super::macros::resolve_macros;
+use super::node::node_fst;
+use super::project::{input_to_epsilons, output_to_epsilons};
+
+/// Number of syllable positions at which `compile_as_linear` applies the
+/// rules: after the initial segment and after each of up to three further
+/// tone + segment sequences.
+///
+/// This bounds how far into a word a process can apply, not how deeply
+/// brackets such as `{3>1>4}` can nest; those are flat in the rule notation
+/// and compile to ordinary FSTs. Applying rules at an unbounded number of
+/// positions would need a pushdown transducer, which `rustfst` (1.3) does not
+/// provide: it has no PDT expansion, and its `replace` only accepts
+/// non-recursive dependencies.
+pub const SYLLABLE_POSITIONS: usize = 4;
+
+/// Options for compiling rules in the linear pipeline.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinearOptions {
+    /// Fail on symbols missing from the symbol table (and undefined macros)
+    /// rather than falling back to epsilon.
+    pub strict: bool,
+    /// Weight of each repetition of a `*` or `+` closure, so that a positive
+    /// weight prefers fewer repetitions.
+    pub closure_weight: f32,
+    /// Check on sampled inputs that determinization kept every output.
+    pub verify: Option<VerifyOptions>,
+    /// Check minimization the same way, and what to do if it lost outputs.
+    pub verify_minimize: Option<(VerifyOptions, OnDivergence)>,
+    /// Whether to minimize compositions that are not input-deterministic.
+    pub nondeterminism: Nondeterminism,
+    /// Where a rule writes its target.
+    pub target: TargetPlacement,
+}
+
+/// Where [`linearze_rule_fst`] writes the target of a rule.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TargetPlacement {
+    /// After the rest of the word, with the source rewritten to its
+    /// underlying form (`L[{S1>S2}->S1]R Σ* T`). This is what linearizing
+    /// means: the word is brought back to its underlying form, followed by
+    /// the processes applied to it in the order of the syllables they apply
+    /// at. As the targets only ever extend the end of the word, the
+    /// positions [`compile_as_linear`] counts syllables from are the same at
+    /// every composition.
+    #[default]
+    Trailing,
+    /// At the rule site, in place of the source (`L[S->T]R Σ*`), as in
+    /// ordinary rewriting. A target with more or fewer syllables than its
+    /// source shifts the positions later compositions apply at.
+    InPlace,
+}
+
+/// Compile a stage script for the linear pipeline. Each rule is checked with
+/// `checks` (under the name `file`), and rules that compile to empty
+/// transducers are left out of the union. A rule with a cost is unioned with
+/// that cost added to its paths.
+pub fn compile_as_linear(
+    symt: Arc<SymbolTable>,
+    script: Script,
+    dump_macros: bool,
+    opts: LinearOptions,
+    file: &str,
+    checks: &mut RuleChecks,
+) -> Result<VectorFst<TropicalWeight>> {
+    checks.check_variant_probabilities(file, &script);
+    script.config.require_cascade(file, "the linear pipeline")?;
+    let Script { statements: script, costs, .. } = script;
+    let resolved = resolve_macros(&script)?;
+    if dump_macros {
+        for (mac, def) in resolved.iter() {
+            println!("::{}:: = {:?}", mac, def);
+        }
+    }
+    let mut base_fst = sigma_star(symt.clone())?;
+    let mut macros = predefined_macros();
+    for (i,statement) in enumerate(script.clone()) {
+        match statement {
+            Statement::Comment => (),
+            Statement::MacroDef((mac, def)) => {
+                macros.insert(mac, def).unwrap_or(RegexAST::Epsilon);
+            },
+            Statement::Rule(rule) => {
+                println!("Processing rule {} of {}: {:?}", i+1, script.len(), rule);
+                if only_word_start(&rule.left, &macros) {
+                    println!(
+                        "Note: rule {} of {} only applies word-initially, which no syllable position is; it never applies",
+                        i + 1, file
+                    );
+                }
+                let mut fst2 = linearze_rule_fst(symt.clone(), &macros, rule.clone(), true, opts)
+                    .inspect_err(|e| {
+                        println!(
+                            "Failed to build rule {:?} having macros {:?}: {}", rule, macros, e
+                        )
+                    })
+                    .with_context(|| format!("In rule {} ({:?})", i + 1, rule))?;
+                if !checks.check(file, i + 1, &fst2)? {
+                    continue;
+                }
+                if let Some(cost) = costs.get(&i) {
+                    let mut weighted: VectorFst<TropicalWeight> = fst![0 => 0; cost.cost];
+                    concat(&mut weighted, &fst2)?;
+                    fst2 = weighted;
+                }
+                optimize_fst(&mut base_fst, 1e-7).unwrap_or(());
+                tr_sort(&mut base_fst, OLabelCompare {});
+                tr_sort(&mut fst2, ILabelCompare {});
+                union(&mut base_fst, &fst2)?;
+            }
+        }
+    }
+    println!("Finished processing {} rules", script.len());
+    println!("Determinizing...");
+    // The undeterminized FST is only kept while it is compared against.
+    let before = opts.verify.map(|_| base_fst.clone());
+    let op = Operation::Determinize { delta: 1e-7, functional: true };
+    base_fst = guard(op, &[&base_fst], || {
+        internal(determinize_with_config(&base_fst, DeterminizeConfig { delta: 1e-7, det_type: DeterminizeType::DeterminizeFunctional }))
+    })?;
+    if let (Some(verify), Some(before)) = (opts.verify, before) {
+        verify_equivalent("Determinization", &symt, &before, &base_fst, &verify)?;
+    }
+    println!("Applying segment contexts...");
+    let seg_first = node_fst(symt.clone(), &macros, opts, RegexAST::Group(vec![RegexAST::Boundary, RegexAST::Macro("segment".to_string())]))?;
+    let tone_seg = node_fst(symt.clone(), &macros, opts, RegexAST::Group(vec![RegexAST::Macro("tone".to_string()), RegexAST::Macro("segment".to_string())]))?;
+    let mut fst = sigma_star(symt.clone())?;
+    for i in 0..SYLLABLE_POSITIONS {
+        let mut fst2 = seg_first.clone();
+        // Concatenate i additional segments
+        for _ in 0..i {
+            concat(&mut fst2, &tone_seg)?;
+        }
+        concat(&mut fst2, &base_fst)?;
+        let _span = profile_span!("syllable_position", position = i + 1);
+        tr_sort(&mut fst, OLabelCompare {});
+        tr_sort(&mut fst2, ILabelCompare {});
+        fst = {
+            let _span = profile_span!("compose");
+            internal(compose(fst, fst2))?
+        };
+        println!("Composition {} of {} complete", i+1, SYLLABLE_POSITIONS);
+        println!("Minimizing...");
+        let _minimize_span = profile_span!("minimize");
+        optimize_fst(&mut fst, 1e-7).unwrap_or(());
+        minimize_verified(&symt, &mut fst, opts.verify_minimize, opts.nondeterminism)?;
+        println!("Minimization complete");
+    }
+
+    Ok(fst)
+}
+
+/// Whether `node` matches nothing but a boundary, so that as a left context
+/// it puts the rule at the start of the word.
+fn only_word_start(node: &RegexAST, macros: &HashMap<String, RegexAST>) -> bool {
+    match node {
+        RegexAST::Boundary => true,
+        RegexAST::Group(nodes) => {
+            let mut rest = nodes.iter().filter(|n| !matches!(n, RegexAST::Epsilon | RegexAST::Comment));
+            rest.next().is_some_and(|n| only_word_start(n, macros)) && rest.next().is_none()
+        }
+        RegexAST::Disjunction(nodes) => !nodes.is_empty() && nodes.iter().all(|n| only_word_start(n, macros)),
+        RegexAST::Plus(n) => only_word_start(n, macros),
+        RegexAST::Macro(m) => macros.get(m).is_some_and(|def| only_word_start(def, macros)),
+        _ => false,
+    }
+}
+
+/// Compile a rule for the linear pipeline. With `opts.strict`, any symbol missing
+/// from `symt` (or undefined macro) is an error rather than an epsilon fallback;
+/// a symbol missing from the target always is. `opts.target` decides where
+/// the target is written (see [`TargetPlacement`]).
+///
+/// `drop_left` leaves out the left context, for a caller that places the rule
+/// after a context of its own, as [`compile_as_linear`] does with the syllable
+/// position (`#(::segment::)...`) the stage scripts spell out. A left context
+/// that is only a boundary is kept anyway: no position after a segment stands
+/// in for the start of the word, and dropping it would let a word-initial rule
+/// apply anywhere.
+pub fn linearze_rule_fst(
+    symt: Arc<SymbolTable>,
+    macros: &HashMap<String, RegexAST>,
+    rule: RewriteRule,
+    drop_left: bool,
+    opts: LinearOptions,
+) -> Result<VectorFst<TropicalWeight>> {
+    check_target_symbols(&symt, macros, &rule, "the rule")?;
+    let keep_left = !drop_left || only_word_start(&rule.left, macros);
+    let mut fst = VectorFst::<TropicalWeight>::new();
+    fst.set_input_symbols(symt.clone());
+    fst.set_output_symbols(symt.clone());
+    let q0 = fst.add_state();
+    fst.set_start(0)?;
+    let q1 = fst.add_state();
+    fst.set_final(q1, TropicalWeight::one())?;
+    fst.emplace_tr(q0, 0, 0, TropicalWeight::one(), q1)?;
+
+    // Compute core (L[{S1>S2}->S1]R##T). Each process in the source maps to
+    // its first stage; an unchanged tone maps to itself.
+    let underlying_seq = rule.source.underlying();
+    println!("Underlying sequence: {:?}", underlying_seq);
+    let underlying_fst = input_to_epsilons(node_fst(symt.clone(), macros, opts, underlying_seq)?);
+
+    let src_fst: VectorFst<TropicalWeight> =
+        output_to_epsilons(node_fst(symt.clone(), macros, opts, rule.source)?);
+    let tgt_fst: VectorFst<TropicalWeight> =
+        input_to_epsilons(node_fst(symt.clone(), macros, opts, rule.target)?);
+    let left_fst = match rule.left {
+        RegexAST::Epsilon => {
+            let mut inner_fst = sigma_star(symt.clone())?;
+            closure(&mut inner_fst, ClosureType::ClosureStar);
+            inner_fst
+        }
+        _ => node_fst(symt.clone(), macros, opts, rule.left)?,
+    };
+    let right_fst = match rule.right {
+        RegexAST::Epsilon => {
+            let mut inner_fst = sigma_star(symt.clone())?;
+            closure(&mut inner_fst, ClosureType::ClosureStar);
+            inner_fst
+        }
+        _ => node_fst(symt.clone(), macros, opts, rule.right)?,
+    };
+    let univ_acc: VectorFst<TropicalWeight> = sigma_star(symt.clone())?;
+
+    // Ignore left context if requested (and not a word edge)
+    if keep_left { concat(&mut fst, &left_fst)?; }
+    concat(&mut fst, &src_fst)?;
+    match opts.target {
+        TargetPlacement::Trailing => {
+            // Map {L>R} to L in-place
+            concat(&mut fst, &underlying_fst)?;
+            // Right context and acceptor are kept
+            concat(&mut fst, &right_fst)?;
+            concat(&mut fst, &univ_acc)?;
+            // Output target at the end
+            concat(&mut fst, &tgt_fst)?;
+        }
+        TargetPlacement::InPlace => {
+            concat(&mut fst, &tgt_fst)?;
+            concat(&mut fst, &right_fst)?;
+            concat(&mut fst, &univ_acc)?;
+        }
+    }
+
+    let last_state: u32 = (fst.num_states() - 1) as u32;
+
+    //fst.emplace_tr(last_state, 0, 0, 0.0, first_state)?;
+    //fst.emplace_tr(first_state, 0, 0, 10.0, last_state)?;
+    fst.set_final(last_state, 0.0)?;
+
+    let mut root: VectorFst<TropicalWeight> = fst![0 => 0];//sigma_star(symt.clone())?;
+
+    concat(&mut root, &fst)?;
+
+    root.set_start(0)?;
+
+    optimize_fst(&mut root, 1e-6).unwrap_or(());
+
+    Ok(root)
+    /*
+    println!("Minimizing...");
+    internal(minimize_with_config(&mut root, MinimizeConfig { delta: 1e-7, allow_nondet: true }))?;
+    println!("Determinizing...");
+    internal(determinize_with_config(&root, DeterminizeConfig { delta: 1e-7, det_type: DeterminizeType::DeterminizeNonFunctional }))
+     */
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parserule::ruleparse::parse_script;
+
+    fn script(raw: &str) -> Vec<Statement> {
+        parse_script(raw).unwrap().1 .0
+    }
+
+    fn rule(raw: &str) -> RewriteRule {
+        match script(raw).into_iter().next() {
+            Some(Statement::Rule(rule)) => rule,
+            other => panic!("not a rule: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_strict_symbols_rejects_unknown_symbols() {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b"]);
+        let macros = HashMap::new();
+        let strict = LinearOptions { strict: true, ..Default::default() };
+        for raw in ["a -> b / _ c\n", "a -> b / _ [bc]\n", "a -> b / _ ::nope::\n"] {
+            assert!(linearze_rule_fst(symt.clone(), &macros, rule(raw), true, LinearOptions::default()).is_ok(), "{}", raw);
+            assert!(linearze_rule_fst(symt.clone(), &macros, rule(raw), true, strict).is_err(), "{}", raw);
+        }
+        let err = linearze_rule_fst(symt, &macros, rule("a -> b / _ c\n"), true, strict).unwrap_err();
+        assert!(err.to_string().contains("'c'"), "{}", err);
+    }
+
+    #[test]
+    fn test_missing_target_symbol_is_an_error_even_when_lenient() {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b"]);
+        let macros = HashMap::from([("t".to_string(), RegexAST::Char("q".to_string()))]);
+        for raw in ["a -> q / _ b\n", "a -> ::t:: / _ b\n"] {
+            let err = linearze_rule_fst(symt.clone(), &macros, rule(raw), true, LinearOptions::default()).unwrap_err();
+            assert!(err.to_string().contains("uses 'q'") && err.to_string().contains("delete"), "{}", err);
+        }
+        // A missing symbol in a context still falls back to epsilon.
+        assert!(linearze_rule_fst(symt, &macros, rule("a -> b / _ q\n"), true, LinearOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_multi_stage_process_maps_to_its_first_stage() {
+        let symt = Arc::new(rustfst::symt!["#", "a", "1", "3", "4", "{", ">", "}"]);
+        let rule = rule("{3\\>1\\>4} -> #3\\>1\\>4# / _ a\n");
+        assert_eq!(rule.source.underlying(), RegexAST::Group(vec![RegexAST::Group(vec![RegexAST::Char("3".to_string())])]));
+        let mut fst = linearze_rule_fst(symt.clone(), &HashMap::new(), rule, true, LinearOptions::default()).unwrap();
+        tr_sort(&mut fst, ILabelCompare {});
+        let outputs = |input: &str| {
+            let lattice = parserule::rulefst::apply_fst_to_string(symt.clone(), fst.clone(), input.to_string()).unwrap();
+            let outputs = crate::decode::decode_distinct_outputs(&lattice, None, &crate::ranking::Lexicographic, |l| crate::decode::display_labels(&symt, l)).unwrap();
+            outputs.into_iter().map(|(_, o)| o).collect::<Vec<_>>()
+        };
+        assert_eq!(outputs("{3>1>4}a"), ["3a#3>1>4#"]);
+        // Only the whole process is read, not one of its steps.
+        assert!(outputs("{3>1}a").is_empty());
+    }
+
+    #[test]
+    fn test_target_placement() {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b", "c"]);
+        let outputs = |target: TargetPlacement, input: &str| {
+            let opts = LinearOptions { target, ..Default::default() };
+            let mut fst = linearze_rule_fst(symt.clone(), &HashMap::new(), rule("a -> b / _ c\n"), true, opts).unwrap();
+            tr_sort(&mut fst, ILabelCompare {});
+            let lattice = parserule::rulefst::apply_fst_to_string(symt.clone(), fst, input.to_string()).unwrap();
+            let outputs = crate::decode::decode_distinct_outputs(&lattice, None, &crate::ranking::Lexicographic, |l| crate::decode::display_labels(&symt, l)).unwrap();
+            outputs.into_iter().map(|(_, o)| o).collect::<Vec<_>>()
+        };
+        // The source is kept and the target follows the rest of the word...
+        assert_eq!(outputs(TargetPlacement::Trailing, "acab"), ["acabb"]);
+        // ...or replaces the source.
+        assert_eq!(outputs(TargetPlacement::InPlace, "acab"), ["bcab"]);
+        for target in [TargetPlacement::Trailing, TargetPlacement::InPlace] {
+            assert!(outputs(target, "abab").is_empty(), "{:?}", target);
+        }
+    }
+
+    #[test]
+    fn test_word_initial_rule_keeps_its_boundary_when_left_is_dropped() {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b"]);
+        let macros = HashMap::from([("start".to_string(), RegexAST::Group(vec![RegexAST::Boundary]))]);
+        let outputs = |raw: &str, input: &str| {
+            let opts = LinearOptions { target: TargetPlacement::InPlace, ..Default::default() };
+            let mut fst = linearze_rule_fst(symt.clone(), &macros, rule(raw), true, opts).unwrap();
+            tr_sort(&mut fst, ILabelCompare {});
+            let lattice = parserule::rulefst::apply_fst_to_string(symt.clone(), fst, input.to_string()).unwrap();
+            let outputs = crate::decode::decode_distinct_outputs(&lattice, None, &crate::ranking::Lexicographic, |l| crate::decode::display_labels(&symt, l)).unwrap();
+            outputs.into_iter().map(|(_, o)| o).collect::<Vec<_>>()
+        };
+        for raw in ["a -> b / # _ \n", "a -> b / ::start:: _ \n", "a -> b / (#|::start::) _ \n"] {
+            assert_eq!(outputs(raw, "#aa#"), ["#ba#"], "{}", raw);
+            // Not at the second a, nor without the boundary to match.
+            assert!(outputs(raw, "aa#").is_empty(), "{}", raw);
+        }
+        // Any other left context is still left to the caller, even one that
+        // starts with a boundary, as the syllable positions of the stage scripts do.
+        assert_eq!(outputs("a -> b / b _ \n", "aa#"), ["ba#"]);
+        assert_eq!(outputs("a -> b / #b _ \n", "aa#"), ["ba#"]);
+        assert!(!only_word_start(&rule("a -> b / (#|b) _ \n").left, &macros));
+    }
+
+    #[test]
+    fn test_combining_marks_make_one_symbol() {
+        use parserule::normalize::nfd_normalize;
+
+        let outputs = |symt: Arc<SymbolTable>, raw: &str, input: &str| {
+            let opts = LinearOptions { target: TargetPlacement::InPlace, ..Default::default() };
+            let mut fst = linearze_rule_fst(symt.clone(), &HashMap::new(), rule(raw), true, opts).unwrap();
+            tr_sort(&mut fst, ILabelCompare {});
+            let lattice = parserule::rulefst::apply_fst_to_string(symt.clone(), fst, nfd_normalize(input)).unwrap();
+            let outputs = crate::decode::decode_distinct_outputs(&lattice, None, &crate::ranking::Lexicographic, |l| crate::decode::display_labels(&symt, l)).unwrap();
+            outputs.into_iter().map(|(_, o)| o).collect::<Vec<_>>()
+        };
+        let mut together = SymbolTable::new();
+        together.add_symbols(["#", "a", "n", "n\u{303}"]);
+        let together = Arc::new(together);
+        // Written precomposed or not, ñ is the one symbol, and n alone is not it.
+        assert_eq!(outputs(together.clone(), "\u{f1} -> a / _ a\n", "\u{f1}a"), ["aa"]);
+        assert_eq!(outputs(together.clone(), "n\u{303} -> a / _ a\n", "\u{f1}a"), ["aa"]);
+        assert!(outputs(together.clone(), "n -> a / _ a\n", "\u{f1}a").is_empty());
+        // A table with the mark as a symbol of its own spells ñ with two.
+        let mut apart = SymbolTable::new();
+        apart.add_symbols(["#", "a", "n", "\u{303}"]);
+        assert_eq!(outputs(Arc::new(apart.clone()), "\u{f1} -> a / _ a\n", "\u{f1}a"), ["aa"]);
+        assert_eq!(outputs(Arc::new(apart), "[\u{f1}] -> a / _ a\n", "\u{f1}a"), ["aa"]);
+    }
+}
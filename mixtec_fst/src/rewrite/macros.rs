@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use parserule::ruleparse::{RegexAST, Statement};
+
+/// The macros defined in `script`, in order of first definition, each with its
+/// fully-expanded definition. A later definition of a name replaces an earlier one,
+/// as it does during compilation. Fails if a macro refers to itself.
+pub fn resolve_macros(script: &[Statement]) -> Result<Vec<(String, RegexAST)>> {
+    let mut names = Vec::new();
+    let mut macros = HashMap::new();
+    for statement in script {
+        if let Statement::MacroDef((mac, def)) = statement
+            && macros.insert(mac.clone(), def.clone()).is_none()
+        {
+            names.push(mac.clone());
+        }
+    }
+    names
+        .into_iter()
+        .map(|mac| {
+            let expanded = expand_macro(&macros, &mac, &mut Vec::new())?;
+            Ok((mac, expanded))
+        })
+        .collect()
+}
+
+/// The definition of `mac` with nested macros expanded; `stack` holds the macros
+/// currently being expanded. Undefined macros are left in place.
+fn expand_macro(macros: &HashMap<String, RegexAST>, mac: &str, stack: &mut Vec<String>) -> Result<RegexAST> {
+    if let Some(i) = stack.iter().position(|m| m == mac) {
+        let cycle = stack[i..].iter().map(String::as_str).chain([mac]).map(|m| format!("::{}::", m));
+        return Err(anyhow!("Macro cycle: {}", cycle.collect::<Vec<_>>().join(" -> ")));
+    }
+    let Some(def) = macros.get(mac) else {
+        return Ok(RegexAST::Macro(mac.to_string()));
+    };
+    stack.push(mac.to_string());
+    let expanded = expand_node(macros, def, stack)?;
+    stack.pop();
+    Ok(expanded)
+}
+
+fn expand_node(macros: &HashMap<String, RegexAST>, node: &RegexAST, stack: &mut Vec<String>) -> Result<RegexAST> {
+    let expand_all = |nodes: &[RegexAST], stack: &mut Vec<String>| {
+        nodes.iter().map(|n| expand_node(macros, n, stack)).collect::<Result<Vec<_>>>()
+    };
+    Ok(match node {
+        RegexAST::Macro(mac) => expand_macro(macros, mac, stack)?,
+        RegexAST::Group(nodes) => RegexAST::Group(expand_all(nodes, stack)?),
+        RegexAST::Disjunction(nodes) => RegexAST::Disjunction(expand_all(nodes, stack)?),
+        RegexAST::Process(stages) => RegexAST::Process(expand_all(stages, stack)?),
+        RegexAST::Option(n) => RegexAST::Option(Box::new(expand_node(macros, n, stack)?)),
+        RegexAST::Star(n) => RegexAST::Star(Box::new(expand_node(macros, n, stack)?)),
+        RegexAST::Plus(n) => RegexAST::Plus(Box::new(expand_node(macros, n, stack)?)),
+        other => other.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parserule::ruleparse::parse_script;
+
+    fn script(raw: &str) -> Vec<Statement> {
+        parse_script(raw).unwrap().1 .0
+    }
+
+    #[test]
+    fn test_resolve_macros_expands_nested() {
+        let resolved = resolve_macros(&script("::v:: = [a]\n::seg:: = b(::v::)\n")).unwrap();
+        let names: Vec<_> = resolved.iter().map(|(m, _)| m.as_str()).collect();
+        assert_eq!(names, ["v", "seg"]);
+        let (_, v) = &resolved[0];
+        let (_, seg) = &resolved[1];
+        assert!(!format!("{:?}", seg).contains("Macro"));
+        assert!(format!("{:?}", seg).contains(&format!("{:?}", v)));
+    }
+
+    #[test]
+    fn test_resolve_macros_reports_cycles() {
+        let err = resolve_macros(&script("::a:: = (::b::)\n::b:: = x(::a::)\n")).unwrap_err();
+        assert!(err.to_string().contains("::a:: -> ::b:: -> ::a::"), "{}", err);
+    }
+
+    #[test]
+    fn test_resolve_macros_keeps_the_last_definition_and_undefined_macros() {
+        let resolved = resolve_macros(&script("::v:: = [a]\n::seg:: = (::v::)(::w::)\n::v:: = b\n")).unwrap();
+        assert_eq!(resolved.iter().map(|(m, _)| m.as_str()).collect::<Vec<_>>(), ["v", "seg"]);
+        let (_, seg) = &resolved[1];
+        let seg = format!("{:?}", seg);
+        assert!(seg.contains(&format!("{:?}", RegexAST::Char("b".to_string()))), "{}", seg);
+        assert!(seg.contains(&format!("{:?}", RegexAST::Macro("w".to_string()))), "{}", seg);
+    }
+}
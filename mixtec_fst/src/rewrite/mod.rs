@@ -0,0 +1,58 @@
+//! Compiling rewrite rules to FSTs for the linear pipeline.
+//!
+//! - [`macros`]: resolving the macros a script defines;
+//! - [`node`]: the FST of one node of a rule ([`node_fst`]);
+//! - [`project`]: the projections a rule's parts are turned into;
+//! - [`linearize`]: the FST of a rule, and of a stage script of them.
+
+mod linearize;
+mod macros;
+mod node;
+mod project;
+
+pub use linearize::{compile_as_linear, LinearOptions, TargetPlacement};
+// Public as they were in `rewrite.rs`, though nothing else in the crate uses them.
+#[allow(unused_imports)]
+pub use linearize::{linearze_rule_fst, SYLLABLE_POSITIONS};
+pub use macros::resolve_macros;
+pub use node::node_fst;
+
+/// Helpers for the tests of the submodules.
+#[cfg(test)]
+mod testing {
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+
+    use parserule::rulefst::string_to_linear_automaton;
+    use rustfst::prelude::{compose::compose, connect, tr_sort, CoreFst, ILabelCompare, OLabelCompare, StateIterator, TropicalWeight, VectorFst};
+    use rustfst::{Label, SymbolTable, EPS_LABEL};
+
+    /// A table of the one-character symbols of `symbols`, after `#`.
+    pub fn symt(symbols: &str) -> Arc<SymbolTable> {
+        let mut symt = SymbolTable::new();
+        symt.add_symbol("#");
+        for c in symbols.chars() {
+            symt.add_symbol(c.to_string());
+        }
+        Arc::new(symt)
+    }
+
+    /// Whether `fst` reads `input`.
+    pub fn accepts(symt: &Arc<SymbolTable>, fst: &VectorFst<TropicalWeight>, input: &str) -> bool {
+        let mut acc = string_to_linear_automaton(symt.clone(), input);
+        let mut fst = fst.clone();
+        tr_sort(&mut acc, OLabelCompare {});
+        tr_sort(&mut fst, ILabelCompare {});
+        let mut matched: VectorFst<TropicalWeight> = compose(acc, fst).unwrap();
+        connect(&mut matched).unwrap();
+        matched.start().is_some()
+    }
+
+    /// The non-epsilon (input, output) label pairs on the transitions of `fst`.
+    pub fn label_pairs(fst: &VectorFst<TropicalWeight>) -> BTreeSet<(Label, Label)> {
+        fst.states_iter()
+            .flat_map(|s| fst.get_trs(s).unwrap().iter().map(|tr| (tr.ilabel, tr.olabel)).collect::<Vec<_>>())
+            .filter(|&pair| pair != (EPS_LABEL, EPS_LABEL))
+            .collect()
+    }
+}
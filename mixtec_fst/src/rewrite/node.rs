@@ -0,0 +1,433 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use rustfst::{
+    algorithms::concat::concat, fst, prelude::{add_super_final_state, closure::{closure, ClosureType}, rm_epsilon::rm_epsilon, union::union, CoreFst, ExpandedFst, Fst, MutableFst, StateIterator, TropicalWeight, VectorFst}, utils::{acceptor, transducer}, Label, Semiring, SymbolTable, Tr, EPS_LABEL
+};
+
+use parserule::ruleparse::RegexAST;
+use parserule::rulefst::symbol_labels;
+
+use crate::status::internal;
+use crate::style::{paint, warn, Stream, Style};
+
+use super::LinearOptions;
+
+/// The label of `sym`, or `fallback` if it is not in `symt`. With `strict`, a
+/// missing symbol is an error instead.
+fn symbol_label(symt: &SymbolTable, sym: &str, fallback: Label, strict: bool) -> Result<Label> {
+    match symt.get_label(sym) {
+        Some(l) => Ok(l),
+        None if strict => Err(anyhow!("Symbol '{}' is not in the symbol table", sym)),
+        None => Ok(fallback),
+    }
+}
+
+/// Apply `closure` to `fst`, adding `weight` to each back-transition it
+/// introduces, i.e. to every repetition after the first.
+fn weighted_closure(fst: &mut VectorFst<TropicalWeight>, closure_type: ClosureType, weight: f32) -> Result<()> {
+    add_closure(fst, closure_type, weight)?;
+    // Closing over an FST that accepts epsilon makes epsilon cycles, on which
+    // path enumeration never ends and minimization struggles.
+    if has_epsilon_cycle(fst)? {
+        warn("Warning: closure introduced epsilon cycles; removing epsilons");
+        internal(rm_epsilon(fst))?;
+    }
+    Ok(())
+}
+
+fn add_closure(fst: &mut VectorFst<TropicalWeight>, closure_type: ClosureType, weight: f32) -> Result<()> {
+    if weight == 0.0 {
+        closure(fst, closure_type);
+        return Ok(());
+    }
+    let Some(start) = fst.start() else {
+        return Ok(());
+    };
+    let finals: Vec<_> = fst.final_states_iter().collect();
+    for s in finals {
+        let final_weight = fst.final_weight(s)?.unwrap_or_else(TropicalWeight::one);
+        fst.add_tr(s, Tr::new(0, 0, final_weight.times(TropicalWeight::new(weight))?, start))?;
+    }
+    if closure_type == ClosureType::ClosureStar {
+        let nstart = fst.add_state();
+        fst.add_tr(nstart, Tr::new(0, 0, TropicalWeight::one(), start))?;
+        fst.set_start(nstart)?;
+        fst.set_final(nstart, TropicalWeight::one())?;
+    }
+    Ok(())
+}
+
+/// Whether `fst` has a cycle made only of epsilon:epsilon transitions.
+fn has_epsilon_cycle(fst: &VectorFst<TropicalWeight>) -> Result<bool> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Color {
+        White,
+        Gray,
+        Black,
+    }
+    let mut color = vec![Color::White; fst.num_states()];
+    for root in fst.states_iter() {
+        if color[root as usize] != Color::White {
+            continue;
+        }
+        // (state, index of the next transition to look at)
+        let mut stack = vec![(root, 0)];
+        color[root as usize] = Color::Gray;
+        while let Some((state, i)) = stack.pop() {
+            let trs = fst.get_trs(state)?;
+            let next = trs.iter().skip(i).position(|tr| tr.ilabel == EPS_LABEL && tr.olabel == EPS_LABEL);
+            match next {
+                Some(offset) => {
+                    let tr = &trs[i + offset];
+                    stack.push((state, i + offset + 1));
+                    match color[tr.nextstate as usize] {
+                        Color::Gray => return Ok(true),
+                        Color::White => {
+                            color[tr.nextstate as usize] = Color::Gray;
+                            stack.push((tr.nextstate, 0));
+                        }
+                        Color::Black => (),
+                    }
+                }
+                None => color[state as usize] = Color::Black,
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// The FST of `node`, an acceptor of what it matches. Macros are looked up
+/// in `macros`, and symbols missing from `symt` fall back to epsilon unless
+/// `opts.strict`.
+pub fn node_fst(
+    symt: Arc<SymbolTable>,
+    macros: &HashMap<String, RegexAST>,
+    opts: LinearOptions,
+    node: RegexAST,
+) -> Result<VectorFst<TropicalWeight>> {
+    let strict = opts.strict;
+    let mut fst: VectorFst<TropicalWeight> = fst![0 => 0];
+    fst.set_input_symbols(symt.clone());
+    fst.set_output_symbols(symt.clone());
+
+    match node {
+        // Interpret an Epsilon node (leaves `fst` unchanged, since it already includes an epsilon transition).
+        RegexAST::Epsilon => (),
+
+        // Interpret a group (a sequence of nodes)
+        RegexAST::Group(nodes) => {
+            for node2 in nodes {
+                let fst2 = node_fst(symt.clone(), macros, opts, node2)?;
+                concat(&mut fst, &fst2)?;
+            }
+        }
+
+        // Interpret a boundary symbol.
+        RegexAST::Boundary => {
+            let bnd_label = symbol_label(&symt, "#", 1, strict)?;
+            let fst2: VectorFst<TropicalWeight> = fst![bnd_label];
+            concat(&mut fst, &fst2)?;
+        }
+
+        // Interpret a symbol, a letter with its combining marks being one.
+        RegexAST::Char(c) => {
+            let labels = match symbol_labels(&symt, &c) {
+                Some(labels) => labels,
+                None => vec![symbol_label(&symt, &c, 0, strict)?],
+            };
+            let fst2: VectorFst<TropicalWeight> = acceptor(&labels, TropicalWeight::one());
+            concat(&mut fst, &fst2)?;
+        }
+
+        // Interpret a disjunction (a set of mutually-exclusive sequences).
+        RegexAST::Disjunction(nodes) => {
+            let mut fst2: VectorFst<TropicalWeight> = VectorFst::<TropicalWeight>::new();
+            let q0 = fst.add_state();
+            let q1 = fst.add_state();
+            fst.emplace_tr(q0, 0, 0, TropicalWeight::zero(), q1)?;
+            for node in nodes {
+                let case_fst = node_fst(symt.clone(), macros, opts, node)?;
+                union(&mut fst2, &case_fst)?;
+            }
+            concat(&mut fst, &fst2)?;
+        }
+
+        // Interpret a character class (a set of characters any of which match the expression).
+        RegexAST::Class(class) => {
+            let mut fst2: VectorFst<TropicalWeight> = VectorFst::<TropicalWeight>::new();
+            let q0 = fst2.add_state();
+            fst2.set_start(q0)?;
+            let q1: u32 = fst2.add_state();
+            fst2.set_final(q1, 0.0)?;
+            fst2.emplace_tr(q1, 0, 0, TropicalWeight::zero(), q1)?;
+            for s in class.iter() {
+                // A member the table only has as separate characters is a
+                // path through them.
+                if let Some(labels) = symbol_labels(&symt, s).filter(|labels| labels.len() > 1) {
+                    let mut from = q0;
+                    for (i, &l) in labels.iter().enumerate() {
+                        let to = if i + 1 == labels.len() { q1 } else { fst2.add_state() };
+                        fst2.emplace_tr(from, l, l, TropicalWeight::one(), to)?;
+                        from = to;
+                    }
+                    continue;
+                }
+                if symt.get_label(s).is_none() && !strict {
+                    eprintln!(
+                        "Warning: Symbol '{}' is not in symbol table, using epsilon",
+                        paint(Stream::Stderr, Style::Error, s)
+                    );
+                }
+                let l = symbol_label(&symt, s, 0, strict)?;
+                fst2.emplace_tr(q0, l, l, TropicalWeight::one(), q1)?;
+            }
+            concat(&mut fst, &fst2)?;
+        }
+
+        // Interpret the complement of a character class (a set of characters none of which match the expression).
+        RegexAST::ClassComplement(mut class) => {
+            let mut fst2: VectorFst<TropicalWeight> = VectorFst::<TropicalWeight>::new();
+            let q0 = fst2.add_state();
+            fst2.set_start(q0)?;
+            let q1: u32 = fst2.add_state();
+            fst2.set_final(q1, 0.0)?;
+            class.insert("#".to_string());
+            class.insert("<eps>".to_string());
+            for (l, s) in symt.iter() {
+                if !class.contains(s) {
+                    fst2.emplace_tr(q0, l, l, TropicalWeight::one(), q1)?;
+                }
+            }
+            concat(&mut fst, &fst2)?;
+        }
+
+        // Interpret a Kleene star.
+        RegexAST::Star(node) => {
+            let mut fst2 = node_fst(symt, macros, opts, *node)?;
+            weighted_closure(&mut fst2, ClosureType::ClosureStar, opts.closure_weight)?;
+            concat(&mut fst, &fst2)?;
+        }
+
+        // Interpret a Kleene plus.
+        RegexAST::Plus(node) => {
+            let mut fst2 = node_fst(symt, macros, opts, *node)?;
+            weighted_closure(&mut fst2, ClosureType::ClosurePlus, opts.closure_weight)?;
+            concat(&mut fst, &fst2)?;
+        }
+
+        // Interpret an optional node
+        RegexAST::Option(node) => {
+            let mut fst2: VectorFst<TropicalWeight> = node_fst(symt, macros, opts, *node)?;
+            let start_state = fst2.start().unwrap_or_else(|| {
+                println!("wFST does not have start state.");
+                0
+            });
+            let final_state = add_super_final_state(&mut fst2);
+            fst2.emplace_tr(start_state, 0, 0, 0.0, final_state)
+                .unwrap_or_else(|e| println!("{e}: Could not add transition."));
+            concat(&mut fst, &fst2)
+                .unwrap_or_else(|e| println!("{e}: Could not concatenate wFSTs."));
+        }
+
+        // Interpret a macro
+        RegexAST::Macro(macro_key) => {
+            let macro_node = match macros.get(&macro_key) {
+                Some(node) => node,
+                None if strict => return Err(anyhow!("Macro {macro_key} not defined")),
+                None => {
+                    println!("Macro {macro_key} not defined!");
+                    &RegexAST::Epsilon
+                }
+            };
+            let fst2 = node_fst(symt, macros, opts, macro_node.clone())?;
+            concat(&mut fst, &fst2)
+                .unwrap_or_else(|e| println!("{e}: Could not concatenate wFSTs."));
+        }
+
+        // Interpret a process as the symbols it is written with.
+        RegexAST::Process(stages) => {
+            let fst2 = node_fst(symt, macros, opts, RegexAST::process_symbols(&stages))?;
+            concat(&mut fst, &fst2)?;
+        }
+
+        RegexAST::Comment => (),
+    }
+
+    // rm_epsilon(&mut fst).unwrap_or_else(|e| {
+    //     eprintln!("Warning: Could not remove epsilon transitions: {}", e);
+    // });
+    // let mut fst = determinize_with_config(
+    //     &fst,
+    //     DeterminizeConfig {
+    //         delta: 1e-6,
+    //         det_type: DeterminizeType::DeterminizeFunctional,
+    //     },
+    // )?;
+    // push_weights(&mut fst, ReweightType::ReweightToInitial)?;
+    // minimize_with_config(
+    //     &mut fst,
+    //     MinimizeConfig {
+    //         delta: 1e-7,
+    //         allow_nondet: (true),
+    //     },
+    // )?;
+
+    Ok(fst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    use rustfst::prelude::{compose::compose, tr_sort, ILabelCompare, OLabelCompare};
+
+    use crate::rewrite::testing::{accepts, label_pairs, symt};
+
+    fn ch(c: &str) -> RegexAST {
+        RegexAST::Char(c.to_string())
+    }
+
+    fn star(node: RegexAST) -> RegexAST {
+        RegexAST::Star(Box::new(node))
+    }
+
+    fn class(symbols: &[&str]) -> std::collections::HashSet<String> {
+        symbols.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn compile(node: RegexAST, macros: &HashMap<String, RegexAST>, strict: bool) -> Result<VectorFst<TropicalWeight>> {
+        node_fst(symt("abc"), macros, LinearOptions { strict, ..Default::default() }, node)
+    }
+
+    /// Checks that the FST of `node` reads each of `accepted` and none of
+    /// `rejected`, and returns the label pairs it has.
+    fn matches(node: RegexAST, accepted: &[&str], rejected: &[&str]) -> BTreeSet<(Label, Label)> {
+        let symt = symt("abc");
+        let fst = compile(node.clone(), &HashMap::new(), false).unwrap();
+        for input in accepted {
+            assert!(accepts(&symt, &fst, input), "{:?} does not accept {:?}", node, input);
+        }
+        for input in rejected {
+            assert!(!accepts(&symt, &fst, input), "{:?} accepts {:?}", node, input);
+        }
+        label_pairs(&fst)
+    }
+
+    // With symt("abc"), # is 1, a 2, b 3 and c 4.
+
+    #[test]
+    fn test_char() {
+        assert_eq!(matches(ch("a"), &["a"], &["", "b", "aa"]), [(2, 2)].into_iter().collect());
+        // A missing symbol is epsilon, or an error with strict.
+        assert!(matches(ch("q"), &[""], &["a"]).is_empty());
+        let err = compile(ch("q"), &HashMap::new(), true).unwrap_err();
+        assert!(err.to_string().contains("'q'"), "{}", err);
+    }
+
+    #[test]
+    fn test_boundary() {
+        assert_eq!(matches(RegexAST::Boundary, &["#"], &["", "a", "##"]), [(1, 1)].into_iter().collect());
+    }
+
+    #[test]
+    fn test_class() {
+        let labels = matches(RegexAST::Class(class(&["a", "b"])), &["a", "b"], &["", "c", "ab", "#"]);
+        assert_eq!(labels, [(2, 2), (3, 3)].into_iter().collect());
+        assert!(compile(RegexAST::Class(class(&["a", "q"])), &HashMap::new(), true).is_err());
+    }
+
+    #[test]
+    fn test_class_complement() {
+        // Neither a boundary nor epsilon is in a complement.
+        let labels = matches(RegexAST::ClassComplement(class(&["a"])), &["b", "c"], &["", "a", "#", "bc"]);
+        assert_eq!(labels, [(3, 3), (4, 4)].into_iter().collect());
+    }
+
+    #[test]
+    fn test_star_plus_and_option() {
+        let a = [(2, 2)].into_iter().collect::<BTreeSet<_>>();
+        assert_eq!(matches(star(ch("a")), &["", "a", "aaa"], &["b", "ab"]), a);
+        assert_eq!(matches(RegexAST::Plus(Box::new(ch("a"))), &["a", "aaa"], &["", "b"]), a);
+        assert_eq!(matches(RegexAST::Option(Box::new(ch("a"))), &["", "a"], &["aa", "b"]), a);
+    }
+
+    #[test]
+    fn test_disjunction_of_groups() {
+        let node = RegexAST::Disjunction(vec![RegexAST::Group(vec![ch("a"), ch("b")]), RegexAST::Group(vec![ch("c")])]);
+        let labels = matches(node, &["ab", "c"], &["", "a", "abc", "cab"]);
+        assert_eq!(labels, [(2, 2), (3, 3), (4, 4)].into_iter().collect());
+    }
+
+    #[test]
+    fn test_macro() {
+        let symt = symt("abc");
+        let macros = HashMap::from([("v".to_string(), RegexAST::Class(class(&["a", "b"])))]);
+        let fst = compile(RegexAST::Group(vec![RegexAST::Macro("v".to_string()), ch("c")]), &macros, false).unwrap();
+        assert!(accepts(&symt, &fst, "ac") && accepts(&symt, &fst, "bc"));
+        assert!(!accepts(&symt, &fst, "cc"));
+        // An undefined macro matches nothing, or is an error with strict.
+        assert!(matches(RegexAST::Macro("w".to_string()), &[""], &["a"]).is_empty());
+        assert!(compile(RegexAST::Macro("w".to_string()), &macros, true).is_err());
+    }
+
+    /// Weights of the `n` best ways `(aa|a)+` matches `aaaa`.
+    fn nbest_repetition_weights(closure_weight: f32, n: usize) -> Vec<f32> {
+        use parserule::rulefst::string_to_linear_automaton;
+        use rustfst::prelude::{shortest_path_with_config, ShortestPathConfig};
+
+        let symt = Arc::new(rustfst::symt!["#", "a"]);
+        let a = || RegexAST::Char("a".to_string());
+        let node = RegexAST::Plus(Box::new(RegexAST::Disjunction(vec![
+            RegexAST::Group(vec![a(), a()]),
+            RegexAST::Group(vec![a()]),
+        ])));
+        let opts = LinearOptions { closure_weight, ..Default::default() };
+        let mut fst = node_fst(symt.clone(), &HashMap::new(), opts, node).unwrap();
+        let mut acc = string_to_linear_automaton(symt, "aaaa");
+        tr_sort(&mut acc, OLabelCompare {});
+        tr_sort(&mut fst, ILabelCompare {});
+        let matched: VectorFst<TropicalWeight> = compose(acc, fst).unwrap();
+        let nbest: VectorFst<TropicalWeight> =
+            shortest_path_with_config(&matched, ShortestPathConfig::default().with_nshortest(n)).unwrap();
+        let mut weights: Vec<f32> = nbest.paths_iter().map(|p| *p.weight.value()).collect();
+        weights.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        weights
+    }
+
+    #[test]
+    fn test_closure_weight_prefers_fewest_repetitions() {
+        // aa+aa (2 repetitions), then aa+a+a in three orders (3), then a+a+a+a (4).
+        assert_eq!(nbest_repetition_weights(0.5, 5), [0.5, 1.0, 1.0, 1.0, 1.5]);
+        assert!(nbest_repetition_weights(0.0, 5).iter().all(|&w| w == 0.0));
+    }
+
+    #[test]
+    fn test_closure_of_epsilon_accepting_node_has_no_epsilon_cycle() {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b", "c"]);
+        // (c?)* matches epsilon in any number of ways.
+        let context = star(RegexAST::Option(Box::new(ch("c"))));
+        for closure_weight in [0.0, 0.5] {
+            let opts = LinearOptions { closure_weight, ..Default::default() };
+            let mut fst = node_fst(symt.clone(), &HashMap::new(), opts, context.clone()).unwrap();
+            assert!(!has_epsilon_cycle(&fst).unwrap());
+            tr_sort(&mut fst, ILabelCompare {});
+            let lattice = parserule::rulefst::apply_fst_to_string(symt.clone(), fst, "cc".to_string()).unwrap();
+            assert!(!parserule::rulefst::is_cyclic(&lattice));
+            let outputs = crate::decode::decode_distinct_outputs(&lattice, None, &crate::ranking::Lexicographic, |l| crate::decode::display_labels(&symt, l)).unwrap();
+            assert_eq!(outputs.iter().map(|(_, o)| o.as_str()).collect::<Vec<_>>(), ["cc"]);
+        }
+    }
+
+    #[test]
+    fn test_has_epsilon_cycle() {
+        let mut fst: VectorFst<TropicalWeight> = fst![1 => 2];
+        assert!(!has_epsilon_cycle(&fst).unwrap());
+        fst.add_tr(1, Tr::new(0, 0, 0.0, 0)).unwrap();
+        // Cycle through a labelled transition.
+        assert!(!has_epsilon_cycle(&fst).unwrap());
+        fst.add_tr(0, Tr::new(0, 0, 0.0, 1)).unwrap();
+        assert!(has_epsilon_cycle(&fst).unwrap());
+    }
+}
@@ -0,0 +1,62 @@
+use rustfst::prelude::{MutableFst, StateIterator, TropicalWeight, VectorFst};
+use rustfst::Tr;
+
+/// `fst` with every output label replaced by epsilon: what it reads, deleted.
+pub(super) fn output_to_epsilons(fst: VectorFst<TropicalWeight>) -> VectorFst<TropicalWeight> {
+    let mut fst2 = fst.clone();
+    for state in fst2.states_iter() {
+        let trs: Vec<Tr<TropicalWeight>> = fst2.pop_trs(state).unwrap_or_default().clone();
+        for tr in trs.iter() {
+            fst2.emplace_tr(state, tr.ilabel, 0, tr.weight, tr.nextstate)
+                .inspect_err(|e| {
+                    println!(
+                        "{e}: Cannot emplace transition from {state} to {}.",
+                        tr.nextstate
+                    )
+                })
+                .unwrap_or(());
+        }
+    }
+    fst2
+}
+
+/// `fst` with every input label replaced by epsilon: what it writes, inserted.
+pub(super) fn input_to_epsilons(fst: VectorFst<TropicalWeight>) -> VectorFst<TropicalWeight> {
+    let mut fst2 = fst.clone();
+    for state in fst2.states_iter() {
+        let trs: Vec<Tr<TropicalWeight>> = fst2.pop_trs(state).unwrap_or_default().clone();
+        for tr in trs.iter() {
+            fst2.emplace_tr(state, 0, tr.olabel, tr.weight, tr.nextstate)
+                .inspect_err(|e| {
+                    println!(
+                        "{e}: Cannot emplace transition from {state} to {}.",
+                        tr.nextstate
+                    )
+                })
+                .unwrap_or(());
+        }
+    }
+    fst2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustfst::fst;
+    use rustfst::prelude::Fst;
+    use rustfst::utils::transducer;
+    use rustfst::Semiring;
+
+    use crate::rewrite::testing::label_pairs;
+
+    #[test]
+    fn test_projections_keep_one_side() {
+        let fst: VectorFst<TropicalWeight> = fst![1, 2 => 3, 4; 0.5];
+        assert_eq!(label_pairs(&output_to_epsilons(fst.clone())), [(1, 0), (2, 0)].into_iter().collect());
+        assert_eq!(label_pairs(&input_to_epsilons(fst.clone())), [(0, 3), (0, 4)].into_iter().collect());
+        // The paths, and their weights, are the same.
+        let weights = |fst: &VectorFst<TropicalWeight>| fst.paths_iter().map(|p| p.weight).collect::<Vec<_>>();
+        assert_eq!(weights(&output_to_epsilons(fst.clone())), weights(&fst));
+        assert_eq!(weights(&input_to_epsilons(fst.clone())), weights(&fst));
+    }
+}
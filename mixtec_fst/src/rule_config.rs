@@ -0,0 +1,130 @@
+//! Settings for one rule file, kept beside it rather than in the rule syntax:
+//! a rule file `<name>.txt` may have a sibling `<name>.toml`, read wherever
+//! the file is loaded. Without one, every setting has its default. The keys,
+//! all optional:
+//!
+//! ```toml
+//! # Added to the weight of every path through the file, as
+//! # `--weight-offset <name>.txt=<offset>` does; the flag wins if both are
+//! # given. A negative offset makes the file outrank the others.
+//! weight_offset = -0.5
+//!
+//! # Whether the rules of the file must apply: a word an obligatory rule can
+//! # rewrite is never left unchanged by it (though it may leave other sites
+//! # in the same word alone). By default a rule may always be skipped.
+//! obligatory = true
+//!
+//! # Which way the rules scan the word: "left-to-right" (the default), where
+//! # a left context is matched against what the rule has already written,
+//! # or "right-to-left", where the right context is.
+//! direction = "right-to-left"
+//! ```
+//!
+//! Only the cascade (`--application sequential`) applies rules obligatorily
+//! or in a direction; compiling a file any other way with either set is an
+//! error.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+/// The way the rules of a file scan the word.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleDirection {
+    #[default]
+    LeftToRight,
+    RightToLeft,
+}
+
+/// The settings of a rule file (see the module documentation).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuleFileConfig {
+    pub weight_offset: Option<f32>,
+    pub obligatory: bool,
+    pub direction: RuleDirection,
+}
+
+/// The settings file of the rule file at `path`.
+pub fn config_path(path: &Path) -> PathBuf {
+    path.with_extension("toml")
+}
+
+/// Whether `path` is a settings file rather than a rule file.
+pub fn is_config_file(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "toml")
+}
+
+impl RuleFileConfig {
+    /// The settings of the rule file at `path`: those of its `.toml`, or the
+    /// defaults if it has none.
+    pub fn load(path: &Path) -> Result<Self> {
+        let config = config_path(path);
+        if !config.is_file() {
+            return Ok(RuleFileConfig::default());
+        }
+        let text = std::fs::read_to_string(&config).with_context(|| format!("Failed to read {}", config.display()))?;
+        Self::parse(&text).with_context(|| format!("Failed to parse {}", config.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let config: RuleFileConfig = toml::from_str(text)?;
+        if let Some(offset) = config.weight_offset
+            && !offset.is_finite()
+        {
+            bail!("weight_offset {} is not finite", offset);
+        }
+        Ok(config)
+    }
+
+    /// Whether the rules are compiled any differently from the defaults.
+    pub fn changes_rules(&self) -> bool {
+        self.obligatory || self.direction != RuleDirection::default()
+    }
+
+    /// Fail if the settings change how the rules of `file` are compiled,
+    /// which `how` (the way it is compiled) has no notion of.
+    pub fn require_cascade(&self, file: &str, how: &str) -> Result<()> {
+        if self.changes_rules() {
+            bail!(
+                "{} sets obligatory or direction, which only the cascade (--application sequential) applies, not {}",
+                config_path(Path::new(file)).display(),
+                how
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_parse_and_defaults() {
+        assert_eq!(RuleFileConfig::parse("").unwrap(), RuleFileConfig::default());
+        let config = RuleFileConfig::parse("weight_offset = -2\nobligatory = true\ndirection = \"right-to-left\"\n").unwrap();
+        assert_eq!(config, RuleFileConfig { weight_offset: Some(-2.0), obligatory: true, direction: RuleDirection::RightToLeft });
+        assert!(config.changes_rules());
+        assert!(!RuleFileConfig { weight_offset: Some(1.0), ..Default::default() }.changes_rules());
+        for bad in ["weight = 1", "direction = \"upwards\"", "obligatory = 1", "weight_offset = nan"] {
+            assert!(RuleFileConfig::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_load_from_sibling() {
+        let dir = TempDir::new("rule-config");
+        let rules = dir.join("from_4.txt");
+        assert_eq!(RuleFileConfig::load(&rules).unwrap(), RuleFileConfig::default());
+        std::fs::write(dir.join("from_4.toml"), "obligatory = true\n").unwrap();
+        assert!(RuleFileConfig::load(&rules).unwrap().obligatory);
+        std::fs::write(dir.join("from_4.toml"), "obligatory = \"yes\"\n").unwrap();
+        let err = format!("{:#}", RuleFileConfig::load(&rules).unwrap_err());
+        assert!(err.contains("from_4.toml"), "{}", err);
+        assert!(is_config_file(&dir.join("from_4.toml")) && !is_config_file(&rules));
+    }
+}
@@ -0,0 +1,1011 @@
+//! Loading rule scripts from disk, and compiling them one rule at a time.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use itertools::Itertools;
+use parserule::ruleparse::{self, RegexAST, RewriteRule, Statement};
+use parserule::rulefst::{self, symbol_labels};
+use rustfst::algorithms::reverse;
+use rustfst::prelude::compose::compose;
+use rustfst::prelude::union::union;
+use rustfst::prelude::{
+    connect, project, tr_sort, CoreFst, ExpandedFst, Fst, ILabelCompare, MutableFst, OLabelCompare, ProjectType,
+    StateIterator, TropicalWeight, VectorFst,
+};
+use rustfst::{Label, Semiring, StateId, SymbolTable, Tr, EPS_LABEL};
+
+use crate::analysis::DEFAULT_MORPH_BOUNDARY;
+use crate::boundary::{mark_written_boundaries, restore_boundaries, with_internal_boundary};
+use crate::composition::{sorted_compose, ComposeFilter, ComposeOptions};
+use crate::profile::profile_span;
+use crate::rule_config::{is_config_file, RuleDirection, RuleFileConfig};
+use crate::simultaneous::complement;
+use crate::status::internal;
+use crate::style::warn;
+
+/// Largest file read as a rule script. Rule scripts are a few kilobytes; a
+/// larger file in a rules directory is a mistake, such as a built FST.
+pub const MAX_SCRIPT_BYTES: u64 = 4 * 1024 * 1024;
+
+/// The rule files in `dir`, sorted by path so builds are reproducible. A file
+/// that is not a rule script (see [`read_script_text`]) is an error, or with
+/// `skip_bad_files` is left out with a warning. The settings of rule files
+/// (see [`crate::rule_config`]) are not rule files themselves.
+pub fn list_rule_files(dir: &Path, skip_bad_files: bool) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read rules directory {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_file() && !is_config_file(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    let mut skipped = 0;
+    let mut kept = Vec::new();
+    for path in files {
+        match read_script_text(&path) {
+            Ok(_) => kept.push(path),
+            Err(e) if skip_bad_files => {
+                warn(format!("Warning: {:#}; skipping it", e));
+                skipped += 1;
+            }
+            Err(e) => bail!("{:#} (--skip-bad-files leaves such files out)", e),
+        }
+    }
+    if skipped > 0 {
+        warn(format!("Warning: skipped {} of the files in {} that are not rule scripts", skipped, dir.display()));
+    }
+    Ok(kept)
+}
+
+/// The text of the rule script at `path`. A file over [`MAX_SCRIPT_BYTES`], or
+/// that is not UTF-8 text, is refused, naming the file and, for one that is not
+/// text, the offset of its first bad byte.
+pub fn read_script_text(path: &Path) -> Result<String> {
+    let size = std::fs::metadata(path).map_err(|e| anyhow!("Failed to read script {}: {}", path.display(), e))?.len();
+    if size > MAX_SCRIPT_BYTES {
+        bail!("{} is {} bytes, more than a rule script could be ({} bytes at most)", path.display(), size, MAX_SCRIPT_BYTES);
+    }
+    let bytes = std::fs::read(path).map_err(|e| anyhow!("Failed to read script {}: {}", path.display(), e))?;
+    let text = String::from_utf8(bytes)
+        .map_err(|e| anyhow!("{} is not a text file: invalid UTF-8 at byte offset {}", path.display(), e.utf8_error().valid_up_to()))?;
+    if let Some(offset) = text.find('\0') {
+        bail!("{} is not a text file: NUL byte at byte offset {}", path.display(), offset);
+    }
+    Ok(text)
+}
+
+/// Read the source text of a rule script, splicing in the contents of any
+/// `@include "other.txt"` lines in place. Include paths are resolved relative
+/// to the including file; an include cycle is an error.
+pub fn read_script_source(path: &Path) -> Result<String> {
+    let mut out = String::new();
+    splice_includes(path, &mut Vec::new(), &mut out)?;
+    Ok(out)
+}
+
+/// The path named by an `@include "..."` directive line, if `line` is one.
+fn include_directive(line: &str) -> Option<&str> {
+    let rest = line.trim().strip_prefix("@include")?;
+    let rest = rest.trim_start();
+    rest.strip_prefix('"')?.strip_suffix('"')
+}
+
+fn splice_includes(path: &Path, stack: &mut Vec<PathBuf>, out: &mut String) -> Result<()> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| anyhow!("Failed to read script {}: {}", path.display(), e))?;
+    if let Some(i) = stack.iter().position(|p| p == &canonical) {
+        let cycle = stack[i..].iter().chain([&canonical]).map(|p| p.display().to_string()).join(" -> ");
+        return Err(anyhow!("Include cycle: {}", cycle));
+    }
+    let raw_script = read_script_text(path)?;
+    stack.push(canonical);
+    for line in raw_script.split_inclusive('\n') {
+        match include_directive(line) {
+            Some(included) => {
+                let included = path.parent().unwrap_or(Path::new("")).join(included);
+                splice_includes(&included, stack, out)?;
+                if !out.is_empty() && !out.ends_with('\n') {
+                    out.push('\n');
+                }
+            }
+            None => out.push_str(line),
+        }
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// The cost of applying a rule, from a weight annotation at the end of its
+/// line: a tropical cost (`:: 2.5`) or a probability (`:: p=0.8`), which costs
+/// -ln(p).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RuleCost {
+    pub cost: f32,
+    /// The probability the cost was given as, if it was.
+    pub probability: Option<f32>,
+}
+
+impl RuleCost {
+    /// Parse the text of an annotation, after its `::`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if let Some(p) = s.strip_prefix("p=") {
+            let p: f32 = p.trim().parse().map_err(|e| anyhow!("Invalid probability '{}': {}", p.trim(), e))?;
+            if !(p > 0.0 && p <= 1.0) {
+                bail!("Probability {} is not in (0, 1]", p);
+            }
+            return Ok(RuleCost { cost: -p.ln(), probability: Some(p) });
+        }
+        let cost: f32 = s.parse().map_err(|e| anyhow!("Invalid cost '{}': {}", s, e))?;
+        if !cost.is_finite() {
+            bail!("Cost {} is not finite", cost);
+        }
+        Ok(RuleCost { cost, probability: None })
+    }
+}
+
+/// A parsed rule script, with the costs and names annotated on its rules.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Script {
+    pub statements: Vec<Statement>,
+    /// Costs by index into `statements`.
+    pub costs: HashMap<usize, RuleCost>,
+    /// Names (`% @name`) by index into `statements`.
+    pub names: HashMap<usize, String>,
+    /// The settings of the script's `.toml` (see [`crate::rule_config`]).
+    pub config: RuleFileConfig,
+}
+
+impl From<Vec<Statement>> for Script {
+    fn from(statements: Vec<Statement>) -> Self {
+        Script { statements, ..Default::default() }
+    }
+}
+
+impl Script {
+    /// The script cut down to its rule named `name`, keeping the macro
+    /// definitions it may use, if it has such a rule.
+    pub fn only_rule(&self, name: &str) -> Option<Script> {
+        let rule = self.names.iter().find(|(_, n)| *n == name).map(|(&i, _)| i)?;
+        let mut script = Script { config: self.config, ..Script::default() };
+        for (i, statement) in self.statements.iter().enumerate() {
+            if i == rule {
+                if let Some(&cost) = self.costs.get(&i) {
+                    script.costs.insert(script.statements.len(), cost);
+                }
+                script.names.insert(script.statements.len(), name.to_string());
+            } else if !matches!(statement, Statement::MacroDef(_)) {
+                continue;
+            }
+            script.statements.push(statement.clone());
+        }
+        Some(script)
+    }
+}
+
+/// Split a weight annotation off a line: a `::` with whitespace on both sides
+/// (unlike the `::` of a macro), ending the line or coming before its comment.
+fn split_cost_annotation(line: &str) -> Result<(String, Option<RuleCost>)> {
+    let (code, comment) = match line.find('%') {
+        Some(i) => line.split_at(i),
+        None => (line, ""),
+    };
+    let chars: Vec<(usize, char)> = code.char_indices().collect();
+    let separator = (1..chars.len().saturating_sub(2)).rev().find(|&k| {
+        chars[k].1 == ':' && chars[k + 1].1 == ':' && chars[k - 1].1.is_whitespace() && chars[k + 2].1.is_whitespace()
+    });
+    let Some(k) = separator else {
+        return Ok((line.to_string(), None));
+    };
+    let (rule, annotation) = (&code[..chars[k].0], &code[chars[k + 2].0..]);
+    let cost = RuleCost::parse(annotation).with_context(|| format!("In the weight annotation of '{}'", line.trim()))?;
+    let rule = rule.trim_end();
+    Ok((if comment.is_empty() { rule.to_string() } else { format!("{} {}", rule, comment) }, Some(cost)))
+}
+
+/// The name given to the statement on `line` by a comment after it that opens
+/// with `@name`, as in `a -> b / _ c % @voicing before c`.
+fn rule_name(line: &str) -> Option<&str> {
+    let (code, comment) = line.split_once('%')?;
+    let name = comment.trim_start().strip_prefix('@')?;
+    let end = name.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-')).unwrap_or(name.len());
+    (!code.trim().is_empty() && end > 0).then_some(&name[..end])
+}
+
+/// Parse the source text of the rule script at `path`, taking the weight
+/// annotations off its rules first.
+pub fn parse_script_source(path: &Path, raw_script: &str) -> Result<Script> {
+    // The parser takes one statement per non-blank line, with an optional
+    // comment after it.
+    let mut stripped = String::new();
+    let mut line_numbers = Vec::new();
+    let mut annotated = HashMap::new();
+    let mut named = HashMap::new();
+    for (i, (n, line)) in raw_script.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()).enumerate() {
+        let (line, cost) = split_cost_annotation(line).with_context(|| format!("Failed to parse script {}", path.display()))?;
+        if let Some(cost) = cost {
+            annotated.insert(i, cost);
+        }
+        if let Some(name) = rule_name(&line) {
+            named.insert(i, name.to_string());
+        }
+        stripped.push_str(&line);
+        stripped.push('\n');
+        line_numbers.push(n + 1);
+    }
+    let (rest, (statements, _)) = ruleparse::parse_script(&stripped)
+        .map_err(|e| anyhow!("Failed to parse script {}: {}", path.display(), e))?;
+    // The parser stops at the first line it cannot read, rather than failing,
+    // so say where the script was cut short.
+    let rest = rest.trim_start();
+    if !rest.is_empty() {
+        let i = stripped[..stripped.len() - rest.len()].matches('\n').count();
+        let line = line_numbers.get(i).map_or(raw_script.lines().count(), |&n| n);
+        let text = raw_script.lines().nth(line - 1).unwrap_or("").trim();
+        warn(format!("Warning: line {} of {} ('{}') is not a rule, macro or comment; it and the lines after it are left out", line, path.display(), text));
+    }
+    let mut costs = HashMap::new();
+    for (i, cost) in annotated {
+        match statements.get(i) {
+            Some(Statement::Rule(_)) => {
+                costs.insert(i, cost);
+            }
+            Some(_) => bail!("Weight annotation on statement {} of {}, which is not a rule", i + 1, path.display()),
+            None => {}
+        }
+    }
+    let mut names: HashMap<usize, String> = HashMap::new();
+    for (i, name) in named.into_iter().sorted() {
+        match statements.get(i) {
+            Some(Statement::Rule(_)) => {}
+            Some(_) => bail!("Rule name @{} on statement {} of {}, which is not a rule", name, i + 1, path.display()),
+            None => continue,
+        }
+        if let Some((j, _)) = names.iter().find(|(_, n)| **n == name) {
+            bail!("Rules {} and {} of {} are both named @{}", j + 1, i + 1, path.display(), name);
+        }
+        names.insert(i, name);
+    }
+    Ok(Script { statements, costs, names, config: RuleFileConfig::default() })
+}
+
+/// Read and parse a rule script, with the settings of its `.toml`.
+pub fn load_script(path: &Path) -> Result<Script> {
+    let mut script = parse_script_source(path, &read_script_source(path)?)?;
+    script.config = RuleFileConfig::load(path)?;
+    Ok(script)
+}
+
+/// `fst`, with `cost` added to every path that changes its input. The cost is
+/// charged once per path, however many changes the path makes, so that the
+/// states are split into those before and after the first change.
+pub fn with_rewrite_cost(fst: &VectorFst<TropicalWeight>, cost: f32) -> Result<VectorFst<TropicalWeight>> {
+    split_at_first_change(fst, cost, true)
+}
+
+/// The paths of `fst` that change their input.
+fn changing_paths(fst: &VectorFst<TropicalWeight>) -> Result<VectorFst<TropicalWeight>> {
+    split_at_first_change(fst, 0.0, false)
+}
+
+/// `fst` with its states split into those before and after the first change
+/// to the input, which costs `cost`. The states before it stay final only
+/// with `unchanged`.
+fn split_at_first_change(fst: &VectorFst<TropicalWeight>, cost: f32, unchanged: bool) -> Result<VectorFst<TropicalWeight>> {
+    let n = fst.num_states() as StateId;
+    let mut out: VectorFst<TropicalWeight> = VectorFst::new();
+    out.add_states(2 * n as usize);
+    if let Some(start) = fst.start() {
+        out.set_start(start)?;
+    }
+    for s in fst.states_iter() {
+        if let Some(w) = fst.final_weight(s)? {
+            if unchanged {
+                out.set_final(s, w)?;
+            }
+            out.set_final(s + n, w)?;
+        }
+        for tr in fst.get_trs(s)?.iter() {
+            let mut before = tr.clone();
+            if tr.ilabel != tr.olabel {
+                before.weight = tr.weight.times(TropicalWeight::new(cost))?;
+                before.nextstate += n;
+            }
+            out.add_tr(s, before)?;
+            let mut after = tr.clone();
+            after.nextstate += n;
+            out.add_tr(s + n, after)?;
+        }
+    }
+    if let Some(symt) = fst.input_symbols() {
+        out.set_input_symbols(symt.clone());
+    }
+    if let Some(symt) = fst.output_symbols() {
+        out.set_output_symbols(symt.clone());
+    }
+    internal(connect(&mut out))?;
+    Ok(out)
+}
+
+/// The compiled rule `fst` made obligatory (see [`crate::rule_config`]): its
+/// paths that change their input, and those that do not for only the inputs
+/// none changes. `symt` is the alphabet of the inputs.
+pub fn obligatory(fst: &VectorFst<TropicalWeight>, symt: &SymbolTable) -> Result<VectorFst<TropicalWeight>> {
+    let changing = changing_paths(fst)?;
+    let mut changed: VectorFst<TropicalWeight> = changing.clone();
+    project(&mut changed, ProjectType::ProjectInput);
+    // Only the inputs matter, and an unweighted acceptor always determinizes.
+    for s in changed.states_iter().collect::<Vec<_>>() {
+        let trs = changed.pop_trs(s)?;
+        for tr in trs {
+            changed.add_tr(s, Tr::new(tr.ilabel, tr.olabel, TropicalWeight::one(), tr.nextstate))?;
+        }
+        if changed.is_final(s)? {
+            changed.set_final(s, TropicalWeight::one())?;
+        }
+    }
+    let alphabet: Vec<Label> = symt.labels().filter(|&l| l != EPS_LABEL).collect();
+    let mut unchanged = complement(&changed, &alphabet)?;
+    let mut fst = fst.clone();
+    tr_sort(&mut unchanged, OLabelCompare {});
+    tr_sort(&mut fst, ILabelCompare {});
+    let mut kept: VectorFst<TropicalWeight> = internal(compose(unchanged, fst.clone()))?;
+    union(&mut kept, &changing)?;
+    internal(connect(&mut kept))?;
+    if let Some(symt) = fst.input_symbols() {
+        kept.set_input_symbols(symt.clone());
+    }
+    if let Some(symt) = fst.output_symbols() {
+        kept.set_output_symbols(symt.clone());
+    }
+    Ok(kept)
+}
+
+/// Compile `rule` to scan the word in `direction`. From right to left, it is
+/// the reversed rule (see [`reversed_node`]) compiled from left to right and
+/// the FST reversed.
+fn directed_rule_fst(
+    symt: Arc<SymbolTable>,
+    macros: &HashMap<String, RegexAST>,
+    rule: RewriteRule,
+    direction: RuleDirection,
+) -> Result<VectorFst<TropicalWeight>> {
+    if direction == RuleDirection::LeftToRight {
+        return rulefst::rule_fst(symt, macros, rule);
+    }
+    let macros: HashMap<String, RegexAST> = macros.iter().map(|(m, def)| (m.clone(), reversed_node(&symt, def.clone()))).collect();
+    let rule = RewriteRule {
+        left: reversed_node(&symt, rule.right),
+        right: reversed_node(&symt, rule.left),
+        source: reversed_node(&symt, rule.source),
+        target: reversed_node(&symt, rule.target),
+    };
+    let fst = rulefst::rule_fst(symt, &macros, rule)?;
+    let mut reversed: VectorFst<TropicalWeight> = reverse(&fst)?;
+    if let Some(symt) = fst.input_symbols() {
+        reversed.set_input_symbols(symt.clone());
+    }
+    if let Some(symt) = fst.output_symbols() {
+        reversed.set_output_symbols(symt.clone());
+    }
+    Ok(reversed)
+}
+
+/// `node` matching the reverse of what it matched. A symbol `symt` only has
+/// as several labels is spelled out with them, reversed, as the reversed FST
+/// will read them back in order.
+fn reversed_node(symt: &SymbolTable, node: RegexAST) -> RegexAST {
+    let spelled = |s: &str| {
+        symbol_labels(symt, s).filter(|labels| labels.len() > 1).map(|labels| {
+            RegexAST::Group(labels.iter().rev().map(|&l| RegexAST::Char(symt.get_symbol(l).unwrap_or_default().to_string())).collect())
+        })
+    };
+    let reversed_all = |nodes: Vec<RegexAST>| nodes.into_iter().map(|n| reversed_node(symt, n)).collect();
+    match node {
+        RegexAST::Char(c) => spelled(&c).unwrap_or(RegexAST::Char(c)),
+        RegexAST::Class(class) if class.iter().any(|s| spelled(s).is_some()) => {
+            let (apart, single): (Vec<String>, Vec<String>) = class.into_iter().partition(|s| spelled(s).is_some());
+            let mut alternatives = vec![RegexAST::Class(single.into_iter().collect())];
+            alternatives.extend(apart.iter().filter_map(|s| spelled(s)));
+            RegexAST::Disjunction(alternatives)
+        }
+        RegexAST::Group(nodes) => RegexAST::Group(reversed_all(nodes.into_iter().rev().collect())),
+        RegexAST::Disjunction(nodes) => RegexAST::Disjunction(reversed_all(nodes)),
+        RegexAST::Process(stages) => reversed_node(symt, RegexAST::process_symbols(&stages)),
+        RegexAST::Option(n) => RegexAST::Option(Box::new(reversed_node(symt, *n))),
+        RegexAST::Star(n) => RegexAST::Star(Box::new(reversed_node(symt, *n))),
+        RegexAST::Plus(n) => RegexAST::Plus(Box::new(reversed_node(symt, *n))),
+        other => other,
+    }
+}
+
+/// What a compiled rule can do, judged from its transitions alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum RuleEffect {
+    /// Some transition writes a different symbol than it reads.
+    Rewrites,
+    /// Every transition copies its input: the rule matches but never changes
+    /// anything.
+    IdentityOnly,
+    /// No path reaches a final state, so composing with the rule empties the
+    /// whole script.
+    Empty,
+}
+
+/// The effect of the compiled rule `fst`.
+pub fn rule_effect(fst: &VectorFst<TropicalWeight>) -> Result<RuleEffect> {
+    let mut connected = fst.clone();
+    internal(connect(&mut connected))?;
+    if connected.start().is_none() {
+        return Ok(RuleEffect::Empty);
+    }
+    for s in connected.states_iter() {
+        if connected.get_trs(s)?.iter().any(|tr| tr.ilabel != tr.olabel) {
+            return Ok(RuleEffect::Rewrites);
+        }
+    }
+    Ok(RuleEffect::IdentityOnly)
+}
+
+/// A rule that compiled to an empty or identity-only transducer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleNote {
+    pub file: String,
+    /// Index of the rule's statement in its script, from 1.
+    pub rule: usize,
+    pub effect: RuleEffect,
+}
+
+/// The empty and identity-only rules met while compiling, for the build
+/// summary. Empty rules are left out of what they would have been combined
+/// with, with a warning, or are an error if `strict`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleChecks {
+    pub strict: bool,
+    /// Warn when the probabilities of the variants of a rule sum to more than 1.
+    pub check_probabilities: bool,
+    pub notes: Vec<RuleNote>,
+    /// How many of the rules checked were kept.
+    pub kept: usize,
+}
+
+impl RuleChecks {
+    pub fn new(strict: bool) -> Self {
+        RuleChecks { strict, ..Default::default() }
+    }
+
+    /// With `check_probabilities`, warn about each group of variants in
+    /// `script` (rules with the same source and contexts) annotated with
+    /// probabilities that sum to more than 1, returning the warnings.
+    pub fn check_variant_probabilities(&self, file: &str, script: &Script) -> Vec<String> {
+        if !self.check_probabilities {
+            return Vec::new();
+        }
+        let mut variants: BTreeMap<String, Vec<(usize, f32)>> = BTreeMap::new();
+        for (i, statement) in script.statements.iter().enumerate() {
+            if let (Statement::Rule(rule), Some(p)) = (statement, script.costs.get(&i).and_then(|c| c.probability)) {
+                let key = format!("{:?} / {:?} _ {:?}", rule.source, rule.left, rule.right);
+                variants.entry(key).or_default().push((i + 1, p));
+            }
+        }
+        let mut warnings = Vec::new();
+        for group in variants.values().filter(|g| g.len() > 1) {
+            let total: f32 = group.iter().map(|(_, p)| p).sum();
+            if total > 1.0 + 1e-6 {
+                let rules = group.iter().map(|(i, _)| i.to_string()).join(", ");
+                let warning = format!("Warning: the probabilities of rules {} of {}, variants of one rule, sum to {}", rules, file, total);
+                warn(&warning);
+                warnings.push(warning);
+            }
+        }
+        warnings
+    }
+
+    /// If `strict`, fail if `rule` uses a macro that `macros` does not define,
+    /// directly or through another macro. Compiled, such a macro only matches
+    /// the empty string. `rule_name` says which rule it is.
+    pub fn check_macros_defined(&self, macros: &HashMap<String, RegexAST>, rule: &RewriteRule, rule_name: &str) -> Result<()> {
+        fn walk<'a>(macros: &'a HashMap<String, RegexAST>, node: &'a RegexAST, stack: &mut Vec<&'a str>, undefined: &mut Vec<&'a str>) {
+            match node {
+                RegexAST::Group(nodes) | RegexAST::Disjunction(nodes) | RegexAST::Process(nodes) => nodes.iter().for_each(|n| walk(macros, n, stack, undefined)),
+                RegexAST::Option(n) | RegexAST::Star(n) | RegexAST::Plus(n) => walk(macros, n, stack, undefined),
+                RegexAST::Macro(name) if stack.contains(&name.as_str()) => {}
+                RegexAST::Macro(name) => match macros.get(name) {
+                    Some(def) => {
+                        stack.push(name);
+                        walk(macros, def, stack, undefined);
+                        stack.pop();
+                    }
+                    None if !undefined.contains(&name.as_str()) => undefined.push(name),
+                    None => {}
+                },
+                RegexAST::Char(_) | RegexAST::Class(_) | RegexAST::ClassComplement(_) | RegexAST::Epsilon | RegexAST::Boundary | RegexAST::Comment => {}
+            }
+        }
+        if !self.strict {
+            return Ok(());
+        }
+        let mut undefined = Vec::new();
+        for node in [&rule.source, &rule.target, &rule.left, &rule.right] {
+            walk(macros, node, &mut Vec::new(), &mut undefined);
+        }
+        if !undefined.is_empty() {
+            let names = undefined.iter().map(|m| format!("::{}::", m)).join(", ");
+            bail!("{} uses {}, which the script does not define", rule_name, names);
+        }
+        Ok(())
+    }
+
+    /// Check the compiled rule `fst`, statement `rule` of `file`, and say
+    /// whether to keep it.
+    pub fn check(&mut self, file: &str, rule: usize, fst: &VectorFst<TropicalWeight>) -> Result<bool> {
+        let effect = rule_effect(fst)?;
+        if effect != RuleEffect::Rewrites {
+            self.note(RuleNote { file: file.to_string(), rule, effect })?;
+        }
+        let keep = effect != RuleEffect::Empty;
+        self.kept += keep as usize;
+        Ok(keep)
+    }
+
+    /// Report and record an empty or identity-only rule, as [`RuleChecks::check`]
+    /// does when it finds one.
+    pub fn note(&mut self, note: RuleNote) -> Result<()> {
+        let RuleNote { file, rule, effect } = &note;
+        match effect {
+            RuleEffect::Rewrites => return Ok(()),
+            RuleEffect::Empty if self.strict => bail!("Rule {} of {} compiled to an empty transducer", rule, file),
+            RuleEffect::Empty => warn(format!("Warning: rule {} of {} compiled to an empty transducer; leaving it out", rule, file)),
+            RuleEffect::IdentityOnly => println!("Note: rule {} of {} never changes its input", rule, file),
+        }
+        self.notes.push(note);
+        Ok(())
+    }
+
+    /// Checks with the same settings, and nothing recorded yet.
+    pub fn fork(&self) -> RuleChecks {
+        RuleChecks { notes: Vec::new(), kept: 0, ..self.clone() }
+    }
+
+    /// Record what the checks `other`, forked from these, found.
+    pub fn merge(&mut self, other: RuleChecks) {
+        self.notes.extend(other.notes);
+        self.kept += other.kept;
+    }
+
+    /// The rules found to have `effect`.
+    pub fn with_effect(&self, effect: RuleEffect) -> impl Iterator<Item = &RuleNote> {
+        self.notes.iter().filter(move |n| n.effect == effect)
+    }
+
+    /// The summary section listing empty and identity-only rules, or nothing
+    /// if there are none.
+    pub fn summary(&self) -> String {
+        let mut summary = String::new();
+        for (effect, what) in [(RuleEffect::Empty, "compiled to empty transducers"), (RuleEffect::IdentityOnly, "never change their input")] {
+            let notes: Vec<&RuleNote> = self.with_effect(effect).collect();
+            if notes.is_empty() {
+                continue;
+            }
+            summary.push_str(&format!("{} rules {}:\n", notes.len(), what));
+            for note in notes {
+                summary.push_str(&format!("  {} rule {}\n", note.file, note.rule));
+            }
+        }
+        summary
+    }
+}
+
+/// The macro rules write a morph boundary with, `::mb::`.
+pub const MORPH_BOUNDARY_MACRO: &str = "mb";
+
+/// The macros every script has without defining them: `::mb::`, the morph
+/// boundary (see [`crate::analysis`]). A script that defines one itself, say
+/// to write another symbol as the morph boundary, has its own definition.
+pub fn predefined_macros() -> HashMap<String, RegexAST> {
+    HashMap::from([(MORPH_BOUNDARY_MACRO.to_string(), RegexAST::Char(DEFAULT_MORPH_BOUNDARY.to_string()))])
+}
+
+/// The macros the rules of `statements` see: as in `compile_script`, the
+/// last definition of each, and the predefined macros (see
+/// [`predefined_macros`]) it does not define.
+pub fn script_macros(statements: &[Statement]) -> HashMap<String, RegexAST> {
+    let mut macros = predefined_macros();
+    macros.extend(statements.iter().filter_map(|s| match s {
+        Statement::MacroDef((mac, def)) => Some((mac.clone(), def.clone())),
+        _ => None,
+    }));
+    macros
+}
+
+/// The rules of a script, compiled one by one for a cascade.
+pub struct CascadeRules {
+    /// The symbol table the rules are compiled against, which has the internal
+    /// boundary (see [`crate::boundary`]).
+    pub symt: Arc<SymbolTable>,
+    /// Each rule with the index of its statement, its cost already charged.
+    pub rules: Vec<(usize, VectorFst<TropicalWeight>)>,
+}
+
+/// Fail if the target of `rule` uses a symbol missing from `symt`, directly or
+/// through `macros`. Compiled, such a symbol would become epsilon, so the rule
+/// would delete what it means to substitute. `rule_name` says which rule it is.
+pub fn check_target_symbols(symt: &SymbolTable, macros: &HashMap<String, RegexAST>, rule: &RewriteRule, rule_name: &str) -> Result<()> {
+    fn walk<'a>(symt: &SymbolTable, macros: &'a HashMap<String, RegexAST>, node: &'a RegexAST, stack: &mut Vec<&'a str>, missing: &mut Vec<String>) {
+        let mut check = |s: String| {
+            if symbol_labels(symt, &s).is_none() && !missing.contains(&s) {
+                missing.push(s);
+            }
+        };
+        match node {
+            RegexAST::Char(c) => check(c.clone()),
+            RegexAST::Class(class) => class.iter().sorted().for_each(|s| check(s.clone())),
+            RegexAST::Group(nodes) | RegexAST::Disjunction(nodes) => nodes.iter().for_each(|n| walk(symt, macros, n, stack, missing)),
+            RegexAST::Process(stages) => {
+                ["{", ">", "}"].into_iter().for_each(|s| check(s.to_string()));
+                stages.iter().for_each(|n| walk(symt, macros, n, stack, missing));
+            }
+            RegexAST::Option(n) | RegexAST::Star(n) | RegexAST::Plus(n) => walk(symt, macros, n, stack, missing),
+            // Undefined and cyclic macros are reported where the rule is compiled.
+            RegexAST::Macro(name) => {
+                if let Some(def) = macros.get(name).filter(|_| !stack.contains(&name.as_str())) {
+                    stack.push(name);
+                    walk(symt, macros, def, stack, missing);
+                    stack.pop();
+                }
+            }
+            RegexAST::ClassComplement(_) | RegexAST::Epsilon | RegexAST::Boundary | RegexAST::Comment => {}
+        }
+    }
+    let mut missing = Vec::new();
+    walk(symt, macros, &rule.target, &mut Vec::new(), &mut missing);
+    if !missing.is_empty() {
+        let symbols = missing.iter().map(|s| format!("'{}'", s)).join(", ");
+        bail!("The target of {} uses {}, not in the symbol table; the rule would delete rather than substitute", rule_name, symbols);
+    }
+    Ok(())
+}
+
+/// Compile the rules of a parsed script one by one, checking each with
+/// `checks` and leaving out empty ones. A rule with a cost charges it to the
+/// paths it changes (see [`with_rewrite_cost`]). `file` names the script in
+/// messages.
+pub fn compile_cascade_rules(
+    symt: Arc<SymbolTable>,
+    script: Script,
+    file: &str,
+    checks: &mut RuleChecks,
+) -> Result<CascadeRules> {
+    checks.check_variant_probabilities(file, &script);
+    let internal = with_internal_boundary(&symt);
+    let Script { statements, costs, config, .. } = script;
+    let script = mark_written_boundaries(statements)?;
+    let macros = script_macros(&script);
+    let mut rules = Vec::new();
+    for (i, statement) in script.into_iter().enumerate() {
+        let Statement::Rule(rule) = statement else { continue };
+        let rule_name = format!("rule {} of {}", i + 1, file);
+        checks.check_macros_defined(&macros, &rule, &rule_name)?;
+        check_target_symbols(&internal, &macros, &rule, &rule_name)?;
+        let mut rule_fst = directed_rule_fst(internal.clone(), &macros, rule, config.direction)
+            .with_context(|| format!("Failed to compile rule {} of {}", i + 1, file))?;
+        if config.obligatory {
+            rule_fst = obligatory(&rule_fst, &internal)?;
+        }
+        if !checks.check(file, i + 1, &rule_fst)? {
+            continue;
+        }
+        if let Some(cost) = costs.get(&i) {
+            rule_fst = with_rewrite_cost(&rule_fst, cost.cost)?;
+        }
+        rules.push((i, rule_fst));
+    }
+    Ok(CascadeRules { symt: internal, rules })
+}
+
+/// Compile a parsed rule script, dropping any states that lie on no complete path.
+/// Boundaries the rules write only match `#` in contexts once the whole script
+/// is compiled (see [`crate::boundary`]).
+///
+/// The rules are compiled as by [`compile_cascade_rules`] and composed in
+/// order by [`rulefst::compose_rules`], as [`rulefst::compile_script`] does.
+pub fn compile_rule_script(
+    symt: Arc<SymbolTable>,
+    script: Script,
+    file: &str,
+    checks: &mut RuleChecks,
+) -> Result<VectorFst<TropicalWeight>> {
+    let CascadeRules { symt: internal_symt, rules } = compile_cascade_rules(symt.clone(), script, file, checks)?;
+    let fsts = rules.into_iter().map(|(_, rule_fst)| rule_fst);
+    let mut fst = rulefst::compose_rules(internal_symt, fsts, |fst, rule_fst| {
+        let _span = profile_span!("compose_rule");
+        sorted_compose(fst, rule_fst, ComposeOptions { filter: ComposeFilter::AltSequence, connect: false })
+    })?;
+    restore_boundaries(&mut fst, symt)?;
+    internal(connect(&mut fst))?;
+    Ok(fst)
+}
+
+/// Read, parse and compile a single rule file, checking its rules with `checks`.
+pub fn compile_rule_file(symt: Arc<SymbolTable>, path: &Path, checks: &mut RuleChecks) -> Result<VectorFst<TropicalWeight>> {
+    compile_rule_script(symt, load_script(path)?, &path.display().to_string(), checks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustfst::prelude::MutableFst;
+    use rustfst::utils::transducer;
+    use rustfst::Semiring;
+
+    use crate::testutil::{root, TempDir};
+
+    fn write(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_files_that_are_not_scripts_are_named_or_skipped() {
+        let dir = root().join("tests/bad_files");
+        let err = format!("{:#}", list_rule_files(&dir, false).unwrap_err());
+        assert!(err.contains("stray.fst is not a text file: invalid UTF-8 at byte offset 0"), "{}", err);
+        assert_eq!(list_rule_files(&dir, true).unwrap(), [dir.join("rules.txt")]);
+
+        let dir = TempDir::new("rules-bad-files");
+        let nul = write(&dir, "nul.txt", "a -> b\0");
+        let err = read_script_text(&nul).unwrap_err().to_string();
+        assert!(err.contains("NUL byte at byte offset 6"), "{}", err);
+        let large = dir.join("large.txt");
+        std::fs::File::create(&large).unwrap().set_len(MAX_SCRIPT_BYTES + 1).unwrap();
+        let err = read_script_text(&large).unwrap_err().to_string();
+        assert!(err.contains("large.txt is 4194305 bytes"), "{}", err);
+        // An include is refused the same way.
+        let main = write(&dir, "main.txt", "@include \"nul.txt\"\n");
+        assert!(load_script(&main).unwrap_err().to_string().contains("NUL byte"));
+    }
+
+    #[test]
+    fn test_substitution_with_missing_target_symbol_is_an_error() {
+        let symt = Arc::new(rustfst::symt!["a", "b", "c"]);
+        let script = parse_script_source(Path::new("sub.txt"), "a -> c\nb -> [cq]\n").unwrap();
+        let Err(err) = compile_cascade_rules(symt, script, "sub.txt", &mut RuleChecks::default()) else {
+            panic!("compiled a rule with a missing target symbol");
+        };
+        assert_eq!(
+            err.to_string(),
+            "The target of rule 2 of sub.txt uses 'q', not in the symbol table; the rule would delete rather than substitute"
+        );
+    }
+
+    #[test]
+    fn test_include_directive() {
+        assert_eq!(include_directive("@include \"a.txt\"\n"), Some("a.txt"));
+        assert_eq!(include_directive("  @include\t\"sub/b.txt\"  "), Some("sub/b.txt"));
+        assert_eq!(include_directive("% @include \"a.txt\""), None);
+        assert_eq!(include_directive("@include a.txt"), None);
+    }
+
+    #[test]
+    fn test_includes_spliced_relative_to_including_file() {
+        let dir = TempDir::new("rules-include");
+        write(&dir, "sub/macros.txt", "::v:: = [aiueo]");
+        write(&dir, "sub/rules.txt", "@include \"macros.txt\"\na -> b / ::v:: _ \n");
+        let main = write(&dir, "main.txt", "% top\n@include \"sub/rules.txt\"\nb -> c / _ \n");
+        let source = read_script_source(&main).unwrap();
+        assert_eq!(source, "% top\n::v:: = [aiueo]\na -> b / ::v:: _ \nb -> c / _ \n");
+        assert!(load_script(&main).is_ok());
+    }
+
+    /// The outputs of `script`, with `config`, for `input`, each with its best weight.
+    fn configured_outputs(script: &str, config: RuleFileConfig, input: &str) -> BTreeMap<String, f32> {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b", "c", "-"]);
+        let script = Script { config, ..parse_script_source(Path::new("t.txt"), script).unwrap() };
+        let mut fst = compile_rule_script(symt.clone(), script, "t.txt", &mut RuleChecks::default()).unwrap();
+        tr_sort(&mut fst, ILabelCompare {});
+        let lattice = rulefst::apply_fst_to_string(symt.clone(), fst, input.to_string()).unwrap();
+        let mut outputs = BTreeMap::new();
+        for (w, out) in rulefst::decode_paths_through_fst(symt, lattice) {
+            let w = *w.value();
+            outputs.entry(out).and_modify(|b: &mut f32| *b = b.min(w)).or_insert(w);
+        }
+        outputs
+    }
+
+    #[test]
+    fn test_obligatory_rules_never_leave_a_match_unchanged() {
+        let rule = "a -> b / a _\n";
+        let optional = configured_outputs(rule, RuleFileConfig::default(), "#aaa#");
+        assert_eq!(optional.keys().collect::<Vec<_>>(), ["#aaa#", "#aab#", "#aba#"]);
+        let obligatory = RuleFileConfig { obligatory: true, ..Default::default() };
+        let rewritten = configured_outputs(rule, obligatory, "#aaa#");
+        assert_eq!(rewritten.keys().collect::<Vec<_>>(), ["#aab#", "#aba#"]);
+        assert_eq!(rewritten["#aba#"], optional["#aba#"]);
+        // A word the rule cannot rewrite goes through as it did.
+        assert_eq!(configured_outputs(rule, obligatory, "#ab#"), configured_outputs(rule, RuleFileConfig::default(), "#ab#"));
+    }
+
+    #[test]
+    fn test_right_to_left_rules_match_what_they_wrote_on_the_right() {
+        let right_to_left = RuleFileConfig { direction: RuleDirection::RightToLeft, ..Default::default() };
+        // From the left, the left context of the last a is the b the rule
+        // wrote before it; from the right, it is still an a.
+        let outputs = configured_outputs("a -> b / a _\n", right_to_left, "#aaa#");
+        assert_eq!(outputs.keys().collect::<Vec<_>>(), ["#aaa#", "#aab#", "#aba#", "#abb#"]);
+        // And the other way round for a right context.
+        let outputs = configured_outputs("a -> b / _ a\n", right_to_left, "#aaa#");
+        assert_eq!(outputs.keys().collect::<Vec<_>>(), ["#aaa#", "#aba#", "#baa#"]);
+        let obligatory = RuleFileConfig { obligatory: true, ..right_to_left };
+        assert_eq!(configured_outputs("a -> b / _ a\n", obligatory, "#aaa#").keys().collect::<Vec<_>>(), ["#aba#", "#baa#"]);
+    }
+
+    #[test]
+    fn test_morph_boundary_macro_writes_the_morph_boundary() {
+        let outputs = configured_outputs("0 -> ::mb:: / a _ b\n", RuleFileConfig::default(), "#ab#");
+        assert_eq!(outputs.keys().collect::<Vec<_>>(), ["#a-b#", "#ab#"]);
+        // And a later rule can match it like any other symbol.
+        let outputs = configured_outputs("0 -> ::mb:: / a _ b\nb -> c / ::mb:: _\n", RuleFileConfig::default(), "#ab#");
+        assert!(outputs.contains_key("#a-c#"), "{:?}", outputs);
+        // A script's own definition wins.
+        let outputs = configured_outputs("::mb:: = c\n0 -> ::mb:: / a _ b\n", RuleFileConfig::default(), "#ab#");
+        assert_eq!(outputs.keys().collect::<Vec<_>>(), ["#ab#", "#acb#"]);
+    }
+
+    #[test]
+    fn test_settings_files_are_not_rule_files() {
+        let dir = TempDir::new("rules-settings");
+        write(&dir, "rules.txt", "a -> b / _ \n");
+        write(&dir, "rules.toml", "obligatory = true\n");
+        assert_eq!(list_rule_files(&dir, false).unwrap(), [dir.join("rules.txt")]);
+        assert!(load_script(&dir.join("rules.txt")).unwrap().config.obligatory);
+    }
+
+    #[test]
+    fn test_rule_effect_tells_empty_from_identity_only() {
+        let empty: VectorFst<TropicalWeight> = VectorFst::new();
+        assert_eq!(rule_effect(&empty).unwrap(), RuleEffect::Empty);
+        // A path that never reaches a final state is as good as none.
+        let mut dead: VectorFst<TropicalWeight> = rustfst::fst![1 => 2];
+        dead.delete_final_weight(1).unwrap();
+        assert_eq!(rule_effect(&dead).unwrap(), RuleEffect::Empty);
+        let identity: VectorFst<TropicalWeight> = rustfst::fst![1, 2 => 1, 2];
+        assert_eq!(rule_effect(&identity).unwrap(), RuleEffect::IdentityOnly);
+        let rewrite: VectorFst<TropicalWeight> = rustfst::fst![1, 2 => 1, 3];
+        assert_eq!(rule_effect(&rewrite).unwrap(), RuleEffect::Rewrites);
+    }
+
+    #[test]
+    fn test_empty_rule_is_left_out_or_an_error_under_strict() {
+        let empty: VectorFst<TropicalWeight> = VectorFst::new();
+        let mut checks = RuleChecks::default();
+        assert!(!checks.check("x.txt", 3, &empty).unwrap());
+        assert_eq!(checks.kept, 0);
+        assert_eq!(checks.summary(), "1 rules compiled to empty transducers:\n  x.txt rule 3\n");
+        let err = RuleChecks::new(true).check("x.txt", 3, &empty).unwrap_err().to_string();
+        assert!(err.contains("Rule 3 of x.txt"), "{}", err);
+    }
+
+    #[test]
+    fn test_undefined_macro_is_an_error_under_strict() {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b"]);
+        let script = ruleparse::parse_script("::v:: = (a|::w::)\n::v:: -> b / _ \n").unwrap().1 .0;
+        assert!(compile_rule_script(symt.clone(), script.clone().into(), "fixture.txt", &mut RuleChecks::default()).is_ok());
+        let err = compile_rule_script(symt.clone(), script.into(), "fixture.txt", &mut RuleChecks::new(true)).unwrap_err().to_string();
+        assert_eq!(err, "rule 2 of fixture.txt uses ::w::, which the script does not define");
+        // A class member missing from the symbol table is always an error.
+        let script = ruleparse::parse_script("[aq] -> b / _ \n").unwrap().1 .0;
+        let err = compile_rule_script(symt, script.into(), "fixture.txt", &mut RuleChecks::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains("Symbol 'q' is not in the symbol table"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_identity_only_rule_is_kept_and_noted() {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b"]);
+        let script = ruleparse::parse_script("a -> b / _ \na -> a / _ \n").unwrap().1 .0;
+        let mut checks = RuleChecks::new(true);
+        let fst = compile_rule_script(symt.clone(), script.clone().into(), "fixture.txt", &mut checks).unwrap();
+        assert_eq!(checks.notes, [RuleNote { file: "fixture.txt".to_string(), rule: 2, effect: RuleEffect::IdentityOnly }]);
+        assert!(checks.summary().starts_with("1 rules never change their input:"));
+        let mut expected = rulefst::compile_script(with_internal_boundary(&symt), script).unwrap();
+        restore_boundaries(&mut expected, symt).unwrap();
+        connect(&mut expected).unwrap();
+        assert_eq!(fst, expected);
+    }
+
+    #[test]
+    fn test_cost_annotations() {
+        assert_eq!(RuleCost::parse(" 2.5").unwrap(), RuleCost { cost: 2.5, probability: None });
+        let p = RuleCost::parse("p=0.8").unwrap();
+        assert!((p.cost - 0.2231).abs() < 1e-4 && p.probability == Some(0.8));
+        assert_eq!(RuleCost::parse("p=1").unwrap().cost, 0.0);
+        for bad in ["p=0", "p=1.5", "p=-0.1", "x", "inf"] {
+            assert!(RuleCost::parse(bad).is_err(), "{}", bad);
+        }
+        let (rule, cost) = split_cost_annotation("a -> b / ::v:: _ :: p=0.5").unwrap();
+        assert_eq!((rule.as_str(), cost.unwrap().probability), ("a -> b / ::v:: _", Some(0.5)));
+        let (rule, cost) = split_cost_annotation("a -> b / _ :: 2 % why").unwrap();
+        assert_eq!((rule.as_str(), cost.unwrap().cost), ("a -> b / _ % why", 2.0));
+        assert_eq!(split_cost_annotation("a -> b / _ ::v::").unwrap(), ("a -> b / _ ::v::".to_string(), None));
+        assert!(split_cost_annotation("a -> b / _ :: p=2").is_err());
+    }
+
+    #[test]
+    fn test_variant_probabilities_round_trip() {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b", "c"]);
+        let path = Path::new("variants.txt");
+        let script = parse_script_source(path, "% a is b 80% of the time\na -> b / _ :: p=0.8\n\na -> c / _ :: p=0.2\n").unwrap();
+        assert_eq!(script.costs.keys().sorted().collect::<Vec<_>>(), [&1, &2]);
+        let mut fst = compile_rule_script(symt.clone(), script, "variants.txt", &mut RuleChecks::default()).unwrap();
+        tr_sort(&mut fst, ILabelCompare {});
+        let e2e = rulefst::apply_fst_to_string(symt.clone(), fst, "#a#".to_string()).unwrap();
+        let mut best: HashMap<String, f32> = HashMap::new();
+        for (w, out) in rulefst::decode_paths_through_fst(symt, e2e) {
+            let w = *w.value();
+            best.entry(out).and_modify(|b| *b = b.min(w)).or_insert(w);
+        }
+        // Normalised over the two variants, exp(-weight) gives back the
+        // annotated probabilities.
+        let (b, c) = ((-best["#b#"]).exp(), (-best["#c#"]).exp());
+        assert!((b / (b + c) - 0.8).abs() < 1e-3, "{:?}", best);
+    }
+
+    #[test]
+    fn test_variant_probabilities_over_one_warn() {
+        let path = Path::new("variants.txt");
+        let script = parse_script_source(path, "a -> b / _ :: p=0.8\na -> c / _ :: p=0.5\nb -> c / _ :: p=0.9\n").unwrap();
+        assert!(RuleChecks::default().check_variant_probabilities("variants.txt", &script).is_empty());
+        let checks = RuleChecks { check_probabilities: true, ..Default::default() };
+        let warnings = checks.check_variant_probabilities("variants.txt", &script);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("rules 1, 2 of variants.txt") && warnings[0].contains("1.3"), "{}", warnings[0]);
+        let macro_line = parse_script_source(path, "::v:: = a :: 2\n");
+        assert!(macro_line.is_err());
+    }
+
+    #[test]
+    fn test_comments_compile_like_their_uncommented_twins() {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b", "c", "e"]);
+        let dir = root().join("tests/comments");
+        let plain = compile_rule_file(symt.clone(), &dir.join("rules.txt"), &mut RuleChecks::default()).unwrap();
+        let commented = compile_rule_file(symt, &dir.join("rules_commented.txt"), &mut RuleChecks::default()).unwrap();
+        assert_eq!(commented, plain);
+    }
+
+    #[test]
+    fn test_named_rule_alone() {
+        let path = Path::new("named.txt");
+        let text = "% @not-a-name\n::v:: = (a|e)\na -> b / _ ::v:: :: 2 % @voicing before vowels\nb -> c / _ % @devoicing\n";
+        let script = parse_script_source(path, text).unwrap();
+        assert_eq!(script.names, HashMap::from([(2, "voicing".to_string()), (3, "devoicing".to_string())]));
+        let only = script.only_rule("voicing").unwrap();
+        assert_eq!(only.statements, [script.statements[1].clone(), script.statements[2].clone()]);
+        assert_eq!((only.names[&1].as_str(), only.costs[&1].cost), ("voicing", 2.0));
+        assert!(script.only_rule("nasal").is_none());
+        let err = parse_script_source(path, "a -> b / _ % @x\nb -> c / _ % @x\n").unwrap_err().to_string();
+        assert!(err.contains("Rules 1 and 2") && err.contains("@x"), "{}", err);
+        assert!(parse_script_source(path, "::v:: = a % @v\n").is_err());
+    }
+
+    #[test]
+    fn test_script_ends_at_an_unparsed_line() {
+        let script = parse_script_source(Path::new("bad.txt"), "% ok\na -> b / _ % why\n\na => b\nb -> c / _ \n").unwrap();
+        assert_eq!(script.statements.len(), 2);
+        assert!(matches!(script.statements[1], Statement::Rule(_)));
+    }
+
+    #[test]
+    fn test_include_cycle_is_an_error() {
+        let dir = TempDir::new("rules-include-cycle");
+        let a = write(&dir, "a.txt", "@include \"b.txt\"\n");
+        write(&dir, "b.txt", "@include \"a.txt\"\n");
+        let err = read_script_source(&a).unwrap_err().to_string();
+        assert!(err.contains("Include cycle"), "{}", err);
+    }
+}
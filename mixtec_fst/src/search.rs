@@ -0,0 +1,322 @@
+//! The analyses of an input one at a time, best first, for callers that stop
+//! early.
+//!
+//! A caller that wants the first analysis passing some test (say, the first
+//! one in a lexicon) should not have to extract the k best paths up front. The
+//! lattice of the input is built whole, since for one word it is small; it is
+//! the extraction of paths that is incremental. The search is A* over the
+//! lattice, with the exact distance from each state to a final state as its
+//! heuristic, so complete paths come off the queue in order of weight and each
+//! is found after expanding only the states on paths at least as good.
+//!
+//! Outputs already returned are skipped, so each analysis comes once, with the
+//! weight of its best path. Analyses of equal weight are ordered by the
+//! prepared [`CandidateRanker`](crate::ranking::CandidateRanker), then by when
+//! the search found them.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+
+use anyhow::{bail, Result};
+use parserule::rulefst::is_cyclic;
+use rustfst::prelude::{connect, shortest_distance, CoreFst, TropicalWeight, VectorFst};
+use rustfst::{Label, Semiring, StateId, EPS_LABEL};
+
+use crate::cancel::CancelToken;
+use crate::check::input_lattice;
+use crate::decode::display_labels;
+use crate::limits::LimitError;
+use crate::prepared::PreparedFst;
+use crate::ranking::CandidateRanker;
+use crate::status::internal;
+
+/// How far apart two weights may be and still be ranked as a tie.
+const TIE_DELTA: f32 = 1e-5;
+
+/// An analysis (unwrapped) and the weight of its best path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub weight: TropicalWeight,
+    pub analysis: String,
+}
+
+/// A path on the search queue: ending at a state, or complete.
+struct Entry {
+    /// The weight so far plus the best weight to a final state.
+    priority: f32,
+    /// When the entry was queued, so that ties are settled the same way every
+    /// run.
+    order: usize,
+    weight: TropicalWeight,
+    /// The state the path ends at, or `None` once it has taken a final weight.
+    state: Option<StateId>,
+    /// The last output label of the path, in `Analyses::labels`.
+    last: Option<usize>,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.total_cmp(&other.priority).then(self.order.cmp(&other.order))
+    }
+}
+
+/// The analyses of one input, best first (see the module documentation).
+pub struct Analyses<'a> {
+    prepared: &'a PreparedFst,
+    ranker: Box<dyn CandidateRanker>,
+    lattice: VectorFst<TropicalWeight>,
+    /// Best weight from each state of `lattice` to a final state.
+    to_final: Vec<TropicalWeight>,
+    queue: BinaryHeap<Reverse<Entry>>,
+    /// The output labels of the queued paths, each with the label before it.
+    labels: Vec<(Label, Option<usize>)>,
+    queued: usize,
+    /// Analyses found and ranked, not yet returned.
+    ready: VecDeque<Candidate>,
+    returned: HashSet<String>,
+    expansions: usize,
+    /// Cancels the search; the prepared token unless another is given.
+    cancel: CancelToken,
+}
+
+impl<'a> Analyses<'a> {
+    pub fn new(prepared: &'a PreparedFst, input: &str) -> Result<Self> {
+        let mut lattice = input_lattice(prepared, input)?.0;
+        internal(connect(&mut lattice))?;
+        if is_cyclic(&lattice) {
+            bail!("Cannot search the analyses of '{}': its lattice is cyclic", input);
+        }
+        let to_final = internal(shortest_distance(&lattice, true))?;
+        let mut analyses = Analyses {
+            prepared,
+            ranker: prepared.ranker(),
+            lattice,
+            to_final,
+            queue: BinaryHeap::new(),
+            labels: Vec::new(),
+            queued: 0,
+            ready: VecDeque::new(),
+            returned: HashSet::new(),
+            expansions: 0,
+            cancel: prepared.cancel.clone(),
+        };
+        if let Some(start) = analyses.lattice.start() {
+            analyses.push(TropicalWeight::one(), Some(start), None);
+        }
+        Ok(analyses)
+    }
+
+    /// Cancel the search with `cancel` rather than the prepared token.
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Number of states expanded so far.
+    #[cfg(test)]
+    pub fn expansions(&self) -> usize {
+        self.expansions
+    }
+
+    fn push(&mut self, weight: TropicalWeight, state: Option<StateId>, last: Option<usize>) {
+        let rest = state.map_or(0.0, |s| *self.to_final[s as usize].value());
+        let priority = weight.value() + rest;
+        self.queue.push(Reverse(Entry { priority, order: self.queued, weight, state, last }));
+        self.queued += 1;
+    }
+
+    /// Queue the paths one transition longer than `entry`, and the path that
+    /// ends at its state, if that state is final. Fails once the search has
+    /// expanded as many paths as the prepared limits allow, or is cancelled.
+    fn expand(&mut self, entry: Entry, state: StateId) -> Result<()> {
+        self.cancel.check()?;
+        let max_expansions = self.prepared.limits.max_expansions;
+        if self.expansions >= max_expansions {
+            return Err(LimitError::TooComplex { max_expansions }.into());
+        }
+        self.expansions += 1;
+        if let Some(final_weight) = self.lattice.final_weight(state)? {
+            self.push(entry.weight.times(final_weight)?, None, entry.last);
+        }
+        let trs = self.lattice.get_trs(state)?;
+        for tr in trs.iter() {
+            let last = if tr.olabel == EPS_LABEL {
+                entry.last
+            } else {
+                self.labels.push((tr.olabel, entry.last));
+                Some(self.labels.len() - 1)
+            };
+            self.push(entry.weight.times(tr.weight)?, Some(tr.nextstate), last);
+        }
+        Ok(())
+    }
+
+    fn analysis(&self, mut last: Option<usize>) -> String {
+        let mut olabels = Vec::new();
+        while let Some(i) = last {
+            let (label, before) = self.labels[i];
+            olabels.push(label);
+            last = before;
+        }
+        olabels.reverse();
+        let analysis = display_labels(&self.prepared.symt, &olabels);
+        self.prepared.fmt.strip(&analysis).to_string()
+    }
+
+    /// Search on until the next weight's analyses are ranked in `ready`, or
+    /// there are no more paths.
+    fn search(&mut self) -> Result<()> {
+        let mut tied: Vec<(usize, Candidate)> = Vec::new();
+        while let Some(Reverse(entry)) = self.queue.peek() {
+            if let Some((_, first)) = tied.first()
+                && entry.priority > first.weight.value() + TIE_DELTA
+            {
+                break;
+            }
+            let Reverse(entry) = self.queue.pop().expect("the queue has an entry");
+            match entry.state {
+                Some(state) => self.expand(entry, state)?,
+                None => {
+                    let analysis = self.analysis(entry.last);
+                    if !self.returned.contains(&analysis) && tied.iter().all(|(_, c)| c.analysis != analysis) {
+                        tied.push((entry.order, Candidate { weight: entry.weight, analysis }));
+                    }
+                }
+            }
+        }
+        tied.sort_by(|(o1, c1), (o2, c2)| {
+            c1.weight
+                .partial_cmp(&c2.weight)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| self.ranker.compare(&c1.analysis, &c2.analysis))
+                .then(o1.cmp(o2))
+        });
+        for (_, candidate) in tied {
+            self.returned.insert(candidate.analysis.clone());
+            self.ready.push_back(candidate);
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Analyses<'_> {
+    type Item = Result<Candidate>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ready.is_empty()
+            && let Err(e) = self.search()
+        {
+            self.queue.clear();
+            return Some(Err(e));
+        }
+        self.ready.pop_front().map(Ok)
+    }
+}
+
+impl Drop for Analyses<'_> {
+    fn drop(&mut self) {
+        log::trace!("analyses: {} returned after {} expansions", self.returned.len(), self.expansions);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use rustfst::prelude::{Fst, MutableFst};
+    use rustfst::{SymbolTable, Tr};
+
+    use crate::alphabet::SurfaceToAnalysisFst;
+    use crate::analysis::AnalysisFormat;
+    use crate::decode::decode_distinct_outputs;
+    use crate::limits::Limits;
+    use crate::ranking::{Lexicographic, TieBreak};
+
+    fn symt() -> Arc<SymbolTable> {
+        Arc::new(rustfst::symt!["#", "a", "x", "y", "z"])
+    }
+
+    /// Copies `#`, and reads each `a` as `x`, `y` or `z` with costs 1, 2 and 4:
+    /// 3^n analyses of `a` n times, many of them tied.
+    fn prepared() -> PreparedFst {
+        let mut fst: VectorFst<TropicalWeight> = VectorFst::new();
+        let s = fst.add_state();
+        fst.set_start(s).unwrap();
+        fst.set_final(s, TropicalWeight::one()).unwrap();
+        fst.add_tr(s, Tr::new(1, 1, TropicalWeight::one(), s)).unwrap();
+        for (label, cost) in [(3, 1.0), (4, 2.0), (5, 4.0)] {
+            fst.add_tr(s, Tr::new(2, label, TropicalWeight::new(cost), s)).unwrap();
+        }
+        fst.set_input_symbols(symt());
+        fst.set_output_symbols(symt());
+        PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap().with_tie_break(TieBreak::Lexicographic)
+    }
+
+    #[test]
+    fn test_analyses_match_full_decoding() {
+        let prepared = prepared();
+        let analyses: Vec<Candidate> = prepared.analyses("aaa").collect::<Result<_>>().unwrap();
+        let mut lattice = input_lattice(&prepared, "aaa").unwrap().0;
+        connect(&mut lattice).unwrap();
+        let expected = decode_distinct_outputs(&lattice, None, &Lexicographic, |l| display_labels(&symt(), l)).unwrap();
+        let expected: Vec<Candidate> = expected
+            .into_iter()
+            .map(|(weight, analysis)| Candidate { weight, analysis: prepared.fmt.strip(&analysis).to_string() })
+            .collect();
+        assert_eq!(analyses.len(), 27);
+        assert_eq!(analyses, expected);
+        assert_eq!(analyses[0], Candidate { weight: TropicalWeight::new(3.0), analysis: "xxx".to_string() });
+        // Ties are ranked, not left in the order they were found.
+        assert_eq!(analyses[1].analysis, "xxy");
+        assert_eq!(analyses[3].analysis, "yxx");
+    }
+
+    #[test]
+    fn test_taking_one_expands_less_than_taking_many() {
+        let prepared = prepared();
+        let expansions = |k: usize| {
+            let mut analyses = Analyses::new(&prepared, "aaaaaa").unwrap();
+            assert_eq!(analyses.by_ref().take(k).count(), k);
+            analyses.expansions()
+        };
+        let (one, many) = (expansions(1), expansions(50));
+        assert!(one * 10 < many, "{} vs {}", one, many);
+        let first = prepared.analyses("aaaaaa").find(|c| c.as_ref().is_ok_and(|c| c.analysis.contains('z'))).unwrap().unwrap();
+        assert_eq!(first, Candidate { weight: TropicalWeight::new(9.0), analysis: "xxxxxz".to_string() });
+    }
+
+    #[test]
+    fn test_errors_and_exhaustion() {
+        let prepared = prepared();
+        assert_eq!(prepared.analyses("").map(|c| c.unwrap().analysis).collect::<Vec<_>>(), [""]);
+        let mut failed = prepared.analyses("b");
+        assert!(failed.next().unwrap().is_err());
+        assert!(failed.next().is_none());
+    }
+
+    #[test]
+    fn test_search_gives_up_after_max_expansions() {
+        let limits = Limits { max_expansions: 20, ..Limits::default() };
+        let prepared = prepared().with_limits(limits);
+        assert_eq!(prepared.analyses("a").count(), 3);
+        let mut analyses = prepared.analyses("aaaaaa");
+        let err = analyses.by_ref().find_map(Result::err).unwrap();
+        assert_eq!(LimitError::of(&err), Some(LimitError::TooComplex { max_expansions: 20 }));
+        assert!(analyses.next().is_none());
+    }
+}
@@ -0,0 +1,528 @@
+//! Answering segmentation queries over HTTP (`segment --serve`), with the FSTs
+//! loaded and prepared once for every request.
+//!
+//! Several FSTs can be served at once, each under a name ([`Models`]).
+//! `GET /segment?word=FORM&n=K&model=NAME` and `POST /segment?n=K&model=NAME`,
+//! with the form as the body, answer with the K best analyses of the form by
+//! the model NAME (the default model without `model`) as JSON (see
+//! [`SegmentOutcome`]). A form with a character that is neither a symbol nor
+//! mapped by the grapheme map is answered 422, as is one whose analyses are
+//! too complex to search (`--max-expansions`); a form longer than
+//! `--max-input-len` is answered 413, an unknown model 404 and a malformed
+//! request 400. `GET /info` lists the models and their provenance.
+//!
+//! Ctrl-C stops the server; the requests it is answering then are answered
+//! 503.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use rustfst::prelude::{Fst, TropicalWeight, VectorFst};
+use rustfst::{Semiring, SymbolTable};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::cache::symt_hash;
+use crate::cancel::{CancelToken, Cancelled};
+use crate::graphemes::GraphemeMap;
+use crate::limits::{given_up, LimitError};
+use crate::prepared::PreparedFst;
+use crate::provenance::Provenance;
+
+/// Analyses answered when neither the request nor `--max-paths` says how many.
+pub const DEFAULT_ANALYSES: usize = 5;
+
+/// Most analyses a request may ask for.
+pub const MAX_ANALYSES: usize = 100;
+
+/// Longest form a request may send, in bytes.
+const MAX_BODY_BYTES: u64 = 4096;
+
+/// How long a worker waits for a request before it looks whether the server
+/// is stopping.
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// One analysis of a form and the weight of its best path.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ScoredAnalysis {
+    pub analysis: String,
+    pub weight: f32,
+}
+
+/// What came of segmenting one form: its analyses, best first, or why it
+/// could not be read.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SegmentOutcome {
+    Ok { word: String, analyses: Vec<ScoredAnalysis> },
+    NoAnalysis { word: String },
+    Invalid { word: String, reason: String },
+    /// Longer than the limit, so not analysed.
+    TooLong { word: String, reason: String },
+    /// The search for the analyses gave up.
+    TooComplex { word: String, reason: String },
+}
+
+impl SegmentOutcome {
+    fn status_code(&self) -> u16 {
+        match self {
+            SegmentOutcome::Ok { .. } | SegmentOutcome::NoAnalysis { .. } => 200,
+            SegmentOutcome::Invalid { .. } | SegmentOutcome::TooComplex { .. } => 422,
+            SegmentOutcome::TooLong { .. } => 413,
+        }
+    }
+}
+
+/// An FST served under a name, with where it came from.
+pub struct Model {
+    pub name: String,
+    /// Path the FST was read from.
+    pub path: String,
+    pub prepared: PreparedFst,
+    /// The provenance in the FST's build info sidecar, if it has one.
+    pub provenance: Option<Provenance>,
+}
+
+/// What `GET /info` says of a model.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+struct ModelInfo<'a> {
+    name: &'a str,
+    path: &'a str,
+    default: bool,
+    provenance: Option<&'a Provenance>,
+}
+
+/// The models a server answers with, and the one answering requests that do
+/// not name a model.
+pub struct Models {
+    models: Vec<Model>,
+    default: usize,
+}
+
+impl Models {
+    /// `models`, answering requests without a model with the one named
+    /// `default`, or the first.
+    pub fn new(models: Vec<Model>, default: Option<&str>) -> Result<Self> {
+        let names: Vec<&str> = models.iter().map(|m| m.name.as_str()).collect();
+        if let Some(name) = names.iter().enumerate().find_map(|(i, name)| names[..i].contains(name).then_some(name)) {
+            bail!("Two models are named '{}'", name);
+        }
+        let default = match default {
+            Some(name) => names.iter().position(|n| *n == name).ok_or_else(|| anyhow!("No model is named '{}'; the models are {}", name, names.join(", ")))?,
+            None if models.is_empty() => bail!("No model to serve"),
+            None => 0,
+        };
+        Ok(Models { models, default })
+    }
+
+    /// The model named `name`, or the default model.
+    fn get(&self, name: Option<&str>) -> Option<&Model> {
+        match name {
+            Some(name) => self.models.iter().find(|m| m.name == name),
+            None => Some(&self.models[self.default]),
+        }
+    }
+
+    /// The JSON answering `GET /info`.
+    fn info(&self) -> serde_json::Value {
+        let models: Vec<ModelInfo> = self
+            .models
+            .iter()
+            .enumerate()
+            .map(|(i, m)| ModelInfo { name: &m.name, path: &m.path, default: i == self.default, provenance: m.provenance.as_ref() })
+            .collect();
+        serde_json::json!({ "models": models })
+    }
+}
+
+/// The symbol tables of the models loaded so far, by hash, so that models
+/// built from the same table hold one copy of it.
+#[derive(Debug, Default)]
+pub struct SharedSymbols(HashMap<String, Arc<SymbolTable>>);
+
+impl SharedSymbols {
+    /// Give `fst` the table already loaded with the hash `recorded` in its
+    /// sidecar (or, without one, the hash of its own table), keeping its own if
+    /// none was.
+    pub fn share(&mut self, fst: &mut VectorFst<TropicalWeight>, recorded: Option<&str>) {
+        let Some(own) = fst.input_symbols().cloned() else { return };
+        let hash = recorded.map_or_else(|| format!("{:016x}", symt_hash(&own)), String::from);
+        let shared = self.0.entry(hash).or_insert(own).clone();
+        fst.set_input_symbols(shared.clone());
+        fst.set_output_symbols(shared);
+    }
+
+    /// The number of distinct tables loaded.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// The `n` best analyses of `word`, unless `cancel` is set first.
+pub fn segment(prepared: &PreparedFst, graphemes: &GraphemeMap, word: &str, n: usize, cancel: CancelToken) -> Result<SegmentOutcome> {
+    let mapped = match graphemes.apply(&prepared.symt, word) {
+        Ok(mapped) => mapped,
+        Err(e) => return Ok(SegmentOutcome::Invalid { word: word.to_string(), reason: e.to_string() }),
+    };
+    let analyses = prepared
+        .analyses_cancelled_by(&mapped, cancel)
+        .take(n)
+        .map(|c| c.map(|c| ScoredAnalysis { analysis: c.analysis, weight: *c.weight.value() }))
+        .collect::<Result<Vec<_>>>();
+    let (word, analyses) = match given_up(analyses)? {
+        Ok(analyses) => (word.to_string(), analyses),
+        Err(limit @ LimitError::TooLong { .. }) => return Ok(SegmentOutcome::TooLong { word: word.to_string(), reason: limit.to_string() }),
+        Err(limit @ LimitError::TooComplex { .. }) => return Ok(SegmentOutcome::TooComplex { word: word.to_string(), reason: limit.to_string() }),
+    };
+    Ok(match analyses.is_empty() {
+        true => SegmentOutcome::NoAnalysis { word },
+        false => SegmentOutcome::Ok { word, analyses },
+    })
+}
+
+/// A segmentation query: the form, how many analyses to answer with, and the
+/// model to analyse it with, if not the default.
+#[derive(Debug, Clone, PartialEq)]
+struct Query {
+    word: String,
+    n: usize,
+    model: Option<String>,
+}
+
+/// `s` with `+` read as a space and `%XX` escapes decoded.
+fn percent_decode(s: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = rest.get(..2).and_then(|h| std::str::from_utf8(h).ok());
+                let byte = hex.and_then(|h| u8::from_str_radix(h, 16).ok()).ok_or_else(|| anyhow!("Bad escape in '{}'", s))?;
+                bytes.push(byte);
+                rest = &rest[2..];
+            }
+            _ => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| anyhow!("'{}' does not decode to UTF-8", s))
+}
+
+/// Read the query parameters `params` of a request, taking the form from
+/// `body` if it is not among them; `n` analyses unless they ask for another
+/// number.
+fn parse_query(params: &str, body: Option<String>, n: usize) -> Result<Query> {
+    let mut query = Query { word: String::new(), n, model: None };
+    let mut word = None;
+    for param in params.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        let value = percent_decode(value)?;
+        match key {
+            "word" => word = Some(value),
+            "model" => query.model = Some(value),
+            "n" => {
+                query.n = value.parse().map_err(|_| anyhow!("n must be a number of analyses, got '{}'", value))?;
+                if !(1..=MAX_ANALYSES).contains(&query.n) {
+                    return Err(anyhow!("n must be between 1 and {}, got {}", MAX_ANALYSES, query.n));
+                }
+            }
+            _ => return Err(anyhow!("Unknown parameter '{}'", key)),
+        }
+    }
+    query.word = match (word, body) {
+        (Some(_), Some(_)) => return Err(anyhow!("Give the form as the word parameter or as the body, not both")),
+        (Some(word), None) | (None, Some(word)) => word.trim().to_string(),
+        (None, None) => return Err(anyhow!("No form; give it as the word parameter")),
+    };
+    if query.word.is_empty() {
+        return Err(anyhow!("Empty form"));
+    }
+    Ok(query)
+}
+
+/// A JSON response with `status`.
+fn json_response(status: u16, json: String) -> Response<std::io::Cursor<Vec<u8>>> {
+    let content_type = Header::from_bytes("Content-Type", "application/json; charset=utf-8").expect("valid header");
+    Response::from_string(json).with_status_code(status).with_header(content_type)
+}
+
+/// An error response with `status` and `message`.
+fn error_response(status: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    json_response(status, serde_json::json!({ "error": message }).to_string())
+}
+
+/// The outcome of a query, with the model that answered it.
+#[derive(serde::Serialize)]
+struct Answer<'a> {
+    model: &'a str,
+    #[serde(flatten)]
+    outcome: &'a SegmentOutcome,
+}
+
+/// The response to `request`: its outcome, or an error. The search for its
+/// analyses stops once `cancel` is set.
+fn respond(models: &Models, graphemes: &GraphemeMap, request: &mut Request, n: usize, cancel: CancelToken) -> Response<std::io::Cursor<Vec<u8>>> {
+    let body = match request.method() {
+        Method::Get => None,
+        Method::Post => {
+            let mut body = String::new();
+            if request.as_reader().take(MAX_BODY_BYTES).read_to_string(&mut body).is_err() {
+                return error_response(400, "The body is not UTF-8 text");
+            }
+            Some(body)
+        }
+        method => return error_response(405, &format!("{} is not supported; use GET or POST", method)),
+    };
+    let (path, params) = request.url().split_once('?').unwrap_or((request.url(), ""));
+    match path {
+        "/segment" => {}
+        "/info" => return json_response(200, models.info().to_string()),
+        _ => return error_response(404, &format!("Unknown path {}; query /segment or /info", path)),
+    }
+    let query = match parse_query(params, body, n) {
+        Ok(query) => query,
+        Err(e) => return error_response(400, &e.to_string()),
+    };
+    let Some(model) = models.get(query.model.as_deref()) else {
+        return error_response(404, &format!("Unknown model '{}'; see /info", query.model.unwrap_or_default()));
+    };
+    match segment(&model.prepared, graphemes, &query.word, query.n, cancel) {
+        Ok(outcome) => match serde_json::to_string(&Answer { model: &model.name, outcome: &outcome }) {
+            Ok(json) => json_response(outcome.status_code(), json),
+            Err(e) => error_response(500, &e.to_string()),
+        },
+        Err(e) if Cancelled::of(&e) => error_response(503, "The server is stopping"),
+        Err(e) => {
+            log::warn!("Failed to segment '{}': {:#}", query.word, e);
+            error_response(500, &format!("{:#}", e))
+        }
+    }
+}
+
+/// Answer segmentation queries on `addr` on `jobs` worker threads, with `n`
+/// analyses unless a request asks for another number, until Ctrl-C stops the
+/// server.
+pub fn serve(models: &Models, graphemes: &GraphemeMap, addr: &str, n: usize, jobs: usize) -> Result<()> {
+    let server = Server::http(addr).map_err(|e| anyhow!("Failed to listen on {}: {}", addr, e))?;
+    let names: Vec<&str> = models.models.iter().map(|m| m.name.as_str()).collect();
+    println!("Serving segmentations by {} (default {}) on http://{}/segment", names.join(", "), names[models.default], addr);
+    answer(&server, models, graphemes, n, jobs, &CancelToken::interrupt());
+    Err(anyhow::Error::from(Cancelled).context("Stopped serving"))
+}
+
+/// Answer the requests `server` receives on `jobs` worker threads until `stop`
+/// is set. Each request is searched with a token of its own, so that one
+/// cancelled request leaves the others be; stopping cancels those being
+/// answered.
+fn answer(server: &Server, models: &Models, graphemes: &GraphemeMap, n: usize, jobs: usize, stop: &CancelToken) {
+    // The token of the request each worker is answering.
+    let answering: Vec<Mutex<CancelToken>> = (0..jobs.max(1)).map(|_| Mutex::default()).collect();
+    std::thread::scope(|scope| {
+        for slot in answering.iter() {
+            scope.spawn(move || {
+                while !stop.is_cancelled() {
+                    let mut request = match server.recv_timeout(STOP_POLL) {
+                        Ok(Some(request)) => request,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            log::warn!("Failed to receive a request: {}", e);
+                            continue;
+                        }
+                    };
+                    let cancel = CancelToken::new();
+                    *slot.lock().unwrap() = cancel.clone();
+                    // Stopped before the token could be seen.
+                    if stop.is_cancelled() {
+                        cancel.cancel();
+                    }
+                    let response = respond(models, graphemes, &mut request, n, cancel);
+                    if let Err(e) = request.respond(response) {
+                        log::warn!("Failed to answer a request: {}", e);
+                    }
+                }
+            });
+        }
+        while !stop.is_cancelled() {
+            std::thread::sleep(STOP_POLL);
+        }
+        for slot in answering.iter() {
+            slot.lock().unwrap().cancel();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustfst::utils::transducer;
+
+    use crate::alphabet::SurfaceToAnalysisFst;
+    use crate::analysis::AnalysisFormat;
+    use crate::limits::Limits;
+
+    /// Analyses `ab` as `ba`, and as `ab` at a higher weight.
+    fn fst() -> VectorFst<TropicalWeight> {
+        let symt = Arc::new(rustfst::symt!["#", "a", "b"]);
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 3, 1 => 1, 3, 2, 1; 1.5];
+        let identity: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 3, 1 => 1, 2, 3, 1; 2.0];
+        rustfst::algorithms::union::union(&mut fst, &identity).unwrap();
+        fst.set_input_symbols(symt.clone());
+        fst.set_output_symbols(symt);
+        fst
+    }
+
+    fn prepared() -> PreparedFst {
+        PreparedFst::new(SurfaceToAnalysisFst(fst()), None, AnalysisFormat::default()).unwrap()
+    }
+
+    /// A model that analyses `ab` as `bb`.
+    fn other_model() -> Model {
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1, 2, 3, 1 => 1, 3, 3, 1; 0.5];
+        fst.set_input_symbols(Arc::new(rustfst::symt!["#", "a", "b"]));
+        fst.set_output_symbols(Arc::new(rustfst::symt!["#", "a", "b"]));
+        let prepared = PreparedFst::new(SurfaceToAnalysisFst(fst), None, AnalysisFormat::default()).unwrap();
+        Model { name: "other".to_string(), path: "other.fst".to_string(), prepared, provenance: None }
+    }
+
+    fn models(default: Option<&str>) -> Result<Models> {
+        let provenance = Provenance::parse("version=0.1.0\nvariant=\n");
+        let model = Model { name: "main".to_string(), path: "main.fst".to_string(), prepared: prepared(), provenance: Some(provenance) };
+        Models::new(vec![model, other_model()], default)
+    }
+
+    /// The status and JSON body of the response to `request`, sent to `addr`.
+    fn send(addr: std::net::SocketAddr, request: &str) -> (u16, serde_json::Value) {
+        use std::io::Write;
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn test_segment_outcomes() {
+        let prepared = prepared();
+        let graphemes = GraphemeMap::default();
+        let scored = |analysis: &str, weight| ScoredAnalysis { analysis: analysis.to_string(), weight };
+        let outcome = segment(&prepared, &graphemes, "ab", 5, CancelToken::new()).unwrap();
+        assert_eq!(outcome, SegmentOutcome::Ok { word: "ab".to_string(), analyses: vec![scored("ba", 1.5), scored("ab", 2.0)] });
+        let json = serde_json::to_value(segment(&prepared, &graphemes, "ab", 1, CancelToken::new()).unwrap()).unwrap();
+        assert_eq!(json, serde_json::json!({ "status": "ok", "word": "ab", "analyses": [{ "analysis": "ba", "weight": 1.5 }] }));
+        assert_eq!(segment(&prepared, &graphemes, "b", 5, CancelToken::new()).unwrap(), SegmentOutcome::NoAnalysis { word: "b".to_string() });
+        let invalid = segment(&prepared, &graphemes, "ax", 5, CancelToken::new()).unwrap();
+        assert!(matches!(&invalid, SegmentOutcome::Invalid { reason, .. } if reason.contains("not in the symbol table")), "{:?}", invalid);
+        assert_eq!(invalid.status_code(), 422);
+    }
+
+    #[test]
+    fn test_limits_have_their_own_status() {
+        let graphemes = GraphemeMap::default();
+        let short = prepared().with_limits(Limits { max_input_len: 3, ..Limits::default() });
+        let too_long = segment(&short, &graphemes, "abab", 5, CancelToken::new()).unwrap();
+        assert_eq!(too_long.status_code(), 413);
+        let json = serde_json::to_value(&too_long).unwrap();
+        assert_eq!((json["status"].as_str(), json["word"].as_str()), (Some("too_long"), Some("abab")));
+        assert!(json["reason"].as_str().unwrap().contains("--max-input-len 3"), "{}", json);
+        let hard = prepared().with_limits(Limits { max_expansions: 1, ..Limits::default() });
+        let too_complex = segment(&hard, &graphemes, "ab", 5, CancelToken::new()).unwrap();
+        assert!(matches!(&too_complex, SegmentOutcome::TooComplex { reason, .. } if reason.contains("too complex")), "{:?}", too_complex);
+        assert_eq!(too_complex.status_code(), 422);
+    }
+
+    #[test]
+    fn test_models() {
+        let models = models(None).unwrap();
+        assert_eq!(models.get(None).unwrap().name, "main");
+        assert_eq!(models.get(Some("other")).unwrap().name, "other");
+        assert!(models.get(Some("base")).is_none());
+        let info = models.info();
+        assert_eq!(info["models"][0]["default"], true);
+        assert_eq!(info["models"][0]["provenance"]["version"], "0.1.0");
+        assert_eq!(info["models"][1], serde_json::json!({ "name": "other", "path": "other.fst", "default": false, "provenance": null }));
+        assert_eq!(self::models(Some("other")).unwrap().get(None).unwrap().name, "other");
+        assert!(self::models(Some("base")).err().unwrap().to_string().contains("No model is named 'base'"));
+        let twice = Models::new(vec![other_model(), other_model()], None);
+        assert!(twice.err().unwrap().to_string().contains("Two models are named 'other'"));
+    }
+
+    #[test]
+    fn test_shared_symbols() {
+        let mut shared = SharedSymbols::default();
+        let (mut a, mut b, mut c) = (fst(), fst(), fst());
+        shared.share(&mut a, Some("00ff"));
+        shared.share(&mut b, Some("00ff"));
+        assert!(Arc::ptr_eq(a.input_symbols().unwrap(), b.input_symbols().unwrap()));
+        assert!(Arc::ptr_eq(b.input_symbols().unwrap(), b.output_symbols().unwrap()));
+        // Without a sidecar hash, the table is hashed, and differs from 00ff.
+        shared.share(&mut c, None);
+        assert!(!Arc::ptr_eq(a.input_symbols().unwrap(), c.input_symbols().unwrap()));
+        assert_eq!(shared.len(), 2);
+    }
+
+    #[test]
+    fn test_serve_several_models() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let models: &'static Models = Box::leak(Box::new(models(None).unwrap()));
+        let graphemes: &'static GraphemeMap = Box::leak(Box::default());
+        let stop = CancelToken::new();
+        let workers = std::thread::spawn({
+            let stop = stop.clone();
+            move || answer(&server, models, graphemes, 5, 2, &stop)
+        });
+        let get = |url: &str| send(addr, &format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", url));
+
+        let (status, json) = get("/segment?word=ab");
+        assert_eq!(status, 200);
+        assert_eq!(json["model"], "main");
+        assert_eq!(json["analyses"][0], serde_json::json!({ "analysis": "ba", "weight": 1.5 }));
+        let (status, json) = get("/segment?word=ab&model=other");
+        assert_eq!(status, 200);
+        assert_eq!(json, serde_json::json!({ "model": "other", "status": "ok", "word": "ab", "analyses": [{ "analysis": "bb", "weight": 0.5 }] }));
+        let (status, json) = get("/segment?word=ab&model=base");
+        assert_eq!(status, 404);
+        assert!(json["error"].as_str().unwrap().contains("Unknown model 'base'"));
+        let (status, json) = get("/info");
+        assert_eq!(status, 200);
+        assert_eq!(json["models"].as_array().unwrap().iter().map(|m| m["name"].as_str().unwrap()).collect::<Vec<_>>(), ["main", "other"]);
+        let body = "ab";
+        let post = format!("POST /segment?model=other HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        assert_eq!(send(addr, &post).1["analyses"][0]["analysis"], "bb");
+
+        // Stopping ends the workers; a request cancelled on its own stops no
+        // other.
+        let cancelled = CancelToken::new();
+        cancelled.cancel();
+        assert!(segment(&models.models[0].prepared, graphemes, "ab", 5, cancelled).is_err());
+        assert_eq!(get("/segment?word=ab").0, 200);
+        stop.cancel();
+        workers.join().unwrap();
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = |word: &str, n| Query { word: word.to_string(), n, model: None };
+        assert_eq!(parse_query("word=ka%CC%81+&n=2", None, 5).unwrap(), query("ka\u{301}", 2));
+        assert_eq!(parse_query("", Some("ab\n".to_string()), 5).unwrap(), query("ab", 5));
+        assert_eq!(parse_query("n=1", Some("ab".to_string()), 5).unwrap(), query("ab", 1));
+        assert_eq!(parse_query("word=ab&model=base", None, 5).unwrap(), Query { model: Some("base".to_string()), ..query("ab", 5) });
+        for (params, body, error) in [
+            ("", None, "No form"),
+            ("word=ab", Some("ab"), "not both"),
+            ("word=ab&n=0", None, "between 1 and"),
+            ("word=ab&n=x", None, "number of analyses"),
+            ("word=%E", None, "Bad escape"),
+            ("form=ab", None, "Unknown parameter"),
+        ] {
+            let e = parse_query(params, body.map(String::from), 5).unwrap_err().to_string();
+            assert!(e.contains(error), "{}: {}", params, e);
+        }
+    }
+}
@@ -0,0 +1,342 @@
+//! Simultaneous application of the rules of a file (`build --application simultaneous`).
+//!
+//! A rule file is normally compiled as a cascade: each rule is composed after
+//! the ones before it and rewrites their output, so a rule can feed a later one
+//! (create the source or context it needs) or bleed it (destroy them), and the
+//! order of the rules matters. The linear pipeline instead unions its rules, so
+//! they do not see each other's output, but each path through the union is a
+//! single rule applied at a single site, its contexts matched against the
+//! original string there and a universal tail standing in for the rest.
+//!
+//! Under simultaneous application every rule is matched against the same
+//! input: any set of non-overlapping sites is rewritten at once, each by a rule
+//! whose source matches the site and whose contexts match the input around it,
+//! whatever happens to the context itself. Rule order does not matter, and
+//! there is neither feeding nor bleeding. Since no rule sees the `#`s another
+//! writes, no internal boundary is needed either.
+//!
+//! The construction brackets the sites. An inserter copies the input, putting
+//! any stretch that matches a rule's source between that rule's brackets; a
+//! filter keeps the bracketings in which each opening bracket follows its
+//! rule's left context and each closing bracket precedes its right context,
+//! both matched with brackets ignored; and a rewriter replaces each bracketed
+//! stretch with the rule's target. Brackets are labels outside the symbol
+//! table, gone once the three are composed.
+//!
+//! As in the cascade, rewriting is optional and rewrites rank first: with `n`
+//! rules, every copied symbol costs `n` (what the cascade's identity path
+//! pays), and a symbol inside a rewritten site `n - 1`, plus the rule's cost
+//! annotation once per site. An insertion rewrites no symbols, so it ranks
+//! level with leaving the input alone unless it has a cost.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use parserule::rulefst::node_fst;
+use parserule::ruleparse::{RegexAST, Statement};
+use rustfst::prelude::closure::{closure, ClosureType};
+use rustfst::prelude::compose::compose;
+use rustfst::prelude::concat::concat;
+use rustfst::prelude::determinize::determinize;
+use rustfst::prelude::rm_epsilon::rm_epsilon;
+use rustfst::prelude::union::union;
+use rustfst::prelude::{
+    connect, tr_sort, CoreFst, ExpandedFst, Fst, ILabelCompare, MutableFst, OLabelCompare, StateIterator,
+    TropicalWeight, VectorFst,
+};
+use rustfst::{Label, Semiring, SymbolTable, Tr, EPS_LABEL};
+
+use crate::rules::{script_macros, RuleChecks, Script};
+use crate::status::internal;
+
+/// How the rules of a file combine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RuleApplication {
+    /// In order, each rule rewriting the output of the ones before it.
+    #[default]
+    Sequential,
+    /// All at once, each rule matching against the input (see [`compile_simultaneous`]).
+    Simultaneous,
+}
+
+/// First bracket label; clear of the symbol table and of the source markers.
+const BRACKET_BASE: Label = 1 << 23;
+
+/// A rule, compiled for bracketing.
+struct Site {
+    open: Label,
+    close: Label,
+    source: VectorFst<TropicalWeight>,
+    target: VectorFst<TropicalWeight>,
+    left: Option<VectorFst<TropicalWeight>>,
+    right: Option<VectorFst<TropicalWeight>>,
+    cost: f32,
+}
+
+impl Site {
+    /// The rule's rewrite out of context: its source read, then its target written.
+    fn rewrite(&self) -> Result<VectorFst<TropicalWeight>> {
+        let mut fst = map_labels(&self.source, |tr| (tr.ilabel, EPS_LABEL, tr.weight));
+        concat(&mut fst, &map_labels(&self.target, |tr| (EPS_LABEL, tr.olabel, tr.weight)))?;
+        Ok(fst)
+    }
+}
+
+/// A copy of `fst` with the labels and weight of every transition replaced.
+fn map_labels(
+    fst: &VectorFst<TropicalWeight>,
+    f: impl Fn(&Tr<TropicalWeight>) -> (Label, Label, TropicalWeight),
+) -> VectorFst<TropicalWeight> {
+    let mut fst = fst.clone();
+    let states: Vec<_> = fst.states_iter().collect();
+    for s in states {
+        for tr in fst.pop_trs(s).unwrap_or_default() {
+            let (ilabel, olabel, weight) = f(&tr);
+            fst.add_tr(s, Tr::new(ilabel, olabel, weight, tr.nextstate)).unwrap();
+        }
+    }
+    fst
+}
+
+/// One transition from `ilabel` to `olabel`.
+fn arc(ilabel: Label, olabel: Label, weight: f32) -> VectorFst<TropicalWeight> {
+    let mut fst = VectorFst::new();
+    let start = fst.add_state();
+    let end = fst.add_state();
+    fst.set_start(start).unwrap();
+    fst.set_final(end, TropicalWeight::one()).unwrap();
+    fst.add_tr(start, Tr::new(ilabel, olabel, weight, end)).unwrap();
+    fst
+}
+
+/// `labels`*, copied with `weight` on each label.
+fn star_of(labels: &[Label], weight: f32) -> VectorFst<TropicalWeight> {
+    let mut fst = VectorFst::new();
+    let q = fst.add_state();
+    fst.set_start(q).unwrap();
+    fst.set_final(q, TropicalWeight::one()).unwrap();
+    for &l in labels {
+        fst.add_tr(q, Tr::new(l, l, weight, q)).unwrap();
+    }
+    fst
+}
+
+fn concat_all(parts: &[VectorFst<TropicalWeight>]) -> Result<VectorFst<TropicalWeight>> {
+    let mut fst = star_of(&[], 0.0);
+    for part in parts {
+        concat(&mut fst, part)?;
+    }
+    Ok(fst)
+}
+
+/// The acceptor `fst` with every label of `labels` allowed anywhere.
+fn ignoring(fst: &VectorFst<TropicalWeight>, labels: &[Label]) -> VectorFst<TropicalWeight> {
+    let mut fst = fst.clone();
+    for s in 0..fst.num_states() as u32 {
+        for &l in labels {
+            fst.add_tr(s, Tr::new(l, l, TropicalWeight::one(), s)).unwrap();
+        }
+    }
+    fst
+}
+
+/// The strings over `alphabet` that the acceptor `fst` rejects.
+pub(crate) fn complement(fst: &VectorFst<TropicalWeight>, alphabet: &[Label]) -> Result<VectorFst<TropicalWeight>> {
+    let mut fst = fst.clone();
+    internal(rm_epsilon(&mut fst))?;
+    let mut dfa: VectorFst<TropicalWeight> = internal(determinize(&fst))?;
+    if dfa.start().is_none() {
+        let start = dfa.add_state();
+        dfa.set_start(start)?;
+    }
+    let sink = dfa.add_state();
+    for s in 0..dfa.num_states() as u32 {
+        let seen: Vec<Label> = dfa.get_trs(s)?.iter().map(|tr| tr.ilabel).collect();
+        for &l in alphabet.iter().filter(|l| !seen.contains(l)) {
+            dfa.add_tr(s, Tr::new(l, l, TropicalWeight::one(), sink))?;
+        }
+        if dfa.is_final(s)? {
+            dfa.delete_final_weight(s)?;
+        } else {
+            dfa.set_final(s, TropicalWeight::one())?;
+        }
+    }
+    Ok(dfa)
+}
+
+/// The paths of `fst` whose output the acceptor `filter` accepts.
+fn restrict_output(
+    mut fst: VectorFst<TropicalWeight>,
+    mut filter: VectorFst<TropicalWeight>,
+) -> Result<VectorFst<TropicalWeight>> {
+    tr_sort(&mut fst, OLabelCompare {});
+    tr_sort(&mut filter, ILabelCompare {});
+    let mut fst: VectorFst<TropicalWeight> = internal(compose(fst, filter))?;
+    connect(&mut fst)?;
+    Ok(fst)
+}
+
+/// Compile a script so that its rules apply simultaneously (see the module
+/// documentation). Rules are checked with `checks` under the name `file` as in
+/// [`compile_rule_script`](crate::rules::compile_rule_script), and empty ones
+/// are left out.
+pub fn compile_simultaneous(
+    symt: Arc<SymbolTable>,
+    script: Script,
+    file: &str,
+    checks: &mut RuleChecks,
+) -> Result<VectorFst<TropicalWeight>> {
+    checks.check_variant_probabilities(file, &script);
+    script.config.require_cascade(file, "--application simultaneous")?;
+    let Script { statements, costs, .. } = script;
+    let macros = script_macros(&statements);
+    let mut sites: Vec<Site> = Vec::new();
+    for (i, statement) in statements.into_iter().enumerate() {
+        let Statement::Rule(rule) = statement else { continue };
+        checks.check_macros_defined(&macros, &rule, &format!("rule {} of {}", i + 1, file))?;
+        let compile = |node: RegexAST| {
+            node_fst(symt.clone(), &macros, node).with_context(|| format!("Failed to compile rule {} of {}", i + 1, file))
+        };
+        let context = |node: RegexAST| match node {
+            RegexAST::Epsilon => Ok(None),
+            node => compile(node).map(Some),
+        };
+        let k = sites.len() as Label;
+        let site = Site {
+            open: BRACKET_BASE + 2 * k,
+            close: BRACKET_BASE + 2 * k + 1,
+            source: compile(rule.source)?,
+            target: compile(rule.target)?,
+            left: context(rule.left)?,
+            right: context(rule.right)?,
+            cost: costs.get(&i).map_or(0.0, |c| c.cost),
+        };
+        if checks.check(file, i + 1, &site.rewrite()?)? {
+            sites.push(site);
+        }
+    }
+
+    let symbols: Vec<Label> = symt.iter().map(|(l, _)| l).filter(|&l| l != EPS_LABEL).collect();
+    let brackets: Vec<Label> = sites.iter().flat_map(|s| [s.open, s.close]).collect();
+    let alphabet: Vec<Label> = symbols.iter().chain(&brackets).copied().collect();
+    let copy_weight = sites.len() as f32;
+
+    let mut inserter = arc(EPS_LABEL, EPS_LABEL, 0.0);
+    let mut rewriter = arc(EPS_LABEL, EPS_LABEL, 0.0);
+    for &l in &symbols {
+        union(&mut inserter, &arc(l, l, copy_weight))?;
+        union(&mut rewriter, &arc(l, l, 0.0))?;
+    }
+    for site in &sites {
+        let source = map_labels(&site.source, |tr| {
+            let weight = if tr.ilabel == EPS_LABEL { 0.0 } else { copy_weight - 1.0 };
+            (tr.ilabel, tr.olabel, weight.into())
+        });
+        union(&mut inserter, &concat_all(&[arc(EPS_LABEL, site.open, 0.0), source, arc(EPS_LABEL, site.close, 0.0)])?)?;
+        union(&mut rewriter, &concat_all(&[arc(site.open, EPS_LABEL, site.cost), site.rewrite()?, arc(site.close, EPS_LABEL, 0.0)])?)?;
+    }
+    closure(&mut inserter, ClosureType::ClosureStar);
+    closure(&mut rewriter, ClosureType::ClosureStar);
+
+    // Keep only the bracketings whose contexts match, with brackets ignored.
+    let anything = star_of(&alphabet, 0.0);
+    let mut fst = inserter;
+    for site in &sites {
+        if let Some(left) = &site.left {
+            let ends_in_left = concat_all(&[anything.clone(), ignoring(left, &brackets)])?;
+            let bad = concat_all(&[complement(&ends_in_left, &alphabet)?, arc(site.open, site.open, 0.0), anything.clone()])?;
+            fst = restrict_output(fst, complement(&bad, &alphabet)?)?;
+        }
+        if let Some(right) = &site.right {
+            let starts_with_right = concat_all(&[ignoring(right, &brackets), anything.clone()])?;
+            let bad = concat_all(&[anything.clone(), arc(site.close, site.close, 0.0), complement(&starts_with_right, &alphabet)?])?;
+            fst = restrict_output(fst, complement(&bad, &alphabet)?)?;
+        }
+    }
+    // An insertion (a site matching the empty string) may not be repeated in
+    // place, or the input would have infinitely many bracketings.
+    let mut empty_site = VectorFst::new();
+    for site in &sites {
+        union(&mut empty_site, &concat_all(&[arc(site.open, site.open, 0.0), arc(site.close, site.close, 0.0)])?)?;
+    }
+    if !sites.is_empty() {
+        let bad = concat_all(&[anything.clone(), empty_site.clone(), empty_site, anything])?;
+        fst = restrict_output(fst, complement(&bad, &alphabet)?)?;
+    }
+
+    tr_sort(&mut fst, OLabelCompare {});
+    tr_sort(&mut rewriter, ILabelCompare {});
+    let mut fst: VectorFst<TropicalWeight> = internal(compose(fst, rewriter))?;
+    connect(&mut fst)?;
+    internal(rm_epsilon(&mut fst))?;
+    fst.set_input_symbols(symt.clone());
+    fst.set_output_symbols(symt);
+    Ok(fst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parserule::rulefst::{apply_fst_to_string, decode_paths_through_fst};
+
+    use crate::rules::{compile_rule_script, parse_script_source};
+
+    fn symt() -> Arc<SymbolTable> {
+        Arc::new(rustfst::symt!["#", "a", "b", "c", "d", "x"])
+    }
+
+    fn script(text: &str) -> Script {
+        parse_script_source(std::path::Path::new("test.txt"), text).unwrap()
+    }
+
+    /// Every output for `input`, sorted.
+    fn outputs(fst: VectorFst<TropicalWeight>, input: &str) -> Vec<String> {
+        let mut fst = fst;
+        tr_sort(&mut fst, ILabelCompare {});
+        let paths = apply_fst_to_string(symt(), fst, input.to_string()).unwrap();
+        let mut outputs: Vec<String> = decode_paths_through_fst(symt(), paths).into_iter().map(|(_, o)| o).collect();
+        outputs.sort();
+        outputs.dedup();
+        outputs
+    }
+
+    fn sequential(text: &str) -> VectorFst<TropicalWeight> {
+        compile_rule_script(symt(), script(text), "test.txt", &mut RuleChecks::default()).unwrap()
+    }
+
+    fn simultaneous(text: &str) -> VectorFst<TropicalWeight> {
+        compile_simultaneous(symt(), script(text), "test.txt", &mut RuleChecks::default()).unwrap()
+    }
+
+    #[test]
+    fn test_feeding_source_only_in_cascade() {
+        // The first rule creates the `b` the second rewrites.
+        let rules = "a -> b / _ \nb -> c / _ \n";
+        assert_eq!(outputs(sequential(rules), "#a#"), ["#a#", "#b#", "#c#"]);
+        assert_eq!(outputs(simultaneous(rules), "#a#"), ["#a#", "#b#"]);
+        assert_eq!(outputs(simultaneous(rules), "#ab#"), ["#ab#", "#ac#", "#bb#", "#bc#"]);
+    }
+
+    #[test]
+    fn test_contexts_match_the_input() {
+        // The first rule creates the left context of the second.
+        let rules = "a -> b / _ \nc -> d / b _ \n";
+        assert!(outputs(sequential(rules), "#ac#").contains(&"#bd#".to_string()));
+        assert_eq!(outputs(simultaneous(rules), "#ac#"), ["#ac#", "#bc#"]);
+        // And rewriting the context does not bleed the second rule.
+        let rules = "b -> a / _ \nc -> d / b _ \n";
+        assert!(!outputs(sequential(rules), "#bc#").contains(&"#ad#".to_string()));
+        assert_eq!(outputs(simultaneous(rules), "#bc#"), ["#ac#", "#ad#", "#bc#", "#bd#"]);
+    }
+
+    #[test]
+    fn test_sites_are_rewritten_together_and_ranked_first() {
+        let rules = "a -> b / # _ \na -> c / _ # \n";
+        assert_eq!(outputs(simultaneous(&format!("{}0 -> x / a _ a\n", rules)), "#aa#"), [
+            "#aa#", "#ac#", "#axa#", "#axc#", "#ba#", "#bc#", "#bxa#", "#bxc#"
+        ]);
+        let mut fst = simultaneous(rules);
+        tr_sort(&mut fst, ILabelCompare {});
+        assert_eq!(parserule::rulefst::apply_fst(symt(), fst, "#aa#".to_string()), "#bc#");
+    }
+}
@@ -0,0 +1,131 @@
+//! The exit status of a run, for scripts that need to tell a failed check
+//! from a typo on the command line or a corrupt artifact:
+//!
+//! | status | meaning |
+//! |--------|---------|
+//! | 0      | success |
+//! | 1      | checks failed: test items, words without an analysis, incompatible symbols |
+//! | 2      | usage error: arguments clap rejects, or a combination it cannot check |
+//! | 3      | data error: a file missing, unreadable or malformed (symbols, FST, rule file, test file), or an input not spelled in the symbols |
+//! | 4      | internal error: a panic, or an FST algorithm or serializer failing on what was built |
+//! | 130    | interrupted (see [`crate::cancel`]) |
+//!
+//! Errors are classified where they are raised, by marking them with a
+//! [`Failure`]; an error without a mark is a data error, since past the
+//! arguments nearly everything that can fail is reading a file. The rustfst
+//! algorithms (composition, minimization and the like) and the serializers
+//! of reports are given only what was built here, so their errors are marked
+//! internal with [`internal`]. A panic on an expected error path is a bug,
+//! but `main` still exits with 4 rather than Rust's 101.
+
+use std::error::Error;
+use std::fmt;
+
+use crate::cancel::Cancelled;
+
+/// The status of a run that panicked or failed with a [`Failure::Internal`].
+pub const INTERNAL: u8 = 4;
+
+/// The status of an interrupted run.
+pub const INTERRUPTED: u8 = 130;
+
+/// What kind of failure an error is (see the module documentation).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Checks,
+    Usage,
+    Data,
+    Internal,
+}
+
+impl Failure {
+    pub fn code(self) -> u8 {
+        match self {
+            Failure::Checks => 1,
+            Failure::Usage => 2,
+            Failure::Data => 3,
+            Failure::Internal => INTERNAL,
+        }
+    }
+
+    /// `error`, marked as this kind of failure. It displays and chains as
+    /// before.
+    pub fn mark(self, error: anyhow::Error) -> anyhow::Error {
+        anyhow::Error::new(Marked { failure: self, error })
+    }
+
+    /// The failure `e` was marked as, nearest first, or else [`Failure::Data`].
+    pub fn of(e: &(dyn Error + 'static)) -> Failure {
+        std::iter::successors(Some(e), |&e| e.source())
+            .find_map(|e| e.downcast_ref::<Marked>().map(|m| m.failure))
+            .unwrap_or(Failure::Data)
+    }
+}
+
+/// `result`, its error marked as a [`Failure::Internal`].
+pub fn internal<T, E: Into<anyhow::Error>>(result: Result<T, E>) -> anyhow::Result<T> {
+    result.map_err(|e| Failure::Internal.mark(e.into()))
+}
+
+/// The exit status for the error `e` a run failed with.
+pub fn code(e: &(dyn Error + 'static)) -> u8 {
+    if Cancelled::in_chain(e) { INTERRUPTED } else { Failure::of(e).code() }
+}
+
+/// An error with its [`Failure`], standing in for it.
+struct Marked {
+    failure: Failure,
+    error: anyhow::Error,
+}
+
+impl fmt::Debug for Marked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.error, f)
+    }
+}
+
+impl fmt::Display for Marked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl Error for Marked {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.source()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_nearest_mark_classifies() {
+        let e = Failure::Usage.mark(anyhow!("needs an FST"));
+        assert_eq!(e.to_string(), "needs an FST");
+        assert_eq!(code(e.as_ref()), 2);
+
+        let e = Failure::Checks.mark(Failure::Data.mark(anyhow!("bad")).context("checking"));
+        assert_eq!(Failure::of(e.as_ref()), Failure::Checks);
+        let e = Err::<(), _>(Failure::Checks.mark(anyhow!("2 failures"))).context("Testing").unwrap_err();
+        assert_eq!(Failure::of(e.as_ref()), Failure::Checks);
+        assert_eq!(format!("{:#}", e), "Testing: 2 failures");
+    }
+
+    #[test]
+    fn test_internal_marks_errors_only() {
+        assert_eq!(internal(Ok::<_, anyhow::Error>(1)).unwrap(), 1);
+        let e = internal(Err::<(), _>(anyhow!("bad").context("Not deterministic"))).context("Failed to compose").unwrap_err();
+        assert_eq!(code(e.as_ref()), INTERNAL);
+        assert_eq!(format!("{:#}", e), "Failed to compose: Not deterministic: bad");
+    }
+
+    #[test]
+    fn test_unmarked_is_data_and_cancelled_interrupted() {
+        assert_eq!(code(anyhow!("truncated").as_ref()), 3);
+        let e = anyhow::Error::from(Cancelled).context("Failed to build");
+        assert_eq!(code(e.as_ref()), INTERRUPTED);
+    }
+}
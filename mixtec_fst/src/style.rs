@@ -0,0 +1,125 @@
+//! Styled terminal output. Every coloured message goes through [`paint`],
+//! which only adds escape codes when the stream it is for is a terminal (or
+//! `--color always` says so) and `NO_COLOR` is not set, so that logs, pipes
+//! and files get plain text. Text written to files is never painted.
+
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+
+/// When to colour output (`--color`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// When the stream is a terminal and `NO_COLOR` is not set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+static CHOICE: OnceLock<ColorChoice> = OnceLock::new();
+
+/// Set when to colour for the rest of the process.
+pub fn set_color_choice(choice: ColorChoice) {
+    let _ = CHOICE.set(choice);
+}
+
+/// The stream a styled message is written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn is_terminal(self) -> bool {
+        match self {
+            Stream::Stdout => std::io::stdout().is_terminal(),
+            Stream::Stderr => std::io::stderr().is_terminal(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Plain,
+    /// Something worth a look that does not stop the run.
+    Warning,
+    /// A failure.
+    Error,
+    /// A failure that needs acting on, such as an unexpected pass.
+    Alert,
+    Success,
+}
+
+impl Style {
+    fn code(self) -> Option<&'static str> {
+        match self {
+            Style::Plain => None,
+            Style::Warning => Some("33"),
+            Style::Error => Some("31"),
+            Style::Alert => Some("1;31"),
+            Style::Success => Some("32"),
+        }
+    }
+}
+
+/// Whether to colour a stream, given the choice, the value of `NO_COLOR` and
+/// whether the stream is a terminal. An empty `NO_COLOR` counts as unset, as
+/// <https://no-color.org> asks.
+fn colors(choice: ColorChoice, no_color: Option<OsString>, is_terminal: impl FnOnce() -> bool) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => no_color.is_none_or(|v| v.is_empty()) && is_terminal(),
+    }
+}
+
+/// Whether text written to `stream` is coloured.
+pub fn enabled(stream: Stream) -> bool {
+    let choice = CHOICE.get().copied().unwrap_or_default();
+    colors(choice, std::env::var_os("NO_COLOR"), || stream.is_terminal())
+}
+
+fn paint_if(colored: bool, style: Style, text: impl Display) -> String {
+    match style.code().filter(|_| colored) {
+        Some(code) => format!("\x1b[{}m{}\x1b[0m", code, text),
+        None => text.to_string(),
+    }
+}
+
+/// `text` in `style`, if `stream` is coloured.
+pub fn paint(stream: Stream, style: Style, text: impl Display) -> String {
+    paint_if(enabled(stream), style, text)
+}
+
+/// Print a warning to stderr.
+pub fn warn(text: impl Display) {
+    eprintln!("{}", paint(Stream::Stderr, Style::Warning, text));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_colors_only_terminals_without_no_color() {
+        let terminal = || true;
+        let pipe = || false;
+        assert!(colors(ColorChoice::Auto, None, terminal));
+        assert!(!colors(ColorChoice::Auto, None, pipe));
+        assert!(!colors(ColorChoice::Auto, Some("1".into()), terminal));
+        assert!(colors(ColorChoice::Auto, Some("".into()), terminal));
+        // The flag overrides both.
+        assert!(colors(ColorChoice::Always, Some("1".into()), pipe));
+        assert!(!colors(ColorChoice::Never, None, terminal));
+    }
+
+    #[test]
+    fn test_paint() {
+        assert_eq!(paint_if(true, Style::Error, "FAILED"), "\x1b[31mFAILED\x1b[0m");
+        assert_eq!(paint_if(false, Style::Error, "FAILED"), "FAILED");
+        assert_eq!(paint_if(true, Style::Plain, "OK"), "OK");
+    }
+}
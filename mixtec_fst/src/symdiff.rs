@@ -0,0 +1,96 @@
+//! Comparison of two symbol tables (`diff-symbols`).
+//!
+//! An FST stores labels, not symbols, so an FST built against one `chars.txt`
+//! and queried with another reads every label whose symbol has moved as
+//! whatever symbol now has it: the results are wrong without any error. The
+//! tables are compatible when they agree on every label they both define;
+//! symbols that only one of them has are reported, and are harmless as long as
+//! they do not take a label the other gives to a different symbol.
+
+use std::fmt;
+
+use rustfst::{Label, SymbolTable};
+
+/// How two symbol tables differ.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymbolDiff {
+    /// Symbols of the current table missing from the other, with their labels.
+    pub only_current: Vec<(String, Label)>,
+    /// Symbols of the other table missing from the current one.
+    pub only_other: Vec<(String, Label)>,
+    /// Symbols in both tables under different labels: current, then other.
+    pub moved: Vec<(String, Label, Label)>,
+    /// Labels that name different symbols in the two tables: current, then other.
+    pub conflicts: Vec<(Label, String, String)>,
+}
+
+impl SymbolDiff {
+    /// Whether an FST built against one table reads the same under the other.
+    pub fn is_compatible(&self) -> bool {
+        self.moved.is_empty() && self.conflicts.is_empty()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.is_compatible() && self.only_current.is_empty() && self.only_other.is_empty()
+    }
+}
+
+/// Compare `current` against `other`, in label order.
+pub fn diff_symbols(current: &SymbolTable, other: &SymbolTable) -> SymbolDiff {
+    let mut diff = SymbolDiff::default();
+    for (label, symbol) in current.iter() {
+        match other.get_label(symbol) {
+            None => diff.only_current.push((symbol.to_string(), label)),
+            Some(theirs) if theirs != label => diff.moved.push((symbol.to_string(), label, theirs)),
+            Some(_) => {}
+        }
+        if let Some(theirs) = other.get_symbol(label).filter(|&s| s != symbol) {
+            diff.conflicts.push((label, symbol.to_string(), theirs.to_string()));
+        }
+    }
+    diff.only_other = other.iter().filter(|(_, s)| !current.contains_symbol(s)).map(|(l, s)| (s.to_string(), l)).collect();
+    diff
+}
+
+impl fmt::Display for SymbolDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (symbol, label) in self.only_current.iter() {
+            writeln!(f, "only in current: {} ({})", symbol, label)?;
+        }
+        for (symbol, label) in self.only_other.iter() {
+            writeln!(f, "only in other: {} ({})", symbol, label)?;
+        }
+        for (symbol, ours, theirs) in self.moved.iter() {
+            writeln!(f, "moved: {} is {} in current, {} in other", symbol, ours, theirs)?;
+        }
+        for (label, ours, theirs) in self.conflicts.iter() {
+            writeln!(f, "conflict: label {} is {} in current, {} in other", label, ours, theirs)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_symbols() {
+        let current = rustfst::symt!["a", "b", "c", "#"];
+        assert!(diff_symbols(&current, &current.clone()).is_empty());
+
+        // A symbol appended to chars.txt leaves the old labels alone.
+        let appended = rustfst::symt!["a", "b", "c", "#", "d"];
+        let diff = diff_symbols(&current, &appended);
+        assert!(diff.is_compatible() && !diff.is_empty());
+        assert_eq!(diff.only_other, [("d".to_string(), 5)]);
+
+        // One inserted before the boundary moves it.
+        let inserted = rustfst::symt!["a", "b", "c", "d", "#"];
+        let diff = diff_symbols(&current, &inserted);
+        assert!(!diff.is_compatible());
+        assert_eq!(diff.moved, [("#".to_string(), 4, 5)]);
+        assert_eq!(diff.conflicts, [(4, "#".to_string(), "d".to_string())]);
+        assert_eq!(diff.to_string(), "only in other: d (4)\nmoved: # is 4 in current, 5 in other\nconflict: label 4 is # in current, d in other\n");
+    }
+}
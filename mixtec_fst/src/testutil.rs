@@ -0,0 +1,44 @@
+//! Fixtures shared by the unit tests: scratch directories, and the symbols,
+//! rule files and gold items of the crate root.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rustfst::SymbolTable;
+
+use crate::{get_symt_from_file, read_tests};
+
+mod tempdir;
+pub use tempdir::TempDir;
+
+/// The crate root, where the fixtures are.
+pub fn root() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+/// The symbols of the crate's `chars.txt`.
+pub fn fixture_symt() -> Arc<SymbolTable> {
+    get_symt_from_file(root().join("chars.txt").to_str().unwrap(), None).unwrap()
+}
+
+/// The gold items of `tests/i4in4.csv`.
+pub fn fixture_golds() -> Vec<(String, String)> {
+    read_tests(root().join("tests/i4in4.csv").to_str().unwrap(), None).unwrap()
+}
+
+/// The paths of the files `names` of the `rules/min` fixture.
+pub fn min_rules(names: &[&str]) -> Vec<PathBuf> {
+    names.iter().map(|name| root().join("rules/min").join(name)).collect()
+}
+
+/// Copies of the files `names` of the `rules/min` fixture in `dir`.
+pub fn copy_min_rules(dir: &Path, names: &[&str]) -> Vec<PathBuf> {
+    min_rules(names)
+        .into_iter()
+        .map(|file| {
+            let copy = dir.join(file.file_name().unwrap());
+            std::fs::copy(&file, &copy).unwrap();
+            copy
+        })
+        .collect()
+}
@@ -0,0 +1,45 @@
+//! A scratch directory for tests. The integration tests cannot reach the
+//! binary's modules, so they include this file with `#[path]`.
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A fresh directory under the system's temporary directory, removed with
+/// everything in it when dropped, so a failing assertion leaves nothing
+/// behind. Dereferences to its path.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// A new empty directory, its name starting with `name`. Tests run in
+    /// parallel, so each one is numbered apart.
+    pub fn new(name: &str) -> TempDir {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("mixtec_fst-{}-{}-{}", name, std::process::id(), n));
+        // Left over from a run that was killed.
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,200 @@
+//! A tokenizer transducer composed before the segmentation FST (`segment
+//! --tokenizer`), for inputs written in an orthography the symbol table does
+//! not spell, such as a digraph that stands for one symbol.
+//!
+//! A tokenizer is a rule script, compiled with [`rulefst::compile_script`]. Its
+//! sources and contexts are written in the raw characters, which need not be
+//! symbols, and its targets in the symbols of the table, e.g.
+//!
+//! ```text
+//! ꞌ -> '
+//! sh -> x
+//! ```
+//!
+//! A raw character that no rule rewrites must be a symbol already: the
+//! tokenizer only writes symbols, so an input with one left over has no
+//! analysis. Unlike a grapheme map (see [`crate::graphemes`]), which rewrites
+//! the input string, rules can look at the context of what they rewrite.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use itertools::Itertools;
+use parserule::rulefst::{self, symbol_labels, weighted_sigma_star};
+use parserule::ruleparse::{RegexAST, Statement};
+use rustfst::algorithms::tr_map;
+use rustfst::algorithms::tr_mappers::RmWeightMapper;
+use rustfst::prelude::{CoreFst, ExpandedFst, Fst, StateIterator};
+use rustfst::{SymbolTable, EPS_LABEL};
+
+use crate::alphabet::{Compose, SurfaceToAnalysisFst, SurfaceToSurfaceFst};
+use crate::composition::{sorted_compose, ComposeFilter};
+use crate::rules::{check_target_symbols, load_script, script_macros, Script};
+
+/// `symt` with the raw characters `statements` read that are not symbols of
+/// it added after its own, so that its symbols keep their labels.
+fn raw_symt(symt: &SymbolTable, statements: &[Statement]) -> SymbolTable {
+    fn walk(node: &RegexAST, out: &mut Vec<String>) {
+        match node {
+            RegexAST::Char(c) => out.push(c.clone()),
+            RegexAST::Class(class) | RegexAST::ClassComplement(class) => out.extend(class.iter().sorted().cloned()),
+            RegexAST::Group(nodes) | RegexAST::Disjunction(nodes) | RegexAST::Process(nodes) => nodes.iter().for_each(|n| walk(n, out)),
+            RegexAST::Option(n) | RegexAST::Star(n) | RegexAST::Plus(n) => walk(n, out),
+            RegexAST::Macro(_) | RegexAST::Epsilon | RegexAST::Boundary | RegexAST::Comment => {}
+        }
+    }
+    let mut read = Vec::new();
+    for statement in statements {
+        match statement {
+            Statement::MacroDef((_, def)) => walk(def, &mut read),
+            Statement::Rule(rule) => [&rule.left, &rule.source, &rule.right].into_iter().for_each(|n| walk(n, &mut read)),
+            Statement::Comment => {}
+        }
+    }
+    let mut raw = symt.clone();
+    for symbol in read {
+        if symbol_labels(&raw, &symbol).is_none() {
+            raw.add_symbol(symbol);
+        }
+    }
+    raw
+}
+
+/// Compile the tokenizer `script` to an unweighted transducer from raw
+/// characters to the symbols of `symt`. Its targets must be symbols of `symt`;
+/// what it reads is added to its input symbols.
+pub fn compile_tokenizer(symt: Arc<SymbolTable>, script: Script) -> Result<SurfaceToSurfaceFst> {
+    let macros = script_macros(&script.statements);
+    for (i, statement) in script.statements.iter().enumerate() {
+        if let Statement::Rule(rule) = statement {
+            check_target_symbols(&symt, &macros, rule, &format!("tokenizer rule {}", i + 1))?;
+        }
+    }
+    let raw = Arc::new(raw_symt(&symt, &script.statements));
+    let fst = rulefst::compile_script(raw.clone(), script.statements)?;
+    // Raw characters left as they were have no symbol to be written as.
+    let mut fst = sorted_compose(fst, weighted_sigma_star(symt.clone(), 0.0)?, ComposeFilter::Auto)?;
+    // The compilation charges every symbol; tokenizing should not change the
+    // weights of the analyses.
+    tr_map(&mut fst, &RmWeightMapper {})?;
+    fst.set_input_symbols(raw);
+    fst.set_output_symbols(symt);
+    Ok(SurfaceToSurfaceFst(fst))
+}
+
+/// Read and compile the tokenizer script at `path` (see [`compile_tokenizer`]).
+pub fn read_tokenizer(symt: Arc<SymbolTable>, path: &Path) -> Result<SurfaceToSurfaceFst> {
+    compile_tokenizer(symt, load_script(path)?).with_context(|| format!("Failed to compile tokenizer {}", path.display()))
+}
+
+/// Fail unless every symbol `tokenizer` writes is read by `fst` under the same
+/// label, since composition matches labels rather than symbols.
+pub fn check_tokenizer(tokenizer: &SurfaceToSurfaceFst, fst: &SurfaceToAnalysisFst) -> Result<()> {
+    let written = tokenizer.output_symbols().ok_or_else(|| anyhow!("Tokenizer has no output symbol table"))?;
+    let read = fst.input_symbols().ok_or_else(|| anyhow!("FST has no input symbol table"))?;
+    let mut labels = BTreeSet::new();
+    for s in tokenizer.states_iter() {
+        labels.extend(tokenizer.get_trs(s)?.iter().map(|tr| tr.olabel).filter(|&l| l != EPS_LABEL));
+    }
+    let mismatched: Vec<String> = labels
+        .into_iter()
+        .filter(|&l| read.get_symbol(l) != written.get_symbol(l))
+        .map(|l| match read.get_symbol(l) {
+            Some(other) => format!("'{}' (read as '{}')", written.get_symbol(l).unwrap_or("?"), other),
+            None => format!("'{}' (not read)", written.get_symbol(l).unwrap_or("?")),
+        })
+        .collect();
+    if !mismatched.is_empty() {
+        bail!("The tokenizer writes symbols the FST does not read under the same label: {}", mismatched.join(", "));
+    }
+    Ok(())
+}
+
+/// `fst` reading raw input through `tokenizer`, once [`check_tokenizer`]
+/// passes. Its input symbols are those of the tokenizer.
+pub fn compose_tokenizer(tokenizer: &SurfaceToSurfaceFst, fst: &SurfaceToAnalysisFst, filter: ComposeFilter) -> Result<SurfaceToAnalysisFst> {
+    check_tokenizer(tokenizer, fst)?;
+    let mut composed = tokenizer.compose(fst, filter)?;
+    if let Some(raw) = tokenizer.input_symbols() {
+        composed.0.set_input_symbols(raw.clone());
+    }
+    if let Some(symt) = fst.output_symbols() {
+        composed.0.set_output_symbols(symt.clone());
+    }
+    log::debug!("tokenizer composed: {} states", composed.num_states());
+    Ok(composed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use rustfst::prelude::TropicalWeight;
+    use rustfst::Semiring;
+
+    use crate::alphabet::SurfaceAcceptor;
+    use crate::automaton::Tokenization;
+    use crate::decode::{decode_distinct_outputs, display_labels};
+    use crate::ranking::Lexicographic;
+    use crate::rules::parse_script_source;
+
+    fn symt() -> Arc<SymbolTable> {
+        Arc::new(rustfst::symt!["#", "a", "s", "x", "'"])
+    }
+
+    fn tokenizer(source: &str) -> SurfaceToSurfaceFst {
+        compile_tokenizer(symt(), parse_script_source(&PathBuf::from("tokenizer.txt"), source).unwrap()).unwrap()
+    }
+
+    /// Copies its input, `#` and symbols.
+    fn identity() -> SurfaceToAnalysisFst {
+        let mut fst = weighted_sigma_star(symt(), 0.0).unwrap();
+        fst.set_input_symbols(symt());
+        fst.set_output_symbols(symt());
+        SurfaceToAnalysisFst(fst)
+    }
+
+    fn analyses(fst: &SurfaceToAnalysisFst, input: &str) -> Vec<(TropicalWeight, String)> {
+        let raw = fst.input_symbols().unwrap().clone();
+        let lattice = SurfaceAcceptor::of(&raw, input, Tokenization::default(), None).unwrap().compose(fst, ComposeFilter::Auto).unwrap();
+        decode_distinct_outputs(&lattice, None, &Lexicographic, |l| display_labels(&symt(), l)).unwrap()
+    }
+
+    fn outputs(fst: &SurfaceToAnalysisFst, input: &str) -> Vec<String> {
+        analyses(fst, input).into_iter().map(|(_, o)| o).collect()
+    }
+
+    fn weights(fst: &SurfaceToAnalysisFst, input: &str) -> Vec<TropicalWeight> {
+        analyses(fst, input).into_iter().map(|(w, _)| w).collect()
+    }
+
+    #[test]
+    fn test_digraph_and_raw_character_become_symbols() {
+        let fst = compose_tokenizer(&tokenizer("sh -> x\nꞌ -> '\n"), &identity(), ComposeFilter::Auto).unwrap();
+        assert_eq!(outputs(&fst, "#shaꞌa#"), ["#xa'a#"]);
+        assert_eq!(outputs(&fst, "#sas#"), ["#sas#"]);
+        assert_eq!(weights(&fst, "#sas#"), [TropicalWeight::one()]);
+        // An h on its own is not a symbol, and no rule rewrites it.
+        assert!(outputs(&fst, "#ha#").is_empty());
+    }
+
+    #[test]
+    fn test_target_must_be_a_symbol() {
+        let script = parse_script_source(&PathBuf::from("tokenizer.txt"), "sh -> š\n").unwrap();
+        let err = compile_tokenizer(symt(), script).unwrap_err().to_string();
+        assert!(err.contains("tokenizer rule 1"), "{}", err);
+    }
+
+    #[test]
+    fn test_symbols_must_match_the_fst() {
+        let tokenizer = tokenizer("sh -> x\n");
+        let mut other = identity();
+        let reordered = Arc::new(rustfst::symt!["#", "a", "x", "s", "'"]);
+        other.0.set_input_symbols(reordered);
+        let err = check_tokenizer(&tokenizer, &other).unwrap_err().to_string();
+        assert!(err.contains("'s' (read as 'x')") && err.contains("'x' (read as 's')"), "{}", err);
+    }
+}
@@ -0,0 +1,164 @@
+//! The tone inventory of the dialect (`--tones`).
+//!
+//! Tones are single symbols, written after the vowel they belong to. Process
+//! annotations (`{3>4}`) rewrite sequences of them, so anything that has to
+//! recognise a tone or a process, such as the script that strips processes
+//! from G3 analyses, is built from a [`ToneSet`] rather than spelling out
+//! `1234`.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use parserule::ruleparse::{parse_script, Statement};
+use rustfst::SymbolTable;
+
+/// The tones of the dialects the rule files were written for.
+pub const DEFAULT_TONES: &str = "1234";
+
+/// Characters the process notation itself uses, which cannot be tones.
+const PROCESS_CHARS: &str = "{}>#";
+
+/// Characters that must be escaped in rule syntax.
+const RULE_SPECIAL_CHARS: &str = "\\ /<>_()[]-|*+^#:%";
+
+/// The tone symbols, in order, without duplicates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToneSet {
+    tones: Vec<char>,
+}
+
+impl Default for ToneSet {
+    fn default() -> Self {
+        ToneSet::parse(DEFAULT_TONES).unwrap()
+    }
+}
+
+impl ToneSet {
+    /// Parse a tone inventory given as its symbols (`12345`), as ranges
+    /// (`1-5`), or as a mix of both.
+    pub fn parse(s: &str) -> Result<Self> {
+        let chars: Vec<char> = s.chars().collect();
+        let mut tones = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            if i + 2 < chars.len() && chars[i + 1] == '-' {
+                let (from, to) = (chars[i], chars[i + 2]);
+                if from > to {
+                    bail!("Empty tone range '{}-{}' in '{}'", from, to, s);
+                }
+                tones.extend(from..=to);
+                i += 3;
+            } else {
+                tones.push(chars[i]);
+                i += 1;
+            }
+        }
+        if tones.is_empty() {
+            bail!("No tones given");
+        }
+        for (j, &t) in tones.iter().enumerate() {
+            if t.is_whitespace() || PROCESS_CHARS.contains(t) {
+                bail!("'{}' cannot be a tone, since process annotations use it", t.escape_default());
+            }
+            if tones[..j].contains(&t) {
+                bail!("Tone '{}' is listed twice in '{}'", t, s);
+            }
+        }
+        Ok(ToneSet { tones })
+    }
+
+    /// Whether `c` is one of the tones.
+    pub fn contains(&self, c: char) -> bool {
+        self.tones.contains(&c)
+    }
+
+    /// Whether the symbol `symbol` is one of the tones.
+    pub fn is_tone_symbol(&self, symbol: &str) -> bool {
+        let mut chars = symbol.chars();
+        matches!((chars.next(), chars.next()), (Some(c), None) if self.contains(c))
+    }
+
+    /// Check that every tone has a label in `symt`.
+    pub fn validate(&self, symt: &Arc<SymbolTable>) -> Result<()> {
+        let missing: Vec<String> =
+            self.tones.iter().map(|t| t.to_string()).filter(|t| symt.get_label(t).is_none()).collect();
+        if !missing.is_empty() {
+            bail!("Symbol table has no label for tones {:?}", missing);
+        }
+        Ok(())
+    }
+
+    /// The tones, spelled for use inside a rule-syntax character class.
+    fn class_members(&self) -> String {
+        self.tones.iter().map(|&t| if RULE_SPECIAL_CHARS.contains(t) { format!("\\{}", t) } else { t.to_string() }).collect()
+    }
+
+    /// The rule script that removes the process annotations of an analysis
+    /// (`{3>4}` and the like), turning a G3 analysis into its base form.
+    pub fn g3_to_base_script(&self) -> String {
+        let tones = self.class_members();
+        format!("\\>[{tones}\\>]*}} -> 0 / {{[{tones}]* _ \n{{ -> 0 / _ [{tones}]+")
+    }
+
+    /// [`ToneSet::g3_to_base_script`], parsed; fails if it does not parse as
+    /// exactly its two rules.
+    pub fn g3_to_base_rules(&self) -> Result<Vec<Statement>> {
+        let script = self.g3_to_base_script();
+        let (rest, (statements, _)) =
+            parse_script(&script).map_err(|e| anyhow!("Failed to parse the G3-to-base script for tones {}: {}", self, e))?;
+        let rules = statements.iter().filter(|s| matches!(s, Statement::Rule(_))).count();
+        if rules != 2 || !rest.trim().is_empty() {
+            bail!("The G3-to-base script for tones {} did not parse as two rules:\n{}", self, script);
+        }
+        Ok(statements)
+    }
+}
+
+impl std::fmt::Display for ToneSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.tones.iter().collect::<String>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parserule::rulefst;
+    use rustfst::prelude::{tr_sort, ILabelCompare};
+
+    #[test]
+    fn test_default_script_matches_four_tone_script() {
+        assert_eq!(ToneSet::default().g3_to_base_script(), "\\>[1234\\>]*} -> 0 / {[1234]* _ \n{ -> 0 / _ [1234]+");
+        assert!(ToneSet::default().g3_to_base_rules().is_ok());
+    }
+
+    #[test]
+    fn test_parse_lists_and_ranges() {
+        assert_eq!(ToneSet::parse("1-5").unwrap().to_string(), "12345");
+        assert_eq!(ToneSet::parse("1-35").unwrap().to_string(), "1235");
+        assert!(ToneSet::parse("").is_err());
+        assert!(ToneSet::parse("121").is_err());
+        assert!(ToneSet::parse("12>").is_err());
+        assert!(ToneSet::parse("3-1").is_err());
+    }
+
+    #[test]
+    fn test_special_tone_symbols_are_escaped() {
+        let tones = ToneSet::parse("12+").unwrap();
+        assert!(tones.g3_to_base_script().contains("[12\\+]"));
+        assert!(tones.g3_to_base_rules().is_ok());
+    }
+
+    #[test]
+    fn test_fifth_tone_processes_are_stripped() {
+        let symt = Arc::new(rustfst::symt!["#", "n", "i", "1", "2", "3", "4", "5", "{", ">", "}"]);
+        let base = |tones: &ToneSet| -> Vec<String> {
+            let mut fst = crate::get_fst_g3_to_base(symt.clone(), tones).unwrap().0;
+            tr_sort(&mut fst, ILabelCompare {});
+            let e2e = rulefst::apply_fst_to_string(symt.clone(), fst, "#ni{5>2}5#".to_string()).unwrap();
+            rulefst::decode_paths_through_fst(symt.clone(), e2e).into_iter().map(|(_, o)| o).collect()
+        };
+        assert!(base(&ToneSet::parse("1-5").unwrap()).contains(&"#ni55#".to_string()));
+        assert!(!base(&ToneSet::default()).contains(&"#ni55#".to_string()));
+    }
+}
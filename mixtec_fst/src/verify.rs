@@ -0,0 +1,506 @@
+//! A sampling check that an FST operation preserved the language
+//! (`--verify-determinize`, `--verify-minimize`).
+//!
+//! Functional determinization can quietly drop paths when its input is not
+//! functional, or when weights differ by less than its delta, and minimizing a
+//! non-deterministic transducer determinizes it first. To catch that,
+//! input strings accepted by the FST before the operation are sampled by random
+//! walks over its input side, both FSTs are applied to each sample, and the
+//! (unweighted) output languages are compared as minimal DFAs, so that inputs
+//! with very many analyses are still cheap to check. Only sampled inputs are
+//! checked, so a clean report is evidence rather than proof.
+//!
+//! Example outputs of a divergence are listed with their best weight on the
+//! side that has them.
+
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Result};
+use rustfst::prelude::compose::compose;
+use rustfst::prelude::determinize::determinize;
+use rustfst::prelude::rm_epsilon::rm_epsilon;
+use rustfst::prelude::{
+    isomorphic, minimize, minimize_with_config, project, shortest_path_with_config, tr_sort, CoreFst, ExpandedFst, Fst, ILabelCompare,
+    MinimizeConfig, MutableFst, ProjectType, ShortestPathConfig, TropicalWeight, VectorFst,
+};
+use rustfst::{Label, Semiring, StateId, SymbolTable, Tr, EPS_LABEL};
+
+use crate::decode::display_labels;
+use crate::dump::{guard_in_place, Operation};
+use crate::status::internal;
+
+/// Most example outputs listed for each side of a divergence.
+const MAX_EXAMPLES: usize = 10;
+
+/// How many inputs to sample, and how.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VerifyOptions {
+    /// Number of distinct inputs to check.
+    pub samples: usize,
+    /// Longest input to sample, in symbols.
+    pub max_len: usize,
+    pub seed: u64,
+    /// Fail on any divergence rather than only reporting it.
+    pub strict: bool,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        VerifyOptions { samples: 200, max_len: 32, seed: 0, strict: false }
+    }
+}
+
+/// What to do when an operation is found to change the outputs of an FST.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnDivergence {
+    /// Report the divergences and keep the result
+    Report,
+    /// Report the divergences and keep the FST from before the operation
+    #[default]
+    Keep,
+    /// Fail
+    Abort,
+}
+
+/// An input on which the two FSTs disagree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub input: String,
+    /// Some outputs of the original FST that the result lacks, with their weights.
+    pub missing: Vec<(String, TropicalWeight)>,
+    /// Some outputs of the result that the original FST lacks, with their weights.
+    pub extra: Vec<(String, TropicalWeight)>,
+}
+
+/// SplitMix64, enough to spread random walks without another dependency.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        ((z ^ (z >> 31)) % n as u64) as usize
+    }
+}
+
+/// One random walk from the start state: walk until at least a randomly chosen
+/// length is read and a final state is reached. `None` for walks that dead-end
+/// or run past `max_len`.
+fn random_walk(fst: &VectorFst<TropicalWeight>, start: StateId, max_len: usize, rng: &mut Rng) -> Result<Option<Vec<Label>>> {
+    let target = rng.below(max_len + 1);
+    let mut state = start;
+    let mut input = Vec::new();
+    // Epsilon arcs count towards the limit too, so that epsilon cycles end.
+    for _ in 0..2 * max_len + 1 {
+        if input.len() >= target && fst.is_final(state)? {
+            return Ok(Some(input));
+        }
+        let trs = fst.get_trs(state)?;
+        if trs.is_empty() {
+            return Ok(None);
+        }
+        let tr = &trs[rng.below(trs.len())];
+        if tr.ilabel != EPS_LABEL {
+            input.push(tr.ilabel);
+            if input.len() > max_len {
+                return Ok(None);
+            }
+        }
+        state = tr.nextstate;
+    }
+    Ok(None)
+}
+
+/// Up to `opts.samples` distinct inputs accepted by `fst`, shortest first.
+pub fn sample_inputs(fst: &VectorFst<TropicalWeight>, opts: &VerifyOptions) -> Result<Vec<Vec<Label>>> {
+    let Some(start) = fst.start() else {
+        return Ok(Vec::new());
+    };
+    let mut rng = Rng(opts.seed);
+    let mut samples = BTreeSet::new();
+    for _ in 0..opts.samples * 20 {
+        if samples.len() >= opts.samples {
+            break;
+        }
+        if let Some(input) = random_walk(fst, start, opts.max_len, &mut rng)? {
+            samples.insert(input);
+        }
+    }
+    let mut samples: Vec<_> = samples.into_iter().collect();
+    samples.sort_by_key(|s| s.len());
+    Ok(samples)
+}
+
+/// The outputs of the input-sorted `fst` on `input`, weighted, as an acceptor
+/// without epsilons.
+fn output_lattice(fst: &VectorFst<TropicalWeight>, input: &[Label]) -> Result<VectorFst<TropicalWeight>> {
+    let mut acceptor = VectorFst::<TropicalWeight>::new();
+    let mut state = acceptor.add_state();
+    acceptor.set_start(state)?;
+    for &label in input {
+        let next = acceptor.add_state();
+        acceptor.add_tr(state, Tr::new(label, label, 0.0, next))?;
+        state = next;
+    }
+    acceptor.set_final(state, 0.0)?;
+    let mut lang: VectorFst<TropicalWeight> =
+        internal(compose::<_, VectorFst<_>, VectorFst<_>, _, _, _>(acceptor, fst))?;
+    project(&mut lang, ProjectType::ProjectOutput);
+    internal(rm_epsilon(&mut lang))?;
+    Ok(lang)
+}
+
+/// The language of the acceptor `lattice`, unweighted, as a minimal DFA. Two
+/// such DFAs are isomorphic exactly when the languages match, however many
+/// outputs there are.
+fn output_language(lattice: &VectorFst<TropicalWeight>) -> Result<VectorFst<TropicalWeight>> {
+    let mut lang = lattice.clone();
+    for s in 0..lang.num_states() as StateId {
+        let num_trs = lang.num_trs(s)?;
+        let mut trs = lang.tr_iter_mut(s)?;
+        for i in 0..num_trs {
+            trs.set_weight(i, TropicalWeight::one())?;
+        }
+        if lang.is_final(s)? {
+            lang.set_final(s, TropicalWeight::one())?;
+        }
+    }
+    let mut lang: VectorFst<TropicalWeight> = internal(determinize(&lang))?;
+    internal(minimize(&mut lang))?;
+    Ok(lang)
+}
+
+fn dfa_accepts(dfa: &VectorFst<TropicalWeight>, labels: &[Label]) -> Result<bool> {
+    let Some(mut state) = dfa.start() else {
+        return Ok(false);
+    };
+    for &label in labels {
+        match dfa.get_trs(state)?.iter().find(|tr| tr.ilabel == label) {
+            Some(tr) => state = tr.nextstate,
+            None => return Ok(false),
+        }
+    }
+    dfa.is_final(state)
+}
+
+/// Up to [`MAX_EXAMPLES`] of the best outputs of `lattice` that the DFA `other`
+/// lacks, with their weights.
+fn examples_missing_from(
+    symt: &SymbolTable,
+    lattice: &VectorFst<TropicalWeight>,
+    other: &VectorFst<TropicalWeight>,
+) -> Result<Vec<(String, TropicalWeight)>> {
+    let mut examples: Vec<(String, TropicalWeight)> = Vec::new();
+    let nbest: VectorFst<TropicalWeight> =
+        internal(shortest_path_with_config(lattice, ShortestPathConfig::default().with_nshortest(MAX_EXAMPLES * 10)))?;
+    for path in nbest.paths_iter() {
+        let output = display_labels(symt, &path.olabels);
+        if examples.len() < MAX_EXAMPLES
+            && !examples.iter().any(|(o, _)| o == &output)
+            && !dfa_accepts(other, &path.olabels)?
+        {
+            examples.push((output, path.weight));
+        }
+    }
+    examples.sort_by(|(o1, _), (o2, _)| o1.cmp(o2));
+    Ok(examples)
+}
+
+/// Compare `before` and `after` on inputs sampled from `before`, returning the
+/// number of inputs checked and those on which they disagree.
+pub fn compare_on_samples(
+    symt: &SymbolTable,
+    before: &VectorFst<TropicalWeight>,
+    after: &VectorFst<TropicalWeight>,
+    opts: &VerifyOptions,
+) -> Result<(usize, Vec<Divergence>)> {
+    let samples = sample_inputs(before, opts)?;
+    let mut before = before.clone();
+    let mut after = after.clone();
+    tr_sort(&mut before, ILabelCompare {});
+    tr_sort(&mut after, ILabelCompare {});
+    let mut divergences = Vec::new();
+    for input in samples.iter() {
+        let (expected, actual) = (output_lattice(&before, input)?, output_lattice(&after, input)?);
+        let (expected_lang, actual_lang) = (output_language(&expected)?, output_language(&actual)?);
+        if !isomorphic(&expected_lang, &actual_lang)? {
+            divergences.push(Divergence {
+                input: display_labels(symt, input),
+                missing: examples_missing_from(symt, &expected, &actual_lang)?,
+                extra: examples_missing_from(symt, &actual, &expected_lang)?,
+            });
+        }
+    }
+    Ok((samples.len(), divergences))
+}
+
+/// Compare `before` and `after` as [`compare_on_samples`] does, printing the
+/// outcome for the operation named `what`; returns the divergences.
+fn report_on_samples(
+    what: &str,
+    symt: &SymbolTable,
+    before: &VectorFst<TropicalWeight>,
+    after: &VectorFst<TropicalWeight>,
+    opts: &VerifyOptions,
+) -> Result<Vec<Divergence>> {
+    let (checked, divergences) = compare_on_samples(symt, before, after, opts)?;
+    if divergences.is_empty() {
+        println!("Verified {} on {} sampled inputs", what, checked);
+        return Ok(divergences);
+    }
+    println!("{} changed the outputs of {} of {} sampled inputs:", what, divergences.len(), checked);
+    let list = |outputs: &[(String, TropicalWeight)]| {
+        outputs.iter().map(|(o, w)| format!("{} ({})", o, w)).collect::<Vec<_>>().join(", ")
+    };
+    for d in divergences.iter() {
+        println!("  '{}': missing [{}], extra [{}]", d.input, list(&d.missing), list(&d.extra));
+    }
+    Ok(divergences)
+}
+
+/// Check that the operation named `what` turned `before` into an equivalent
+/// `after`, printing any divergences, and failing on them under
+/// `opts.strict`.
+pub fn verify_equivalent(
+    what: &str,
+    symt: &SymbolTable,
+    before: &VectorFst<TropicalWeight>,
+    after: &VectorFst<TropicalWeight>,
+    opts: &VerifyOptions,
+) -> Result<()> {
+    let divergences = report_on_samples(what, symt, before, after, opts)?;
+    if opts.strict && !divergences.is_empty() {
+        bail!("{} does not preserve the language ({} diverging inputs)", what, divergences.len());
+    }
+    Ok(())
+}
+
+/// Apply the operation `op`, named `what`, to `fst`, and with `verify`, check
+/// the result on inputs sampled from `fst` beforehand. Divergences are handled
+/// as `on_divergence` says; returns whether `fst` holds the result of `op`.
+pub fn apply_verified<F>(
+    what: &str,
+    symt: &SymbolTable,
+    fst: &mut VectorFst<TropicalWeight>,
+    verify: Option<(VerifyOptions, OnDivergence)>,
+    op: F,
+) -> Result<bool>
+where
+    F: FnOnce(&mut VectorFst<TropicalWeight>) -> Result<()>,
+{
+    let Some((opts, on_divergence)) = verify else {
+        op(fst)?;
+        return Ok(true);
+    };
+    let before = fst.clone();
+    op(fst)?;
+    let divergences = report_on_samples(what, symt, &before, fst, &opts)?;
+    if divergences.is_empty() {
+        return Ok(true);
+    }
+    match on_divergence {
+        OnDivergence::Report => Ok(true),
+        OnDivergence::Keep => {
+            println!("Keeping the FST from before {}", what.to_lowercase());
+            *fst = before;
+            Ok(false)
+        }
+        OnDivergence::Abort => bail!("{} does not preserve the language ({} diverging inputs)", what, divergences.len()),
+    }
+}
+
+/// Whether to minimize an FST that is not input-deterministic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Nondeterminism {
+    /// Minimize it with `allow_nondet`, which determinizes it first and need
+    /// not give a minimal FST.
+    #[default]
+    Allow,
+    /// Fail (`build --validate-determinism`).
+    Refuse,
+}
+
+/// The states of `fst` with two transitions reading the same input label.
+pub fn nondeterministic_states(fst: &VectorFst<TropicalWeight>) -> Vec<StateId> {
+    (0..fst.num_states() as StateId)
+        .filter(|&s| {
+            fst.get_trs(s).is_ok_and(|trs| {
+                let mut ilabels: Vec<Label> = trs.iter().map(|tr| tr.ilabel).collect();
+                ilabels.sort_unstable();
+                ilabels.windows(2).any(|w| w[0] == w[1])
+            })
+        })
+        .collect()
+}
+
+/// Minimize `fst` as `policy` says if it is not input-deterministic, and
+/// return the number of its states that are not. rustfst says nothing of that
+/// beyond the FST's properties, so they are counted here and logged.
+pub fn minimize_nondet(fst: &mut VectorFst<TropicalWeight>, policy: Nondeterminism) -> Result<usize> {
+    let states = nondeterministic_states(fst);
+    if let Some(&first) = states.first() {
+        log::debug!("Minimizing an FST with {} of {} states not input-deterministic (first {})", states.len(), fst.num_states(), first);
+        if policy == Nondeterminism::Refuse {
+            bail!(
+                "The FST is not input-deterministic at {} of {} states (first {}): the rule set is ambiguous, and minimizing it may leave it non-minimal",
+                states.len(),
+                fst.num_states(),
+                first
+            );
+        }
+    }
+    internal(minimize_with_config(fst, MinimizeConfig { delta: 1e-7, allow_nondet: true }))?;
+    Ok(states.len())
+}
+
+/// Minimize `fst` as `policy` says if it is not input-deterministic, checked
+/// as [`apply_verified`] does.
+pub fn minimize_verified(
+    symt: &SymbolTable,
+    fst: &mut VectorFst<TropicalWeight>,
+    verify: Option<(VerifyOptions, OnDivergence)>,
+    policy: Nondeterminism,
+) -> Result<bool> {
+    apply_verified("Minimization", symt, fst, verify, |fst| {
+        let op = Operation::Minimize { delta: 1e-7, allow_nondet: true };
+        guard_in_place(op, fst, |fst| {
+            let nondet = minimize_nondet(fst, policy)?;
+            if nondet > 0 {
+                println!("The FST was not input-deterministic at {} states, so the rule set is ambiguous and it may not be minimal (--validate-determinism refuses it)", nondet);
+            }
+            Ok(())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustfst::prelude::determinize::{determinize_with_config, DeterminizeConfig, DeterminizeType};
+    use rustfst::prelude::union::union;
+    use rustfst::utils::transducer;
+
+    fn symt() -> SymbolTable {
+        rustfst::symt!["a", "b", "c"]
+    }
+
+    /// a -> b or a -> c, as two paths.
+    fn ambiguous() -> VectorFst<TropicalWeight> {
+        let mut fst: VectorFst<TropicalWeight> = rustfst::fst![1 => 2; 1.0];
+        let other: VectorFst<TropicalWeight> = rustfst::fst![1 => 3; 2.0];
+        union(&mut fst, &other).unwrap();
+        fst
+    }
+
+    #[test]
+    fn test_samples_are_accepted_and_reproducible() {
+        let mut fst = VectorFst::<TropicalWeight>::new();
+        let s = fst.add_state();
+        fst.set_start(s).unwrap();
+        fst.set_final(s, 0.0).unwrap();
+        fst.add_tr(s, Tr::new(1, 1, 0.0, s)).unwrap();
+        fst.add_tr(s, Tr::new(2, 2, 0.0, s)).unwrap();
+        let opts = VerifyOptions { samples: 20, max_len: 6, ..Default::default() };
+        let samples = sample_inputs(&fst, &opts).unwrap();
+        assert_eq!(samples.len(), 20);
+        assert!(samples.iter().all(|s| s.len() <= 6 && s.iter().all(|l| [1, 2].contains(l))));
+        assert_eq!(samples, sample_inputs(&fst, &opts).unwrap());
+    }
+
+    #[test]
+    fn test_non_functional_determinize_preserves_outputs() {
+        let fst = ambiguous();
+        let det: VectorFst<TropicalWeight> =
+            determinize_with_config(&fst, DeterminizeConfig { delta: 1e-7, det_type: DeterminizeType::DeterminizeNonFunctional }).unwrap();
+        let (checked, divergences) = compare_on_samples(&symt(), &fst, &det, &VerifyOptions::default()).unwrap();
+        assert_eq!(checked, 1);
+        assert!(divergences.is_empty(), "{:?}", divergences);
+    }
+
+    #[test]
+    fn test_nondeterminism_policy() {
+        // a -> b or a -> c, on two transitions from the start.
+        let mut ambiguous = VectorFst::<TropicalWeight>::new();
+        let (s, t) = (ambiguous.add_state(), ambiguous.add_state());
+        ambiguous.set_start(s).unwrap();
+        ambiguous.set_final(t, 0.0).unwrap();
+        ambiguous.add_tr(s, Tr::new(1, 2, 1.0, t)).unwrap();
+        ambiguous.add_tr(s, Tr::new(1, 3, 2.0, t)).unwrap();
+        let mut fst = ambiguous.clone();
+        assert_eq!(nondeterministic_states(&fst), [s]);
+        let err = minimize_nondet(&mut fst, Nondeterminism::Refuse).unwrap_err();
+        assert!(err.to_string().contains("the rule set is ambiguous"), "{}", err);
+        assert_eq!(fst, ambiguous);
+        assert_eq!(minimize_nondet(&mut fst, Nondeterminism::Allow).unwrap(), 1);
+        let mut deterministic: VectorFst<TropicalWeight> = rustfst::fst![1, 2 => 2, 3; 1.0];
+        assert_eq!(minimize_nondet(&mut deterministic, Nondeterminism::Refuse).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_dropped_path_is_reported() {
+        let fst = ambiguous();
+        let dropped: VectorFst<TropicalWeight> = rustfst::fst![1 => 2; 1.0];
+        let (_, divergences) = compare_on_samples(&symt(), &fst, &dropped, &VerifyOptions::default()).unwrap();
+        let missing = vec![("c".to_string(), TropicalWeight::new(2.0))];
+        assert_eq!(divergences, [Divergence { input: "a".to_string(), missing, extra: vec![] }]);
+        let strict = VerifyOptions { strict: true, ..Default::default() };
+        assert!(verify_equivalent("determinize", &symt(), &fst, &dropped, &VerifyOptions::default()).is_ok());
+        let err = verify_equivalent("determinize", &symt(), &fst, &dropped, &strict).unwrap_err();
+        assert!(err.to_string().contains("1 diverging inputs"), "{}", err);
+    }
+
+    /// ab -> xy (1) or ab -> xz (2), on two paths that share nothing, and
+    /// what merging their states on the input side alone makes of it: the
+    /// second output is lost.
+    fn two_analyses_and_naive_merge() -> (VectorFst<TropicalWeight>, VectorFst<TropicalWeight>) {
+        let symt = rustfst::symt!["a", "b", "x", "y", "z"];
+        let label = |s: &str| symt.get_label(s).unwrap();
+        let mut fst: VectorFst<TropicalWeight> = VectorFst::new();
+        let start = fst.add_state();
+        fst.set_start(start).unwrap();
+        for (out, w) in [("y", 1.0), ("z", 2.0)] {
+            let (mid, end) = (fst.add_state(), fst.add_state());
+            fst.add_tr(start, Tr::new(label("a"), label("x"), w, mid)).unwrap();
+            fst.add_tr(mid, Tr::new(label("b"), label(out), 0.0, end)).unwrap();
+            fst.set_final(end, 0.0).unwrap();
+        }
+        let merged: VectorFst<TropicalWeight> = rustfst::fst![label("a"), label("b") => label("x"), label("y"); 1.0];
+        (fst, merged)
+    }
+
+    #[test]
+    fn test_minimize_keeps_every_candidate() {
+        let (fst, _) = two_analyses_and_naive_merge();
+        let symt = rustfst::symt!["a", "b", "x", "y", "z"];
+        let mut minimized = fst.clone();
+        let verify = Some((VerifyOptions { strict: true, ..Default::default() }, OnDivergence::Abort));
+        assert!(minimize_verified(&symt, &mut minimized, verify, Nondeterminism::Allow).unwrap());
+        let (_, divergences) = compare_on_samples(&symt, &fst, &minimized, &VerifyOptions::default()).unwrap();
+        assert!(divergences.is_empty(), "{:?}", divergences);
+    }
+
+    #[test]
+    fn test_lossy_merge_is_reported_and_handled() {
+        let (fst, merged) = two_analyses_and_naive_merge();
+        let symt = rustfst::symt!["a", "b", "x", "y", "z"];
+        let (_, divergences) = compare_on_samples(&symt, &fst, &merged, &VerifyOptions::default()).unwrap();
+        let missing = vec![("xz".to_string(), TropicalWeight::new(2.0))];
+        assert_eq!(divergences, [Divergence { input: "ab".to_string(), missing, extra: vec![] }]);
+
+        let lossy = |f: &mut VectorFst<TropicalWeight>| {
+            *f = merged.clone();
+            Ok(())
+        };
+        let opts = VerifyOptions::default();
+        let mut kept = fst.clone();
+        assert!(!apply_verified("Merging", &symt, &mut kept, Some((opts, OnDivergence::Keep)), lossy).unwrap());
+        assert_eq!(kept, fst);
+        let mut reported = fst.clone();
+        assert!(apply_verified("Merging", &symt, &mut reported, Some((opts, OnDivergence::Report)), lossy).unwrap());
+        assert_eq!(reported, merged);
+        let err = apply_verified("Merging", &symt, &mut fst.clone(), Some((opts, OnDivergence::Abort)), lossy).unwrap_err();
+        assert!(err.to_string().contains("1 diverging inputs"), "{}", err);
+    }
+}
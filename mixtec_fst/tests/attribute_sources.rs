@@ -0,0 +1,35 @@
+//! An FST built with `--attribute-sources` is an FST like any other to the
+//! commands run on it without the flag: its source markers never show up in
+//! what they output or compare.
+
+#[path = "../src/testutil/tempdir.rs"]
+mod tempdir;
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+use tempdir::TempDir;
+
+fn mixtec_fst(dir: &Path, args: &[&str]) -> Output {
+    // chars.txt is read from the working directory.
+    let output = Command::new(env!("CARGO_BIN_EXE_mixtec_fst")).current_dir(env!("CARGO_MANIFEST_DIR")).arg("--out-dir").arg(dir).args(args).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+#[test]
+fn test_marked_fst_tests_and_segments_without_the_flag() {
+    let dir = TempDir::new("sources");
+    std::fs::create_dir(dir.join("rules")).unwrap();
+    std::fs::write(dir.join("rules/a_to_e.txt"), "a -> e / _ 1\n").unwrap();
+    let fst = dir.join("t.fst").to_str().unwrap().to_string();
+    mixtec_fst(&dir, &["build", &fst, "--srcdir", dir.join("rules").to_str().unwrap(), "--attribute-sources"]);
+    assert!(dir.join("t.fst.sources").exists());
+
+    std::fs::write(dir.join("gold.csv"), "segmentation,form\nke1,ka1\n").unwrap();
+    let gold = dir.join("gold.csv");
+    mixtec_fst(&dir, &["test", &fst, "-t", gold.to_str().unwrap()]);
+    mixtec_fst(&dir, &["test", &fst, "-t", gold.to_str().unwrap(), "--fast-check"]);
+    let segmented = mixtec_fst(&dir, &["segment", &fst, "ka1"]);
+    assert!(String::from_utf8_lossy(&segmented.stdout).contains("ke1"), "{}", String::from_utf8_lossy(&segmented.stdout));
+}
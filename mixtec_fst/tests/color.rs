@@ -0,0 +1,61 @@
+//! Coloured output only reaches terminals: runs the binary with its output
+//! piped and checks that neither the output nor the files it writes contain
+//! escape sequences unless `--color always` asks for them on the output.
+
+#[path = "../src/testutil/tempdir.rs"]
+mod tempdir;
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use tempdir::TempDir;
+
+const ESC: u8 = 0x1b;
+
+fn mixtec_fst(dir: &Path, args: &[&str]) -> Output {
+    // chars.txt is read from the working directory.
+    Command::new(env!("CARGO_BIN_EXE_mixtec_fst"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .env_remove("NO_COLOR")
+        .arg("--out-dir")
+        .arg(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn has_escape(bytes: &[u8]) -> bool {
+    bytes.contains(&ESC)
+}
+
+fn read(path: PathBuf) -> Vec<u8> {
+    std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+}
+
+#[test]
+fn test_piped_output_and_reports_are_plain() {
+    let dir = TempDir::new("color");
+    std::fs::create_dir(dir.join("rules")).unwrap();
+    std::fs::write(dir.join("rules/a_to_e.txt"), "a -> e / _ 1\n").unwrap();
+    // One item passes and one fails.
+    std::fs::write(dir.join("gold.csv"), "segmentation,form\nke1,ka1\nki1,ka1\n").unwrap();
+    let fst = dir.join("t.fst");
+    let (fst, rules, gold) = (fst.to_str().unwrap(), dir.join("rules"), dir.join("gold.csv"));
+    let built = mixtec_fst(&dir, &["build", fst, "--srcdir", rules.to_str().unwrap(), "--no-min"]);
+    assert!(built.status.success(), "{}", String::from_utf8_lossy(&built.stderr));
+    assert!(!has_escape(&built.stdout) && !has_escape(&built.stderr));
+
+    let test = ["test", fst, "-t", gold.to_str().unwrap(), "--json-report", "report.json"];
+    let auto = mixtec_fst(&dir, &test);
+    assert!(!auto.status.success());
+    assert!(String::from_utf8_lossy(&auto.stdout).contains("FAILED"));
+    assert!(!has_escape(&auto.stdout) && !has_escape(&auto.stderr));
+    assert!(!has_escape(&read(dir.join("log.txt"))));
+    assert!(!has_escape(&read(dir.join("report.json"))));
+
+    let always = mixtec_fst(&dir, &[&["--color", "always"], &test[..]].concat());
+    assert!(has_escape(&always.stdout));
+    // The failure log and report stay plain whatever the terminal gets.
+    assert!(!has_escape(&read(dir.join("log.txt"))));
+    assert!(!has_escape(&read(dir.join("report.json"))));
+}
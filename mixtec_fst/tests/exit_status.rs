@@ -0,0 +1,128 @@
+//! The exit status tells failures apart (see `src/status.rs`): runs the binary
+//! on a failing test, missing, corrupt and malformed inputs and bad arguments,
+//! and checks the status of each.
+//!
+//! The binary is run with `std::process::Command` rather than `assert_cmd`:
+//! Cargo already gives integration tests its path (`CARGO_BIN_EXE_*`), and the
+//! crate has no dev-dependencies, as in `tests/color.rs`.
+
+#[path = "../src/testutil/tempdir.rs"]
+mod tempdir;
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+use rustfst::prelude::{MutableFst, SerializableFst, TropicalWeight, VectorFst};
+use rustfst::utils::transducer;
+use rustfst::{Semiring, Tr};
+
+use tempdir::TempDir;
+
+fn mixtec_fst_in(cwd: &Path, dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mixtec_fst")).current_dir(cwd).arg("--out-dir").arg(dir).args(args).output().unwrap()
+}
+
+fn mixtec_fst(dir: &Path, args: &[&str]) -> Output {
+    // chars.txt is read from the working directory.
+    mixtec_fst_in(Path::new(env!("CARGO_MANIFEST_DIR")), dir, args)
+}
+
+fn status(output: &Output) -> i32 {
+    output.status.code().unwrap_or_else(|| panic!("killed by a signal: {}", String::from_utf8_lossy(&output.stderr)))
+}
+
+fn scratch(name: &str) -> TempDir {
+    let dir = TempDir::new(&format!("status-{}", name));
+    std::fs::create_dir(dir.join("rules")).unwrap();
+    dir
+}
+
+/// Build the FST of one rule into `dir`, returning its path.
+fn build(dir: &Path) -> String {
+    std::fs::write(dir.join("rules/a_to_e.txt"), "a -> e / _ 1\n").unwrap();
+    let fst = dir.join("t.fst").to_str().unwrap().to_string();
+    let built = mixtec_fst(dir, &["build", &fst, "--srcdir", dir.join("rules").to_str().unwrap(), "--no-min"]);
+    assert_eq!(status(&built), 0, "{}", String::from_utf8_lossy(&built.stderr));
+    fst
+}
+
+#[test]
+fn test_failing_test_is_1_and_passing_is_0() {
+    let dir = scratch("test");
+    let fst = build(&dir);
+    std::fs::write(dir.join("pass.csv"), "segmentation,form\nke1,ka1\n").unwrap();
+    std::fs::write(dir.join("fail.csv"), "segmentation,form\nki1,ka1\n").unwrap();
+    let passed = mixtec_fst(&dir, &["test", &fst, "-t", dir.join("pass.csv").to_str().unwrap()]);
+    assert_eq!(status(&passed), 0, "{}", String::from_utf8_lossy(&passed.stderr));
+    let failed = mixtec_fst(&dir, &["test", &fst, "-t", dir.join("fail.csv").to_str().unwrap()]);
+    assert_eq!(status(&failed), 1, "{}", String::from_utf8_lossy(&failed.stderr));
+}
+
+#[test]
+fn test_missing_symbols_file_is_3() {
+    let dir = scratch("symbols");
+    // No chars.txt in the working directory.
+    let output = mixtec_fst_in(&dir, &dir, &["segment", "t.fst", "ka1"]);
+    assert_eq!(status(&output), 3, "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_truncated_fst_is_3() {
+    let dir = scratch("truncated");
+    let fst = build(&dir);
+    let bytes = std::fs::read(&fst).unwrap();
+    std::fs::write(&fst, &bytes[..bytes.len() / 2]).unwrap();
+    let output = mixtec_fst(&dir, &["segment", &fst, "ka1"]);
+    assert_eq!(status(&output), 3, "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_malformed_rule_file_is_3() {
+    let dir = scratch("rules");
+    std::fs::write(dir.join("rules/bad.txt"), "a -> e / _ 1\n\0").unwrap();
+    let fst = dir.join("t.fst");
+    let output = mixtec_fst(&dir, &["build", fst.to_str().unwrap(), "--srcdir", dir.join("rules").to_str().unwrap()]);
+    assert_eq!(status(&output), 3, "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_rule_syntax_error_is_3() {
+    let dir = scratch("syntax");
+    std::fs::write(dir.join("rules/bad.txt"), "a -> e / _ 1 :: cheap\n").unwrap();
+    let fst = dir.join("t.fst");
+    let output = mixtec_fst(&dir, &["build", fst.to_str().unwrap(), "--srcdir", dir.join("rules").to_str().unwrap()]);
+    assert_eq!(status(&output), 3, "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_input_outside_the_symbol_table_is_3() {
+    let dir = scratch("oov");
+    let fst = build(&dir);
+    let output = mixtec_fst(&dir, &["segment", &fst, "kΩ1"]);
+    assert_eq!(status(&output), 3, "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_failing_fst_algorithm_is_4() {
+    let dir = scratch("internal");
+    // A dump of a minimization rustfst refuses: the FST reads `a` two ways.
+    let mut fst: VectorFst<TropicalWeight> = transducer(&[1], &[1], TropicalWeight::one());
+    fst.add_tr(0, Tr::new(1, 2, TropicalWeight::new(1.0), 1)).unwrap();
+    fst.write(dir.join("operand1.fst")).unwrap();
+    let manifest = r#"{"operation": {"name": "minimize", "delta": 1e-7, "allow_nondet": false}, "operands": ["operand1.fst"], "command_line": [], "error": ""}"#;
+    std::fs::write(dir.join("dump.json"), manifest).unwrap();
+    let output = mixtec_fst(&dir, &["replay", dir.to_str().unwrap()]);
+    assert_eq!(status(&output), 4, "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_usage_errors_are_2() {
+    let dir = scratch("usage");
+    let unknown = mixtec_fst(&dir, &["test", "--no-such-flag"]);
+    assert_eq!(status(&unknown), 2, "{}", String::from_utf8_lossy(&unknown.stderr));
+    // Clap cannot tell that `test --assert-accepts-all` needs an FST.
+    let words = dir.join("words.txt");
+    std::fs::write(&words, "ka1\n").unwrap();
+    let no_fst = mixtec_fst(&dir, &["test", "--assert-accepts-all", words.to_str().unwrap()]);
+    assert_eq!(status(&no_fst), 2, "{}", String::from_utf8_lossy(&no_fst.stderr));
+}
@@ -155,7 +155,7 @@ fn compile_mapping_fst(
     // Optional FST visualization - only if debug mode and external tools available
     #[cfg(debug_assertions)]
     {
-        if let Ok(_) = fst.draw(
+        if fst.draw(
             "map_fst.dot",
             &DrawingConfig {
                 vertical: false,
@@ -169,7 +169,7 @@ fn compile_mapping_fst(
                 show_weight_one: (true),
                 print_weight: (true),
             },
-        ) {
+        ).is_ok() {
             // Only attempt to run dot if the file was created successfully
             // This is optional and won't fail the function if dot is not available
             if let Err(e) = std::process::Command::new("dot")
@@ -3,6 +3,7 @@
 //! This module provides NFD (Normalized Form Decomposition) normalization
 //! for all language data that enters the system from external sources.
 
+use unicode_normalization::char::is_combining_mark;
 use unicode_normalization::UnicodeNormalization;
 
 /// Normalize text using NFD (Normalized Form Decomposition)
@@ -37,15 +38,42 @@ pub fn nfd_normalize(text: &str) -> String {
 /// The text with each line NFD-normalized
 pub fn nfd_normalize_lines(text: &str) -> String {
     text.lines()
-        .map(|line| nfd_normalize(line))
+        .map(nfd_normalize)
         .collect::<Vec<_>>()
         .join("\n")
 }
 
+/// Split text into graphemes: each character with the combining marks that
+/// follow it, as a letter and its diacritics make one symbol
+///
+/// A combining mark with nothing before it is a grapheme of its own.
+pub fn graphemes(text: &str) -> Vec<&str> {
+    let mut graphemes = Vec::new();
+    let mut start = 0;
+    for (i, c) in text.char_indices().skip(1) {
+        if !is_combining_mark(c) {
+            graphemes.push(&text[start..i]);
+            start = i;
+        }
+    }
+    if !text.is_empty() {
+        graphemes.push(&text[start..]);
+    }
+    graphemes
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_graphemes_keep_combining_marks() {
+        let text = nfd_normalize("ñá1");
+        assert_eq!(graphemes(&text), ["n\u{303}", "a\u{301}", "1"]);
+        assert_eq!(graphemes("\u{301}a"), ["\u{301}", "a"]);
+        assert!(graphemes("").is_empty());
+    }
+
     #[test]
     fn test_nfd_normalize_basic() {
         // Test basic ASCII text (should remain unchanged)
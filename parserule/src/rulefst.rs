@@ -3,7 +3,7 @@
 
 // cSpell:disable
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use rustfst::algorithms::compose::{
     compose, compose_with_config, ComposeConfig, ComposeFilterEnum, MatcherConfig,
 };
@@ -32,6 +32,7 @@ use std::sync::Arc;
 
 use colored::Colorize;
 
+use crate::normalize::graphemes;
 use crate::ruleparse::{RegexAST, RewriteRule, Statement};
 use crate::utils::optimize_fst;
 
@@ -92,34 +93,61 @@ pub fn compile_script(
         }
     }
 
-    let mut rule_iter = rules.into_iter();
-    if let Some(first_rule) = rule_iter.next() {
-        let mut fst: VectorFst<TropicalWeight> =
-            rule_fst(symt.clone(), &macros, first_rule).unwrap();
-        rule_iter.for_each(|rule| {
-            let mut new_fst: VectorFst<TropicalWeight> =
-                rule_fst(symt.clone(), &macros, rule).unwrap();
-            tr_sort(&mut fst, OLabelCompare {});
-            tr_sort(&mut new_fst, ILabelCompare {});
-            fst = compose_with_config(
-                fst.clone(),
-                new_fst,
-                ComposeConfig {
-                    compose_filter: ComposeFilterEnum::AltSequenceFilter,
-                    matcher1_config: MatcherConfig::default(),
-                    matcher2_config: MatcherConfig::default(),
-                    connect: false,
-                },
-            )
-            .unwrap();
+    let fsts = rules
+        .into_iter()
+        .map(|rule| rule_fst(symt.clone(), &macros, rule))
+        .collect::<Result<Vec<_>>>()?;
+    compose_rules(symt, fsts, compose_alt_sequence)
+}
+
+/// Compose the compiled rules of a script in order, as `compile_script` does.
+/// `compose` is given the rules composed so far, sorted on output labels, and
+/// the next rule, sorted on input labels. A script without rules compiles to
+/// Σ*.
+pub fn compose_rules<F>(
+    symt: Arc<SymbolTable>,
+    fsts: impl IntoIterator<Item = VectorFst<TropicalWeight>>,
+    mut compose: F,
+) -> Result<VectorFst<TropicalWeight>>
+where
+    F: FnMut(VectorFst<TropicalWeight>, VectorFst<TropicalWeight>) -> Result<VectorFst<TropicalWeight>>,
+{
+    let mut composed: Option<VectorFst<TropicalWeight>> = None;
+    for mut new_fst in fsts {
+        composed = Some(match composed {
+            None => new_fst,
+            Some(mut fst) => {
+                tr_sort(&mut fst, OLabelCompare {});
+                tr_sort(&mut new_fst, ILabelCompare {});
+                compose(fst, new_fst)?
+            }
         });
-        Ok(fst)
-    } else {
-        let fst: VectorFst<TropicalWeight> = weighted_sigma_star(symt.clone(), 0.0)?;
-        Ok(fst)
+    }
+    match composed {
+        Some(fst) => Ok(fst),
+        None => weighted_sigma_star(symt, 0.0),
     }
 }
 
+/// Compose two rules of a script, reading the epsilons of the second before
+/// those of the first.
+pub fn compose_alt_sequence(
+    fst1: VectorFst<TropicalWeight>,
+    fst2: VectorFst<TropicalWeight>,
+) -> Result<VectorFst<TropicalWeight>> {
+    let fst = compose_with_config(
+        fst1,
+        fst2,
+        ComposeConfig {
+            compose_filter: ComposeFilterEnum::AltSequenceFilter,
+            matcher1_config: MatcherConfig::default(),
+            matcher2_config: MatcherConfig::default(),
+            connect: false,
+        },
+    )?;
+    Ok(fst)
+}
+
 pub fn rule_fst(
     symt: Arc<SymbolTable>,
     macros: &HashMap<String, RegexAST>,
@@ -519,7 +547,7 @@ fn automaton_complement(
     // Add dead state (sink)
     let sink = fst.add_state();
 
-    let alphabet: HashSet<Label> = symt.labels().filter(|l| !exclude.contains(&l)).collect();
+    let alphabet: HashSet<Label> = symt.labels().filter(|l| !exclude.contains(l)).collect();
 
     // Add self-loops to sink for all alphabet symbols
     alphabet
@@ -536,7 +564,7 @@ fn automaton_complement(
             .collect();
         alphabet
             .iter()
-            .filter(|&l| !existing_labels.contains(&l))
+            .filter(|&l| !existing_labels.contains(l))
             .for_each(|l| fst.emplace_tr(*s, *l, *l, 0.0, sink).unwrap());
     });
 
@@ -651,7 +679,6 @@ fn fst_complement(
 /// # Returns
 ///
 /// A WFST corresponding to a rewrite rule
-
 fn output_to_epsilons(fst: VectorFst<TropicalWeight>) -> VectorFst<TropicalWeight> {
     let mut fst2 = fst.clone();
     for state in fst2.states_iter() {
@@ -722,7 +749,10 @@ pub fn weighted_sigma_star(
     Ok(fst)
 }
 
-fn node_fst(
+/// Return an unweighted acceptor of the strings matched by `node`, as used for
+/// the source, target and contexts of a rule by `rule_fst`. Macros are looked
+/// up in `macros`; see [`regex_to_fst`] for a node with none.
+pub fn node_fst(
     symt: Arc<SymbolTable>,
     macros: &HashMap<String, RegexAST>,
     node: RegexAST,
@@ -735,18 +765,21 @@ fn node_fst(
             new_fst
         }
         RegexAST::Char(c) => {
-            let label: Label = symt.get_label(c.to_string()).unwrap();
-            let new_fst: VectorFst<TropicalWeight> = fst![label => label];
-            new_fst
+            let labels = symbol_labels(&symt, &c).ok_or_else(|| anyhow!("Symbol '{}' is not in the symbol table", c))?;
+            acceptor(&labels, TropicalWeight::one())
         }
         RegexAST::Class(k) => {
             let mut symbols = k.into_iter();
 
             if let Some(first_sym) = symbols.next() {
-                let label: Label = symt.get_label(first_sym).unwrap();
+                let label_of = |s: String| {
+                    symt.get_label(&s)
+                        .ok_or_else(|| anyhow!("Symbol '{}' is not in the symbol table", s))
+                };
+                let label: Label = label_of(first_sym)?;
                 let mut new_fst: VectorFst<TropicalWeight> = fst![label => label; 0.0];
                 for s in symbols {
-                    let label: Label = symt.get_label(s).unwrap();
+                    let label: Label = label_of(s)?;
                     let newer_fst: VectorFst<TropicalWeight> = fst![label => label];
                     union(&mut new_fst, &newer_fst)?;
                 }
@@ -831,6 +864,7 @@ fn node_fst(
             rm_epsilon(&mut new_fst)?;
             new_fst
         }
+        RegexAST::Process(stages) => node_fst(symt, macros, RegexAST::process_symbols(&stages))?,
         RegexAST::Macro(macro_key) => {
             let macro_node = macros.get(&macro_key).unwrap_or_else(|| {
                 println!("Macro {macro_key} not defined!");
@@ -845,7 +879,38 @@ fn node_fst(
     Ok(fst)
 }
 
-/// Interpret an RegexAST node as a wFST
+/// Return an unweighted acceptor of the strings matched by `node`, which
+/// refers to no macros: [`node_fst`] with an empty macro map. A
+/// [`RegexAST::Macro`] node is an error, having no definition to expand to;
+/// use [`node_fst`] with the definitions instead.
+///
+/// # Examples
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use rustfst::prelude::*;
+/// # use parserule::rulefst::{decode_paths_through_fst, regex_to_fst};
+/// # use parserule::ruleparse::RegexAST;
+/// let symt = Arc::new(symt!["a", "b"]);
+/// // a(b)
+/// let node = RegexAST::Group(vec![RegexAST::Char("a".to_string()), RegexAST::Option(Box::new(RegexAST::Char("b".to_string())))]);
+/// let mut fst = regex_to_fst(symt.clone(), node).unwrap();
+/// fst.set_input_symbols(symt.clone());
+/// fst.set_output_symbols(symt.clone());
+/// let mut strings: Vec<String> = decode_paths_through_fst(symt.clone(), fst).into_iter().map(|(_, s)| s).collect();
+/// strings.sort();
+/// assert_eq!(strings, ["a", "ab"]);
+///
+/// assert!(regex_to_fst(symt, RegexAST::Star(Box::new(RegexAST::Macro("V".to_string())))).is_err());
+/// ```
+pub fn regex_to_fst(symt: Arc<SymbolTable>, node: RegexAST) -> Result<VectorFst<TropicalWeight>> {
+    if let Some(name) = node.first_macro() {
+        return Err(anyhow!("Macro {} has no definition; use node_fst with the macros", name));
+    }
+    node_fst(symt, &HashMap::new(), node)
+}
+
+// Interpret an RegexAST node as a wFST
 // fn old_node_fst(
 //     symt: Arc<SymbolTable>,
 //     macros: &HashMap<String, RegexAST>,
@@ -1047,12 +1112,27 @@ fn _get_start(fst: VectorFst<TropicalWeight>) -> Result<StateId> {
     panic!("FST has no start state!")
 }
 
-/// Convert a string to a linear automaton
+/// The labels of `symbol` in `symt`: its own, or if it is a character with
+/// combining marks that `symt` only has apart, those of its characters.
+pub fn symbol_labels(symt: &SymbolTable, symbol: &str) -> Option<Vec<Label>> {
+    if let Some(label) = symt.get_label(symbol) {
+        return Some(vec![label]);
+    }
+    if symbol.chars().count() < 2 {
+        return None;
+    }
+    symbol.chars().map(|c| symt.get_label(c.to_string())).collect()
+}
+
+/// Convert a string to a linear automaton, one symbol per grapheme where
+/// `symt` has it; characters not in `symt` are left out.
 pub fn string_to_linear_automaton(symt: Arc<SymbolTable>, s: &str) -> VectorFst<TropicalWeight> {
-    let symt = symt.clone();
-    let labels: Vec<u32> = s
-        .chars()
-        .filter_map(|c| symt.get_label(c.to_string()))
+    let labels: Vec<u32> = graphemes(s)
+        .into_iter()
+        .flat_map(|g| match symt.get_label(g) {
+            Some(label) => vec![label],
+            None => g.chars().filter_map(|c| symt.get_label(c.to_string())).collect(),
+        })
         .collect();
     acceptor(&labels, TropicalWeight::one())
 }
@@ -1116,7 +1196,6 @@ pub fn apply_fst_to_string(
 /// fst.set_output_symbols(symt.clone());
 /// assert_eq!(decode_paths_through_fst(symt, fst), vec![(TropicalWeight::from(0.1), "cdc".to_string())]);
 /// ```
-
 pub fn decode_paths_through_fst(
     symt: Arc<SymbolTable>,
     mut fst: VectorFst<TropicalWeight>,
@@ -1149,6 +1228,85 @@ fn decode_path(symt: Arc<SymbolTable>, path: StringPath<TropicalWeight>) -> Stri
         .join("")
 }
 
+/// Decode each of the paths through a wFST as an (input, output, weight) triple,
+/// best first
+///
+/// Epsilons are dropped on both sides, so the input of a path that inserts or
+/// deletes material is the epsilon-collapsed input string rather than an
+/// alignment with the output.
+///
+/// # Examples
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use rustfst::prelude::*;
+/// # use rustfst::fst_impls::VectorFst;
+/// # use rustfst::utils::transducer;
+/// # use parserule::rulefst::decode_path_triples;
+/// let symt = Arc::new(symt!["a", "b", "c", "d"]);
+/// let fst: VectorFst<TropicalWeight> = fst![1, 2 => 3, 4; 0.5];
+/// let triples = decode_path_triples(symt, fst).unwrap();
+/// assert_eq!(triples, vec![("ab".to_string(), "cd".to_string(), TropicalWeight::new(0.5))]);
+/// ```
+pub fn decode_path_triples(
+    symt: Arc<SymbolTable>,
+    mut fst: VectorFst<TropicalWeight>,
+) -> Result<Vec<(String, String, TropicalWeight)>> {
+    fst.set_input_symbols(symt.clone());
+    fst.set_output_symbols(symt.clone());
+    let paths: Vec<_> = fst.string_paths_iter()?.collect();
+    let decode = |labels: &[Label]| {
+        labels
+            .iter()
+            .map(|&l| symt.get_symbol(l).unwrap_or(""))
+            .collect::<String>()
+    };
+    let mut triples: Vec<(String, String, TropicalWeight)> = paths
+        .iter()
+        .map(|p| (decode(p.ilabels()), decode(p.olabels()), *p.weight()))
+        .collect();
+    triples.sort_by(|(_, _, w1), (_, _, w2)| w1.partial_cmp(w2).unwrap_or(Ordering::Equal));
+    Ok(triples)
+}
+
+/// Apply a wFST to a string, yielding the `n` best paths as (input, output,
+/// weight) triples, best first
+///
+/// See [`decode_path_triples`] for how inputs containing epsilons are decoded.
+///
+/// # Examples
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use rustfst::prelude::*;
+/// # use rustfst::fst_impls::VectorFst;
+/// # use rustfst::utils::transducer;
+/// # use parserule::rulefst::apply_fst_nbest;
+/// let symt = Arc::new(symt!["a", "b", "c", "d"]);
+/// let mut fst: VectorFst<TropicalWeight> = fst![1, 2 => 3, 4; 0.5];
+/// let other: VectorFst<TropicalWeight> = fst![1, 2 => 1, 2; 1.0];
+/// union::union(&mut fst, &other).unwrap();
+/// let triples = apply_fst_nbest(symt, fst, "ab".to_string(), 2).unwrap();
+/// assert_eq!(
+///     triples,
+///     vec![
+///         ("ab".to_string(), "cd".to_string(), TropicalWeight::new(0.5)),
+///         ("ab".to_string(), "ab".to_string(), TropicalWeight::new(1.0)),
+///     ]
+/// );
+/// ```
+pub fn apply_fst_nbest(
+    symt: Arc<SymbolTable>,
+    fst: VectorFst<TropicalWeight>,
+    input: String,
+    n: usize,
+) -> Result<Vec<(String, String, TropicalWeight)>> {
+    let composed_fst = apply_fst_to_string(symt.clone(), fst, input)?;
+    let nbest: VectorFst<TropicalWeight> =
+        shortest_path_with_config(&composed_fst, ShortestPathConfig::default().with_nshortest(n))?;
+    decode_path_triples(symt, nbest)
+}
+
 /// Apply a wFST to a string, yielding a string
 ///
 /// # Examples
@@ -1342,6 +1500,22 @@ mod tests {
     //     assert_eq!(output, "#b$ac$ad#".to_string());
     // }
 
+    #[test]
+    fn test_symbols_with_combining_marks() {
+        let nye = "n\u{303}";
+        let together: Arc<SymbolTable> = Arc::new(symt!["a", "n", nye]);
+        let apart: Arc<SymbolTable> = Arc::new(symt!["a", "n", "\u{303}"]);
+        assert_eq!(symbol_labels(&together, nye), Some(vec![3]));
+        assert_eq!(symbol_labels(&apart, nye), Some(vec![2, 3]));
+        assert_eq!(symbol_labels(&apart, "\u{301}"), None);
+        assert_eq!(string_to_linear_automaton(together.clone(), "n\u{303}a"), acceptor(&[3, 1], TropicalWeight::one()));
+        assert_eq!(string_to_linear_automaton(apart.clone(), "n\u{303}a"), acceptor(&[2, 3, 1], TropicalWeight::one()));
+        let node = RegexAST::Char(nye.to_string());
+        assert!(node_fst(together, &HashMap::new(), node.clone()).is_ok());
+        let missing: Arc<SymbolTable> = Arc::new(symt!["a", "n"]);
+        assert!(node_fst(missing, &HashMap::new(), node).is_err());
+    }
+
     #[test]
     fn test_component_build_fst_r1_one_char1() {
         let symt: Arc<SymbolTable> = Arc::new(symt!["a", "b", "c", "d", "#", "$", "^", "%"]);
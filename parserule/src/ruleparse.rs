@@ -6,18 +6,20 @@ use nom::{
     branch::alt,
     bytes::complete::{is_not, tag},
     character::complete::{
-        alpha1, char as nom_char, multispace0, newline, none_of, one_of, space0,
+        alpha1, char as nom_char, multispace0, newline, none_of, one_of, satisfy, space0,
     },
-    combinator::{map_res, recognize, success, value},
-    multi::{many1, separated_list0, separated_list1},
+    combinator::{map_res, not, opt, recognize, success, value},
+    multi::{many0, many1, separated_list0, separated_list1},
     sequence::{delimited, pair, preceded, terminated, tuple},
     IResult, Parser,
 };
-use std::char;
+use unicode_normalization::char::is_combining_mark;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum RegexAST {
-    Char(char),
+    /// A symbol: a character with the combining marks that follow it, NFD
+    /// normalized, so that a letter with a diacritic is one symbol.
+    Char(String),
     Group(Vec<RegexAST>),
     Option(Box<RegexAST>),
     Star(Box<RegexAST>),
@@ -26,11 +28,54 @@ pub enum RegexAST {
     Class(HashSet<String>),
     ClassComplement(HashSet<String>),
     Macro(String),
+    /// A tone process in braces, `{3\>1\>14}`: the stages it goes through,
+    /// in order.
+    Process(Vec<RegexAST>),
     Epsilon,
     Boundary,
     Comment,
 }
 
+impl RegexAST {
+    /// The symbols a process is written with: its stages between braces,
+    /// separated by arrows.
+    pub fn process_symbols(stages: &[RegexAST]) -> RegexAST {
+        let mut nodes = vec![RegexAST::Char("{".to_string())];
+        for (i, stage) in stages.iter().enumerate() {
+            if i > 0 {
+                nodes.push(RegexAST::Char(">".to_string()));
+            }
+            nodes.push(stage.clone());
+        }
+        nodes.push(RegexAST::Char("}".to_string()));
+        RegexAST::Group(nodes)
+    }
+
+    /// The underlying form of `self`: each process stands for its first stage.
+    pub fn underlying(&self) -> RegexAST {
+        let all = |nodes: &[RegexAST]| nodes.iter().map(RegexAST::underlying).collect();
+        match self {
+            RegexAST::Process(stages) => stages.first().map_or(RegexAST::Epsilon, RegexAST::underlying),
+            RegexAST::Group(nodes) => RegexAST::Group(all(nodes)),
+            RegexAST::Disjunction(nodes) => RegexAST::Disjunction(all(nodes)),
+            RegexAST::Option(n) => RegexAST::Option(Box::new(n.underlying())),
+            RegexAST::Star(n) => RegexAST::Star(Box::new(n.underlying())),
+            RegexAST::Plus(n) => RegexAST::Plus(Box::new(n.underlying())),
+            other => other.clone(),
+        }
+    }
+
+    /// The name of the first macro `self` refers to, if any.
+    pub fn first_macro(&self) -> Option<&str> {
+        match self {
+            RegexAST::Macro(name) => Some(name),
+            RegexAST::Group(nodes) | RegexAST::Disjunction(nodes) | RegexAST::Process(nodes) => nodes.iter().find_map(RegexAST::first_macro),
+            RegexAST::Option(n) | RegexAST::Star(n) | RegexAST::Plus(n) => n.first_macro(),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Statement {
     Comment,
@@ -46,11 +91,23 @@ pub struct RewriteRule {
     pub target: RegexAST,
 }
 
+/// A symbol written as a character, none of `special`, with the combining
+/// marks after it.
+fn grapheme<'a>(special: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, (RegexAST, HashSet<String>)> {
+    move |input| {
+        let (input, g) = recognize(pair(none_of(special), many0(satisfy(is_combining_mark))))(input)?;
+        Ok((input, symbol(g)))
+    }
+}
+
+/// The node of the symbol `s`, and the set of symbols it uses.
+fn symbol(s: &str) -> (RegexAST, HashSet<String>) {
+    let normalized = nfd_normalize(s);
+    (RegexAST::Char(normalized.clone()), HashSet::from([normalized]))
+}
+
 fn character(input: &str) -> IResult<&str, (RegexAST, HashSet<String>)> {
-    let (input, c) = none_of(" />_()[]-|*+^#:%\\\n\r")(input)?;
-    let normalized_char = nfd_normalize(&c.to_string());
-    let syms: HashSet<String> = HashSet::from([normalized_char]);
-    Ok((input, (RegexAST::Char(c), syms)))
+    grapheme(" />_()[]-|*+^#:%\\\n\r")(input)
 }
 
 fn uni_esc(input: &str) -> IResult<&str, (RegexAST, HashSet<String>)> {
@@ -69,17 +126,59 @@ fn uni_esc(input: &str) -> IResult<&str, (RegexAST, HashSet<String>)> {
         );
         '\u{FFFD}' // Unicode replacement character
     });
-    let normalized_char = nfd_normalize(&c.to_string());
-    let syms: HashSet<String> = HashSet::from([normalized_char]);
-    Ok((input, (RegexAST::Char(c), syms)))
+    Ok((input, symbol(&c.to_string())))
 }
 
 fn escape(input: &str) -> IResult<&str, (RegexAST, HashSet<String>)> {
     let mut parser = preceded(tag("\\"), one_of("\\ /<>_()[]-|*+^#:%"));
     let (input, c) = parser.parse(input)?;
-    let normalized_char = nfd_normalize(&c.to_string());
-    let syms: HashSet<String> = HashSet::from([normalized_char]);
-    Ok((input, (RegexAST::Char(c), syms)))
+    Ok((input, symbol(&c.to_string())))
+}
+
+/// A character that may stand in a stage of a process, where braces cannot.
+fn process_character(input: &str) -> IResult<&str, (RegexAST, HashSet<String>)> {
+    grapheme(" />_()[]-|*+^#:%\\\n\r{}")(input)
+}
+
+/// A tone process, `{S1\>S2...}`, split into its stages at the arrows at its
+/// top level. Braces with no arrow at their top level are not a process, and
+/// are left to be read as characters, as are arrows elsewhere (`#1\>3#`).
+fn process(input: &str) -> IResult<&str, (RegexAST, HashSet<String>)> {
+    let stage = many0(alt((
+        uni_esc,
+        boundary_mark,
+        epsilon_mark,
+        mac,
+        star,
+        plus,
+        option,
+        disjunction,
+        class,
+        complement_class,
+        group,
+        preceded(not(tag("\\>")), escape),
+        process_character,
+    )));
+    let (rest, stages) = delimited(nom_char('{'), separated_list1(tag("\\>"), stage), nom_char('}')).parse(input)?;
+    if stages.len() < 2 {
+        return Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::SeparatedList)));
+    }
+    let mut set = HashSet::from(["{".to_string(), ">".to_string(), "}".to_string()]);
+    let stages = stages
+        .into_iter()
+        .map(|elems| {
+            if elems.is_empty() {
+                return RegexAST::Epsilon;
+            }
+            let mut nodes = Vec::new();
+            for (node, syms) in elems {
+                set.extend(syms);
+                nodes.push(node);
+            }
+            RegexAST::Group(nodes)
+        })
+        .collect();
+    Ok((rest, (RegexAST::Process(stages), set)))
 }
 
 fn class(input: &str) -> IResult<&str, (RegexAST, HashSet<String>)> {
@@ -92,7 +191,7 @@ fn class(input: &str) -> IResult<&str, (RegexAST, HashSet<String>)> {
     let strings: Vec<String> = chars
         .iter()
         .filter_map(|node| match node {
-            (RegexAST::Char(c), _) => Some(nfd_normalize(&c.to_string())),
+            (RegexAST::Char(c), _) => Some(c.clone()),
             _ => None,
         })
         .collect();
@@ -110,7 +209,7 @@ fn complement_class(input: &str) -> IResult<&str, (RegexAST, HashSet<String>)> {
     let strings: Vec<String> = chars
         .iter()
         .filter_map(|node| match node {
-            (RegexAST::Char(c), _) => Some(nfd_normalize(&c.to_string())),
+            (RegexAST::Char(c), _) => Some(c.clone()),
             _ => None,
         })
         .collect();
@@ -124,6 +223,7 @@ fn sequence(input: &str) -> IResult<&str, (RegexAST, HashSet<String>)> {
         boundary_mark,
         epsilon_mark,
         mac,
+        process,
         star,
         plus,
         option,
@@ -218,10 +318,11 @@ fn epsilon_mark(input: &str) -> IResult<&str, (RegexAST, HashSet<String>)> {
     Ok((input, (re, HashSet::new())))
 }
 
+/// A comment: `%` to the end of the line, which may be empty.
 fn comment(input: &str) -> IResult<&str, (RegexAST, HashSet<String>)> {
     value(
         (RegexAST::Comment, HashSet::new()),
-        pair(nom_char('%'), is_not("\n")),
+        pair(nom_char('%'), opt(is_not("\n"))),
     )
     .parse(input)
 }
@@ -372,7 +473,11 @@ pub fn parse_script(input: &str) -> IResult<String, (Vec<Statement>, HashSet<Str
         multispace0,
         separated_list0(
             tuple((space0, newline, multispace0)),
-            alt((comment_statement, macro_statement, rule_statement)),
+            // A comment after a statement, on the same line, belongs to it.
+            terminated(
+                alt((comment_statement, macro_statement, rule_statement)),
+                opt(preceded(space0, comment)),
+            ),
         ),
         multispace0,
     ));
@@ -422,7 +527,19 @@ mod tests {
     fn test_character() {
         assert_eq!(
             character("abc"),
-            Ok(("bc", (RegexAST::Char('a'), hashset_str!["a"])))
+            Ok(("bc", (RegexAST::Char("a".to_string()), hashset_str!["a"])))
+        );
+    }
+
+    #[test]
+    fn test_character_keeps_combining_marks() {
+        let nye = || (RegexAST::Char("n\u{303}".to_string()), hashset_str!["n\u{303}"]);
+        assert_eq!(character("n\u{303}a"), Ok(("a", nye())));
+        // Precomposed, it is the same symbol.
+        assert_eq!(character("\u{f1}a"), Ok(("a", nye())));
+        assert_eq!(
+            class("[\u{f1}n]"),
+            Ok(("", (RegexAST::Class(hashset_str!["n\u{303}", "n"]), hashset_str!["n\u{303}", "n"])))
         );
     }
 
@@ -430,7 +547,7 @@ mod tests {
     fn test_uni_esc() {
         assert_eq!(
             uni_esc(r#"\u014B"#),
-            Ok(("", (RegexAST::Char('ŋ'), hashset_str!["ŋ"])))
+            Ok(("", (RegexAST::Char("ŋ".to_string()), hashset_str!["ŋ"])))
         );
     }
 
@@ -477,7 +594,7 @@ mod tests {
        fn test_sequence4() {
            debug_assert_eq!(
                sequence("a"),
-               Ok(("", RegexAST::Group(vec![RegexAST::Char('a')])))
+               Ok(("", RegexAST::Group(vec![RegexAST::Char("a".to_string())])))
            );
        }
 
@@ -485,7 +602,7 @@ mod tests {
        fn test_group3() {
            debug_assert_eq!(
                group("(a)"),
-               Ok(("", RegexAST::Group(vec![RegexAST::Char('a')])))
+               Ok(("", RegexAST::Group(vec![RegexAST::Char("a".to_string())])))
            );
        }
 
@@ -496,7 +613,7 @@ mod tests {
                Ok((
                    "",
                    RegexAST::Group(vec![
-                       RegexAST::Char('a'),
+                       RegexAST::Char("a".to_string()),
                        RegexAST::Class(
                            vec!["d", "e", "f"]
                                .into_iter()
@@ -512,7 +629,7 @@ mod tests {
        fn test_regex1() {
            debug_assert_eq!(
                regex("a"),
-               Ok(("", RegexAST::Group(vec![RegexAST::Char('a')])))
+               Ok(("", RegexAST::Group(vec![RegexAST::Char("a".to_string())])))
            );
        }
 
@@ -525,7 +642,7 @@ mod tests {
        fn test_plus() {
            debug_assert_eq!(
                plus("a+"),
-               Ok(("", RegexAST::Plus(Box::new(RegexAST::Char('a')))))
+               Ok(("", RegexAST::Plus(Box::new(RegexAST::Char("a".to_string())))))
            )
        }
 
@@ -536,8 +653,8 @@ mod tests {
                Ok((
                    "",
                    RegexAST::Group(vec![
-                       RegexAST::Plus(Box::new(RegexAST::Char('a'))),
-                       RegexAST::Char('b'),
+                       RegexAST::Plus(Box::new(RegexAST::Char("a".to_string()))),
+                       RegexAST::Char("b".to_string()),
                    ])
                ))
            );
@@ -563,8 +680,8 @@ mod tests {
                Ok((
                    "",
                    RegexAST::Group(vec![RegexAST::Plus(Box::new(RegexAST::Disjunction(vec![
-                       RegexAST::Group(vec![RegexAST::Char('c')]),
-                       RegexAST::Group(vec![RegexAST::Char('d')])
+                       RegexAST::Group(vec![RegexAST::Char("c".to_string())]),
+                       RegexAST::Group(vec![RegexAST::Char("d".to_string())])
                    ])))])
                ))
            );
@@ -577,8 +694,8 @@ mod tests {
                Ok((
                    "",
                    RegexAST::Group(vec![
-                       RegexAST::Star(Box::new(RegexAST::Char('a'))),
-                       RegexAST::Char('b'),
+                       RegexAST::Star(Box::new(RegexAST::Char("a".to_string()))),
+                       RegexAST::Char("b".to_string()),
                    ])
                ))
            );
@@ -604,8 +721,8 @@ mod tests {
                Ok((
                    "",
                    RegexAST::Group(vec![RegexAST::Star(Box::new(RegexAST::Disjunction(vec![
-                       RegexAST::Group(vec![RegexAST::Char('c')]),
-                       RegexAST::Group(vec![RegexAST::Char('d')])
+                       RegexAST::Group(vec![RegexAST::Char("c".to_string())]),
+                       RegexAST::Group(vec![RegexAST::Char("d".to_string())])
                    ])))])
                ))
            );
@@ -619,6 +736,32 @@ mod tests {
             Ok(("", (RegexAST::Macro("vowel".to_string()), HashSet::new())))
         );
     }
+    #[test]
+    fn test_process() {
+        let stage = |cs: &str| RegexAST::Group(cs.chars().map(|c| RegexAST::Char(c.to_string())).collect());
+        let (rest, (re, set)) = process("{3\\>1\\>14}a").unwrap();
+        debug_assert_eq!(rest, "a");
+        debug_assert_eq!(re, RegexAST::Process(vec![stage("3"), stage("1"), stage("14")]));
+        debug_assert_eq!(set, hashset_str!["{", ">", "}", "1", "3", "4"]);
+        debug_assert_eq!(
+            RegexAST::process_symbols(&[stage("3"), stage("1")]),
+            RegexAST::Group(vec![RegexAST::Char("{".to_string()), stage("3"), RegexAST::Char(">".to_string()), stage("1"), RegexAST::Char("}".to_string())])
+        );
+        // An empty first stage, and an arrow below the top level.
+        let (_, (re, _)) = process("{\\>1}").unwrap();
+        debug_assert_eq!(re, RegexAST::Process(vec![RegexAST::Epsilon, stage("1")]));
+        let (_, (re, _)) = process("{(3\\>)?1\\>14}").unwrap();
+        let RegexAST::Process(stages) = &re else { panic!("{:?}", re) };
+        debug_assert_eq!(stages.len(), 2);
+        debug_assert_eq!(re.underlying(), RegexAST::Group(vec![RegexAST::Option(Box::new(stage("3>"))), RegexAST::Char("1".to_string())]));
+        // Braces without a top-level arrow, and lone braces, are characters.
+        assert!(process("{3}").is_err());
+        let (_, (re, _)) = sequence("{[1234]*").unwrap();
+        assert!(matches!(&re, RegexAST::Group(nodes) if nodes[0] == RegexAST::Char("{".to_string())));
+        let (_, (re, _)) = sequence("#1\\>3#").unwrap();
+        debug_assert_eq!(re, RegexAST::Group(vec![RegexAST::Boundary, RegexAST::Char("1".to_string()), RegexAST::Char(">".to_string()), RegexAST::Char("3".to_string()), RegexAST::Boundary]));
+    }
+
     /*
            #[test]
            fn test_re_mac_def() {
@@ -629,8 +772,8 @@ mod tests {
                        (
                            "abc".to_string(),
                            RegexAST::Group(vec![RegexAST::Disjunction(vec![
-                               RegexAST::Group(vec!(RegexAST::Char('d'))),
-                               RegexAST::Group(vec!(RegexAST::Char('e'))),
+                               RegexAST::Group(vec!(RegexAST::Char("d".to_string()))),
+                               RegexAST::Group(vec!(RegexAST::Char("e".to_string()))),
                            ])])
                        )
                    ))
@@ -651,8 +794,8 @@ mod tests {
                        RewriteRule {
                            left: RegexAST::Epsilon,
                            right: RegexAST::Epsilon,
-                           source: RegexAST::Group(vec![RegexAST::Char('a')]),
-                           target: RegexAST::Group(vec![RegexAST::Char('b')]),
+                           source: RegexAST::Group(vec![RegexAST::Char("a".to_string())]),
+                           target: RegexAST::Group(vec![RegexAST::Char("b".to_string())]),
                        }
                    ))
                );
@@ -666,10 +809,10 @@ mod tests {
                 "",
                 (
                     RewriteRule {
-                        left: RegexAST::Group(vec![RegexAST::Char('c')]),
-                        right: RegexAST::Group(vec![RegexAST::Char('d')]),
-                        source: RegexAST::Group(vec![RegexAST::Char('a')]),
-                        target: RegexAST::Group(vec![RegexAST::Char('b')]),
+                        left: RegexAST::Group(vec![RegexAST::Char("c".to_string())]),
+                        right: RegexAST::Group(vec![RegexAST::Char("d".to_string())]),
+                        source: RegexAST::Group(vec![RegexAST::Char("a".to_string())]),
+                        target: RegexAST::Group(vec![RegexAST::Char("b".to_string())]),
                     },
                     hashset_str!["a", "b", "c", "d"]
                 )
@@ -687,8 +830,8 @@ mod tests {
                 RewriteRule {
                     left: RegexAST::Epsilon,
                     right: RegexAST::Group(vec![RegexAST::Boundary]),
-                    source: RegexAST::Group(vec![RegexAST::Char('b')]),
-                    target: RegexAST::Group(vec![RegexAST::Char('p')]),
+                    source: RegexAST::Group(vec![RegexAST::Char("b".to_string())]),
+                    target: RegexAST::Group(vec![RegexAST::Char("p".to_string())]),
                 }
             ))
         )
@@ -717,10 +860,10 @@ mod tests {
             Ok((
                 "",
                 RewriteRule {
-                    left: RegexAST::Group(vec![RegexAST::Char('c')]),
-                    right: RegexAST::Group(vec![RegexAST::Char('d')]),
-                    source: RegexAST::Group(vec![RegexAST::Char('a')]),
-                    target: RegexAST::Group(vec![RegexAST::Char('b')]),
+                    left: RegexAST::Group(vec![RegexAST::Char("c".to_string())]),
+                    right: RegexAST::Group(vec![RegexAST::Char("d".to_string())]),
+                    source: RegexAST::Group(vec![RegexAST::Char("a".to_string())]),
+                    target: RegexAST::Group(vec![RegexAST::Char("b".to_string())]),
                 }
             ))
         );
@@ -732,16 +875,16 @@ mod tests {
             parse_script("a -> b / c _ d\nb -> p / _ #"),
             Ok(vec![
                 Statement::Rule(RewriteRule {
-                    left: RegexAST::Group(vec![RegexAST::Char('c')]),
-                    right: RegexAST::Group(vec![RegexAST::Char('d')]),
-                    source: RegexAST::Group(vec![RegexAST::Char('a')]),
-                    target: RegexAST::Group(vec![RegexAST::Char('b')]),
+                    left: RegexAST::Group(vec![RegexAST::Char("c".to_string())]),
+                    right: RegexAST::Group(vec![RegexAST::Char("d".to_string())]),
+                    source: RegexAST::Group(vec![RegexAST::Char("a".to_string())]),
+                    target: RegexAST::Group(vec![RegexAST::Char("b".to_string())]),
                 }),
                 Statement::Rule(RewriteRule {
                     left: RegexAST::Epsilon,
                     right: RegexAST::Group(vec![RegexAST::Boundary]),
-                    source: RegexAST::Group(vec![RegexAST::Char('b')]),
-                    target: RegexAST::Group(vec![RegexAST::Char('p')]),
+                    source: RegexAST::Group(vec![RegexAST::Char("b".to_string())]),
+                    target: RegexAST::Group(vec![RegexAST::Char("p".to_string())]),
                 })
             ])
         );
@@ -758,16 +901,16 @@ mod tests {
                 (
                     vec![
                         Statement::Rule(RewriteRule {
-                            left: RegexAST::Group(vec![RegexAST::Char('c')]),
-                            right: RegexAST::Group(vec![RegexAST::Char('d')]),
-                            source: RegexAST::Group(vec![RegexAST::Char('a')]),
-                            target: RegexAST::Group(vec![RegexAST::Char('b')]),
+                            left: RegexAST::Group(vec![RegexAST::Char("c".to_string())]),
+                            right: RegexAST::Group(vec![RegexAST::Char("d".to_string())]),
+                            source: RegexAST::Group(vec![RegexAST::Char("a".to_string())]),
+                            target: RegexAST::Group(vec![RegexAST::Char("b".to_string())]),
                         }),
                         Statement::Rule(RewriteRule {
                             left: RegexAST::Epsilon,
                             right: RegexAST::Group(vec![RegexAST::Boundary]),
-                            source: RegexAST::Group(vec![RegexAST::Char('b')]),
-                            target: RegexAST::Group(vec![RegexAST::Char('p')]),
+                            source: RegexAST::Group(vec![RegexAST::Char("b".to_string())]),
+                            target: RegexAST::Group(vec![RegexAST::Char("p".to_string())]),
                         })
                     ],
                     hashset_str!["c", "d", "a", "b", "b", "p"]
@@ -786,10 +929,10 @@ mod tests {
                     vec![Statement::MacroDef((
                         "vowel".to_string(),
                         RegexAST::Group(vec![RegexAST::Disjunction(vec![
-                            RegexAST::Group(vec![RegexAST::Char('a')]),
-                            RegexAST::Group(vec![RegexAST::Char('b')]),
-                            RegexAST::Group(vec![RegexAST::Char('c')]),
-                            RegexAST::Group(vec![RegexAST::Char('d')]),
+                            RegexAST::Group(vec![RegexAST::Char("a".to_string())]),
+                            RegexAST::Group(vec![RegexAST::Char("b".to_string())]),
+                            RegexAST::Group(vec![RegexAST::Char("c".to_string())]),
+                            RegexAST::Group(vec![RegexAST::Char("d".to_string())]),
                         ])])
                     )),],
                     hashset_str!["a", "b", "c", "d"]
@@ -809,18 +952,18 @@ mod tests {
                         Statement::MacroDef((
                             "letter".to_string(),
                             RegexAST::Group(vec![RegexAST::Disjunction(vec![
-                                RegexAST::Group(vec![RegexAST::Char('a')]),
-                                RegexAST::Group(vec![RegexAST::Char('b')]),
-                                RegexAST::Group(vec![RegexAST::Char('c')]),
-                                RegexAST::Group(vec![RegexAST::Char('d')]),
+                                RegexAST::Group(vec![RegexAST::Char("a".to_string())]),
+                                RegexAST::Group(vec![RegexAST::Char("b".to_string())]),
+                                RegexAST::Group(vec![RegexAST::Char("c".to_string())]),
+                                RegexAST::Group(vec![RegexAST::Char("d".to_string())]),
                             ])])
                         )),
                         // Statement::Comment,
                         Statement::Rule(RewriteRule {
-                            left: RegexAST::Group(vec![RegexAST::Char('c')]),
+                            left: RegexAST::Group(vec![RegexAST::Char("c".to_string())]),
                             right: RegexAST::Group(vec![RegexAST::Macro("letter".to_string())]),
-                            source: RegexAST::Group(vec![RegexAST::Char('a')]),
-                            target: RegexAST::Group(vec![RegexAST::Char('b')])
+                            source: RegexAST::Group(vec![RegexAST::Char("a".to_string())]),
+                            target: RegexAST::Group(vec![RegexAST::Char("b".to_string())])
                         }),
                     ],
                     hashset_str!["a", "b", "c", "d"]
@@ -930,19 +1073,19 @@ mod tests {
                         Statement::MacroDef((
                             "vowel".to_string(),
                             RegexAST::Group(vec![RegexAST::Disjunction(vec![
-                                RegexAST::Group(vec![RegexAST::Char('a')]),
-                                RegexAST::Group(vec![RegexAST::Char('e')]),
-                                RegexAST::Group(vec![RegexAST::Char('i')]),
-                                RegexAST::Group(vec![RegexAST::Char('o')]),
-                                RegexAST::Group(vec![RegexAST::Char('u')]),
+                                RegexAST::Group(vec![RegexAST::Char("a".to_string())]),
+                                RegexAST::Group(vec![RegexAST::Char("e".to_string())]),
+                                RegexAST::Group(vec![RegexAST::Char("i".to_string())]),
+                                RegexAST::Group(vec![RegexAST::Char("o".to_string())]),
+                                RegexAST::Group(vec![RegexAST::Char("u".to_string())]),
                             ])])
                         )),
                         Statement::Comment,
                         Statement::Rule(RewriteRule {
                             left: RegexAST::Epsilon,
                             right: RegexAST::Group(vec![RegexAST::Macro("vowel".to_string())]),
-                            source: RegexAST::Group(vec![RegexAST::Char('u')]),
-                            target: RegexAST::Group(vec![RegexAST::Char('w')])
+                            source: RegexAST::Group(vec![RegexAST::Char("u".to_string())]),
+                            target: RegexAST::Group(vec![RegexAST::Char("w".to_string())])
                         }),
                     ],
                     hashset_str!["a", "e", "i", "o", "u", "w"]
@@ -962,19 +1105,19 @@ mod tests {
                         Statement::MacroDef((
                             "vowel".to_string(),
                             RegexAST::Group(vec![RegexAST::Disjunction(vec![
-                                RegexAST::Group(vec!(RegexAST::Char('a'))),
-                                RegexAST::Group(vec!(RegexAST::Char('e'))),
-                                RegexAST::Group(vec!(RegexAST::Char('i'))),
-                                RegexAST::Group(vec!(RegexAST::Char('o'))),
-                                RegexAST::Group(vec!(RegexAST::Char('u'))),
+                                RegexAST::Group(vec!(RegexAST::Char("a".to_string()))),
+                                RegexAST::Group(vec!(RegexAST::Char("e".to_string()))),
+                                RegexAST::Group(vec!(RegexAST::Char("i".to_string()))),
+                                RegexAST::Group(vec!(RegexAST::Char("o".to_string()))),
+                                RegexAST::Group(vec!(RegexAST::Char("u".to_string()))),
                             ])])
                         )),
                         // Statement::Comment,
                         Statement::Rule(RewriteRule {
                             left: RegexAST::Epsilon,
                             right: RegexAST::Group(vec![RegexAST::Macro("vowel".to_string())]),
-                            source: RegexAST::Group(vec![RegexAST::Char('u')]),
-                            target: RegexAST::Group(vec![RegexAST::Char('w')])
+                            source: RegexAST::Group(vec![RegexAST::Char("u".to_string())]),
+                            target: RegexAST::Group(vec![RegexAST::Char("w".to_string())])
                         }),
                     ],
                     hashset_str!["a", "e", "i", "o", "u", "w"]
@@ -998,19 +1141,19 @@ u -> w / _ ::vowel::"##
                         Statement::MacroDef((
                             "vowel".to_string(),
                             RegexAST::Group(vec![RegexAST::Disjunction(vec![
-                                RegexAST::Group(vec!(RegexAST::Char('a'))),
-                                RegexAST::Group(vec!(RegexAST::Char('e'))),
-                                RegexAST::Group(vec!(RegexAST::Char('i'))),
-                                RegexAST::Group(vec!(RegexAST::Char('o'))),
-                                RegexAST::Group(vec!(RegexAST::Char('u'))),
+                                RegexAST::Group(vec!(RegexAST::Char("a".to_string()))),
+                                RegexAST::Group(vec!(RegexAST::Char("e".to_string()))),
+                                RegexAST::Group(vec!(RegexAST::Char("i".to_string()))),
+                                RegexAST::Group(vec!(RegexAST::Char("o".to_string()))),
+                                RegexAST::Group(vec!(RegexAST::Char("u".to_string()))),
                             ])])
                         )),
                         Statement::Comment,
                         Statement::Rule(RewriteRule {
                             left: RegexAST::Epsilon,
                             right: RegexAST::Group(vec![RegexAST::Macro("vowel".to_string())]),
-                            source: RegexAST::Group(vec![RegexAST::Char('u')]),
-                            target: RegexAST::Group(vec![RegexAST::Char('w')])
+                            source: RegexAST::Group(vec![RegexAST::Char("u".to_string())]),
+                            target: RegexAST::Group(vec![RegexAST::Char("w".to_string())])
                         }),
                     ],
                     hashset_str!["a", "e", "i", "o", "u", "w"]
@@ -1019,6 +1162,20 @@ u -> w / _ ::vowel::"##
         );
     }
 
+    #[test]
+    fn test_trailing_comments_end_their_statement() {
+        let plain = parse_script("::v:: = (a|e)\na -> b / c _ ::v::\nb -> p\nb -> p / _ #\n").unwrap();
+        let commented = parse_script(
+            "::v:: = (a|e) % vowels\na -> b / c _ ::v::   % voicing\nb -> p%\nb -> p / _ # % final\n",
+        )
+        .unwrap();
+        debug_assert_eq!(commented, plain);
+        // Standalone comments, even empty ones, are statements of their own.
+        let (rest, (statements, _)) = parse_script("%\n  % indented\nb -> p\n").unwrap();
+        debug_assert_eq!(rest, "");
+        debug_assert_eq!(statements[..2], [Statement::Comment, Statement::Comment]);
+    }
+
     /*
            #[test]
            fn test_rule_and_macro() {
@@ -1028,15 +1185,15 @@ u -> w / _ ::vowel::"##
                        Statement::MacroDef((
                            "abc".to_string(),
                            RegexAST::Group(vec![RegexAST::Disjunction(vec![
-                               RegexAST::Group(vec!(RegexAST::Char('d'))),
-                               RegexAST::Group(vec!(RegexAST::Char('e'))),
+                               RegexAST::Group(vec!(RegexAST::Char("d".to_string()))),
+                               RegexAST::Group(vec!(RegexAST::Char("e".to_string()))),
                            ])])
                        )),
                        Statement::Rule(RewriteRule {
-                           left: RegexAST::Group(vec![RegexAST::Char('c')]),
-                           right: RegexAST::Group(vec![RegexAST::Char('d')]),
-                           source: RegexAST::Group(vec![RegexAST::Char('a')]),
-                           target: RegexAST::Group(vec![RegexAST::Char('b')]),
+                           left: RegexAST::Group(vec![RegexAST::Char("c".to_string())]),
+                           right: RegexAST::Group(vec![RegexAST::Char("d".to_string())]),
+                           source: RegexAST::Group(vec![RegexAST::Char("a".to_string())]),
+                           target: RegexAST::Group(vec![RegexAST::Char("b".to_string())]),
                        })
                    ]
                    hashset_str!["a", "b", "c" "d"]
@@ -19,7 +19,7 @@ pub fn optimize_fst(
     minimize_with_config(
         fst,
         MinimizeConfig {
-            delta: delta,
+            delta,
             allow_nondet: true,
         },
     )?;
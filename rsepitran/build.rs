@@ -102,11 +102,7 @@ fn main() -> Result<()> {
 }
 
 fn extract_lang_code(filename: &str) -> Option<String> {
-    if let Some(stem) = filename.strip_suffix(".csv") {
-        Some(stem.to_string())
-    } else {
-        None
-    }
+    filename.strip_suffix(".csv").map(|stem| stem.to_string())
 }
 
 fn get_language_data(lang_code: &str) -> Result<(String, String, String)> {
@@ -20,13 +20,16 @@ include!(concat!(env!("OUT_DIR"), "/language_data.rs"));
 /// Cached FST data for a language
 type CachedFst = (Arc<SymbolTable>, VectorFst<TropicalWeight>);
 
+/// Lazily compiled FST cache, keyed by language code
+type FstCache = Arc<Mutex<HashMap<String, Arc<OnceCell<Result<CachedFst, String>>>>>>;
+
 /// Main struct for handling phonetic transliteration
 ///
 /// Contains lazily compiled wFSTs for supported languages and provides
 /// methods for transliterating text. FSTs are built on-demand when first
 /// requested and cached in memory for subsequent use.
 pub struct Epitran {
-    fst_cache: Arc<Mutex<HashMap<String, Arc<OnceCell<Result<CachedFst, String>>>>>>,
+    fst_cache: FstCache,
 }
 
 impl Epitran {
@@ -57,7 +60,7 @@ impl Epitran {
             .chars()
             .map(|c| {
                 // Only convert Latin script uppercase to lowercase
-                if c.is_ascii_uppercase() || (c >= 'À' && c <= 'Ÿ') {
+                if c.is_ascii_uppercase() || ('À'..='Ÿ').contains(&c) {
                     c.to_lowercase().collect::<String>()
                 } else {
                     c.to_string()
@@ -116,12 +116,10 @@ pub fn filter_by_symbols(input: &str, syms: &HashSet<String>) -> String {
         // Try to find the longest matching symbol starting at position i
         for j in (i + 1)..=input_chars.len() {
             let candidate: String = input_chars[i..j].iter().collect();
-            if syms.contains(&candidate) {
-                if candidate.len() > best_match_len {
-                    best_match_len = candidate.len();
-                    best_match = candidate;
-                    matched = true;
-                }
+            if syms.contains(&candidate) && candidate.len() > best_match_len {
+                best_match_len = candidate.len();
+                best_match = candidate;
+                matched = true;
             }
         }
 